@@ -0,0 +1,90 @@
+//! Measures plan time, pipeline build time, and end-to-end latency excluding the database round
+//! trip for requests with deep relationships, wide projections, and many variable sets.
+//!
+//! Run with `cargo bench -p benchmarks`. Compare runs before and after a change to the pipeline
+//! strategy with criterion's `--save-baseline` and `--baseline` options.
+
+use benchmarks::{config, deep_relationships, many_variable_sets, wide_projection, Scenario};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use mongodb_agent_common::{
+    mongo_query_plan::MongoConfiguration,
+    query::{pipeline_for_query_request, response::serialize_query_response},
+};
+use ndc_query_plan::plan_for_query_request;
+
+fn scenarios() -> Vec<Scenario> {
+    [1, 4, 8]
+        .into_iter()
+        .map(|depth| deep_relationships(depth, 100))
+        .chain(
+            [10, 50, 200]
+                .into_iter()
+                .map(|width| wide_projection(width, 100)),
+        )
+        .chain(
+            [10, 100, 1000]
+                .into_iter()
+                .map(|sets| many_variable_sets(sets, 10)),
+        )
+        .collect()
+}
+
+fn plan(c: &mut Criterion) {
+    let config = config();
+    let mut group = c.benchmark_group("plan");
+    for scenario in scenarios() {
+        group.bench_function(BenchmarkId::from_parameter(&scenario.name), |b| {
+            b.iter_batched(
+                || scenario.request.clone(),
+                |request| plan_for_query_request(&config, request).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn pipeline(c: &mut Criterion) {
+    let config = config();
+    let mut group = c.benchmark_group("pipeline");
+    for scenario in scenarios() {
+        let query_plan = plan_for_query_request(&config, scenario.request.clone()).unwrap();
+        group.bench_function(BenchmarkId::from_parameter(&scenario.name), |b| {
+            b.iter(|| pipeline_for_query_request(&config, &query_plan).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn end_to_end(c: &mut Criterion) {
+    let config = config();
+    let mut group = c.benchmark_group("end_to_end");
+    for scenario in scenarios() {
+        group.bench_function(BenchmarkId::from_parameter(&scenario.name), |b| {
+            b.iter_batched(
+                || scenario.clone(),
+                |scenario| run_end_to_end(&config, scenario),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Plans a request, builds its pipeline, and serializes canned response documents
+fn run_end_to_end(config: &MongoConfiguration, scenario: Scenario) -> usize {
+    let query_plan = plan_for_query_request(config, scenario.request).unwrap();
+    pipeline_for_query_request(config, &query_plan).unwrap();
+    let response = serialize_query_response(
+        config.serialization_options(),
+        config.object_id_formats(),
+        &Default::default(),
+        &query_plan,
+        scenario.response_documents,
+    )
+    .unwrap();
+    response.len()
+}
+
+criterion_group!(benches, plan, pipeline, end_to_end);
+criterion_main!(benches);
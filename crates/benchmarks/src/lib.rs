@@ -0,0 +1,216 @@
+//! Fixtures for benchmarks that compare the cost of query planning, pipeline building, and
+//! response serialization for request shapes that stress different parts of the connector:
+//! chains of relationships, wide projections, and requests with many variable sets.
+//!
+//! Each [Scenario] pairs a query request with canned response documents in the shape that the
+//! pipeline for that request produces so that end-to-end latency can be measured without
+//! a database.
+
+use configuration::Configuration;
+use indexmap::IndexMap;
+use mongodb::bson::{doc, Bson, Document, RawDocumentBuf};
+use mongodb_agent_common::mongo_query_plan::MongoConfiguration;
+use ndc_models::{Field, Query, QueryRequest};
+use ndc_test_helpers::{
+    binop, collection, named_type, object_type, query, query_request, relationship, target,
+    variable,
+};
+
+pub const NODES: &str = "nodes";
+pub const WIDE: &str = "wide";
+
+const PARENT_RELATIONSHIP: &str = "parent";
+const WIDE_FIELD_COUNT: usize = 200;
+
+/// A request to benchmark, and documents that stand in for the database response
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    pub name: String,
+    pub request: QueryRequest,
+    pub response_documents: Vec<RawDocumentBuf>,
+}
+
+/// A configuration with a self-referencing `nodes` collection for relationship chains, and
+/// a `wide` collection with many fields for wide projections
+pub fn config() -> MongoConfiguration {
+    let wide_fields: Vec<_> = (0..WIDE_FIELD_COUNT)
+        .map(|n| (wide_field_name(n), named_type("String")))
+        .collect();
+    MongoConfiguration(Configuration {
+        collections: [collection(NODES), collection(WIDE)].into(),
+        object_types: [
+            (
+                NODES.into(),
+                object_type([
+                    ("_id", named_type("Int")),
+                    ("parent_id", named_type("Int")),
+                    ("name", named_type("String")),
+                ]),
+            ),
+            (WIDE.into(), object_type(wide_fields)),
+        ]
+        .into(),
+        functions: Default::default(),
+        procedures: Default::default(),
+        native_mutations: Default::default(),
+        native_queries: Default::default(),
+        lookup_functions: Default::default(),
+        collection_policies: Default::default(),
+        relationships: Default::default(),
+        object_id_formats: Default::default(),
+        options: Default::default(),
+    })
+}
+
+/// Selects `name` from each node, and from each of its ancestors up to the given depth
+pub fn deep_relationships(depth: usize, rows: usize) -> Scenario {
+    let request = query_request()
+        .collection(NODES)
+        .query(Query {
+            fields: Some(node_fields(depth)),
+            ..Query::from(query())
+        })
+        .relationships([(
+            PARENT_RELATIONSHIP,
+            relationship(NODES, [("parent_id", "_id")]).object_type(),
+        )])
+        .into();
+    let response_documents = (0..rows)
+        .map(|_| raw_document(&node_document(depth)))
+        .collect();
+    Scenario {
+        name: format!("deep_relationships/depth={depth}"),
+        request,
+        response_documents,
+    }
+}
+
+/// Selects the given number of fields from the `wide` collection
+pub fn wide_projection(width: usize, rows: usize) -> Scenario {
+    let fields = (0..width.min(WIDE_FIELD_COUNT))
+        .map(|n| {
+            let name = wide_field_name(n);
+            (name.clone().into(), column(&name))
+        })
+        .collect();
+    let request = query_request()
+        .collection(WIDE)
+        .query(Query {
+            fields: Some(fields),
+            ..Query::from(query())
+        })
+        .into();
+    let row: Document = (0..width.min(WIDE_FIELD_COUNT))
+        .map(|n| (wide_field_name(n), Bson::String(format!("value {n}"))))
+        .collect();
+    let response_documents = (0..rows).map(|_| raw_document(&row)).collect();
+    Scenario {
+        name: format!("wide_projection/width={width}"),
+        request,
+        response_documents,
+    }
+}
+
+/// Filters nodes by name once for each of the given number of variable sets
+pub fn many_variable_sets(variable_sets: usize, rows_per_set: usize) -> Scenario {
+    let request = query_request()
+        .collection(NODES)
+        .query(query().fields([("name", column("name"))]).predicate(binop(
+            "_eq",
+            target!("name"),
+            variable!(name),
+        )))
+        .variables((0..variable_sets).map(|n| [("name", format!("node {n}"))]))
+        .into();
+    let response_documents = (0..variable_sets)
+        .map(|n| {
+            let rows: Vec<Bson> = (0..rows_per_set)
+                .map(|_| Bson::Document(doc! { "name": format!("node {n}") }))
+                .collect();
+            raw_document(&doc! { "rows": rows })
+        })
+        .collect();
+    Scenario {
+        name: format!("many_variable_sets/sets={variable_sets}"),
+        request,
+        response_documents,
+    }
+}
+
+fn node_fields(depth: usize) -> IndexMap<ndc_models::FieldName, Field> {
+    let mut fields: IndexMap<_, _> = [("name".into(), column("name"))].into();
+    if depth > 0 {
+        fields.insert(
+            PARENT_RELATIONSHIP.into(),
+            Field::Relationship {
+                query: Box::new(Query {
+                    fields: Some(node_fields(depth - 1)),
+                    ..Query::from(query())
+                }),
+                relationship: PARENT_RELATIONSHIP.into(),
+                arguments: Default::default(),
+            },
+        );
+    }
+    fields
+}
+
+fn node_document(depth: usize) -> Document {
+    let mut document = doc! { "name": format!("node {depth}") };
+    if depth > 0 {
+        document.insert(
+            PARENT_RELATIONSHIP,
+            doc! { "rows": [node_document(depth - 1)] },
+        );
+    }
+    document
+}
+
+fn column(name: &str) -> Field {
+    Field::Column {
+        column: name.into(),
+        arguments: Default::default(),
+        fields: None,
+    }
+}
+
+fn wide_field_name(n: usize) -> String {
+    format!("field_{n:03}")
+}
+
+fn raw_document(document: &Document) -> RawDocumentBuf {
+    RawDocumentBuf::from_document(document).expect("fixture documents are valid BSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb_agent_common::query::{
+        pipeline_for_query_request, response::serialize_query_response,
+    };
+    use ndc_query_plan::plan_for_query_request;
+
+    use super::{config, deep_relationships, many_variable_sets, wide_projection};
+
+    #[test]
+    fn scenarios_plan_and_serialize() -> anyhow::Result<()> {
+        let config = config();
+        for scenario in [
+            deep_relationships(3, 2),
+            wide_projection(50, 2),
+            many_variable_sets(10, 2),
+        ] {
+            let query_plan = plan_for_query_request(&config, scenario.request)?;
+            pipeline_for_query_request(&config, &query_plan)?;
+            let response = serialize_query_response(
+                config.serialization_options(),
+                config.object_id_formats(),
+                &Default::default(),
+                &query_plan,
+                scenario.response_documents,
+            )?;
+            let response: serde_json::Value = serde_json::from_slice(&response)?;
+            assert!(response.is_array(), "{}: {response}", scenario.name);
+        }
+        Ok(())
+    }
+}
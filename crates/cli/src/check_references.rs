@@ -0,0 +1,272 @@
+//! Checks declared object relationships for orphaned references: documents in the source
+//! collection whose join fields are set, but that have no matching document in the target
+//! collection. The engine assumes that object relationships are valid, so orphans show up as
+//! missing related objects in query results. Documents whose join fields are null or missing are
+//! not counted as orphans. Array relationships are skipped because a source document with no
+//! related documents is expected for those.
+
+use anyhow::Context as _;
+use clap::Parser;
+use configuration::{schema, Configuration};
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::AggregateOptions,
+};
+use ndc_models::RelationshipType;
+
+use crate::Context;
+
+const PARENT_FIELD: &str = "__parent";
+
+#[derive(Debug, Clone, Parser)]
+pub struct CheckReferencesArgs {
+    /// Name of a declared relationship to check. Defaults to checking every declared object
+    /// relationship.
+    #[arg(value_name = "RELATIONSHIP", required = false)]
+    relationship: Option<String>,
+
+    /// Check a random sample of this many source documents instead of every document
+    #[arg(long = "sample-size", value_name = "N", required = false)]
+    sample_size: Option<u32>,
+
+    /// Number of orphaned document ids to report for each relationship
+    #[arg(long = "examples", value_name = "N", default_value_t = 5)]
+    examples: u32,
+
+    /// Number of documents that MongoDB returns in each batch of results
+    #[arg(long = "batch-size", value_name = "N", required = false)]
+    batch_size: Option<u32>,
+}
+
+/// A relationship declared in a schema file, along with the collection that declares it
+#[derive(Clone, Debug)]
+struct DeclaredRelationship {
+    name: String,
+    source_collection: String,
+    relationship: schema::Relationship,
+}
+
+pub async fn check_references(context: &Context, args: &CheckReferencesArgs) -> anyhow::Result<()> {
+    // Parse the full configuration first so that invalid relationship declarations are reported
+    Configuration::parse_configuration(&context.path).await?;
+    let schemas = configuration::read_schema_directory(&context.path).await?;
+    let relationships: Vec<DeclaredRelationship> = schemas
+        .into_values()
+        .flat_map(|schema| schema.collections)
+        .flat_map(|(source_collection, collection)| {
+            collection
+                .relationships
+                .into_iter()
+                .map(move |(name, relationship)| DeclaredRelationship {
+                    name: name.to_string(),
+                    source_collection: source_collection.to_string(),
+                    relationship,
+                })
+        })
+        .filter(|declared| match &args.relationship {
+            Some(name) => &declared.name == name,
+            None => declared.relationship.relationship_type == RelationshipType::Object,
+        })
+        .collect();
+    if let Some(name) = &args.relationship {
+        anyhow::ensure!(
+            !relationships.is_empty(),
+            "no relationship named {name} is declared in schema files"
+        );
+    }
+
+    let database = context.connector_state.database();
+    let mut orphans_found = false;
+    for declared in relationships {
+        let mut options = AggregateOptions::default();
+        options.batch_size = args.batch_size;
+        let result = database
+            .collection::<Document>(&declared.source_collection)
+            .aggregate(orphans_pipeline(&declared, args), options)
+            .await?
+            .try_next()
+            .await?
+            .context("the aggregation produced no result")?;
+        let report = Report::from_document(&result)
+            .with_context(|| format!("checking the relationship, {}", declared.name))?;
+        orphans_found |= report.orphaned > 0;
+        println!(
+            "{} ({} -> {}): {} orphaned of {} checked{}",
+            declared.name,
+            declared.source_collection,
+            declared.relationship.target_collection,
+            report.orphaned,
+            report.checked,
+            if report.example_ids.is_empty() {
+                String::new()
+            } else {
+                format!("; examples: {}", report.example_ids.join(", "))
+            }
+        );
+    }
+    anyhow::ensure!(!orphans_found, "found orphaned references");
+    Ok(())
+}
+
+/// Aggregation pipeline to run against the source collection of a relationship. Produces a single
+/// document with the number of documents checked, the number of orphans, and example ids.
+fn orphans_pipeline(declared: &DeclaredRelationship, args: &CheckReferencesArgs) -> Vec<Document> {
+    let column_mapping = &declared.relationship.column_mapping;
+    let has_join_keys: Document = column_mapping
+        .keys()
+        .map(|source_field| (source_field.to_string(), doc! { "$ne": null }.into()))
+        .collect();
+    let lookup_variables: Document = column_mapping
+        .keys()
+        .enumerate()
+        .map(|(index, source_field)| (format!("v{index}"), format!("${source_field}").into()))
+        .collect();
+    let join_conditions: Vec<Bson> = column_mapping
+        .values()
+        .enumerate()
+        .map(|(index, target_field)| {
+            doc! { "$eq": [format!("${target_field}"), format!("$$v{index}")] }.into()
+        })
+        .collect();
+    let is_orphan = doc! { "$match": { PARENT_FIELD: { "$size": 0 } } };
+
+    args.sample_size
+        .map(|size| doc! { "$sample": { "size": size as i64 } })
+        .into_iter()
+        .chain([
+            doc! { "$match": has_join_keys },
+            doc! {
+                "$lookup": {
+                    "from": declared.relationship.target_collection.as_str(),
+                    "let": lookup_variables,
+                    "pipeline": [
+                        { "$match": { "$expr": { "$and": join_conditions } } },
+                        { "$limit": 1 },
+                        { "$project": { "_id": 1 } },
+                    ],
+                    "as": PARENT_FIELD,
+                }
+            },
+            doc! {
+                "$facet": {
+                    "checked": [{ "$count": "count" }],
+                    "orphaned": [is_orphan.clone(), { "$count": "count" }],
+                    "examples": [is_orphan, { "$limit": args.examples as i64 }, { "$project": { "_id": 1 } }],
+                }
+            },
+        ])
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+struct Report {
+    checked: i64,
+    orphaned: i64,
+    example_ids: Vec<String>,
+}
+
+impl Report {
+    fn from_document(document: &Document) -> anyhow::Result<Self> {
+        // A facet that matches no documents produces an empty array instead of a zero count
+        let count = |facet: &str| -> anyhow::Result<i64> {
+            let counts = document.get_array(facet)?;
+            match counts.first().and_then(Bson::as_document) {
+                Some(count) => match count.get("count") {
+                    Some(Bson::Int32(n)) => Ok(*n as i64),
+                    Some(Bson::Int64(n)) => Ok(*n),
+                    _ => Err(anyhow::anyhow!("unexpected count in {facet}: {count}")),
+                },
+                None => Ok(0),
+            }
+        };
+        let example_ids = document
+            .get_array("examples")?
+            .iter()
+            .filter_map(|example| example.as_document()?.get("_id"))
+            .map(|id| match id {
+                Bson::ObjectId(oid) => oid.to_hex(),
+                Bson::String(s) => s.clone(),
+                id => id.to_string(),
+            })
+            .collect();
+        Ok(Report {
+            checked: count("checked")?,
+            orphaned: count("orphaned")?,
+            example_ids,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::schema;
+    use mongodb::bson::{doc, oid::ObjectId, Bson};
+    use ndc_models::RelationshipType;
+
+    use super::{orphans_pipeline, CheckReferencesArgs, DeclaredRelationship, Report};
+
+    #[test]
+    fn builds_pipeline_that_counts_orphans() -> anyhow::Result<()> {
+        let declared = DeclaredRelationship {
+            name: "movie".into(),
+            source_collection: "comments".into(),
+            relationship: schema::Relationship {
+                target_collection: "movies".into(),
+                column_mapping: [("movie_id".into(), "_id".into())].into(),
+                relationship_type: RelationshipType::Object,
+                single_object: false,
+                description: None,
+            },
+        };
+        let args = CheckReferencesArgs {
+            relationship: None,
+            sample_size: Some(1000),
+            examples: 2,
+            batch_size: None,
+        };
+        let is_orphan = doc! { "$match": { "__parent": { "$size": 0 } } };
+        assert_eq!(
+            orphans_pipeline(&declared, &args),
+            vec![
+                doc! { "$sample": { "size": Bson::Int64(1000) } },
+                doc! { "$match": { "movie_id": { "$ne": null } } },
+                doc! {
+                    "$lookup": {
+                        "from": "movies",
+                        "let": { "v0": "$movie_id" },
+                        "pipeline": [
+                            { "$match": { "$expr": { "$and": [{ "$eq": ["$_id", "$$v0"] }] } } },
+                            { "$limit": 1 },
+                            { "$project": { "_id": 1 } },
+                        ],
+                        "as": "__parent",
+                    }
+                },
+                doc! {
+                    "$facet": {
+                        "checked": [{ "$count": "count" }],
+                        "orphaned": [is_orphan.clone(), { "$count": "count" }],
+                        "examples": [is_orphan, { "$limit": Bson::Int64(2) }, { "$project": { "_id": 1 } }],
+                    }
+                },
+            ]
+        );
+
+        let id = ObjectId::new();
+        let report = Report::from_document(&doc! {
+            "checked": [{ "count": 1000 }],
+            "orphaned": [{ "count": 1 }],
+            "examples": [{ "_id": id }],
+        })?;
+        assert_eq!(
+            report,
+            Report {
+                checked: 1000,
+                orphaned: 1,
+                example_ids: vec![id.to_hex()],
+            }
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+//! Compares the schema that two configuration directories present to the engine, and classifies
+//! each difference as breaking or non-breaking. A change is breaking if a query or mutation that
+//! is valid against the old schema may fail, or may produce values that the old schema did not
+//! allow: removing a collection, function, procedure, object type, field, or relationship,
+//! adding a required argument, or changing a type other than by widening it.
+
+use std::{collections::BTreeMap, fmt::Display, path::PathBuf};
+
+use clap::Parser;
+use configuration::Configuration;
+use ndc_models as ndc;
+
+use crate::Context;
+
+#[derive(Debug, Clone, Parser)]
+pub struct CompareConfigurationArgs {
+    /// The configuration directory to compare against, such as a checkout of the deployed
+    /// configuration
+    #[arg(value_name = "OLD_DIR")]
+    old: PathBuf,
+
+    /// The changed configuration directory. Defaults to the current configuration directory.
+    #[arg(value_name = "NEW_DIR", required = false)]
+    new: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Compatibility {
+    Breaking,
+    NonBreaking,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Change {
+    compatibility: Compatibility,
+    description: String,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self.compatibility {
+            Compatibility::Breaking => "breaking",
+            Compatibility::NonBreaking => "non-breaking",
+        };
+        write!(f, "{label}: {}", self.description)
+    }
+}
+
+pub async fn compare_configuration(
+    context: &Context,
+    args: &CompareConfigurationArgs,
+) -> anyhow::Result<()> {
+    let old = Configuration::parse_configuration(&args.old).await?;
+    let new_dir = args.new.as_ref().unwrap_or(&context.path);
+    let new = Configuration::parse_configuration(new_dir).await?;
+
+    let changes = compare(&old, &new);
+    if changes.is_empty() {
+        println!("no changes");
+    }
+    for change in &changes {
+        println!("{change}");
+    }
+    let breaking = changes
+        .iter()
+        .filter(|change| change.compatibility == Compatibility::Breaking)
+        .count();
+    anyhow::ensure!(breaking == 0, "found {breaking} breaking changes");
+    Ok(())
+}
+
+fn compare(old: &Configuration, new: &Configuration) -> Vec<Change> {
+    let mut changes = Changes::default();
+    changes.compare_maps(
+        |name| format!("the collection, {name}"),
+        &old.collections,
+        &new.collections,
+        |changes, name, old, new| changes.compare_collections(name, old, new),
+    );
+    changes.compare_maps(
+        |name| format!("the function, {name}"),
+        &old.functions,
+        &new.functions,
+        |changes, name, (old_info, _), (new_info, _)| {
+            let context = format!("the result of the function, {name}");
+            changes.compare_types(&context, &old_info.result_type, &new_info.result_type);
+            changes.compare_arguments(
+                &format!("the function, {name}"),
+                &old_info.arguments,
+                &new_info.arguments,
+            );
+        },
+    );
+    changes.compare_maps(
+        |name| format!("the procedure, {name}"),
+        &old.procedures,
+        &new.procedures,
+        |changes, name, old, new| {
+            let context = format!("the result of the procedure, {name}");
+            changes.compare_types(&context, &old.result_type, &new.result_type);
+            changes.compare_arguments(
+                &format!("the procedure, {name}"),
+                &old.arguments,
+                &new.arguments,
+            );
+        },
+    );
+    changes.compare_maps(
+        |name| format!("the object type, {name}"),
+        &old.object_types,
+        &new.object_types,
+        |changes, name, old, new| {
+            let describe = |field: &ndc::FieldName| format!("the field, {name}.{field}");
+            changes.compare_maps(
+                describe,
+                &old.fields,
+                &new.fields,
+                |changes, field, old, new| {
+                    changes.compare_types(&describe(field), &old.r#type, &new.r#type)
+                },
+            )
+        },
+    );
+    changes.compare_maps(
+        |name| format!("the relationship, {name}"),
+        &old.relationships,
+        &new.relationships,
+        |changes, name, old, new| {
+            if old != new {
+                changes.breaking(format!("the relationship, {name}, changed"))
+            }
+        },
+    );
+    let mut changes = changes.0;
+    changes.sort();
+    changes
+}
+
+#[derive(Debug, Default)]
+struct Changes(Vec<Change>);
+
+impl Changes {
+    fn breaking(&mut self, description: String) {
+        self.0.push(Change {
+            compatibility: Compatibility::Breaking,
+            description,
+        })
+    }
+
+    fn non_breaking(&mut self, description: String) {
+        self.0.push(Change {
+            compatibility: Compatibility::NonBreaking,
+            description,
+        })
+    }
+
+    /// Reports removed entries as breaking, added entries as non-breaking, and compares entries
+    /// that are present in both maps with `compare_entry`
+    fn compare_maps<K: Ord, V>(
+        &mut self,
+        describe: impl Fn(&K) -> String,
+        old: &BTreeMap<K, V>,
+        new: &BTreeMap<K, V>,
+        compare_entry: impl Fn(&mut Self, &K, &V, &V),
+    ) {
+        for (name, old_value) in old {
+            match new.get(name) {
+                Some(new_value) => compare_entry(self, name, old_value, new_value),
+                None => self.breaking(format!("removed {}", describe(name))),
+            }
+        }
+        for name in new.keys().filter(|name| !old.contains_key(*name)) {
+            self.non_breaking(format!("added {}", describe(name)))
+        }
+    }
+
+    fn compare_collections(
+        &mut self,
+        name: &impl Display,
+        old: &ndc::CollectionInfo,
+        new: &ndc::CollectionInfo,
+    ) {
+        if old.collection_type != new.collection_type {
+            self.breaking(format!(
+                "the type of the collection, {name}, changed from {} to {}",
+                old.collection_type, new.collection_type
+            ))
+        }
+        self.compare_arguments(
+            &format!("the collection, {name}"),
+            &old.arguments,
+            &new.arguments,
+        );
+    }
+
+    /// Removed arguments and new required arguments break requests that were valid before.
+    /// Argument types may be widened, but not narrowed.
+    fn compare_arguments(
+        &mut self,
+        context: &str,
+        old: &BTreeMap<ndc::ArgumentName, ndc::ArgumentInfo>,
+        new: &BTreeMap<ndc::ArgumentName, ndc::ArgumentInfo>,
+    ) {
+        for (name, old_argument) in old {
+            match new.get(name) {
+                Some(new_argument) => self.compare_types(
+                    &format!("the argument, {name}, of {context}"),
+                    &old_argument.argument_type,
+                    &new_argument.argument_type,
+                ),
+                None => self.breaking(format!("removed the argument, {name}, from {context}")),
+            }
+        }
+        for (name, argument) in new.iter().filter(|(name, _)| !old.contains_key(*name)) {
+            if matches!(argument.argument_type, ndc::Type::Nullable { .. }) {
+                self.non_breaking(format!("added the optional argument, {name}, to {context}"))
+            } else {
+                self.breaking(format!("added the required argument, {name}, to {context}"))
+            }
+        }
+    }
+
+    fn compare_types(&mut self, context: &str, old: &ndc::Type, new: &ndc::Type) {
+        if old == new {
+            return;
+        }
+        let description = format!(
+            "the type of {context} changed from {} to {}",
+            type_name(old),
+            type_name(new)
+        );
+        if widens(old, new) {
+            self.non_breaking(description)
+        } else {
+            self.breaking(description)
+        }
+    }
+}
+
+/// True if every value of the old type is also a value of the new type
+fn widens(old: &ndc::Type, new: &ndc::Type) -> bool {
+    match (old, new) {
+        _ if old == new => true,
+        (_, ndc::Type::Nullable { underlying_type }) if is_extended_json(underlying_type) => true,
+        (
+            ndc::Type::Nullable {
+                underlying_type: old,
+            },
+            ndc::Type::Nullable {
+                underlying_type: new,
+            },
+        ) => widens(old, new),
+        (old, ndc::Type::Nullable { underlying_type }) => widens(old, underlying_type),
+        (
+            ndc::Type::Array {
+                element_type: old_element,
+            },
+            ndc::Type::Array {
+                element_type: new_element,
+            },
+        ) => widens(old_element, new_element),
+        (ndc::Type::Named { name: old }, ndc::Type::Named { name: new }) => matches!(
+            (old.as_str(), new.as_str()),
+            ("Int", "Long") | ("Int", "Double")
+        ),
+        _ => false,
+    }
+}
+
+fn is_extended_json(t: &ndc::Type) -> bool {
+    matches!(t, ndc::Type::Named { name } if name.as_str() == "ExtendedJSON")
+}
+
+fn type_name(t: &ndc::Type) -> String {
+    match t {
+        ndc::Type::Named { name } => format!("{name}!"),
+        ndc::Type::Nullable { underlying_type } => {
+            type_name(underlying_type).trim_end_matches('!').to_owned()
+        }
+        ndc::Type::Array { element_type } => format!("[{}]!", type_name(element_type)),
+        ndc::Type::Predicate { object_type_name } => format!("predicate<{object_type_name}>!"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, ObjectField, ObjectType, Type},
+        Configuration, Schema,
+    };
+    use mongodb_support::BsonScalarType;
+
+    use super::{compare, Change, Compatibility};
+
+    fn configuration(fields: &[(&str, Type)]) -> anyhow::Result<Configuration> {
+        let schema = Schema {
+            collections: [(
+                "movies".into(),
+                Collection {
+                    r#type: "movies".into(),
+                    description: None,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "movies".into(),
+                ObjectType {
+                    fields: fields
+                        .iter()
+                        .map(|(name, t)| {
+                            let field = ObjectField {
+                                r#type: t.clone(),
+                                description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
+                            };
+                            ((*name).into(), field)
+                        })
+                        .collect(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        Configuration::from_schema(schema)
+    }
+
+    #[test]
+    fn classifies_breaking_and_non_breaking_changes() -> anyhow::Result<()> {
+        let id = ("_id", Type::Scalar(BsonScalarType::ObjectId));
+        let old = configuration(&[
+            id.clone(),
+            ("title", Type::Scalar(BsonScalarType::String)),
+            ("year", Type::Scalar(BsonScalarType::Int)),
+            (
+                "rating",
+                Type::Nullable(Box::new(Type::Scalar(BsonScalarType::Double))),
+            ),
+        ])?;
+        let new = configuration(&[
+            id,
+            (
+                "year",
+                Type::Nullable(Box::new(Type::Scalar(BsonScalarType::Long))),
+            ),
+            ("rating", Type::Scalar(BsonScalarType::Double)),
+            ("runtime", Type::Scalar(BsonScalarType::Int)),
+        ])?;
+
+        let breaking = |description: &str| Change {
+            compatibility: Compatibility::Breaking,
+            description: description.to_owned(),
+        };
+        let non_breaking = |description: &str| Change {
+            compatibility: Compatibility::NonBreaking,
+            description: description.to_owned(),
+        };
+        assert_eq!(
+            compare(&old, &new),
+            vec![
+                breaking("removed the field, movies.title"),
+                breaking("the type of the field, movies.rating, changed from Double to Double!"),
+                non_breaking("added the field, movies.runtime"),
+                non_breaking("the type of the field, movies.year, changed from Int! to Long"),
+            ]
+        );
+        assert_eq!(compare(&old, &old), vec![]);
+        Ok(())
+    }
+}
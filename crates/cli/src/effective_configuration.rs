@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+
+use configuration::{native_query::NativeQueryRepresentation, Configuration, ConfigurationOptions};
+use mongodb::bson;
+use serde::Serialize;
+
+/// Summary of a parsed connector configuration, used to check which configuration a connector
+/// deployment is actually running. Native query pipelines and native mutation commands may embed
+/// sensitive values so they are redacted: only the names of pipeline stages and commands are
+/// included.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectiveConfiguration {
+    pub server_version: Option<String>,
+    pub collections: BTreeMap<String, CollectionSummary>,
+    pub functions: Vec<String>,
+    pub procedures: Vec<String>,
+    pub native_queries: BTreeMap<String, NativeQuerySummary>,
+    pub native_mutations: BTreeMap<String, NativeMutationSummary>,
+    pub relationships: BTreeMap<String, ndc_models::Relationship>,
+    pub object_types: Vec<String>,
+    pub options: ConfigurationOptions,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionSummary {
+    pub r#type: String,
+    pub arguments: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NativeQuerySummary {
+    pub representation: NativeQueryRepresentation,
+    pub input_collection: Option<String>,
+    pub arguments: Vec<String>,
+    pub result_document_type: String,
+    pub pipeline_stages: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NativeMutationSummary {
+    pub arguments: Vec<String>,
+    pub command: Option<String>,
+}
+
+impl EffectiveConfiguration {
+    pub fn new(configuration: &Configuration, server_version: Option<String>) -> Self {
+        EffectiveConfiguration {
+            server_version,
+            collections: configuration
+                .collections
+                .iter()
+                .map(|(name, collection)| {
+                    (
+                        name.to_string(),
+                        CollectionSummary {
+                            r#type: collection.collection_type.to_string(),
+                            arguments: collection
+                                .arguments
+                                .keys()
+                                .map(ToString::to_string)
+                                .collect(),
+                        },
+                    )
+                })
+                .collect(),
+            functions: configuration
+                .functions
+                .keys()
+                .map(ToString::to_string)
+                .collect(),
+            procedures: configuration
+                .procedures
+                .keys()
+                .map(ToString::to_string)
+                .collect(),
+            native_queries: configuration
+                .native_queries
+                .iter()
+                .map(|(name, native_query)| {
+                    (
+                        name.to_string(),
+                        NativeQuerySummary {
+                            representation: native_query.representation,
+                            input_collection: native_query
+                                .input_collection
+                                .as_ref()
+                                .map(ToString::to_string),
+                            arguments: native_query
+                                .arguments
+                                .keys()
+                                .map(ToString::to_string)
+                                .collect(),
+                            result_document_type: native_query.result_document_type.to_string(),
+                            pipeline_stages: native_query.pipeline.iter().map(redact).collect(),
+                        },
+                    )
+                })
+                .collect(),
+            native_mutations: configuration
+                .native_mutations
+                .iter()
+                .map(|(name, native_mutation)| {
+                    (
+                        name.to_string(),
+                        NativeMutationSummary {
+                            arguments: native_mutation
+                                .arguments
+                                .keys()
+                                .map(ToString::to_string)
+                                .collect(),
+                            command: native_mutation.command.keys().next().cloned(),
+                        },
+                    )
+                })
+                .collect(),
+            relationships: configuration
+                .relationships
+                .iter()
+                .map(|(name, relationship)| (name.to_string(), relationship.clone()))
+                .collect(),
+            object_types: configuration
+                .object_types
+                .keys()
+                .map(ToString::to_string)
+                .collect(),
+            options: configuration.options.clone(),
+        }
+    }
+}
+
+/// Reduce a pipeline stage to its operator name, e.g. `$match`
+fn redact(stage: &bson::Document) -> String {
+    stage
+        .keys()
+        .next()
+        .cloned()
+        .unwrap_or_else(|| "<empty stage>".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::Configuration;
+    use serde_json::json;
+
+    use super::EffectiveConfiguration;
+
+    #[test]
+    fn redacts_native_query_pipelines_and_native_mutation_commands() -> anyhow::Result<()> {
+        let native_query = serde_json::from_value(json!({
+            "representation": "collection",
+            "inputCollection": "accounts",
+            "arguments": { "owner": { "type": { "scalar": "string" } } },
+            "resultDocumentType": "Account",
+            "objectTypes": {
+                "Account": { "fields": { "_id": { "type": { "scalar": "objectId" } } } },
+            },
+            "pipeline": [
+                { "$match": { "owner": "{{ owner }}", "apiKey": "s3cret" } },
+                { "$project": { "_id": 1 } },
+            ],
+        }))?;
+        let native_mutation = serde_json::from_value(json!({
+            "resultType": { "object": "InsertAccount" },
+            "arguments": { "owner": { "type": { "scalar": "string" } } },
+            "objectTypes": {
+                "InsertAccount": {
+                    "fields": {
+                        "ok": { "type": { "scalar": "double" } },
+                        "n": { "type": { "scalar": "int" } },
+                    },
+                },
+            },
+            "command": {
+                "insert": "accounts",
+                "documents": [{ "owner": "{{ owner }}", "apiKey": "s3cret" }],
+            },
+        }))?;
+        let configuration = Configuration::validate(
+            Default::default(),
+            [("insertAccount".into(), native_mutation)].into(),
+            [("accountsByOwner".into(), native_query)].into(),
+            Default::default(),
+        )?;
+
+        let effective = serde_json::to_value(EffectiveConfiguration::new(
+            &configuration,
+            Some("7.0.2".to_owned()),
+        ))?;
+
+        assert!(!effective.to_string().contains("s3cret"));
+        assert_eq!(effective["serverVersion"], json!("7.0.2"));
+        assert_eq!(
+            effective["nativeQueries"],
+            json!({
+                "accountsByOwner": {
+                    "representation": "collection",
+                    "inputCollection": "accounts",
+                    "arguments": ["owner"],
+                    "resultDocumentType": "Account",
+                    "pipelineStages": ["$match", "$project"],
+                },
+            })
+        );
+        assert_eq!(
+            effective["nativeMutations"],
+            json!({
+                "insertAccount": {
+                    "arguments": ["owner"],
+                    "command": "insert",
+                },
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+//! Writes the documents of a collection, or the results of a native query without arguments, to
+//! NDJSON or CSV. Documents are converted to JSON according to the configured object type the same
+//! way that the connector converts query responses, so exported values have the same
+//! representation that clients see. CSV columns are taken from the object type rather than from
+//! the documents so that every row has the same columns.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context as _};
+use clap::{Parser, ValueEnum};
+use configuration::{Configuration, ConfigurationSerializationOptions};
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{Bson, Document},
+    Cursor, Database,
+};
+use mongodb_agent_common::{
+    mongo_query_plan::{MongoConfiguration, ObjectType, Type},
+    query::serialization::bson_to_json,
+};
+use ndc_query_plan::QueryContext as _;
+use serde_json::{Map, Value};
+
+use crate::Context;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Ndjson,
+    Csv,
+}
+
+/// How nested objects are written
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Flatten {
+    /// Each field of a nested object becomes a separate field or column with a dotted name, such
+    /// as `imdb.rating`
+    Dotted,
+    /// Nested objects are written as JSON strings
+    Json,
+}
+
+#[derive(Debug, Clone, Parser)]
+pub struct ExportArgs {
+    /// Name of the collection or native query to export
+    #[arg(value_name = "COLLECTION")]
+    collection: String,
+
+    #[arg(long = "format", value_enum, default_value_t = ExportFormat::Ndjson)]
+    format: ExportFormat,
+
+    /// Defaults to leaving nested objects as they are in NDJSON, and to `json` in CSV
+    #[arg(long = "flatten", value_enum, required = false)]
+    flatten: Option<Flatten>,
+
+    /// File to write to. Defaults to stdout.
+    #[arg(long = "output", value_name = "FILE", required = false)]
+    output: Option<PathBuf>,
+}
+
+pub async fn export(context: &Context, args: &ExportArgs) -> anyhow::Result<()> {
+    let config = MongoConfiguration(Configuration::parse_configuration(&context.path).await?);
+    let collection_name = args.collection.as_str().into();
+    let object_type = config
+        .find_collection_object_type(&collection_name)
+        .with_context(|| format!("no collection or native query named {}", args.collection))?;
+    let mut cursor = documents(
+        &config,
+        &context.connector_state.database(),
+        &args.collection,
+    )
+    .await?;
+
+    let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    });
+    let exporter = Exporter {
+        options: config.serialization_options(),
+        object_type,
+        flatten: args.flatten,
+    };
+    let columns = exporter.columns();
+    if args.format == ExportFormat::Csv {
+        writeln!(writer, "{}", csv_line(columns.iter().cloned()))?;
+    }
+    while let Some(document) = cursor.try_next().await? {
+        let row = exporter.row(document)?;
+        match args.format {
+            ExportFormat::Ndjson => writeln!(writer, "{}", serde_json::to_string(&row)?)?,
+            ExportFormat::Csv => {
+                let values = columns.iter().map(|column| match row.get(column) {
+                    None | Some(Value::Null) => String::new(),
+                    Some(Value::String(s)) => s.clone(),
+                    Some(value) => value.to_string(),
+                });
+                writeln!(writer, "{}", csv_line(values))?
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Native queries are run directly, without their pushdown points. Native queries with arguments
+/// cannot be exported because there is no way to supply argument values.
+async fn documents(
+    config: &MongoConfiguration,
+    database: &Database,
+    name: &str,
+) -> anyhow::Result<Cursor<Document>> {
+    let Some(native_query) = config.native_queries().get(name) else {
+        return Ok(database
+            .collection::<Document>(name)
+            .find(None, None)
+            .await?);
+    };
+    if !native_query.arguments.is_empty() {
+        return Err(anyhow!(
+            "the native query, {name}, takes arguments so it cannot be exported"
+        ));
+    }
+    let pipeline = native_query.without_pushdown_points(native_query.pipeline.clone());
+    let cursor = match &native_query.input_collection {
+        Some(collection) => {
+            database
+                .collection::<Document>(collection.as_str())
+                .aggregate(pipeline, None)
+                .await?
+        }
+        None => database.aggregate(pipeline, None).await?,
+    };
+    Ok(cursor)
+}
+
+struct Exporter {
+    options: ConfigurationSerializationOptions,
+    object_type: ObjectType,
+    flatten: Option<Flatten>,
+}
+
+impl Exporter {
+    /// CSV columns in the order that fields appear in the object type
+    fn columns(&self) -> Vec<String> {
+        match self.flatten {
+            Some(Flatten::Dotted) => dotted_columns("", &self.object_type),
+            _ => self
+                .object_type
+                .fields
+                .keys()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+
+    fn row(&self, document: Document) -> anyhow::Result<Map<String, Value>> {
+        let Value::Object(object) = bson_to_json(
+            self.options,
+            &Type::Object(self.object_type.clone()),
+            Bson::Document(document),
+        )?
+        else {
+            unreachable!("converting a document with an object type produces an object");
+        };
+        let row = match self.flatten {
+            None => object,
+            Some(Flatten::Dotted) => {
+                let mut row = Map::new();
+                flatten_dotted("", &self.object_type, object, &mut row);
+                row
+            }
+            Some(Flatten::Json) => object
+                .into_iter()
+                .map(|(name, value)| match value {
+                    Value::Object(_) => (name, Value::String(value.to_string())),
+                    value => (name, value),
+                })
+                .collect(),
+        };
+        Ok(row)
+    }
+}
+
+fn dotted_columns(prefix: &str, object_type: &ObjectType) -> Vec<String> {
+    object_type
+        .fields
+        .iter()
+        .flat_map(|(name, field_type)| {
+            let column = format!("{prefix}{name}");
+            match nested_object_type(field_type) {
+                Some(nested) => dotted_columns(&format!("{column}."), nested),
+                None => vec![column],
+            }
+        })
+        .collect()
+}
+
+fn nested_object_type(field_type: &Type) -> Option<&ObjectType> {
+    match field_type {
+        Type::Object(object_type) => Some(object_type),
+        Type::Nullable(t) => nested_object_type(t),
+        _ => None,
+    }
+}
+
+/// Only objects with an object type are flattened so that the output matches the columns from
+/// [dotted_columns]. Values of type `ExtendedJSON` are left as they are.
+fn flatten_dotted(
+    prefix: &str,
+    object_type: &ObjectType,
+    object: Map<String, Value>,
+    row: &mut Map<String, Value>,
+) {
+    for (name, value) in object {
+        let column = format!("{prefix}{name}");
+        let nested_type = object_type
+            .fields
+            .get(name.as_str())
+            .and_then(nested_object_type);
+        match (nested_type, value) {
+            (Some(nested_type), Value::Object(nested)) => {
+                flatten_dotted(&format!("{column}."), nested_type, nested, row)
+            }
+            (_, value) => {
+                row.insert(column, value);
+            }
+        }
+    }
+}
+
+/// Quotes values that contain separators, quotes, or line breaks as described in RFC 4180
+fn csv_line(values: impl IntoIterator<Item = String>) -> String {
+    values
+        .into_iter()
+        .map(|value| {
+            if value.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{ConfigurationSerializationOptions, MongoScalarType};
+    use mongodb::bson::doc;
+    use mongodb_agent_common::mongo_query_plan::{ObjectType, Type};
+    use mongodb_support::BsonScalarType as S;
+    use serde_json::json;
+
+    use super::{csv_line, Exporter, Flatten};
+
+    #[test]
+    fn flattens_nested_objects_into_dotted_columns() -> anyhow::Result<()> {
+        let exporter = Exporter {
+            options: ConfigurationSerializationOptions::default(),
+            object_type: ObjectType {
+                name: Some("movies".into()),
+                fields: [
+                    (
+                        "title".into(),
+                        Type::Scalar(MongoScalarType::Bson(S::String)),
+                    ),
+                    (
+                        "imdb".into(),
+                        Type::Nullable(Box::new(Type::Object(ObjectType {
+                            name: Some("movies_imdb".into()),
+                            fields: [(
+                                "rating".into(),
+                                Type::Scalar(MongoScalarType::Bson(S::Double)),
+                            )]
+                            .into(),
+                        }))),
+                    ),
+                ]
+                .into(),
+            },
+            flatten: Some(Flatten::Dotted),
+        };
+        assert_eq!(exporter.columns(), vec!["imdb.rating", "title"]);
+
+        let row = exporter.row(doc! { "title": "Alien, the movie", "imdb": { "rating": 8.5 } })?;
+        assert_eq!(
+            serde_json::Value::Object(row),
+            json!({ "imdb.rating": 8.5, "title": "Alien, the movie" })
+        );
+
+        assert_eq!(
+            csv_line(["8.5".to_owned(), "Alien, the movie".to_owned()]),
+            "8.5,\"Alien, the movie\""
+        );
+        Ok(())
+    }
+}
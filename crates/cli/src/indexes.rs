@@ -0,0 +1,321 @@
+//! Derives the indexes that the configuration implies, compares them with the indexes that exist
+//! in the database, and optionally creates missing indexes. Indexes are implied by:
+//!
+//! - relationships, which look up documents in the target collection by the target fields of the
+//!   column mapping
+//! - default sort orders, which sort every query that does not specify an order
+//! - uniqueness constraints, which are created as unique indexes
+//!
+//! An existing index covers an implied index if the implied keys are a prefix of the existing
+//! keys, with the same directions. A unique implied index is only covered by a unique index on
+//! exactly the same keys. Every collection has a unique index on `_id`, so implied indexes on
+//! `_id` alone are skipped.
+
+use std::collections::BTreeMap;
+
+use clap::Parser;
+use configuration::Configuration;
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{Bson, Document},
+    options::IndexOptions,
+    IndexModel,
+};
+use ndc_models as ndc;
+
+use crate::Context;
+
+#[derive(Debug, Clone, Parser)]
+pub struct IndexesArgs {
+    /// Create missing indexes. Without this flag the command only reports which indexes exist
+    /// and which would be created.
+    #[arg(long = "apply", default_value_t = false)]
+    apply: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ImpliedIndex {
+    keys: Document,
+    unique: bool,
+    /// Descriptions of the configuration that implies the index
+    reasons: Vec<String>,
+}
+
+pub async fn indexes(context: &Context, args: &IndexesArgs) -> anyhow::Result<()> {
+    let configuration = Configuration::parse_configuration(&context.path).await?;
+    let database = context.connector_state.database();
+    for (collection_name, implied_indexes) in implied_indexes(&configuration) {
+        let collection = database.collection::<Document>(collection_name.as_str());
+        let existing: Vec<IndexModel> = collection.list_indexes(None).await?.try_collect().await?;
+        for index in implied_indexes {
+            let description = format!(
+                "{collection_name} {}{} ({})",
+                Bson::Document(index.keys.clone()).into_relaxed_extjson(),
+                if index.unique { " unique" } else { "" },
+                index.reasons.join(", ")
+            );
+            if existing.iter().any(|existing| covers(existing, &index)) {
+                println!("exists: {description}");
+                continue;
+            }
+            if args.apply {
+                let options = IndexOptions::builder().unique(index.unique).build();
+                let model = IndexModel::builder()
+                    .keys(index.keys)
+                    .options(options)
+                    .build();
+                collection.create_index(model, None).await?;
+                println!("created: {description}");
+            } else {
+                println!("missing: {description}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Indexes implied by the configuration for each collection that exists in the database
+fn implied_indexes(
+    configuration: &Configuration,
+) -> BTreeMap<ndc::CollectionName, Vec<ImpliedIndex>> {
+    let is_database_collection = |name: &ndc::CollectionName| {
+        configuration.collections.contains_key(name)
+            && !configuration
+                .native_queries
+                .contains_key(&ndc::FunctionName::from(name.to_string()))
+    };
+
+    let relationship_indexes = configuration
+        .relationships
+        .iter()
+        .map(|(name, relationship)| {
+            let keys: Document = relationship
+                .column_mapping
+                .values()
+                .map(|field| (field.to_string(), Bson::Int32(1)))
+                .collect();
+            (
+                relationship.target_collection.clone(),
+                keys,
+                false,
+                format!("relationship {name}"),
+            )
+        });
+    let sort_indexes = configuration
+        .collection_policies
+        .iter()
+        .filter_map(|(name, policies)| {
+            let keys: Document = policies
+                .default_order_by
+                .as_ref()?
+                .iter()
+                .map(|element| {
+                    let direction = match element.direction {
+                        ndc::OrderDirection::Asc => 1,
+                        ndc::OrderDirection::Desc => -1,
+                    };
+                    (element.column.to_string(), Bson::Int32(direction))
+                })
+                .collect();
+            Some((name.clone(), keys, false, "default order".to_owned()))
+        });
+    let unique_indexes = configuration
+        .collections
+        .iter()
+        .flat_map(|(name, collection)| {
+            collection
+                .uniqueness_constraints
+                .iter()
+                .map(move |(constraint_name, constraint)| {
+                    let keys: Document = constraint
+                        .unique_columns
+                        .iter()
+                        .map(|field| (field.to_string(), Bson::Int32(1)))
+                        .collect();
+                    (
+                        name.clone(),
+                        keys,
+                        true,
+                        format!("uniqueness constraint {constraint_name}"),
+                    )
+                })
+        });
+
+    let mut indexes: BTreeMap<ndc::CollectionName, Vec<ImpliedIndex>> = BTreeMap::new();
+    for (collection_name, keys, unique, reason) in relationship_indexes
+        .chain(sort_indexes)
+        .chain(unique_indexes)
+    {
+        if !is_database_collection(&collection_name) || keys.is_empty() || is_id_index(&keys) {
+            continue;
+        }
+        let collection_indexes = indexes.entry(collection_name).or_default();
+        match collection_indexes
+            .iter_mut()
+            .find(|index| same_keys(&index.keys, &keys))
+        {
+            Some(index) => {
+                index.unique |= unique;
+                index.reasons.push(reason);
+            }
+            None => collection_indexes.push(ImpliedIndex {
+                keys,
+                unique,
+                reasons: vec![reason],
+            }),
+        }
+    }
+    indexes
+}
+
+fn is_id_index(keys: &Document) -> bool {
+    keys.len() == 1 && keys.contains_key("_id")
+}
+
+fn covers(existing: &IndexModel, implied: &ImpliedIndex) -> bool {
+    let existing_unique = existing
+        .options
+        .as_ref()
+        .and_then(|options| options.unique)
+        .unwrap_or(false);
+    if implied.unique {
+        existing_unique && same_keys(&existing.keys, &implied.keys)
+    } else {
+        existing.keys.len() >= implied.keys.len()
+            && existing
+                .keys
+                .iter()
+                .zip(&implied.keys)
+                .all(|(a, b)| same_key(a, b))
+    }
+}
+
+fn same_keys(a: &Document, b: &Document) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_key(a, b))
+}
+
+/// The server may report index directions as integers or doubles of either width
+fn same_key(
+    (a_field, a_direction): (&String, &Bson),
+    (b_field, b_direction): (&String, &Bson),
+) -> bool {
+    let direction = |value: &Bson| match value {
+        Bson::Int32(n) => Some((*n as f64).signum()),
+        Bson::Int64(n) => Some((*n as f64).signum()),
+        Bson::Double(n) => Some(n.signum()),
+        _ => None,
+    };
+    a_field == b_field
+        && direction(a_direction).is_some()
+        && direction(a_direction) == direction(b_direction)
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, DefaultOrderByElement, ObjectField, ObjectType, Relationship, Type},
+        Configuration, Schema,
+    };
+    use mongodb::{bson::doc, options::IndexOptions, IndexModel};
+    use mongodb_support::BsonScalarType;
+
+    use super::{covers, implied_indexes, ImpliedIndex};
+
+    #[test]
+    fn derives_indexes_and_compares_with_existing_indexes() -> anyhow::Result<()> {
+        let field = |name: &str| ObjectField::new(name, Type::Scalar(BsonScalarType::Int));
+        let collection = |name: &str| Collection {
+            r#type: name.into(),
+            description: None,
+            ..Default::default()
+        };
+        let mut movies = collection("movies");
+        movies.default_order_by = Some(vec![
+            DefaultOrderByElement {
+                column: "year".into(),
+                direction: ndc_models::OrderDirection::Desc,
+            },
+            DefaultOrderByElement {
+                column: "title".into(),
+                direction: ndc_models::OrderDirection::Asc,
+            },
+        ]);
+        movies.relationships = [(
+            "comments".into(),
+            Relationship {
+                target_collection: "comments".into(),
+                column_mapping: [("_id".into(), "movie_id".into())].into(),
+                relationship_type: ndc_models::RelationshipType::Array,
+                single_object: false,
+                description: None,
+            },
+        )]
+        .into();
+        let mut comments = collection("comments");
+        comments.relationships = [(
+            "movie".into(),
+            Relationship {
+                target_collection: "movies".into(),
+                column_mapping: [("movie_id".into(), "_id".into())].into(),
+                relationship_type: ndc_models::RelationshipType::Object,
+                single_object: false,
+                description: None,
+            },
+        )]
+        .into();
+        let object_type = |fields: Vec<(String, ObjectField)>| ObjectType {
+            fields: fields
+                .into_iter()
+                .map(|(name, field)| (name.into(), field))
+                .collect(),
+            description: None,
+            extends: Default::default(),
+        };
+        let schema = Schema {
+            collections: [("movies".into(), movies), ("comments".into(), comments)].into(),
+            object_types: [
+                (
+                    "movies".into(),
+                    object_type(vec![field("_id"), field("year"), field("title")]),
+                ),
+                (
+                    "comments".into(),
+                    object_type(vec![field("_id"), field("movie_id")]),
+                ),
+            ]
+            .into(),
+        };
+        let configuration = Configuration::from_schema(schema)?;
+
+        let implied = implied_indexes(&configuration);
+        let movie_id_index = ImpliedIndex {
+            keys: doc! { "movie_id": 1 },
+            unique: false,
+            reasons: vec!["relationship comments".to_owned()],
+        };
+        let default_order_index = ImpliedIndex {
+            keys: doc! { "year": -1, "title": 1 },
+            unique: false,
+            reasons: vec!["default order".to_owned()],
+        };
+        assert_eq!(
+            implied,
+            [
+                ("comments".into(), vec![movie_id_index.clone()]),
+                ("movies".into(), vec![default_order_index.clone()]),
+            ]
+            .into()
+        );
+
+        let existing = IndexModel::builder()
+            .keys(doc! { "movie_id": 1.0, "created": -1 })
+            .build();
+        assert!(covers(&existing, &movie_id_index));
+        let existing = IndexModel::builder()
+            .keys(doc! { "year": 1, "title": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        assert!(!covers(&existing, &default_order_index));
+        Ok(())
+    }
+}
@@ -85,6 +85,7 @@ async fn sample_schema_from_collection(
             schema::Collection {
                 description: None,
                 r#type: collection_name.into(),
+                ..Default::default()
             },
         );
         Ok(Some(Schema {
@@ -122,6 +123,7 @@ fn make_object_type(
         schema::ObjectType {
             description: None,
             fields: WithName::into_map(object_fields),
+            extends: Default::default(),
         },
     );
 
@@ -144,6 +146,11 @@ fn make_object_field(
         schema::ObjectField {
             description: None,
             r#type: field_type,
+            deprecated: false,
+            previous_names: Default::default(),
+            read_transform: None,
+            write_rules: None,
+            object_id_format: None,
         },
     );
     let object_field = if all_schema_nullable && !(is_collection_type && field_name == "_id") {
@@ -259,6 +266,11 @@ mod tests {
                         ObjectField {
                             r#type: Type::Scalar(BsonScalarType::Int),
                             description: None,
+                            deprecated: false,
+                            previous_names: Default::default(),
+                            read_transform: None,
+                            write_rules: None,
+                            object_id_format: None,
                         },
                     ),
                     (
@@ -266,10 +278,16 @@ mod tests {
                         ObjectField {
                             r#type: Type::Scalar(BsonScalarType::String),
                             description: None,
+                            deprecated: false,
+                            previous_names: Default::default(),
+                            read_transform: None,
+                            write_rules: None,
+                            object_id_format: None,
                         },
                     ),
                 ]),
                 description: None,
+                extends: Default::default(),
             },
         )]);
 
@@ -294,6 +312,11 @@ mod tests {
                         ObjectField {
                             r#type: Type::Scalar(BsonScalarType::Int),
                             description: None,
+                            deprecated: false,
+                            previous_names: Default::default(),
+                            read_transform: None,
+                            write_rules: None,
+                            object_id_format: None,
                         },
                     ),
                     (
@@ -301,6 +324,11 @@ mod tests {
                         ObjectField {
                             r#type: Type::Nullable(Box::new(Type::Scalar(BsonScalarType::Int))),
                             description: None,
+                            deprecated: false,
+                            previous_names: Default::default(),
+                            read_transform: None,
+                            write_rules: None,
+                            object_id_format: None,
                         },
                     ),
                     (
@@ -308,10 +336,16 @@ mod tests {
                         ObjectField {
                             r#type: Type::Nullable(Box::new(Type::Scalar(BsonScalarType::String))),
                             description: None,
+                            deprecated: false,
+                            previous_names: Default::default(),
+                            read_transform: None,
+                            write_rules: None,
+                            object_id_format: None,
                         },
                     ),
                 ]),
                 description: None,
+                extends: Default::default(),
             },
         )]);
 
@@ -341,6 +375,11 @@ mod tests {
                             ObjectField {
                                 r#type: Type::Nullable(Box::new(Type::Scalar(BsonScalarType::Int))),
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                         (
@@ -348,6 +387,11 @@ mod tests {
                             ObjectField {
                                 r#type: Type::Scalar(BsonScalarType::String),
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                         (
@@ -357,10 +401,16 @@ mod tests {
                                     BsonScalarType::Double,
                                 ))),
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                     ]),
                     description: None,
+                    extends: Default::default(),
                 },
             ),
             (
@@ -373,9 +423,15 @@ mod tests {
                                 "foo_my_array".to_owned(),
                             ))),
                             description: None,
+                            deprecated: false,
+                            previous_names: Default::default(),
+                            read_transform: None,
+                            write_rules: None,
+                            object_id_format: None,
                         },
                     )]),
                     description: None,
+                    extends: Default::default(),
                 },
             ),
         ]);
@@ -406,6 +462,11 @@ mod tests {
                             ObjectField {
                                 r#type: Type::Nullable(Box::new(Type::Scalar(BsonScalarType::Int))),
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                         (
@@ -413,6 +474,11 @@ mod tests {
                             ObjectField {
                                 r#type: Type::ExtendedJSON,
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                         (
@@ -422,10 +488,16 @@ mod tests {
                                     BsonScalarType::Double,
                                 ))),
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                     ]),
                     description: None,
+                    extends: Default::default(),
                 },
             ),
             (
@@ -438,9 +510,15 @@ mod tests {
                                 "foo_my_array".to_owned(),
                             ))),
                             description: None,
+                            deprecated: false,
+                            previous_names: Default::default(),
+                            read_transform: None,
+                            write_rules: None,
+                            object_id_format: None,
                         },
                     )]),
                     description: None,
+                    extends: Default::default(),
                 },
             ),
         ]);
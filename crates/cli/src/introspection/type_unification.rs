@@ -86,6 +86,11 @@ pub fn make_nullable_field(field: ObjectField) -> ObjectField {
         schema::ObjectField {
             r#type: field.value.r#type.make_nullable(),
             description: field.value.description,
+            deprecated: field.value.deprecated,
+            previous_names: field.value.previous_names,
+            read_transform: field.value.read_transform,
+            write_rules: field.value.write_rules,
+            object_id_format: field.value.object_id_format,
         },
     )
 }
@@ -127,6 +132,7 @@ fn unify_object_type(object_type_a: ObjectType, object_type_b: ObjectType) -> Ob
                 .value
                 .description
                 .or(object_type_b.value.description),
+            extends: Default::default(),
         },
     )
 }
@@ -142,6 +148,26 @@ fn unify_object_field(object_field_a: ObjectField, object_field_b: ObjectField)
                 .value
                 .description
                 .or(object_field_b.value.description),
+            deprecated: object_field_a.value.deprecated || object_field_b.value.deprecated,
+            previous_names: object_field_a
+                .value
+                .previous_names
+                .into_iter()
+                .chain(object_field_b.value.previous_names)
+                .unique()
+                .collect(),
+            read_transform: object_field_a
+                .value
+                .read_transform
+                .or(object_field_b.value.read_transform),
+            write_rules: object_field_a
+                .value
+                .write_rules
+                .or(object_field_b.value.write_rules),
+            object_id_format: object_field_a
+                .value
+                .object_id_format
+                .or(object_field_b.value.object_id_format),
         },
     )
 }
@@ -304,11 +330,13 @@ mod tests {
             let name = "foo";
             let left_object = WithName::named(name.into(), schema::ObjectType {
                 fields: left_fields.into_iter().map(|(k, v)| (k.into(), schema::ObjectField{r#type: v, description: None})).collect(),
-                description: None
+                description: None,
+                extends: Default::default(),
             });
             let right_object = WithName::named(name.into(), schema::ObjectType {
                 fields: right_fields.into_iter().map(|(k, v)| (k.into(), schema::ObjectField{r#type: v, description: None})).collect(),
-                description: None
+                description: None,
+                extends: Default::default(),
             });
             let result = unify_object_type(left_object, right_object);
 
@@ -75,6 +75,11 @@ fn make_collection(
             schema::ObjectField {
                 description: Some("primary key _id".to_string()),
                 r#type: Type::Scalar(BsonScalarType::ObjectId),
+                deprecated: false,
+                previous_names: Default::default(),
+                read_transform: None,
+                write_rules: None,
+                object_id_format: None,
             },
         );
         let (object_type_defs, mut object_fields): (Vec<Vec<ObjectType>>, Vec<ObjectField>) =
@@ -95,6 +100,7 @@ fn make_collection(
         schema::ObjectType {
             description: Some(format!("Object type for collection {collection_name}")),
             fields: WithName::into_map(object_fields),
+            extends: Default::default(),
         },
     );
 
@@ -105,6 +111,7 @@ fn make_collection(
         schema::Collection {
             description: validator_schema.description.clone(),
             r#type: collection_name.into(),
+            ..Default::default()
         },
     );
 
@@ -126,6 +133,11 @@ fn make_object_field(
         schema::ObjectField {
             description,
             r#type: maybe_nullable(field_type, !required_labels.contains(prop_name)),
+            deprecated: false,
+            previous_names: Default::default(),
+            read_transform: None,
+            write_rules: None,
+            object_id_format: None,
         },
     );
 
@@ -164,6 +176,7 @@ fn make_field_type(object_type_name: &str, prop_schema: &Property) -> (Vec<Objec
                 schema::ObjectType {
                     description: Some("generated from MongoDB validation schema".to_string()),
                     fields: WithName::into_map(otd_fields),
+                    extends: Default::default(),
                 },
             );
 
@@ -1,15 +1,33 @@
 //! The interpretation of the commands that the CLI can handle.
 
+mod check_references;
+mod compare_configuration;
+mod effective_configuration;
+mod export;
+mod indexes;
 mod introspection;
 mod logging;
+mod migrate;
+mod seed;
+mod validators;
 
 use std::path::PathBuf;
 
+use check_references::CheckReferencesArgs;
 use clap::{Parser, Subcommand};
+use compare_configuration::CompareConfigurationArgs;
+use itertools::Itertools as _;
 
+use configuration::Configuration;
+use effective_configuration::EffectiveConfiguration;
+use export::ExportArgs;
+use indexes::IndexesArgs;
+use migrate::MigrateArgs;
 // Exported for use in tests
 pub use introspection::type_from_bson;
-use mongodb_agent_common::state::ConnectorState;
+use mongodb_agent_common::{server_info::get_server_version, state::ConnectorState};
+use seed::SeedArgs;
+use validators::GenerateValidatorsArgs;
 
 #[derive(Debug, Clone, Parser)]
 pub struct UpdateArgs {
@@ -23,11 +41,73 @@ pub struct UpdateArgs {
     all_schema_nullable: Option<bool>,
 }
 
+#[derive(Debug, Clone, Parser)]
+pub struct ValidateArgs {
+    /// Also reject unknown keys in configuration files, object types that are not used by any
+    /// collection, native query, or native mutation, and native query or native mutation
+    /// arguments that are never used
+    #[arg(long = "strict", default_value_t = false)]
+    strict: bool,
+}
+
 /// The command invoked by the user.
 #[derive(Debug, Clone, Subcommand)]
 pub enum Command {
     /// Update the configuration by introspecting the database, using the configuration options.
     Update(UpdateArgs),
+
+    /// Print a summary of the configuration as the connector sees it after parsing and merging
+    /// configuration files, along with the version of the connected MongoDB server. Native query
+    /// pipelines and native mutation commands are redacted.
+    PrintConfiguration,
+
+    /// Check that the configuration can be loaded, and report problems that would prevent the
+    /// connector from starting.
+    Validate(ValidateArgs),
+
+    /// Rewrite schema files so that object types with conflicting definitions in more than one
+    /// collection are prefixed with their collection names. This makes the renaming that the
+    /// `namespaceObjectTypes` option applies at startup permanent.
+    NamespaceObjectTypes,
+
+    /// Load JSON or newline-delimited JSON fixture files into the database. Each file is named
+    /// after the collection it populates, and documents are parsed according to the collection's
+    /// type in configuration.
+    Seed(SeedArgs),
+
+    /// Write the documents of a collection, or the results of a native query that takes no
+    /// arguments, as NDJSON or CSV. Values are converted according to the configured types.
+    Export(ExportArgs),
+
+    /// Scan the source collections of declared object relationships for orphaned references,
+    /// documents whose join fields have no matching document in the target collection. Reports
+    /// counts and example ids, and fails if any orphans are found.
+    CheckReferences(CheckReferencesArgs),
+
+    /// Compare the schema of another configuration directory with this one, and classify each
+    /// difference as breaking or non-breaking. Fails if there are breaking changes, which makes
+    /// it suitable for gating schema changes in CI.
+    CompareConfiguration(CompareConfigurationArgs),
+
+    /// Rewrite configuration files that were written in an older format version in the current
+    /// format. The connector upgrades older files when it reads them, so this is only needed to
+    /// keep files up to date. Comments in JSON5 and YAML files are not preserved.
+    UpgradeConfiguration,
+
+    /// Generate MongoDB `$jsonSchema` validators from the configured collection types, and print
+    /// them or apply them to collections in the database with `collMod`. This lets the database
+    /// enforce the types that the configuration declares.
+    GenerateValidators(GenerateValidatorsArgs),
+
+    /// Report indexes implied by relationships, default sort orders, and uniqueness constraints,
+    /// and whether each already exists in the database. With `--apply`, create the missing
+    /// indexes.
+    Indexes(IndexesArgs),
+
+    /// Apply pending data migrations from the `migrations/` subdirectory in order of their
+    /// names. Each migration is applied once, and is recorded in a migrations collection in the
+    /// database.
+    Migrate(MigrateArgs),
 }
 
 pub struct Context {
@@ -39,6 +119,21 @@ pub struct Context {
 pub async fn run(command: Command, context: &Context) -> anyhow::Result<()> {
     match command {
         Command::Update(args) => update(context, &args).await?,
+        Command::PrintConfiguration => print_configuration(context).await?,
+        Command::Validate(args) => validate(context, &args).await?,
+        Command::NamespaceObjectTypes => namespace_object_types(context).await?,
+        Command::Seed(args) => seed::seed(context, &args).await?,
+        Command::Export(args) => export::export(context, &args).await?,
+        Command::CheckReferences(args) => {
+            check_references::check_references(context, &args).await?
+        }
+        Command::CompareConfiguration(args) => {
+            compare_configuration::compare_configuration(context, &args).await?
+        }
+        Command::UpgradeConfiguration => upgrade_configuration(context).await?,
+        Command::GenerateValidators(args) => {
+            validators::generate_validators(context, &args).await?
+        }
     };
     Ok(())
 }
@@ -46,7 +141,7 @@ pub async fn run(command: Command, context: &Context) -> anyhow::Result<()> {
 /// Update the configuration in the current directory by introspecting the database.
 async fn update(context: &Context, args: &UpdateArgs) -> anyhow::Result<()> {
     let configuration_options =
-        configuration::parse_configuration_options_file(&context.path).await;
+        configuration::parse_configuration_options_file(&context.path).await?;
     // Prefer arguments passed to cli, and fallback to the configuration file
     let sample_size = match args.sample_size {
         Some(size) => size,
@@ -87,3 +182,68 @@ async fn update(context: &Context, args: &UpdateArgs) -> anyhow::Result<()> {
     .await?;
     configuration::write_schema_directory(&context.path, schemas_from_sampling).await
 }
+
+/// Print the effective configuration for the configuration directory in the current context.
+async fn print_configuration(context: &Context) -> anyhow::Result<()> {
+    let configuration = Configuration::parse_configuration(&context.path).await?;
+    let server_version = match get_server_version(&context.connector_state).await {
+        Ok(version) => Some(version),
+        Err(err) => {
+            log_warning!("could not determine MongoDB server version: {err}");
+            None
+        }
+    };
+    let effective_configuration = EffectiveConfiguration::new(&configuration, server_version);
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&effective_configuration)?
+    );
+    Ok(())
+}
+
+/// Validate the configuration in the current context, optionally applying strict checks.
+async fn validate(context: &Context, args: &ValidateArgs) -> anyhow::Result<()> {
+    let configuration = Configuration::parse_configuration(&context.path).await?;
+    if args.strict {
+        let problems = configuration::strict_validation(&context.path, &configuration).await?;
+        for problem in &problems {
+            println!("{problem}");
+        }
+        anyhow::ensure!(
+            problems.is_empty(),
+            "found {} problems in strict validation",
+            problems.len()
+        );
+    }
+    println!("configuration is valid");
+    Ok(())
+}
+
+/// Rename conflicting object types in the schema files in the current context.
+async fn namespace_object_types(context: &Context) -> anyhow::Result<()> {
+    let schemas = configuration::read_schema_directory(&context.path).await?;
+    let conflicts = configuration::conflicting_object_type_names(&schemas);
+    if conflicts.is_empty() {
+        println!("no conflicting object type names found");
+        return Ok(());
+    }
+    let schemas = configuration::namespace_object_types(schemas);
+    configuration::write_schema_directory(&context.path, schemas).await?;
+    println!(
+        "renamed conflicting object types: {}",
+        conflicts.iter().join(", ")
+    );
+    Ok(())
+}
+
+/// Rewrite configuration files in the current context in the current format version.
+async fn upgrade_configuration(context: &Context) -> anyhow::Result<()> {
+    let upgraded = configuration::upgrade_configuration_directory(&context.path).await?;
+    if upgraded.is_empty() {
+        println!("configuration files are up to date");
+    }
+    for path in upgraded {
+        println!("upgraded {}", path.display());
+    }
+    Ok(())
+}
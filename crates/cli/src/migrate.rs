@@ -0,0 +1,190 @@
+//! Applies data migrations from the `migrations/` subdirectory of the configuration directory.
+//! Each migration runs an aggregation pipeline that writes with `$merge` or `$out`, or runs
+//! a command such as `update`. Migrations are applied in order of their names, and each is
+//! applied at most once.
+//!
+//! Applied migrations are recorded in a collection, with the migration name as the document id.
+//! A record is inserted before a migration runs so that two concurrent runs cannot both apply it.
+//! The record is marked as applied when the migration succeeds, and removed if it fails so that
+//! the migration can be retried. If the process stops while a migration is running the record is
+//! left in the running state, and must be removed by hand after checking the state of the data.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context as _};
+use clap::Parser;
+use configuration::serialized::{Migration, MigrationOperation};
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    Database,
+};
+
+use crate::Context;
+
+const STATUS_RUNNING: &str = "running";
+const STATUS_APPLIED: &str = "applied";
+
+#[derive(Debug, Clone, Parser)]
+pub struct MigrateArgs {
+    /// Collection that records which migrations have been applied
+    #[arg(
+        long = "migrations-collection",
+        value_name = "COLLECTION",
+        default_value = "_ndc_migrations"
+    )]
+    migrations_collection: String,
+
+    /// List pending migrations without applying them
+    #[arg(long = "dry-run", default_value_t = false)]
+    dry_run: bool,
+}
+
+pub async fn migrate(context: &Context, args: &MigrateArgs) -> anyhow::Result<()> {
+    let migrations = configuration::read_migrations_directory(&context.path).await?;
+    let database = context.connector_state.database();
+    let records_collection = database.collection::<Document>(&args.migrations_collection);
+    let records: Vec<Document> = records_collection
+        .find(None, None)
+        .await?
+        .try_collect()
+        .await?;
+    let pending = pending_migrations(&migrations, &records)?;
+
+    if pending.is_empty() {
+        println!("no pending migrations");
+    }
+    for (name, migration) in pending {
+        if args.dry_run {
+            println!("pending: {name}");
+            continue;
+        }
+        records_collection
+            .insert_one(
+                doc! { "_id": name, "status": STATUS_RUNNING, "startedAt": DateTime::now() },
+                None,
+            )
+            .await
+            .with_context(|| {
+                format!("recording the migration, {name}; another process may be applying it")
+            })?;
+        if let Err(err) = run_migration(&database, migration).await {
+            records_collection
+                .delete_one(doc! { "_id": name }, None)
+                .await?;
+            return Err(err.context(format!("applying the migration, {name}")));
+        }
+        records_collection
+            .update_one(
+                doc! { "_id": name },
+                doc! { "$set": { "status": STATUS_APPLIED, "appliedAt": DateTime::now() } },
+                None,
+            )
+            .await?;
+        println!("applied: {name}");
+    }
+    Ok(())
+}
+
+/// Migrations that have no record, in order. Fails if a migration has a record that is still in
+/// the running state.
+fn pending_migrations<'a>(
+    migrations: &'a BTreeMap<String, Migration>,
+    records: &[Document],
+) -> anyhow::Result<Vec<(&'a str, &'a Migration)>> {
+    let statuses: BTreeMap<&str, &str> = records
+        .iter()
+        .map(|record| {
+            Ok((
+                record.get_str("_id")?,
+                record.get_str("status").unwrap_or(STATUS_APPLIED),
+            ))
+        })
+        .collect::<Result<_, mongodb::bson::document::ValueAccessError>>()
+        .map_err(|err| anyhow!("unexpected document in the migrations collection: {err}"))?;
+    let mut pending = vec![];
+    for (name, migration) in migrations {
+        match statuses.get(name.as_str()) {
+            None => pending.push((name.as_str(), migration)),
+            Some(&STATUS_RUNNING) => bail!("the migration, {name}, was started but did not finish; check the data, then remove its record from the migrations collection to retry it"),
+            Some(_) => (),
+        }
+    }
+    Ok(pending)
+}
+
+async fn run_migration(database: &Database, migration: &Migration) -> anyhow::Result<()> {
+    match &migration.operation {
+        MigrationOperation::Pipeline {
+            collection,
+            pipeline,
+        } => {
+            // The pipeline writes its output, so the cursor is only drained
+            let _: Vec<Document> = database
+                .collection::<Document>(collection.as_str())
+                .aggregate(pipeline.clone(), None)
+                .await?
+                .try_collect()
+                .await?;
+        }
+        MigrationOperation::Command { command } => {
+            let result = database.run_command(command.clone(), None).await?;
+            // Write commands report errors for individual documents without failing
+            if let Ok(write_errors) = result.get_array("writeErrors") {
+                if !write_errors.is_empty() {
+                    bail!("the command reported write errors: {write_errors:?}");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use configuration::serialized::Migration;
+    use mongodb::bson::doc;
+    use serde_json::json;
+
+    use super::pending_migrations;
+
+    #[test]
+    fn applies_migrations_in_order_and_only_once() -> anyhow::Result<()> {
+        let migrations: BTreeMap<String, Migration> = [
+            (
+                "0002_backfill_slugs",
+                json!({
+                    "collection": "movies",
+                    "pipeline": [
+                        { "$set": { "slug": { "$toLower": "$title" } } },
+                        { "$merge": { "into": "movies" } },
+                    ],
+                }),
+            ),
+            (
+                "0001_rename_year",
+                json!({ "command": { "update": "movies", "updates": [] } }),
+            ),
+            (
+                "0003_drop_legacy",
+                json!({ "command": { "drop": "legacy" } }),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, value)| Ok((name.to_owned(), serde_json::from_value(value)?)))
+        .collect::<anyhow::Result<_>>()?;
+
+        let records = [doc! { "_id": "0001_rename_year", "status": "applied" }];
+        let pending: Vec<&str> = pending_migrations(&migrations, &records)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(pending, vec!["0002_backfill_slugs", "0003_drop_legacy"]);
+
+        let records = [doc! { "_id": "0002_backfill_slugs", "status": "running" }];
+        assert!(pending_migrations(&migrations, &records).is_err());
+        Ok(())
+    }
+}
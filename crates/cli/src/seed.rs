@@ -0,0 +1,149 @@
+//! Loads fixture data into the database. Each file in the fixtures directory holds documents for
+//! the collection with the same name as the file, minus its `.json` or `.ndjson` extension.
+//! A file may contain a JSON array of documents, or one document per line. Documents are
+//! converted to BSON according to the collection's object type in configuration, the same way
+//! that query arguments are, so fixtures use the same JSON representation that the connector uses
+//! in responses. Fields with the `ExtendedJSON` type are read as Extended JSON.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context as _};
+use clap::Parser;
+use configuration::Configuration;
+use mongodb::bson::{Bson, Document};
+use mongodb_agent_common::{
+    mongo_query_plan::{MongoConfiguration, Type},
+    query::serialization::json_to_bson,
+};
+use ndc_query_plan::QueryContext as _;
+use serde_json::Value;
+
+use crate::Context;
+
+#[derive(Debug, Clone, Parser)]
+pub struct SeedArgs {
+    /// Directory containing fixture files
+    #[arg(value_name = "DIRECTORY")]
+    fixtures: PathBuf,
+
+    /// Drop each collection before loading its fixtures
+    #[arg(long = "drop", required = false)]
+    drop: bool,
+}
+
+pub async fn seed(context: &Context, args: &SeedArgs) -> anyhow::Result<()> {
+    let config = MongoConfiguration(Configuration::parse_configuration(&context.path).await?);
+    let database = context.connector_state.database();
+
+    for (collection_name, path) in fixture_files(&args.fixtures).await? {
+        let object_type = config
+            .find_collection_object_type(&collection_name.as_str().into())
+            .with_context(|| format!("no collection in configuration for fixture {path:?}"))?;
+        let input = tokio::fs::read_to_string(&path).await?;
+        let documents = parse_fixture(&Type::Object(object_type), &input)
+            .with_context(|| format!("error reading fixture {path:?}"))?;
+
+        let collection = database.collection::<Document>(&collection_name);
+        if args.drop {
+            collection.drop(None).await?;
+        }
+        if !documents.is_empty() {
+            collection.insert_many(&documents, None).await?;
+        }
+        println!(
+            "loaded {} documents into {collection_name}",
+            documents.len()
+        );
+    }
+    Ok(())
+}
+
+/// Lists fixture files with the collection name for each, sorted by collection name
+async fn fixture_files(dir: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("error reading fixtures directory {dir:?}"))?;
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let collection_name = file_name
+            .strip_suffix(".ndjson")
+            .or_else(|| file_name.strip_suffix(".json"));
+        if let Some(collection_name) = collection_name {
+            files.push((collection_name.to_owned(), path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn parse_fixture(document_type: &Type, input: &str) -> anyhow::Result<Vec<Document>> {
+    let values: Vec<Value> = if input.trim_start().starts_with('[') {
+        serde_json::from_str(input)?
+    } else {
+        serde_json::Deserializer::from_str(input)
+            .into_iter()
+            .collect::<Result<_, _>>()?
+    };
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| match json_to_bson(document_type, value) {
+            Ok(Bson::Document(document)) => Ok(document),
+            Ok(_) => Err(anyhow!("fixture {index} is not an object")),
+            Err(err) => Err(anyhow!("fixture {index}: {err}")),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::MongoScalarType;
+    use mongodb::bson::{doc, oid::ObjectId, DateTime};
+    use mongodb_agent_common::mongo_query_plan::{ObjectType, Type};
+    use mongodb_support::BsonScalarType as S;
+
+    use super::parse_fixture;
+
+    #[test]
+    fn parses_documents_according_to_configured_types() -> anyhow::Result<()> {
+        let document_type = Type::Object(ObjectType {
+            name: Some("movies".into()),
+            fields: [
+                (
+                    "_id".into(),
+                    Type::Scalar(MongoScalarType::Bson(S::ObjectId)),
+                ),
+                (
+                    "released".into(),
+                    Type::Scalar(MongoScalarType::Bson(S::Date)),
+                ),
+                ("extra".into(), Type::Scalar(MongoScalarType::ExtendedJSON)),
+            ]
+            .into(),
+        });
+        let input = r#"
+            { "_id": "573a1390f29313caabcd42e8", "released": "1903-12-01T00:00:00Z", "extra": { "$numberLong": "1" } }
+            { "_id": "573a1390f29313caabcd4323", "released": "1909-01-01T00:00:00Z", "extra": null }
+        "#;
+        assert_eq!(
+            parse_fixture(&document_type, input)?,
+            vec![
+                doc! {
+                    "_id": ObjectId::parse_str("573a1390f29313caabcd42e8")?,
+                    "released": DateTime::parse_rfc3339_str("1903-12-01T00:00:00Z")?,
+                    "extra": 1i64,
+                },
+                doc! {
+                    "_id": ObjectId::parse_str("573a1390f29313caabcd4323")?,
+                    "released": DateTime::parse_rfc3339_str("1909-01-01T00:00:00Z")?,
+                    "extra": null,
+                },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! Generates MongoDB `$jsonSchema` validators from the configured collection types, and optionally
+//! applies them with `collMod` so that the database rejects writes that do not match the
+//! configuration. Non-nullable fields are required, and nullable fields also accept null.
+//! Fields that are not in the configured type are allowed so that applying a validator does not
+//! reject documents with fields that the connector does not expose.
+
+use anyhow::Context as _;
+use clap::{Parser, ValueEnum};
+use configuration::{Configuration, MongoScalarType};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb_agent_common::mongo_query_plan::{MongoConfiguration, ObjectType, Type};
+use ndc_models as ndc;
+use ndc_query_plan::QueryContext as _;
+
+use crate::Context;
+
+/// See https://www.mongodb.com/docs/manual/core/schema-validation/specify-validation-level/
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ValidationLevel {
+    /// Validate all inserts and updates
+    Strict,
+    /// Do not validate updates to existing documents that do not already match the validator
+    Moderate,
+}
+
+/// See https://www.mongodb.com/docs/manual/core/schema-validation/handle-invalid-documents/
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ValidationAction {
+    /// Reject invalid writes
+    Error,
+    /// Allow invalid writes, and log a warning
+    Warn,
+}
+
+#[derive(Debug, Clone, Parser)]
+pub struct GenerateValidatorsArgs {
+    /// Name of a collection to generate a validator for. Defaults to every collection in schema
+    /// files.
+    #[arg(value_name = "COLLECTION", required = false)]
+    collection: Option<String>,
+
+    /// Set validators on collections in the database instead of printing them
+    #[arg(long = "apply", default_value_t = false)]
+    apply: bool,
+
+    #[arg(long = "validation-level", value_enum, default_value_t = ValidationLevel::Moderate)]
+    validation_level: ValidationLevel,
+
+    #[arg(long = "validation-action", value_enum, default_value_t = ValidationAction::Error)]
+    validation_action: ValidationAction,
+}
+
+pub async fn generate_validators(
+    context: &Context,
+    args: &GenerateValidatorsArgs,
+) -> anyhow::Result<()> {
+    let config = MongoConfiguration(Configuration::parse_configuration(&context.path).await?);
+    let collection_names: Vec<ndc::CollectionName> = match &args.collection {
+        Some(name) => vec![name.as_str().into()],
+        None => config
+            .0
+            .collections
+            .keys()
+            .filter(|name| {
+                // Collections that are backed by native queries do not exist in the database
+                !config
+                    .native_queries()
+                    .contains_key(&ndc::FunctionName::from(name.to_string()))
+            })
+            .cloned()
+            .collect(),
+    };
+
+    let mut validators = Document::new();
+    for name in collection_names {
+        let object_type = config
+            .find_collection_object_type(&name)
+            .with_context(|| format!("no collection named {name}"))?;
+        validators.insert(name.to_string(), object_validator(&object_type));
+    }
+
+    if !args.apply {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&Bson::Document(validators).into_relaxed_extjson())?
+        );
+        return Ok(());
+    }
+
+    let database = context.connector_state.database();
+    for (name, validator) in validators {
+        database
+            .run_command(
+                doc! {
+                    "collMod": &name,
+                    "validator": { "$jsonSchema": validator },
+                    "validationLevel": match args.validation_level {
+                        ValidationLevel::Strict => "strict",
+                        ValidationLevel::Moderate => "moderate",
+                    },
+                    "validationAction": match args.validation_action {
+                        ValidationAction::Error => "error",
+                        ValidationAction::Warn => "warn",
+                    },
+                },
+                None,
+            )
+            .await
+            .with_context(|| format!("setting the validator for the collection, {name}"))?;
+        println!("set the validator for {name}");
+    }
+    Ok(())
+}
+
+fn object_validator(object_type: &ObjectType) -> Document {
+    let required: Vec<&str> = object_type
+        .fields
+        .iter()
+        .filter(|(_, field_type)| {
+            !matches!(
+                field_type,
+                Type::Nullable(_) | Type::Scalar(MongoScalarType::ExtendedJSON)
+            )
+        })
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let properties: Document = object_type
+        .fields
+        .iter()
+        .map(|(name, field_type)| (name.to_string(), type_validator(field_type).into()))
+        .collect();
+    let mut validator = doc! { "bsonType": "object" };
+    if !required.is_empty() {
+        validator.insert("required", required);
+    }
+    validator.insert("properties", properties);
+    validator
+}
+
+/// Extended JSON fields accept any value, so their validator is empty. They are also not
+/// required.
+fn type_validator(t: &Type) -> Document {
+    match t {
+        Type::Scalar(MongoScalarType::ExtendedJSON) => Document::new(),
+        Type::Scalar(MongoScalarType::Bson(scalar_type)) => {
+            doc! { "bsonType": scalar_type.bson_name() }
+        }
+        Type::Object(object_type) => object_validator(object_type),
+        Type::ArrayOf(element_type) => {
+            doc! { "bsonType": "array", "items": type_validator(element_type) }
+        }
+        Type::Nullable(underlying_type) => {
+            let mut validator = type_validator(underlying_type);
+            if let Some(Bson::String(bson_type)) = validator.get("bsonType").cloned() {
+                validator.insert("bsonType", vec![bson_type, "null".into()]);
+            }
+            validator
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::MongoScalarType;
+    use mongodb::bson::doc;
+    use mongodb_agent_common::mongo_query_plan::{ObjectType, Type};
+    use mongodb_support::BsonScalarType as S;
+
+    use super::object_validator;
+
+    #[test]
+    fn generates_validator_from_object_type() {
+        let scalar = |t| Type::Scalar(MongoScalarType::Bson(t));
+        let nullable = |t| Type::Nullable(Box::new(t));
+        let object_type = ObjectType {
+            name: Some("movies".into()),
+            fields: [
+                ("_id".into(), scalar(S::ObjectId)),
+                ("title".into(), scalar(S::String)),
+                ("year".into(), nullable(scalar(S::Int))),
+                (
+                    "imdb".into(),
+                    nullable(Type::Object(ObjectType {
+                        name: Some("movies_imdb".into()),
+                        fields: [("rating".into(), scalar(S::Double))].into(),
+                    })),
+                ),
+                ("tags".into(), Type::ArrayOf(Box::new(scalar(S::String)))),
+                ("extra".into(), Type::Scalar(MongoScalarType::ExtendedJSON)),
+            ]
+            .into(),
+        };
+        assert_eq!(
+            object_validator(&object_type),
+            doc! {
+                "bsonType": "object",
+                "required": ["_id", "tags", "title"],
+                "properties": {
+                    "_id": { "bsonType": "objectId" },
+                    "extra": {},
+                    "imdb": {
+                        "bsonType": ["object", "null"],
+                        "required": ["rating"],
+                        "properties": { "rating": { "bsonType": "double" } },
+                    },
+                    "tags": { "bsonType": "array", "items": { "bsonType": "string" } },
+                    "title": { "bsonType": "string" },
+                    "year": { "bsonType": ["int", "null"] },
+                },
+            }
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Built-in arguments that are accepted by every regular (non-native-query) collection. These
+//! arguments do not change the shape of query results - they are passed through to MongoDB as
+//! options that affect how a query is executed. Built-in arguments only apply to the root
+//! collection of a query request; they are rejected when given for the target of a relationship.
+
+use std::collections::BTreeMap;
+
+use mongodb_support::BsonScalarType;
+use ndc_models as ndc;
+use ndc_query_plan::{self as plan, inline_object_types, QueryPlanError};
+
+use crate::{
+    schema::{ObjectField, Type},
+    MongoScalarType,
+};
+
+/// Caps the number of documents returned by a query. If the query request also specifies
+/// a limit the smaller of the two limits is used.
+pub const LIMIT: &str = "limit";
+
+/// Read concern level to use for the query, e.g. "local" or "majority".
+///
+/// See https://www.mongodb.com/docs/manual/reference/read-concern/
+pub const READ_CONCERN: &str = "readConcern";
+
+/// Index hint for the query. May be given as the name of an index, or as an index specification
+/// document in Extended JSON.
+///
+/// See https://www.mongodb.com/docs/manual/reference/command/aggregate/#std-label-aggregate-cmd-hint
+pub const HINT: &str = "hint";
+
+/// If true, documents that have been soft-deleted are included in query results. This argument is
+/// only accepted by collections that declare a soft delete field.
+pub const INCLUDE_DELETED: &str = "includeDeleted";
+
+/// Argument definitions in the form that they appear in collection info in the schema response.
+/// All built-in arguments are optional.
+pub fn builtin_collection_arguments() -> BTreeMap<ndc::ArgumentName, ObjectField> {
+    to_object_fields([
+        (
+            LIMIT,
+            Type::Nullable(Box::new(Type::Scalar(BsonScalarType::Int))),
+            "Maximum number of documents to return; the smaller of this and the query limit is used",
+        ),
+        (
+            READ_CONCERN,
+            Type::Nullable(Box::new(Type::Scalar(BsonScalarType::String))),
+            "Read concern level for the query: one of local, available, majority, linearizable, or snapshot",
+        ),
+        (
+            HINT,
+            Type::ExtendedJSON,
+            "Index to use for the query, given either as an index name or as an index specification document",
+        ),
+    ])
+}
+
+/// Additional arguments for collections that declare a soft delete field
+pub fn soft_delete_collection_arguments() -> BTreeMap<ndc::ArgumentName, ObjectField> {
+    to_object_fields([(
+        INCLUDE_DELETED,
+        Type::Nullable(Box::new(Type::Scalar(BsonScalarType::Bool))),
+        "Include documents that have been soft-deleted",
+    )])
+}
+
+fn to_object_fields<const N: usize>(
+    arguments: [(&str, Type, &str); N],
+) -> BTreeMap<ndc::ArgumentName, ObjectField> {
+    arguments
+        .into_iter()
+        .map(|(name, r#type, description)| {
+            (
+                name.into(),
+                ObjectField {
+                    r#type,
+                    description: Some(description.to_owned()),
+                    deprecated: false,
+                    previous_names: Default::default(),
+                    read_transform: None,
+                    write_rules: None,
+                    object_id_format: None,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Argument types in the form used for resolving argument values in query requests. This includes
+/// arguments that only some collections accept.
+pub fn builtin_collection_parameters(
+) -> Result<BTreeMap<ndc::ArgumentName, plan::Type<MongoScalarType>>, QueryPlanError> {
+    builtin_collection_arguments()
+        .into_iter()
+        .chain(soft_delete_collection_arguments())
+        .map(|(name, field)| {
+            let t = inline_object_types(
+                &Default::default(),
+                &field.r#type.into(),
+                MongoScalarType::lookup_scalar_type,
+            )?;
+            Ok((name, t))
+        })
+        .collect()
+}
@@ -0,0 +1,87 @@
+//! Per-collection behavior that is declared in schema files alongside the collection type, such as
+//! a soft delete field, default ordering, maximum limit, gap filling, window fields, previous
+//! names of renamed fields, or read transformations. These
+//! settings are kept in [crate::Configuration] so that query planning can consult them by
+//! collection name.
+
+use std::collections::BTreeMap;
+
+use ndc_models as ndc;
+
+use crate::schema;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CollectionPolicies {
+    /// See [schema::Collection::soft_delete_field]
+    pub soft_delete_field: Option<ndc::FieldName>,
+
+    /// See [schema::Collection::default_order_by]
+    pub default_order_by: Option<Vec<schema::DefaultOrderByElement>>,
+
+    /// See [schema::Collection::max_limit]
+    pub max_limit: Option<u32>,
+
+    /// See [schema::Collection::gap_fill]
+    pub gap_fill: Option<schema::GapFill>,
+
+    /// See [schema::Collection::window_fields]
+    pub window_fields: BTreeMap<ndc::FieldName, schema::WindowField>,
+
+    /// Previous names of fields of the collection type. See [schema::ObjectField::previous_names]
+    pub field_aliases: BTreeMap<ndc::FieldName, Vec<ndc::FieldName>>,
+
+    /// Read transformations of fields of the collection type. See
+    /// [schema::ObjectField::read_transform]
+    pub read_transforms: BTreeMap<ndc::FieldName, schema::ReadTransform>,
+}
+
+impl CollectionPolicies {
+    pub fn from_schema_collection(
+        collection: &schema::Collection,
+        object_types: &BTreeMap<ndc::ObjectTypeName, schema::ObjectType>,
+    ) -> Self {
+        let fields = || {
+            object_types
+                .get(&collection.r#type)
+                .into_iter()
+                .flat_map(|object_type| &object_type.fields)
+        };
+        let field_aliases = fields()
+            .filter(|(_, field)| !field.previous_names.is_empty())
+            .map(|(name, field)| (name.clone(), field.previous_names.clone()))
+            .collect();
+        let read_transforms = fields()
+            .filter_map(|(name, field)| Some((name.clone(), field.read_transform.clone()?)))
+            .collect();
+        CollectionPolicies {
+            soft_delete_field: collection.soft_delete_field.clone(),
+            default_order_by: collection.default_order_by.clone(),
+            max_limit: collection.max_limit,
+            gap_fill: collection.gap_fill.clone(),
+            window_fields: collection.window_fields.clone(),
+            field_aliases,
+            read_transforms,
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        self == &Default::default()
+    }
+}
+
+/// Policies for each collection in the schema that declares any
+pub fn collection_policies(
+    collections: &BTreeMap<ndc::CollectionName, schema::Collection>,
+    object_types: &BTreeMap<ndc::ObjectTypeName, schema::ObjectType>,
+) -> BTreeMap<ndc::CollectionName, CollectionPolicies> {
+    collections
+        .iter()
+        .map(|(name, collection)| {
+            (
+                name.clone(),
+                CollectionPolicies::from_schema_collection(collection, object_types),
+            )
+        })
+        .filter(|(_, policies)| !policies.is_default())
+        .collect()
+}
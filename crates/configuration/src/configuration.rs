@@ -1,15 +1,35 @@
-use std::{collections::BTreeMap, path::Path};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{anyhow, ensure};
+use anyhow::{anyhow, bail, ensure, Context as _};
 use itertools::Itertools;
-use mongodb_support::ExtendedJsonMode;
+use mongodb_support::{BsonScalarType, ExtendedJsonMode};
 use ndc_models as ndc;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    collection_arguments::{builtin_collection_arguments, soft_delete_collection_arguments},
+    collection_policies::{collection_policies, CollectionPolicies},
+    histogram::histogram_functions,
+    hybrid_search::hybrid_search_collections,
+    lookup_function::{lookup_functions, LookupFunction},
     native_mutation::NativeMutation,
     native_query::{NativeQuery, NativeQueryRepresentation},
-    read_directory, schema, serialized,
+    object_type_composition::flatten_object_types,
+    pushdown::find_pushdown_points,
+    raw_pipeline::raw_pipeline_function,
+    read_directory,
+    relationships::{declared_relationships, validate_relationships},
+    schema, serialized,
+    soft_delete::apply_soft_deletes,
+    system_collections::check_system_collections,
+    system_native_queries::{collection_stats_functions, system_native_queries},
+    vector_search::vector_search_collections,
+    versioning::apply_version_checks,
+    window_fields::add_window_fields,
+    write_rules::{argument_write_rules, object_field_write_rules},
 };
 
 #[derive(Clone, Debug, Default)]
@@ -38,6 +58,21 @@ pub struct Configuration {
     /// Native queries allow arbitrary aggregation pipelines that can be included in a query plan.
     pub native_queries: BTreeMap<ndc::FunctionName, NativeQuery>,
 
+    /// Generated functions that look up a document by unique key. Each is also present in
+    /// `native_queries` which supplies the pipeline that is used when a `find` is not sufficient.
+    pub lookup_functions: BTreeMap<ndc::FunctionName, LookupFunction>,
+
+    /// Settings from schema files for collections that declare any, such as a soft delete field
+    pub collection_policies: BTreeMap<ndc::CollectionName, CollectionPolicies>,
+
+    /// Relationships declared in schema files. Query requests may reference these without
+    /// including them in `collection_relationships`.
+    pub relationships: BTreeMap<ndc::RelationshipName, ndc::Relationship>,
+
+    /// Fields of each object type that set `objectIdFormat`. See
+    /// [schema::ObjectField::object_id_format].
+    pub object_id_formats: ObjectIdFormats,
+
     /// Object types defined for this connector include types of documents in each collection,
     /// types for objects inside collection documents, types for native query and native mutation
     /// arguments and results.
@@ -50,6 +85,10 @@ pub struct Configuration {
     pub options: ConfigurationOptions,
 }
 
+/// `objectIdFormat` settings of object type fields, keyed by object type name
+pub type ObjectIdFormats =
+    BTreeMap<ndc::ObjectTypeName, BTreeMap<ndc::FieldName, schema::ObjectIdFormat>>;
+
 impl Configuration {
     pub fn validate(
         schema: serialized::Schema,
@@ -57,6 +96,143 @@ impl Configuration {
         native_queries: BTreeMap<ndc::FunctionName, serialized::NativeQuery>,
         options: ConfigurationOptions,
     ) -> anyhow::Result<Self> {
+        // Object types may extend types from schema files, or types defined in the same native
+        // query or native mutation file.
+        let mut schema = schema;
+        flatten_object_types(&mut schema.object_types, &Default::default())?;
+        let mut native_queries = native_queries;
+        for (name, native_query) in native_queries.iter_mut() {
+            flatten_object_types(&mut native_query.object_types, &schema.object_types)
+                .with_context(|| format!("in the native query, {name}"))?;
+        }
+        let mut native_mutations = native_mutations;
+        for (name, native_mutation) in native_mutations.iter_mut() {
+            flatten_object_types(&mut native_mutation.object_types, &schema.object_types)
+                .with_context(|| format!("in the native mutation, {name}"))?;
+        }
+
+        if !options.allow_system_collections {
+            check_system_collections(&schema, &native_queries, &native_mutations)?;
+        }
+
+        // Generated native queries may not take names of collections or object types that are
+        // defined in the configuration directory, or names of other generated native queries
+        let configured_native_query_names: BTreeSet<ndc::FunctionName> =
+            native_queries.keys().cloned().collect();
+        let configured_object_type_names: BTreeSet<ndc::ObjectTypeName> =
+            merge_object_types(&schema, &native_mutations, &native_queries)
+                .map(|(name, _)| name.clone())
+                .collect();
+
+        // Native queries in the configuration directory take precedence over system native
+        // queries with the same name.
+        if options.system_native_queries {
+            let generated = system_native_queries(schema.collections.keys());
+            check_generated_native_queries(
+                "system native query",
+                &generated,
+                &schema,
+                &native_queries,
+                &configured_native_query_names,
+                &configured_object_type_names,
+            )?;
+            for (name, native_query) in generated {
+                native_queries.entry(name).or_insert(native_query);
+            }
+        }
+
+        if let Some(raw_pipeline) = &options.raw_pipeline {
+            let (name, native_query) = raw_pipeline_function(raw_pipeline);
+            ensure!(
+                !native_queries.contains_key(&name),
+                "the raw pipeline function name, {name}, conflicts with a native query"
+            );
+            native_queries.insert(name, native_query);
+        }
+
+        if options.collection_stats.is_some() {
+            let generated = collection_stats_functions(schema.collections.keys());
+            check_generated_native_queries(
+                "collection statistics function",
+                &generated,
+                &schema,
+                &native_queries,
+                &configured_native_query_names,
+                &configured_object_type_names,
+            )?;
+            for (name, native_query) in generated {
+                native_queries.entry(name).or_insert(native_query);
+            }
+        }
+
+        // Lookup functions are also not generated if they would replace a configured native query.
+        let mut lookup_function_map = BTreeMap::new();
+        if options.lookup_functions {
+            let functions = lookup_functions(&schema);
+            let generated = functions
+                .iter()
+                .map(|(name, _, native_query)| (name.clone(), native_query.clone()))
+                .collect();
+            check_generated_native_queries(
+                "lookup function",
+                &generated,
+                &schema,
+                &native_queries,
+                &configured_native_query_names,
+                &configured_object_type_names,
+            )?;
+            for (name, lookup_function, native_query) in functions {
+                if let Entry::Vacant(entry) = native_queries.entry(name.clone()) {
+                    entry.insert(native_query);
+                    lookup_function_map.insert(name, lookup_function);
+                }
+            }
+        }
+
+        // Histogram functions and search collections are not generated if they would replace
+        // a configured native query either.
+        let generated = histogram_functions(&schema)?.into_iter().collect();
+        check_generated_native_queries(
+            "histogram function",
+            &generated,
+            &schema,
+            &native_queries,
+            &configured_native_query_names,
+            &configured_object_type_names,
+        )?;
+        for (name, native_query) in generated {
+            native_queries.entry(name).or_insert(native_query);
+        }
+        let generated = vector_search_collections(&schema)?.into_iter().collect();
+        check_generated_native_queries(
+            "vector search collection",
+            &generated,
+            &schema,
+            &native_queries,
+            &configured_native_query_names,
+            &configured_object_type_names,
+        )?;
+        for (name, native_query) in generated {
+            native_queries.entry(name).or_insert(native_query);
+        }
+        let generated = hybrid_search_collections(&schema)?.into_iter().collect();
+        check_generated_native_queries(
+            "hybrid search collection",
+            &generated,
+            &schema,
+            &native_queries,
+            &configured_native_query_names,
+            &configured_object_type_names,
+        )?;
+        for (name, native_query) in generated {
+            native_queries.entry(name).or_insert(native_query);
+        }
+
+        let version_fields = apply_version_checks(&schema, &mut native_mutations)?;
+        apply_soft_deletes(&schema, &mut native_mutations);
+        let collection_policies = collection_policies(&schema.collections, &schema.object_types);
+        let declared_relationships = declared_relationships(&schema.collections)?;
+
         let object_types_iter = || merge_object_types(&schema, &native_mutations, &native_queries);
         let object_type_errors = {
             let duplicate_type_names: Vec<&ndc::TypeName> = object_types_iter()
@@ -76,11 +252,24 @@ impl Configuration {
                 ))
             }
         };
-        let object_types = object_types_iter()
+        let mut object_types = object_types_iter()
             .map(|(name, ot)| (name.to_owned(), ot.clone()))
             .collect();
+        add_window_fields(&schema.collections, &mut object_types)?;
+        let object_field_write_rules = object_field_write_rules(&object_types)?;
+        let object_id_formats = object_types
+            .iter()
+            .filter_map(|(type_name, object_type)| {
+                let formats: BTreeMap<_, _> = object_type
+                    .fields
+                    .iter()
+                    .filter_map(|(name, field)| Some((name.clone(), field.object_id_format?)))
+                    .collect();
+                (!formats.is_empty()).then(|| (type_name.clone(), formats))
+            })
+            .collect();
 
-        let collections = {
+        let mut collections: BTreeMap<ndc::CollectionName, ndc::CollectionInfo> = {
             let regular_collections = schema.collections.into_iter().map(|(name, collection)| {
                 (
                     name.clone(),
@@ -104,7 +293,10 @@ impl Configuration {
                 .collect()
         };
 
-        let (functions, function_errors): (BTreeMap<_, _>, Vec<_>) = native_queries
+        let (mut functions, function_errors): (
+            BTreeMap<ndc::FunctionName, (ndc::FunctionInfo, ndc::CollectionInfo)>,
+            Vec<_>,
+        ) = native_queries
             .iter()
             .filter_map(|(name, native_query)| {
                 if native_query.representation == NativeQueryRepresentation::Function {
@@ -123,7 +315,7 @@ impl Configuration {
             })
             .partition_result();
 
-        let procedures = native_mutations
+        let mut procedures = native_mutations
             .iter()
             .map(|(name, native_mutation)| {
                 (
@@ -133,26 +325,45 @@ impl Configuration {
             })
             .collect();
 
+        if let Some(idempotency) = &options.idempotency {
+            add_idempotency_key_argument(idempotency, &mut procedures)?;
+        }
+
+        if let Some(tenancy) = &options.tenancy {
+            add_tenant_argument(tenancy, &mut collections, &mut functions, &mut procedures)?;
+        }
+
         let ndc_object_types = object_types
             .into_iter()
             .map(|(name, ot)| (name, ot.into()))
             .collect();
 
+        let relationships =
+            validate_relationships(declared_relationships, &collections, &ndc_object_types)?;
+
         let internal_native_queries: BTreeMap<_, _> = native_queries
             .into_iter()
             .map(|(name, nq)| {
-                Ok((name, NativeQuery::from_serialized(&ndc_object_types, nq)?))
-                    as Result<_, anyhow::Error>
+                ensure!(
+                    nq.materialized.is_none() || nq.arguments.is_empty(),
+                    "the native query, {name}, is materialized so it may not have arguments"
+                );
+                let mut native_query = NativeQuery::from_serialized(&name, &ndc_object_types, nq)?;
+                native_query.pushdown_points = find_pushdown_points(&native_query.pipeline)
+                    .with_context(|| format!("in the native query, {name}"))?;
+                Ok((name.clone(), native_query)) as Result<_, anyhow::Error>
             })
             .try_collect()?;
 
         let internal_native_mutations: BTreeMap<_, _> = native_mutations
             .into_iter()
             .map(|(name, np)| {
-                Ok((
-                    name,
-                    NativeMutation::from_serialized(&ndc_object_types, np)?,
-                )) as Result<_, anyhow::Error>
+                let write_rules = argument_write_rules(&np, &object_field_write_rules)
+                    .with_context(|| format!("in the native mutation, {name}"))?;
+                let mut native_mutation = NativeMutation::from_serialized(&ndc_object_types, np)?;
+                native_mutation.version_field = version_fields.get(&name).cloned();
+                native_mutation.write_rules = write_rules;
+                Ok((name, native_mutation)) as Result<_, anyhow::Error>
             })
             .try_collect()?;
 
@@ -173,6 +384,10 @@ impl Configuration {
             procedures,
             native_mutations: internal_native_mutations,
             native_queries: internal_native_queries,
+            lookup_functions: lookup_function_map,
+            collection_policies,
+            relationships,
+            object_id_formats,
             object_types: ndc_object_types,
             options,
         })
@@ -197,6 +412,13 @@ impl Configuration {
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ConfigurationOptions {
+    /// In `readOnly` mode the schema response includes no procedures, and mutation requests are
+    /// rejected. This is useful for exposing a production replica safely. `mock` mode is also
+    /// read-only, and serves synthesized data so that clients can be developed against the schema
+    /// before data exists.
+    #[serde(default)]
+    pub mode: ConnectorMode,
+
     /// Options for introspection
     pub introspection_options: ConfigurationIntrospectionOptions,
 
@@ -204,6 +426,118 @@ pub struct ConfigurationOptions {
     /// responses.
     #[serde(default)]
     pub serialization_options: ConfigurationSerializationOptions,
+
+    /// Options that affect how query requests are translated to MongoDB aggregation pipelines
+    #[serde(default)]
+    pub query_options: ConfigurationQueryOptions,
+
+    /// If set, independent query requests with variable sets that arrive in quick succession, and
+    /// that differ only in their variable values, are coalesced into a single query that combines
+    /// their variable sets. Batching is off by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_batching: Option<ConfigurationQueryBatchingOptions>,
+
+    /// If set, the number of queries that may run at once against each collection is limited so
+    /// that a flood of queries on one collection cannot use up the connection pool and starve
+    /// queries on other collections. Queries over the limit wait for a slot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection_concurrency: Option<ConfigurationCollectionConcurrencyOptions>,
+
+    /// If set, each collection in the schema gets two additional virtual collections,
+    /// `<collection>_index_stats` and `<collection>_plan_cache_stats`, that expose the results of
+    /// the `$indexStats` and `$planCacheStats` aggregation stages. Reading plan cache statistics
+    /// requires the `planCacheRead` privilege.
+    #[serde(default)]
+    pub system_native_queries: bool,
+
+    /// If set, each collection in the schema gets a `<collection>_stats` function that reports its
+    /// document count and average document size, and the connector keeps these statistics for
+    /// each collection cached. See [ConfigurationCollectionStatsOptions].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection_stats: Option<ConfigurationCollectionStatsOptions>,
+
+    /// If set, configuration and requests may name `system.*` and oplog collections, and the
+    /// `admin`, `local`, and `config` databases. These are rejected by default so that server
+    /// internals cannot be exposed by accident. See [crate::system_collections].
+    #[serde(default)]
+    pub allow_system_collections: bool,
+
+    /// If set, object types that are defined with differing definitions in more than one schema
+    /// file are renamed when configuration is loaded by prefixing each definition with the name of
+    /// its collection. Otherwise such conflicts are a configuration error. The
+    /// `namespace-object-types` CLI command applies the same renaming to schema files.
+    #[serde(default)]
+    pub namespace_object_types: bool,
+
+    /// If set, each collection with a unique key gets a function that looks up a single document
+    /// by that key, such as `movies_by_id`. Requests that only select fields of the result run
+    /// as a `find` command instead of an aggregation pipeline.
+    #[serde(default)]
+    pub lookup_functions: bool,
+
+    /// If set, query requests may select a tenant database with a designated argument instead of
+    /// using the database given in the connection URI. See [ConfigurationTenancyOptions].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenancy: Option<ConfigurationTenancyOptions>,
+
+    /// If set, each mutation is recorded in an audit collection in the same transaction as the
+    /// mutation itself. See [ConfigurationAuditOptions].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<ConfigurationAuditOptions>,
+
+    /// If set, every procedure accepts an idempotency key argument. See
+    /// [ConfigurationIdempotencyOptions].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency: Option<ConfigurationIdempotencyOptions>,
+
+    /// If set, the schema includes a function that runs an aggregation pipeline given as an
+    /// argument against a named collection. This is meant for prototyping native queries in
+    /// development, and is off by default. See [ConfigurationRawPipelineOptions].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_pipeline: Option<ConfigurationRawPipelineOptions>,
+
+    /// If set, a record of the shape of each query request is written to the configured sink for
+    /// offline analysis of which schema features are used. See [ConfigurationQueryLogOptions].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_log: Option<ConfigurationQueryLogOptions>,
+
+    /// If set, query interactions with MongoDB are recorded to, or replayed from, files in
+    /// a directory. See [ConfigurationRecordingOptions].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording: Option<ConfigurationRecordingOptions>,
+
+    /// If set, the connector opens database connections and checks that the database is
+    /// reachable at startup, and the health endpoint does not report ready until those checks
+    /// pass. See [ConfigurationWarmUpOptions].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warm_up: Option<ConfigurationWarmUpOptions>,
+
+    /// Options for draining in-flight requests when the connector receives `SIGTERM`
+    #[serde(default)]
+    pub shutdown: ConfigurationShutdownOptions,
+
+    /// Options for responses to explain requests
+    #[serde(default)]
+    pub explain: ConfigurationExplainOptions,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationExplainOptions {
+    /// If set, explain responses include a `diagram` entry that describes the generated pipeline
+    /// in the given format. Stages are drawn in order, and the sub-pipelines of `$lookup`,
+    /// `$facet`, and `$unionWith` stages are drawn as nested subgraphs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diagram: Option<DiagramFormat>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DiagramFormat {
+    /// A Mermaid flowchart, which renders in GitHub comments and many issue trackers
+    Mermaid,
+    /// A Graphviz DOT digraph
+    Graphviz,
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
@@ -229,13 +563,616 @@ impl Default for ConfigurationIntrospectionOptions {
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ConfigurationSerializationOptions {
     /// Extended JSON has two modes: canonical and relaxed. This option determines which mode is
     /// used for output. This setting has no effect on inputs (query arguments, etc.).
     #[serde(default)]
     pub extended_json_mode: ExtendedJsonMode,
+
+    /// JSON cannot represent NaN or infinite numbers. This option determines how such double
+    /// values are written in responses. Decimal values are always written as strings, such as
+    /// `"NaN"`.
+    #[serde(default)]
+    pub non_finite_numbers: NonFiniteNumberPolicy,
+
+    /// Query responses fail if a selected value has documents or arrays nested more deeply than
+    /// this. The limit guards against pathological documents exhausting the stack during
+    /// serialization.
+    #[serde(default = "default_max_nesting_depth")]
+    pub max_nesting_depth: usize,
+
+    /// If set, query responses fail if any row is larger than this many bytes when serialized to
+    /// JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_row_size_bytes: Option<usize>,
+
+    /// If set, query requests fail once the rows read from MongoDB and the response written so
+    /// far amount to more than this many bytes. This bounds the memory that any one request can
+    /// hold while its response is built.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_memory_bytes: Option<usize>,
+
+    /// Determines what happens when a row in a query response cannot be converted to its
+    /// expected type, or exceeds `maxRowSizeBytes`.
+    #[serde(default)]
+    pub row_errors: RowErrorPolicy,
+
+    /// Determines how ObjectId values are written in query responses. Fields may override this
+    /// with `objectIdFormat`.
+    #[serde(default)]
+    pub object_id_format: schema::ObjectIdFormat,
+
+    /// In relaxed extended JSON mode 64-bit integers in `ExtendedJSON` values are written as JSON
+    /// numbers. JavaScript clients silently round integers beyond ±(2^53 - 1). If this option is
+    /// set such integers are written as strings instead. Values of type `Long` are always written
+    /// as strings, and `Long` inputs are given as strings.
+    #[serde(default)]
+    pub large_longs_as_strings: bool,
+}
+
+impl Default for ConfigurationSerializationOptions {
+    fn default() -> Self {
+        ConfigurationSerializationOptions {
+            extended_json_mode: Default::default(),
+            non_finite_numbers: Default::default(),
+            max_nesting_depth: default_max_nesting_depth(),
+            max_row_size_bytes: None,
+            max_response_memory_bytes: None,
+            row_errors: Default::default(),
+            object_id_format: Default::default(),
+            large_longs_as_strings: false,
+        }
+    }
+}
+
+// MongoDB does not store documents with more than 100 levels of nesting, but aggregation
+// pipelines can produce deeper values.
+fn default_max_nesting_depth() -> usize {
+    200
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectorMode {
+    /// Queries and mutations are allowed
+    #[default]
+    ReadWrite,
+    /// Only queries are allowed
+    ReadOnly,
+    /// Queries are answered with synthesized documents that match the configured object types,
+    /// without a database connection. Mutations are not allowed.
+    Mock,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NonFiniteNumberPolicy {
+    /// Fail the request
+    #[default]
+    Error,
+    /// Write `null`
+    Null,
+    /// Write a string such as `"NaN"`, `"Infinity"`, or `"-Infinity"`
+    String,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RowErrorPolicy {
+    /// Fail the request
+    #[default]
+    Error,
+    /// Leave the row out of the response, and log the row index, the path to the value that
+    /// failed, and the error message. Row sets that leave out rows get a `skippedRows` field with
+    /// the number of rows left out.
+    Skip,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationQueryOptions {
+    /// If set, queries that use `limit` or `offset` are sorted by `_id` after any requested
+    /// ordering so that pagination does not produce overlapping pages when the requested
+    /// ordering has ties.
+    #[serde(default)]
+    pub deterministic_pagination: bool,
+
+    /// If set, comparisons with `_neq`, `_lt`, `_lte`, `_gt`, and `_gte` do not match documents
+    /// where the compared field is null or missing. This matches the three-valued logic of SQL
+    /// connectors. By default MongoDB semantics apply, where for example `_neq` matches documents
+    /// where the field is missing.
+    #[serde(default)]
+    pub sql_null_semantics: bool,
+
+    /// If set, queries against regular collections that do not use relationships, aggregates, or
+    /// variables run as a `find` command with a filter, projection, sort, skip, and limit instead
+    /// of as an aggregation pipeline. Projections use aggregation expressions which requires
+    /// MongoDB 4.4 or later.
+    #[serde(default)]
+    pub find_fast_path: bool,
+
+    /// If set, query pipelines avoid aggregation features that are missing from servers that
+    /// implement an older version of the MongoDB wire protocol, such as Amazon DocumentDB or Azure
+    /// Cosmos DB for MongoDB. `$getField` and the `$first` array operator are replaced with
+    /// equivalent expressions, relationship lookups do not use the concise correlated subquery
+    /// syntax, and requests with variable sets run one aggregation per variable set instead of
+    /// using a `$documents` stage.
+    #[serde(default)]
+    pub compatibility_mode: bool,
+
+    /// Additional aggregation functions to declare in the schema. Each entry applies a built-in
+    /// function such as `percentile` or `concat` with fixed arguments under a new name, for example
+    /// `p95` for `percentile` with `p: 0.95`. The new function is available on every scalar type
+    /// that supports the underlying function.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aggregate_functions: BTreeMap<ndc::AggregateFunctionName, AggregateFunctionOptions>,
+
+    /// Restrictions on patterns given to the `_regex` and `_iregex` comparison operators
+    #[serde(default)]
+    pub regex: ConfigurationRegexOptions,
+
+    /// Collation locale, such as `"en"`, for the `_ieq` operator. If set, queries that use `_ieq`
+    /// run with a collation of this locale at strength 2 so that `_ieq` compiles to `$eq`, which
+    /// can use case-insensitive indexes created with the same collation. The collation applies to
+    /// every string comparison and sort in such a query. If unset, or if compatibility mode is
+    /// enabled, `_ieq` compares lower-cased strings instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub case_insensitive_collation: Option<String>,
+
+    /// If set, queries that select rows without aggregates or variables check the size of each
+    /// row before it is returned, and fail with an error that names the largest selected fields
+    /// if a row would exceed the 16MB BSON document limit. Requires MongoDB 4.4 or later.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub large_documents: Option<ConfigurationLargeDocumentOptions>,
+
+    /// If set, aggregation cursors are opened with an explicit `batchSize`. Unless `adaptive` is
+    /// disabled the batch size for each collection is tuned from the sizes of documents in the
+    /// first batch of earlier queries: small documents get larger batches so that fewer round
+    /// trips are needed, and large documents get smaller batches to keep messages small.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor_batch_size: Option<ConfigurationCursorBatchSizeOptions>,
+
+    /// If set, query requests that may run as several commands, such as requests with more than
+    /// one variable set, run all of their commands in one session with snapshot reads so that
+    /// every command reads the same point in time. Requires MongoDB 5.0 or later on a replica set
+    /// or sharded cluster. Commands run without a session on servers that do not support snapshot
+    /// reads.
+    #[serde(default)]
+    pub snapshot_reads: bool,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationCursorBatchSizeOptions {
+    /// Batch size for the first query against each collection, or for every query if `adaptive`
+    /// is disabled.
+    #[serde(default = "default_initial_cursor_batch_size")]
+    pub initial: u32,
+
+    /// Upper bound for adapted batch sizes
+    #[serde(default = "default_max_cursor_batch_size")]
+    pub max: u32,
+
+    /// Adapted batch sizes aim for batches of roughly this many bytes.
+    #[serde(default = "default_target_cursor_batch_bytes")]
+    pub target_batch_bytes: u64,
+
+    #[serde(default = "default_true")]
+    pub adaptive: bool,
+}
+
+impl Default for ConfigurationCursorBatchSizeOptions {
+    fn default() -> Self {
+        Self {
+            initial: default_initial_cursor_batch_size(),
+            max: default_max_cursor_batch_size(),
+            target_batch_bytes: default_target_cursor_batch_bytes(),
+            adaptive: true,
+        }
+    }
+}
+
+/// The server's default size for a first batch
+fn default_initial_cursor_batch_size() -> u32 {
+    101
+}
+
+fn default_max_cursor_batch_size() -> u32 {
+    10_000
+}
+
+fn default_target_cursor_batch_bytes() -> u64 {
+    4 * 1024 * 1024
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationLargeDocumentOptions {
+    /// Maximum number of elements to return from each selected array field. Longer arrays are
+    /// truncated on the server so that rows that select large arrays fit in a response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_array_length: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationRegexOptions {
+    /// If set, `_regex` and `_iregex` are removed from the schema, and requests that use them are
+    /// rejected.
+    #[serde(default)]
+    pub disabled: bool,
+
+    /// Maximum length of a pattern in characters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<usize>,
+
+    /// Maximum nesting depth of groups, repetitions, and alternations in a pattern. This limits
+    /// the complexity of patterns that the server has to evaluate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_nesting_depth: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AggregateFunctionOptions {
+    /// Name of the built-in aggregation function to apply
+    pub function: ndc::AggregateFunctionName,
+
+    /// Arguments for the function. `percentile` requires `p`, a number between 0 and 1. `concat`
+    /// accepts a `separator` string. `avg`, `count`, `sum`, and `concat` accept a `distinct`
+    /// boolean that ignores repeated values. `count_where` requires a `value` in extended JSON,
+    /// and accepts an `operator` such as `_gt` that defaults to `_eq`.
+    #[serde(default)]
+    pub arguments: BTreeMap<ndc::ArgumentName, serde_json::Value>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationQueryBatchingOptions {
+    /// Maximum time in milliseconds that the first request in a batch waits for other requests to
+    /// join the batch before the batch is executed.
+    pub max_delay_ms: u64,
+
+    /// A batch is executed immediately when it reaches this many requests.
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+}
+
+fn default_max_batch_size() -> usize {
+    100
+}
+
+/// Per-collection limits on concurrent queries. Each collection gets its own limit; queries on
+/// one collection do not count against the limit of another.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationCollectionConcurrencyOptions {
+    /// Maximum number of queries that may run at once against any one collection.
+    pub max_concurrent_queries: usize,
+
+    /// Limits for specific collections that override `maxConcurrentQueries`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub collections: BTreeMap<ndc::CollectionName, usize>,
+}
+
+impl ConfigurationCollectionConcurrencyOptions {
+    pub fn limit_for(&self, collection: &ndc::CollectionName) -> usize {
+        self.collections
+            .get(collection)
+            .copied()
+            .unwrap_or(self.max_concurrent_queries)
+    }
+}
+
+/// Database-per-tenant routing. When configured every collection, function, and procedure accepts
+/// an additional argument that names the database to run the query or mutation against. The argument is
+/// typically populated from a request header using an argument preset in the engine's data
+/// connector link. Requests that omit the argument use the database from the connection URI.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationTenancyOptions {
+    /// Name of the argument that selects the tenant database.
+    #[serde(default = "default_tenant_argument")]
+    pub tenant_argument: ndc::ArgumentName,
+
+    /// Regular expression that tenant database names must match in full. Requests that name
+    /// a database that does not match are rejected.
+    pub database_pattern: TenantDatabasePattern,
+}
+
+/// A tenant database pattern compiled when configuration is parsed, so that an invalid pattern is
+/// reported when the connector starts instead of when a request names a tenant.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TenantDatabasePattern {
+    pattern: String,
+    regex: regex::Regex,
+}
+
+impl TenantDatabasePattern {
+    /// Checks that the given name matches the pattern in full
+    pub fn is_match(&self, database_name: &str) -> bool {
+        self.regex.is_match(database_name)
+    }
+}
+
+impl TryFrom<String> for TenantDatabasePattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        let regex = regex::Regex::new(&format!("^(?:{pattern})$"))?;
+        Ok(TenantDatabasePattern { pattern, regex })
+    }
+}
+
+impl From<TenantDatabasePattern> for String {
+    fn from(value: TenantDatabasePattern) -> Self {
+        value.pattern
+    }
+}
+
+/// Mutations that are given an idempotency key record their result under that key in a dedicated
+/// collection. A repeat of the same key for the same procedure returns the recorded result instead
+/// of running the mutation again, which protects against double submits from clients and gateways
+/// that retry requests. Keys expire through a TTL index.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationIdempotencyOptions {
+    /// Name of the argument that carries the idempotency key
+    #[serde(default = "default_idempotency_key_argument")]
+    pub key_argument: ndc::ArgumentName,
+
+    /// Name of the collection that records keys and results
+    #[serde(default = "default_idempotency_collection")]
+    pub collection: String,
+
+    /// Time in seconds that a key is remembered after the first request that used it
+    #[serde(default = "default_idempotency_ttl_seconds")]
+    pub ttl_seconds: u64,
+}
+
+impl ConfigurationIdempotencyOptions {
+    fn argument_info(&self) -> ndc::ArgumentInfo {
+        ndc::ArgumentInfo {
+            argument_type: schema::Type::Nullable(Box::new(schema::Type::Scalar(
+                BsonScalarType::String,
+            )))
+            .into(),
+            description: Some(
+                "Key that identifies repeats of this mutation. A repeated key returns the result of the first mutation instead of running the mutation again.".to_owned(),
+            ),
+        }
+    }
+}
+
+/// The raw pipeline function takes a `collection` argument and a `pipeline` argument, and returns
+/// the list of documents that the pipeline produces. Stages are checked against an
+/// allow-list, including stages in sub-pipelines of `$lookup`, `$facet`, and `$unionWith`, so that
+/// the function cannot write to the database.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationRawPipelineOptions {
+    /// Name of the function in the schema
+    #[serde(default = "default_raw_pipeline_function_name")]
+    pub function_name: ndc::FunctionName,
+
+    /// Aggregation stages that pipelines may use, such as `$match`
+    #[serde(default = "default_raw_pipeline_allowed_stages")]
+    pub allowed_stages: BTreeSet<String>,
+}
+
+impl Default for ConfigurationRawPipelineOptions {
+    fn default() -> Self {
+        ConfigurationRawPipelineOptions {
+            function_name: default_raw_pipeline_function_name(),
+            allowed_stages: default_raw_pipeline_allowed_stages(),
+        }
+    }
+}
+
+/// Audit records are written with the `insert` command in a transaction with the mutation, so
+/// auditing requires a replica set or sharded cluster.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationAuditOptions {
+    /// Name of the collection that audit records are written to
+    #[serde(default = "default_audit_collection")]
+    pub collection: String,
+
+    /// If set, audit records for `update`, `delete`, and `findAndModify` commands include the
+    /// documents matched by the command as they were before the mutation, and for updates as
+    /// they were after the mutation.
+    #[serde(default)]
+    pub capture_documents: bool,
+}
+
+/// Query log records are anonymized: they include the collection, the names of operators used in
+/// predicates, the depth of nested relationships, and the time taken to run the query, but no
+/// field values or argument values.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationQueryLogOptions {
+    /// Where query log records are written. Records are written as one JSON object per line to
+    /// stdout or a file, or as documents in a collection.
+    #[serde(default)]
+    pub sink: QueryLogSink,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum QueryLogSink {
+    #[default]
+    Stdout,
+    File {
+        path: PathBuf,
+    },
+    Collection {
+        name: String,
+    },
+}
+
+/// In `record` mode every aggregate, find, and count that a query request runs is written to the
+/// recording directory with its response. In `replay` mode responses are read from the recording
+/// directory instead of the database, and the connector does not need a database connection.
+/// Interactions are identified by their target, operation, and request so a replayed query
+/// request must produce exactly the same commands as the recorded one. Mutations are not
+/// recorded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationRecordingOptions {
+    pub mode: RecordingMode,
+    pub directory: PathBuf,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordingMode {
+    Record,
+    Replay,
+}
+
+/// Cached collection statistics are refreshed in the background. The connector uses average
+/// document sizes to pick the initial cursor batch size for collections that have not been queried
+/// yet.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationCollectionStatsOptions {
+    /// Time in seconds between refreshes of cached statistics
+    #[serde(default = "default_collection_stats_refresh_interval_seconds")]
+    pub refresh_interval_seconds: u64,
+}
+
+impl Default for ConfigurationCollectionStatsOptions {
+    fn default() -> Self {
+        ConfigurationCollectionStatsOptions {
+            refresh_interval_seconds: default_collection_stats_refresh_interval_seconds(),
+        }
+    }
+}
+
+fn default_collection_stats_refresh_interval_seconds() -> u64 {
+    5 * 60
+}
+
+/// Warm-up runs once at startup, and is retried until it succeeds. It avoids latency spikes on the
+/// first queries after a deploy, which would otherwise wait for connections to be established.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationWarmUpOptions {
+    /// Number of connections to open before reporting ready. This is also set as the minimum size
+    /// of the connection pool so that the driver keeps the connections open.
+    #[serde(default)]
+    pub min_pool_size: u32,
+
+    /// If set, warm-up also runs `listCollections` to check that the database user can read the
+    /// configured database.
+    #[serde(default)]
+    pub list_collections: bool,
+
+    /// If set, warm-up also sends each native query pipeline, and each native mutation command
+    /// that supports `explain`, to the server in an `explain` command with synthesized argument
+    /// values. If the server rejects any of them the connector never reports ready, so that
+    /// configuration errors surface at deploy time instead of on first use.
+    #[serde(default)]
+    pub verify_native_queries: bool,
+}
+
+/// On shutdown the connector stops accepting requests, and waits for in-flight requests to
+/// complete. Requests that are still running after the drain timeout are cancelled, and then the
+/// MongoDB client is closed.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigurationShutdownOptions {
+    /// Maximum time in seconds to wait for in-flight requests. This should be less than the grace
+    /// period that the deployment environment allows before it kills the process.
+    #[serde(default = "default_drain_timeout_seconds")]
+    pub drain_timeout_seconds: u64,
+}
+
+impl Default for ConfigurationShutdownOptions {
+    fn default() -> Self {
+        ConfigurationShutdownOptions {
+            drain_timeout_seconds: default_drain_timeout_seconds(),
+        }
+    }
+}
+
+fn default_drain_timeout_seconds() -> u64 {
+    25
+}
+
+fn default_audit_collection() -> String {
+    "audit_log".to_owned()
+}
+
+fn default_tenant_argument() -> ndc::ArgumentName {
+    "tenant".into()
+}
+
+fn default_raw_pipeline_function_name() -> ndc::FunctionName {
+    "_raw_pipeline".into()
+}
+
+fn default_raw_pipeline_allowed_stages() -> BTreeSet<String> {
+    [
+        "$addFields",
+        "$bucket",
+        "$bucketAuto",
+        "$count",
+        "$densify",
+        "$facet",
+        "$fill",
+        "$group",
+        "$limit",
+        "$lookup",
+        "$match",
+        "$project",
+        "$redact",
+        "$replaceRoot",
+        "$replaceWith",
+        "$sample",
+        "$set",
+        "$setWindowFields",
+        "$skip",
+        "$sort",
+        "$sortByCount",
+        "$unionWith",
+        "$unset",
+        "$unwind",
+    ]
+    .into_iter()
+    .map(ToOwned::to_owned)
+    .collect()
+}
+
+fn default_idempotency_key_argument() -> ndc::ArgumentName {
+    "idempotencyKey".into()
+}
+
+fn default_idempotency_collection() -> String {
+    "idempotency_keys".to_owned()
+}
+
+fn default_idempotency_ttl_seconds() -> u64 {
+    24 * 60 * 60
+}
+
+impl ConfigurationTenancyOptions {
+    fn argument_info(&self) -> ndc::ArgumentInfo {
+        ndc::ArgumentInfo {
+            argument_type: schema::Type::Nullable(Box::new(schema::Type::Scalar(
+                BsonScalarType::String,
+            )))
+            .into(),
+            description: Some("Name of the tenant database to use".to_owned()),
+        }
+    }
 }
 
 fn merge_object_types<'a>(
@@ -255,6 +1192,42 @@ fn merge_object_types<'a>(
         .chain(object_types_from_native_queries)
 }
 
+/// Generated native queries are not used if a configured native query has the same name.
+/// Otherwise a generated native query may not have the name of a schema collection or of another
+/// generated native query, and its object types may not have names of configured object types.
+fn check_generated_native_queries(
+    description: &str,
+    generated: &BTreeMap<ndc::FunctionName, serialized::NativeQuery>,
+    schema: &serialized::Schema,
+    native_queries: &BTreeMap<ndc::FunctionName, serialized::NativeQuery>,
+    configured_native_query_names: &BTreeSet<ndc::FunctionName>,
+    configured_object_type_names: &BTreeSet<ndc::ObjectTypeName>,
+) -> anyhow::Result<()> {
+    for (name, native_query) in generated {
+        if configured_native_query_names.contains(name) {
+            continue;
+        }
+        ensure!(
+            !native_queries.contains_key(name),
+            "the {description}, {name}, conflicts with another generated function"
+        );
+        ensure!(
+            !schema.collections.contains_key(name.as_str()),
+            "the {description}, {name}, conflicts with a collection in the schema"
+        );
+        if let Some(type_name) = native_query
+            .object_types
+            .keys()
+            .find(|type_name| configured_object_type_names.contains(*type_name))
+        {
+            bail!(
+                "the object type, {type_name}, of the {description}, {name}, conflicts with a configured object type"
+            );
+        }
+    }
+    Ok(())
+}
+
 fn collection_to_collection_info(
     object_types: &BTreeMap<ndc::ObjectTypeName, schema::ObjectType>,
     name: ndc::CollectionName,
@@ -263,11 +1236,16 @@ fn collection_to_collection_info(
     let pk_constraint =
         get_primary_key_uniqueness_constraint(object_types, &name, &collection.r#type);
 
+    let mut arguments = builtin_collection_arguments();
+    if collection.soft_delete_field.is_some() {
+        arguments.extend(soft_delete_collection_arguments());
+    }
+
     ndc::CollectionInfo {
         name,
         collection_type: collection.r#type,
         description: collection.description,
-        arguments: Default::default(),
+        arguments: arguments_to_ndc_arguments(arguments),
         foreign_keys: Default::default(),
         uniqueness_constraints: BTreeMap::from_iter(pk_constraint),
     }
@@ -295,7 +1273,7 @@ fn native_query_to_collection_info(
     }
 }
 
-fn get_primary_key_uniqueness_constraint(
+pub(crate) fn get_primary_key_uniqueness_constraint(
     object_types: &BTreeMap<ndc::ObjectTypeName, schema::ObjectType>,
     name: &ndc::CollectionName,
     collection_type: &ndc::ObjectTypeName,
@@ -353,6 +1331,60 @@ fn native_mutation_to_procedure_info(
     }
 }
 
+fn add_tenant_argument(
+    tenancy: &ConfigurationTenancyOptions,
+    collections: &mut BTreeMap<ndc::CollectionName, ndc::CollectionInfo>,
+    functions: &mut BTreeMap<ndc::FunctionName, (ndc::FunctionInfo, ndc::CollectionInfo)>,
+    procedures: &mut BTreeMap<ndc::ProcedureName, ndc::ProcedureInfo>,
+) -> anyhow::Result<()> {
+    let argument_info = tenancy.argument_info();
+    let collection_arguments = collections.iter_mut().map(|(name, collection_info)| {
+        (format!("collection {name}"), &mut collection_info.arguments)
+    });
+    let function_arguments =
+        functions
+            .iter_mut()
+            .flat_map(|(name, (function_info, collection_info))| {
+                [
+                    (format!("function {name}"), &mut function_info.arguments),
+                    (format!("function {name}"), &mut collection_info.arguments),
+                ]
+            });
+    let procedure_arguments = procedures
+        .iter_mut()
+        .map(|(name, procedure)| (format!("procedure {name}"), &mut procedure.arguments));
+    for (description, arguments) in collection_arguments
+        .chain(function_arguments)
+        .chain(procedure_arguments)
+    {
+        ensure!(
+            !arguments.contains_key(&tenancy.tenant_argument),
+            "{description} declares an argument named {}, which is reserved for tenant database names",
+            tenancy.tenant_argument
+        );
+        arguments.insert(tenancy.tenant_argument.clone(), argument_info.clone());
+    }
+    Ok(())
+}
+
+fn add_idempotency_key_argument(
+    idempotency: &ConfigurationIdempotencyOptions,
+    procedures: &mut BTreeMap<ndc::ProcedureName, ndc::ProcedureInfo>,
+) -> anyhow::Result<()> {
+    let argument_info = idempotency.argument_info();
+    for (name, procedure) in procedures {
+        ensure!(
+            !procedure.arguments.contains_key(&idempotency.key_argument),
+            "native mutation {name} declares an argument named {}, which is reserved for idempotency keys",
+            idempotency.key_argument
+        );
+        procedure
+            .arguments
+            .insert(idempotency.key_argument.clone(), argument_info.clone());
+    }
+    Ok(())
+}
+
 fn arguments_to_ndc_arguments(
     configured_arguments: BTreeMap<ndc::ArgumentName, schema::ObjectField>,
 ) -> BTreeMap<ndc::ArgumentName, ndc::ArgumentInfo> {
@@ -395,6 +1427,7 @@ mod tests {
                 schema::ObjectType {
                     fields: Default::default(),
                     description: Default::default(),
+                    extends: Default::default(),
                 },
             )]
             .into_iter()
@@ -408,6 +1441,7 @@ mod tests {
                     schema::ObjectType {
                         fields: Default::default(),
                         description: Default::default(),
+                        extends: Default::default(),
                     },
                 )]
                 .into_iter()
@@ -431,4 +1465,307 @@ mod tests {
         assert!(error_msg.contains("multiple definitions"));
         assert!(error_msg.contains("Album"));
     }
+
+    #[test]
+    fn includes_system_native_queries_when_enabled() -> anyhow::Result<()> {
+        let schema = Schema {
+            collections: [(
+                "movies".into(),
+                schema::Collection {
+                    r#type: "movies".into(),
+                    description: None,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "movies".into(),
+                schema::ObjectType {
+                    fields: Default::default(),
+                    description: Default::default(),
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        let options = ConfigurationOptions {
+            system_native_queries: true,
+            ..Default::default()
+        };
+        let config =
+            Configuration::validate(schema, Default::default(), Default::default(), options)?;
+
+        assert!(config.collections.contains_key("movies_index_stats"));
+        assert!(config.collections.contains_key("movies_plan_cache_stats"));
+        assert!(config
+            .object_types
+            .contains_key("movies_index_stats_accesses"));
+
+        let index_stats = &config.native_queries["movies_index_stats"];
+        assert_eq!(index_stats.input_collection, Some("movies".into()));
+        assert_eq!(index_stats.pipeline, vec![doc! { "$indexStats": {} }]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_system_native_queries_that_conflict_with_configuration() {
+        let object_type = || schema::ObjectType {
+            fields: Default::default(),
+            description: Default::default(),
+            extends: Default::default(),
+        };
+        let collection = |r#type: &str| schema::Collection {
+            r#type: r#type.into(),
+            ..Default::default()
+        };
+        let options = || ConfigurationOptions {
+            system_native_queries: true,
+            ..Default::default()
+        };
+
+        let schema = Schema {
+            collections: [
+                ("movies".into(), collection("movies")),
+                ("movies_index_stats".into(), collection("movies")),
+            ]
+            .into(),
+            object_types: [("movies".into(), object_type())].into(),
+        };
+        let result =
+            Configuration::validate(schema, Default::default(), Default::default(), options());
+        assert!(result.unwrap_err().to_string().contains(
+            "the system native query, movies_index_stats, conflicts with a collection in the schema"
+        ));
+
+        let schema = Schema {
+            collections: [("movies".into(), collection("movies"))].into(),
+            object_types: [
+                ("movies".into(), object_type()),
+                ("movies_index_stats_accesses".into(), object_type()),
+            ]
+            .into(),
+        };
+        let result =
+            Configuration::validate(schema, Default::default(), Default::default(), options());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("the object type, movies_index_stats_accesses,"));
+    }
+
+    #[test]
+    fn rejects_collection_stats_functions_that_conflict_with_configuration() {
+        let schema = Schema {
+            collections: [(
+                "movies".into(),
+                schema::Collection {
+                    r#type: "movies".into(),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: ["movies", "movies_stats_value"]
+                .map(|name| {
+                    (
+                        ndc::ObjectTypeName::from(name),
+                        schema::ObjectType {
+                            fields: Default::default(),
+                            description: Default::default(),
+                            extends: Default::default(),
+                        },
+                    )
+                })
+                .into(),
+        };
+        let options = ConfigurationOptions {
+            collection_stats: Some(Default::default()),
+            ..Default::default()
+        };
+        let result =
+            Configuration::validate(schema, Default::default(), Default::default(), options);
+        assert!(result.unwrap_err().to_string().contains(
+            "the object type, movies_stats_value, of the collection statistics function, movies_stats,"
+        ));
+    }
+
+    /// Schema with a `movies` collection, and empty object types with the given names
+    fn movies_schema(object_type_names: &[&str]) -> Schema {
+        let movies = schema::ObjectType {
+            fields: [schema::ObjectField::new(
+                "_id",
+                Type::Scalar(BsonScalarType::ObjectId),
+            )]
+            .into_iter()
+            .map(|(name, field)| (name.into(), field))
+            .collect(),
+            description: Default::default(),
+            extends: Default::default(),
+        };
+        let empty_object_types = object_type_names.iter().map(|name| {
+            (
+                ndc::ObjectTypeName::from(*name),
+                schema::ObjectType {
+                    fields: Default::default(),
+                    description: Default::default(),
+                    extends: Default::default(),
+                },
+            )
+        });
+        Schema {
+            collections: [(
+                "movies".into(),
+                schema::Collection {
+                    r#type: "movies".into(),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [("movies".into(), movies)]
+                .into_iter()
+                .chain(empty_object_types)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn rejects_lookup_functions_that_conflict_with_configuration() {
+        let options = ConfigurationOptions {
+            lookup_functions: true,
+            ..Default::default()
+        };
+        let result = Configuration::validate(
+            movies_schema(&["movies_by_id_result"]),
+            Default::default(),
+            Default::default(),
+            options,
+        );
+        assert!(result.unwrap_err().to_string().contains(
+            "the object type, movies_by_id_result, of the lookup function, movies_by_id,"
+        ));
+    }
+
+    #[test]
+    fn rejects_histogram_functions_that_conflict_with_configuration() {
+        let mut schema = movies_schema(&["movies__id_histogram_bucket"]);
+        if let Some(movies) = schema.collections.get_mut("movies") {
+            movies.histograms = vec![schema::Histogram {
+                field: "_id".into(),
+                boundaries: None,
+            }];
+        }
+        let result = Configuration::validate(
+            schema,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        assert!(result.unwrap_err().to_string().contains(
+            "the object type, movies__id_histogram_bucket, of the histogram function, movies__id_histogram,"
+        ));
+    }
+
+    #[test]
+    fn rejects_vector_search_collections_that_conflict_with_configuration() {
+        let mut schema = movies_schema(&["movies_embedding_vector_search_result"]);
+        if let Some(movies) = schema.collections.get_mut("movies") {
+            movies.vector_search = vec![schema::VectorSearch {
+                index: "vector_index".to_owned(),
+                path: "embedding".into(),
+                num_candidates: None,
+            }];
+        }
+        let result = Configuration::validate(
+            schema,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        assert!(result.unwrap_err().to_string().contains(
+            "the object type, movies_embedding_vector_search_result, of the vector search collection, movies_embedding_vector_search,"
+        ));
+    }
+
+    #[test]
+    fn rejects_hybrid_search_collections_that_conflict_with_configuration() {
+        let mut schema = movies_schema(&["movies_hybrid_search_result"]);
+        if let Some(movies) = schema.collections.get_mut("movies") {
+            movies.hybrid_search = Some(schema::HybridSearch {
+                text_index: "text_index".to_owned(),
+                text_paths: vec!["title".into()],
+                text_weight: None,
+                vector: schema::VectorSearch {
+                    index: "vector_index".to_owned(),
+                    path: "embedding".into(),
+                    num_candidates: None,
+                },
+                vector_weight: None,
+                rank_constant: None,
+            });
+        }
+        let result = Configuration::validate(
+            schema,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        assert!(result.unwrap_err().to_string().contains(
+            "the object type, movies_hybrid_search_result, of the hybrid search collection, movies_hybrid_search,"
+        ));
+    }
+
+    #[test]
+    fn rejects_tenant_argument_that_conflicts_with_collection_argument() -> anyhow::Result<()> {
+        let schema = Schema {
+            collections: [(
+                "movies".into(),
+                schema::Collection {
+                    r#type: "movies".into(),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "movies".into(),
+                schema::ObjectType {
+                    fields: Default::default(),
+                    description: Default::default(),
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        let options = ConfigurationOptions {
+            tenancy: Some(ConfigurationTenancyOptions {
+                tenant_argument: "limit".into(),
+                database_pattern: "tenant_.+".to_owned().try_into()?,
+            }),
+            ..Default::default()
+        };
+        let result =
+            Configuration::validate(schema, Default::default(), Default::default(), options);
+        assert!(result.unwrap_err().to_string().contains(
+            "collection movies declares an argument named limit, which is reserved for tenant database names"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn includes_raw_pipeline_function_when_enabled() -> anyhow::Result<()> {
+        let options = ConfigurationOptions {
+            raw_pipeline: Some(Default::default()),
+            ..Default::default()
+        };
+        let config = Configuration::validate(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            options,
+        )?;
+
+        let (function_info, _) = &config.functions["_raw_pipeline"];
+        assert!(function_info.arguments.contains_key("collection"));
+        assert!(function_info.arguments.contains_key("pipeline"));
+        assert_eq!(config.native_queries["_raw_pipeline"].placeholders.len(), 2);
+        Ok(())
+    }
 }
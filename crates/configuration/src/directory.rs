@@ -1,7 +1,8 @@
-use anyhow::{anyhow, Context as _};
+use anyhow::{anyhow, ensure, Context as _};
 use futures::stream::TryStreamExt as _;
 use itertools::Itertools as _;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
     collections::{BTreeMap, HashSet},
     fs::Metadata,
@@ -11,12 +12,18 @@ use tokio::{fs, io::AsyncWriteExt};
 use tokio_stream::wrappers::ReadDirStream;
 
 use crate::{
-    configuration::ConfigurationOptions, serialized::Schema, with_name::WithName, Configuration,
+    configuration::ConfigurationOptions,
+    format_version,
+    schema_namespacing::{conflicting_object_type_names, namespace_object_types},
+    serialized::{self, Schema},
+    with_name::WithName,
+    Configuration,
 };
 
 pub const SCHEMA_DIRNAME: &str = "schema";
 pub const NATIVE_MUTATIONS_DIRNAME: &str = "native_mutations";
 pub const NATIVE_QUERIES_DIRNAME: &str = "native_queries";
+pub const MIGRATIONS_DIRNAME: &str = "migrations";
 pub const CONFIGURATION_OPTIONS_BASENAME: &str = "configuration";
 pub const CONFIGURATION_OPTIONS_METADATA: &str = ".configuration_metadata";
 
@@ -25,17 +32,25 @@ pub const CONFIGURATION_OPTIONS_METADATA: &str = ".configuration_metadata";
 // with some CHANGELOG/Docs messaging around deprecation
 pub const NATIVE_PROCEDURES_DIRNAME: &str = "native_procedures";
 
-pub const CONFIGURATION_EXTENSIONS: [(&str, FileFormat); 3] =
-    [("json", JSON), ("yaml", YAML), ("yml", YAML)];
+pub const CONFIGURATION_EXTENSIONS: [(&str, FileFormat); 4] = [
+    ("json", JSON),
+    ("json5", JSON5),
+    ("yaml", YAML),
+    ("yml", YAML),
+];
 pub const DEFAULT_EXTENSION: &str = "json";
 
 #[derive(Clone, Copy, Debug)]
 pub enum FileFormat {
     Json,
+    /// JSON5 permits comments, trailing commas, and unquoted keys, which makes it easier to edit
+    /// native query pipelines by hand.
+    Json5,
     Yaml,
 }
 
 const JSON: FileFormat = FileFormat::Json;
+const JSON5: FileFormat = FileFormat::Json5;
 const YAML: FileFormat = FileFormat::Yaml;
 
 /// Read configuration from a directory
@@ -44,9 +59,20 @@ pub async fn read_directory(
 ) -> anyhow::Result<Configuration> {
     let dir = configuration_dir.as_ref();
 
-    let schemas = read_subdir_configs::<String, Schema>(&dir.join(SCHEMA_DIRNAME))
-        .await?
-        .unwrap_or_default();
+    let options = parse_configuration_options_file(dir).await?;
+
+    let schemas = read_schema_directory(dir).await?;
+    let schemas = if options.namespace_object_types {
+        namespace_object_types(schemas)
+    } else {
+        let conflicts = conflicting_object_type_names(&schemas);
+        ensure!(
+            conflicts.is_empty(),
+            "schema files contain conflicting definitions for object types: {}; set the `namespaceObjectTypes` option or run the `namespace-object-types` command to resolve",
+            conflicts.iter().join(", ")
+        );
+        schemas
+    };
     let schema = schemas.into_values().fold(Schema::default(), Schema::merge);
 
     // Deprecated see message above at NATIVE_PROCEDURES_DIRNAME
@@ -63,16 +89,36 @@ pub async fn read_directory(
         .await?
         .unwrap_or_default();
 
-    let options = parse_configuration_options_file(dir).await;
-
     native_mutations.extend(native_procedures.into_iter());
 
     Configuration::validate(schema, native_mutations, native_queries, options)
 }
 
+/// Read schema files, keyed by the collection name given in each file
+pub async fn read_schema_directory(
+    configuration_dir: impl AsRef<Path>,
+) -> anyhow::Result<BTreeMap<String, Schema>> {
+    let dir = configuration_dir.as_ref();
+    Ok(read_subdir_configs(&dir.join(SCHEMA_DIRNAME))
+        .await?
+        .unwrap_or_default())
+}
+
+/// Read data migration files, keyed by the name given in each file. Migrations are not part of
+/// the connector configuration; they are applied by the `migrate` CLI command.
+pub async fn read_migrations_directory(
+    configuration_dir: impl AsRef<Path>,
+) -> anyhow::Result<BTreeMap<String, serialized::Migration>> {
+    let dir = configuration_dir.as_ref();
+    Ok(read_subdir_configs(&dir.join(MIGRATIONS_DIRNAME))
+        .await?
+        .unwrap_or_default())
+}
+
 /// Parse all files in a directory with one of the allowed configuration extensions according to
 /// the given type argument. For example if `T` is `NativeMutation` this function assumes that all
-/// json and yaml files in the given directory should be parsed as native mutation configurations.
+/// json, json5, and yaml files in the given directory should be parsed as native mutation
+/// configurations.
 ///
 /// Assumes that every configuration file has a `name` field.
 async fn read_subdir_configs<N, T>(subdir: &Path) -> anyhow::Result<Option<BTreeMap<N, T>>>
@@ -95,17 +141,7 @@ where
             }
 
             let path = dir_entry.path();
-            let extension = path.extension().and_then(|ext| ext.to_str());
-
-            let format_option = extension
-                .and_then(|ext| {
-                    CONFIGURATION_EXTENSIONS
-                        .iter()
-                        .find(|(expected_ext, _)| ext == *expected_ext)
-                })
-                .map(|(_, format)| *format);
-
-            Ok(format_option.map(|format| (path, format)))
+            Ok(file_format(&path).map(|format| (path, format)))
         })
         .and_then(|(path, format)| async move {
             parse_config_file::<WithName<N, T>>(path, format).await
@@ -129,40 +165,201 @@ where
     }
 }
 
-pub async fn parse_configuration_options_file(dir: &Path) -> ConfigurationOptions {
-    let json_filename = CONFIGURATION_OPTIONS_BASENAME.to_owned() + ".json";
-    let json_config_file = parse_config_file(&dir.join(json_filename), JSON).await;
-    if let Ok(config_options) = json_config_file {
-        return config_options;
-    }
-
-    let yaml_filename = CONFIGURATION_OPTIONS_BASENAME.to_owned() + ".yaml";
-    let yaml_config_file = parse_config_file(&dir.join(yaml_filename), YAML).await;
-    if let Ok(config_options) = yaml_config_file {
-        return config_options;
+/// Reads the configuration options file. If there is no options file the defaults are used, and
+/// are written to a new file. An options file that exists but cannot be parsed is an error because
+/// options include safety switches, such as read-only mode, that must not silently fall back to
+/// their defaults.
+pub async fn parse_configuration_options_file(dir: &Path) -> anyhow::Result<ConfigurationOptions> {
+    for (extension, format) in [("json", JSON), ("json5", JSON5), ("yaml", YAML)] {
+        let path = dir.join(format!("{CONFIGURATION_OPTIONS_BASENAME}.{extension}"));
+        if fs::try_exists(&path).await? {
+            return parse_config_file(&path, format).await;
+        }
     }
 
     // If a configuration file does not exist use defaults and write the file
     let defaults: ConfigurationOptions = Default::default();
     let _ = write_file(dir, CONFIGURATION_OPTIONS_BASENAME, &defaults).await;
     let _ = write_config_metadata_file(dir).await;
-    defaults
+    Ok(defaults)
 }
 
+fn file_format(path: &Path) -> Option<FileFormat> {
+    let extension = path.extension().and_then(|ext| ext.to_str())?;
+    CONFIGURATION_EXTENSIONS
+        .iter()
+        .find(|(expected_ext, _)| extension == *expected_ext)
+        .map(|(_, format)| *format)
+}
+
+/// Parses a configuration file, and upgrades it to the current format version before
+/// deserializing.
 async fn parse_config_file<T>(path: impl AsRef<Path>, format: FileFormat) -> anyhow::Result<T>
 where
     for<'a> T: Deserialize<'a>,
 {
-    let bytes = fs::read(path.as_ref()).await?;
-    let value = match format {
-        FileFormat::Json => serde_json::from_slice(&bytes)
-            .with_context(|| format!("error parsing {:?}", path.as_ref()))?,
-        FileFormat::Yaml => serde_yaml::from_slice(&bytes)
-            .with_context(|| format!("error parsing {:?}", path.as_ref()))?,
-    };
+    let value = read_unversioned_value(path.as_ref(), format).await?;
+    let value = format_version::migrate(value)
+        .and_then(|value| Ok(serde_json::from_value(value)?))
+        .with_context(|| format!("error parsing {:?}", path.as_ref()))?;
     Ok(value)
 }
 
+/// Parses a configuration file without upgrading or deserializing it. Syntax errors report the
+/// file, line, and column in the same form for every file format.
+async fn read_unversioned_value(path: &Path, format: FileFormat) -> anyhow::Result<Value> {
+    let bytes = fs::read(path)
+        .await
+        .with_context(|| format!("error reading {:?}", path))?;
+    parse_value(&bytes, format).map_err(|err| match err.location {
+        Some((line, column)) => anyhow!(
+            "error parsing {:?} at line {line}, column {column}: {}",
+            path,
+            err.message
+        ),
+        None => anyhow!("error parsing {:?}: {}", path, err.message),
+    })
+}
+
+#[derive(Debug)]
+struct ParseError {
+    message: String,
+    /// One-based line and column
+    location: Option<(usize, usize)>,
+}
+
+impl ParseError {
+    /// Parser messages for JSON and YAML end with the error location. That is removed from the
+    /// message since it is reported separately.
+    fn new(message: impl ToString, location: Option<(usize, usize)>) -> Self {
+        let message = message.to_string();
+        let message = match location {
+            Some((line, column)) => message
+                .strip_suffix(&format!(" at line {line} column {column}"))
+                .map(ToOwned::to_owned)
+                .unwrap_or(message),
+            None => message,
+        };
+        ParseError { message, location }
+    }
+}
+
+fn parse_value(bytes: &[u8], format: FileFormat) -> Result<Value, ParseError> {
+    match format {
+        FileFormat::Json => serde_json::from_slice(bytes)
+            .map_err(|err| ParseError::new(&err, Some((err.line(), err.column())))),
+        FileFormat::Json5 => {
+            let text = std::str::from_utf8(bytes).map_err(|err| ParseError::new(err, None))?;
+            json5::from_str(text).map_err(|err| {
+                let location = match &err {
+                    json5::Error::Message { location, .. } => location
+                        .as_ref()
+                        .map(|location| (location.line, location.column)),
+                };
+                ParseError::new(err, location)
+            })
+        }
+        FileFormat::Yaml => serde_yaml::from_slice(bytes).map_err(|err| {
+            let location = err
+                .location()
+                .map(|location| (location.line(), location.column()));
+            ParseError::new(err, location)
+        }),
+    }
+}
+
+/// Identifies the type of a configuration file by the directory that contains it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FileKind {
+    Options,
+    Schema,
+    NativeMutation,
+    NativeQuery,
+}
+
+/// Lists configuration files with one of the allowed configuration extensions, sorted by path
+async fn configuration_files(
+    configuration_dir: &Path,
+) -> anyhow::Result<Vec<(PathBuf, FileKind, FileFormat)>> {
+    let mut paths: Vec<(PathBuf, FileKind)> = CONFIGURATION_EXTENSIONS
+        .iter()
+        .map(|(ext, _)| {
+            let path = configuration_dir.join(format!("{CONFIGURATION_OPTIONS_BASENAME}.{ext}"));
+            (path, FileKind::Options)
+        })
+        .collect();
+    for (subdir, kind) in [
+        (SCHEMA_DIRNAME, FileKind::Schema),
+        (NATIVE_MUTATIONS_DIRNAME, FileKind::NativeMutation),
+        (NATIVE_PROCEDURES_DIRNAME, FileKind::NativeMutation),
+        (NATIVE_QUERIES_DIRNAME, FileKind::NativeQuery),
+    ] {
+        let subdir = configuration_dir.join(subdir);
+        if !(fs::try_exists(&subdir).await?) {
+            continue;
+        }
+        let mut entries = fs::read_dir(&subdir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                paths.push((entry.path(), kind));
+            }
+        }
+    }
+    paths.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut files = vec![];
+    for (path, kind) in paths {
+        let Some(format) = file_format(&path) else {
+            continue;
+        };
+        if fs::try_exists(&path).await? {
+            files.push((path, kind, format));
+        }
+    }
+    Ok(files)
+}
+
+/// Reads every configuration file without deserializing it. Values are upgraded to the current
+/// format version.
+pub(crate) async fn read_configuration_values(
+    configuration_dir: impl AsRef<Path>,
+) -> anyhow::Result<Vec<(PathBuf, FileKind, Value)>> {
+    let mut values = vec![];
+    for (path, kind, format) in configuration_files(configuration_dir.as_ref()).await? {
+        let value = read_unversioned_value(&path, format).await?;
+        let value =
+            format_version::migrate(value).with_context(|| format!("error parsing {:?}", path))?;
+        values.push((path, kind, value));
+    }
+    Ok(values)
+}
+
+/// Rewrites configuration files that were written in an older format version in the current
+/// format. Files keep their original file format. Returns the paths of rewritten files.
+pub async fn upgrade_configuration_directory(
+    configuration_dir: impl AsRef<Path>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut upgraded = vec![];
+    for (path, _, format) in configuration_files(configuration_dir.as_ref()).await? {
+        let value = read_unversioned_value(&path, format).await?;
+        if format_version::file_version(&value)? >= format_version::CURRENT_VERSION {
+            continue;
+        }
+        let value = format_version::migrate(value)
+            .with_context(|| format!("error upgrading {:?}", path))?;
+        let bytes = match format {
+            // JSON is valid JSON5
+            FileFormat::Json | FileFormat::Json5 => serde_json::to_vec_pretty(&value)?,
+            FileFormat::Yaml => serde_yaml::to_string(&value)?.into_bytes(),
+        };
+        fs::write(&path, bytes)
+            .await
+            .with_context(|| format!("error writing {:?}", path))?;
+        upgraded.push(path);
+    }
+    Ok(upgraded)
+}
+
 async fn write_subdir_configs<T>(
     subdir: &Path,
     configs: impl IntoIterator<Item = (String, T)>,
@@ -204,7 +401,8 @@ where
     T: Serialize,
 {
     let path = default_file_path(configuration_dir, basename);
-    let bytes = serde_json::to_vec_pretty(value)?;
+    let value = format_version::with_current_version(serde_json::to_value(value)?);
+    let bytes = serde_json::to_vec_pretty(&value)?;
 
     // Don't write the file if it hasn't changed.
     if let Ok(existing_bytes) = fs::read(&path).await {
@@ -220,12 +418,8 @@ where
 pub async fn list_existing_schemas(
     configuration_dir: impl AsRef<Path>,
 ) -> anyhow::Result<HashSet<String>> {
-    let dir = configuration_dir.as_ref();
-
     // TODO: we don't really need to read and parse all the schema files here, just get their names.
-    let schemas = read_subdir_configs::<_, Schema>(&dir.join(SCHEMA_DIRNAME))
-        .await?
-        .unwrap_or_default();
+    let schemas = read_schema_directory(configuration_dir).await?;
 
     Ok(schemas.into_keys().collect())
 }
@@ -270,3 +464,105 @@ pub async fn get_config_file_changed(dir: impl AsRef<Path>) -> anyhow::Result<bo
         _ => Ok(true),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use tokio::fs;
+
+    use crate::ConnectorMode;
+
+    use super::{parse_configuration_options_file, parse_value, FileFormat};
+
+    /// Creates an empty directory for a test that reads configuration files
+    async fn test_dir(name: &str) -> anyhow::Result<PathBuf> {
+        let dir =
+            std::env::temp_dir().join(format!("configuration-test-{name}-{}", std::process::id()));
+        if fs::try_exists(&dir).await? {
+            fs::remove_dir_all(&dir).await?;
+        }
+        fs::create_dir_all(&dir).await?;
+        Ok(dir)
+    }
+
+    #[tokio::test]
+    async fn rejects_options_file_that_does_not_parse() -> anyhow::Result<()> {
+        let dir = test_dir("invalid-options").await?;
+        let options_file = dir.join("configuration.json");
+        let contents = r#"{ "mode": "readonly" }"#;
+        fs::write(&options_file, contents).await?;
+
+        assert!(parse_configuration_options_file(&dir).await.is_err());
+        // The file is not replaced with defaults
+        assert_eq!(fs::read_to_string(&options_file).await?, contents);
+
+        fs::write(&options_file, r#"{ "mode": "readOnly" }"#).await?;
+        let options = parse_configuration_options_file(&dir).await?;
+        assert_eq!(options.mode, ConnectorMode::ReadOnly);
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_options_file_errors_without_replacing_the_file() -> anyhow::Result<()> {
+        let dir = test_dir("options-errors").await?;
+        let options_file = dir.join("configuration.json5");
+
+        let syntax_error = "{\n  mode: 'readOnly',\n  queryOptions: {]\n}";
+        fs::write(&options_file, syntax_error).await?;
+        let err = parse_configuration_options_file(&dir).await.unwrap_err();
+        assert!(err.to_string().contains("at line 3"), "{err}");
+        assert_eq!(fs::read_to_string(&options_file).await?, syntax_error);
+
+        let future_version = "{ version: 999, mode: 'readOnly' }";
+        fs::write(&options_file, future_version).await?;
+        let err = parse_configuration_options_file(&dir).await.unwrap_err();
+        assert!(format!("{err:#}").contains("version 999"), "{err:#}");
+        assert_eq!(fs::read_to_string(&options_file).await?, future_version);
+        assert!(!fs::try_exists(dir.join("configuration.json")).await?);
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_default_options_file_when_there_is_none() -> anyhow::Result<()> {
+        let dir = test_dir("missing-options").await?;
+        let options = parse_configuration_options_file(&dir).await?;
+        assert_eq!(options.mode, ConnectorMode::ReadWrite);
+        assert!(fs::try_exists(dir.join("configuration.json")).await?);
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn reports_parse_error_locations_consistently() -> anyhow::Result<()> {
+        let json5 = b"{\n  // pipelines may have comments\n  name: 'movies',\n  pipeline: [{ $match: {} },],\n}";
+        assert_eq!(
+            parse_value(json5, FileFormat::Json5).map_err(|err| anyhow::anyhow!(err.message))?,
+            json!({ "name": "movies", "pipeline": [{ "$match": {} }] })
+        );
+
+        for (format, text) in [
+            (
+                FileFormat::Json,
+                "{\n  \"name\": \"movies\",\n  \"pipeline\": [}\n}",
+            ),
+            (FileFormat::Json5, "{\n  name: 'movies',\n  pipeline: [}\n}"),
+            (FileFormat::Yaml, "name: movies\npipeline: [\n  }\n"),
+        ] {
+            let err = parse_value(text.as_bytes(), format).unwrap_err();
+            let (line, _) = err
+                .location
+                .unwrap_or_else(|| panic!("expected a location for {format:?}: {err:?}"));
+            assert_eq!(line, 3, "{format:?}: {}", err.message);
+            assert!(!err.message.contains(" at line "), "{}", err.message);
+        }
+        Ok(())
+    }
+}
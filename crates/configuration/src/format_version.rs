@@ -0,0 +1,93 @@
+//! Configuration files record the format they were written in with a top-level `version` field.
+//! Files are upgraded to the current format when they are read, so a connector release that
+//! changes the format still accepts files written by earlier releases. Files without a `version`
+//! field predate versioning, and are treated as version 0. The `upgrade-configuration` CLI command
+//! rewrites files in the current format.
+//!
+//! To change the format, bump [CURRENT_VERSION] and append a migration to [MIGRATIONS] that
+//! rewrites a file from the previous version.
+
+use anyhow::{anyhow, bail};
+use serde_json::{Map, Value};
+
+pub const VERSION_FIELD: &str = "version";
+
+/// The format version that the connector writes
+pub const CURRENT_VERSION: u64 = 1;
+
+/// Rewrites the fields of a file from the format of one version to the format of the next
+type Migration = fn(&mut Map<String, Value>) -> anyhow::Result<()>;
+
+/// The migration at each index upgrades a file from that version to the next version.
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [
+    // Version 1 introduced the `version` field, and is otherwise the same as the unversioned
+    // format.
+    |_| Ok(()),
+];
+
+/// Reads the format version of a parsed configuration file
+pub fn file_version(value: &Value) -> anyhow::Result<u64> {
+    match value.get(VERSION_FIELD) {
+        None => Ok(0),
+        Some(version) => version.as_u64().ok_or_else(|| {
+            anyhow!("the {VERSION_FIELD} field must be a non-negative integer, but it is {version}")
+        }),
+    }
+}
+
+/// Upgrades a parsed configuration file to the current format. Fails if the file was written by a
+/// newer release of the connector.
+pub fn migrate(value: Value) -> anyhow::Result<Value> {
+    let version = file_version(&value)?;
+    if version > CURRENT_VERSION {
+        bail!("the file has format version {version}, but this release of the connector reads versions up to {CURRENT_VERSION}; upgrade the connector to read it");
+    }
+    let Value::Object(mut fields) = value else {
+        bail!("expected a configuration file to contain an object");
+    };
+    for (from_version, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(&mut fields)
+            .map_err(|err| anyhow!("error upgrading from format version {from_version}: {err}"))?;
+    }
+    Ok(with_current_version(Value::Object(fields)))
+}
+
+/// Sets the `version` field of a serialized configuration file to the current version, and moves
+/// it to the top of the file.
+pub fn with_current_version(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => {
+            let mut versioned = Map::new();
+            versioned.insert(VERSION_FIELD.to_owned(), CURRENT_VERSION.into());
+            versioned.extend(fields.into_iter().filter(|(key, _)| key != VERSION_FIELD));
+            Value::Object(versioned)
+        }
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::{file_version, migrate, CURRENT_VERSION};
+
+    #[test]
+    fn upgrades_unversioned_files_and_rejects_newer_files() -> anyhow::Result<()> {
+        let unversioned = json!({ "name": "movies", "collections": {} });
+        assert_eq!(file_version(&unversioned)?, 0);
+
+        let migrated = migrate(unversioned)?;
+        assert_eq!(file_version(&migrated)?, CURRENT_VERSION);
+        assert_eq!(
+            migrated,
+            json!({ "version": CURRENT_VERSION, "name": "movies", "collections": {} })
+        );
+
+        let newer = json!({ "version": CURRENT_VERSION + 1, "name": "movies" });
+        assert!(migrate(newer).is_err());
+        assert!(migrate(json!({ "version": "one" })).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Histogram functions are generated for histograms that collections declare in schema files. Each
+//! is backed by a generated native query with collection representation that produces one
+//! document per bucket with the bucket's bounds and the number of documents in it.
+//!
+//! Bounds are converted to doubles so that the result type does not depend on the type of the
+//! bucketed field.
+
+use anyhow::ensure;
+use mongodb::bson::{doc, Bson};
+use mongodb_support::BsonScalarType;
+use ndc_models as ndc;
+
+use crate::{
+    native_query::NativeQueryRepresentation,
+    schema::{Histogram, ObjectField, ObjectType, Type},
+    serialized,
+};
+
+pub const BUCKETS_ARGUMENT: &str = "buckets";
+
+/// Produces a native query for each histogram of each collection in the given schema
+pub fn histogram_functions(
+    schema: &serialized::Schema,
+) -> anyhow::Result<Vec<(ndc::FunctionName, serialized::NativeQuery)>> {
+    schema
+        .collections
+        .iter()
+        .flat_map(|(collection_name, collection)| {
+            collection
+                .histograms
+                .iter()
+                .map(move |histogram| histogram_function(collection_name, histogram))
+        })
+        .collect()
+}
+
+fn histogram_function(
+    collection_name: &ndc::CollectionName,
+    histogram: &Histogram,
+) -> anyhow::Result<(ndc::FunctionName, serialized::NativeQuery)> {
+    let field = histogram.field.as_str();
+    let name = format!("{collection_name}_{field}_histogram");
+    let result_type_name = format!("{name}_bucket");
+    let field_path = format!("${field}");
+
+    let (pipeline, arguments) = match &histogram.boundaries {
+        Some(boundaries) => {
+            ensure!(
+                boundaries.len() >= 2,
+                "the histogram, {name}, must have at least two boundaries"
+            );
+            ensure!(
+                boundaries
+                    .windows(2)
+                    .all(|pair| pair[0].as_f64() < pair[1].as_f64()),
+                "the boundaries of the histogram, {name}, must be in ascending order"
+            );
+            let boundaries: Vec<Bson> = boundaries.iter().map(number_to_bson).collect();
+            let lower = &boundaries[0];
+            let upper = &boundaries[boundaries.len() - 1];
+            let upper_bound_of_bucket = doc! {
+                "$arrayElemAt": [
+                    { "$literal": boundaries.clone() },
+                    { "$add": [{ "$indexOfArray": [{ "$literal": boundaries.clone() }, "$_id"] }, 1] },
+                ]
+            };
+            let pipeline = vec![
+                doc! { "$match": { field: { "$gte": lower.clone(), "$lt": upper.clone() } } },
+                doc! { "$bucket": { "groupBy": field_path.as_str(), "boundaries": boundaries.clone() } },
+                doc! {
+                    "$replaceWith": {
+                        "min": { "$toDouble": "$_id" },
+                        "max": { "$toDouble": upper_bound_of_bucket },
+                        "count": "$count",
+                    }
+                },
+            ];
+            (pipeline, Default::default())
+        }
+        None => {
+            let pipeline = vec![
+                doc! { "$match": { field: { "$type": "number" } } },
+                doc! {
+                    "$bucketAuto": {
+                        "groupBy": field_path.as_str(),
+                        "buckets": format!("{{{{ {BUCKETS_ARGUMENT} }}}}"),
+                    }
+                },
+                doc! {
+                    "$replaceWith": {
+                        "min": { "$toDouble": "$_id.min" },
+                        "max": { "$toDouble": "$_id.max" },
+                        "count": "$count",
+                    }
+                },
+            ];
+            let (argument_name, argument) =
+                ObjectField::new(BUCKETS_ARGUMENT, Type::Scalar(BsonScalarType::Int));
+            (pipeline, [(argument_name.into(), argument)].into())
+        }
+    };
+
+    let result_type = ObjectType {
+        fields: [
+            ObjectField::new("min", Type::Scalar(BsonScalarType::Double)),
+            ObjectField::new("max", Type::Scalar(BsonScalarType::Double)),
+            ObjectField::new("count", Type::Scalar(BsonScalarType::Int)),
+        ]
+        .into_iter()
+        .map(|(name, field)| (name.into(), field))
+        .collect(),
+        description: Some(format!("A bucket of the {name} function")),
+        extends: Default::default(),
+    };
+
+    let native_query = serialized::NativeQuery {
+        representation: NativeQueryRepresentation::Collection,
+        input_collection: Some(collection_name.clone()),
+        arguments,
+        result_document_type: result_type_name.clone().into(),
+        object_types: [(result_type_name.into(), result_type)].into(),
+        pipeline,
+        materialized: None,
+        description: Some(format!(
+            "Counts documents in the {collection_name} collection by buckets of {field}"
+        )),
+    };
+    Ok((name.into(), native_query))
+}
+
+fn number_to_bson(number: &serde_json::Number) -> Bson {
+    match number.as_i64() {
+        Some(n) => Bson::Int64(n),
+        None => Bson::Double(number.as_f64().unwrap_or(f64::NAN)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    use crate::schema::Histogram;
+
+    use super::histogram_function;
+
+    #[test]
+    fn generates_bucket_pipeline_for_explicit_boundaries() -> anyhow::Result<()> {
+        let histogram = Histogram {
+            field: "runtime".into(),
+            boundaries: Some(vec![0.into(), 60.into(), 120.into()]),
+        };
+        let (name, native_query) = histogram_function(&"movies".into(), &histogram)?;
+        assert_eq!(name, "movies_runtime_histogram".into());
+        assert!(native_query.arguments.is_empty());
+        assert_eq!(
+            native_query.pipeline,
+            vec![
+                doc! { "$match": { "runtime": { "$gte": 0_i64, "$lt": 120_i64 } } },
+                doc! {
+                    "$bucket": { "groupBy": "$runtime", "boundaries": [0_i64, 60_i64, 120_i64] }
+                },
+                doc! {
+                    "$replaceWith": {
+                        "min": { "$toDouble": "$_id" },
+                        "max": {
+                            "$toDouble": {
+                                "$arrayElemAt": [
+                                    { "$literal": [0_i64, 60_i64, 120_i64] },
+                                    {
+                                        "$add": [
+                                            { "$indexOfArray": [{ "$literal": [0_i64, 60_i64, 120_i64] }, "$_id"] },
+                                            1,
+                                        ]
+                                    },
+                                ]
+                            }
+                        },
+                        "count": "$count",
+                    }
+                },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! A hybrid search collection is generated for each collection that declares `hybridSearch` in
+//! its schema file. The generated native query runs a vector branch with `$vectorSearch`, and
+//! a full-text branch with `$search` inside `$unionWith`. Each branch ranks its results, and
+//! documents are grouped by `_id` so that a document found by both branches gets both scores.
+//! Reciprocal rank fusion only uses ranks so the scores of the two kinds of search do not need to
+//! be comparable.
+
+use anyhow::anyhow;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb_support::BsonScalarType;
+use ndc_models as ndc;
+
+use crate::{
+    native_query::NativeQueryRepresentation,
+    schema::{HybridSearch, ObjectType, Type},
+    serialized,
+    vector_search::{
+        argument, embedding_argument, k_argument, placeholder, scored_result_type,
+        vector_search_spec, K_ARGUMENT, SCORE_FIELD,
+    },
+};
+
+pub const QUERY_ARGUMENT: &str = "query";
+
+const DEFAULT_RANK_CONSTANT: u32 = 60;
+
+/// Produces a native query for each collection in the given schema that declares hybrid search
+pub fn hybrid_search_collections(
+    schema: &serialized::Schema,
+) -> anyhow::Result<Vec<(ndc::FunctionName, serialized::NativeQuery)>> {
+    schema
+        .collections
+        .iter()
+        .filter_map(|(collection_name, collection)| {
+            let hybrid_search = collection.hybrid_search.as_ref()?;
+            Some((collection_name, collection, hybrid_search))
+        })
+        .map(|(collection_name, collection, hybrid_search)| {
+            let object_type = schema.object_types.get(&collection.r#type).ok_or_else(|| {
+                anyhow!(
+                    "the collection, {collection_name}, has an unknown type: {}",
+                    collection.r#type
+                )
+            })?;
+            hybrid_search_collection(collection_name, object_type, hybrid_search)
+        })
+        .collect()
+}
+
+fn hybrid_search_collection(
+    collection_name: &ndc::CollectionName,
+    collection_type: &ObjectType,
+    hybrid_search: &HybridSearch,
+) -> anyhow::Result<(ndc::FunctionName, serialized::NativeQuery)> {
+    let name = format!("{collection_name}_hybrid_search");
+    let result_type_name = format!("{name}_result");
+    let result_type = scored_result_type(collection_name, collection_type, &name)?;
+
+    let rank_constant = hybrid_search.rank_constant.unwrap_or(DEFAULT_RANK_CONSTANT);
+    let weight = |weight: &Option<serde_json::Number>| {
+        weight
+            .as_ref()
+            .and_then(|weight| weight.as_f64())
+            .unwrap_or(1.0)
+    };
+
+    let text_paths: Vec<&str> = hybrid_search
+        .text_paths
+        .iter()
+        .map(|path| path.as_str())
+        .collect();
+    let text_branch: Vec<Document> = [
+        doc! {
+            "$search": {
+                "index": hybrid_search.text_index.as_str(),
+                "text": { "query": placeholder(QUERY_ARGUMENT), "path": text_paths },
+            }
+        },
+        doc! { "$limit": placeholder(K_ARGUMENT) },
+    ]
+    .into_iter()
+    .chain(rank_stages(
+        "text_score",
+        weight(&hybrid_search.text_weight),
+        rank_constant,
+    ))
+    .collect();
+
+    let pipeline = [doc! { "$vectorSearch": vector_search_spec(&hybrid_search.vector) }]
+        .into_iter()
+        .chain(rank_stages(
+            "vector_score",
+            weight(&hybrid_search.vector_weight),
+            rank_constant,
+        ))
+        .chain([
+            doc! {
+                "$unionWith": { "coll": collection_name.as_str(), "pipeline": text_branch }
+            },
+            doc! {
+                "$group": {
+                    "_id": "$_id",
+                    "doc": { "$first": "$doc" },
+                    "vector_score": { "$max": "$vector_score" },
+                    "text_score": { "$max": "$text_score" },
+                }
+            },
+            doc! {
+                "$replaceWith": {
+                    "$mergeObjects": [
+                        "$doc",
+                        {
+                            SCORE_FIELD: {
+                                "$add": [
+                                    { "$ifNull": ["$vector_score", 0] },
+                                    { "$ifNull": ["$text_score", 0] },
+                                ]
+                            }
+                        },
+                    ]
+                }
+            },
+            doc! { "$sort": { SCORE_FIELD: -1, "_id": 1 } },
+            doc! { "$limit": placeholder(K_ARGUMENT) },
+        ])
+        .collect();
+
+    let arguments = [
+        argument(
+            QUERY_ARGUMENT,
+            Type::Scalar(BsonScalarType::String),
+            "Text to search for",
+        ),
+        embedding_argument(),
+        k_argument(),
+    ]
+    .into();
+
+    let native_query = serialized::NativeQuery {
+        representation: NativeQueryRepresentation::Collection,
+        input_collection: Some(collection_name.clone()),
+        arguments,
+        result_document_type: result_type_name.clone().into(),
+        object_types: [(result_type_name.into(), result_type)].into(),
+        pipeline,
+        materialized: None,
+        description: Some(format!(
+            "Documents in the {collection_name} collection that best match both a text query and an embedding"
+        )),
+    };
+    Ok((name.into(), native_query))
+}
+
+/// Replaces the documents produced by a search branch with documents of the form `{ _id, doc,
+/// <score_field> }` where the score is computed from the position of each document in the branch
+/// results
+fn rank_stages(score_field: &str, weight: f64, rank_constant: u32) -> [Document; 3] {
+    [
+        doc! { "$group": { "_id": Bson::Null, "docs": { "$push": "$$ROOT" } } },
+        doc! { "$unwind": { "path": "$docs", "includeArrayIndex": "rank" } },
+        doc! {
+            "$project": {
+                "_id": "$docs._id",
+                "doc": "$docs",
+                score_field: {
+                    "$divide": [weight, { "$add": ["$rank", rank_constant as i64 + 1] }]
+                },
+            }
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    use super::rank_stages;
+
+    #[test]
+    fn ranks_branch_results_from_one() {
+        assert_eq!(
+            rank_stages("text_score", 0.5, 60),
+            [
+                doc! { "$group": { "_id": null, "docs": { "$push": "$$ROOT" } } },
+                doc! { "$unwind": { "path": "$docs", "includeArrayIndex": "rank" } },
+                doc! {
+                    "$project": {
+                        "_id": "$docs._id",
+                        "doc": "$docs",
+                        "text_score": { "$divide": [0.5, { "$add": ["$rank", 61_i64] }] },
+                    }
+                },
+            ]
+        );
+    }
+}
@@ -1,18 +1,55 @@
+pub mod collection_arguments;
+pub mod collection_policies;
 mod configuration;
 mod directory;
+mod format_version;
+mod histogram;
+mod hybrid_search;
+pub mod lookup_function;
 mod mongo_scalar_type;
 pub mod native_mutation;
 pub mod native_query;
+mod object_type_composition;
+pub mod placeholders;
+pub mod pushdown;
+pub mod raw_pipeline;
+mod relationships;
 pub mod schema;
+mod schema_namespacing;
 pub mod serialized;
+mod soft_delete;
+mod strict;
+pub mod system_collections;
+mod system_native_queries;
+mod vector_search;
+mod versioning;
+mod window_fields;
 mod with_name;
+mod write_rules;
 
-pub use crate::configuration::Configuration;
+pub use crate::configuration::{
+    AggregateFunctionOptions, Configuration, ConfigurationAuditOptions,
+    ConfigurationCollectionConcurrencyOptions, ConfigurationCollectionStatsOptions,
+    ConfigurationCursorBatchSizeOptions, ConfigurationExplainOptions,
+    ConfigurationIdempotencyOptions, ConfigurationLargeDocumentOptions, ConfigurationOptions,
+    ConfigurationQueryBatchingOptions, ConfigurationQueryLogOptions, ConfigurationQueryOptions,
+    ConfigurationRawPipelineOptions, ConfigurationRecordingOptions, ConfigurationRegexOptions,
+    ConfigurationSerializationOptions, ConfigurationShutdownOptions, ConfigurationTenancyOptions,
+    ConfigurationWarmUpOptions, ConnectorMode, DiagramFormat, NonFiniteNumberPolicy,
+    ObjectIdFormats, QueryLogSink, RecordingMode, RowErrorPolicy, TenantDatabasePattern,
+};
 pub use crate::directory::get_config_file_changed;
 pub use crate::directory::list_existing_schemas;
 pub use crate::directory::parse_configuration_options_file;
 pub use crate::directory::read_directory;
+pub use crate::directory::read_migrations_directory;
+pub use crate::directory::read_schema_directory;
+pub use crate::directory::upgrade_configuration_directory;
 pub use crate::directory::write_schema_directory;
 pub use crate::mongo_scalar_type::MongoScalarType;
+pub use crate::schema_namespacing::{conflicting_object_type_names, namespace_object_types};
 pub use crate::serialized::Schema;
+pub use crate::strict::strict_validation;
+pub use crate::system_native_queries::collection_stats_pipeline;
 pub use crate::with_name::{WithName, WithNameRef};
+pub use crate::write_rules::ArgumentWriteRules;
@@ -0,0 +1,202 @@
+//! Lookup functions are generated when the `lookupFunctions` option is set. For each uniqueness
+//! constraint of each collection in the schema there is a function, such as `movies_by_id`, that
+//! takes the unique fields as arguments and returns the matching document or null.
+//!
+//! Each lookup function is backed by a generated native query so that it works anywhere a native
+//! query function does. But when a request only selects fields of the returned document the
+//! connector runs a `find` with a limit of 1 instead of an aggregation pipeline.
+
+use std::collections::BTreeMap;
+
+use mongodb::bson::{doc, Document};
+use ndc_models as ndc;
+
+use crate::{
+    configuration::get_primary_key_uniqueness_constraint,
+    native_query::NativeQueryRepresentation,
+    schema::{ObjectField, ObjectType, Type},
+    serialized,
+};
+
+#[derive(Clone, Debug)]
+pub struct LookupFunction {
+    pub collection: ndc::CollectionName,
+
+    /// Maps each function argument to the unique field that it is matched against
+    pub key: BTreeMap<ndc::ArgumentName, ndc::FieldName>,
+}
+
+impl LookupFunction {
+    /// Filter document that selects the document with the given key values
+    pub fn filter(
+        &self,
+        arguments: impl Fn(&ndc::ArgumentName) -> mongodb::bson::Bson,
+    ) -> Document {
+        self.key
+            .iter()
+            .map(|(argument, field)| (field.to_string(), arguments(argument)))
+            .collect()
+    }
+}
+
+/// Produces a lookup function, and the native query that backs it, for each uniqueness constraint
+/// of each collection in the given schema.
+pub fn lookup_functions(
+    schema: &serialized::Schema,
+) -> Vec<(ndc::FunctionName, LookupFunction, serialized::NativeQuery)> {
+    schema
+        .collections
+        .iter()
+        .flat_map(|(collection_name, collection)| {
+            let object_type = schema.object_types.get(&collection.r#type)?;
+            let (_, constraint) = get_primary_key_uniqueness_constraint(
+                &schema.object_types,
+                collection_name,
+                &collection.r#type,
+            )?;
+            let key_fields = constraint
+                .unique_columns
+                .into_iter()
+                .map(|field_name| {
+                    let field = object_type.fields.get(&field_name)?;
+                    let argument_name: ndc::ArgumentName = field_name
+                        .as_str()
+                        .trim_start_matches('_')
+                        .to_owned()
+                        .into();
+                    Some((argument_name, field_name, field.r#type.clone()))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(lookup_function(
+                collection_name,
+                &collection.r#type,
+                key_fields,
+            ))
+        })
+        .collect()
+}
+
+fn lookup_function(
+    collection_name: &ndc::CollectionName,
+    collection_type: &ndc::ObjectTypeName,
+    key_fields: Vec<(ndc::ArgumentName, ndc::FieldName, Type)>,
+) -> (ndc::FunctionName, LookupFunction, serialized::NativeQuery) {
+    let name = format!(
+        "{collection_name}_by_{}",
+        key_fields
+            .iter()
+            .map(|(argument_name, _, _)| argument_name.as_str())
+            .collect::<Vec<_>>()
+            .join("_and_")
+    );
+    let result_type_name = format!("{name}_result");
+
+    let lookup_function = LookupFunction {
+        collection: collection_name.clone(),
+        key: key_fields
+            .iter()
+            .map(|(argument_name, field_name, _)| (argument_name.clone(), field_name.clone()))
+            .collect(),
+    };
+
+    // The $facet stage always produces exactly one document, so the function produces a null
+    // value instead of an empty result when there is no matching document.
+    let filter = lookup_function.filter(|argument| format!("{{{{ {argument} }}}}").into());
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! { "$limit": 1 },
+        doc! { "$facet": { "__value": [{ "$limit": 1 }] } },
+        doc! { "$replaceWith": { "__value": { "$arrayElemAt": ["$__value", 0] } } },
+    ];
+
+    let arguments = key_fields
+        .into_iter()
+        .map(|(argument_name, _, t)| {
+            let (_, field) = ObjectField::new(&argument_name, t);
+            (argument_name, field)
+        })
+        .collect();
+
+    let (value_field_name, value_field) = ObjectField::new(
+        "__value",
+        Type::Nullable(Box::new(Type::Object(collection_type.to_string()))),
+    );
+    let result_type = ObjectType {
+        fields: [(value_field_name.into(), value_field)].into(),
+        description: None,
+        extends: Default::default(),
+    };
+
+    let native_query = serialized::NativeQuery {
+        representation: NativeQueryRepresentation::Function,
+        input_collection: Some(collection_name.clone()),
+        arguments,
+        result_document_type: result_type_name.clone().into(),
+        object_types: [(result_type_name.into(), result_type)].into(),
+        pipeline,
+        materialized: None,
+        description: Some(format!(
+            "Look up a document in the {collection_name} collection by unique key"
+        )),
+    };
+
+    (name.into(), lookup_function, native_query)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use mongodb_support::BsonScalarType;
+
+    use crate::{
+        schema::{Collection, ObjectField, ObjectType, Type},
+        serialized::Schema,
+        Configuration, ConfigurationOptions,
+    };
+
+    #[test]
+    fn generates_lookup_function_for_primary_key() -> anyhow::Result<()> {
+        let schema = Schema {
+            collections: [(
+                "movies".into(),
+                Collection {
+                    r#type: "movies".into(),
+                    description: None,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "movies".into(),
+                ObjectType {
+                    fields: [
+                        ObjectField::new("_id", Type::Scalar(BsonScalarType::ObjectId)),
+                        ObjectField::new("title", Type::Scalar(BsonScalarType::String)),
+                    ]
+                    .into_iter()
+                    .map(|(name, field)| (name.into(), field))
+                    .collect(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        let options = ConfigurationOptions {
+            lookup_functions: true,
+            ..Default::default()
+        };
+        let config =
+            Configuration::validate(schema, Default::default(), Default::default(), options)?;
+
+        let (function_info, _) = &config.functions["movies_by_id"];
+        assert!(function_info.arguments.contains_key("id"));
+        let lookup_function = &config.lookup_functions["movies_by_id"];
+        assert_eq!(lookup_function.collection.as_str(), "movies");
+        assert_eq!(
+            config.native_queries["movies_by_id"].pipeline[0],
+            doc! { "$match": { "_id": "{{ id }}" } }
+        );
+        Ok(())
+    }
+}
@@ -6,7 +6,7 @@ use ndc_models as ndc;
 use ndc_query_plan as plan;
 use plan::{inline_object_types, QueryPlanError};
 
-use crate::{serialized, MongoScalarType};
+use crate::{serialized, ArgumentWriteRules, MongoScalarType};
 
 /// Internal representation of Native Mutations. For doc comments see
 /// [crate::serialized::NativeMutation]
@@ -21,6 +21,14 @@ pub struct NativeMutation {
     pub command: bson::Document,
     pub selection_criteria: Option<SelectionCriteria>,
     pub description: Option<String>,
+
+    /// Set if the command has been rewritten to check the version field of the documents that it
+    /// updates. See [crate::schema::Collection::version_field].
+    pub version_field: Option<ndc::FieldName>,
+
+    /// Normalizations and validation rules that are applied to argument values before they are
+    /// substituted into the command. See [crate::schema::ObjectField::write_rules].
+    pub write_rules: ArgumentWriteRules,
 }
 
 impl NativeMutation {
@@ -55,6 +63,8 @@ impl NativeMutation {
             command: input.command,
             selection_criteria: input.selection_criteria,
             description: input.description,
+            version_field: None,
+            write_rules: Default::default(),
         })
     }
 }
@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, time::Duration};
 
 use itertools::Itertools as _;
 use mongodb::bson;
@@ -6,9 +6,13 @@ use ndc_models as ndc;
 use ndc_query_plan as plan;
 use plan::{inline_object_types, QueryPlanError};
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{serialized, MongoScalarType};
+use crate::{
+    placeholders::{find_placeholders, Placeholder},
+    pushdown::PushdownPoint,
+    serialized, MongoScalarType,
+};
 
 /// Internal representation of Native Queries. For doc comments see
 /// [crate::serialized::NativeQuery]
@@ -23,11 +27,23 @@ pub struct NativeQuery {
     pub arguments: BTreeMap<ndc::ArgumentName, plan::Type<MongoScalarType>>,
     pub result_document_type: ndc::ObjectTypeName,
     pub pipeline: Vec<bson::Document>,
+
+    /// Positions of argument placeholders in `pipeline`, found when configuration is loaded
+    pub placeholders: Vec<Placeholder>,
+
+    /// Positions in `pipeline` where stages from query requests may be injected. See
+    /// [crate::pushdown].
+    pub pushdown_points: Vec<PushdownPoint>,
+
     pub description: Option<String>,
+
+    /// Set if query results are served from a backing collection that is refreshed periodically
+    pub materialized: Option<MaterializedView>,
 }
 
 impl NativeQuery {
     pub fn from_serialized(
+        name: &ndc::FunctionName,
         object_types: &BTreeMap<ndc::ObjectTypeName, ndc::ObjectType>,
         input: serialized::NativeQuery,
     ) -> Result<NativeQuery, QueryPlanError> {
@@ -51,13 +67,56 @@ impl NativeQuery {
             input_collection: input.input_collection,
             arguments,
             result_document_type: input.result_document_type,
+            placeholders: find_placeholders(&input.pipeline),
+            pushdown_points: Default::default(),
             pipeline: input.pipeline,
             description: input.description,
+            materialized: input.materialized.map(|materialization| MaterializedView {
+                collection: materialization
+                    .collection
+                    .unwrap_or_else(|| format!("{name}_materialized").into()),
+                refresh_interval: Duration::from_secs(materialization.refresh_interval_seconds),
+            }),
         })
     }
+
+    /// Removes the `$pushdown` marker stages from the given pipeline stages, for running the
+    /// pipeline outside of a query request where there are no stages to push down. The stages must
+    /// line up with [NativeQuery::pipeline], for example after placeholders have been
+    /// interpolated. MongoDB rejects pipelines that still contain the markers.
+    pub fn without_pushdown_points(&self, stages: Vec<bson::Document>) -> Vec<bson::Document> {
+        stages
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                !self
+                    .pushdown_points
+                    .iter()
+                    .any(|point| point.stage == *index)
+            })
+            .map(|(_, stage)| stage)
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Materialization {
+    /// Name of the collection that stores results. Defaults to `<native query name>_materialized`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<ndc::CollectionName>,
+
+    /// Time between refreshes in seconds
+    pub refresh_interval_seconds: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MaterializedView {
+    pub collection: ndc::CollectionName,
+    pub refresh_interval: Duration,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash, JsonSchema)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub enum NativeQueryRepresentation {
     Collection,
@@ -0,0 +1,165 @@
+//! Object types in configuration files may list other object types in `extends` to include their
+//! fields. This lets groups of fields that appear in many types, such as audit fields or address
+//! blocks, be defined once. Composition is resolved when configuration is loaded so the rest of
+//! the connector only sees object types with a complete list of fields.
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use anyhow::{anyhow, bail};
+use ndc_models as ndc;
+
+use crate::schema::{ObjectField, ObjectType};
+
+/// Replaces each object type that extends other types with a type that declares the inherited
+/// fields directly. Extended types are looked up first in `object_types`, then in `shared_types`.
+///
+/// Fields declared by a type take precedence over inherited fields with the same name. It is an
+/// error for a type to inherit different definitions of the same field from two extended types,
+/// to extend a type that does not exist, or to extend itself directly or indirectly.
+pub fn flatten_object_types(
+    object_types: &mut BTreeMap<ndc::ObjectTypeName, ObjectType>,
+    shared_types: &BTreeMap<ndc::ObjectTypeName, ObjectType>,
+) -> anyhow::Result<()> {
+    let flattened: Vec<(ndc::ObjectTypeName, BTreeMap<ndc::FieldName, ObjectField>)> = object_types
+        .iter()
+        .filter(|(_, object_type)| !object_type.extends.is_empty())
+        .map(|(name, _)| {
+            let fields = resolve_fields(name, object_types, shared_types, &mut vec![])?;
+            Ok((name.clone(), fields))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    for (name, fields) in flattened {
+        if let Some(object_type) = object_types.get_mut(&name) {
+            object_type.fields = fields;
+            object_type.extends = vec![];
+        }
+    }
+    Ok(())
+}
+
+fn resolve_fields(
+    name: &ndc::ObjectTypeName,
+    object_types: &BTreeMap<ndc::ObjectTypeName, ObjectType>,
+    shared_types: &BTreeMap<ndc::ObjectTypeName, ObjectType>,
+    extending: &mut Vec<ndc::ObjectTypeName>,
+) -> anyhow::Result<BTreeMap<ndc::FieldName, ObjectField>> {
+    if extending.contains(name) {
+        bail!("the object type, {name}, extends itself");
+    }
+    let object_type = object_types.get(name).or(shared_types.get(name));
+    let object_type = object_type.ok_or_else(|| match extending.last() {
+        Some(child) => anyhow!("the object type, {child}, extends an unknown type, {name}"),
+        None => anyhow!("there is no object type named {name}"),
+    })?;
+
+    extending.push(name.clone());
+    let mut fields = BTreeMap::new();
+    for parent in &object_type.extends {
+        for (field_name, field) in resolve_fields(parent, object_types, shared_types, extending)? {
+            match fields.entry(field_name) {
+                Entry::Vacant(entry) => {
+                    entry.insert(field);
+                }
+                Entry::Occupied(entry) if entry.get() != &field => bail!(
+                    "the object type, {name}, inherits conflicting definitions of the field, {}",
+                    entry.key()
+                ),
+                Entry::Occupied(_) => (),
+            }
+        }
+    }
+    extending.pop();
+
+    fields.extend(object_type.fields.clone());
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use mongodb_support::BsonScalarType;
+    use pretty_assertions::assert_eq;
+
+    use crate::schema::{ObjectField, ObjectType, Type};
+
+    use super::flatten_object_types;
+
+    fn field(scalar_type: BsonScalarType) -> ObjectField {
+        ObjectField {
+            r#type: Type::Scalar(scalar_type),
+            description: None,
+            deprecated: false,
+            previous_names: Default::default(),
+            read_transform: None,
+            write_rules: None,
+            object_id_format: None,
+        }
+    }
+
+    fn object_type(extends: &[&str], fields: &[(&str, BsonScalarType)]) -> ObjectType {
+        ObjectType {
+            fields: fields
+                .iter()
+                .map(|(name, scalar_type)| ((*name).into(), field(*scalar_type)))
+                .collect(),
+            description: None,
+            extends: extends.iter().map(|name| (*name).into()).collect(),
+        }
+    }
+
+    #[test]
+    fn flattens_extended_object_types() -> anyhow::Result<()> {
+        let audit_fields: BTreeMap<_, _> = [(
+            "audit".into(),
+            object_type(
+                &[],
+                &[
+                    ("createdAt", BsonScalarType::Date),
+                    ("updatedAt", BsonScalarType::Date),
+                ],
+            ),
+        )]
+        .into();
+        let mut object_types: BTreeMap<_, _> = [
+            (
+                "named".into(),
+                object_type(&[], &[("name", BsonScalarType::String)]),
+            ),
+            (
+                "customers".into(),
+                object_type(
+                    &["audit", "named"],
+                    &[
+                        ("_id", BsonScalarType::ObjectId),
+                        ("updatedAt", BsonScalarType::Timestamp),
+                    ],
+                ),
+            ),
+        ]
+        .into();
+        flatten_object_types(&mut object_types, &audit_fields)?;
+
+        // Fields declared by `customers` take precedence over inherited fields
+        assert_eq!(
+            object_types[&"customers".into()],
+            object_type(
+                &[],
+                &[
+                    ("_id", BsonScalarType::ObjectId),
+                    ("createdAt", BsonScalarType::Date),
+                    ("name", BsonScalarType::String),
+                    ("updatedAt", BsonScalarType::Timestamp),
+                ]
+            )
+        );
+
+        let mut cycle: BTreeMap<_, _> = [
+            ("a".into(), object_type(&["b"], &[])),
+            ("b".into(), object_type(&["a"], &[])),
+        ]
+        .into();
+        assert!(flatten_object_types(&mut cycle, &BTreeMap::new()).is_err());
+        Ok(())
+    }
+}
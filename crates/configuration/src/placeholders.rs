@@ -0,0 +1,195 @@
+//! Native queries and native mutations reference arguments using placeholders with the syntax
+//! `{{<argument name>}}`. Native query pipelines are scanned for placeholders when configuration
+//! is loaded so that argument substitution for each request patches values at recorded positions
+//! instead of re-parsing every string in the pipeline.
+
+use mongodb::bson::{Bson, Document};
+use ndc_models as ndc;
+
+/// The position of a placeholder in a pipeline, and the template that produces the value to
+/// substitute at that position
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Placeholder {
+    /// Index of the pipeline stage that contains the placeholder
+    pub stage: usize,
+
+    /// Path from the stage document to the value, or to the document that contains the key, that
+    /// the placeholder appears in
+    pub path: Vec<PathElement>,
+
+    pub target: PlaceholderTarget,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathElement {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlaceholderTarget {
+    /// Placeholders appear in a string value. If the entire string is a single placeholder the
+    /// value is replaced with the argument value, which may be of any type.
+    Value(Vec<TemplatePart>),
+
+    /// Placeholders appear in a document key. `key` is the key as it appears in the pipeline.
+    Key {
+        key: String,
+        parts: Vec<TemplatePart>,
+    },
+}
+
+/// A part of a template string, either raw text or a parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplatePart {
+    /// A raw text part
+    Text(String),
+    /// A parameter
+    Parameter(ndc::ArgumentName),
+}
+
+/// Parse a string or key in a native query or native mutation into parts where variables have the
+/// syntax `{{<variable>}}`.
+pub fn parse_template(string: &str) -> Vec<TemplatePart> {
+    let vec: Vec<Vec<TemplatePart>> = string
+        .split("{{")
+        .filter(|part| !part.is_empty())
+        .map(|part| match part.split_once("}}") {
+            None => vec![TemplatePart::Text(part.to_string())],
+            Some((var, text)) => {
+                if text.is_empty() {
+                    vec![TemplatePart::Parameter(var.trim().into())]
+                } else {
+                    vec![
+                        TemplatePart::Parameter(var.trim().into()),
+                        TemplatePart::Text(text.to_string()),
+                    ]
+                }
+            }
+        })
+        .collect();
+    vec.concat()
+}
+
+/// Finds all placeholders in the given pipeline. Value placeholders are listed before key
+/// placeholders, and key placeholders are ordered from the most deeply-nested to the least so that
+/// patching keys in order does not invalidate the paths of placeholders that are patched later.
+pub fn find_placeholders(pipeline: &[Document]) -> Vec<Placeholder> {
+    let mut placeholders = Vec::new();
+    for (stage, document) in pipeline.iter().enumerate() {
+        find_in_document(stage, &mut vec![], document, &mut placeholders);
+    }
+    placeholders.sort_by_key(|placeholder| match placeholder.target {
+        PlaceholderTarget::Value(_) => (0, 0),
+        PlaceholderTarget::Key { .. } => (1, usize::MAX - placeholder.path.len()),
+    });
+    placeholders
+}
+
+fn find_in_document(
+    stage: usize,
+    path: &mut Vec<PathElement>,
+    document: &Document,
+    placeholders: &mut Vec<Placeholder>,
+) {
+    for (key, value) in document {
+        if has_placeholder(key) {
+            placeholders.push(Placeholder {
+                stage,
+                path: path.clone(),
+                target: PlaceholderTarget::Key {
+                    key: key.clone(),
+                    parts: parse_template(key),
+                },
+            });
+        }
+        path.push(PathElement::Key(key.clone()));
+        find_in_value(stage, path, value, placeholders);
+        path.pop();
+    }
+}
+
+fn find_in_value(
+    stage: usize,
+    path: &mut Vec<PathElement>,
+    value: &Bson,
+    placeholders: &mut Vec<Placeholder>,
+) {
+    match value {
+        Bson::Document(document) => find_in_document(stage, path, document, placeholders),
+        Bson::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                path.push(PathElement::Index(index));
+                find_in_value(stage, path, value, placeholders);
+                path.pop();
+            }
+        }
+        Bson::String(string) if has_placeholder(string) => placeholders.push(Placeholder {
+            stage,
+            path: path.clone(),
+            target: PlaceholderTarget::Value(parse_template(string)),
+        }),
+        // TODO: Support interpolation within other scalar types
+        _ => (),
+    }
+}
+
+// Template parsing drops the braces from "{{" even when it is not followed by a matching "}}" so
+// any string that contains the opening delimiter must be treated as a template.
+fn has_placeholder(string: &str) -> bool {
+    string.contains("{{")
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::{find_placeholders, PathElement, Placeholder, PlaceholderTarget, TemplatePart};
+
+    #[test]
+    fn finds_placeholders_in_values_and_keys() {
+        let pipeline = [
+            doc! { "$match": { "title": "{{ title }}", "year": 2000 } },
+            doc! {
+                "$project": {
+                    "{{field}}": 1,
+                    "tags": ["a", "prefix-{{tag}}"],
+                },
+            },
+        ];
+        let placeholders = find_placeholders(&pipeline);
+        assert_eq!(
+            placeholders,
+            vec![
+                Placeholder {
+                    stage: 0,
+                    path: vec![
+                        PathElement::Key("$match".into()),
+                        PathElement::Key("title".into())
+                    ],
+                    target: PlaceholderTarget::Value(vec![TemplatePart::Parameter("title".into())]),
+                },
+                Placeholder {
+                    stage: 1,
+                    path: vec![
+                        PathElement::Key("$project".into()),
+                        PathElement::Key("tags".into()),
+                        PathElement::Index(1),
+                    ],
+                    target: PlaceholderTarget::Value(vec![
+                        TemplatePart::Text("prefix-".into()),
+                        TemplatePart::Parameter("tag".into()),
+                    ]),
+                },
+                Placeholder {
+                    stage: 1,
+                    path: vec![PathElement::Key("$project".into())],
+                    target: PlaceholderTarget::Key {
+                        key: "{{field}}".into(),
+                        parts: vec![TemplatePart::Parameter("field".into())],
+                    },
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Native queries with collection representation may mark positions in their pipelines where the
+//! connector may inject stages from a query request. A pushdown point is a stage of the form
+//! `{ "$pushdown": ["match", "sort", "limit"] }` which lists the kinds of request stages that may
+//! be placed at that position. The native query author is responsible for placing pushdown points
+//! where documents already have the shape of the native query's result type, and where injected
+//! stages do not change the meaning of the remaining stages. Pushdown points are removed from the
+//! pipeline before it is run.
+
+use std::collections::BTreeSet;
+
+use anyhow::{anyhow, bail};
+use mongodb::bson::{Bson, Document};
+
+pub const PUSHDOWN_STAGE: &str = "$pushdown";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PushdownKind {
+    /// The request predicate as a `$match` stage
+    Match,
+
+    /// The request `order_by` as a `$sort` stage
+    Sort,
+
+    /// The request `offset` and `limit` as `$skip` and `$limit` stages
+    Limit,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PushdownPoint {
+    /// Index of the `$pushdown` stage in the pipeline
+    pub stage: usize,
+    pub kinds: BTreeSet<PushdownKind>,
+}
+
+/// Finds pushdown points in a native query pipeline. Fails if a `$pushdown` stage is malformed.
+pub fn find_pushdown_points(pipeline: &[Document]) -> anyhow::Result<Vec<PushdownPoint>> {
+    pipeline
+        .iter()
+        .enumerate()
+        .filter_map(|(stage, document)| Some((stage, document.get(PUSHDOWN_STAGE)?, document)))
+        .map(|(stage, spec, document)| {
+            if document.len() != 1 {
+                bail!("a {PUSHDOWN_STAGE} stage must not have other keys (stage {stage})");
+            }
+            let names = match spec {
+                Bson::String(name) => vec![name.as_str()],
+                Bson::Array(names) => names
+                    .iter()
+                    .map(|name| {
+                        name.as_str().ok_or_else(|| {
+                            anyhow!("{PUSHDOWN_STAGE} lists must contain strings (stage {stage})")
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+                _ => {
+                    bail!("{PUSHDOWN_STAGE} must be a string or a list of strings (stage {stage})")
+                }
+            };
+            let kinds = names
+                .into_iter()
+                .map(|name| match name {
+                    "match" => Ok(PushdownKind::Match),
+                    "sort" => Ok(PushdownKind::Sort),
+                    "limit" => Ok(PushdownKind::Limit),
+                    _ => Err(anyhow!(
+                        "unknown {PUSHDOWN_STAGE} kind, {name}; expected match, sort, or limit"
+                    )),
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok(PushdownPoint { stage, kinds })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    use super::{find_pushdown_points, PushdownKind, PushdownPoint};
+
+    #[test]
+    fn finds_pushdown_points() -> anyhow::Result<()> {
+        let pipeline = [
+            doc! { "$unwind": "$items" },
+            doc! { "$pushdown": "match" },
+            doc! { "$lookup": { "from": "products", "as": "product" } },
+            doc! { "$pushdown": ["sort", "limit"] },
+        ];
+        assert_eq!(
+            find_pushdown_points(&pipeline)?,
+            vec![
+                PushdownPoint {
+                    stage: 1,
+                    kinds: [PushdownKind::Match].into(),
+                },
+                PushdownPoint {
+                    stage: 3,
+                    kinds: [PushdownKind::Sort, PushdownKind::Limit].into(),
+                },
+            ]
+        );
+        assert!(find_pushdown_points(&[doc! { "$pushdown": "group" }]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+//! The raw pipeline function runs an aggregation pipeline that is given as an argument. It is
+//! backed by a generated native query with function representation. The native query starts
+//! from an empty `$documents` stage, and brings in the documents of the requested collection with
+//! a `$unionWith` stage that runs the given pipeline. Like other native queries with function
+//! representation it produces a single document with a `__value` field, which holds the list of
+//! resulting documents so that the result type does not depend on the pipeline.
+//!
+//! Stages of the given pipeline are checked against the configured allow-list when a query
+//! request is executed.
+
+use mongodb::bson::doc;
+use mongodb_support::BsonScalarType;
+use ndc_models as ndc;
+
+use crate::{
+    native_query::NativeQueryRepresentation,
+    schema::{ObjectField, ObjectType, Type},
+    serialized, ConfigurationRawPipelineOptions,
+};
+
+pub const COLLECTION_ARGUMENT: &str = "collection";
+pub const PIPELINE_ARGUMENT: &str = "pipeline";
+
+pub fn raw_pipeline_function(
+    options: &ConfigurationRawPipelineOptions,
+) -> (ndc::FunctionName, serialized::NativeQuery) {
+    let name = &options.function_name;
+    let result_type_name = format!("{name}_result");
+
+    let arguments = [
+        ObjectField::new(COLLECTION_ARGUMENT, Type::Scalar(BsonScalarType::String)),
+        ObjectField::new(PIPELINE_ARGUMENT, Type::ExtendedJSON),
+    ]
+    .into_iter()
+    .map(|(name, field)| (name.into(), field))
+    .collect();
+
+    let result_type = ObjectType {
+        fields: [ObjectField::new(
+            "__value",
+            Type::ArrayOf(Box::new(Type::ExtendedJSON)),
+        )]
+        .into_iter()
+        .map(|(name, field)| (name.into(), field))
+        .collect(),
+        description: Some(format!("Documents produced by the {name} function")),
+        extends: Default::default(),
+    };
+
+    let pipeline = vec![
+        doc! { "$documents": [] },
+        doc! {
+            "$unionWith": {
+                "coll": format!("{{{{ {COLLECTION_ARGUMENT} }}}}"),
+                "pipeline": format!("{{{{ {PIPELINE_ARGUMENT} }}}}"),
+            }
+        },
+        doc! { "$facet": { "__value": [{ "$replaceWith": "$$ROOT" }] } },
+    ];
+
+    let native_query = serialized::NativeQuery {
+        representation: NativeQueryRepresentation::Function,
+        input_collection: None,
+        arguments,
+        result_document_type: result_type_name.clone().into(),
+        object_types: [(result_type_name.into(), result_type)].into(),
+        pipeline,
+        materialized: None,
+        description: Some(
+            "Runs an aggregation pipeline against the given collection. For prototyping native queries in development; stages are restricted to the configured allow-list.".to_owned(),
+        ),
+    };
+    (name.clone(), native_query)
+}
@@ -0,0 +1,197 @@
+//! Relationships declared in schema files alongside the collection that is the source of the
+//! relationship. Declared relationships are checked against collection and object types when
+//! configuration is loaded so that a mistyped collection or field name is reported at startup
+//! instead of when a query uses the relationship. Query requests may reference declared
+//! relationships by name without including them in `collection_relationships`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, bail, ensure};
+use ndc_models as ndc;
+
+use crate::schema;
+
+/// Collects relationships declared on each collection. Relationship names share a single
+/// namespace, as they do in query requests, so a name may only be declared once.
+pub fn declared_relationships(
+    collections: &BTreeMap<ndc::CollectionName, schema::Collection>,
+) -> anyhow::Result<BTreeMap<ndc::RelationshipName, (ndc::CollectionName, schema::Relationship)>> {
+    let mut declared = BTreeMap::new();
+    for (collection_name, collection) in collections {
+        for (name, relationship) in &collection.relationships {
+            if let Some((other_collection, _)) = declared.insert(
+                name.clone(),
+                (collection_name.clone(), relationship.clone()),
+            ) {
+                bail!("the relationship, {name}, is declared by both {other_collection} and {collection_name}");
+            }
+        }
+    }
+    Ok(declared)
+}
+
+/// Checks that the source and target collections of each declared relationship exist, and that
+/// the mapped fields exist in their collection types. Produces relationships in the form that
+/// query requests use.
+pub fn validate_relationships(
+    declared: BTreeMap<ndc::RelationshipName, (ndc::CollectionName, schema::Relationship)>,
+    collections: &BTreeMap<ndc::CollectionName, ndc::CollectionInfo>,
+    object_types: &BTreeMap<ndc::ObjectTypeName, ndc::ObjectType>,
+) -> anyhow::Result<BTreeMap<ndc::RelationshipName, ndc::Relationship>> {
+    let fields_of = |collection_name: &ndc::CollectionName| {
+        let collection = collections
+            .get(collection_name)
+            .ok_or_else(|| anyhow!("there is no collection named {collection_name}"))?;
+        object_types
+            .get(&collection.collection_type)
+            .map(|object_type| &object_type.fields)
+            .ok_or_else(|| {
+                anyhow!("the type of the collection, {collection_name}, is not a known object type")
+            })
+    };
+
+    declared
+        .into_iter()
+        .map(|(name, (source_collection, relationship))| {
+            let in_relationship = |err: anyhow::Error| anyhow!("in the relationship, {name}: {err}");
+            let source_fields = fields_of(&source_collection).map_err(in_relationship)?;
+            let target_fields =
+                fields_of(&relationship.target_collection).map_err(in_relationship)?;
+            ensure!(
+                !relationship.column_mapping.is_empty(),
+                "the relationship, {name}, must map at least one field"
+            );
+            for (source_field, target_field) in &relationship.column_mapping {
+                ensure!(
+                    source_fields.contains_key(source_field),
+                    "in the relationship, {name}: {source_collection} has no field named {source_field}"
+                );
+                ensure!(
+                    target_fields.contains_key(target_field),
+                    "in the relationship, {name}: {} has no field named {target_field}",
+                    relationship.target_collection
+                );
+            }
+            if relationship.single_object {
+                ensure!(
+                    relationship.relationship_type == ndc::RelationshipType::Object,
+                    "the relationship, {name}, sets singleObject, but it is not an object relationship"
+                );
+                let target_fields: BTreeSet<&ndc::FieldName> =
+                    relationship.column_mapping.values().collect();
+                let is_unique = collections[&relationship.target_collection]
+                    .uniqueness_constraints
+                    .values()
+                    .any(|constraint| {
+                        constraint
+                            .unique_columns
+                            .iter()
+                            .all(|field| target_fields.contains(field))
+                    });
+                ensure!(
+                    is_unique,
+                    "the relationship, {name}, sets singleObject, but its target fields do not include a uniqueness constraint of {}",
+                    relationship.target_collection
+                );
+            }
+            Ok((
+                name,
+                ndc::Relationship {
+                    column_mapping: relationship.column_mapping,
+                    relationship_type: relationship.relationship_type,
+                    target_collection: relationship.target_collection,
+                    arguments: Default::default(),
+                },
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb_support::BsonScalarType;
+
+    use crate::{
+        schema::{Collection, ObjectField, ObjectType, Relationship, Type},
+        serialized::Schema,
+        Configuration,
+    };
+
+    fn schema_with_relationship(target_field: &str) -> Schema {
+        let id_field = || ObjectField {
+            r#type: Type::Scalar(BsonScalarType::ObjectId),
+            description: None,
+            deprecated: false,
+            previous_names: Default::default(),
+            read_transform: None,
+            write_rules: None,
+            object_id_format: None,
+        };
+        let collection = |name: &str, relationships: Vec<(&str, Relationship)>| Collection {
+            r#type: name.into(),
+            description: None,
+            relationships: relationships
+                .into_iter()
+                .map(|(name, relationship)| (name.into(), relationship))
+                .collect(),
+            ..Default::default()
+        };
+        Schema {
+            collections: [
+                (
+                    "comments".into(),
+                    collection(
+                        "comments",
+                        vec![(
+                            "movie",
+                            Relationship {
+                                target_collection: "movies".into(),
+                                column_mapping: [("movie_id".into(), target_field.into())].into(),
+                                relationship_type: ndc_models::RelationshipType::Object,
+                                single_object: false,
+                                description: None,
+                            },
+                        )],
+                    ),
+                ),
+                ("movies".into(), collection("movies", vec![])),
+            ]
+            .into(),
+            object_types: [
+                (
+                    "comments".into(),
+                    ObjectType {
+                        fields: [("_id".into(), id_field()), ("movie_id".into(), id_field())]
+                            .into(),
+                        description: None,
+                        extends: Default::default(),
+                    },
+                ),
+                (
+                    "movies".into(),
+                    ObjectType {
+                        fields: [("_id".into(), id_field())].into(),
+                        description: None,
+                        extends: Default::default(),
+                    },
+                ),
+            ]
+            .into(),
+        }
+    }
+
+    #[test]
+    fn validates_declared_relationships() -> anyhow::Result<()> {
+        let config = Configuration::from_schema(schema_with_relationship("_id"))?;
+        let relationship = &config.relationships[&"movie".into()];
+        assert_eq!(relationship.target_collection.as_str(), "movies");
+        assert_eq!(
+            relationship.relationship_type,
+            ndc_models::RelationshipType::Object
+        );
+
+        let err = Configuration::from_schema(schema_with_relationship("id")).unwrap_err();
+        assert!(err.to_string().contains("movies has no field named id"));
+        Ok(())
+    }
+}
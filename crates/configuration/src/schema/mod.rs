@@ -7,7 +7,7 @@ use mongodb_support::BsonScalarType;
 
 use crate::{WithName, WithNameRef};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Collection {
     /// The name of a type declared in `objectTypes` that describes the fields of this collection.
@@ -15,6 +15,197 @@ pub struct Collection {
     pub r#type: ndc_models::ObjectTypeName,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    /// A numeric field that is incremented by every update made with a native mutation. Native
+    /// mutations that update this collection take an `expectedVersion` argument, and fail with
+    /// a conflict error if the document's version does not match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_field: Option<ndc_models::FieldName>,
+    /// A date field that marks documents as deleted. Native mutations that delete documents from
+    /// this collection set the field instead, and queries exclude documents where the field is
+    /// set unless the `includeDeleted` argument is given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft_delete_field: Option<ndc_models::FieldName>,
+    /// Ordering to apply to queries against this collection that do not specify an ordering
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_order_by: Option<Vec<DefaultOrderByElement>>,
+    /// Maximum number of documents that a query may return from this collection. Queries with no
+    /// limit, or a larger limit, are limited to this number of rows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_limit: Option<u32>,
+    /// Inserts documents for missing steps of a date or numeric field so that query results have
+    /// a continuous axis, and fills in other fields of the inserted documents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gap_fill: Option<GapFill>,
+    /// Fields computed over a window of documents, such as running totals or ranks. Window fields
+    /// are added to the collection's object type so they can be selected like other fields. They
+    /// are computed after query predicates are applied so predicates cannot reference them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub window_fields: BTreeMap<ndc_models::FieldName, WindowField>,
+    /// Each histogram generates a function named `<collection>_<field>_histogram` that counts
+    /// documents in buckets of values of a numeric field.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub histograms: Vec<Histogram>,
+    /// Each Atlas Vector Search index listed here generates a native query collection named
+    /// `<collection>_<path>_vector_search` that returns the nearest documents to an embedding with
+    /// a `_score` field.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vector_search: Vec<VectorSearch>,
+    /// Generates a native query collection named `<collection>_hybrid_search` that combines Atlas
+    /// Search full-text results with Atlas Vector Search results using reciprocal rank fusion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hybrid_search: Option<HybridSearch>,
+    /// Relationships from this collection to other collections. Relationship names share one
+    /// namespace across all collections. Query requests may use declared relationships without
+    /// including them in `collection_relationships`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub relationships: BTreeMap<ndc_models::RelationshipName, Relationship>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Relationship {
+    /// The collection or collection-representation native query that the relationship targets
+    pub target_collection: ndc_models::CollectionName,
+    /// Maps fields of the source collection to fields of the target collection
+    pub column_mapping: BTreeMap<ndc_models::FieldName, ndc_models::FieldName>,
+    /// `object` if each source document relates to at most one target document, `array` otherwise
+    pub relationship_type: ndc_models::RelationshipType,
+    /// Asserts that each source document relates to at most one target document. Configuration
+    /// fails to load unless this is an `object` relationship whose target fields include
+    /// a uniqueness constraint of the target collection. Responses still wrap the related document
+    /// in a row set since NDC requires row sets for relationship fields.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub single_object: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DefaultOrderByElement {
+    pub column: ndc_models::FieldName,
+    pub direction: ndc_models::OrderDirection,
+}
+
+/// Gap filling runs the `$densify` and `$fill` aggregation stages after query predicates are
+/// applied, so the range that is filled is the range of the matching documents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GapFill {
+    /// The date or numeric field to densify
+    pub field: ndc_models::FieldName,
+    /// Distance between consecutive values of `field`
+    pub step: i64,
+    /// Required if `field` is a date
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<GapFillUnit>,
+    /// If given, gaps are filled separately for each distinct combination of values of these
+    /// fields, within the range of `field` in that partition.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub partition_by: Vec<ndc_models::FieldName>,
+    /// How to set fields other than `field` in inserted documents. Fields that are not listed are
+    /// missing from inserted documents.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fill: BTreeMap<ndc_models::FieldName, FillMethod>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Histogram {
+    /// The numeric field to bucket
+    pub field: ndc_models::FieldName,
+    /// Bucket boundaries in ascending order. Each bucket includes its lower boundary, and excludes
+    /// its upper boundary. Values outside of the boundaries are not counted. If omitted the
+    /// generated function takes a `buckets` argument, and boundaries are chosen to distribute
+    /// documents evenly with `$bucketAuto`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boundaries: Option<Vec<serde_json::Number>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VectorSearch {
+    /// Name of the Atlas Vector Search index
+    pub index: String,
+    /// The indexed field that holds embeddings
+    pub path: ndc_models::FieldName,
+    /// Number of nearest neighbors to consider in approximate search. This must be at least as
+    /// large as the `k` argument of any query. If omitted the generated native query runs an exact
+    /// search.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_candidates: Option<u32>,
+}
+
+/// Hybrid search runs a `$vectorSearch` branch and a `$search` branch, and scores each document by
+/// the sum of `weight / (rankConstant + rank)` over the branches that returned it, where `rank`
+/// starts at 1.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HybridSearch {
+    /// Name of the Atlas Search index for the full-text branch
+    pub text_index: String,
+    /// Fields to match the `query` argument against
+    pub text_paths: Vec<ndc_models::FieldName>,
+    /// Weight of the full-text branch. Defaults to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_weight: Option<serde_json::Number>,
+    /// The Atlas Vector Search index for the vector branch
+    pub vector: VectorSearch,
+    /// Weight of the vector branch. Defaults to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector_weight: Option<serde_json::Number>,
+    /// Dampens the influence of top ranks. Defaults to 60.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rank_constant: Option<u32>,
+}
+
+/// A field computed with the `$setWindowFields` aggregation stage
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WindowField {
+    /// Type of the computed values
+    pub r#type: Type,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Fields that divide documents into partitions. Windows do not cross partition boundaries.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub partition_by: Vec<ndc_models::FieldName>,
+    /// Order of documents within each partition. Required by operators such as `$rank`, and by
+    /// windows with document bounds.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sort_by: Vec<DefaultOrderByElement>,
+    /// A window operator with its argument, in Extended JSON, such as `{ "$sum": "$total" }` or
+    /// `{ "$rank": {} }`
+    pub operator: serde_json::Value,
+    /// Bounds of the window, such as `{ "documents": ["unbounded", "current"] }` for a running
+    /// total. Omit for operators that do not take a window such as `$rank`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum GapFillUnit {
+    Millisecond,
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum FillMethod {
+    /// Use the last value before the gap
+    Locf,
+    /// Interpolate between the values on either side of the gap
+    Linear,
+    /// Use a constant value, given as Extended JSON
+    Value(serde_json::Value),
 }
 
 /// The type of values that a column, field, or argument may take.
@@ -96,6 +287,10 @@ pub struct ObjectType {
     pub fields: BTreeMap<ndc_models::FieldName, ObjectField>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    /// Names of object types whose fields are included in this type. Fields declared in `fields`
+    /// take precedence over inherited fields with the same name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extends: Vec<ndc_models::ObjectTypeName>,
 }
 
 impl ObjectType {
@@ -136,6 +331,93 @@ pub struct ObjectField {
     pub r#type: Type,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    /// Mark a field as deprecated. The field still appears in the schema, and may still be
+    /// queried, but its description is annotated to inform API consumers that it should not be
+    /// used.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deprecated: bool,
+    /// Keys that stored this field before it was renamed. While documents are being migrated,
+    /// queries read the field from the first of its current name or its previous names that is
+    /// set, so that renaming a field does not break queries. Applies to fields of collection
+    /// document types, not to fields of nested objects.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_names: Vec<ndc_models::FieldName>,
+    /// Computes the value of the field that queries return from the stored value. The field's
+    /// `type` is the type of the computed value. Applies to fields of collection document types,
+    /// not to fields of nested objects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_transform: Option<ReadTransform>,
+    /// Normalizations and validation rules applied to values of this field in native mutation
+    /// arguments, including fields of objects nested in arguments
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_rules: Option<WriteRules>,
+    /// Overrides `serializationOptions.objectIdFormat` for values of this field in query
+    /// responses, including ObjectIds nested in arrays or objects in this field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_id_format: Option<ObjectIdFormat>,
+}
+
+/// How ObjectId values are written in responses. Inputs accept either form regardless of this
+/// setting.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ObjectIdFormat {
+    /// A 24-character hex string, such as `"5a9427648b0beebeb69579cc"`
+    #[default]
+    HexString,
+    /// An extended JSON object, such as `{ "$oid": "5a9427648b0beebeb69579cc" }`
+    ExtendedJson,
+}
+
+/// Normalizations are applied first, in the order given. Then the normalized value is checked
+/// against each validation rule. A native mutation request fails if any value in its arguments
+/// breaks a rule. Null values are not checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteRules {
+    /// Normalizations for string values
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub normalize: Vec<WriteNormalization>,
+    /// A regular expression that string values must match. Use `^` and `$` to match the whole
+    /// string.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Inclusive lower bound for numeric values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<serde_json::Number>,
+    /// Inclusive upper bound for numeric values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<serde_json::Number>,
+    /// Minimum number of characters in a string, or of elements in an array
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<u64>,
+    /// Maximum number of characters in a string, or of elements in an array
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum WriteNormalization {
+    /// Removes leading and trailing whitespace
+    Trim,
+    Lowercase,
+    Uppercase,
+    /// Replaces the value with the hex-encoded SHA-256 hash of the value
+    Sha256,
+}
+
+/// An aggregation expression applied to a field when it is read
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadTransform {
+    /// An aggregation expression in Extended JSON, such as `{ "$toLower": "$$value" }`. The stored
+    /// value of the field is available as `$$value`.
+    pub expression: serde_json::Value,
+    /// By default predicates, sorting, and aggregates use the stored value of the field, which
+    /// lets them use indexes. If set they use the computed value instead.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub filter_on_transformed: bool,
 }
 
 impl ObjectField {
@@ -145,17 +427,84 @@ impl ObjectField {
             ObjectField {
                 r#type,
                 description: Default::default(),
+                deprecated: false,
+                previous_names: Default::default(),
+                read_transform: None,
+                write_rules: None,
+                object_id_format: None,
             },
         )
     }
 }
 
+impl ObjectField {
+    /// The NDC spec version that we use does not have a dedicated deprecation flag for object
+    /// fields so deprecation is reported in the field description.
+    fn ndc_description(&self) -> Option<String> {
+        match (self.deprecated, &self.description) {
+            (false, description) => description.clone(),
+            (true, Some(description)) => Some(format!("{DEPRECATED_PREFIX} {description}")),
+            (true, None) => Some(DEPRECATED_PREFIX.to_owned()),
+        }
+    }
+}
+
+const DEPRECATED_PREFIX: &str = "DEPRECATED.";
+
 impl From<ObjectField> for ndc_models::ObjectField {
     fn from(field: ObjectField) -> Self {
         ndc_models::ObjectField {
-            description: field.description,
+            description: field.ndc_description(),
             r#type: field.r#type.into(),
             arguments: BTreeMap::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mongodb_support::BsonScalarType;
+    use serde_json::json;
+
+    use super::{ObjectField, Type};
+
+    #[test]
+    fn parses_deprecated_field() -> anyhow::Result<()> {
+        let field: ObjectField = serde_json::from_value(json!({
+            "type": { "scalar": "string" },
+            "description": "name of the artist",
+            "deprecated": true,
+        }))?;
+        assert_eq!(
+            field,
+            ObjectField {
+                r#type: Type::Scalar(BsonScalarType::String),
+                description: Some("name of the artist".to_owned()),
+                deprecated: true,
+                previous_names: Default::default(),
+                read_transform: None,
+                write_rules: None,
+                object_id_format: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reports_deprecation_in_ndc_field_description() {
+        let field = ObjectField {
+            r#type: Type::Scalar(BsonScalarType::String),
+            description: Some("name of the artist".to_owned()),
+            deprecated: true,
+            previous_names: Default::default(),
+            read_transform: None,
+            write_rules: None,
+            object_id_format: None,
+        };
+        let ndc_field: ndc_models::ObjectField = field.into();
+        assert_eq!(
+            ndc_field.description,
+            Some("DEPRECATED. name of the artist".to_owned())
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! Each file in the schema directory describes one collection, and introspection names object
+//! types for embedded documents after the collection and field path where they were found. Those
+//! names can still collide: for example collection `users` with a field `home_address` and
+//! collection `users_home` with a field `address` both produce `users_home_address`. Schema files
+//! are merged by type name so a collision would otherwise drop one of the definitions.
+//!
+//! Namespacing resolves collisions by prefixing each conflicting definition, and references to it
+//! within the same schema file, with the name of the collection that the file describes.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use ndc_models as ndc;
+
+use crate::{
+    schema::{ObjectType, Type},
+    serialized::Schema,
+};
+
+/// Object type names that are defined in more than one schema file with differing definitions.
+/// Identical definitions in multiple files are not conflicts.
+pub fn conflicting_object_type_names(
+    schemas: &BTreeMap<String, Schema>,
+) -> BTreeSet<ndc::ObjectTypeName> {
+    let mut definitions: BTreeMap<&ndc::ObjectTypeName, Vec<&ObjectType>> = BTreeMap::new();
+    for (name, object_type) in schemas.values().flat_map(|schema| &schema.object_types) {
+        definitions.entry(name).or_default().push(object_type);
+    }
+    definitions
+        .into_iter()
+        .filter(|(_, defs)| defs.iter().any(|def| *def != defs[0]))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Renames object types whose names conflict across schema files by prefixing them with the name
+/// of the schema (which is the collection name). Types that do not conflict keep their names.
+pub fn namespace_object_types(schemas: BTreeMap<String, Schema>) -> BTreeMap<String, Schema> {
+    let conflicts = conflicting_object_type_names(&schemas);
+    schemas
+        .into_iter()
+        .map(|(schema_name, schema)| {
+            let renames: BTreeMap<ndc::ObjectTypeName, ndc::ObjectTypeName> = schema
+                .object_types
+                .keys()
+                .filter(|name| conflicts.contains(*name))
+                .map(|name| (name.clone(), format!("{schema_name}_{name}").into()))
+                .collect();
+            let schema = if renames.is_empty() {
+                schema
+            } else {
+                rename_object_types(schema, &renames)
+            };
+            (schema_name, schema)
+        })
+        .collect()
+}
+
+fn rename_object_types(
+    schema: Schema,
+    renames: &BTreeMap<ndc::ObjectTypeName, ndc::ObjectTypeName>,
+) -> Schema {
+    let rename = |name: ndc::ObjectTypeName| renames.get(&name).cloned().unwrap_or(name);
+    let collections = schema
+        .collections
+        .into_iter()
+        .map(|(name, mut collection)| {
+            collection.r#type = rename(collection.r#type);
+            (name, collection)
+        })
+        .collect();
+    let object_types = schema
+        .object_types
+        .into_iter()
+        .map(|(name, mut object_type)| {
+            for field in object_type.fields.values_mut() {
+                field.r#type = rename_in_type(field.r#type.clone(), renames);
+            }
+            object_type.extends = object_type.extends.into_iter().map(rename).collect();
+            (rename(name), object_type)
+        })
+        .collect();
+    Schema {
+        collections,
+        object_types,
+    }
+}
+
+fn rename_in_type(t: Type, renames: &BTreeMap<ndc::ObjectTypeName, ndc::ObjectTypeName>) -> Type {
+    match t {
+        Type::Object(name) => match renames.get(&ndc::ObjectTypeName::from(name.clone())) {
+            Some(new_name) => Type::Object(new_name.to_string()),
+            None => Type::Object(name),
+        },
+        Type::ArrayOf(t) => Type::ArrayOf(Box::new(rename_in_type(*t, renames))),
+        Type::Nullable(t) => Type::Nullable(Box::new(rename_in_type(*t, renames))),
+        t @ (Type::ExtendedJSON | Type::Scalar(_)) => t,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use mongodb_support::BsonScalarType;
+
+    use super::{conflicting_object_type_names, namespace_object_types};
+    use crate::{
+        schema::{Collection, ObjectField, ObjectType, Type},
+        serialized::Schema,
+    };
+
+    fn schema<const N: usize>(collection: &str, object_types: [(&str, ObjectType); N]) -> Schema {
+        Schema {
+            collections: [(
+                collection.into(),
+                Collection {
+                    r#type: collection.into(),
+                    description: None,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: object_types
+                .into_iter()
+                .map(|(name, object_type)| (name.into(), object_type))
+                .collect(),
+        }
+    }
+
+    fn object_type<const N: usize>(fields: [(&str, Type); N]) -> ObjectType {
+        ObjectType {
+            fields: fields
+                .into_iter()
+                .map(|(name, t)| {
+                    let (name, field) = ObjectField::new(name, t);
+                    (name.into(), field)
+                })
+                .collect(),
+            description: None,
+            extends: Default::default(),
+        }
+    }
+
+    #[test]
+    fn prefixes_conflicting_types_with_collection_name() {
+        let users = schema(
+            "users",
+            [
+                (
+                    "users",
+                    object_type([(
+                        "home_address",
+                        Type::Nullable(Box::new(Type::Object("users_home_address".into()))),
+                    )]),
+                ),
+                (
+                    "users_home_address",
+                    object_type([("street", Type::Scalar(BsonScalarType::String))]),
+                ),
+            ],
+        );
+        let users_home = schema(
+            "users_home",
+            [
+                (
+                    "users_home",
+                    object_type([(
+                        "address",
+                        Type::ArrayOf(Box::new(Type::Object("users_home_address".into()))),
+                    )]),
+                ),
+                (
+                    "users_home_address",
+                    object_type([("zip", Type::Scalar(BsonScalarType::Int))]),
+                ),
+            ],
+        );
+        let schemas = BTreeMap::from([
+            ("users".to_owned(), users),
+            ("users_home".to_owned(), users_home),
+        ]);
+        assert_eq!(
+            conflicting_object_type_names(&schemas),
+            BTreeSet::from(["users_home_address".into()])
+        );
+
+        let namespaced = namespace_object_types(schemas);
+        let users = &namespaced["users"];
+        assert!(users.object_types.contains_key("users_users_home_address"));
+        assert_eq!(
+            users.object_types["users"].fields["home_address"].r#type,
+            Type::Nullable(Box::new(Type::Object("users_users_home_address".into())))
+        );
+        let users_home = &namespaced["users_home"];
+        assert!(users_home
+            .object_types
+            .contains_key("users_home_users_home_address"));
+        assert_eq!(
+            users_home.object_types["users_home"].fields["address"].r#type,
+            Type::ArrayOf(Box::new(Type::Object(
+                "users_home_users_home_address".into()
+            )))
+        );
+        assert!(conflicting_object_type_names(&namespaced).is_empty());
+    }
+
+    #[test]
+    fn does_not_rename_identical_definitions() {
+        let shared = || object_type([("name", Type::Scalar(BsonScalarType::String))]);
+        let schemas = BTreeMap::from([
+            (
+                "a".to_owned(),
+                schema("a", [("a", shared()), ("tag", shared())]),
+            ),
+            (
+                "b".to_owned(),
+                schema("b", [("b", shared()), ("tag", shared())]),
+            ),
+        ]);
+        assert!(conflicting_object_type_names(&schemas).is_empty());
+        let namespaced = namespace_object_types(schemas);
+        assert!(namespaced["a"].object_types.contains_key("tag"));
+        assert!(namespaced["b"].object_types.contains_key("tag"));
+    }
+}
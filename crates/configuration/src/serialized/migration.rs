@@ -0,0 +1,33 @@
+use mongodb::bson;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// A data migration that the `migrate` CLI command applies once. Migrations are applied in order
+/// of their names, so names should start with a sortable version such as `0001_` or a date.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Migration {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    #[serde(flatten)]
+    pub operation: MigrationOperation,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(untagged, rename_all = "camelCase")]
+pub enum MigrationOperation {
+    /// Runs an aggregation pipeline against a collection. The pipeline should end with a `$merge`
+    /// or `$out` stage that writes its results. The pipeline is read as Extended JSON.
+    Pipeline {
+        collection: ndc_models::CollectionName,
+        #[schemars(with = "Vec<serde_json::Value>")]
+        pipeline: Vec<bson::Document>,
+    },
+    /// Runs a command using MongoDB's `runCommand` API, such as an `update` command. The command
+    /// is read as Extended JSON.
+    Command {
+        #[schemars(with = "serde_json::Map<String, serde_json::Value>")]
+        command: bson::Document,
+    },
+}
@@ -1,5 +1,11 @@
+mod migration;
 mod native_mutation;
 mod native_query;
 mod schema;
 
-pub use self::{native_mutation::NativeMutation, native_query::NativeQuery, schema::Schema};
+pub use self::{
+    migration::{Migration, MigrationOperation},
+    native_mutation::NativeMutation,
+    native_query::NativeQuery,
+    schema::Schema,
+};
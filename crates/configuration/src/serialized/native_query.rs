@@ -5,7 +5,7 @@ use schemars::JsonSchema;
 use serde::Deserialize;
 
 use crate::{
-    native_query::NativeQueryRepresentation,
+    native_query::{Materialization, NativeQueryRepresentation},
     schema::{ObjectField, ObjectType},
 };
 
@@ -89,9 +89,22 @@ pub struct NativeQuery {
     /// }])
     /// ```
     ///
+    /// Native queries with collection representation may include pushdown points, stages of the
+    /// form `{ "$pushdown": ["match", "sort", "limit"] }`, where the connector injects the
+    /// request's filter, sort, and offset and limit instead of applying them after the pipeline.
+    /// Place pushdown points where documents have the shape of the result document type.
+    ///
     #[schemars(with = "Vec<serde_json::Value>")]
     pub pipeline: Vec<bson::Document>,
 
+    /// If set, the connector periodically runs the pipeline and stores its results in a backing
+    /// collection using a `$merge` stage. Queries read from the backing collection instead of
+    /// running the pipeline. This gives cheap reads for expensive pipelines at the cost of
+    /// results being as old as the refresh interval. Materialized native queries may not have
+    /// arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub materialized: Option<Materialization>,
+
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
 }
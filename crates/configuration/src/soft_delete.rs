@@ -0,0 +1,116 @@
+//! Soft deletes for collections that declare a `softDeleteField`. Native mutations that run
+//! a `delete` command, or a `findAndModify` command with `remove: true`, against such
+//! a collection are rewritten to set the soft delete field to the current time instead of
+//! removing documents. Queries exclude documents where the field is set unless the request
+//! passes the `includeDeleted` collection argument.
+
+use std::collections::BTreeMap;
+
+use mongodb::bson::{doc, Bson, Document};
+use ndc_models as ndc;
+
+use crate::serialized;
+
+/// Rewrites native mutations that delete documents from collections with a soft delete field.
+pub fn apply_soft_deletes(
+    schema: &serialized::Schema,
+    native_mutations: &mut BTreeMap<ndc::ProcedureName, serialized::NativeMutation>,
+) {
+    for native_mutation in native_mutations.values_mut() {
+        let Some((_, Bson::String(collection_name))) = native_mutation.command.iter().next() else {
+            continue;
+        };
+        let soft_delete_field = schema
+            .collections
+            .get(collection_name.as_str())
+            .and_then(|collection| collection.soft_delete_field.as_ref());
+        if let Some(field) = soft_delete_field {
+            native_mutation.command = soft_delete_command(native_mutation.command.clone(), field);
+        }
+    }
+}
+
+fn soft_delete_command(command: Document, field: &ndc::FieldName) -> Document {
+    let field = field.as_str();
+    let set_deleted = Bson::Array(vec![doc! { "$set": { field: "$$NOW" } }.into()]);
+    let not_deleted = |filter: Option<&Document>| -> Document {
+        match filter {
+            Some(filter) if !filter.is_empty() => {
+                doc! { "$and": [filter.clone(), { field: null }] }
+            }
+            _ => doc! { field: null },
+        }
+    };
+
+    let Some((command_name, collection)) = command.iter().next() else {
+        return command;
+    };
+    match command_name.as_str() {
+        "delete" => {
+            let updates: Vec<Bson> = command
+                .get_array("deletes")
+                .map(|deletes| {
+                    deletes
+                        .iter()
+                        .filter_map(Bson::as_document)
+                        .map(|statement| {
+                            // A limit of 0 deletes all matching documents
+                            let multi = !matches!(
+                                statement.get("limit"),
+                                Some(Bson::Int32(1) | Bson::Int64(1))
+                            );
+                            doc! {
+                                "q": not_deleted(statement.get_document("q").ok()),
+                                "u": set_deleted.clone(),
+                                "multi": multi,
+                            }
+                            .into()
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            let mut rewritten = doc! { "update": collection.clone(), "updates": updates };
+            for (key, value) in command.iter().skip(1) {
+                if key != "deletes" {
+                    rewritten.insert(key, value.clone());
+                }
+            }
+            rewritten
+        }
+        "findAndModify" | "findandmodify" if command.get_bool("remove").unwrap_or(false) => {
+            let mut rewritten = command.clone();
+            rewritten.remove("remove");
+            rewritten.insert("query", not_deleted(command.get_document("query").ok()));
+            rewritten.insert("update", set_deleted);
+            rewritten
+        }
+        _ => command,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    use super::soft_delete_command;
+
+    #[test]
+    fn rewrites_delete_as_update_of_soft_delete_field() {
+        let command = doc! {
+            "delete": "posts",
+            "deletes": [{ "q": { "_id": "{{ id }}" }, "limit": 1 }],
+        };
+        assert_eq!(
+            soft_delete_command(command, &"deleted_at".into()),
+            doc! {
+                "update": "posts",
+                "updates": [{
+                    "q": { "$and": [{ "_id": "{{ id }}" }, { "deleted_at": null }] },
+                    "u": [{ "$set": { "deleted_at": "$$NOW" } }],
+                    "multi": false,
+                }],
+            }
+        );
+    }
+}
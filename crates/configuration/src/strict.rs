@@ -0,0 +1,217 @@
+//! Checks for configuration that loads successfully but that is probably a mistake, or is left
+//! over from earlier changes: keys that the connector ignores, object types that nothing
+//! references, and native query or native mutation arguments that are never substituted. The
+//! connector does not run these checks at startup. They are run by the `validate --strict` CLI
+//! command to keep large configuration directories clean.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use itertools::Itertools as _;
+use mongodb::bson::Document;
+use ndc_models as ndc;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    directory::{read_configuration_values, FileKind},
+    format_version::VERSION_FIELD,
+    placeholders::{find_placeholders, PlaceholderTarget, TemplatePart},
+    serialized, Configuration, ConfigurationOptions,
+};
+
+/// Runs strict checks against the files in a configuration directory, and the configuration that
+/// was read from them. Returns a description of each problem found.
+pub async fn strict_validation(
+    configuration_dir: impl AsRef<Path>,
+    configuration: &Configuration,
+) -> anyhow::Result<Vec<String>> {
+    let mut problems = vec![];
+    for (path, kind, value) in read_configuration_values(configuration_dir).await? {
+        let in_file = |problem: String| format!("{}: {problem}", path.display());
+        let ignored_keys = match kind {
+            FileKind::Options => ignored_keys::<ConfigurationOptions>(value),
+            FileKind::Schema => ignored_keys::<serialized::Schema>(value),
+            FileKind::NativeMutation => {
+                let native_mutation = parse::<serialized::NativeMutation>(&value)?;
+                problems.extend(
+                    unused_arguments(
+                        &native_mutation.arguments,
+                        std::slice::from_ref(&native_mutation.command),
+                    )
+                    .into_iter()
+                    .map(in_file),
+                );
+                ignored_keys::<serialized::NativeMutation>(value)
+            }
+            FileKind::NativeQuery => {
+                let native_query = parse::<serialized::NativeQuery>(&value)?;
+                problems.extend(
+                    unused_arguments(&native_query.arguments, &native_query.pipeline)
+                        .into_iter()
+                        .map(in_file),
+                );
+                ignored_keys::<serialized::NativeQuery>(value)
+            }
+        }?;
+        problems.extend(
+            ignored_keys
+                .into_iter()
+                .map(|key| in_file(format!("unknown key, {key}"))),
+        );
+    }
+    problems.extend(
+        unused_object_types(configuration)
+            .into_iter()
+            .map(|name| format!("the object type, {name}, is not used by any collection, native query, or native mutation")),
+    );
+    Ok(problems)
+}
+
+fn parse<T: DeserializeOwned>(value: &Value) -> anyhow::Result<T> {
+    Ok(serde_json::from_value(without_file_keys(value.clone()))?)
+}
+
+/// Removes keys that are read separately from the rest of the file
+fn without_file_keys(mut value: Value) -> Value {
+    if let Value::Object(fields) = &mut value {
+        fields.remove(VERSION_FIELD);
+        fields.remove("name");
+    }
+    value
+}
+
+/// Deserializes a file, and lists the paths of keys that deserialization ignored
+fn ignored_keys<T: DeserializeOwned>(value: Value) -> anyhow::Result<Vec<String>> {
+    let mut ignored = vec![];
+    let _: T = serde_ignored::deserialize(without_file_keys(value), |path| {
+        ignored.push(path.to_string())
+    })?;
+    Ok(ignored)
+}
+
+/// Lists declared arguments that do not appear in any placeholder
+fn unused_arguments<T>(
+    arguments: &BTreeMap<ndc::ArgumentName, T>,
+    pipeline: &[Document],
+) -> Vec<String> {
+    let referenced: BTreeSet<ndc::ArgumentName> = find_placeholders(pipeline)
+        .into_iter()
+        .flat_map(|placeholder| match placeholder.target {
+            PlaceholderTarget::Value(parts) => parts,
+            PlaceholderTarget::Key { parts, .. } => parts,
+        })
+        .filter_map(|part| match part {
+            TemplatePart::Parameter(name) => Some(name),
+            TemplatePart::Text(_) => None,
+        })
+        .collect();
+    arguments
+        .keys()
+        .filter(|name| !referenced.contains(*name))
+        .map(|name| format!("the argument, {name}, is not used"))
+        .collect()
+}
+
+/// Lists object types that are not reachable from the types of collections, functions, or
+/// procedures
+fn unused_object_types(configuration: &Configuration) -> Vec<ndc::ObjectTypeName> {
+    let collections = configuration.collections.values().chain(
+        configuration
+            .functions
+            .values()
+            .map(|(_, collection)| collection),
+    );
+    let mut pending: Vec<&ndc::Type> = configuration
+        .functions
+        .values()
+        .flat_map(|(function, _)| {
+            std::iter::once(&function.result_type)
+                .chain(function.arguments.values().map(|arg| &arg.argument_type))
+        })
+        .chain(configuration.procedures.values().flat_map(|procedure| {
+            std::iter::once(&procedure.result_type)
+                .chain(procedure.arguments.values().map(|arg| &arg.argument_type))
+        }))
+        .chain(
+            collections
+                .clone()
+                .flat_map(|collection| collection.arguments.values())
+                .map(|arg| &arg.argument_type),
+        )
+        .collect();
+    let mut used: BTreeSet<ndc::ObjectTypeName> = BTreeSet::new();
+    let mut pending_names: Vec<ndc::ObjectTypeName> = collections
+        .map(|collection| collection.collection_type.clone())
+        .collect();
+
+    loop {
+        while let Some(t) = pending.pop() {
+            match t {
+                ndc::Type::Named { name } => pending_names.push(name.to_string().into()),
+                ndc::Type::Nullable { underlying_type } => pending.push(underlying_type),
+                ndc::Type::Array { element_type } => pending.push(element_type),
+                ndc::Type::Predicate { object_type_name } => {
+                    pending_names.push(object_type_name.clone())
+                }
+            }
+        }
+        let Some(name) = pending_names.pop() else {
+            break;
+        };
+        if let Some(object_type) = configuration.object_types.get(&name) {
+            if used.insert(name) {
+                pending.extend(object_type.fields.values().map(|field| &field.r#type));
+            }
+        }
+    }
+
+    configuration
+        .object_types
+        .keys()
+        .filter(|name| !used.contains(*name))
+        .cloned()
+        .collect_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::{serialized, Configuration};
+
+    use super::{ignored_keys, unused_arguments, unused_object_types};
+
+    #[test]
+    fn finds_unknown_keys_unused_types_and_unused_arguments() -> anyhow::Result<()> {
+        let schema = json!({
+            "version": 1,
+            "name": "movies",
+            "collections": {
+                "movies": { "type": "movies", "descripton": "typo" },
+            },
+            "objectTypes": {
+                "movies": { "fields": { "_id": { "type": { "scalar": "objectId" } } } },
+                "leftover": { "fields": { "title": { "type": { "scalar": "string" } } } },
+            },
+        });
+        assert_eq!(
+            ignored_keys::<serialized::Schema>(schema.clone())?,
+            vec!["collections.movies.descripton".to_owned()]
+        );
+
+        let configuration = Configuration::from_schema(super::parse(&schema)?)?;
+        assert_eq!(unused_object_types(&configuration), vec!["leftover".into()]);
+
+        let arguments = [("title".into(), ()), ("year".into(), ())].into();
+        assert_eq!(
+            unused_arguments(&arguments, &[doc! { "$match": { "title": "{{ title }}" } }]),
+            vec!["the argument, year, is not used".to_owned()]
+        );
+        Ok(())
+    }
+}
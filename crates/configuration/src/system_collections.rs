@@ -0,0 +1,221 @@
+//! MongoDB keeps internal state in `system.*` collections, in the oplog, and in the `admin`,
+//! `local`, and `config` databases. The connector does not expose or query these unless the
+//! `allowSystemCollections` option is set. Configuration that names them fails validation, and
+//! collection names that reach native query pipelines or native mutation commands through
+//! arguments are checked when requests are executed.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context as _};
+use mongodb::bson::{Bson, Document};
+use ndc_models as ndc;
+
+use crate::serialized;
+
+/// Databases that hold server state
+const SYSTEM_DATABASES: [&str; 3] = ["admin", "local", "config"];
+
+pub fn is_system_collection(name: &str) -> bool {
+    name.starts_with("system.") || name.starts_with("oplog.")
+}
+
+pub fn is_system_database(name: &str) -> bool {
+    SYSTEM_DATABASES.contains(&name)
+}
+
+/// Names of collections and databases that the given pipeline stages read from or write to, such as
+/// the `from` collection of `$lookup`. Stages in sub-pipelines are included. Names that are
+/// documents, such as `{ db, coll }` in `$merge` and `$out`, produce both names.
+pub fn referenced_namespaces(stages: &[Document]) -> Vec<Namespace<'_>> {
+    let mut namespaces = vec![];
+    for stage in stages {
+        push_referenced_namespaces(stage, &mut namespaces);
+    }
+    namespaces
+}
+
+/// Names of collections and databases that a native mutation command writes to or reads from: the
+/// target collection, and collections that stages of an `aggregate` command's pipeline reference
+pub fn command_namespaces(command: &Document) -> Vec<Namespace<'_>> {
+    let mut namespaces = vec![];
+    // Write commands name their target collection in the first field
+    if let Some((_, Bson::String(target))) = command.iter().next() {
+        namespaces.push(Namespace::Collection(target));
+    }
+    if let Ok(pipeline) = command.get_array("pipeline") {
+        push_sub_pipeline_stages(pipeline, &mut namespaces);
+    }
+    namespaces
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Namespace<'a> {
+    Collection(&'a str),
+    Database(&'a str),
+}
+
+impl<'a> Namespace<'a> {
+    pub fn name(&self) -> &'a str {
+        match self {
+            Namespace::Collection(name) | Namespace::Database(name) => name,
+        }
+    }
+
+    pub fn is_system(&self) -> bool {
+        match self {
+            Namespace::Collection(name) => is_system_collection(name),
+            Namespace::Database(name) => is_system_database(name),
+        }
+    }
+}
+
+/// Fails if the pipeline references a system collection or database by a literal name. Names
+/// that contain argument placeholders are checked when requests are executed instead.
+pub fn check_pipeline_namespaces(stages: &[Document]) -> anyhow::Result<()> {
+    for namespace in referenced_namespaces(stages) {
+        let name = namespace.name();
+        if !name.contains("{{") && namespace.is_system() {
+            bail!("the pipeline references {name}, which is a system collection or database");
+        }
+    }
+    Ok(())
+}
+
+/// Fails if the schema, native queries, or native mutations name a system collection or database
+pub fn check_system_collections(
+    schema: &serialized::Schema,
+    native_queries: &BTreeMap<ndc::FunctionName, serialized::NativeQuery>,
+    native_mutations: &BTreeMap<ndc::ProcedureName, serialized::NativeMutation>,
+) -> anyhow::Result<()> {
+    for name in schema.collections.keys() {
+        if is_system_collection(name.as_str()) {
+            bail!("the collection, {name}, is a system collection. Set the allowSystemCollections option to expose it.");
+        }
+    }
+    for (name, native_query) in native_queries {
+        let input_collection = native_query.input_collection.as_ref().map(|c| c.as_str());
+        if input_collection.is_some_and(is_system_collection) {
+            bail!("the native query, {name}, reads from a system collection. Set the allowSystemCollections option to allow this.");
+        }
+        check_pipeline_namespaces(&native_query.pipeline)
+            .with_context(|| format!("in the native query, {name}"))?;
+    }
+    for (name, native_mutation) in native_mutations {
+        let system_namespace = command_namespaces(&native_mutation.command)
+            .into_iter()
+            .find(|namespace| !namespace.name().contains("{{") && namespace.is_system());
+        if let Some(namespace) = system_namespace {
+            bail!("the native mutation, {name}, references {}, which is a system collection or database. Set the allowSystemCollections option to allow this.", namespace.name());
+        }
+    }
+    Ok(())
+}
+
+fn push_referenced_namespaces<'a>(stage: &'a Document, namespaces: &mut Vec<Namespace<'a>>) {
+    let Some((operator, spec)) = stage.iter().next() else {
+        return;
+    };
+    match (operator.as_str(), spec) {
+        ("$lookup" | "$graphLookup", Bson::Document(spec)) => {
+            push_name(spec.get("from"), namespaces);
+            push_sub_pipeline(spec.get("pipeline"), namespaces);
+        }
+        ("$unionWith", Bson::Document(spec)) => {
+            push_name(spec.get("coll"), namespaces);
+            push_sub_pipeline(spec.get("pipeline"), namespaces);
+        }
+        ("$unionWith" | "$out", name) => push_name(Some(name), namespaces),
+        ("$merge", Bson::Document(spec)) => push_name(spec.get("into"), namespaces),
+        ("$merge", name) => push_name(Some(name), namespaces),
+        ("$facet", Bson::Document(facets)) => {
+            for pipeline in facets.values() {
+                push_sub_pipeline(Some(pipeline), namespaces);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn push_name<'a>(name: Option<&'a Bson>, namespaces: &mut Vec<Namespace<'a>>) {
+    match name {
+        Some(Bson::String(collection)) => namespaces.push(Namespace::Collection(collection)),
+        Some(Bson::Document(namespace)) => {
+            if let Ok(database) = namespace.get_str("db") {
+                namespaces.push(Namespace::Database(database));
+            }
+            if let Ok(collection) = namespace.get_str("coll") {
+                namespaces.push(Namespace::Collection(collection));
+            }
+        }
+        _ => (),
+    }
+}
+
+fn push_sub_pipeline<'a>(pipeline: Option<&'a Bson>, namespaces: &mut Vec<Namespace<'a>>) {
+    if let Some(Bson::Array(stages)) = pipeline {
+        push_sub_pipeline_stages(stages, namespaces);
+    }
+}
+
+fn push_sub_pipeline_stages<'a>(stages: &'a [Bson], namespaces: &mut Vec<Namespace<'a>>) {
+    for stage in stages.iter().filter_map(Bson::as_document) {
+        push_referenced_namespaces(stage, namespaces);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::{check_pipeline_namespaces, command_namespaces, referenced_namespaces, Namespace};
+
+    #[test]
+    fn finds_system_collections_referenced_by_pipeline_stages() {
+        let pipeline = vec![
+            doc! { "$match": { "from": "system.users" } },
+            doc! {
+                "$lookup": {
+                    "from": "comments",
+                    "as": "comments",
+                    "pipeline": [{ "$unionWith": "system.profile" }],
+                }
+            },
+            doc! { "$merge": { "into": { "db": "admin", "coll": "copy" } } },
+        ];
+        assert_eq!(
+            referenced_namespaces(&pipeline),
+            vec![
+                Namespace::Collection("comments"),
+                Namespace::Collection("system.profile"),
+                Namespace::Database("admin"),
+                Namespace::Collection("copy"),
+            ]
+        );
+        assert!(check_pipeline_namespaces(&pipeline).is_err());
+
+        let with_placeholder = vec![doc! { "$unionWith": { "coll": "{{ collection }}" } }];
+        assert!(check_pipeline_namespaces(&with_placeholder).is_ok());
+    }
+
+    #[test]
+    fn finds_system_collections_referenced_by_commands() {
+        let command = doc! {
+            "aggregate": "orders",
+            "pipeline": [{ "$out": "system.js" }],
+            "cursor": {},
+        };
+        assert_eq!(
+            command_namespaces(&command),
+            vec![
+                Namespace::Collection("orders"),
+                Namespace::Collection("system.js"),
+            ]
+        );
+
+        let command = doc! { "update": "system.users", "updates": [] };
+        assert_eq!(
+            command_namespaces(&command),
+            vec![Namespace::Collection("system.users")]
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Native queries that ship with the connector, and that are included in configuration when the
+//! `systemNativeQueries` option is set. For each collection in the schema these expose index usage
+//! statistics from `$indexStats`, and plan cache entries from `$planCacheStats` as virtual
+//! collections so that the database can be monitored through the same API as application data.
+//!
+//! Collection statistics functions, `<collection>_stats`, are included when the `collectionStats`
+//! option is set. They report the document count and average document size from `$collStats`.
+
+use std::collections::BTreeMap;
+
+use mongodb::bson::doc;
+use mongodb_support::BsonScalarType as S;
+use ndc_models as ndc;
+
+use crate::{
+    native_query::NativeQueryRepresentation,
+    schema::{ObjectField, ObjectType, Type},
+    serialized,
+};
+
+/// Produces system native queries for each of the given collections. Names have the form
+/// `<collection>_index_stats` and `<collection>_plan_cache_stats`.
+pub fn system_native_queries<'a>(
+    collections: impl IntoIterator<Item = &'a ndc::CollectionName>,
+) -> BTreeMap<ndc::FunctionName, serialized::NativeQuery> {
+    collections
+        .into_iter()
+        .flat_map(|collection| {
+            [
+                index_stats_native_query(collection),
+                plan_cache_stats_native_query(collection),
+            ]
+        })
+        .collect()
+}
+
+/// Produces a `<collection>_stats` function for each of the given collections
+pub fn collection_stats_functions<'a>(
+    collections: impl IntoIterator<Item = &'a ndc::CollectionName>,
+) -> BTreeMap<ndc::FunctionName, serialized::NativeQuery> {
+    collections
+        .into_iter()
+        .map(collection_stats_function)
+        .collect()
+}
+
+/// Produces a single document with the `count`, `size`, and `avgObjSize` of a collection. On
+/// a sharded cluster `$collStats` produces a document for each shard so these are summed.
+pub fn collection_stats_pipeline() -> Vec<mongodb::bson::Document> {
+    vec![
+        doc! { "$collStats": { "storageStats": {} } },
+        doc! {
+            "$group": {
+                "_id": null,
+                "count": { "$sum": "$storageStats.count" },
+                "size": { "$sum": "$storageStats.size" },
+            }
+        },
+        doc! {
+            "$replaceWith": {
+                "count": { "$toLong": "$count" },
+                "size": { "$toLong": "$size" },
+                "avgObjSize": {
+                    "$cond": [{ "$gt": ["$count", 0] }, { "$divide": ["$size", "$count"] }, null]
+                },
+            }
+        },
+    ]
+}
+
+fn collection_stats_function(
+    collection: &ndc::CollectionName,
+) -> (ndc::FunctionName, serialized::NativeQuery) {
+    let name = format!("{collection}_stats");
+    let stats_type_name = format!("{name}_value");
+    // Native queries with function representation produce a single document with a `__value`
+    // field
+    let object_types = [
+        (
+            name.clone().into(),
+            object_type(
+                format!("Result of the {name} function"),
+                [("__value", Type::Object(stats_type_name.clone()))],
+            ),
+        ),
+        (
+            stats_type_name.into(),
+            object_type(
+                format!("Statistics for the {collection} collection"),
+                [
+                    ("count", Type::Scalar(S::Long)),
+                    ("size", Type::Scalar(S::Long)),
+                    ("avgObjSize", nullable(Type::Scalar(S::Double))),
+                ],
+            ),
+        ),
+    ];
+    let mut pipeline = collection_stats_pipeline();
+    pipeline.push(doc! { "$replaceWith": { "__value": "$$ROOT" } });
+    let native_query = serialized::NativeQuery {
+        representation: NativeQueryRepresentation::Function,
+        input_collection: Some(collection.clone()),
+        arguments: Default::default(),
+        result_document_type: name.clone().into(),
+        object_types: object_types.into_iter().collect(),
+        pipeline,
+        materialized: None,
+        description: Some(format!(
+            "Number of documents, total uncompressed size in bytes, and average document size of the {collection} collection"
+        )),
+    };
+    (name.into(), native_query)
+}
+
+fn index_stats_native_query(
+    collection: &ndc::CollectionName,
+) -> (ndc::FunctionName, serialized::NativeQuery) {
+    let name = format!("{collection}_index_stats");
+    let accesses_type_name = format!("{name}_accesses");
+    let object_types = [
+        (
+            name.clone().into(),
+            object_type(
+                format!("Usage statistics for each index of the {collection} collection"),
+                [
+                    ("name", Type::Scalar(S::String)),
+                    ("key", Type::ExtendedJSON),
+                    ("host", Type::Scalar(S::String)),
+                    ("accesses", Type::Object(accesses_type_name.clone())),
+                    ("shard", nullable(Type::Scalar(S::String))),
+                    ("spec", Type::ExtendedJSON),
+                    ("building", nullable(Type::Scalar(S::Bool))),
+                ],
+            ),
+        ),
+        (
+            accesses_type_name.into(),
+            object_type(
+                "Number of operations that used the index since the given time".to_owned(),
+                [
+                    ("ops", Type::Scalar(S::Long)),
+                    ("since", Type::Scalar(S::Date)),
+                ],
+            ),
+        ),
+    ];
+    let native_query = system_native_query(
+        collection,
+        &name,
+        object_types,
+        doc! { "$indexStats": {} },
+        format!("Index usage statistics for the {collection} collection"),
+    );
+    (name.into(), native_query)
+}
+
+fn plan_cache_stats_native_query(
+    collection: &ndc::CollectionName,
+) -> (ndc::FunctionName, serialized::NativeQuery) {
+    let name = format!("{collection}_plan_cache_stats");
+    // The fields of plan cache entries vary between MongoDB versions so all fields are nullable
+    let object_types = [(
+        name.clone().into(),
+        object_type(
+            format!("An entry in the query plan cache of the {collection} collection"),
+            [
+                ("queryHash", nullable(Type::Scalar(S::String))),
+                ("planCacheKey", nullable(Type::Scalar(S::String))),
+                ("isActive", nullable(Type::Scalar(S::Bool))),
+                ("works", nullable(Type::Scalar(S::Long))),
+                ("timeOfCreation", nullable(Type::Scalar(S::Date))),
+                ("createdFromQuery", Type::ExtendedJSON),
+                ("cachedPlan", Type::ExtendedJSON),
+                ("host", nullable(Type::Scalar(S::String))),
+            ],
+        ),
+    )];
+    let native_query = system_native_query(
+        collection,
+        &name,
+        object_types,
+        doc! { "$planCacheStats": {} },
+        format!("Query plan cache entries for the {collection} collection"),
+    );
+    (name.into(), native_query)
+}
+
+fn system_native_query(
+    collection: &ndc::CollectionName,
+    result_document_type: &str,
+    object_types: impl IntoIterator<Item = (ndc::ObjectTypeName, ObjectType)>,
+    stage: mongodb::bson::Document,
+    description: String,
+) -> serialized::NativeQuery {
+    serialized::NativeQuery {
+        representation: NativeQueryRepresentation::Collection,
+        input_collection: Some(collection.clone()),
+        arguments: Default::default(),
+        result_document_type: result_document_type.to_owned().into(),
+        object_types: object_types.into_iter().collect(),
+        pipeline: vec![stage],
+        materialized: None,
+        description: Some(description),
+    }
+}
+
+fn object_type<const N: usize>(description: String, fields: [(&str, Type); N]) -> ObjectType {
+    ObjectType {
+        fields: fields
+            .into_iter()
+            .map(|(name, field_type)| {
+                let (name, field) = ObjectField::new(name, field_type);
+                (name.into(), field)
+            })
+            .collect(),
+        description: Some(description),
+        extends: Default::default(),
+    }
+}
+
+fn nullable(t: Type) -> Type {
+    Type::Nullable(Box::new(t))
+}
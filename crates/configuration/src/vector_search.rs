@@ -0,0 +1,209 @@
+//! Vector search collections are generated for Atlas Vector Search indexes that collections
+//! declare in schema files. Each is backed by a generated native query with collection
+//! representation that runs `$vectorSearch` and adds the search score to each document as
+//! `_score`. Because the native query has collection representation, query predicates, ordering,
+//! and relationships apply to the search results.
+
+use anyhow::{anyhow, ensure};
+use mongodb::bson::{doc, Document};
+use mongodb_support::BsonScalarType;
+use ndc_models as ndc;
+
+use crate::{
+    native_query::NativeQueryRepresentation,
+    schema::{ObjectField, ObjectType, Type, VectorSearch},
+    serialized,
+};
+
+pub const EMBEDDING_ARGUMENT: &str = "embedding";
+pub const K_ARGUMENT: &str = "k";
+pub const FILTER_ARGUMENT: &str = "filter";
+pub const SCORE_FIELD: &str = "_score";
+
+/// Produces a native query for each vector search index of each collection in the given schema
+pub fn vector_search_collections(
+    schema: &serialized::Schema,
+) -> anyhow::Result<Vec<(ndc::FunctionName, serialized::NativeQuery)>> {
+    schema
+        .collections
+        .iter()
+        .flat_map(|(collection_name, collection)| {
+            collection.vector_search.iter().map(move |vector_search| {
+                let object_type = schema.object_types.get(&collection.r#type).ok_or_else(|| {
+                    anyhow!(
+                        "the collection, {collection_name}, has an unknown type: {}",
+                        collection.r#type
+                    )
+                })?;
+                vector_search_collection(collection_name, object_type, vector_search)
+            })
+        })
+        .collect()
+}
+
+fn vector_search_collection(
+    collection_name: &ndc::CollectionName,
+    collection_type: &ObjectType,
+    vector_search: &VectorSearch,
+) -> anyhow::Result<(ndc::FunctionName, serialized::NativeQuery)> {
+    let path = vector_search.path.as_str();
+    let name = format!("{collection_name}_{path}_vector_search");
+    let result_type_name = format!("{name}_result");
+    let result_type = scored_result_type(collection_name, collection_type, &name)?;
+
+    let mut spec = vector_search_spec(vector_search);
+    spec.insert("filter", placeholder(FILTER_ARGUMENT));
+    let pipeline = vec![
+        doc! { "$vectorSearch": spec },
+        doc! { "$set": { SCORE_FIELD: { "$meta": "vectorSearchScore" } } },
+    ];
+
+    let arguments = [
+        embedding_argument(),
+        k_argument(),
+        argument(
+            FILTER_ARGUMENT,
+            Type::ExtendedJSON,
+            "A query filter on fields indexed as filter fields. Use {} to search all documents.",
+        ),
+    ]
+    .into();
+
+    let native_query = serialized::NativeQuery {
+        representation: NativeQueryRepresentation::Collection,
+        input_collection: Some(collection_name.clone()),
+        arguments,
+        result_document_type: result_type_name.clone().into(),
+        object_types: [(result_type_name.into(), result_type)].into(),
+        pipeline,
+        materialized: None,
+        description: Some(format!(
+            "Documents in the {collection_name} collection nearest to an embedding by {path}"
+        )),
+    };
+    Ok((name.into(), native_query))
+}
+
+/// The collection's object type with an added score field
+pub fn scored_result_type(
+    collection_name: &ndc::CollectionName,
+    collection_type: &ObjectType,
+    name: &str,
+) -> anyhow::Result<ObjectType> {
+    ensure!(
+        !collection_type.fields.contains_key(SCORE_FIELD),
+        "the collection, {collection_name}, has a field named {SCORE_FIELD} which conflicts with the score field of {name}"
+    );
+    let mut result_type = collection_type.clone();
+    let (score_name, score_field) =
+        ObjectField::new(SCORE_FIELD, Type::Scalar(BsonScalarType::Double));
+    result_type.fields.insert(score_name.into(), score_field);
+    result_type.description = Some(format!("A result of the {name} collection"));
+    Ok(result_type)
+}
+
+pub fn embedding_argument() -> (ndc::ArgumentName, ObjectField) {
+    argument(
+        EMBEDDING_ARGUMENT,
+        Type::ArrayOf(Box::new(Type::Scalar(BsonScalarType::Double))),
+        "The vector to find nearest neighbors of",
+    )
+}
+
+pub fn k_argument() -> (ndc::ArgumentName, ObjectField) {
+    argument(
+        K_ARGUMENT,
+        Type::Scalar(BsonScalarType::Int),
+        "Number of documents to return",
+    )
+}
+
+pub fn argument(
+    name: &str,
+    argument_type: Type,
+    description: &str,
+) -> (ndc::ArgumentName, ObjectField) {
+    let (name, mut argument) = ObjectField::new(name, argument_type);
+    argument.description = Some(description.to_owned());
+    (name.into(), argument)
+}
+
+/// The `$vectorSearch` stage specification, without a filter
+pub fn vector_search_spec(vector_search: &VectorSearch) -> Document {
+    let mut spec = doc! {
+        "index": vector_search.index.as_str(),
+        "path": vector_search.path.as_str(),
+        "queryVector": placeholder(EMBEDDING_ARGUMENT),
+        "limit": placeholder(K_ARGUMENT),
+    };
+    match vector_search.num_candidates {
+        Some(num_candidates) => spec.insert("numCandidates", num_candidates as i64),
+        None => spec.insert("exact", true),
+    };
+    spec
+}
+
+pub fn placeholder(argument: &str) -> String {
+    format!("{{{{ {argument} }}}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use mongodb_support::BsonScalarType;
+    use pretty_assertions::assert_eq;
+
+    use crate::schema::{ObjectField, ObjectType, Type, VectorSearch};
+
+    use super::vector_search_collection;
+
+    #[test]
+    fn generates_vector_search_pipeline_with_score_field() -> anyhow::Result<()> {
+        let collection_type = ObjectType {
+            fields: [ObjectField::new(
+                "title",
+                Type::Scalar(BsonScalarType::String),
+            )]
+            .into_iter()
+            .map(|(name, field)| (name.into(), field))
+            .collect(),
+            description: None,
+            extends: Default::default(),
+        };
+        let vector_search = VectorSearch {
+            index: "plot_index".into(),
+            path: "plot_embedding".into(),
+            num_candidates: Some(100),
+        };
+        let (name, native_query) =
+            vector_search_collection(&"movies".into(), &collection_type, &vector_search)?;
+        assert_eq!(name, "movies_plot_embedding_vector_search".into());
+        assert_eq!(
+            native_query.pipeline,
+            vec![
+                doc! {
+                    "$vectorSearch": {
+                        "index": "plot_index",
+                        "path": "plot_embedding",
+                        "queryVector": "{{ embedding }}",
+                        "limit": "{{ k }}",
+                        "numCandidates": 100_i64,
+                        "filter": "{{ filter }}",
+                    }
+                },
+                doc! { "$set": { "_score": { "$meta": "vectorSearchScore" } } },
+            ]
+        );
+        let result_type = &native_query.object_types
+            [&ndc_models::ObjectTypeName::from("movies_plot_embedding_vector_search_result")];
+        assert_eq!(
+            result_type
+                .fields
+                .keys()
+                .map(|k| k.as_str())
+                .collect::<Vec<_>>(),
+            vec!["_score", "title"]
+        );
+        Ok(())
+    }
+}
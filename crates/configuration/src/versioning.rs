@@ -0,0 +1,242 @@
+//! Optimistic concurrency for native mutations. A collection in the schema may declare
+//! a `versionField`. Native mutations that run an `update` or `findAndModify` command against
+//! such a collection get an additional `expectedVersion` argument. The command is rewritten so
+//! that it only matches documents where the version field has the expected value, and so that it
+//! increments the version field. If no document matches the connector responds with a conflict
+//! error.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context as _};
+use mongodb::bson::{self, doc, Bson, Document};
+use ndc_models as ndc;
+
+use crate::{
+    schema::{ObjectField, Type},
+    serialized,
+};
+
+pub const EXPECTED_VERSION_ARGUMENT: &str = "expectedVersion";
+
+/// Rewrites native mutations that update documents in collections with a version field. Returns
+/// the version field for each rewritten mutation.
+pub fn apply_version_checks(
+    schema: &serialized::Schema,
+    native_mutations: &mut BTreeMap<ndc::ProcedureName, serialized::NativeMutation>,
+) -> anyhow::Result<BTreeMap<ndc::ProcedureName, ndc::FieldName>> {
+    let mut version_fields = BTreeMap::new();
+    for (name, native_mutation) in native_mutations.iter_mut() {
+        let Some((version_field, version_type)) =
+            version_field_for_command(schema, &native_mutation.command)
+        else {
+            continue;
+        };
+        check_version_checked_command(&native_mutation.command)
+            .with_context(|| format!("in the native mutation, {name}"))?;
+        native_mutation.command =
+            add_version_check(native_mutation.command.clone(), &version_field);
+        if !native_mutation
+            .arguments
+            .contains_key(EXPECTED_VERSION_ARGUMENT)
+        {
+            let (_, argument) = ObjectField::new(EXPECTED_VERSION_ARGUMENT, version_type);
+            native_mutation
+                .arguments
+                .insert(EXPECTED_VERSION_ARGUMENT.into(), argument);
+        }
+        version_fields.insert(name.clone(), version_field);
+    }
+    Ok(version_fields)
+}
+
+/// The version check is added to the literal structure of the command, so the parts of the
+/// command that it rewrites may not be argument placeholders. An `update` command may only have
+/// one statement because the command result reports the total number of matched documents, so
+/// a stale version in one statement could not be detected when another statement matches.
+fn check_version_checked_command(command: &Document) -> anyhow::Result<()> {
+    if command.contains_key("updates") {
+        let Ok(statements) = command.get_array("updates") else {
+            bail!(
+                "`updates` must be an array of statements when the collection has a version field"
+            );
+        };
+        let [Bson::Document(statement)] = statements.as_slice() else {
+            bail!(
+                "`updates` must have exactly one statement when the collection has a version field"
+            );
+        };
+        check_update_spec(statement.get("u"), "u")
+    } else if command.get_bool("remove").unwrap_or(false) {
+        Ok(())
+    } else {
+        check_update_spec(command.get("update"), "update")
+    }
+}
+
+fn check_update_spec(update: Option<&Bson>, key: &str) -> anyhow::Result<()> {
+    match update {
+        Some(Bson::Document(_) | Bson::Array(_)) => Ok(()),
+        Some(_) => bail!(
+            "`{key}` must be an update document, an update pipeline, or a replacement document \
+            when the collection has a version field"
+        ),
+        None => bail!("the command must have an `{key}` field"),
+    }
+}
+
+fn version_field_for_command(
+    schema: &serialized::Schema,
+    command: &Document,
+) -> Option<(ndc::FieldName, Type)> {
+    let (command_name, Bson::String(collection_name)) = command.iter().next()? else {
+        return None;
+    };
+    if !matches!(
+        command_name.as_str(),
+        "update" | "findAndModify" | "findandmodify"
+    ) {
+        return None;
+    }
+    let collection = schema.collections.get(collection_name.as_str())?;
+    let version_field = collection.version_field.as_ref()?;
+    let version_type = schema
+        .object_types
+        .get(&collection.r#type)?
+        .fields
+        .get(version_field)?
+        .r#type
+        .clone();
+    Some((version_field.clone(), version_type))
+}
+
+fn add_version_check(mut command: Document, version_field: &ndc::FieldName) -> Document {
+    let placeholder = format!("{{{{ {EXPECTED_VERSION_ARGUMENT} }}}}");
+    let with_expected_version = |filter: Document| -> Document {
+        if filter.contains_key(version_field.as_str()) {
+            doc! { "$and": [filter, { version_field.as_str(): placeholder.clone() }] }
+        } else {
+            let mut filter = filter;
+            filter.insert(version_field.as_str(), placeholder.clone());
+            filter
+        }
+    };
+
+    if let Ok(statements) = command.get_array_mut("updates") {
+        for statement in statements.iter_mut() {
+            if let Bson::Document(statement) = statement {
+                let filter = statement.get_document("q").cloned().unwrap_or_default();
+                statement.insert("q", with_expected_version(filter));
+                if let Some(update) = statement.remove("u") {
+                    statement.insert("u", increment_version(update, version_field));
+                }
+            }
+        }
+    } else if !command.get_bool("remove").unwrap_or(false) {
+        let filter = command.get_document("query").cloned().unwrap_or_default();
+        command.insert("query", with_expected_version(filter));
+        if let Some(update) = command.remove("update") {
+            command.insert("update", increment_version(update, version_field));
+        }
+    }
+    command
+}
+
+/// Adds an increment of the version field to an update document, an update pipeline, or
+/// a replacement document.
+fn increment_version(update: Bson, version_field: &ndc::FieldName) -> Bson {
+    let field = version_field.as_str();
+    let next_version = doc! { "$add": [format!("${field}"), 1] };
+    match update {
+        Bson::Document(mut update) if is_update_document(&update) => {
+            match update.get_document_mut("$inc") {
+                Ok(increments) => {
+                    increments.insert(field, 1);
+                }
+                Err(_) => {
+                    update.insert("$inc", doc! { field: 1 });
+                }
+            }
+            Bson::Document(update)
+        }
+        Bson::Array(mut pipeline) => {
+            pipeline.push(doc! { "$set": { field: next_version } }.into());
+            Bson::Array(pipeline)
+        }
+        // A replacement document is turned into a pipeline so that the new version can be
+        // computed from the current document.
+        replacement => bson::bson!([{
+            "$replaceWith": {
+                "$mergeObjects": [
+                    { "_id": "$_id" },
+                    { "$literal": replacement },
+                    { field: next_version },
+                ]
+            }
+        }]),
+    }
+}
+
+fn is_update_document(update: &Document) -> bool {
+    update.keys().next().is_some_and(|key| key.starts_with('$'))
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    use super::{add_version_check, check_version_checked_command};
+
+    #[test]
+    fn adds_expected_version_to_filter_and_increments_version() {
+        let command = doc! {
+            "update": "posts",
+            "updates": [{
+                "q": { "_id": "{{ id }}" },
+                "u": { "$set": { "title": "{{ title }}" } },
+            }],
+        };
+        assert_eq!(
+            add_version_check(command, &"version".into()),
+            doc! {
+                "update": "posts",
+                "updates": [{
+                    "q": { "_id": "{{ id }}", "version": "{{ expectedVersion }}" },
+                    "u": { "$set": { "title": "{{ title }}" }, "$inc": { "version": 1 } },
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_commands_that_cannot_be_version_checked() {
+        let placeholder_update = doc! {
+            "update": "posts",
+            "updates": [{ "q": { "_id": "{{ id }}" }, "u": "{{ update }}" }],
+        };
+        assert!(check_version_checked_command(&placeholder_update).is_err());
+
+        let multiple_statements = doc! {
+            "update": "posts",
+            "updates": [
+                { "q": { "_id": "{{ a }}" }, "u": { "$set": { "title": "a" } } },
+                { "q": { "_id": "{{ b }}" }, "u": { "$set": { "title": "b" } } },
+            ],
+        };
+        assert!(check_version_checked_command(&multiple_statements).is_err());
+
+        let placeholder_find_and_modify = doc! {
+            "findAndModify": "posts",
+            "query": { "_id": "{{ id }}" },
+            "update": "{{ update }}",
+        };
+        assert!(check_version_checked_command(&placeholder_find_and_modify).is_err());
+
+        let replacement = doc! {
+            "findAndModify": "posts",
+            "query": { "_id": "{{ id }}" },
+            "update": { "title": "{{ title }}" },
+        };
+        assert!(check_version_checked_command(&replacement).is_ok());
+    }
+}
@@ -0,0 +1,49 @@
+//! Window fields declared on collections are added to the collection's object type when
+//! configuration is loaded so that they appear in the schema response, and so that query planning
+//! can find their types.
+
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use ndc_models as ndc;
+
+use crate::schema;
+
+/// Adds window fields to the object type of each collection that declares them. Fails if a window
+/// field has the same name as a field of the object type.
+pub fn add_window_fields(
+    collections: &BTreeMap<ndc::CollectionName, schema::Collection>,
+    object_types: &mut BTreeMap<ndc::ObjectTypeName, schema::ObjectType>,
+) -> anyhow::Result<()> {
+    for (collection_name, collection) in collections {
+        if collection.window_fields.is_empty() {
+            continue;
+        }
+        let object_type = object_types.get_mut(&collection.r#type).ok_or_else(|| {
+            anyhow!(
+                "the collection, {collection_name}, has window fields, but its type, {}, is not defined",
+                collection.r#type
+            )
+        })?;
+        for (field_name, window_field) in &collection.window_fields {
+            if object_type.fields.contains_key(field_name) {
+                return Err(anyhow!(
+                    "the window field, {field_name}, conflicts with a field of the same name in the type of the collection, {collection_name}"
+                ));
+            }
+            object_type.fields.insert(
+                field_name.clone(),
+                schema::ObjectField {
+                    r#type: window_field.r#type.clone(),
+                    description: window_field.description.clone(),
+                    deprecated: false,
+                    previous_names: Default::default(),
+                    read_transform: None,
+                    write_rules: None,
+                    object_id_format: None,
+                },
+            );
+        }
+    }
+    Ok(())
+}
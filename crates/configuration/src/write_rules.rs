@@ -0,0 +1,70 @@
+//! Collects the write rules that apply to the arguments of each native mutation. Rules may be
+//! declared on native mutation arguments, and on fields of object types. Rules on object type
+//! fields apply wherever an object of that type appears in an argument. See
+//! [crate::schema::ObjectField::write_rules].
+
+use std::collections::BTreeMap;
+
+use anyhow::Context as _;
+use ndc_models as ndc;
+use regex::Regex;
+
+use crate::{
+    schema::{ObjectField, ObjectType, WriteRules},
+    serialized,
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArgumentWriteRules {
+    pub arguments: BTreeMap<ndc::ArgumentName, WriteRules>,
+    pub object_fields: BTreeMap<ndc::ObjectTypeName, BTreeMap<ndc::FieldName, WriteRules>>,
+}
+
+impl ArgumentWriteRules {
+    pub fn is_empty(&self) -> bool {
+        self.arguments.is_empty() && self.object_fields.is_empty()
+    }
+}
+
+/// Write rules for fields of each object type that declares any. Fails if a rule has an invalid
+/// pattern.
+pub fn object_field_write_rules<'a>(
+    object_types: impl IntoIterator<Item = (&'a ndc::ObjectTypeName, &'a ObjectType)>,
+) -> anyhow::Result<BTreeMap<ndc::ObjectTypeName, BTreeMap<ndc::FieldName, WriteRules>>> {
+    let mut rules = BTreeMap::new();
+    for (type_name, object_type) in object_types {
+        let field_rules = declared_rules(&object_type.fields)
+            .with_context(|| format!("in the object type, {type_name}"))?;
+        if !field_rules.is_empty() {
+            rules.insert(type_name.clone(), field_rules);
+        }
+    }
+    Ok(rules)
+}
+
+/// Write rules for the arguments of a native mutation, combined with rules for object type fields
+pub fn argument_write_rules(
+    native_mutation: &serialized::NativeMutation,
+    object_fields: &BTreeMap<ndc::ObjectTypeName, BTreeMap<ndc::FieldName, WriteRules>>,
+) -> anyhow::Result<ArgumentWriteRules> {
+    Ok(ArgumentWriteRules {
+        arguments: declared_rules(&native_mutation.arguments)?,
+        object_fields: object_fields.clone(),
+    })
+}
+
+fn declared_rules<Name: Clone + Ord + std::fmt::Display>(
+    fields: &BTreeMap<Name, ObjectField>,
+) -> anyhow::Result<BTreeMap<Name, WriteRules>> {
+    fields
+        .iter()
+        .filter_map(|(name, field)| Some((name, field.write_rules.as_ref()?)))
+        .map(|(name, rules)| {
+            if let Some(pattern) = &rules.pattern {
+                Regex::new(pattern)
+                    .with_context(|| format!("invalid write rule pattern for {name}"))?;
+            }
+            Ok((name.clone(), rules.clone()))
+        })
+        .collect()
+}
@@ -0,0 +1,23 @@
+use crate::run_connector_query;
+use insta::assert_yaml_snapshot;
+use ndc_test_helpers::{binop, column_aggregate, query, query_request, target, value};
+
+#[tokio::test]
+async fn runs_min_and_max_aggregates_over_dates_and_strings() -> anyhow::Result<()> {
+    assert_yaml_snapshot!(
+        run_connector_query(
+            query_request().collection("movies").query(
+                query()
+                    .predicate(binop("_eq", target!("year"), value!(1915)))
+                    .aggregates([
+                        column_aggregate!("min_released" => "released", "min"),
+                        column_aggregate!("max_released" => "released", "max"),
+                        column_aggregate!("min_title" => "title", "min"),
+                        column_aggregate!("max_title" => "title", "max"),
+                    ]),
+            ),
+        )
+        .await?
+    );
+    Ok(())
+}
@@ -1,4 +1,8 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
 use enum_iterator::{all, Sequence};
+use mongodb::bson::Bson;
 
 // TODO: How can we unify this with the Accumulator type in the mongodb module?
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Sequence)]
@@ -8,11 +12,42 @@ pub enum AggregationFunction {
     Min,
     Max,
     Sum,
+    /// Counts documents for each distinct value of a column. Produces an array of `{ value, count
+    /// }` documents in descending order of count so that a query can return facet counts for
+    /// several columns alongside its rows.
+    FacetCounts,
+    /// Computes a percentile of a numeric column given by the `p` argument. Requires MongoDB 7.0
+    /// or later.
+    Percentile,
+    /// Joins the values of a string column using the `separator` argument
+    Concat,
+    /// Counts documents where the column compares to the `value` argument with the `operator`
+    /// argument, which defaults to `_eq`. Several filtered counts can be requested in one query.
+    CountWhere,
 }
 
+use ndc_models as ndc;
 use ndc_query_plan::QueryPlanError;
 use AggregationFunction as A;
 
+use crate::{comparison_function::ComparisonFunction, interface_types::MongoAgentError};
+
+/// Arguments for an aggregation function. Functions that are declared in configuration apply
+/// a built-in function with fixed arguments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AggregationFunctionArguments {
+    /// Ignore repeated values
+    pub distinct: bool,
+    /// The percentile to compute, between 0 and 1
+    pub p: Option<f64>,
+    /// String to put between concatenated values
+    pub separator: Option<String>,
+    /// Operator that compares column values to `value`
+    pub operator: Option<ComparisonFunction>,
+    /// Value to compare column values to, parsed from extended JSON
+    pub value: Option<Bson>,
+}
+
 impl AggregationFunction {
     pub fn graphql_name(self) -> &'static str {
         match self {
@@ -21,6 +56,10 @@ impl AggregationFunction {
             A::Min => "min",
             A::Max => "max",
             A::Sum => "sum",
+            A::FacetCounts => "facet_counts",
+            A::Percentile => "percentile",
+            A::Concat => "concat",
+            A::CountWhere => "count_where",
         }
     }
 
@@ -32,6 +71,67 @@ impl AggregationFunction {
             })
     }
 
+    /// Names of the arguments that this function accepts
+    pub fn argument_names(self) -> &'static [&'static str] {
+        match self {
+            A::Avg | A::Count | A::Sum => &["distinct"],
+            A::Min | A::Max | A::FacetCounts => &[],
+            A::Percentile => &["p"],
+            A::Concat => &["distinct", "separator"],
+            A::CountWhere => &["operator", "value"],
+        }
+    }
+
+    pub fn parse_arguments(
+        self,
+        arguments: &BTreeMap<ndc::ArgumentName, serde_json::Value>,
+    ) -> Result<AggregationFunctionArguments, MongoAgentError> {
+        let invalid = |message: String| {
+            MongoAgentError::BadQuery(anyhow!(
+                "invalid arguments for aggregation function {}: {message}",
+                self.graphql_name()
+            ))
+        };
+        let mut parsed = AggregationFunctionArguments::default();
+        for (name, value) in arguments {
+            if !self.argument_names().contains(&name.as_str()) {
+                return Err(invalid(format!("unknown argument, {name}")));
+            }
+            match (name.as_str(), value) {
+                ("distinct", serde_json::Value::Bool(distinct)) => parsed.distinct = *distinct,
+                ("p", serde_json::Value::Number(p)) => {
+                    parsed.p = p.as_f64().filter(|p| (0.0..=1.0).contains(p));
+                    if parsed.p.is_none() {
+                        return Err(invalid(format!("p must be between 0 and 1, got {p}")));
+                    }
+                }
+                ("separator", serde_json::Value::String(separator)) => {
+                    parsed.separator = Some(separator.clone())
+                }
+                ("operator", serde_json::Value::String(operator)) => {
+                    let operator = ComparisonFunction::from_graphql_name(operator)
+                        .map_err(|err| invalid(err.to_string()))?;
+                    parsed.operator = Some(operator)
+                }
+                ("value", value) => {
+                    let value = Bson::try_from(value.clone())
+                        .map_err(|err| invalid(format!("value is not extended JSON: {err}")))?;
+                    parsed.value = Some(value)
+                }
+                (name, value) => {
+                    return Err(invalid(format!("unexpected value for {name}: {value}")))
+                }
+            }
+        }
+        if self == A::Percentile && parsed.p.is_none() {
+            return Err(invalid("missing required argument, p".to_owned()));
+        }
+        if self == A::CountWhere && parsed.value.is_none() {
+            return Err(invalid("missing required argument, value".to_owned()));
+        }
+        Ok(parsed)
+    }
+
     pub fn is_count(self) -> bool {
         match self {
             A::Avg => false,
@@ -39,6 +139,10 @@ impl AggregationFunction {
             A::Min => false,
             A::Max => false,
             A::Sum => false,
+            A::FacetCounts => false,
+            A::Percentile => false,
+            A::Concat => false,
+            A::CountWhere => true,
         }
     }
 }
@@ -0,0 +1,181 @@
+//! Per-collection concurrency limits. Each collection gets its own semaphore sized from
+//! configuration, so queries that pile up on one collection wait for that collection's slots
+//! instead of taking connections that queries on other collections need. Time spent waiting for
+//! a slot is reported in the `ndc_mongodb_collection_queue_seconds` histogram.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use configuration::ConfigurationCollectionConcurrencyOptions;
+use ndc_models as ndc;
+use prometheus::{HistogramOpts, HistogramVec, IntGauge, IntGaugeVec, Opts, Registry};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Default)]
+pub struct Bulkheads {
+    semaphores: Mutex<HashMap<ndc::CollectionName, Arc<Semaphore>>>,
+    metrics: Option<BulkheadMetrics>,
+}
+
+#[derive(Clone)]
+struct BulkheadMetrics {
+    queue_seconds: HistogramVec,
+    waiting: IntGaugeVec,
+}
+
+impl Bulkheads {
+    /// Registers metrics for time spent waiting for slots, and for the number of queries that are
+    /// waiting, both labeled by collection.
+    pub fn with_metrics(registry: &Registry) -> Result<Self, prometheus::Error> {
+        let queue_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "ndc_mongodb_collection_queue_seconds",
+                "Time that queries waited for a per-collection concurrency slot",
+            ),
+            &["collection"],
+        )?;
+        let waiting = IntGaugeVec::new(
+            Opts::new(
+                "ndc_mongodb_collection_queued_queries",
+                "Number of queries waiting for a per-collection concurrency slot",
+            ),
+            &["collection"],
+        )?;
+        registry.register(Box::new(queue_seconds.clone()))?;
+        registry.register(Box::new(waiting.clone()))?;
+        Ok(Bulkheads {
+            semaphores: Default::default(),
+            metrics: Some(BulkheadMetrics {
+                queue_seconds,
+                waiting,
+            }),
+        })
+    }
+
+    /// Waits for a slot for a query on the given collection. The slot is released when the
+    /// returned permit is dropped.
+    pub async fn acquire(
+        &self,
+        options: &ConfigurationCollectionConcurrencyOptions,
+        collection: &ndc::CollectionName,
+    ) -> OwnedSemaphorePermit {
+        let semaphore = self
+            .semaphores
+            .lock()
+            .unwrap()
+            .entry(collection.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(options.limit_for(collection).max(1))))
+            .clone();
+
+        let start = Instant::now();
+        let waiting = Waiting::start(
+            self.metrics
+                .as_ref()
+                .map(|metrics| metrics.waiting.with_label_values(&[collection.as_str()])),
+        );
+        let permit = semaphore
+            .acquire_owned()
+            .await
+            .expect("collection semaphores are never closed");
+        drop(waiting);
+
+        let queue_time = start.elapsed();
+        if let Some(metrics) = &self.metrics {
+            metrics
+                .queue_seconds
+                .with_label_values(&[collection.as_str()])
+                .observe(queue_time.as_secs_f64());
+        }
+        tracing::debug!(%collection, ?queue_time, "acquired collection concurrency slot");
+        permit
+    }
+}
+
+/// Counts a query as waiting until dropped, including when the waiting query is cancelled
+struct Waiting(Option<IntGauge>);
+
+impl Waiting {
+    fn start(gauge: Option<IntGauge>) -> Self {
+        if let Some(gauge) = &gauge {
+            gauge.inc();
+        }
+        Waiting(gauge)
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(gauge) = &self.0 {
+            gauge.dec();
+        }
+    }
+}
+
+impl fmt::Debug for Bulkheads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bulkheads")
+            .field("semaphores", &self.semaphores)
+            .field("metrics", &self.metrics.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use configuration::ConfigurationCollectionConcurrencyOptions;
+    use prometheus::Registry;
+
+    use super::Bulkheads;
+
+    #[tokio::test]
+    async fn queues_queries_per_collection() -> anyhow::Result<()> {
+        let options = ConfigurationCollectionConcurrencyOptions {
+            max_concurrent_queries: 1,
+            collections: [("movies".into(), 2)].into(),
+        };
+        let registry = Registry::new();
+        let bulkheads = Bulkheads::with_metrics(&registry)?;
+        let short_wait = Duration::from_millis(20);
+
+        let comments = bulkheads.acquire(&options, &"comments".into()).await;
+        let blocked =
+            tokio::time::timeout(short_wait, bulkheads.acquire(&options, &"comments".into()));
+        assert!(blocked.await.is_err(), "second comments query should wait");
+
+        // Other collections have their own slots
+        let _movies_1 = bulkheads.acquire(&options, &"movies".into()).await;
+        let _movies_2 = bulkheads.acquire(&options, &"movies".into()).await;
+
+        drop(comments);
+        let unblocked =
+            tokio::time::timeout(short_wait, bulkheads.acquire(&options, &"comments".into()));
+        assert!(unblocked.await.is_ok(), "comments slot should be released");
+
+        let queue_counts = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "ndc_mongodb_collection_queue_seconds")
+            .map(|family| family.get_metric().len());
+        assert_eq!(queue_counts, Some(2));
+
+        let waiting = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "ndc_mongodb_collection_queued_queries")
+            .map(|family| {
+                family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| metric.get_gauge().get_value())
+                    .sum::<f64>()
+            });
+        assert_eq!(waiting, Some(0.0));
+        Ok(())
+    }
+}
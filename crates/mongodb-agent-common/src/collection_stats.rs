@@ -0,0 +1,150 @@
+//! Cached collection statistics. This is enabled by the `collectionStats` configuration option.
+//! A background task reads the document count and average document size of each collection in
+//! the schema with `$collStats` on a fixed interval, and writes them to a [CollectionStatsCache]
+//! held in connector state. Query planning reads the cached values instead of querying statistics
+//! for each request. Collections that have not been read yet, or that failed to refresh, have no
+//! statistics.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+use configuration::{collection_stats_pipeline, ConfigurationCollectionStatsOptions};
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{Bson, Document},
+    Database,
+};
+use ndc_models as ndc;
+use ndc_query_plan::QueryContext as _;
+use tokio::task::JoinHandle;
+
+use crate::{interface_types::MongoAgentError, mongo_query_plan::MongoConfiguration};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CollectionStats {
+    pub count: u64,
+    /// Average uncompressed size of documents in bytes. This is `None` if the collection is empty.
+    pub avg_obj_size: Option<f64>,
+}
+
+/// The most recently read statistics for each collection
+#[derive(Clone, Debug, Default)]
+pub struct CollectionStatsCache {
+    stats: Arc<RwLock<HashMap<ndc::CollectionName, CollectionStats>>>,
+}
+
+impl CollectionStatsCache {
+    /// The most recently read statistics for the given collection
+    pub fn get(&self, collection: &ndc::CollectionName) -> Option<CollectionStats> {
+        self.stats
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(collection)
+            .copied()
+    }
+
+    pub(crate) fn insert(&self, collection: ndc::CollectionName, stats: CollectionStats) {
+        self.stats
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(collection, stats);
+    }
+}
+
+/// Starts a task that refreshes statistics for each collection in the schema. The task runs until
+/// it is aborted.
+pub fn spawn_refresh_task(
+    config: &MongoConfiguration,
+    options: &ConfigurationCollectionStatsOptions,
+    database: Database,
+    cache: CollectionStatsCache,
+) -> JoinHandle<()> {
+    // Native queries are listed as collections too, but have no statistics of their own
+    let collections: Vec<ndc::CollectionName> = config
+        .collections()
+        .keys()
+        .filter(|name| !config.native_queries().contains_key(name.as_str()))
+        .cloned()
+        .collect();
+    let refresh_interval = Duration::from_secs(options.refresh_interval_seconds.max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(refresh_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for collection in &collections {
+                match read_collection_stats(&database, collection).await {
+                    Ok(Some(stats)) => cache.insert(collection.clone(), stats),
+                    Ok(None) => (),
+                    Err(err) => tracing::warn!(
+                        %collection,
+                        error = %err,
+                        "failed to refresh collection statistics"
+                    ),
+                }
+            }
+        }
+    })
+}
+
+async fn read_collection_stats(
+    database: &Database,
+    collection: &ndc::CollectionName,
+) -> Result<Option<CollectionStats>, MongoAgentError> {
+    let documents: Vec<Document> = database
+        .collection::<Document>(collection.as_str())
+        .aggregate(collection_stats_pipeline(), None)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(documents.first().and_then(parse_collection_stats))
+}
+
+fn parse_collection_stats(document: &Document) -> Option<CollectionStats> {
+    let count = match document.get("count")? {
+        Bson::Int64(n) => u64::try_from(*n).ok()?,
+        Bson::Int32(n) => u64::try_from(*n).ok()?,
+        _ => return None,
+    };
+    let avg_obj_size = match document.get("avgObjSize") {
+        Some(Bson::Double(n)) => Some(*n),
+        Some(Bson::Int32(n)) => Some(*n as f64),
+        Some(Bson::Int64(n)) => Some(*n as f64),
+        _ => None,
+    };
+    Some(CollectionStats {
+        count,
+        avg_obj_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, Bson};
+
+    use super::{parse_collection_stats, CollectionStats};
+
+    #[test]
+    fn reads_count_and_average_size_from_stats_document() {
+        let stats = doc! { "count": 4_i64, "size": 1000_i64, "avgObjSize": 250.0 };
+        assert_eq!(
+            parse_collection_stats(&stats),
+            Some(CollectionStats {
+                count: 4,
+                avg_obj_size: Some(250.0)
+            })
+        );
+
+        let empty = doc! { "count": 0_i64, "size": 0_i64, "avgObjSize": Bson::Null };
+        assert_eq!(
+            parse_collection_stats(&empty),
+            Some(CollectionStats {
+                count: 0,
+                avg_obj_size: None
+            })
+        );
+    }
+}
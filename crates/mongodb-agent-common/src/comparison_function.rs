@@ -1,5 +1,10 @@
+use configuration::ConfigurationRegexOptions;
 use enum_iterator::{all, Sequence};
 use mongodb::bson::{doc, Bson, Document};
+use mongodb_support::BsonScalarType;
+use regex_syntax::ast::{self, parse::ParserBuilder};
+
+use crate::mongodb::sanitize::is_name_safe;
 
 /// Supported binary comparison operators. This type provides GraphQL names, MongoDB operator
 /// names, and aggregation pipeline code for each operator. Argument types are defined in
@@ -13,12 +18,41 @@ pub enum ComparisonFunction {
     LessThanOrEqual,
     GreaterThan,
     GreaterThanOrEqual,
+    /// Inclusive range comparison with a `[lower, upper]` argument
+    Between,
     Equal,
     NotEqual,
+    /// Tests whether a field is present, given `true`, or absent, given `false`. Unlike `_is_null`
+    /// this distinguishes a missing field from a field with a null value.
+    IsSet,
+    /// Compares the runtime BSON type of a field to a type alias such as `"string"` or `"object"`.
+    /// Declared for fields of type `ExtendedJSON` whose values may have any type.
+    TypeEqual,
+    /// Compares a value nested in an `ExtendedJSON` field to a literal. The argument is an object
+    /// with a `path` array of field names, and a `value` to compare to.
+    PathEqual,
+    /// Case-insensitive string equality. Compiles to `$eq` when a case-insensitive collation is
+    /// configured, or to a comparison of lower-cased strings otherwise.
+    IEqual,
 
     Regex,
     /// case-insensitive regex
     IRegex,
+
+    /// Compares the remainder of dividing an integer by the first element of a `[divisor,
+    /// remainder]` argument to the second element
+    ModEqual,
+    /// True if an integer is divisible by the argument
+    DivisibleBy,
+
+    /// String operators that match a literal substring without regular expression syntax. Each has
+    /// a case-insensitive variant.
+    StartsWith,
+    IStartsWith,
+    EndsWith,
+    IEndsWith,
+    Contains,
+    IContains,
 }
 
 use ndc_query_plan::QueryPlanError;
@@ -31,10 +65,23 @@ impl ComparisonFunction {
             C::LessThanOrEqual => "_lte",
             C::GreaterThan => "_gt",
             C::GreaterThanOrEqual => "_gte",
+            C::Between => "_between",
             C::Equal => "_eq",
             C::NotEqual => "_neq",
+            C::IEqual => "_ieq",
+            C::IsSet => "_is_set",
+            C::TypeEqual => "_type_eq",
+            C::PathEqual => "_path_eq",
             C::Regex => "_regex",
             C::IRegex => "_iregex",
+            C::ModEqual => "_mod_eq",
+            C::DivisibleBy => "_divisible_by",
+            C::StartsWith => "_starts_with",
+            C::IStartsWith => "_istarts_with",
+            C::EndsWith => "_ends_with",
+            C::IEndsWith => "_iends_with",
+            C::Contains => "_contains",
+            C::IContains => "_icontains",
         }
     }
 
@@ -44,10 +91,20 @@ impl ComparisonFunction {
             C::LessThanOrEqual => "$lte",
             C::GreaterThan => "$gt",
             C::GreaterThanOrEqual => "$gte",
+            C::Between => "$gte",
             C::Equal => "$eq",
             C::NotEqual => "$ne",
+            C::IEqual => "$eq",
+            C::IsSet => "$exists",
+            C::TypeEqual => "$type",
+            C::PathEqual => "$eq",
             C::Regex => "$regex",
             C::IRegex => "$regex",
+            C::ModEqual => "$mod",
+            C::DivisibleBy => "$mod",
+            C::StartsWith | C::IStartsWith => "$regex",
+            C::EndsWith | C::IEndsWith => "$regex",
+            C::Contains | C::IContains => "$regex",
         }
     }
 
@@ -59,6 +116,16 @@ impl ComparisonFunction {
             ))
     }
 
+    /// True for operators that evaluate to unknown, and so exclude a document, under SQL
+    /// three-valued logic when an operand is null. MongoDB's `$ne` and aggregation-expression
+    /// ordering operators instead treat null as an ordinary value.
+    pub fn is_null_sensitive(self) -> bool {
+        matches!(
+            self,
+            C::LessThan | C::LessThanOrEqual | C::GreaterThan | C::GreaterThanOrEqual | C::NotEqual
+        )
+    }
+
     /// Produce a MongoDB expression for use in a match query that applies this function to the given operands.
     pub fn mongodb_match_query(
         self,
@@ -69,6 +136,63 @@ impl ComparisonFunction {
             C::IRegex => {
                 doc! { column_ref: { self.mongodb_name(): comparison_value, "$options": "i" } }
             }
+            // Without a collation there is no match query operator for case-insensitive equality
+            C::IEqual => doc! {
+                "$expr": self.mongodb_aggregation_expression(
+                    format!("${}", Into::<String>::into(column_ref)),
+                    comparison_value,
+                )
+            },
+            C::Between => {
+                let (lower, upper) = array_pair(comparison_value);
+                doc! { column_ref: { "$gte": lower, "$lte": upper } }
+            }
+            // Use a dotted match key when possible so that the comparison can use an index
+            C::PathEqual => {
+                let column_ref: String = column_ref.into();
+                let match_key = path_and_value(&comparison_value)
+                    .filter(|(path, _)| path.iter().all(|name| is_name_safe(name)))
+                    .map(|(path, value)| (format!("{column_ref}.{}", path.join(".")), value));
+                match match_key {
+                    Some((key, value)) => doc! { key: { "$eq": value } },
+                    None => doc! {
+                        "$expr": self.mongodb_aggregation_expression(
+                            format!("${column_ref}"),
+                            comparison_value,
+                        )
+                    },
+                }
+            }
+            C::ModEqual | C::DivisibleBy => {
+                let (divisor, remainder) = self.modulus_operands(comparison_value);
+                // The server rejects a divisor of zero, so that comparison matches nothing instead
+                if is_zero(&divisor) {
+                    doc! { column_ref: { "$in": [] } }
+                } else {
+                    doc! { column_ref: { "$mod": [divisor, remainder] } }
+                }
+            }
+            C::StartsWith
+            | C::IStartsWith
+            | C::EndsWith
+            | C::IEndsWith
+            | C::Contains
+            | C::IContains => match comparison_value {
+                Bson::String(substring) => {
+                    let pattern = self.substring_pattern(&substring);
+                    if self.is_case_insensitive() {
+                        doc! { column_ref: { "$regex": pattern, "$options": "i" } }
+                    } else {
+                        doc! { column_ref: { "$regex": pattern } }
+                    }
+                }
+                value => doc! {
+                    "$expr": self.mongodb_aggregation_expression(
+                        format!("${}", Into::<String>::into(column_ref)),
+                        value,
+                    )
+                },
+            },
             _ => doc! { column_ref: { self.mongodb_name(): comparison_value } },
         }
     }
@@ -87,7 +211,395 @@ impl ComparisonFunction {
             C::IRegex => {
                 doc! { "$regexMatch": { "input": column_ref, "regex": comparison_value, "options": "i" } }
             }
+            C::IEqual => doc! {
+                "$eq": [{ "$toLower": column_ref }, { "$toLower": comparison_value }]
+            },
+            // There is no `$exists` in aggregation expressions, but `$type` reports missing fields
+            C::IsSet => doc! {
+                "$eq": [{ "$ne": [{ "$type": column_ref }, "missing"] }, comparison_value]
+            },
+            // The `$type` expression reports the specific type of a number, so the `"number"`
+            // alias that match queries accept needs a separate check
+            C::TypeEqual => {
+                let type_name: Bson = comparison_value.into();
+                match type_name {
+                    Bson::String(alias) if alias == "number" => {
+                        doc! { "$isNumber": column_ref }
+                    }
+                    type_name => doc! { "$eq": [{ "$type": column_ref }, type_name] },
+                }
+            }
+            C::PathEqual => {
+                let column_ref: Bson = column_ref.into();
+                let comparison_value: Bson = comparison_value.into();
+                match path_and_value(&comparison_value) {
+                    Some((path, value)) => doc! {
+                        "$eq": [get_field_chain(column_ref, path), { "$literal": value }]
+                    },
+                    None => doc! {
+                        "$eq": [
+                            get_path_dynamically(column_ref, &comparison_value),
+                            { "$getField": { "field": "value", "input": comparison_value } },
+                        ]
+                    },
+                }
+            }
+            C::Between => {
+                let column_ref: Bson = column_ref.into();
+                let (lower, upper) = array_pair(comparison_value.into());
+                doc! {
+                    "$and": [
+                        { "$gte": [column_ref.clone(), lower] },
+                        { "$lte": [column_ref, upper] },
+                    ]
+                }
+            }
+            C::ModEqual | C::DivisibleBy => {
+                let (divisor, remainder) = self.modulus_operands(comparison_value.into());
+                // The divisor may be a variable, so check for zero when the expression is
+                // evaluated
+                doc! {
+                    "$cond": {
+                        "if": { "$in": [divisor.clone(), [0, null]] },
+                        "then": false,
+                        "else": { "$eq": [{ "$mod": [column_ref, divisor] }, remainder] },
+                    }
+                }
+            }
+            C::StartsWith
+            | C::IStartsWith
+            | C::EndsWith
+            | C::IEndsWith
+            | C::Contains
+            | C::IContains => {
+                let column_ref: Bson = column_ref.into();
+                let comparison_value: Bson = comparison_value.into();
+                match comparison_value {
+                    Bson::String(substring) => {
+                        let pattern = self.substring_pattern(&substring);
+                        if self.is_case_insensitive() {
+                            doc! { "$regexMatch": { "input": column_ref, "regex": pattern, "options": "i" } }
+                        } else {
+                            doc! { "$regexMatch": { "input": column_ref, "regex": pattern } }
+                        }
+                    }
+                    // The substring is not known until the expression is evaluated so it cannot be
+                    // escaped for use in a regular expression
+                    substring => self.substring_expression(column_ref, substring),
+                }
+            }
             _ => doc! { self.mongodb_name(): [column_ref, comparison_value] },
         }
     }
+
+    pub fn is_regex(self) -> bool {
+        matches!(self, C::Regex | C::IRegex)
+    }
+
+    fn is_case_insensitive(self) -> bool {
+        matches!(
+            self,
+            C::IRegex | C::IStartsWith | C::IEndsWith | C::IContains
+        )
+    }
+
+    /// Anchored regular expression that matches the given literal substring
+    fn substring_pattern(self, substring: &str) -> String {
+        let escaped = regex::escape(substring);
+        match self {
+            C::StartsWith | C::IStartsWith => format!("^{escaped}"),
+            C::EndsWith | C::IEndsWith => format!("{escaped}\\z"),
+            _ => escaped,
+        }
+    }
+
+    /// Compares code points of a string column to a substring given by an expression
+    fn substring_expression(self, column_ref: Bson, substring: Bson) -> Document {
+        let (input, substring) = if self.is_case_insensitive() {
+            (
+                Bson::from(doc! { "$toLower": column_ref }),
+                Bson::from(doc! { "$toLower": substring }),
+            )
+        } else {
+            (column_ref, substring)
+        };
+        let condition = match self {
+            C::StartsWith | C::IStartsWith => {
+                doc! { "$eq": [{ "$indexOfCP": ["$$input", "$$substring"] }, 0] }
+            }
+            C::EndsWith | C::IEndsWith => doc! {
+                "$and": [
+                    { "$gte": [{ "$strLenCP": "$$input" }, { "$strLenCP": "$$substring" }] },
+                    { "$eq": [
+                        {
+                            "$substrCP": [
+                                "$$input",
+                                { "$subtract": [{ "$strLenCP": "$$input" }, { "$strLenCP": "$$substring" }] },
+                                { "$strLenCP": "$$substring" },
+                            ]
+                        },
+                        "$$substring",
+                    ] },
+                ]
+            },
+            _ => doc! { "$gte": [{ "$indexOfCP": ["$$input", "$$substring"] }, 0] },
+        };
+        // `$strLenCP` and `$indexOfCP` fail on values that are not strings
+        doc! {
+            "$let": {
+                "vars": { "input": input, "substring": substring },
+                "in": {
+                    "$and": [
+                        { "$eq": [{ "$type": "$$input" }, "string"] },
+                        { "$eq": [{ "$type": "$$substring" }, "string"] },
+                        condition,
+                    ]
+                },
+            }
+        }
+    }
+
+    /// Splits the argument of a modulus operator into a divisor and a remainder. The argument of
+    /// `_mod_eq` is a `[divisor, remainder]` array which may be a literal, or an expression such
+    /// as a variable reference.
+    fn modulus_operands(self, comparison_value: Bson) -> (Bson, Bson) {
+        match self {
+            C::DivisibleBy => (comparison_value, Bson::Int32(0)),
+            _ => array_pair(comparison_value),
+        }
+    }
+}
+
+/// Unpacks the literal argument of `_path_eq`. Returns `None` if the argument is not a literal
+/// object, for example if it is a variable reference.
+fn path_and_value(comparison_value: &Bson) -> Option<(Vec<&str>, Bson)> {
+    let argument = comparison_value.as_document()?;
+    let path = argument
+        .get_array("path")
+        .ok()?
+        .iter()
+        .map(|name| name.as_str())
+        .collect::<Option<Vec<_>>>()?;
+    let value = argument.get("value").cloned().unwrap_or(Bson::Null);
+    Some((path, value))
+}
+
+fn get_field_chain(input: Bson, path: Vec<&str>) -> Bson {
+    path.into_iter().fold(input, |input, name| {
+        doc! { "$getField": { "field": { "$literal": name }, "input": input } }.into()
+    })
+}
+
+/// Follows a path that is not known until the expression is evaluated. `$getField` requires
+/// a constant field name so each step searches the entries of the current object instead.
+fn get_path_dynamically(input: Bson, argument: &Bson) -> Bson {
+    doc! {
+        "$reduce": {
+            "input": { "$getField": { "field": "path", "input": argument.clone() } },
+            "initialValue": input,
+            "in": {
+                "$arrayElemAt": [
+                    {
+                        "$map": {
+                            "input": {
+                                "$filter": {
+                                    "input": {
+                                        "$objectToArray": {
+                                            "$cond": [
+                                                { "$eq": [{ "$type": "$$value" }, "object"] },
+                                                "$$value",
+                                                {},
+                                            ]
+                                        }
+                                    },
+                                    "as": "entry",
+                                    "cond": { "$eq": ["$$entry.k", "$$this"] },
+                                }
+                            },
+                            "as": "entry",
+                            "in": "$$entry.v",
+                        }
+                    },
+                    0,
+                ]
+            },
+        }
+    }
+    .into()
+}
+
+/// Checks that the argument of `_path_eq` has a non-empty `path` of field names and a `value`
+pub fn validate_path_argument(argument: &serde_json::Value) -> Result<(), String> {
+    let path = argument
+        .get("path")
+        .and_then(|path| path.as_array())
+        .ok_or_else(|| "expected a \"path\" array of field names".to_owned())?;
+    if path.is_empty() || !path.iter().all(|name| name.is_string()) {
+        return Err("expected a \"path\" array of one or more field names".to_owned());
+    }
+    if argument.get("value").is_none() {
+        return Err("expected a \"value\" to compare to".to_owned());
+    }
+    Ok(())
+}
+
+/// Splits a two-element array argument. The argument may be a literal, or an expression such as a
+/// variable reference in which case the elements are extracted when the expression is evaluated.
+fn array_pair(value: Bson) -> (Bson, Bson) {
+    match value {
+        Bson::Array(elements) if elements.len() == 2 => (elements[0].clone(), elements[1].clone()),
+        value => (
+            doc! { "$arrayElemAt": [value.clone(), 0] }.into(),
+            doc! { "$arrayElemAt": [value, 1] }.into(),
+        ),
+    }
+}
+
+fn is_zero(value: &Bson) -> bool {
+    match value {
+        Bson::Int32(n) => *n == 0,
+        Bson::Int64(n) => *n == 0,
+        Bson::Double(n) => *n == 0.0,
+        _ => false,
+    }
+}
+
+/// Checks that the argument of `_type_eq` is a type alias that MongoDB recognizes
+pub fn validate_type_alias(alias: &str) -> Result<(), String> {
+    let is_known = matches!(alias, "object" | "array" | "number")
+        || all::<BsonScalarType>().any(|scalar_type| scalar_type.bson_name() == alias);
+    if is_known {
+        Ok(())
+    } else {
+        Err(format!("unknown BSON type alias, \"{alias}\""))
+    }
+}
+
+/// Checks the syntax of a pattern for `_regex` or `_iregex`, and applies configured limits. Patterns
+/// are parsed with Rust regular expression syntax which is close to the PCRE syntax that MongoDB
+/// uses, but PCRE has constructs that Rust does not support, such as `\Z`, atomic groups, and
+/// possessive quantifiers. So only errors that mean the same in both syntaxes, such as unbalanced
+/// groups, are reported. Other patterns are passed to the server which reports their errors.
+pub fn validate_regex_pattern(
+    options: &ConfigurationRegexOptions,
+    pattern: &str,
+) -> Result<(), String> {
+    if let Some(max_length) = options.max_length {
+        let length = pattern.chars().count();
+        if length > max_length {
+            return Err(format!(
+                "pattern is {length} characters long which exceeds the limit of {max_length}"
+            ));
+        }
+    }
+    let mut parser = ParserBuilder::new();
+    if let Some(max_nesting_depth) = options.max_nesting_depth {
+        parser.nest_limit(max_nesting_depth);
+    }
+    match parser.build().parse(pattern) {
+        Ok(_) => Ok(()),
+        Err(err) => match err.kind() {
+            ast::ErrorKind::NestLimitExceeded(limit) => Err(format!(
+                "pattern is nested more deeply than the limit of {limit}"
+            )),
+            kind @ (ast::ErrorKind::GroupUnclosed
+            | ast::ErrorKind::GroupUnopened
+            | ast::ErrorKind::RepetitionCountInvalid) => Err(format!(
+                "invalid pattern at position {}: {kind}",
+                err.span().start.offset
+            )),
+            _ => Ok(()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ConfigurationRegexOptions;
+    use mongodb::bson::doc;
+
+    use super::{
+        validate_path_argument, validate_regex_pattern, validate_type_alias, ComparisonFunction,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn validates_regex_patterns() {
+        let options = ConfigurationRegexOptions {
+            max_length: Some(20),
+            max_nesting_depth: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(validate_regex_pattern(&options, "^Led Zep"), Ok(()));
+        assert_eq!(validate_regex_pattern(&options, "(?=Led)Led"), Ok(()));
+        assert!(validate_regex_pattern(&options, "Led (Zep").is_err());
+        assert!(validate_regex_pattern(&options, "a{2,1}").is_err());
+        assert!(validate_regex_pattern(&options, &"a".repeat(21)).is_err());
+        assert!(validate_regex_pattern(&options, "((((((a))))))").is_err());
+    }
+
+    #[test]
+    fn accepts_pcre_constructs_that_rust_does_not_support() {
+        let options = ConfigurationRegexOptions::default();
+        for pattern in [
+            r"Zeppelin\Z",
+            r"(?>Led|Le)d",
+            r"a++b",
+            r"\QLed (Zep\E",
+            r"Led\hZep",
+            r"(a)\1",
+            r"(?<=Led )Zep",
+        ] {
+            assert_eq!(
+                validate_regex_pattern(&options, pattern),
+                Ok(()),
+                "{pattern}"
+            );
+        }
+        assert!(validate_regex_pattern(&options, "Led Zep)").is_err());
+    }
+
+    #[test]
+    fn compares_runtime_types_of_fields() {
+        assert_eq!(validate_type_alias("objectId"), Ok(()));
+        assert_eq!(validate_type_alias("number"), Ok(()));
+        assert!(validate_type_alias("ObjectId").is_err());
+        assert_eq!(
+            ComparisonFunction::TypeEqual.mongodb_match_query("metadata", "array".into()),
+            doc! { "metadata": { "$type": "array" } }
+        );
+        assert_eq!(
+            ComparisonFunction::TypeEqual.mongodb_aggregation_expression("$metadata", "number"),
+            doc! { "$isNumber": "$metadata" }
+        );
+    }
+
+    #[test]
+    fn compares_values_at_paths_in_extended_json_fields() {
+        let argument = doc! { "path": ["address", "city"], "value": "Paris" };
+        assert_eq!(
+            ComparisonFunction::PathEqual.mongodb_match_query("metadata", argument.clone().into()),
+            doc! { "metadata.address.city": { "$eq": "Paris" } }
+        );
+
+        let argument = doc! { "path": ["$ref"], "value": "users" };
+        assert_eq!(
+            ComparisonFunction::PathEqual.mongodb_match_query("metadata", argument.into()),
+            doc! {
+                "$expr": {
+                    "$eq": [
+                        { "$getField": { "field": { "$literal": "$ref" }, "input": "$metadata" } },
+                        { "$literal": "users" },
+                    ]
+                }
+            }
+        );
+
+        assert_eq!(
+            validate_path_argument(&json!({ "path": ["a"], "value": null })),
+            Ok(())
+        );
+        assert!(validate_path_argument(&json!({ "path": [], "value": 1 })).is_err());
+        assert!(validate_path_argument(&json!({ "path": "a.b", "value": 1 })).is_err());
+    }
 }
@@ -1,55 +1,212 @@
 use std::collections::BTreeMap;
 
-use mongodb::bson::{doc, to_bson, Bson};
+use mongodb::{
+    bson::{doc, to_bson, Bson, Document},
+    Database,
+};
 use ndc_models::{ExplainResponse, QueryRequest};
 use ndc_query_plan::plan_for_query_request;
 
 use crate::{
     interface_types::MongoAgentError,
     mongo_query_plan::MongoConfiguration,
-    query::{self, QueryTarget},
+    mongodb::{pipeline_diagram, Pipeline},
+    query::{self, CollectionArguments, CountCommand, FindCommand, LookupRequest, QueryTarget},
     state::ConnectorState,
+    tenancy::database_for_request,
 };
 
 pub async fn explain_query(
     config: &MongoConfiguration,
     state: &ConnectorState,
-    query_request: QueryRequest,
+    mut query_request: QueryRequest,
 ) -> Result<ExplainResponse, MongoAgentError> {
-    let db = state.database();
-    let query_plan = plan_for_query_request(config, query_request)?;
+    let db = database_for_request(config, state, &mut query_request)?;
+    let mut query_plan = plan_for_query_request(config, query_request)?;
+    let mut details = BTreeMap::new();
+
+    // Queries that do not run as an aggregation pipeline explain the command that does run, in
+    // the same order of precedence as query execution.
+    if let Some(lookup_request) = LookupRequest::for_query_plan(config, &query_plan) {
+        let find_command = lookup_request.find_command(config, &query_plan)?;
+        let query_command = find_command_document(None, &find_command)?;
+        details.insert("plan".to_owned(), explain(&db, &query_command).await?);
+        details.insert("query".to_owned(), to_json(&query_command)?);
+        return Ok(ExplainResponse { details });
+    }
+
+    let collection_arguments = CollectionArguments::for_request(config, &query_plan)?;
+    collection_arguments.apply_limit(&mut query_plan.query);
+    query::apply_collection_policies(config, &query_plan.collection, &mut query_plan.query);
 
-    let pipeline = query::pipeline_for_query_request(config, &query_plan)?;
-    let pipeline_bson = to_bson(&pipeline)?;
+    let query_command =
+        match CountCommand::for_query_plan(config, &query_plan, &collection_arguments)? {
+            Some(count_command) => Some(count_command_document(
+                &collection_arguments,
+                &count_command,
+            )?),
+            None => FindCommand::for_query_plan(config, &query_plan, &collection_arguments)?
+                .map(|find_command| {
+                    find_command_document(Some(&collection_arguments), &find_command)
+                })
+                .transpose()?,
+        };
+    if let Some(query_command) = query_command {
+        details.insert("plan".to_owned(), explain(&db, &query_command).await?);
+        details.insert("query".to_owned(), to_json(&query_command)?);
+        return Ok(ExplainResponse { details });
+    }
 
     let target = QueryTarget::for_request(config, &query_plan);
-    let aggregate_target = match (target.input_collection(), query_plan.has_variables()) {
-        (Some(collection_name), false) => Bson::String(collection_name.to_string()),
-        _ => Bson::Int32(1),
+
+    // Servers that do not support `$documents` cannot run the combined foreach pipeline, so in
+    // compatibility mode only the branches are explained.
+    if !(query_plan.has_variables() && config.compatibility_mode()) {
+        let pipeline = query::pipeline_for_query_request(config, &query_plan)?;
+        let query_command = aggregate_command(
+            &collection_arguments,
+            target
+                .input_collection()
+                .filter(|_| !query_plan.has_variables()),
+            &pipeline,
+        )?;
+        details.insert("plan".to_owned(), explain(&db, &query_command).await?);
+        details.insert("query".to_owned(), to_json(&query_command)?);
+        if let Some(format) = config.explain_options().diagram {
+            details.insert("diagram".to_owned(), pipeline_diagram(format, &pipeline)?);
+        }
+    }
+
+    // Explain each variable set branch separately so that users can see which branch is slow.
+    // Details for each branch are keyed by the index of its variable set in the request.
+    if let Some(variable_sets) = &query_plan.variables {
+        let pipelines = query::pipelines_for_variable_sets(variable_sets, config, &query_plan)?;
+        for (index, pipeline) in pipelines.iter().enumerate() {
+            let query_command =
+                aggregate_command(&collection_arguments, target.input_collection(), pipeline)?;
+            details.insert(
+                format!("plan[{index}]"),
+                explain(&db, &query_command).await?,
+            );
+            details.insert(format!("query[{index}]"), to_json(&query_command)?);
+            if let Some(format) = config.explain_options().diagram {
+                details.insert(
+                    format!("diagram[{index}]"),
+                    pipeline_diagram(format, pipeline)?,
+                );
+            }
+        }
+    }
+
+    Ok(ExplainResponse { details })
+}
+
+fn aggregate_command(
+    collection_arguments: &CollectionArguments,
+    input_collection: Option<&ndc_models::CollectionName>,
+    pipeline: &Pipeline,
+) -> Result<Document, MongoAgentError> {
+    let aggregate_target = match input_collection {
+        Some(collection_name) => Bson::String(collection_name.to_string()),
+        None => Bson::Int32(1),
     };
 
-    let query_command = doc! {
+    let mut query_command = doc! {
         "aggregate": aggregate_target,
-        "pipeline": pipeline_bson,
+        "pipeline": to_bson(pipeline)?,
         "cursor": {},
     };
+    insert_collection_arguments(
+        collection_arguments,
+        input_collection.is_some(),
+        &mut query_command,
+    )?;
+    Ok(query_command)
+}
 
+/// The `find` command that the driver sends for a [FindCommand]. Lookup function requests do not
+/// take collection arguments.
+fn find_command_document(
+    collection_arguments: Option<&CollectionArguments>,
+    command: &FindCommand,
+) -> Result<Document, MongoAgentError> {
+    let options = &command.options;
+    let mut query_command = doc! {
+        "find": command.collection.as_str(),
+        "filter": command.filter.clone(),
+    };
+    if let Some(projection) = &options.projection {
+        query_command.insert("projection", projection.clone());
+    }
+    if let Some(sort) = &options.sort {
+        query_command.insert("sort", sort.clone());
+    }
+    if let Some(skip) = options.skip {
+        query_command.insert("skip", skip as i64);
+    }
+    if let Some(limit) = options.limit {
+        query_command.insert("limit", limit);
+    }
+    if let Some(collection_arguments) = collection_arguments {
+        insert_collection_arguments(collection_arguments, true, &mut query_command)?;
+    }
+    Ok(query_command)
+}
+
+/// The driver runs `countDocuments` as an aggregation pipeline that counts matching documents
+/// with `$group`. This produces the same command.
+fn count_command_document(
+    collection_arguments: &CollectionArguments,
+    command: &CountCommand,
+) -> Result<Document, MongoAgentError> {
+    let options = &command.options;
+    let mut pipeline = vec![doc! { "$match": command.filter.clone() }];
+    if let Some(skip) = options.skip {
+        pipeline.push(doc! { "$skip": skip as i64 });
+    }
+    if let Some(limit) = options.limit {
+        pipeline.push(doc! { "$limit": limit as i64 });
+    }
+    pipeline.push(doc! { "$group": { "_id": 1, "n": { "$sum": 1 } } });
+    let mut query_command = doc! {
+        "aggregate": command.collection.as_str(),
+        "pipeline": pipeline,
+        "cursor": {},
+    };
+    insert_collection_arguments(collection_arguments, true, &mut query_command)?;
+    Ok(query_command)
+}
+
+/// Index hints only apply to commands that read a collection
+fn insert_collection_arguments(
+    collection_arguments: &CollectionArguments,
+    reads_collection: bool,
+    query_command: &mut Document,
+) -> Result<(), MongoAgentError> {
+    if let (Some(hint), true) = (&collection_arguments.hint, reads_collection) {
+        query_command.insert("hint", hint.clone());
+    }
+    if let Some(read_concern) = &collection_arguments.read_concern {
+        query_command.insert("readConcern", to_bson(read_concern)?);
+    }
+    if let Some(collation) = &collection_arguments.collation {
+        query_command.insert("collation", to_bson(collation)?);
+    }
+    Ok(())
+}
+
+async fn explain(db: &Database, query_command: &Document) -> Result<String, MongoAgentError> {
     let explain_command = doc! {
-        "explain": &query_command,
+        "explain": query_command,
         "verbosity": "allPlansExecution",
     };
 
     tracing::debug!(explain_command = %serde_json::to_string(&explain_command).unwrap());
 
     let explain_result = db.run_command(explain_command, None).await?;
+    to_json(&explain_result)
+}
 
-    let plan =
-        serde_json::to_string_pretty(&explain_result).map_err(MongoAgentError::Serialization)?;
-
-    let query =
-        serde_json::to_string_pretty(&query_command).map_err(MongoAgentError::Serialization)?;
-
-    Ok(ExplainResponse {
-        details: BTreeMap::from_iter([("plan".to_owned(), plan), ("query".to_owned(), query)]),
-    })
+fn to_json(document: &Document) -> Result<String, MongoAgentError> {
+    serde_json::to_string_pretty(document).map_err(MongoAgentError::Serialization)
 }
@@ -4,6 +4,10 @@ use mongodb::bson::{doc, Document};
 use crate::{interface_types::MongoAgentError, state::ConnectorState};
 
 pub async fn check_health(state: &ConnectorState) -> Result<StatusCode, MongoAgentError> {
+    if !state.is_ready() {
+        return Ok(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
     let db = state.database();
 
     let status: Result<Document, _> = db.run_command(doc! { "ping": 1 }, None).await;
@@ -5,16 +5,24 @@ use mongodb::bson;
 use ndc_query_plan::QueryPlanError;
 use thiserror::Error;
 
-use crate::{procedure::ProcedureError, query::QueryResponseError};
+use crate::{
+    procedure::ProcedureError,
+    query::{
+        arguments::ArgumentError, DocumentTooLargeError, MemoryBudgetExceeded, QueryResponseError,
+    },
+};
 
 /// A superset of the DC-API `AgentError` type. This enum adds error cases specific to the MongoDB
 /// agent.
 #[derive(Debug, Error)]
 pub enum MongoAgentError {
+    Arguments(#[from] ArgumentError),
     BadCollectionSchema(String, bson::Bson, bson::de::Error),
     BadQuery(anyhow::Error),
+    DocumentTooLarge(#[from] DocumentTooLargeError),
     InvalidVariableName(String),
     InvalidScalarTypeName(String),
+    MemoryBudgetExceeded(#[from] MemoryBudgetExceeded),
     MongoDB(#[from] mongodb::error::Error),
     MongoDBDeserialization(#[from] mongodb::bson::de::Error),
     MongoDBSerialization(#[from] mongodb::bson::ser::Error),
@@ -24,6 +32,7 @@ pub enum MongoAgentError {
     QueryPlan(#[from] QueryPlanError),
     ResponseSerialization(#[from] QueryResponseError),
     Serialization(serde_json::Error),
+    ShuttingDown,
     UnknownAggregationFunction(String),
     UnspecifiedRelation(String),
     AdHoc(#[from] anyhow::Error),
@@ -34,6 +43,7 @@ use MongoAgentError::*;
 impl MongoAgentError {
     pub fn status_and_error_response(&self) -> (StatusCode, ErrorResponse) {
         match self {
+            Arguments(err) => (StatusCode::BAD_REQUEST, ErrorResponse::new(err)),
             BadCollectionSchema(collection_name, schema, err) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 ErrorResponse {
@@ -60,6 +70,33 @@ impl MongoAgentError {
                 },
             ),
             BadQuery(err) => (StatusCode::BAD_REQUEST, ErrorResponse::new(&err)),
+            DocumentTooLarge(err) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    message: err.to_string(),
+                    details: Some(
+                        [
+                            (
+                                "causes".to_owned(),
+                                serde_json::to_value(&err.causes).unwrap_or_default(),
+                            ),
+                            (
+                                "suggestions".to_owned(),
+                                err.causes
+                                    .iter()
+                                    .map(|cause| serde_json::Value::String(cause.suggestion()))
+                                    .collect(),
+                            ),
+                            (
+                                "server_message".to_owned(),
+                                serde_json::Value::String(err.server_message.clone()),
+                            ),
+                        ]
+                        .into(),
+                    ),
+                    r#type: None,
+                },
+            ),
             InvalidVariableName(name) => (
                 StatusCode::BAD_REQUEST,
                 ErrorResponse::new(&format!("Column identifier includes characters that are not permitted in a MongoDB variable name: {name}"))
@@ -68,6 +105,17 @@ impl MongoAgentError {
                 StatusCode::BAD_REQUEST,
                 ErrorResponse::new(&format!("Scalar value includes invalid type name: {name}"))
             ),
+            MemoryBudgetExceeded(err)
+            | ResponseSerialization(QueryResponseError::MemoryBudgetExceeded(err)) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    message: err.to_string(),
+                    details: serde_json::to_value(err)
+                        .ok()
+                        .and_then(|details| serde_json::from_value(details).ok()),
+                    r#type: None,
+                },
+            ),
             MongoDB(err) => (StatusCode::BAD_REQUEST, ErrorResponse::new(&err)),
             MongoDBDeserialization(err) => (StatusCode::BAD_REQUEST, ErrorResponse::new(&err)),
             MongoDBSerialization(err) => {
@@ -79,6 +127,10 @@ impl MongoAgentError {
             QueryPlan(err) => (StatusCode::BAD_REQUEST, ErrorResponse::new(err)),
             ResponseSerialization(err) => (StatusCode::BAD_REQUEST, ErrorResponse::new(err)),
             Serialization(err) => (StatusCode::INTERNAL_SERVER_ERROR, ErrorResponse::new(&err)),
+            ShuttingDown => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorResponse::new("The connector is shutting down"),
+            ),
             UnknownAggregationFunction(function) => (
                 StatusCode::BAD_REQUEST,
                 ErrorResponse::new(&format!("Unknown aggregation function, {function}")),
@@ -1,16 +1,27 @@
 pub mod aggregation_function;
+pub mod bulkheads;
+pub mod collection_stats;
 pub mod comparison_function;
 pub mod explain;
 pub mod health;
 pub mod interface_types;
+pub mod materialization;
 pub mod mongo_query_plan;
 pub mod mongodb;
 pub mod mongodb_connection;
+pub mod monitoring;
+pub mod native_query_verification;
+pub mod post_processing;
 pub mod procedure;
 pub mod query;
+pub mod query_log;
 pub mod scalar_types_capabilities;
 pub mod schema;
+pub mod server_info;
+pub mod shutdown;
 pub mod state;
+pub mod tenancy;
+pub mod warm_up;
 
 #[cfg(test)]
 mod test_helpers;
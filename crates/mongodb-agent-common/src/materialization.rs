@@ -0,0 +1,229 @@
+//! Periodic refresh of materialized native queries. Each native query with a `materialized`
+//! setting gets a background task that runs the native query pipeline on a fixed interval, and
+//! writes the results to the backing collection with a `$merge` stage. Documents that were not
+//! produced by the latest run are deleted after the merge so that the backing collection matches
+//! the pipeline output. Queries against the native query read from the backing collection (see
+//! [crate::query::QueryTarget]).
+
+use configuration::native_query::{MaterializedView, NativeQuery};
+use std::time::Duration;
+
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    Database,
+};
+use tokio::task::AbortHandle;
+
+use crate::{interface_types::MongoAgentError, mongo_query_plan::MongoConfiguration};
+
+/// Field that records the time of the refresh that last wrote each document
+const MATERIALIZED_AT: &str = "__materialized_at";
+
+/// Starts a refresh task for each materialized native query. Tasks run until they are aborted
+/// with the returned handles. A task that panics is logged since refreshes would otherwise stop
+/// without notice.
+///
+/// Refreshes write to the backing collections, so in read-only mode no tasks are started and
+/// queries read whatever the backing collections already contain.
+pub fn spawn_refresh_tasks(config: &MongoConfiguration, database: Database) -> Vec<AbortHandle> {
+    let materialized_queries = config
+        .native_queries()
+        .iter()
+        .filter(|(_, native_query)| native_query.materialized.is_some());
+    if config.is_read_only() {
+        for (name, _) in materialized_queries {
+            tracing::warn!(
+                native_query = %name,
+                "not refreshing materialized native query because the connector is read-only"
+            );
+        }
+        return vec![];
+    }
+    materialized_queries
+        .map(|(name, native_query)| {
+            let name = name.clone();
+            let native_query = native_query.clone();
+            let database = database.clone();
+            let task = tokio::spawn({
+                let name = name.clone();
+                async move {
+                    let Some(materialized) = &native_query.materialized else {
+                        return;
+                    };
+                    let mut interval = tokio::time::interval(refresh_interval(materialized));
+                    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                    loop {
+                        interval.tick().await;
+                        if let Err(err) = refresh(&database, &native_query, materialized).await {
+                            tracing::error!(
+                                native_query = %name,
+                                error = %err,
+                                "failed to refresh materialized native query"
+                            );
+                        }
+                    }
+                }
+            });
+            let abort_handle = task.abort_handle();
+            tokio::spawn(async move {
+                if let Err(err) = task.await {
+                    if err.is_panic() {
+                        tracing::error!(
+                            native_query = %name,
+                            error = %err,
+                            "materialized native query refresh task failed; results will not be refreshed"
+                        );
+                    }
+                }
+            });
+            abort_handle
+        })
+        .collect()
+}
+
+/// `tokio::time::interval` panics on a zero interval
+fn refresh_interval(materialized: &MaterializedView) -> Duration {
+    materialized.refresh_interval.max(Duration::from_secs(1))
+}
+
+async fn refresh(
+    database: &Database,
+    native_query: &NativeQuery,
+    materialized: &MaterializedView,
+) -> Result<(), MongoAgentError> {
+    let refreshed_at = DateTime::now();
+    let pipeline = refresh_pipeline(native_query, materialized, refreshed_at);
+    let cursor = match &native_query.input_collection {
+        Some(collection) => {
+            database
+                .collection::<Document>(collection.as_str())
+                .aggregate(pipeline, None)
+                .await?
+        }
+        None => database.aggregate(pipeline, None).await?,
+    };
+    // A pipeline that ends with `$merge` produces no documents, but the cursor must be consumed
+    // for the pipeline to run to completion.
+    let _: Vec<Document> = cursor.try_collect().await?;
+
+    database
+        .collection::<Document>(materialized.collection.as_str())
+        .delete_many(doc! { MATERIALIZED_AT: { "$lt": refreshed_at } }, None)
+        .await?;
+    Ok(())
+}
+
+/// The native query pipeline followed by stages that stamp each output document with the refresh
+/// time, and merge output into the backing collection. Materialized native queries may not have
+/// arguments so the pipeline has no placeholders to interpolate. Pushdown points are dropped since
+/// there is no request to push down.
+fn refresh_pipeline(
+    native_query: &NativeQuery,
+    materialized: &MaterializedView,
+    refreshed_at: DateTime,
+) -> Vec<Document> {
+    let mut pipeline = native_query.without_pushdown_points(native_query.pipeline.clone());
+    pipeline.push(doc! { "$set": { MATERIALIZED_AT: refreshed_at } });
+    pipeline.push(doc! {
+        "$merge": {
+            "into": materialized.collection.as_str(),
+            "on": "_id",
+            "whenMatched": "replace",
+            "whenNotMatched": "insert",
+        }
+    });
+    pipeline
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use configuration::{
+        native_query::{MaterializedView, NativeQuery, NativeQueryRepresentation},
+        pushdown::{PushdownKind, PushdownPoint},
+    };
+    use mongodb::bson::{doc, DateTime};
+    use pretty_assertions::assert_eq;
+
+    use super::{refresh_interval, refresh_pipeline};
+
+    #[test]
+    fn merges_native_query_output_into_backing_collection() {
+        let native_query = NativeQuery {
+            representation: NativeQueryRepresentation::Collection,
+            input_collection: Some("movies".into()),
+            arguments: Default::default(),
+            result_document_type: "TitleCount".into(),
+            pipeline: vec![doc! { "$group": { "_id": "$title", "count": { "$count": {} } } }],
+            placeholders: Default::default(),
+            pushdown_points: Default::default(),
+            description: None,
+            materialized: None,
+        };
+        let materialized = MaterializedView {
+            collection: "title_counts".into(),
+            refresh_interval: Duration::from_secs(60),
+        };
+        let refreshed_at = DateTime::from_millis(0);
+        assert_eq!(
+            refresh_pipeline(&native_query, &materialized, refreshed_at),
+            vec![
+                doc! { "$group": { "_id": "$title", "count": { "$count": {} } } },
+                doc! { "$set": { "__materialized_at": refreshed_at } },
+                doc! {
+                    "$merge": {
+                        "into": "title_counts",
+                        "on": "_id",
+                        "whenMatched": "replace",
+                        "whenNotMatched": "insert",
+                    }
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_pushdown_points_from_refresh_pipeline() {
+        let native_query = NativeQuery {
+            representation: NativeQueryRepresentation::Collection,
+            input_collection: Some("movies".into()),
+            arguments: Default::default(),
+            result_document_type: "Movie".into(),
+            pipeline: vec![
+                doc! { "$match": { "year": { "$gt": 2000 } } },
+                doc! { "$pushdown": ["match", "sort"] },
+                doc! { "$project": { "title": 1 } },
+            ],
+            placeholders: Default::default(),
+            pushdown_points: vec![PushdownPoint {
+                stage: 1,
+                kinds: [PushdownKind::Match, PushdownKind::Sort].into(),
+            }],
+            description: None,
+            materialized: None,
+        };
+        let materialized = MaterializedView {
+            collection: "recent_movies".into(),
+            refresh_interval: Duration::from_secs(60),
+        };
+        let pipeline = refresh_pipeline(&native_query, &materialized, DateTime::from_millis(0));
+        assert_eq!(
+            pipeline[..2],
+            [
+                doc! { "$match": { "year": { "$gt": 2000 } } },
+                doc! { "$project": { "title": 1 } },
+            ]
+        );
+    }
+
+    #[test]
+    fn refreshes_at_least_once_per_second() {
+        let materialized = MaterializedView {
+            collection: "title_counts".into(),
+            refresh_interval: Duration::ZERO,
+        };
+        assert_eq!(refresh_interval(&materialized), Duration::from_secs(1));
+    }
+}
@@ -1,24 +1,179 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, time::Duration};
 
 use configuration::{
-    native_mutation::NativeMutation, native_query::NativeQuery, Configuration, MongoScalarType,
+    collection_policies::CollectionPolicies, lookup_function::LookupFunction,
+    native_mutation::NativeMutation, native_query::NativeQuery, Configuration,
+    ConfigurationAuditOptions, ConfigurationCollectionConcurrencyOptions,
+    ConfigurationCollectionStatsOptions, ConfigurationCursorBatchSizeOptions,
+    ConfigurationExplainOptions, ConfigurationIdempotencyOptions,
+    ConfigurationLargeDocumentOptions, ConfigurationQueryBatchingOptions,
+    ConfigurationQueryLogOptions, ConfigurationRawPipelineOptions, ConfigurationRecordingOptions,
+    ConfigurationRegexOptions, ConfigurationSerializationOptions, ConfigurationTenancyOptions,
+    ConfigurationWarmUpOptions, ConnectorMode, MongoScalarType, ObjectIdFormats, RecordingMode,
 };
-use mongodb_support::{ExtendedJsonMode, EXTENDED_JSON_TYPE_NAME};
+use mongodb_support::EXTENDED_JSON_TYPE_NAME;
 use ndc_models as ndc;
-use ndc_query_plan::{ConnectorTypes, QueryContext, QueryPlanError};
+use ndc_query_plan::{ConnectorTypes, QueryContext, QueryPlanError, SupportedFeatures};
 
 use crate::aggregation_function::AggregationFunction;
-use crate::comparison_function::ComparisonFunction;
-use crate::scalar_types_capabilities::SCALAR_TYPES;
+use crate::comparison_function::{
+    validate_path_argument, validate_regex_pattern, validate_type_alias, ComparisonFunction,
+};
+use crate::scalar_types_capabilities::{aggregate_function_definition, SCALAR_TYPES};
 
 pub use ndc_query_plan::OrderByTarget;
 
+/// Query features that the MongoDB connector implements. Connector capabilities are derived from
+/// this table, and the query planner uses it to reject requests for anything else.
+pub const SUPPORTED_FEATURES: SupportedFeatures = SupportedFeatures {
+    aggregates: true,
+    variables: true,
+    explain: true,
+    nested_field_filter_by: true,
+    nested_field_order_by: true,
+    nested_field_aggregates: false,
+    relationships: true,
+    relation_comparisons: true,
+    order_by_aggregate: false, // TODO: MDB-150, MDB-151
+};
+
 #[derive(Clone, Debug)]
 pub struct MongoConfiguration(pub Configuration);
 
 impl MongoConfiguration {
-    pub fn extended_json_mode(&self) -> ExtendedJsonMode {
-        self.0.options.serialization_options.extended_json_mode
+    pub fn serialization_options(&self) -> ConfigurationSerializationOptions {
+        self.0.options.serialization_options
+    }
+
+    pub fn object_id_formats(&self) -> &ObjectIdFormats {
+        &self.0.object_id_formats
+    }
+
+    pub fn deterministic_pagination(&self) -> bool {
+        self.0.options.query_options.deterministic_pagination
+    }
+
+    pub fn sql_null_semantics(&self) -> bool {
+        self.0.options.query_options.sql_null_semantics
+    }
+
+    pub fn find_fast_path(&self) -> bool {
+        self.0.options.query_options.find_fast_path
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self.0.options.mode,
+            ConnectorMode::ReadOnly | ConnectorMode::Mock
+        )
+    }
+
+    pub fn is_mock(&self) -> bool {
+        self.0.options.mode == ConnectorMode::Mock
+    }
+
+    pub fn regex_options(&self) -> &ConfigurationRegexOptions {
+        &self.0.options.query_options.regex
+    }
+
+    pub fn compatibility_mode(&self) -> bool {
+        self.0.options.query_options.compatibility_mode
+    }
+
+    pub fn explain_options(&self) -> &ConfigurationExplainOptions {
+        &self.0.options.explain
+    }
+
+    pub fn large_document_options(&self) -> Option<&ConfigurationLargeDocumentOptions> {
+        self.0.options.query_options.large_documents.as_ref()
+    }
+
+    pub fn allow_system_collections(&self) -> bool {
+        self.0.options.allow_system_collections
+    }
+
+    pub fn collection_stats(&self) -> Option<&ConfigurationCollectionStatsOptions> {
+        self.0.options.collection_stats.as_ref()
+    }
+
+    pub fn cursor_batch_size(&self) -> Option<&ConfigurationCursorBatchSizeOptions> {
+        self.0.options.query_options.cursor_batch_size.as_ref()
+    }
+
+    /// Locale of the collation that backs `_ieq`, if one is configured and the server is expected
+    /// to support collations
+    pub fn case_insensitive_collation(&self) -> Option<&str> {
+        if self.compatibility_mode() {
+            return None;
+        }
+        self.0
+            .options
+            .query_options
+            .case_insensitive_collation
+            .as_deref()
+    }
+
+    pub fn snapshot_reads(&self) -> bool {
+        self.0.options.query_options.snapshot_reads
+    }
+
+    pub fn query_batching(&self) -> Option<&ConfigurationQueryBatchingOptions> {
+        self.0.options.query_batching.as_ref()
+    }
+
+    pub fn collection_concurrency(&self) -> Option<&ConfigurationCollectionConcurrencyOptions> {
+        self.0.options.collection_concurrency.as_ref()
+    }
+
+    pub fn tenancy(&self) -> Option<&ConfigurationTenancyOptions> {
+        self.0.options.tenancy.as_ref()
+    }
+
+    pub fn audit(&self) -> Option<&ConfigurationAuditOptions> {
+        self.0.options.audit.as_ref()
+    }
+
+    pub fn idempotency(&self) -> Option<&ConfigurationIdempotencyOptions> {
+        self.0.options.idempotency.as_ref()
+    }
+
+    pub fn raw_pipeline(&self) -> Option<&ConfigurationRawPipelineOptions> {
+        self.0.options.raw_pipeline.as_ref()
+    }
+
+    pub fn query_log(&self) -> Option<&ConfigurationQueryLogOptions> {
+        self.0.options.query_log.as_ref()
+    }
+
+    pub fn recording(&self) -> Option<&ConfigurationRecordingOptions> {
+        self.0.options.recording.as_ref()
+    }
+
+    pub fn warm_up(&self) -> Option<&ConfigurationWarmUpOptions> {
+        self.0.options.warm_up.as_ref()
+    }
+
+    pub fn shutdown_drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.0.options.shutdown.drain_timeout_seconds)
+    }
+
+    /// True if the connector answers queries without a database connection
+    pub fn is_offline(&self) -> bool {
+        self.is_mock()
+            || self
+                .recording()
+                .is_some_and(|recording| recording.mode == RecordingMode::Replay)
+    }
+
+    pub fn collection_policies(
+        &self,
+        collection: &ndc::CollectionName,
+    ) -> Option<&CollectionPolicies> {
+        self.0.collection_policies.get(collection)
+    }
+
+    pub fn lookup_functions(&self) -> &BTreeMap<ndc::FunctionName, LookupFunction> {
+        &self.0.lookup_functions
     }
 
     pub fn native_queries(&self) -> &BTreeMap<ndc::FunctionName, NativeQuery> {
@@ -28,6 +183,30 @@ impl MongoConfiguration {
     pub fn native_mutations(&self) -> &BTreeMap<ndc::ProcedureName, NativeMutation> {
         &self.0.native_mutations
     }
+
+    /// Scalar types for the schema response, including aggregation functions declared in
+    /// configuration on each scalar type that supports the underlying function
+    pub fn scalar_types(&self) -> BTreeMap<ndc::ScalarTypeName, ndc::ScalarType> {
+        let mut scalar_types = SCALAR_TYPES.clone();
+        for (type_name, scalar_type) in scalar_types.iter_mut() {
+            if self.regex_options().disabled {
+                scalar_type.comparison_operators.retain(|name, _| {
+                    ComparisonFunction::from_graphql_name(name.as_str())
+                        .map_or(true, |operator| !operator.is_regex())
+                });
+            }
+            for (function_name, options) in &self.0.options.query_options.aggregate_functions {
+                if let Some(definition) =
+                    aggregate_function_definition(type_name.as_str(), &options.function, true)
+                {
+                    scalar_type
+                        .aggregate_functions
+                        .insert(function_name.clone(), definition.clone());
+                }
+            }
+        }
+        scalar_types
+    }
 }
 
 impl ConnectorTypes for MongoConfiguration {
@@ -37,6 +216,10 @@ impl ConnectorTypes for MongoConfiguration {
 }
 
 impl QueryContext for MongoConfiguration {
+    fn supported_features(&self) -> SupportedFeatures {
+        SUPPORTED_FEATURES
+    }
+
     fn lookup_scalar_type(type_name: &ndc::ScalarTypeName) -> Option<Self::ScalarType> {
         type_name.try_into().ok()
     }
@@ -46,16 +229,38 @@ impl QueryContext for MongoConfiguration {
         input_type: &Type,
         function_name: &ndc::AggregateFunctionName,
     ) -> Result<(Self::AggregateFunction, &ndc::AggregateFunctionDefinition), QueryPlanError> {
-        let function = AggregationFunction::from_graphql_name(function_name.as_str())?;
+        // Functions declared in configuration apply a built-in function with fixed arguments
+        let configured = self
+            .0
+            .options
+            .query_options
+            .aggregate_functions
+            .get(function_name);
+        let builtin_name = configured.map_or(function_name, |options| &options.function);
+        let function = AggregationFunction::from_graphql_name(builtin_name.as_str())?;
         let definition = scalar_type_name(input_type)
-            .and_then(|name| SCALAR_TYPES.get(name))
-            .and_then(|scalar_type_def| scalar_type_def.aggregate_functions.get(function_name))
+            .and_then(|name| {
+                aggregate_function_definition(name, builtin_name, configured.is_some())
+            })
             .ok_or_else(|| QueryPlanError::UnknownAggregateFunction {
                 aggregate_function: function_name.to_owned(),
             })?;
         Ok((function, definition))
     }
 
+    fn aggregation_function_arguments(
+        &self,
+        function_name: &ndc::AggregateFunctionName,
+    ) -> BTreeMap<ndc::ArgumentName, serde_json::Value> {
+        self.0
+            .options
+            .query_options
+            .aggregate_functions
+            .get(function_name)
+            .map(|options| options.arguments.clone())
+            .unwrap_or_default()
+    }
+
     fn lookup_comparison_operator(
         &self,
         left_operand_type: &Type,
@@ -65,6 +270,11 @@ impl QueryContext for MongoConfiguration {
         Self: Sized,
     {
         let operator = ComparisonFunction::from_graphql_name(operator_name.as_str())?;
+        if operator.is_regex() && self.regex_options().disabled {
+            return Err(QueryPlanError::UnknownComparisonOperator(
+                operator_name.to_owned(),
+            ));
+        }
         let definition = scalar_type_name(left_operand_type)
             .and_then(|name| SCALAR_TYPES.get(name))
             .and_then(|scalar_type_def| scalar_type_def.comparison_operators.get(operator_name))
@@ -72,6 +282,51 @@ impl QueryContext for MongoConfiguration {
         Ok((operator, definition))
     }
 
+    fn validate_comparison_value(
+        &self,
+        operator: &Self::ComparisonOperator,
+        value: &serde_json::Value,
+    ) -> Result<(), QueryPlanError> {
+        match value {
+            serde_json::Value::String(pattern) if operator.is_regex() => {
+                validate_regex_pattern(self.regex_options(), pattern).map_err(|message| {
+                    QueryPlanError::InvalidComparisonValue {
+                        operator: operator.graphql_name().into(),
+                        message,
+                    }
+                })
+            }
+            serde_json::Value::String(alias) if *operator == ComparisonFunction::TypeEqual => {
+                validate_type_alias(alias).map_err(|message| {
+                    QueryPlanError::InvalidComparisonValue {
+                        operator: operator.graphql_name().into(),
+                        message,
+                    }
+                })
+            }
+            argument if *operator == ComparisonFunction::PathEqual => {
+                validate_path_argument(argument).map_err(|message| {
+                    QueryPlanError::InvalidComparisonValue {
+                        operator: operator.graphql_name().into(),
+                        message,
+                    }
+                })
+            }
+            serde_json::Value::Array(bounds)
+                if *operator == ComparisonFunction::Between && bounds.len() != 2 =>
+            {
+                Err(QueryPlanError::InvalidComparisonValue {
+                    operator: operator.graphql_name().into(),
+                    message: format!(
+                        "expected a [lower, upper] pair of bounds, but got {} values",
+                        bounds.len()
+                    ),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
     fn collections(&self) -> &BTreeMap<ndc::CollectionName, ndc::CollectionInfo> {
         &self.0.collections
     }
@@ -87,6 +342,10 @@ impl QueryContext for MongoConfiguration {
     fn procedures(&self) -> &BTreeMap<ndc::ProcedureName, ndc::ProcedureInfo> {
         &self.0.procedures
     }
+
+    fn configured_relationships(&self) -> &BTreeMap<ndc::RelationshipName, ndc::Relationship> {
+        &self.0.relationships
+    }
 }
 
 fn scalar_type_name(t: &Type) -> Option<&'static str> {
@@ -115,3 +374,47 @@ pub type Relationship = ndc_query_plan::Relationship<MongoConfiguration>;
 pub type Relationships = ndc_query_plan::Relationships<MongoConfiguration>;
 pub type Type = ndc_query_plan::Type<MongoScalarType>;
 pub type VariableTypes = ndc_query_plan::VariableTypes<MongoScalarType>;
+
+#[cfg(test)]
+mod tests {
+    use ndc_models::{OrderByElement, OrderByTarget, OrderDirection};
+    use ndc_query_plan::{plan_for_query_request, QueryPlanError};
+    use ndc_test_helpers::{field, path_element, query, query_request, relation_field};
+
+    use crate::test_helpers::{chinook_config, chinook_relationships};
+
+    use super::SUPPORTED_FEATURES;
+
+    #[test]
+    fn rejects_ordering_by_aggregates_while_planning() -> anyhow::Result<()> {
+        assert!(!SUPPORTED_FEATURES.order_by_aggregate);
+        assert!(SUPPORTED_FEATURES
+            .capabilities()
+            .relationships
+            .is_some_and(|r| r.order_by_aggregate.is_none()));
+
+        let request = query_request()
+            .collection("Artist")
+            .query(
+                query()
+                    .fields([
+                        relation_field!("Albums" => "Albums", query().fields([field!("Title")])),
+                    ])
+                    .order_by(vec![OrderByElement {
+                        order_direction: OrderDirection::Desc,
+                        target: OrderByTarget::StarCountAggregate {
+                            path: vec![path_element("Albums".into()).into()],
+                        },
+                    }]),
+            )
+            .relationships(chinook_relationships())
+            .into();
+
+        let result = plan_for_query_request(&chinook_config(), request);
+        assert!(matches!(
+            result,
+            Err(QueryPlanError::NotImplemented("ordering by aggregates"))
+        ));
+        Ok(())
+    }
+}
@@ -6,6 +6,12 @@ use serde::{Deserialize, Serialize};
 /// See https://www.mongodb.com/docs/manual/reference/operator/aggregation/group/#std-label-accumulators-group
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum Accumulator {
+    /// Returns an array of unique expression values for each group.
+    ///
+    /// See https://www.mongodb.com/docs/manual/reference/operator/aggregation/addToSet/#mongodb-group-grp.-addToSet
+    #[serde(rename = "$addToSet")]
+    AddToSet(bson::Bson),
+
     /// Returns an average of numerical values. Ignores non-numeric values.
     ///
     /// See https://www.mongodb.com/docs/manual/reference/operator/aggregation/avg/#mongodb-group-grp.-avg
@@ -30,6 +36,17 @@ pub enum Accumulator {
     #[serde(rename = "$max")]
     Max(bson::Bson),
 
+    /// Returns an array of scalar values that correspond to specified percentile values. Requires
+    /// MongoDB 7.0 or later.
+    ///
+    /// See https://www.mongodb.com/docs/manual/reference/operator/aggregation/percentile/#mongodb-group-grp.-percentile
+    #[serde(rename = "$percentile")]
+    Percentile {
+        input: bson::Bson,
+        p: Vec<f64>,
+        method: String,
+    },
+
     #[serde(rename = "$push")]
     Push(bson::Bson),
 
@@ -1,9 +1,9 @@
 use async_trait::async_trait;
 use futures_util::Stream;
 use mongodb::{
-    bson::Document,
+    bson::{Document, RawDocumentBuf},
     error::Error,
-    options::{AggregateOptions, FindOptions},
+    options::{AggregateOptions, CountOptions, FindOptions},
     Collection,
 };
 use serde::de::DeserializeOwned;
@@ -21,16 +21,16 @@ use super::test_helpers::MockCursor;
 /// The mock provides a variety of methods for mocking and spying on database behavior in tests.
 /// See https://docs.rs/mockall/latest/mockall/
 #[cfg_attr(test, automock(
-    type DocumentCursor=MockCursor<Document>;
-    type RowCursor=MockCursor<T>;
+    type DocumentCursor=MockCursor<RawDocumentBuf>;
 ))]
 #[async_trait]
 pub trait CollectionTrait<T>
 where
     T: DeserializeOwned + Unpin + Send + Sync + 'static,
 {
-    type DocumentCursor: Stream<Item = Result<Document, Error>> + 'static;
-    type RowCursor: Stream<Item = Result<T, Error>> + 'static;
+    /// Aggregation and find results are read as raw BSON so that response serialization can read
+    /// values without parsing documents into an intermediate representation.
+    type DocumentCursor: Stream<Item = Result<RawDocumentBuf, Error>> + 'static;
 
     async fn aggregate<Options>(
         &self,
@@ -44,10 +44,19 @@ where
         &self,
         filter: Filter,
         options: Options,
-    ) -> Result<Self::RowCursor, Error>
+    ) -> Result<Self::DocumentCursor, Error>
     where
         Filter: Into<Option<Document>> + Send + 'static,
         Options: Into<Option<FindOptions>> + Send + 'static;
+
+    async fn count_documents<Filter, Options>(
+        &self,
+        filter: Filter,
+        options: Options,
+    ) -> Result<u64, Error>
+    where
+        Filter: Into<Option<Document>> + Send + 'static,
+        Options: Into<Option<CountOptions>> + Send + 'static;
 }
 
 #[async_trait]
@@ -55,8 +64,7 @@ impl<T> CollectionTrait<T> for Collection<T>
 where
     T: DeserializeOwned + Unpin + Send + Sync + 'static,
 {
-    type DocumentCursor = mongodb::Cursor<Document>;
-    type RowCursor = mongodb::Cursor<T>;
+    type DocumentCursor = mongodb::Cursor<RawDocumentBuf>;
 
     async fn aggregate<Options>(
         &self,
@@ -66,18 +74,32 @@ where
     where
         Options: Into<Option<AggregateOptions>> + Send + 'static,
     {
-        Collection::aggregate(self, pipeline, options).await
+        let cursor = Collection::aggregate(self, pipeline, options).await?;
+        Ok(cursor.with_type())
     }
 
     async fn find<Filter, Options>(
         &self,
         filter: Filter,
         options: Options,
-    ) -> Result<Self::RowCursor, Error>
+    ) -> Result<Self::DocumentCursor, Error>
     where
         Filter: Into<Option<Document>> + Send + 'static,
         Options: Into<Option<FindOptions>> + Send + 'static,
     {
-        Collection::find(self, filter, options).await
+        let cursor = Collection::find(self, filter, options).await?;
+        Ok(cursor.with_type())
+    }
+
+    async fn count_documents<Filter, Options>(
+        &self,
+        filter: Filter,
+        options: Options,
+    ) -> Result<u64, Error>
+    where
+        Filter: Into<Option<Document>> + Send + 'static,
+        Options: Into<Option<CountOptions>> + Send + 'static,
+    {
+        Collection::count_documents(self, filter, options).await
     }
 }
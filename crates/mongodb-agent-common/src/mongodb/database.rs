@@ -1,6 +1,11 @@
 use async_trait::async_trait;
 use futures_util::Stream;
-use mongodb::{bson::Document, error::Error, options::AggregateOptions, Database};
+use mongodb::{
+    bson::{Document, RawDocumentBuf},
+    error::Error,
+    options::AggregateOptions,
+    Database,
+};
 
 #[cfg(test)]
 use mockall::automock;
@@ -23,12 +28,12 @@ use super::test_helpers::MockCursor;
 /// `Document`. That's the way we're using collections in this app anyway.
 #[cfg_attr(test, automock(
     type Collection = MockCollectionTrait<Document>;
-    type DocumentCursor = MockCursor<Document>;
+    type DocumentCursor = MockCursor<RawDocumentBuf>;
 ))]
 #[async_trait]
 pub trait DatabaseTrait {
     type Collection: CollectionTrait<Document>;
-    type DocumentCursor: Stream<Item = Result<Document, Error>>;
+    type DocumentCursor: Stream<Item = Result<RawDocumentBuf, Error>>;
 
     async fn aggregate<Options>(
         &self,
@@ -44,7 +49,7 @@ pub trait DatabaseTrait {
 #[async_trait]
 impl DatabaseTrait for Database {
     type Collection = mongodb::Collection<Document>;
-    type DocumentCursor = mongodb::Cursor<Document>;
+    type DocumentCursor = mongodb::Cursor<RawDocumentBuf>;
 
     async fn aggregate<Options>(
         &self,
@@ -54,7 +59,8 @@ impl DatabaseTrait for Database {
     where
         Options: Into<Option<AggregateOptions>> + Send + 'static,
     {
-        Database::aggregate(self, pipeline, options).await
+        let cursor = Database::aggregate(self, pipeline, options).await?;
+        Ok(cursor.with_type())
     }
 
     fn collection(&self, name: &str) -> Self::Collection {
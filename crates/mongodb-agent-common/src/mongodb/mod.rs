@@ -2,16 +2,28 @@ mod accumulator;
 mod collection;
 mod database;
 mod pipeline;
+mod pipeline_diagram;
+mod recording;
 pub mod sanitize;
 mod selection;
+mod snapshot;
 mod stage;
+mod tagging;
 
 #[cfg(test)]
 pub mod test_helpers;
 
 pub use self::{
-    accumulator::Accumulator, collection::CollectionTrait, database::DatabaseTrait,
-    pipeline::Pipeline, selection::Selection, stage::Stage,
+    accumulator::Accumulator,
+    collection::CollectionTrait,
+    database::DatabaseTrait,
+    pipeline::Pipeline,
+    pipeline_diagram::pipeline_diagram,
+    recording::RecordingDatabase,
+    selection::Selection,
+    snapshot::{SnapshotDatabase, SnapshotSupport},
+    stage::Stage,
+    tagging::{request_comment, TaggedDatabase},
 };
 
 // MockCollectionTrait is generated by automock when the test flag is active.
@@ -0,0 +1,259 @@
+//! Renders an aggregation pipeline as a diagram for explain responses. Each stage is a node, and
+//! stages are connected in order. Stages that run sub-pipelines (`$lookup`, `$facet`, and
+//! `$unionWith`) are connected to a subgraph for each sub-pipeline.
+
+use std::fmt::Write as _;
+
+use configuration::DiagramFormat;
+use itertools::Itertools as _;
+use mongodb::bson::{to_bson, Bson, Document};
+
+use crate::interface_types::MongoAgentError;
+
+use super::Pipeline;
+
+/// Longest label text before it is truncated with an ellipsis
+const MAX_DETAIL_LENGTH: usize = 60;
+
+pub fn pipeline_diagram(
+    format: DiagramFormat,
+    pipeline: &Pipeline,
+) -> Result<String, MongoAgentError> {
+    let stages = match to_bson(pipeline)? {
+        Bson::Array(stages) => stages,
+        _ => vec![],
+    };
+    let nodes = nodes_for_pipeline("s", &stages);
+    let diagram = match format {
+        DiagramFormat::Mermaid => mermaid(&nodes),
+        DiagramFormat::Graphviz => graphviz(&nodes),
+    };
+    Ok(diagram)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Node {
+    id: String,
+    label: String,
+    subgraphs: Vec<Subgraph>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Subgraph {
+    id: String,
+    title: String,
+    nodes: Vec<Node>,
+}
+
+fn nodes_for_pipeline(id_prefix: &str, stages: &[Bson]) -> Vec<Node> {
+    stages
+        .iter()
+        .enumerate()
+        .map(|(index, stage)| {
+            let id = format!("{id_prefix}_{index}");
+            match stage {
+                Bson::Document(stage) => node_for_stage(id, stage),
+                other => Node {
+                    id,
+                    label: other.to_string(),
+                    subgraphs: vec![],
+                },
+            }
+        })
+        .collect()
+}
+
+fn node_for_stage(id: String, stage: &Document) -> Node {
+    let Some((name, spec)) = stage.iter().next() else {
+        return Node {
+            id,
+            label: "{}".to_owned(),
+            subgraphs: vec![],
+        };
+    };
+    let mut subgraphs = vec![];
+    let mut sub_pipeline = |title: String, stages: Option<&Vec<Bson>>| {
+        if let Some(stages) = stages {
+            let subgraph_id = format!("{id}_{}", subgraphs.len());
+            subgraphs.push(Subgraph {
+                nodes: nodes_for_pipeline(&subgraph_id, stages),
+                id: subgraph_id,
+                title,
+            });
+        }
+    };
+    let detail = match (name.as_str(), spec) {
+        ("$lookup", Bson::Document(spec)) => {
+            let from = spec.get_str("from").unwrap_or("(documents)");
+            let as_field = spec.get_str("as").unwrap_or_default();
+            sub_pipeline(as_field.to_owned(), spec.get_array("pipeline").ok());
+            format!("from {from} as {as_field}")
+        }
+        ("$unionWith", Bson::String(collection)) => collection.clone(),
+        ("$unionWith", Bson::Document(spec)) => {
+            let collection = spec.get_str("coll").unwrap_or("(documents)");
+            sub_pipeline(collection.to_owned(), spec.get_array("pipeline").ok());
+            collection.to_owned()
+        }
+        ("$facet", Bson::Document(spec)) => {
+            for (key, facet) in spec {
+                sub_pipeline(key.clone(), facet.as_array());
+            }
+            spec.keys().join(", ")
+        }
+        ("$limit" | "$skip" | "$count" | "$sample", value) => value.to_string(),
+        ("$sort", Bson::Document(spec)) => spec
+            .iter()
+            .map(|(key, direction)| format!("{key}: {direction}"))
+            .join(", "),
+        (_, Bson::Document(spec)) => spec.keys().join(", "),
+        _ => String::new(),
+    };
+    Node {
+        label: if detail.is_empty() {
+            name.clone()
+        } else {
+            format!("{name} {}", truncate(&detail))
+        },
+        id,
+        subgraphs,
+    }
+}
+
+fn truncate(detail: &str) -> String {
+    if detail.chars().count() <= MAX_DETAIL_LENGTH {
+        detail.to_owned()
+    } else {
+        let truncated: String = detail.chars().take(MAX_DETAIL_LENGTH).collect();
+        format!("{truncated}…")
+    }
+}
+
+fn mermaid(nodes: &[Node]) -> String {
+    let mut output = "flowchart TD\n".to_owned();
+    mermaid_nodes(&mut output, nodes, 1);
+    output
+}
+
+fn mermaid_nodes(output: &mut String, nodes: &[Node], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for node in nodes {
+        let label = node.label.replace('"', "#quot;");
+        let _ = writeln!(output, "{indent}{}[\"{label}\"]", node.id);
+        for subgraph in &node.subgraphs {
+            let title = subgraph.title.replace('"', "#quot;");
+            let _ = writeln!(output, "{indent}subgraph {}[\"{title}\"]", subgraph.id);
+            mermaid_nodes(output, &subgraph.nodes, depth + 1);
+            let _ = writeln!(output, "{indent}end");
+            let _ = writeln!(output, "{indent}{} -.-> {}", node.id, subgraph.id);
+        }
+    }
+    for (a, b) in nodes.iter().tuple_windows() {
+        let _ = writeln!(output, "{indent}{} --> {}", a.id, b.id);
+    }
+}
+
+fn graphviz(nodes: &[Node]) -> String {
+    let mut output = "digraph pipeline {\n    node [shape=box];\n".to_owned();
+    graphviz_nodes(&mut output, nodes, 1);
+    output.push_str("}\n");
+    output
+}
+
+fn graphviz_nodes(output: &mut String, nodes: &[Node], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for node in nodes {
+        let label = graphviz_escape(&node.label);
+        let _ = writeln!(output, "{indent}{} [label=\"{label}\"];", node.id);
+        for subgraph in &node.subgraphs {
+            let title = graphviz_escape(&subgraph.title);
+            // Graphviz only draws subgraphs whose names begin with "cluster" as boxes
+            let _ = writeln!(output, "{indent}subgraph cluster_{} {{", subgraph.id);
+            let _ = writeln!(output, "{indent}    label=\"{title}\";");
+            graphviz_nodes(output, &subgraph.nodes, depth + 1);
+            let _ = writeln!(output, "{indent}}}");
+            if let Some(first) = subgraph.nodes.first() {
+                let _ = writeln!(
+                    output,
+                    "{indent}{} -> {} [style=dashed];",
+                    node.id, first.id
+                );
+            }
+        }
+    }
+    for (a, b) in nodes.iter().tuple_windows() {
+        let _ = writeln!(output, "{indent}{} -> {};", a.id, b.id);
+    }
+}
+
+fn graphviz_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::DiagramFormat;
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    use crate::mongodb::{Pipeline, Stage};
+
+    use super::pipeline_diagram;
+
+    fn pipeline() -> Pipeline {
+        Pipeline::from_iter([
+            Stage::Match(doc! { "Name": "Queen" }),
+            Stage::Other(doc! {
+                "$lookup": {
+                    "from": "Album",
+                    "as": "albums",
+                    "pipeline": [{ "$limit": 10 }],
+                }
+            }),
+            Stage::Limit(5),
+        ])
+    }
+
+    #[test]
+    fn draws_lookup_pipelines_as_subgraphs_in_mermaid() -> anyhow::Result<()> {
+        let diagram = pipeline_diagram(DiagramFormat::Mermaid, &pipeline())?;
+        assert_eq!(
+            diagram,
+            r#"flowchart TD
+    s_0["$match Name"]
+    s_1["$lookup from Album as albums"]
+    subgraph s_1_0["albums"]
+        s_1_0_0["$limit 10"]
+    end
+    s_1 -.-> s_1_0
+    s_2["$limit 5"]
+    s_0 --> s_1
+    s_1 --> s_2
+"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn draws_lookup_pipelines_as_clusters_in_graphviz() -> anyhow::Result<()> {
+        let diagram = pipeline_diagram(DiagramFormat::Graphviz, &pipeline())?;
+        assert_eq!(
+            diagram,
+            r#"digraph pipeline {
+    node [shape=box];
+    s_0 [label="$match Name"];
+    s_1 [label="$lookup from Album as albums"];
+    subgraph cluster_s_1_0 {
+        label="albums";
+        s_1_0_0 [label="$limit 10"];
+    }
+    s_1 -> s_1_0_0 [style=dashed];
+    s_2 [label="$limit 5"];
+    s_0 -> s_1;
+    s_1 -> s_2;
+}
+"#
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+//! Implementations of [DatabaseTrait] and [CollectionTrait] that record interactions with MongoDB
+//! to files, or replay recorded interactions without a database. See
+//! [configuration::ConfigurationRecordingOptions].
+//!
+//! Each interaction is written to a file named by a hash of its target, operation, request, and
+//! options. Requests and responses are written as canonical extended JSON so that recordings
+//! preserve BSON types, and can be read and edited by hand to build bug reproduction bundles.
+//! Request comments (see [super::TaggedDatabase]) are different for every request so they are
+//! left out of interactions.
+
+use std::{future::Future, io, path::PathBuf};
+
+use async_trait::async_trait;
+use configuration::{ConfigurationRecordingOptions, RecordingMode};
+use futures::stream;
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{self, Bson, Document, RawDocumentBuf},
+    error::Error,
+    options::{AggregateOptions, CountOptions, FindOptions},
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{CollectionTrait, DatabaseTrait, Pipeline};
+
+type Documents<T> = stream::Iter<std::vec::IntoIter<Result<T, Error>>>;
+
+/// Records or replays commands that would run against the given database, which may itself be
+/// a wrapper such as [super::SnapshotDatabase].
+#[derive(Clone, Debug)]
+pub struct RecordingDatabase<D = Database> {
+    database: D,
+    recorder: Recorder,
+}
+
+impl<D> RecordingDatabase<D> {
+    pub fn new(options: &ConfigurationRecordingOptions, database: D) -> Self {
+        RecordingDatabase {
+            database,
+            recorder: Recorder {
+                mode: options.mode,
+                directory: options.directory.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordingCollection<C = Collection<Document>> {
+    /// Collection name which identifies recorded interactions
+    name: String,
+    collection: C,
+    recorder: Recorder,
+}
+
+#[async_trait]
+impl<D> DatabaseTrait for RecordingDatabase<D>
+where
+    D: DatabaseTrait + Clone + Send + Sync,
+    D::Collection: Clone + Send + Sync,
+    D::DocumentCursor: Send,
+{
+    type Collection = RecordingCollection<D::Collection>;
+    type DocumentCursor = Documents<RawDocumentBuf>;
+
+    async fn aggregate<Options>(
+        &self,
+        pipeline: Pipeline,
+        options: Options,
+    ) -> Result<Self::DocumentCursor, Error>
+    where
+        Options: Into<Option<AggregateOptions>> + Send + 'static,
+    {
+        let options: Option<AggregateOptions> = options.into();
+        let interaction = Interaction::new(
+            None,
+            "aggregate",
+            to_bson(&pipeline)?,
+            &options.clone().map(|mut options| {
+                options.comment = None;
+                options
+            }),
+        );
+        let database = self.database.clone();
+        let response = self
+            .recorder
+            .run(interaction, async move {
+                let cursor = DatabaseTrait::aggregate(&database, pipeline, options).await?;
+                raw_documents_to_bson(cursor.try_collect().await?)
+            })
+            .await?;
+        raw_documents(response)
+    }
+
+    fn collection(&self, name: &str) -> Self::Collection {
+        RecordingCollection {
+            name: name.to_owned(),
+            collection: self.database.collection(name),
+            recorder: self.recorder.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<C> CollectionTrait<Document> for RecordingCollection<C>
+where
+    C: CollectionTrait<Document> + Clone + Send + Sync,
+    C::DocumentCursor: Send,
+{
+    type DocumentCursor = Documents<RawDocumentBuf>;
+
+    async fn aggregate<Options>(
+        &self,
+        pipeline: Pipeline,
+        options: Options,
+    ) -> Result<Self::DocumentCursor, Error>
+    where
+        Options: Into<Option<AggregateOptions>> + Send + 'static,
+    {
+        let options: Option<AggregateOptions> = options.into();
+        let interaction = Interaction::new(
+            Some(self.name.as_str()),
+            "aggregate",
+            to_bson(&pipeline)?,
+            &options.clone().map(|mut options| {
+                options.comment = None;
+                options
+            }),
+        );
+        let collection = self.collection.clone();
+        let response = self
+            .recorder
+            .run(interaction, async move {
+                let cursor = CollectionTrait::aggregate(&collection, pipeline, options).await?;
+                raw_documents_to_bson(cursor.try_collect().await?)
+            })
+            .await?;
+        raw_documents(response)
+    }
+
+    async fn find<Filter, Options>(
+        &self,
+        filter: Filter,
+        options: Options,
+    ) -> Result<Self::DocumentCursor, Error>
+    where
+        Filter: Into<Option<Document>> + Send + 'static,
+        Options: Into<Option<FindOptions>> + Send + 'static,
+    {
+        let filter: Option<Document> = filter.into();
+        let options: Option<FindOptions> = options.into();
+        let interaction = Interaction::new(
+            Some(self.name.as_str()),
+            "find",
+            filter.clone().map_or(Bson::Null, Bson::Document),
+            &options.clone().map(|mut options| {
+                options.comment_bson = None;
+                options
+            }),
+        );
+        let collection = self.collection.clone();
+        let response = self
+            .recorder
+            .run(interaction, async move {
+                let cursor = CollectionTrait::find(&collection, filter, options).await?;
+                raw_documents_to_bson(cursor.try_collect().await?)
+            })
+            .await?;
+        raw_documents(response)
+    }
+
+    async fn count_documents<Filter, Options>(
+        &self,
+        filter: Filter,
+        options: Options,
+    ) -> Result<u64, Error>
+    where
+        Filter: Into<Option<Document>> + Send + 'static,
+        Options: Into<Option<CountOptions>> + Send + 'static,
+    {
+        let filter: Option<Document> = filter.into();
+        let options: Option<CountOptions> = options.into();
+        let interaction = Interaction::new(
+            Some(self.name.as_str()),
+            "count",
+            filter.clone().map_or(Bson::Null, Bson::Document),
+            &options.clone().map(|mut options| {
+                options.comment = None;
+                options
+            }),
+        );
+        let collection = self.collection.clone();
+        let response = self
+            .recorder
+            .run(interaction, async move {
+                let count = CollectionTrait::count_documents(&collection, filter, options).await?;
+                Ok(vec![Bson::Int64(count as i64)])
+            })
+            .await?;
+        match response.as_slice() {
+            [Bson::Int64(count)] => Ok(*count as u64),
+            _ => Err(invalid_data("expected a recorded count")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Recorder {
+    mode: RecordingMode,
+    directory: PathBuf,
+}
+
+impl Recorder {
+    /// In record mode runs the given operation, and writes its response. In replay mode reads the
+    /// recorded response instead.
+    async fn run(
+        &self,
+        interaction: Interaction,
+        operation: impl Future<Output = Result<Vec<Bson>, Error>>,
+    ) -> Result<Vec<Bson>, Error> {
+        let path = self.directory.join(interaction.file_name()?);
+        match self.mode {
+            RecordingMode::Record => {
+                let response = operation.await?;
+                let recording = Recording {
+                    interaction,
+                    response: response
+                        .iter()
+                        .map(|value| value.clone().into_canonical_extjson())
+                        .collect(),
+                };
+                tokio::fs::create_dir_all(&self.directory).await?;
+                tokio::fs::write(
+                    &path,
+                    serde_json::to_vec_pretty(&recording).map_err(invalid_data)?,
+                )
+                .await?;
+                Ok(response)
+            }
+            RecordingMode::Replay => {
+                let bytes = tokio::fs::read(&path).await.map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "no recording for {} at {path:?}: {err}",
+                            interaction.operation
+                        ),
+                    )
+                })?;
+                let recording: Recording = serde_json::from_slice(&bytes).map_err(invalid_data)?;
+                if recording.interaction != interaction {
+                    return Err(invalid_data(format!(
+                        "the recording at {path:?} is for a different interaction"
+                    )));
+                }
+                recording
+                    .response
+                    .into_iter()
+                    .map(|value| Bson::try_from(value).map_err(invalid_data))
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct Interaction {
+    /// The collection name, or `None` for database-level aggregations
+    target: Option<String>,
+    operation: String,
+    request: Value,
+    /// Debug formatting of driver options which are not serializable
+    options: String,
+}
+
+impl Interaction {
+    fn new(
+        target: Option<&str>,
+        operation: &str,
+        request: Bson,
+        options: &impl std::fmt::Debug,
+    ) -> Self {
+        Interaction {
+            target: target.map(ToOwned::to_owned),
+            operation: operation.to_owned(),
+            request: request.into_canonical_extjson(),
+            options: format!("{options:?}"),
+        }
+    }
+
+    fn file_name(&self) -> Result<String, Error> {
+        let key = serde_json::to_vec(self).map_err(invalid_data)?;
+        // FNV-1a
+        let hash = key.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+        Ok(format!("{hash:016x}.json"))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Recording {
+    #[serde(flatten)]
+    interaction: Interaction,
+    response: Vec<Value>,
+}
+
+fn to_bson(pipeline: &Pipeline) -> Result<Bson, Error> {
+    bson::to_bson(pipeline).map_err(invalid_data)
+}
+
+fn raw_documents_to_bson(documents: Vec<RawDocumentBuf>) -> Result<Vec<Bson>, Error> {
+    documents
+        .into_iter()
+        .map(|document| {
+            Ok(Bson::Document(
+                document.to_document().map_err(invalid_data)?,
+            ))
+        })
+        .collect()
+}
+
+fn raw_documents(values: Vec<Bson>) -> Result<Documents<RawDocumentBuf>, Error> {
+    let documents = values
+        .into_iter()
+        .map(|value| match value {
+            Bson::Document(document) => {
+                RawDocumentBuf::from_document(&document).map_err(invalid_data)
+            }
+            _ => Err(invalid_data("expected a recorded document")),
+        })
+        .collect::<Vec<_>>();
+    Ok(stream::iter(documents))
+}
+
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, err).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future, io};
+
+    use configuration::RecordingMode;
+    use mongodb::bson::{bson, Bson};
+    use pretty_assertions::assert_eq;
+
+    use super::{Interaction, Recorder};
+
+    #[tokio::test]
+    async fn replays_recorded_responses() -> anyhow::Result<()> {
+        let directory = std::env::temp_dir().join(format!("recording-{}", std::process::id()));
+        let interaction = || {
+            Interaction::new(
+                Some("movies"),
+                "aggregate",
+                bson!([{ "$match": { "year": 1999 } }]),
+                &None::<()>,
+            )
+        };
+        let response = vec![bson!({ "title": "The Matrix", "year": 1999_i64 })];
+
+        let recorder = Recorder {
+            mode: RecordingMode::Record,
+            directory: directory.clone(),
+        };
+        let recorded = recorder
+            .run(interaction(), future::ready(Ok(response.clone())))
+            .await?;
+        assert_eq!(recorded, response);
+
+        let replayer = Recorder {
+            mode: RecordingMode::Replay,
+            directory: directory.clone(),
+        };
+        let replayed = replayer
+            .run(
+                interaction(),
+                future::ready(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "replay should not run the operation",
+                )
+                .into())),
+            )
+            .await?;
+        assert_eq!(replayed, response);
+
+        let missing = replayer
+            .run(
+                Interaction::new(Some("movies"), "find", Bson::Null, &None::<()>),
+                future::ready(Ok(vec![])),
+            )
+            .await;
+        assert!(missing.is_err());
+
+        std::fs::remove_dir_all(directory)?;
+        Ok(())
+    }
+}
@@ -32,6 +32,13 @@ impl Selection {
         } else {
             &empty_map
         };
+        Self::from_fields(fields)
+    }
+
+    /// Selection for the given fields relative to the root of the input document
+    pub fn from_fields(
+        fields: &IndexMap<ndc_models::FieldName, Field>,
+    ) -> Result<Selection, MongoAgentError> {
         let doc = from_query_request_helper(&[], fields)?;
         Ok(Selection(doc))
     }
@@ -94,7 +101,6 @@ fn selection_for_field(parent_columns: &[&str], field: &Field) -> Result<Bson, M
             relationship,
             aggregates,
             fields,
-            ..
         } => {
             // The pipeline for the relationship has already selected the requested fields with the
             // appropriate aliases. At this point all we need to do is to prune the selection down
@@ -399,6 +405,10 @@ mod tests {
             procedures: Default::default(),
             native_mutations: Default::default(),
             native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
             options: Default::default(),
         })
     }
@@ -434,6 +444,10 @@ mod tests {
             procedures: Default::default(),
             native_mutations: Default::default(),
             native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
             options: Default::default(),
         })
     }
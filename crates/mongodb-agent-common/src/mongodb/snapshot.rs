@@ -0,0 +1,249 @@
+//! Implementations of [DatabaseTrait] and [CollectionTrait] that run every command in one session
+//! with snapshot reads. This is enabled by the `snapshotReads` query option. A query request that
+//! is split into several commands, such as a request with variable sets that is split to fit the
+//! command size limit, otherwise reads each part at a different point in time, and concurrent
+//! writes can make the combined response internally inconsistent.
+//!
+//! Snapshot reads require MongoDB 5.0 or later on a replica set or sharded cluster. If the server
+//! does not support them commands run without a session, and later requests made with the same
+//! [SnapshotSupport] do not try again.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use async_trait::async_trait;
+use futures::stream;
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{Document, RawDocumentBuf},
+    error::{Error, ErrorKind},
+    options::{AggregateOptions, CountOptions, FindOptions, SessionOptions},
+    ClientSession, Collection, Database,
+};
+use tokio::sync::Mutex;
+
+use super::{CollectionTrait, DatabaseTrait, Pipeline};
+
+type Documents<T> = stream::Iter<std::vec::IntoIter<Result<T, Error>>>;
+
+/// Tracks whether the MongoDB deployment that a connector uses supports snapshot reads. This is
+/// kept in connector state so that the result of one connector's check does not affect others.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotSupport {
+    /// Set when the server reports that it does not support snapshot reads
+    unsupported: Arc<AtomicBool>,
+}
+
+impl SnapshotSupport {
+    fn is_known_unsupported(&self) -> bool {
+        self.unsupported.load(Ordering::Relaxed)
+    }
+
+    /// Checks whether a command failed because the server does not support snapshot reads. If so
+    /// later requests do not start snapshot sessions.
+    fn is_unsupported<T>(&self, result: &Result<T, Error>) -> bool {
+        let unsupported = matches!(
+            result,
+            Err(err) if matches!(*err.kind, ErrorKind::IncompatibleServer { .. })
+        );
+        if unsupported && !self.unsupported.swap(true, Ordering::Relaxed) {
+            tracing::warn!("the MongoDB server does not support snapshot reads; queries that run several commands will read each at a different point in time");
+        }
+        unsupported
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SnapshotDatabase {
+    database: Database,
+    session: Option<Arc<Mutex<ClientSession>>>,
+    support: SnapshotSupport,
+}
+
+impl SnapshotDatabase {
+    /// Starts a session with snapshot reads. The snapshot is taken when the first command runs.
+    pub async fn start(database: Database, support: &SnapshotSupport) -> Result<Self, Error> {
+        let session = if support.is_known_unsupported() {
+            None
+        } else {
+            let options = SessionOptions::builder().snapshot(true).build();
+            let session = database.client().start_session(options).await?;
+            Some(Arc::new(Mutex::new(session)))
+        };
+        Ok(SnapshotDatabase {
+            database,
+            session,
+            support: support.clone(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SnapshotCollection {
+    collection: Collection<Document>,
+    session: Option<Arc<Mutex<ClientSession>>>,
+    support: SnapshotSupport,
+}
+
+#[async_trait]
+impl DatabaseTrait for SnapshotDatabase {
+    type Collection = SnapshotCollection;
+    type DocumentCursor = Documents<RawDocumentBuf>;
+
+    async fn aggregate<Options>(
+        &self,
+        pipeline: Pipeline,
+        options: Options,
+    ) -> Result<Self::DocumentCursor, Error>
+    where
+        Options: Into<Option<AggregateOptions>> + Send + 'static,
+    {
+        let options: Option<AggregateOptions> = options.into();
+        if let Some(session) = &self.session {
+            let mut session = session.lock().await;
+            let result = async {
+                let mut cursor = self
+                    .database
+                    .aggregate_with_session(pipeline.clone(), options.clone(), &mut session)
+                    .await?
+                    .with_type::<RawDocumentBuf>();
+                cursor.stream(&mut session).try_collect::<Vec<_>>().await
+            }
+            .await;
+            if !self.support.is_unsupported(&result) {
+                return Ok(documents(result?));
+            }
+        }
+        let cursor = DatabaseTrait::aggregate(&self.database, pipeline, options).await?;
+        Ok(documents(cursor.try_collect().await?))
+    }
+
+    fn collection(&self, name: &str) -> Self::Collection {
+        SnapshotCollection {
+            collection: self.database.collection(name),
+            session: self.session.clone(),
+            support: self.support.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl CollectionTrait<Document> for SnapshotCollection {
+    type DocumentCursor = Documents<RawDocumentBuf>;
+
+    async fn aggregate<Options>(
+        &self,
+        pipeline: Pipeline,
+        options: Options,
+    ) -> Result<Self::DocumentCursor, Error>
+    where
+        Options: Into<Option<AggregateOptions>> + Send + 'static,
+    {
+        let options: Option<AggregateOptions> = options.into();
+        if let Some(session) = &self.session {
+            let mut session = session.lock().await;
+            let result = async {
+                let mut cursor = self
+                    .collection
+                    .aggregate_with_session(pipeline.clone(), options.clone(), &mut session)
+                    .await?
+                    .with_type::<RawDocumentBuf>();
+                cursor.stream(&mut session).try_collect::<Vec<_>>().await
+            }
+            .await;
+            if !self.support.is_unsupported(&result) {
+                return Ok(documents(result?));
+            }
+        }
+        let cursor = CollectionTrait::aggregate(&self.collection, pipeline, options).await?;
+        Ok(documents(cursor.try_collect().await?))
+    }
+
+    async fn find<Filter, Options>(
+        &self,
+        filter: Filter,
+        options: Options,
+    ) -> Result<Self::DocumentCursor, Error>
+    where
+        Filter: Into<Option<Document>> + Send + 'static,
+        Options: Into<Option<FindOptions>> + Send + 'static,
+    {
+        let filter: Option<Document> = filter.into();
+        let options: Option<FindOptions> = options.into();
+        if let Some(session) = &self.session {
+            let mut session = session.lock().await;
+            let result = async {
+                let mut cursor = self
+                    .collection
+                    .find_with_session(filter.clone(), options.clone(), &mut session)
+                    .await?
+                    .with_type::<RawDocumentBuf>();
+                cursor.stream(&mut session).try_collect::<Vec<_>>().await
+            }
+            .await;
+            if !self.support.is_unsupported(&result) {
+                return Ok(documents(result?));
+            }
+        }
+        let cursor = CollectionTrait::find(&self.collection, filter, options).await?;
+        Ok(documents(cursor.try_collect().await?))
+    }
+
+    async fn count_documents<Filter, Options>(
+        &self,
+        filter: Filter,
+        options: Options,
+    ) -> Result<u64, Error>
+    where
+        Filter: Into<Option<Document>> + Send + 'static,
+        Options: Into<Option<CountOptions>> + Send + 'static,
+    {
+        let filter: Option<Document> = filter.into();
+        let options: Option<CountOptions> = options.into();
+        if let Some(session) = &self.session {
+            let mut session = session.lock().await;
+            let result = self
+                .collection
+                .count_documents_with_session(filter.clone(), options.clone(), &mut session)
+                .await;
+            if !self.support.is_unsupported(&result) {
+                return result;
+            }
+        }
+        self.collection.count_documents(filter, options).await
+    }
+}
+
+fn documents<T>(documents: Vec<T>) -> Documents<T> {
+    stream::iter(documents.into_iter().map(Ok).collect::<Vec<_>>())
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::error::{Error, ErrorKind};
+
+    use super::SnapshotSupport;
+
+    #[test]
+    fn stops_using_snapshot_sessions_when_server_does_not_support_them() {
+        let support = SnapshotSupport::default();
+        let other_error: Result<(), Error> = Err(ErrorKind::InvalidResponse {
+            message: "unexpected response".to_owned(),
+        }
+        .into());
+        assert!(!support.is_unsupported(&other_error));
+        assert!(!support.is_known_unsupported());
+
+        let unsupported: Result<(), Error> = Err(ErrorKind::IncompatibleServer {
+            message: "Snapshot reads require MongoDB 5.0 or later".to_owned(),
+        }
+        .into());
+        assert!(support.is_unsupported(&unsupported));
+        assert!(support.is_known_unsupported());
+
+        // Support is tracked separately for each connector
+        assert!(!SnapshotSupport::default().is_known_unsupported());
+    }
+}
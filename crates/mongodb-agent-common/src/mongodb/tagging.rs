@@ -0,0 +1,183 @@
+//! Implementations of [DatabaseTrait] and [CollectionTrait] that attach a `comment` to every
+//! aggregate, find, and count command. MongoDB records the comment in the profiler, in the slow
+//! query log, and in `currentOp` output, so entries there can be correlated with connector traces
+//! and with the query request that produced them.
+
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, Document},
+    error::Error,
+    options::{AggregateOptions, CountOptions, FindOptions},
+};
+use opentelemetry::trace::TraceContextExt as _;
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+use super::{CollectionTrait, DatabaseTrait, Pipeline};
+
+/// Builds the comment for a request against the given collection. Each call produces a new
+/// request id. The trace id is taken from the current tracing span when it belongs to a trace.
+pub fn request_comment(collection: &str) -> Bson {
+    let mut comment = doc! {
+        "requestId": ObjectId::new().to_hex(),
+        "collection": collection,
+    };
+    if let Some(trace_id) = current_trace_id() {
+        comment.insert("traceId", trace_id);
+    }
+    Bson::Document(comment)
+}
+
+fn current_trace_id() -> Option<String> {
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+#[derive(Clone, Debug)]
+pub struct TaggedDatabase<D> {
+    database: D,
+    comment: Bson,
+}
+
+impl<D> TaggedDatabase<D> {
+    pub fn new(database: D, comment: Bson) -> Self {
+        TaggedDatabase { database, comment }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TaggedCollection<C> {
+    collection: C,
+    comment: Bson,
+}
+
+#[async_trait]
+impl<D> DatabaseTrait for TaggedDatabase<D>
+where
+    D: DatabaseTrait + Send + Sync,
+    D::Collection: Send + Sync,
+{
+    type Collection = TaggedCollection<D::Collection>;
+    type DocumentCursor = D::DocumentCursor;
+
+    async fn aggregate<Options>(
+        &self,
+        pipeline: Pipeline,
+        options: Options,
+    ) -> Result<Self::DocumentCursor, Error>
+    where
+        Options: Into<Option<AggregateOptions>> + Send + 'static,
+    {
+        let mut options = options.into().unwrap_or_default();
+        options.comment = Some(self.comment.clone());
+        self.database.aggregate(pipeline, Some(options)).await
+    }
+
+    fn collection(&self, name: &str) -> Self::Collection {
+        TaggedCollection {
+            collection: self.database.collection(name),
+            comment: self.comment.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<C> CollectionTrait<Document> for TaggedCollection<C>
+where
+    C: CollectionTrait<Document> + Send + Sync,
+{
+    type DocumentCursor = C::DocumentCursor;
+
+    async fn aggregate<Options>(
+        &self,
+        pipeline: Pipeline,
+        options: Options,
+    ) -> Result<Self::DocumentCursor, Error>
+    where
+        Options: Into<Option<AggregateOptions>> + Send + 'static,
+    {
+        let mut options = options.into().unwrap_or_default();
+        options.comment = Some(self.comment.clone());
+        self.collection.aggregate(pipeline, Some(options)).await
+    }
+
+    async fn find<Filter, Options>(
+        &self,
+        filter: Filter,
+        options: Options,
+    ) -> Result<Self::DocumentCursor, Error>
+    where
+        Filter: Into<Option<Document>> + Send + 'static,
+        Options: Into<Option<FindOptions>> + Send + 'static,
+    {
+        let mut options = options.into().unwrap_or_default();
+        options.comment_bson = Some(self.comment.clone());
+        self.collection.find(filter.into(), Some(options)).await
+    }
+
+    async fn count_documents<Filter, Options>(
+        &self,
+        filter: Filter,
+        options: Options,
+    ) -> Result<u64, Error>
+    where
+        Filter: Into<Option<Document>> + Send + 'static,
+        Options: Into<Option<CountOptions>> + Send + 'static,
+    {
+        let mut options = options.into().unwrap_or_default();
+        options.comment = Some(self.comment.clone());
+        self.collection
+            .count_documents(filter.into(), Some(options))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::{bson::Bson, options::AggregateOptions};
+    use pretty_assertions::assert_eq;
+
+    use crate::mongodb::{
+        test_helpers::mock_stream, CollectionTrait as _, DatabaseTrait as _, MockCollectionTrait,
+        MockDatabaseTrait, Pipeline,
+    };
+
+    use super::{request_comment, TaggedDatabase};
+
+    #[tokio::test]
+    async fn attaches_comment_to_collection_aggregate() -> anyhow::Result<()> {
+        let comment = request_comment("movies");
+        let Bson::Document(fields) = &comment else {
+            panic!("expected a document");
+        };
+        assert_eq!(fields.get_str("collection")?, "movies");
+        assert_eq!(fields.get_str("requestId")?.len(), 24);
+
+        let expected_comment = comment.clone();
+        let mut db = MockDatabaseTrait::new();
+        db.expect_collection().returning(move |_| {
+            let expected_comment = expected_comment.clone();
+            let mut collection = MockCollectionTrait::new();
+            collection
+                .expect_aggregate()
+                .returning(move |_, options: Option<AggregateOptions>| {
+                    assert_eq!(
+                        options.and_then(|options| options.comment),
+                        Some(expected_comment.clone())
+                    );
+                    Ok(mock_stream(vec![]))
+                });
+            collection
+        });
+
+        let tagged = TaggedDatabase::new(db, comment);
+        let collection = tagged.collection("movies");
+        collection
+            .aggregate(Pipeline::empty(), None::<AggregateOptions>)
+            .await?;
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use futures_util::stream::{iter, Iter};
 use mongodb::{
-    bson::{to_bson, Bson},
+    bson::{to_bson, Bson, Document, RawDocumentBuf},
     error::Error,
     options::AggregateOptions,
 };
@@ -25,6 +25,11 @@ pub fn mock_stream<T>(
     iter(items)
 }
 
+/// Aggregate cursors produce raw BSON documents
+fn raw_document(doc: &Document) -> RawDocumentBuf {
+    RawDocumentBuf::from_document(doc).expect("mock pipeline result should be serializable to BSON")
+}
+
 /// Mocks the result of an aggregate call on a given collection.
 pub fn mock_collection_aggregate_response(
     collection: impl ToString,
@@ -54,7 +59,7 @@ pub fn mock_collection_aggregate_response(
                     items
                         .into_iter()
                         .map(|x| match x {
-                            Bson::Document(doc) => Ok(doc),
+                            Bson::Document(doc) => Ok(raw_document(&doc)),
                             _ => panic!("mock pipeline result should be an array of documents"),
                         })
                         .collect()
@@ -104,7 +109,7 @@ pub fn mock_collection_aggregate_response_for_pipeline(
                     items
                         .into_iter()
                         .map(|x| match x {
-                            Bson::Document(doc) => Ok(doc),
+                            Bson::Document(doc) => Ok(raw_document(&doc)),
                             _ => panic!("mock pipeline result should be an array of documents"),
                         })
                         .collect()
@@ -139,7 +144,7 @@ pub fn mock_aggregate_response_for_pipeline(
                 items
                     .into_iter()
                     .map(|x| match x {
-                        Bson::Document(doc) => Ok(doc),
+                        Bson::Document(doc) => Ok(raw_document(&doc)),
                         _ => panic!("mock pipeline result should be an array of documents"),
                     })
                     .collect()
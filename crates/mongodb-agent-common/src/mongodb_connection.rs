@@ -7,7 +7,11 @@ use crate::interface_types::MongoAgentError;
 
 const DRIVER_NAME: &str = "Hasura";
 
-pub async fn get_mongodb_client(database_uri: &str) -> Result<Client, MongoAgentError> {
+/// If `min_pool_size` is given it overrides the `minPoolSize` option in the connection URI.
+pub async fn get_mongodb_client(
+    database_uri: &str,
+    min_pool_size: Option<u32>,
+) -> Result<Client, MongoAgentError> {
     // An extra line of code to work around a DNS issue on Windows:
     let mut options =
         ClientOptions::parse_with_resolver_config(database_uri, ResolverConfig::cloudflare())
@@ -16,6 +20,10 @@ pub async fn get_mongodb_client(database_uri: &str) -> Result<Client, MongoAgent
     // Helps MongoDB to collect statistics on Hasura use
     options.driver_info = Some(DriverInfo::builder().name(DRIVER_NAME).build());
 
+    if let Some(min_pool_size) = min_pool_size {
+        options.min_pool_size = Some(min_pool_size);
+    }
+
     let client = Client::with_options(options)?;
     Ok(client)
 }
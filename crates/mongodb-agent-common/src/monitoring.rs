@@ -0,0 +1,80 @@
+//! Hooks for observing query requests. Connectors built on this crate can implement
+//! [QueryObserver] to add their own logging or metrics, and register observers with
+//! `MongoConnector::with_query_observer` when the connector is set up. Observers are called
+//! synchronously on the request path, so they should hand off any slow work.
+//!
+//! Registered observers are stored in connector state, and passed to query execution as
+//! [QueryObservers].
+
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use crate::{interface_types::MongoAgentError, mongo_query_plan::QueryPlan, mongodb::Pipeline};
+
+/// Receives events for each query request. Every method has a default implementation that does
+/// nothing so that observers only implement the events they need.
+pub trait QueryObserver: Send + Sync {
+    /// A query request was translated to a query plan
+    fn plan_built(&self, _event: &PlanBuilt<'_>) {}
+
+    /// An aggregation pipeline is about to be sent to MongoDB. A request may issue several.
+    /// Queries that run as a `find` or `countDocuments` command report the equivalent pipeline.
+    fn pipeline_issued(&self, _event: &PipelineIssued<'_>) {}
+
+    /// MongoDB returned all documents for an issued pipeline
+    fn response_received(&self, _event: &ResponseReceived<'_>) {}
+
+    /// A query request failed
+    fn query_failed(&self, _event: &QueryFailed<'_>) {}
+}
+
+#[derive(Debug)]
+pub struct PlanBuilt<'a> {
+    pub query_plan: &'a QueryPlan,
+}
+
+#[derive(Debug)]
+pub struct PipelineIssued<'a> {
+    /// The collection that the pipeline runs against, or `None` for a database-level aggregation
+    pub collection: Option<&'a ndc_models::CollectionName>,
+    pub pipeline: &'a Pipeline,
+}
+
+#[derive(Debug)]
+pub struct ResponseReceived<'a> {
+    pub collection: Option<&'a ndc_models::CollectionName>,
+    pub documents: usize,
+    /// Time from issuing the pipeline until the last document was received
+    pub elapsed: Duration,
+}
+
+#[derive(Debug)]
+pub struct QueryFailed<'a> {
+    /// The collection named in the query request
+    pub collection: &'a ndc_models::CollectionName,
+    pub error: &'a MongoAgentError,
+}
+
+/// The observers registered with a connector
+#[derive(Clone, Default)]
+pub struct QueryObservers(Arc<Vec<Arc<dyn QueryObserver>>>);
+
+impl QueryObservers {
+    pub fn new(observers: Vec<Arc<dyn QueryObserver>>) -> Self {
+        QueryObservers(Arc::new(observers))
+    }
+
+    /// Calls the given function with each registered observer
+    pub(crate) fn notify(&self, event: impl Fn(&dyn QueryObserver)) {
+        for observer in self.0.iter() {
+            event(observer.as_ref());
+        }
+    }
+}
+
+impl Debug for QueryObservers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryObservers")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
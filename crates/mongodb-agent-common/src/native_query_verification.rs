@@ -0,0 +1,233 @@
+//! Startup verification of native queries and native mutations. This is enabled by the
+//! `verifyNativeQueries` warm-up option. Each native query pipeline, and each native mutation
+//! command that supports `explain`, is sent to the server in an `explain` command with synthesized
+//! argument values. Explaining a command checks that the server accepts it without running it, so
+//! mistakes in configuration surface at deploy time instead of on first use.
+//!
+//! Native mutation commands that cannot be explained, such as `insert`, are not checked. The raw
+//! pipeline function is not checked because its pipeline comes from query requests.
+
+use std::fmt::{self, Display};
+
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::ErrorKind,
+    Database,
+};
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::MongoConfiguration,
+    procedure::{interpolate_placeholders, interpolated_command},
+    query::mock_argument_value,
+};
+
+/// Commands that the `explain` command accepts
+const EXPLAINABLE_COMMANDS: [&str; 7] = [
+    "aggregate",
+    "count",
+    "delete",
+    "distinct",
+    "find",
+    "findAndModify",
+    "update",
+];
+
+/// An `explain` command for one native query or native mutation
+#[derive(Clone, Debug)]
+pub struct Verification {
+    pub subject: Subject,
+    /// An error if the command could not be built, in which case the native query or mutation is
+    /// reported as rejected without contacting the server
+    command: Result<Document, String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Subject {
+    NativeQuery(ndc_models::FunctionName),
+    NativeMutation(ndc_models::ProcedureName),
+}
+
+/// A native query or native mutation that the server rejected
+#[derive(Clone, Debug)]
+pub struct Rejection {
+    pub subject: Subject,
+    pub message: String,
+}
+
+impl Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::NativeQuery(name) => write!(f, "native query, {name}"),
+            Subject::NativeMutation(name) => write!(f, "native mutation, {name}"),
+        }
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the {} was rejected: {}", self.subject, self.message)
+    }
+}
+
+/// Builds an `explain` command for each native query, and for each native mutation that can be
+/// explained
+pub fn verifications(config: &MongoConfiguration) -> Vec<Verification> {
+    let raw_pipeline_function = config.raw_pipeline().map(|options| &options.function_name);
+    let native_queries = config
+        .native_queries()
+        .iter()
+        .filter(|(name, _)| Some(*name) != raw_pipeline_function)
+        .map(|(name, native_query)| {
+            let arguments = native_query
+                .arguments
+                .iter()
+                .map(|(name, arg_type)| (name.clone(), mock_argument_value(arg_type, name)))
+                .collect();
+            let mut stages = native_query.pipeline.clone();
+            let command =
+                interpolate_placeholders(&mut stages, &native_query.placeholders, &arguments)
+                    .map(|_| {
+                        let pipeline = native_query.without_pushdown_points(stages);
+                        let aggregate = match &native_query.input_collection {
+                            Some(collection) => Bson::String(collection.to_string()),
+                            None => Bson::Int32(1),
+                        };
+                        doc! { "aggregate": aggregate, "pipeline": pipeline, "cursor": {} }
+                    })
+                    .map_err(|err| err.to_string());
+            Verification {
+                subject: Subject::NativeQuery(name.clone()),
+                command,
+            }
+        });
+
+    let native_mutations =
+        config
+            .native_mutations()
+            .iter()
+            .filter_map(|(name, native_mutation)| {
+                let command_name = native_mutation.command.keys().next()?;
+                if !EXPLAINABLE_COMMANDS.contains(&command_name.as_str()) {
+                    return None;
+                }
+                let arguments = native_mutation
+                    .arguments
+                    .iter()
+                    .map(|(name, arg_type)| (name.clone(), mock_argument_value(arg_type, name)))
+                    .collect();
+                let command = interpolated_command(&native_mutation.command, &arguments)
+                    .map(|mut command| {
+                        // `explain` does not accept a write concern
+                        command.remove("writeConcern");
+                        command
+                    })
+                    .map_err(|err| err.to_string());
+                Some(Verification {
+                    subject: Subject::NativeMutation(name.clone()),
+                    command,
+                })
+            });
+
+    native_queries.chain(native_mutations).collect()
+}
+
+/// Explains each command. Commands that the server rejects are returned. Other errors, such as
+/// network errors, fail verification so that it can be retried.
+pub async fn verify(
+    database: &Database,
+    verifications: &[Verification],
+) -> Result<Vec<Rejection>, MongoAgentError> {
+    let mut rejections = vec![];
+    for verification in verifications {
+        let command = match &verification.command {
+            Ok(command) => command,
+            Err(message) => {
+                rejections.push(Rejection {
+                    subject: verification.subject.clone(),
+                    message: message.clone(),
+                });
+                continue;
+            }
+        };
+        let explain = doc! { "explain": command.clone(), "verbosity": "queryPlanner" };
+        match database.run_command(explain, None).await {
+            Ok(_) => (),
+            Err(err) => match err.kind.as_ref() {
+                ErrorKind::Command(command_error) => rejections.push(Rejection {
+                    subject: verification.subject.clone(),
+                    message: command_error.message.clone(),
+                }),
+                _ => return Err(err.into()),
+            },
+        }
+    }
+    Ok(rejections)
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        native_query::NativeQueryRepresentation,
+        schema::{ObjectField, ObjectType, Type},
+        serialized::NativeQuery,
+        Configuration,
+    };
+    use mongodb::bson::{doc, Bson};
+    use mongodb_support::BsonScalarType as S;
+    use pretty_assertions::assert_eq;
+
+    use super::{verifications, Subject};
+    use crate::mongo_query_plan::MongoConfiguration;
+
+    #[test]
+    fn explains_native_query_pipelines_with_synthesized_arguments() -> anyhow::Result<()> {
+        let (argument_name, argument) = ObjectField::new("year", Type::Scalar(S::Int));
+        let (field_name, field) = ObjectField::new("title", Type::Scalar(S::String));
+        let native_query = NativeQuery {
+            representation: NativeQueryRepresentation::Collection,
+            input_collection: Some("movies".into()),
+            arguments: [(argument_name.into(), argument)].into(),
+            result_document_type: "movie_titles".into(),
+            object_types: [(
+                "movie_titles".into(),
+                ObjectType {
+                    fields: [(field_name.into(), field)].into(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+            pipeline: vec![doc! { "$match": { "year": "{{ year }}" } }],
+            materialized: None,
+            description: None,
+        };
+        let config = MongoConfiguration(Configuration::validate(
+            Default::default(),
+            Default::default(),
+            [("movies_by_year".into(), native_query)].into(),
+            Default::default(),
+        )?);
+
+        let verifications = verifications(&config);
+        assert_eq!(verifications.len(), 1);
+        assert_eq!(
+            verifications[0].subject,
+            Subject::NativeQuery("movies_by_year".into())
+        );
+        let command = verifications[0]
+            .command
+            .clone()
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(command.get_str("aggregate")?, "movies");
+        let stage = command.get_array("pipeline")?[0]
+            .as_document()
+            .cloned()
+            .unwrap_or_default();
+        assert!(matches!(
+            stage.get_document("$match")?.get("year"),
+            Some(Bson::Int32(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! Hooks for rewriting rows in query responses, such as masking sensitive values, converting
+//! currencies, or injecting computed fields. Connectors built on this crate can implement
+//! [ResponsePostProcessor], and register processors with
+//! `MongoConnector::with_response_post_processor` when the connector is set up.
+//!
+//! Processors see each row of the top-level row set as JSON after it has been serialized from
+//! BSON, and before the row size limit is checked. Rows of relationship fields are not processed
+//! separately; they are part of the row that contains them. Each row is buffered as a JSON value
+//! when any processor is registered, so processing costs some serialization performance.
+//!
+//! Registered processors are stored in connector state, and passed to response serialization as
+//! [ResponsePostProcessors].
+
+use std::{fmt::Debug, sync::Arc};
+
+use ndc_models::CollectionName;
+use serde_json::{Map, Value};
+
+pub trait ResponsePostProcessor: Send + Sync {
+    /// Rewrites a row in place. An error fails the query request, or leaves the row out of the
+    /// response if the `rowErrors` serialization option is set to `skip`.
+    fn process_row(
+        &self,
+        context: &RowContext<'_>,
+        row: &mut Map<String, Value>,
+    ) -> anyhow::Result<()>;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RowContext<'a> {
+    /// The collection named in the query request
+    pub collection: &'a CollectionName,
+    /// Index of the row in its row set
+    pub row: usize,
+}
+
+/// The post-processors registered with a connector, in the order that they run
+#[derive(Clone, Default)]
+pub struct ResponsePostProcessors(Arc<Vec<Arc<dyn ResponsePostProcessor>>>);
+
+impl ResponsePostProcessors {
+    pub fn new(processors: Vec<Arc<dyn ResponsePostProcessor>>) -> Self {
+        ResponsePostProcessors(Arc::new(processors))
+    }
+
+    pub(crate) fn processors(&self) -> &[Arc<dyn ResponsePostProcessor>] {
+        &self.0
+    }
+}
+
+impl Debug for ResponsePostProcessors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponsePostProcessors")
+            .field("count", &self.0.len())
+            .finish()
+    }
+}
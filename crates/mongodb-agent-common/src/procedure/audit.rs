@@ -0,0 +1,252 @@
+//! Records mutations in an audit collection when the `audit` configuration option is set. The
+//! mutation command, the audit record insert, and optionally reads of the affected documents run
+//! in a single transaction so that there is an audit record for every committed mutation.
+
+use configuration::ConfigurationAuditOptions;
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    options::{FindOptions, SelectionCriteria},
+    ClientSession, Database,
+};
+
+use super::ProcedureError;
+
+/// Documents matched by one statement of an `update`, `delete`, or `findAndModify` command
+#[derive(Clone, Debug, PartialEq)]
+struct AffectedDocuments {
+    collection: String,
+    filter: Document,
+    multi: bool,
+    is_update: bool,
+}
+
+/// Runs the given command, and records it in the audit collection. The transaction is aborted if
+/// the command, the audit record insert, or any other step fails.
+pub async fn run_audited_command(
+    database: &Database,
+    audit: &ConfigurationAuditOptions,
+    procedure_name: &ndc_models::ProcedureName,
+    version_field: Option<&ndc_models::FieldName>,
+    command: Document,
+    selection_criteria: Option<SelectionCriteria>,
+) -> Result<Document, ProcedureError> {
+    let mut session = database.client().start_session(None).await?;
+    session.start_transaction(None).await?;
+
+    let result = audit_command(
+        database,
+        &mut session,
+        audit,
+        procedure_name,
+        version_field,
+        command,
+        selection_criteria,
+    )
+    .await;
+    match result {
+        Ok(result) => {
+            session.commit_transaction().await?;
+            Ok(result)
+        }
+        Err(err) => {
+            if let Err(abort_err) = session.abort_transaction().await {
+                tracing::warn!(error = %abort_err, "failed to abort audited mutation transaction");
+            }
+            Err(err)
+        }
+    }
+}
+
+async fn audit_command(
+    database: &Database,
+    session: &mut ClientSession,
+    audit: &ConfigurationAuditOptions,
+    procedure_name: &ndc_models::ProcedureName,
+    version_field: Option<&ndc_models::FieldName>,
+    command: Document,
+    selection_criteria: Option<SelectionCriteria>,
+) -> Result<Document, ProcedureError> {
+    let affected = if audit.capture_documents {
+        affected_documents(&command)
+    } else {
+        vec![]
+    };
+    let mut before = Vec::new();
+    for target in &affected {
+        let options = FindOptions::builder()
+            .limit(if target.multi { None } else { Some(1) })
+            .build();
+        before.push(
+            find_documents(
+                database,
+                session,
+                target,
+                target.filter.clone(),
+                Some(options),
+            )
+            .await?,
+        );
+    }
+
+    let result = database
+        .run_command_with_session(command.clone(), selection_criteria, session)
+        .await?;
+    super::write_errors::check_write_result(&command, &result, true)?;
+    if let Some(version_field) = version_field {
+        super::version_check::check_version(
+            database,
+            Some(&mut *session),
+            version_field,
+            &command,
+            &result,
+        )
+        .await?;
+    }
+
+    let mut after = Vec::new();
+    for (target, documents) in affected.iter().zip(&before) {
+        if !target.is_update {
+            continue;
+        }
+        let ids: Vec<Bson> = documents
+            .iter()
+            .filter_map(|document| document.get("_id").cloned())
+            .collect();
+        let filter = doc! { "_id": { "$in": ids } };
+        after.push(find_documents(database, session, target, filter, None).await?);
+    }
+
+    let mut record = doc! {
+        "procedure": procedure_name.as_str(),
+        "database": database.name(),
+        "timestamp": DateTime::now(),
+        "command": command,
+        "result": result.clone(),
+    };
+    if audit.capture_documents {
+        record.insert("before", before.into_iter().flatten().collect::<Vec<_>>());
+        record.insert("after", after.into_iter().flatten().collect::<Vec<_>>());
+    }
+    database
+        .collection::<Document>(&audit.collection)
+        .insert_one_with_session(record, None, session)
+        .await?;
+    Ok(result)
+}
+
+async fn find_documents(
+    database: &Database,
+    session: &mut ClientSession,
+    target: &AffectedDocuments,
+    filter: Document,
+    options: Option<FindOptions>,
+) -> Result<Vec<Document>, ProcedureError> {
+    let mut cursor = database
+        .collection::<Document>(&target.collection)
+        .find_with_session(filter, options, session)
+        .await?;
+    let documents = cursor.stream(session).try_collect().await?;
+    Ok(documents)
+}
+
+/// Reads the filters of the statements in a mutation command. Commands other than `update`,
+/// `delete`, and `findAndModify` do not modify existing documents so there is nothing to capture.
+fn affected_documents(command: &Document) -> Vec<AffectedDocuments> {
+    let Some((command_name, Bson::String(collection))) = command.iter().next() else {
+        return vec![];
+    };
+    let statements = |field: &str| -> Vec<Document> {
+        match command.get_array(field) {
+            Ok(statements) => statements
+                .iter()
+                .filter_map(|statement| statement.as_document().cloned())
+                .collect(),
+            Err(_) => vec![],
+        }
+    };
+    match command_name.as_str() {
+        "update" => statements("updates")
+            .into_iter()
+            .map(|statement| AffectedDocuments {
+                collection: collection.clone(),
+                filter: statement.get_document("q").cloned().unwrap_or_default(),
+                multi: statement.get_bool("multi").unwrap_or(false),
+                is_update: true,
+            })
+            .collect(),
+        "delete" => statements("deletes")
+            .into_iter()
+            .map(|statement| AffectedDocuments {
+                collection: collection.clone(),
+                filter: statement.get_document("q").cloned().unwrap_or_default(),
+                // A limit of 0 deletes all matching documents
+                multi: !matches!(
+                    statement.get("limit"),
+                    Some(Bson::Int32(1) | Bson::Int64(1))
+                ),
+                is_update: false,
+            })
+            .collect(),
+        "findAndModify" | "findandmodify" => vec![AffectedDocuments {
+            collection: collection.clone(),
+            filter: command.get_document("query").cloned().unwrap_or_default(),
+            multi: false,
+            is_update: !command.get_bool("remove").unwrap_or(false),
+        }],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    use super::{affected_documents, AffectedDocuments};
+
+    #[test]
+    fn reads_filters_of_update_and_delete_statements() {
+        let update = doc! {
+            "update": "movies",
+            "updates": [
+                { "q": { "title": "Alien" }, "u": { "$set": { "year": 1979 } } },
+                { "q": { "year": 1980 }, "u": { "$set": { "decade": "80s" } }, "multi": true },
+            ],
+        };
+        assert_eq!(
+            affected_documents(&update),
+            vec![
+                AffectedDocuments {
+                    collection: "movies".to_owned(),
+                    filter: doc! { "title": "Alien" },
+                    multi: false,
+                    is_update: true,
+                },
+                AffectedDocuments {
+                    collection: "movies".to_owned(),
+                    filter: doc! { "year": 1980 },
+                    multi: true,
+                    is_update: true,
+                },
+            ]
+        );
+
+        let delete = doc! {
+            "delete": "movies",
+            "deletes": [{ "q": { "year": 1980 }, "limit": 0 }],
+        };
+        assert_eq!(
+            affected_documents(&delete),
+            vec![AffectedDocuments {
+                collection: "movies".to_owned(),
+                filter: doc! { "year": 1980 },
+                multi: true,
+                is_update: false,
+            }]
+        );
+
+        let insert = doc! { "insert": "movies", "documents": [{ "title": "Alien" }] };
+        assert_eq!(affected_documents(&insert), vec![]);
+    }
+}
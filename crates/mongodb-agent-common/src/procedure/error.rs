@@ -1,8 +1,11 @@
+use itertools::Itertools as _;
 use mongodb::bson::Bson;
 use thiserror::Error;
 
 use crate::query::arguments::ArgumentError;
 
+use super::{PartialWriteFailure, WriteRuleViolation};
+
 #[derive(Debug, Error)]
 pub enum ProcedureError {
     #[error("error executing mongodb command: {0}")]
@@ -17,6 +20,29 @@ pub enum ProcedureError {
     #[error("object keys must be strings, but got: \"{0}\"")]
     NonStringKey(Bson),
 
+    #[error("native mutation arguments may not reference the system collection or database, {0}")]
+    SystemCollection(String),
+
+    #[error("arguments do not satisfy write rules: {}", .0.iter().join("; "))]
+    InvalidArguments(Vec<WriteRuleViolation>),
+
     #[error("could not resolve arguments: {0}")]
     UnresolvableArguments(#[from] ArgumentError),
+
+    #[error(
+        "the document was not updated because its version field does not have the expected value"
+    )]
+    VersionConflict,
+
+    #[error("no document matches the mutation filter")]
+    DocumentNotFound,
+
+    #[error("{0}")]
+    PartialWrite(PartialWriteFailure),
+
+    #[error("a mutation with the same idempotency key is still in progress")]
+    IdempotencyKeyInUse,
+
+    #[error("the idempotency key was already used for a mutation with different arguments")]
+    IdempotencyKeyReused,
 }
@@ -0,0 +1,410 @@
+//! Idempotency keys for mutations. When idempotency is configured every procedure accepts a key
+//! argument. The key is reserved in the idempotency collection before the mutation runs, and the
+//! result is recorded under the key when the mutation succeeds. A repeat of a key returns the
+//! recorded result without running the mutation again. If the mutation fails the reservation is
+//! removed so that the request can be retried. Keys expire through a TTL index on `createdAt`.
+//!
+//! A hash of the mutation arguments is recorded with each key so that a key that is reused for
+//! a different mutation is rejected instead of getting the result of the first one.
+
+use std::{collections::BTreeMap, future::Future, time::Duration};
+
+use async_trait::async_trait;
+use configuration::ConfigurationIdempotencyOptions;
+use itertools::Itertools as _;
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    error::{ErrorKind, WriteFailure},
+    options::IndexOptions,
+    Collection, Database, IndexModel,
+};
+use sha2::{Digest as _, Sha256};
+
+#[cfg(test)]
+use mockall::automock;
+
+use crate::query::arguments::ArgumentError;
+
+use super::ProcedureError;
+
+/// MongoDB error code for a unique index violation
+const DUPLICATE_KEY: i32 = 11000;
+
+const STATUS_PENDING: &str = "pending";
+const STATUS_COMPLETE: &str = "complete";
+
+/// Removes the idempotency key argument from procedure arguments
+pub fn take_idempotency_key(
+    options: &ConfigurationIdempotencyOptions,
+    arguments: &mut BTreeMap<ndc_models::ArgumentName, serde_json::Value>,
+) -> Result<Option<String>, ProcedureError> {
+    let name = &options.key_argument;
+    match arguments.remove(name) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(key)) if !key.is_empty() => Ok(Some(key)),
+        Some(value) => Err(ArgumentError::InvalidValue {
+            name: name.clone(),
+            message: format!("expected a non-empty string, but got {value}"),
+        })?,
+    }
+}
+
+/// Runs the given mutation unless a mutation of the same procedure with the same key has already
+/// completed, in which case the recorded result is returned instead. A repeat of a key with
+/// different arguments is rejected. See [arguments_hash].
+pub async fn run_with_idempotency_key(
+    database: &Database,
+    options: &ConfigurationIdempotencyOptions,
+    procedure_name: &ndc_models::ProcedureName,
+    key: &str,
+    arguments_hash: &str,
+    mutation: impl Future<Output = Result<Document, ProcedureError>>,
+) -> Result<Document, ProcedureError> {
+    let collection = database.collection::<Document>(&options.collection);
+    run_with_records(&collection, procedure_name, key, arguments_hash, mutation).await
+}
+
+async fn run_with_records(
+    records: &impl IdempotencyRecords,
+    procedure_name: &ndc_models::ProcedureName,
+    key: &str,
+    arguments_hash: &str,
+    mutation: impl Future<Output = Result<Document, ProcedureError>>,
+) -> Result<Document, ProcedureError> {
+    let id = doc! { "procedure": procedure_name.as_str(), "key": key };
+
+    let reservation = doc! {
+        "_id": id.clone(),
+        "status": STATUS_PENDING,
+        "argumentsHash": arguments_hash,
+        "createdAt": DateTime::now(),
+    };
+    match records.insert(reservation).await {
+        Ok(()) => (),
+        Err(err) if is_duplicate_key_error(&err) => {
+            let record = records.find(id).await?;
+            return match record {
+                Some(record) if record.get_str("argumentsHash") != Ok(arguments_hash) => {
+                    Err(ProcedureError::IdempotencyKeyReused)
+                }
+                Some(record) if record.get_str("status") == Ok(STATUS_COMPLETE) => {
+                    match record.get("result") {
+                        Some(Bson::Document(result)) => Ok(result.clone()),
+                        _ => Ok(Document::new()),
+                    }
+                }
+                // Either the first request is still running, or its reservation expired between
+                // the insert and the read
+                _ => Err(ProcedureError::IdempotencyKeyInUse),
+            };
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    match mutation.await {
+        Ok(result) => {
+            let update = doc! {
+                "$set": { "status": STATUS_COMPLETE, "result": result.clone() }
+            };
+            // The mutation has been applied so its result is returned even if it cannot be
+            // recorded. A repeat of the key then gets a conflict until the reservation expires.
+            if let Err(err) = records.update(id, update).await {
+                tracing::warn!(error = %err, procedure = %procedure_name, "failed to record result for idempotency key");
+            }
+            Ok(result)
+        }
+        Err(mutation_err) => {
+            if let Err(err) = records.delete(id).await {
+                tracing::warn!(error = %err, procedure = %procedure_name, "failed to release idempotency key");
+            }
+            Err(mutation_err)
+        }
+    }
+}
+
+/// Hash of procedure arguments that is recorded with an idempotency key. Object keys are sorted
+/// so that the hash does not depend on the order that a client writes them in.
+pub fn arguments_hash(arguments: &BTreeMap<ndc_models::ArgumentName, serde_json::Value>) -> String {
+    let canonical: serde_json::Map<String, serde_json::Value> = arguments
+        .iter()
+        .map(|(name, value)| (name.to_string(), with_sorted_keys(value)))
+        .collect();
+    let json = serde_json::Value::Object(canonical).to_string();
+    format!("{:x}", Sha256::digest(json.as_bytes()))
+}
+
+fn with_sorted_keys(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(fields) => fields
+            .iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(name, value)| (name.clone(), with_sorted_keys(value)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        serde_json::Value::Array(values) => values.iter().map(with_sorted_keys).collect(),
+        value => value.clone(),
+    }
+}
+
+/// Operations on the idempotency collection. This lets us mock the collection in tests.
+#[cfg_attr(test, automock)]
+#[async_trait]
+trait IdempotencyRecords: Sync {
+    async fn insert(&self, record: Document) -> Result<(), mongodb::error::Error>;
+    async fn find(&self, id: Document) -> Result<Option<Document>, mongodb::error::Error>;
+    async fn update(&self, id: Document, update: Document) -> Result<(), mongodb::error::Error>;
+    async fn delete(&self, id: Document) -> Result<(), mongodb::error::Error>;
+}
+
+#[async_trait]
+impl IdempotencyRecords for Collection<Document> {
+    async fn insert(&self, record: Document) -> Result<(), mongodb::error::Error> {
+        self.insert_one(record, None).await?;
+        Ok(())
+    }
+
+    async fn find(&self, id: Document) -> Result<Option<Document>, mongodb::error::Error> {
+        self.find_one(doc! { "_id": id }, None).await
+    }
+
+    async fn update(&self, id: Document, update: Document) -> Result<(), mongodb::error::Error> {
+        self.update_one(doc! { "_id": id }, update, None).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: Document) -> Result<(), mongodb::error::Error> {
+        self.delete_one(doc! { "_id": id }, None).await?;
+        Ok(())
+    }
+}
+
+/// Creates the TTL index that expires idempotency keys. Creating an index that already exists
+/// with the same options has no effect.
+pub async fn create_ttl_index(
+    database: &Database,
+    options: &ConfigurationIdempotencyOptions,
+) -> Result<(), ProcedureError> {
+    let index = IndexModel::builder()
+        .keys(doc! { "createdAt": 1 })
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(options.ttl_seconds))
+                .build(),
+        )
+        .build();
+    database
+        .collection::<Document>(&options.collection)
+        .create_index(index, None)
+        .await?;
+    Ok(())
+}
+
+fn is_duplicate_key_error(err: &mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == DUPLICATE_KEY
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ConfigurationIdempotencyOptions;
+    use mockall::predicate::eq;
+    use mongodb::{
+        bson::{self, doc, Document},
+        error::{Error, ErrorKind, WriteError, WriteFailure},
+    };
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::{arguments_hash, run_with_records, take_idempotency_key, MockIdempotencyRecords};
+    use crate::procedure::ProcedureError;
+
+    #[test]
+    fn removes_idempotency_key_from_arguments() -> anyhow::Result<()> {
+        let options = ConfigurationIdempotencyOptions {
+            key_argument: "idempotencyKey".into(),
+            collection: "idempotency_keys".to_owned(),
+            ttl_seconds: 60,
+        };
+
+        let mut arguments = [
+            ("idempotencyKey".into(), json!("order-1234")),
+            ("id".into(), json!(1)),
+        ]
+        .into();
+        let key = take_idempotency_key(&options, &mut arguments)?;
+        assert_eq!(key.as_deref(), Some("order-1234"));
+        assert_eq!(arguments, [("id".into(), json!(1))].into());
+
+        let mut arguments = [("idempotencyKey".into(), json!(null))].into();
+        assert_eq!(take_idempotency_key(&options, &mut arguments)?, None);
+
+        let mut arguments = [("idempotencyKey".into(), json!(5))].into();
+        assert!(matches!(
+            take_idempotency_key(&options, &mut arguments),
+            Err(ProcedureError::UnresolvableArguments(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_arguments_regardless_of_key_order() {
+        let a = [(
+            "order".into(),
+            json!({ "id": 1, "items": [{ "sku": "a", "qty": 2 }] }),
+        )]
+        .into();
+        let b = [(
+            "order".into(),
+            json!({ "items": [{ "qty": 2, "sku": "a" }], "id": 1 }),
+        )]
+        .into();
+        let c = [(
+            "order".into(),
+            json!({ "id": 2, "items": [{ "sku": "a", "qty": 2 }] }),
+        )]
+        .into();
+        assert_eq!(arguments_hash(&a), arguments_hash(&b));
+        assert_ne!(arguments_hash(&a), arguments_hash(&c));
+    }
+
+    #[tokio::test]
+    async fn reserves_key_and_records_result() -> anyhow::Result<()> {
+        let mut records = MockIdempotencyRecords::new();
+        records
+            .expect_insert()
+            .withf(|record| {
+                record.get_str("status") == Ok("pending")
+                    && record.get_str("argumentsHash") == Ok("hash")
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        records
+            .expect_update()
+            .with(
+                eq(id()),
+                eq(doc! { "$set": { "status": "complete", "result": { "n": 1 } } }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let result = run_with_records(
+            &records,
+            &"insertOrder".into(),
+            "order-1234",
+            "hash",
+            async { Ok(doc! { "n": 1 }) },
+        )
+        .await?;
+        assert_eq!(result, doc! { "n": 1 });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replays_recorded_result_without_running_mutation() -> anyhow::Result<()> {
+        let records = records_with_existing(doc! {
+            "_id": id(),
+            "status": "complete",
+            "argumentsHash": "hash",
+            "result": { "n": 1 },
+        });
+        let result = run_with_records(
+            &records,
+            &"insertOrder".into(),
+            "order-1234",
+            "hash",
+            async { panic!("the mutation should not run again") },
+        )
+        .await?;
+        assert_eq!(result, doc! { "n": 1 });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_key_that_is_in_use() {
+        let records = records_with_existing(doc! {
+            "_id": id(),
+            "status": "pending",
+            "argumentsHash": "hash",
+        });
+        let result = run_with_records(
+            &records,
+            &"insertOrder".into(),
+            "order-1234",
+            "hash",
+            async { panic!("the mutation should not run concurrently") },
+        )
+        .await;
+        assert!(matches!(result, Err(ProcedureError::IdempotencyKeyInUse)));
+    }
+
+    #[tokio::test]
+    async fn rejects_key_reused_with_different_arguments() {
+        let records = records_with_existing(doc! {
+            "_id": id(),
+            "status": "complete",
+            "argumentsHash": "other hash",
+            "result": { "n": 1 },
+        });
+        let result = run_with_records(
+            &records,
+            &"insertOrder".into(),
+            "order-1234",
+            "hash",
+            async { panic!("the mutation should not run") },
+        )
+        .await;
+        assert!(matches!(result, Err(ProcedureError::IdempotencyKeyReused)));
+    }
+
+    #[tokio::test]
+    async fn releases_key_when_mutation_fails() {
+        let mut records = MockIdempotencyRecords::new();
+        records.expect_insert().times(1).returning(|_| Ok(()));
+        records.expect_update().never();
+        records
+            .expect_delete()
+            .with(eq(id()))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let result = run_with_records(
+            &records,
+            &"insertOrder".into(),
+            "order-1234",
+            "hash",
+            async { Err(ProcedureError::VersionConflict) },
+        )
+        .await;
+        assert!(matches!(result, Err(ProcedureError::VersionConflict)));
+    }
+
+    fn id() -> Document {
+        doc! { "procedure": "insertOrder", "key": "order-1234" }
+    }
+
+    /// Records where the reservation insert fails because the key already has a record
+    fn records_with_existing(record: Document) -> MockIdempotencyRecords {
+        let mut records = MockIdempotencyRecords::new();
+        records
+            .expect_insert()
+            .times(1)
+            .returning(|_| Err(duplicate_key_error()));
+        records
+            .expect_find()
+            .with(eq(id()))
+            .times(1)
+            .returning(move |_| Ok(Some(record.clone())));
+        records.expect_update().never();
+        records.expect_delete().never();
+        records
+    }
+
+    fn duplicate_key_error() -> Error {
+        let write_error: WriteError =
+            bson::from_document(doc! { "code": 11000, "errmsg": "E11000 duplicate key error" })
+                .unwrap();
+        ErrorKind::Write(WriteFailure::WriteError(write_error)).into()
+    }
+}
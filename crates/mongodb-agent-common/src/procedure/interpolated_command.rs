@@ -1,5 +1,8 @@
 use std::collections::BTreeMap;
 
+use configuration::placeholders::{
+    parse_template, PathElement, Placeholder, PlaceholderTarget, TemplatePart,
+};
 use itertools::Itertools as _;
 use mongodb::bson::{self, Bson};
 
@@ -78,29 +81,106 @@ fn interpolate_string(
     string: &str,
     arguments: &BTreeMap<ndc_models::ArgumentName, Bson>,
 ) -> Result<Bson> {
-    let parts = parse_native_mutation(string);
-    if parts.len() == 1 {
-        let mut parts = parts;
-        match parts.remove(0) {
-            NativeMutationPart::Text(string) => Ok(Bson::String(string)),
-            NativeMutationPart::Parameter(param) => resolve_argument(&param, arguments),
+    interpolate_parts(&parse_template(string), arguments)
+}
+
+fn interpolate_parts(
+    parts: &[TemplatePart],
+    arguments: &BTreeMap<ndc_models::ArgumentName, Bson>,
+) -> Result<Bson> {
+    match parts {
+        [TemplatePart::Text(string)] => Ok(Bson::String(string.clone())),
+        [TemplatePart::Parameter(param)] => resolve_argument(param, arguments),
+        parts => {
+            let interpolated_parts: Vec<String> = parts
+                .iter()
+                .map(|part| match part {
+                    TemplatePart::Text(string) => Ok(string.clone()),
+                    TemplatePart::Parameter(param) => {
+                        let argument_value = resolve_argument(param, arguments)?;
+                        match argument_value {
+                            Bson::String(string) => Ok(string),
+                            _ => Err(ProcedureError::NonStringInStringContext(param.clone())),
+                        }
+                    }
+                })
+                .try_collect()?;
+            Ok(Bson::String(interpolated_parts.join("")))
         }
-    } else {
-        let interpolated_parts: Vec<String> = parts
-            .into_iter()
-            .map(|part| match part {
-                NativeMutationPart::Text(string) => Ok(string),
-                NativeMutationPart::Parameter(param) => {
-                    let argument_value = resolve_argument(&param, arguments)?;
-                    match argument_value {
-                        Bson::String(string) => Ok(string),
-                        _ => Err(ProcedureError::NonStringInStringContext(param)),
+    }
+}
+
+/// Substitute arguments into a native query pipeline at placeholder positions that were recorded
+/// when configuration was loaded. The result is the same as applying [interpolated_command] to
+/// each stage, but strings that do not contain placeholders are not re-parsed.
+pub fn interpolate_placeholders(
+    pipeline: &mut [bson::Document],
+    placeholders: &[Placeholder],
+    arguments: &BTreeMap<ndc_models::ArgumentName, Bson>,
+) -> Result<()> {
+    for placeholder in placeholders {
+        let Some(stage) = pipeline.get_mut(placeholder.stage) else {
+            continue;
+        };
+        match &placeholder.target {
+            PlaceholderTarget::Value(parts) => {
+                if let Some(value) = value_at_path(stage, &placeholder.path) {
+                    *value = interpolate_parts(parts, arguments)?;
+                }
+            }
+            PlaceholderTarget::Key { key, parts } => {
+                let document = if placeholder.path.is_empty() {
+                    Some(stage)
+                } else {
+                    match value_at_path(stage, &placeholder.path) {
+                        Some(Bson::Document(document)) => Some(document),
+                        _ => None,
                     }
+                };
+                if let Some(document) = document {
+                    let interpolated_key = match interpolate_parts(parts, arguments)? {
+                        Bson::String(string_key) => Ok(string_key),
+                        interpolated_key => Err(ProcedureError::NonStringKey(interpolated_key)),
+                    }?;
+                    rename_key(document, key, interpolated_key);
                 }
-            })
-            .try_collect()?;
-        Ok(Bson::String(interpolated_parts.join("")))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn value_at_path<'a>(
+    document: &'a mut bson::Document,
+    path: &[PathElement],
+) -> Option<&'a mut Bson> {
+    let (first, rest) = path.split_first()?;
+    let PathElement::Key(key) = first else {
+        return None;
+    };
+    let mut value = document.get_mut(key)?;
+    for element in rest {
+        value = match (element, value) {
+            (PathElement::Key(key), Bson::Document(document)) => document.get_mut(key)?,
+            (PathElement::Index(index), Bson::Array(values)) => values.get_mut(*index)?,
+            _ => return None,
+        };
     }
+    Some(value)
+}
+
+// Replaces a key while preserving the order of document fields
+fn rename_key(document: &mut bson::Document, key: &str, new_key: String) {
+    *document = std::mem::take(document)
+        .into_iter()
+        .map(|(k, v)| {
+            if k == key {
+                (new_key.clone(), v)
+            } else {
+                (k, v)
+            }
+        })
+        .collect();
 }
 
 fn resolve_argument(
@@ -113,41 +193,10 @@ fn resolve_argument(
     Ok(argument.clone())
 }
 
-/// A part of a Native Mutation command text, either raw text or a parameter.
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum NativeMutationPart {
-    /// A raw text part
-    Text(String),
-    /// A parameter
-    Parameter(ndc_models::ArgumentName),
-}
-
-/// Parse a string or key in a native procedure into parts where variables have the syntax
-/// `{{<variable>}}`.
-fn parse_native_mutation(string: &str) -> Vec<NativeMutationPart> {
-    let vec: Vec<Vec<NativeMutationPart>> = string
-        .split("{{")
-        .filter(|part| !part.is_empty())
-        .map(|part| match part.split_once("}}") {
-            None => vec![NativeMutationPart::Text(part.to_string())],
-            Some((var, text)) => {
-                if text.is_empty() {
-                    vec![NativeMutationPart::Parameter(var.trim().into())]
-                } else {
-                    vec![
-                        NativeMutationPart::Parameter(var.trim().into()),
-                        NativeMutationPart::Text(text.to_string()),
-                    ]
-                }
-            }
-        })
-        .collect();
-    vec.concat()
-}
-
 #[cfg(test)]
 mod tests {
     use configuration::{native_mutation::NativeMutation, MongoScalarType};
+    use itertools::Itertools as _;
     use mongodb::bson::doc;
     use mongodb_support::BsonScalarType as S;
     use ndc_models::Argument;
@@ -185,6 +234,8 @@ mod tests {
             },
             selection_criteria: Default::default(),
             description: Default::default(),
+            version_field: None,
+            write_rules: Default::default(),
         };
 
         let input_arguments = [
@@ -246,6 +297,8 @@ mod tests {
             },
             selection_criteria: Default::default(),
             description: Default::default(),
+            version_field: None,
+            write_rules: Default::default(),
         };
 
         let input_arguments = [(
@@ -306,6 +359,8 @@ mod tests {
             },
             selection_criteria: Default::default(),
             description: Default::default(),
+            version_field: None,
+            write_rules: Default::default(),
         };
 
         let input_arguments = [
@@ -337,4 +392,36 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn patches_placeholders_to_match_full_interpolation() -> anyhow::Result<()> {
+        let pipeline = vec![
+            doc! { "$match": { "year": "{{ year }}", "title": { "$regex": "^{{prefix}}" } } },
+            doc! {
+                "$project": {
+                    "{{field}}": 1,
+                    "labels": ["{{prefix}}-{{field}}", "constant"],
+                    "nested": { "{{field}}": "{{year}}" },
+                },
+            },
+        ];
+        let arguments = [
+            ("year".into(), bson::Bson::Int32(1999)),
+            ("prefix".into(), bson::Bson::String("The".into())),
+            ("field".into(), bson::Bson::String("title".into())),
+        ]
+        .into();
+
+        let expected: Vec<bson::Document> = pipeline
+            .iter()
+            .map(|stage| interpolated_command(stage, &arguments))
+            .try_collect()?;
+
+        let mut patched = pipeline.clone();
+        let placeholders = configuration::placeholders::find_placeholders(&pipeline);
+        interpolate_placeholders(&mut patched, &placeholders, &arguments)?;
+
+        assert_eq!(patched, expected);
+        Ok(())
+    }
 }
@@ -1,10 +1,19 @@
+mod audit;
 mod error;
+mod idempotency;
 mod interpolated_command;
+mod version_check;
+mod write_errors;
+mod write_rules;
 
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 
-use configuration::native_mutation::NativeMutation;
+use configuration::{
+    native_mutation::NativeMutation,
+    system_collections::{command_namespaces, Namespace},
+    ArgumentWriteRules, ConfigurationAuditOptions, ConfigurationIdempotencyOptions,
+};
 use mongodb::options::SelectionCriteria;
 use mongodb::{bson, Database};
 use ndc_models::Argument;
@@ -13,24 +22,37 @@ use crate::mongo_query_plan::Type;
 use crate::query::arguments::resolve_arguments;
 
 pub use self::error::ProcedureError;
-pub use self::interpolated_command::interpolated_command;
+pub use self::idempotency::{create_ttl_index, run_with_idempotency_key};
+pub use self::interpolated_command::{interpolate_placeholders, interpolated_command};
+pub use self::write_errors::{
+    OperationResult, OperationStatus, PartialWriteFailure, WriteErrorDetail,
+};
+pub use self::write_rules::WriteRuleViolation;
 
 /// Encapsulates running arbitrary mongodb commands with interpolated arguments
 #[derive(Clone, Debug)]
 pub struct Procedure<'a> {
+    allow_system_collections: bool,
     arguments: BTreeMap<ndc_models::ArgumentName, serde_json::Value>,
     command: Cow<'a, bson::Document>,
     parameters: Cow<'a, BTreeMap<ndc_models::ArgumentName, Type>>,
     result_type: Type,
     selection_criteria: Option<Cow<'a, SelectionCriteria>>,
+    version_field: Option<ndc_models::FieldName>,
+    write_rules: Cow<'a, ArgumentWriteRules>,
 }
 
 impl<'a> Procedure<'a> {
+    /// Arguments may name collections, so unless `allow_system_collections` is set the
+    /// interpolated command is checked for references to system collections and databases before
+    /// it runs.
     pub fn from_native_mutation(
         native_mutation: &'a NativeMutation,
         arguments: BTreeMap<ndc_models::ArgumentName, serde_json::Value>,
+        allow_system_collections: bool,
     ) -> Self {
         Procedure {
+            allow_system_collections,
             arguments,
             command: Cow::Borrowed(&native_mutation.command),
             parameters: Cow::Borrowed(&native_mutation.arguments),
@@ -39,33 +61,212 @@ impl<'a> Procedure<'a> {
                 .selection_criteria
                 .as_ref()
                 .map(Cow::Borrowed),
+            version_field: native_mutation.version_field.clone(),
+            write_rules: Cow::Borrowed(&native_mutation.write_rules),
         }
     }
 
+    pub fn result_type(&self) -> &Type {
+        &self.result_type
+    }
+
+    /// Removes the idempotency key argument, if one was given, so that it is not interpolated
+    /// into the command
+    pub fn take_idempotency_key(
+        &mut self,
+        options: &ConfigurationIdempotencyOptions,
+    ) -> Result<Option<String>, ProcedureError> {
+        self::idempotency::take_idempotency_key(options, &mut self.arguments)
+    }
+
+    /// Hash of the arguments that is recorded with an idempotency key. Call this after
+    /// [Procedure::take_idempotency_key] so that the key is not part of the hash.
+    pub fn arguments_hash(&self) -> String {
+        self::idempotency::arguments_hash(&self.arguments)
+    }
+
     pub async fn execute(
         self,
         database: Database,
     ) -> Result<(bson::Document, Type), ProcedureError> {
         let selection_criteria = self.selection_criteria.map(Cow::into_owned);
-        let command = interpolate(&self.parameters, self.arguments, &self.command)?;
-        let result = database.run_command(command, selection_criteria).await?;
+        let command = interpolate(
+            self.allow_system_collections,
+            &self.write_rules,
+            &self.parameters,
+            self.arguments,
+            &self.command,
+        )?;
+        let result = database
+            .run_command(command.clone(), selection_criteria)
+            .await?;
+        self::write_errors::check_write_result(&command, &result, false)?;
+        if let Some(version_field) = &self.version_field {
+            self::version_check::check_version(&database, None, version_field, &command, &result)
+                .await?;
+        }
+        Ok((result, self.result_type))
+    }
+
+    /// Like [Procedure::execute], but records the mutation in the configured audit collection in
+    /// the same transaction.
+    pub async fn execute_with_audit(
+        self,
+        database: Database,
+        procedure_name: &ndc_models::ProcedureName,
+        audit: &ConfigurationAuditOptions,
+    ) -> Result<(bson::Document, Type), ProcedureError> {
+        let selection_criteria = self.selection_criteria.map(Cow::into_owned);
+        let command = interpolate(
+            self.allow_system_collections,
+            &self.write_rules,
+            &self.parameters,
+            self.arguments,
+            &self.command,
+        )?;
+        let result = self::audit::run_audited_command(
+            &database,
+            audit,
+            procedure_name,
+            self.version_field.as_ref(),
+            command,
+            selection_criteria,
+        )
+        .await?;
         Ok((result, self.result_type))
     }
 
     pub fn interpolated_command(self) -> Result<bson::Document, ProcedureError> {
-        interpolate(&self.parameters, self.arguments, &self.command)
+        interpolate(
+            self.allow_system_collections,
+            &self.write_rules,
+            &self.parameters,
+            self.arguments,
+            &self.command,
+        )
     }
 }
 
 fn interpolate(
+    allow_system_collections: bool,
+    write_rules: &ArgumentWriteRules,
     parameters: &BTreeMap<ndc_models::ArgumentName, Type>,
-    arguments: BTreeMap<ndc_models::ArgumentName, serde_json::Value>,
+    mut arguments: BTreeMap<ndc_models::ArgumentName, serde_json::Value>,
     command: &bson::Document,
 ) -> Result<bson::Document, ProcedureError> {
+    let violations = self::write_rules::apply_write_rules(write_rules, parameters, &mut arguments);
+    if !violations.is_empty() {
+        return Err(ProcedureError::InvalidArguments(violations));
+    }
     let arguments = arguments
         .into_iter()
         .map(|(name, value)| (name, Argument::Literal { value }))
         .collect();
     let bson_arguments = resolve_arguments(parameters, arguments)?;
-    interpolated_command(command, &bson_arguments)
+    let command = interpolated_command(command, &bson_arguments)?;
+    if !allow_system_collections {
+        if let Some(namespace) = command_namespaces(&command)
+            .into_iter()
+            .find(Namespace::is_system)
+        {
+            return Err(ProcedureError::SystemCollection(
+                namespace.name().to_owned(),
+            ));
+        }
+    }
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use configuration::{native_mutation::NativeMutation, MongoScalarType};
+    use mongodb::bson::doc;
+    use mongodb_support::BsonScalarType as S;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::mongo_query_plan::Type;
+
+    use super::{Procedure, ProcedureError};
+
+    fn update_mutation() -> NativeMutation {
+        NativeMutation {
+            result_type: Type::Scalar(MongoScalarType::Bson(S::Int)),
+            arguments: [
+                (
+                    "collection".into(),
+                    Type::Scalar(MongoScalarType::Bson(S::String)),
+                ),
+                (
+                    "name".into(),
+                    Type::Scalar(MongoScalarType::Bson(S::String)),
+                ),
+            ]
+            .into(),
+            command: doc! {
+                "update": "{{ collection }}",
+                "updates": [{ "q": {}, "u": { "$set": { "name": "{{ name }}" } } }],
+            },
+            selection_criteria: Default::default(),
+            description: Default::default(),
+            version_field: None,
+            write_rules: Default::default(),
+        }
+    }
+
+    #[test]
+    fn rejects_arguments_that_name_system_collections() -> anyhow::Result<()> {
+        let native_mutation = update_mutation();
+        let arguments = |collection: &str| -> BTreeMap<_, _> {
+            [
+                ("collection".into(), json!(collection)),
+                ("name".into(), json!("Ada")),
+            ]
+            .into()
+        };
+
+        let result =
+            Procedure::from_native_mutation(&native_mutation, arguments("system.users"), false)
+                .interpolated_command();
+        assert!(
+            matches!(result, Err(ProcedureError::SystemCollection(ref name)) if name == "system.users"),
+            "expected a system collection error, got {result:?}"
+        );
+
+        let command =
+            Procedure::from_native_mutation(&native_mutation, arguments("system.users"), true)
+                .interpolated_command()?;
+        assert_eq!(command.get_str("update")?, "system.users");
+
+        let command = Procedure::from_native_mutation(&native_mutation, arguments("users"), false)
+            .interpolated_command()?;
+        assert_eq!(command.get_str("update")?, "users");
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_pipeline_arguments_that_write_to_system_collections() {
+        let native_mutation = NativeMutation {
+            arguments: [(
+                "target".into(),
+                Type::Scalar(MongoScalarType::Bson(S::String)),
+            )]
+            .into(),
+            command: doc! {
+                "aggregate": "users",
+                "pipeline": [{ "$merge": { "into": "{{ target }}" } }],
+                "cursor": {},
+            },
+            ..update_mutation()
+        };
+        let result = Procedure::from_native_mutation(
+            &native_mutation,
+            [("target".into(), json!("system.js"))].into(),
+            false,
+        )
+        .interpolated_command();
+        assert!(matches!(result, Err(ProcedureError::SystemCollection(_))));
+    }
 }
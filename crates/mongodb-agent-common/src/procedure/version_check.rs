@@ -0,0 +1,130 @@
+//! Detects failed version checks in native mutations against collections with a `versionField`.
+//! See `configuration::versioning` for how commands are rewritten to check the version.
+
+use mongodb::{
+    bson::{Bson, Document},
+    options::CountOptions,
+    ClientSession, Database,
+};
+
+use super::ProcedureError;
+
+/// A command that checks a version field matches no document if the expected version is stale,
+/// but also if there is no document to update. When the command matched nothing the collection is
+/// read again without the version condition to tell the two cases apart. In an audited mutation
+/// this runs in the mutation's transaction so that a conflict aborts it.
+pub async fn check_version(
+    database: &Database,
+    session: Option<&mut ClientSession>,
+    version_field: &ndc_models::FieldName,
+    command: &Document,
+    result: &Document,
+) -> Result<(), ProcedureError> {
+    if !matched_nothing(command, result) {
+        return Ok(());
+    }
+    let Some((collection, filter)) = filter_without_version_check(command, version_field) else {
+        return Err(ProcedureError::VersionConflict);
+    };
+    let collection = database.collection::<Document>(&collection);
+    let options = CountOptions::builder().limit(1).build();
+    let count = match session {
+        Some(session) => {
+            collection
+                .count_documents_with_session(filter, options, session)
+                .await?
+        }
+        None => collection.count_documents(filter, options).await?,
+    };
+    if count > 0 {
+        Err(ProcedureError::VersionConflict)
+    } else {
+        Err(ProcedureError::DocumentNotFound)
+    }
+}
+
+/// Configuration validation allows only one statement in a version checked `update` command, so
+/// the total count of matched documents tells whether the version check failed.
+fn matched_nothing(command: &Document, result: &Document) -> bool {
+    match command.keys().next().map(String::as_str) {
+        Some("update") => matches!(result.get("n"), Some(Bson::Int32(0) | Bson::Int64(0))),
+        Some("findAndModify" | "findandmodify") => {
+            matches!(result.get("value"), None | Some(Bson::Null))
+        }
+        _ => false,
+    }
+}
+
+/// Reads the collection name and the filter of an `update` or `findAndModify` command, without
+/// the version condition that was added to it
+fn filter_without_version_check(
+    command: &Document,
+    version_field: &ndc_models::FieldName,
+) -> Option<(String, Document)> {
+    let (_, Bson::String(collection)) = command.iter().next()? else {
+        return None;
+    };
+    let filter = match command.get_array("updates") {
+        Ok(statements) => statements
+            .first()?
+            .as_document()?
+            .get_document("q")
+            .ok()?
+            .clone(),
+        Err(_) => command.get_document("query").cloned().unwrap_or_default(),
+    };
+    let filter = remove_version_check(filter, version_field);
+    Some((collection.clone(), filter))
+}
+
+fn remove_version_check(mut filter: Document, version_field: &ndc_models::FieldName) -> Document {
+    let field = version_field.as_str();
+    // If the filter already referenced the version field the check was added with `$and`
+    if filter.len() == 1 {
+        if let Ok(conditions) = filter.get_array("$and") {
+            if let [Bson::Document(original), Bson::Document(check)] = conditions.as_slice() {
+                if check.len() == 1 && check.contains_key(field) {
+                    return original.clone();
+                }
+            }
+        }
+    }
+    filter.remove(field);
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    use super::filter_without_version_check;
+
+    #[test]
+    fn removes_version_check_from_filters() {
+        let update = doc! {
+            "update": "accounts",
+            "updates": [{
+                "q": { "_id": 1, "version": 3 },
+                "u": { "$set": { "balance": 10 }, "$inc": { "version": 1 } },
+            }],
+        };
+        assert_eq!(
+            filter_without_version_check(&update, &"version".into()),
+            Some(("accounts".to_owned(), doc! { "_id": 1 }))
+        );
+
+        let find_and_modify = doc! {
+            "findAndModify": "accounts",
+            "query": { "$and": [{ "_id": 1, "version": { "$gte": 2 } }, { "version": 3 }] },
+            "update": { "$set": { "balance": 10 }, "$inc": { "version": 1 } },
+        };
+        assert_eq!(
+            filter_without_version_check(&find_and_modify, &"version".into()),
+            Some((
+                "accounts".to_owned(),
+                doc! { "_id": 1, "version": { "$gte": 2 } }
+            ))
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Write commands with several statements, such as `insert`, `update`, and `delete`, report
+//! failures of individual statements in `writeErrors`, and replication failures in
+//! `writeConcernError`, while the command itself still succeeds. These are turned into an error
+//! that lists the outcome of each statement so that callers can retry only the statements that
+//! did not apply.
+
+use std::fmt::{self, Display};
+
+use mongodb::bson::{Bson, Document};
+use serde::Serialize;
+
+use super::ProcedureError;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PartialWriteFailure {
+    /// Outcome of each statement in the command, in statement order
+    pub operations: Vec<OperationResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_concern_error: Option<WriteErrorDetail>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationResult {
+    pub index: usize,
+    pub status: OperationStatus,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub error: Option<WriteErrorDetail>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OperationStatus {
+    Applied,
+    Failed,
+    /// Ordered commands stop at the first failed statement
+    NotAttempted,
+    /// The statement succeeded, but the transaction that it ran in was aborted
+    RolledBack,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WriteErrorDetail {
+    pub code: i32,
+    pub message: String,
+}
+
+impl Display for PartialWriteFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed = self
+            .operations
+            .iter()
+            .filter(|operation| operation.status == OperationStatus::Failed)
+            .count();
+        if failed > 0 {
+            write!(
+                f,
+                "{failed} of {} write operations failed",
+                self.operations.len()
+            )?;
+            if let Some(first) = self.operations.iter().find_map(|op| op.error.as_ref()) {
+                write!(f, "; first error: {}", first.message)?;
+            }
+        }
+        if let Some(write_concern_error) = &self.write_concern_error {
+            if failed > 0 {
+                write!(f, "; ")?;
+            }
+            write!(
+                f,
+                "write concern was not satisfied: {}",
+                write_concern_error.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Fails if the result of the given command reports write errors or a write concern error.
+/// Statements in a transaction are reported as rolled back because the failure aborts the
+/// transaction.
+pub fn check_write_result(
+    command: &Document,
+    result: &Document,
+    in_transaction: bool,
+) -> Result<(), ProcedureError> {
+    let write_errors: Vec<(usize, WriteErrorDetail)> = match result.get_array("writeErrors") {
+        Ok(errors) => errors
+            .iter()
+            .filter_map(Bson::as_document)
+            .filter_map(|error| Some((read_index(error)?, read_detail(error))))
+            .collect(),
+        Err(_) => vec![],
+    };
+    let write_concern_error = result
+        .get_document("writeConcernError")
+        .ok()
+        .map(read_detail);
+    if write_errors.is_empty() && write_concern_error.is_none() {
+        return Ok(());
+    }
+
+    let statement_count = statement_count(command).max(
+        write_errors
+            .iter()
+            .map(|(index, _)| index + 1)
+            .max()
+            .unwrap_or(0),
+    );
+    let ordered = command.get_bool("ordered").unwrap_or(true);
+    let first_failure = write_errors.iter().map(|(index, _)| *index).min();
+
+    let operations = (0..statement_count)
+        .map(|index| {
+            let error = write_errors
+                .iter()
+                .find(|(error_index, _)| *error_index == index)
+                .map(|(_, detail)| detail.clone());
+            let status = match (&error, first_failure) {
+                (Some(_), _) => OperationStatus::Failed,
+                (None, Some(first)) if ordered && index > first => OperationStatus::NotAttempted,
+                (None, _) if in_transaction => OperationStatus::RolledBack,
+                (None, _) => OperationStatus::Applied,
+            };
+            OperationResult {
+                index,
+                status,
+                error,
+            }
+        })
+        .collect();
+
+    Err(ProcedureError::PartialWrite(PartialWriteFailure {
+        operations,
+        write_concern_error,
+    }))
+}
+
+/// Number of statements in an `insert`, `update`, or `delete` command. Other commands have one
+/// statement.
+fn statement_count(command: &Document) -> usize {
+    let statements_field = match command.keys().next().map(String::as_str) {
+        Some("insert") => "documents",
+        Some("update") => "updates",
+        Some("delete") => "deletes",
+        _ => return 1,
+    };
+    command
+        .get_array(statements_field)
+        .map_or(1, |statements| statements.len())
+}
+
+fn read_index(error: &Document) -> Option<usize> {
+    match error.get("index")? {
+        Bson::Int32(n) => usize::try_from(*n).ok(),
+        Bson::Int64(n) => usize::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+fn read_detail(error: &Document) -> WriteErrorDetail {
+    WriteErrorDetail {
+        code: error.get_i32("code").unwrap_or_default(),
+        message: error.get_str("errmsg").unwrap_or_default().to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::check_write_result;
+    use crate::procedure::ProcedureError;
+
+    #[test]
+    fn reports_outcome_of_each_statement_of_partially_failed_write() -> anyhow::Result<()> {
+        let command = doc! {
+            "insert": "users",
+            "documents": [{ "_id": 1 }, { "_id": 2 }, { "_id": 3 }],
+        };
+        let result = doc! {
+            "ok": 1,
+            "n": 1,
+            "writeErrors": [{
+                "index": 1,
+                "code": 11000,
+                "errmsg": "E11000 duplicate key error",
+            }],
+            "writeConcernError": { "code": 64, "errmsg": "waiting for replication timed out" },
+        };
+        let Err(ProcedureError::PartialWrite(failure)) =
+            check_write_result(&command, &result, false)
+        else {
+            panic!("expected a partial write failure");
+        };
+        assert_eq!(
+            serde_json::to_value(&failure)?,
+            json!({
+                "operations": [
+                    { "index": 0, "status": "applied" },
+                    { "index": 1, "status": "failed", "code": 11000, "message": "E11000 duplicate key error" },
+                    { "index": 2, "status": "notAttempted" },
+                ],
+                "writeConcernError": { "code": 64, "message": "waiting for replication timed out" },
+            })
+        );
+        assert_eq!(
+            failure.to_string(),
+            "1 of 3 write operations failed; first error: E11000 duplicate key error; write concern was not satisfied: waiting for replication timed out"
+        );
+
+        assert!(check_write_result(&command, &doc! { "ok": 1, "n": 3 }, false).is_ok());
+        Ok(())
+    }
+}
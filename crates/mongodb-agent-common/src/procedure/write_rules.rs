@@ -0,0 +1,295 @@
+//! Applies write rules to native mutation arguments before they are substituted into the command.
+//! See [configuration::schema::ObjectField::write_rules].
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use configuration::{
+    schema::{WriteNormalization, WriteRules},
+    ArgumentWriteRules,
+};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+
+use crate::mongo_query_plan::Type;
+
+/// A value in the arguments of a mutation request that does not satisfy a write rule
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct WriteRuleViolation {
+    /// Location of the value, starting with the argument name, such as `artist.name` or
+    /// `tracks[2].title`
+    pub path: String,
+    /// Name of the rule in configuration, such as `pattern` or `maxLength`
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl Display for WriteRuleViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Normalizes argument values in place, and lists values that break validation rules. Arguments
+/// and object fields are checked against their declared types, so rules for fields of an object
+/// type apply to objects of that type at any depth.
+pub fn apply_write_rules(
+    rules: &ArgumentWriteRules,
+    parameters: &BTreeMap<ndc_models::ArgumentName, Type>,
+    arguments: &mut BTreeMap<ndc_models::ArgumentName, Value>,
+) -> Vec<WriteRuleViolation> {
+    let mut violations = vec![];
+    if rules.is_empty() {
+        return violations;
+    }
+    for (name, value) in arguments.iter_mut() {
+        if let Some(parameter_type) = parameters.get(name) {
+            apply_to_value(
+                rules,
+                &mut violations,
+                name.to_string(),
+                rules.arguments.get(name),
+                parameter_type,
+                value,
+            );
+        }
+    }
+    violations
+}
+
+fn apply_to_value(
+    rules: &ArgumentWriteRules,
+    violations: &mut Vec<WriteRuleViolation>,
+    path: String,
+    value_rules: Option<&WriteRules>,
+    value_type: &Type,
+    value: &mut Value,
+) {
+    if value.is_null() {
+        return;
+    }
+    if let Some(value_rules) = value_rules {
+        normalize(&value_rules.normalize, value);
+        violations.extend(validate(&path, value_rules, value));
+    }
+    match (value_type, value) {
+        (Type::Nullable(underlying_type), value) => {
+            apply_to_value(rules, violations, path, None, underlying_type, value)
+        }
+        (Type::ArrayOf(element_type), Value::Array(elements)) => {
+            for (index, element) in elements.iter_mut().enumerate() {
+                apply_to_value(
+                    rules,
+                    violations,
+                    format!("{path}[{index}]"),
+                    None,
+                    element_type,
+                    element,
+                )
+            }
+        }
+        (Type::Object(object_type), Value::Object(fields)) => {
+            let field_rules = object_type
+                .name
+                .as_ref()
+                .and_then(|name| rules.object_fields.get(name));
+            for (field_name, field_type) in &object_type.fields {
+                if let Some(field_value) = fields.get_mut(field_name.as_str()) {
+                    apply_to_value(
+                        rules,
+                        violations,
+                        format!("{path}.{field_name}"),
+                        field_rules.and_then(|field_rules| field_rules.get(field_name)),
+                        field_type,
+                        field_value,
+                    )
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn normalize(normalizations: &[WriteNormalization], value: &mut Value) {
+    let Value::String(string) = value else {
+        return;
+    };
+    for normalization in normalizations {
+        *string = match normalization {
+            WriteNormalization::Trim => string.trim().to_owned(),
+            WriteNormalization::Lowercase => string.to_lowercase(),
+            WriteNormalization::Uppercase => string.to_uppercase(),
+            WriteNormalization::Sha256 => format!("{:x}", Sha256::digest(string.as_bytes())),
+        };
+    }
+}
+
+fn validate(path: &str, rules: &WriteRules, value: &Value) -> Vec<WriteRuleViolation> {
+    let mut violations = vec![];
+    let mut violation = |rule, message| {
+        violations.push(WriteRuleViolation {
+            path: path.to_owned(),
+            rule,
+            message,
+        })
+    };
+
+    if let (Some(pattern), Value::String(string)) = (&rules.pattern, value) {
+        // Patterns are checked when configuration is loaded
+        let matches = Regex::new(pattern).map_or(false, |regex| regex.is_match(string));
+        if !matches {
+            violation("pattern", format!("must match the pattern {pattern}"));
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = &rules.minimum {
+            if minimum.as_f64().is_some_and(|minimum| number < minimum) {
+                violation("minimum", format!("must be at least {minimum}"));
+            }
+        }
+        if let Some(maximum) = &rules.maximum {
+            if maximum.as_f64().is_some_and(|maximum| number > maximum) {
+                violation("maximum", format!("must be at most {maximum}"));
+            }
+        }
+    }
+
+    let length = match value {
+        Value::String(string) => Some(string.chars().count() as u64),
+        Value::Array(elements) => Some(elements.len() as u64),
+        _ => None,
+    };
+    if let Some(length) = length {
+        if let Some(min_length) = rules.min_length.filter(|min| length < *min) {
+            violation(
+                "minLength",
+                format!("must have a length of at least {min_length}"),
+            );
+        }
+        if let Some(max_length) = rules.max_length.filter(|max| length > *max) {
+            violation(
+                "maxLength",
+                format!("must have a length of at most {max_length}"),
+            );
+        }
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{WriteNormalization, WriteRules},
+        ArgumentWriteRules, MongoScalarType,
+    };
+    use mongodb_support::BsonScalarType as S;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::mongo_query_plan::{ObjectType, Type};
+
+    use super::{apply_write_rules, WriteRuleViolation};
+
+    #[test]
+    fn normalizes_and_validates_arguments_and_nested_fields() -> anyhow::Result<()> {
+        let string = || Type::Scalar(MongoScalarType::Bson(S::String));
+        let artist_type = Type::Object(ObjectType {
+            name: Some("Artist".into()),
+            fields: [
+                ("email".into(), string()),
+                ("age".into(), Type::Scalar(MongoScalarType::Bson(S::Int))),
+            ]
+            .into(),
+        });
+        let parameters = [
+            ("code".into(), string()),
+            (
+                "artists".into(),
+                Type::ArrayOf(Box::new(Type::Nullable(Box::new(artist_type)))),
+            ),
+        ]
+        .into();
+        let rules = ArgumentWriteRules {
+            arguments: [(
+                "code".into(),
+                WriteRules {
+                    normalize: vec![WriteNormalization::Trim, WriteNormalization::Uppercase],
+                    pattern: Some("^[A-Z]{3}$".to_owned()),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_fields: [(
+                "Artist".into(),
+                [
+                    (
+                        "email".into(),
+                        WriteRules {
+                            normalize: vec![WriteNormalization::Lowercase],
+                            max_length: Some(16),
+                            ..Default::default()
+                        },
+                    ),
+                    (
+                        "age".into(),
+                        WriteRules {
+                            minimum: Some(0.into()),
+                            ..Default::default()
+                        },
+                    ),
+                ]
+                .into(),
+            )]
+            .into(),
+        };
+
+        let mut arguments = [
+            ("code".into(), json!("  abc ")),
+            (
+                "artists".into(),
+                json!([
+                    { "email": "Ada@Example.com", "age": 36 },
+                    null,
+                    { "email": "Charles.Babbage@Example.com", "age": -1 },
+                ]),
+            ),
+        ]
+        .into();
+        let violations = apply_write_rules(&rules, &parameters, &mut arguments);
+
+        assert_eq!(
+            arguments,
+            [
+                ("code".into(), json!("ABC")),
+                (
+                    "artists".into(),
+                    json!([
+                        { "email": "ada@example.com", "age": 36 },
+                        null,
+                        { "email": "charles.babbage@example.com", "age": -1 },
+                    ])
+                ),
+            ]
+            .into()
+        );
+        assert_eq!(
+            violations,
+            vec![
+                WriteRuleViolation {
+                    path: "artists[2].age".to_owned(),
+                    rule: "minimum",
+                    message: "must be at least 0".to_owned(),
+                },
+                WriteRuleViolation {
+                    path: "artists[2].email".to_owned(),
+                    rule: "maxLength",
+                    message: "must have a length of at most 16".to_owned(),
+                },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -23,6 +23,18 @@ pub enum ArgumentError {
 
     #[error("missing variables or arguments: {}", .0.join(", "))]
     Missing(Vec<ndc_models::ArgumentName>),
+
+    #[error("argument, {0}, must be given as a literal value, not as a variable")]
+    NotLiteral(ndc_models::ArgumentName),
+
+    #[error("argument, {0}, is not accepted for the target of a relationship")]
+    NotOnRelationship(ndc_models::ArgumentName),
+
+    #[error("invalid value for argument, {name}: {message}")]
+    InvalidValue {
+        name: ndc_models::ArgumentName,
+        message: String,
+    },
 }
 
 /// Translate arguments to queries or native queries to BSON according to declared parameter types.
@@ -0,0 +1,342 @@
+//! Micro-batching for query requests. When the engine resolves remote joins it may send many
+//! query requests with variable sets in quick succession against the same collection that differ
+//! only in their variable values. With batching enabled those requests are coalesced into one
+//! request that combines the variable sets of all of the original requests, which runs as
+//! a single MongoDB aggregation.
+//!
+//! Only requests that already use variables are batched. Rewriting literal predicate values as
+//! variables would change query semantics: literal comparisons are translated to match queries,
+//! while comparisons against variables are translated to aggregation expressions, which for
+//! example treat null and missing values differently.
+//!
+//! The first request to arrive with a given shape becomes the leader of a new batch. The leader
+//! waits up to the configured delay for other requests with the same shape to join, runs the
+//! batched query, and hands each follower its row set. If the batched query fails, or if the
+//! leader is cancelled, followers fall back to running their own requests individually.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use configuration::{ConfigurationQueryBatchingOptions, RowErrorPolicy};
+use mongodb::{bson::Bson, Database};
+use ndc_models::{QueryRequest, QueryResponse, RowSet};
+use ndc_query_plan::VariableSet;
+use tokio::sync::{oneshot, Notify};
+
+use crate::{
+    interface_types::MongoAgentError, mongo_query_plan::MongoConfiguration, state::ConnectorState,
+};
+
+use super::execute_unbatched;
+
+type Result<T> = std::result::Result<T, MongoAgentError>;
+
+/// Batches that are waiting for requests to join them, keyed by request shape
+#[derive(Debug, Default)]
+pub struct QueryBatcher {
+    pending: Mutex<HashMap<String, Arc<PendingBatch>>>,
+}
+
+#[derive(Debug, Default)]
+struct PendingBatch {
+    state: Mutex<BatchState>,
+
+    /// Signals the leader to stop waiting when the batch reaches the maximum size
+    full: Notify,
+}
+
+/// The first group of variable sets belongs to the leader; the remaining groups correspond, in
+/// order, to followers.
+#[derive(Debug, Default)]
+struct BatchState {
+    variable_sets: Vec<Vec<VariableSet>>,
+    followers: Vec<oneshot::Sender<Vec<RowSet>>>,
+}
+
+enum Role {
+    Leader(Arc<PendingBatch>),
+    Follower(oneshot::Receiver<Vec<RowSet>>),
+}
+
+impl QueryBatcher {
+    fn join(&self, key: &str, variable_sets: Vec<VariableSet>, max_batch_size: usize) -> Role {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(key).cloned() {
+            Some(batch) => {
+                let (sender, receiver) = oneshot::channel();
+                let mut state = batch.state.lock().unwrap();
+                state.variable_sets.push(variable_sets);
+                state.followers.push(sender);
+                if state.variable_sets.len() >= max_batch_size {
+                    pending.remove(key);
+                    batch.full.notify_one();
+                }
+                Role::Follower(receiver)
+            }
+            None => {
+                let batch = Arc::new(PendingBatch {
+                    state: Mutex::new(BatchState {
+                        variable_sets: vec![variable_sets],
+                        followers: vec![],
+                    }),
+                    full: Notify::new(),
+                });
+                if max_batch_size > 1 {
+                    pending.insert(key.to_owned(), batch.clone());
+                }
+                Role::Leader(batch)
+            }
+        }
+    }
+
+    /// Stops accepting requests into the given batch, and takes its contents.
+    fn close(&self, key: &str, batch: &Arc<PendingBatch>) -> BatchState {
+        let mut pending = self.pending.lock().unwrap();
+        if pending
+            .get(key)
+            .is_some_and(|pending_batch| Arc::ptr_eq(pending_batch, batch))
+        {
+            pending.remove(key);
+        }
+        let mut state = batch.state.lock().unwrap();
+        std::mem::take(&mut *state)
+    }
+}
+
+/// Closes the batch if the leader's request is cancelled before the batch runs so that followers
+/// do not wait indefinitely.
+struct BatchLeader<'a> {
+    batcher: &'a QueryBatcher,
+    key: &'a str,
+    batch: Arc<PendingBatch>,
+}
+
+impl BatchLeader<'_> {
+    fn close(&self) -> BatchState {
+        self.batcher.close(self.key, &self.batch)
+    }
+}
+
+impl Drop for BatchLeader<'_> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Runs a query request, possibly as part of a batch with other requests of the same shape.
+/// Requests that cannot be batched run immediately.
+pub async fn execute_batched(
+    state: &ConnectorState,
+    database: Database,
+    comment: Bson,
+    options: &ConfigurationQueryBatchingOptions,
+    config: &MongoConfiguration,
+    query_request: QueryRequest,
+) -> Result<Bytes> {
+    // Batched responses are split by parsing them as a `QueryResponse` which does not keep the
+    // `skippedRows` field that row sets get when rows are skipped
+    let skips_rows = config.serialization_options().row_errors == RowErrorPolicy::Skip;
+    let Some((template, variable_sets)) = batch_template(&query_request).filter(|_| !skips_rows)
+    else {
+        return execute_unbatched(database, comment, config, state, query_request).await;
+    };
+    // Requests for different tenant databases must not share a batch
+    let key = format!(
+        "{}:{}",
+        database.name(),
+        serde_json::to_string(&template).map_err(MongoAgentError::Serialization)?
+    );
+
+    match state
+        .query_batcher()
+        .join(&key, variable_sets, options.max_batch_size)
+    {
+        Role::Follower(receiver) => match receiver.await {
+            Ok(row_sets) => serialize_row_sets(row_sets),
+            Err(_) => execute_unbatched(database, comment, config, state, query_request).await,
+        },
+        Role::Leader(batch) => {
+            let leader = BatchLeader {
+                batcher: state.query_batcher(),
+                key: &key,
+                batch,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(options.max_delay_ms)) => (),
+                _ = leader.batch.full.notified() => (),
+            }
+            let BatchState {
+                variable_sets,
+                followers,
+            } = leader.close();
+            if followers.is_empty() {
+                return execute_unbatched(
+                    database.clone(),
+                    comment.clone(),
+                    config,
+                    state,
+                    query_request,
+                )
+                .await;
+            }
+
+            tracing::debug!(batch_size = variable_sets.len(), "executing batched query");
+            let group_sizes = variable_sets.iter().map(Vec::len).collect::<Vec<_>>();
+            let batched_request = QueryRequest {
+                variables: Some(variable_sets.into_iter().flatten().collect()),
+                ..template
+            };
+            // The batched request has many variable sets so it runs in a snapshot session if
+            // snapshot reads are enabled
+            let batched_response = execute_unbatched(
+                database.clone(),
+                comment.clone(),
+                config,
+                state,
+                batched_request,
+            )
+            .await
+            .and_then(|response| {
+                serde_json::from_slice::<QueryResponse>(&response)
+                    .map_err(MongoAgentError::Serialization)
+            });
+            match batched_response {
+                Ok(QueryResponse(row_sets)) if row_sets.len() == group_sizes.iter().sum() => {
+                    let mut row_sets = row_sets.into_iter();
+                    let mut groups = group_sizes
+                        .into_iter()
+                        .map(|size| row_sets.by_ref().take(size).collect::<Vec<_>>());
+                    let own_row_sets = groups.next().unwrap_or_default();
+                    for (follower, row_sets) in followers.into_iter().zip(groups) {
+                        // The follower's request may have been cancelled in which case there is
+                        // no one to receive its response.
+                        let _ = follower.send(row_sets);
+                    }
+                    serialize_row_sets(own_row_sets)
+                }
+                // Dropping the follower channels signals followers to run their own requests.
+                // Running requests individually also gives each request its own error response.
+                _ => {
+                    drop(followers);
+                    execute_unbatched(database, comment, config, state, query_request).await
+                }
+            }
+        }
+    }
+}
+
+fn serialize_row_sets(row_sets: Vec<RowSet>) -> Result<Bytes> {
+    let response =
+        serde_json::to_vec(&QueryResponse(row_sets)).map_err(MongoAgentError::Serialization)?;
+    Ok(response.into())
+}
+
+/// Splits a request into a template without variable sets, and the request's variable sets.
+/// Requests that produce identical templates can be batched together. Returns `None` if the
+/// request is not eligible for batching: requests without variable sets are not batched, and
+/// neither are requests with arguments since collection arguments like index hints do not apply
+/// to queries with variable sets.
+fn batch_template(request: &QueryRequest) -> Option<(QueryRequest, Vec<VariableSet>)> {
+    let variable_sets = request.variables.clone().filter(|sets| !sets.is_empty())?;
+    if !request.arguments.is_empty() {
+        return None;
+    }
+    let template = QueryRequest {
+        variables: None,
+        ..request.clone()
+    };
+    Some((template, variable_sets))
+}
+
+#[cfg(test)]
+mod tests {
+    use ndc_test_helpers::{binop, field, query, query_request, target, value, variable};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::{batch_template, QueryBatcher, Role};
+
+    #[test]
+    fn separates_variable_sets_from_batch_template() -> anyhow::Result<()> {
+        let request = query_request()
+            .collection("movies")
+            .query(query().fields([field!("title")]).predicate(binop(
+                "_eq",
+                target!("year"),
+                variable!(year),
+            )))
+            .variables([[("year", json!(1999))], [("year", json!(2000))]])
+            .into();
+
+        let (template, variable_sets) =
+            batch_template(&request).ok_or_else(|| anyhow::anyhow!("not batchable"))?;
+
+        let expected_template: ndc_models::QueryRequest = query_request()
+            .collection("movies")
+            .query(query().fields([field!("title")]).predicate(binop(
+                "_eq",
+                target!("year"),
+                variable!(year),
+            )))
+            .into();
+        assert_eq!(template, expected_template);
+        assert_eq!(
+            variable_sets,
+            vec![
+                [("year".into(), json!(1999))].into(),
+                [("year".into(), json!(2000))].into(),
+            ]
+        );
+        Ok(())
+    }
+
+    // Literal comparisons are translated to match queries while comparisons against variables
+    // are translated to aggregation expressions, so substituting variables for literals could
+    // change which rows a request returns.
+    #[test]
+    fn does_not_batch_requests_without_variables() -> anyhow::Result<()> {
+        let request = query_request()
+            .collection("movies")
+            .query(query().fields([field!("title")]).predicate(binop(
+                "_lt",
+                target!("year"),
+                value!(1999),
+            )))
+            .into();
+        assert_eq!(batch_template(&request), None);
+        Ok(())
+    }
+
+    #[test]
+    fn requests_join_existing_batch_until_it_is_full() -> anyhow::Result<()> {
+        let batcher = QueryBatcher::default();
+        let leader = batcher.join("key", Default::default(), 3);
+        assert!(matches!(leader, Role::Leader(_)));
+        assert!(matches!(
+            batcher.join("key", Default::default(), 3),
+            Role::Follower(_)
+        ));
+        assert!(matches!(
+            batcher.join("key", Default::default(), 3),
+            Role::Follower(_)
+        ));
+
+        // The batch is full so the next request starts a new batch
+        assert!(matches!(
+            batcher.join("key", Default::default(), 3),
+            Role::Leader(_)
+        ));
+
+        let Role::Leader(batch) = leader else {
+            unreachable!()
+        };
+        let state = batcher.close("key", &batch);
+        assert_eq!(state.variable_sets.len(), 3);
+        assert_eq!(state.followers.len(), 2);
+        Ok(())
+    }
+}
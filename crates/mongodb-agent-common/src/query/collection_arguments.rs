@@ -0,0 +1,471 @@
+use std::collections::BTreeMap;
+
+use configuration::collection_arguments::{
+    builtin_collection_parameters, HINT, INCLUDE_DELETED, LIMIT, READ_CONCERN,
+};
+use mongodb::{
+    bson::Bson,
+    options::{
+        AggregateOptions, Collation, CollationStrength, CountOptions, FindOptions, Hint,
+        ReadConcern,
+    },
+};
+use ndc_models::Argument;
+
+use crate::{
+    comparison_function::ComparisonFunction,
+    interface_types::MongoAgentError,
+    mongo_query_plan::{Expression, MongoConfiguration, Query, QueryPlan},
+};
+
+use super::{
+    arguments::{resolve_arguments, validate_no_excess_arguments, ArgumentError},
+    QueryTarget,
+};
+
+type Result<T> = std::result::Result<T, MongoAgentError>;
+
+/// Values for the built-in arguments that may be given for any regular collection. See
+/// [configuration::collection_arguments] for argument definitions.
+///
+/// Native queries declare their own arguments so built-in arguments do not apply to native query
+/// targets. Built-in arguments are only honored for the root collection of a request, so they are
+/// rejected when given for the target of a relationship.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CollectionArguments {
+    pub limit: Option<u32>,
+    pub read_concern: Option<ReadConcern>,
+
+    /// Either an index name or an index specification document
+    pub hint: Option<Bson>,
+
+    /// Include soft-deleted documents from collections that declare a soft delete field
+    pub include_deleted: bool,
+
+    /// Case-insensitive collation for queries that use `_ieq` when one is configured. This is not
+    /// a request argument, but it is passed to MongoDB as a command option like the others.
+    pub collation: Option<Collation>,
+
+    /// Cursor batch size for aggregate commands when one is configured. Like `collation` this is
+    /// not a request argument. It is set at execution time from the batch sizes learned by
+    /// previous queries, which are held in connector state.
+    pub batch_size: Option<u32>,
+}
+
+impl CollectionArguments {
+    pub fn for_request(config: &MongoConfiguration, query_plan: &QueryPlan) -> Result<Self> {
+        check_relationship_arguments(config, &query_plan.query)?;
+        for join in query_plan.unrelated_collections.values() {
+            check_relationship_arguments(config, &join.query)?;
+        }
+        let mut arguments = match QueryTarget::for_request(config, query_plan) {
+            QueryTarget::Collection(_) => Self::from_arguments(&query_plan.arguments)?,
+            QueryTarget::NativeQuery { .. } => Default::default(),
+        };
+        arguments.collation = case_insensitive_collation(config, query_plan);
+        Ok(arguments)
+    }
+
+    fn from_arguments(arguments: &BTreeMap<ndc_models::ArgumentName, Argument>) -> Result<Self> {
+        let parameters = builtin_collection_parameters()?;
+        validate_no_excess_arguments(&parameters, arguments)?;
+
+        // Built-in arguments are optional so we only resolve parameters for arguments that were
+        // given. Values are passed to MongoDB as command options, not as pipeline expressions, so
+        // they cannot reference request variables.
+        for (name, argument) in arguments {
+            if let Argument::Variable { .. } = argument {
+                Err(ArgumentError::NotLiteral(name.clone()))?
+            }
+        }
+        let given_parameters = parameters
+            .into_iter()
+            .filter(|(name, _)| arguments.contains_key(name))
+            .collect();
+        let mut resolved = resolve_arguments(&given_parameters, arguments.clone())?;
+
+        let mut take = |name: &str| {
+            let name = ndc_models::ArgumentName::from(name);
+            match resolved.remove(&name) {
+                Some(Bson::Null) | None => None,
+                Some(value) => Some((name, value)),
+            }
+        };
+
+        Ok(CollectionArguments {
+            limit: take(LIMIT).map(parse_limit).transpose()?,
+            read_concern: take(READ_CONCERN).map(parse_read_concern).transpose()?,
+            hint: take(HINT).map(parse_hint).transpose()?,
+            include_deleted: take(INCLUDE_DELETED)
+                .map(|(_, value)| value == Bson::Boolean(true))
+                .unwrap_or(false),
+            collation: None,
+            batch_size: None,
+        })
+    }
+
+    /// Applies the `limit` argument, if it was given, to the given query.
+    pub fn apply_limit(&self, query: &mut Query) {
+        if let Some(max) = self.limit {
+            query.limit = Some(query.limit.map_or(max, |limit| limit.min(max)));
+            query.aggregates_limit =
+                Some(query.aggregates_limit.map_or(max, |limit| limit.min(max)));
+        }
+    }
+
+    /// Options to pass to the MongoDB aggregate command. Index hints only make sense when the
+    /// pipeline runs directly against the target collection, so the caller indicates whether that
+    /// is the case.
+    pub fn aggregate_options(&self, runs_against_collection: bool) -> Option<AggregateOptions> {
+        let hint = self.index_hint().filter(|_| runs_against_collection);
+        if self.read_concern.is_none()
+            && hint.is_none()
+            && self.collation.is_none()
+            && self.batch_size.is_none()
+        {
+            return None;
+        }
+        Some(
+            AggregateOptions::builder()
+                .read_concern(self.read_concern.clone())
+                .hint(hint)
+                .collation(self.collation.clone())
+                .batch_size(self.batch_size)
+                .build(),
+        )
+    }
+
+    /// Options to pass to the MongoDB find command
+    pub fn find_options(&self) -> FindOptions {
+        FindOptions::builder()
+            .read_concern(self.read_concern.clone())
+            .hint(self.index_hint())
+            .collation(self.collation.clone())
+            .build()
+    }
+
+    /// Options to pass to the MongoDB countDocuments command
+    pub fn count_options(&self) -> CountOptions {
+        CountOptions::builder()
+            .read_concern(self.read_concern.clone())
+            .hint(self.index_hint())
+            .collation(self.collation.clone())
+            .build()
+    }
+
+    fn index_hint(&self) -> Option<Hint> {
+        match self.hint.clone()? {
+            Bson::Document(keys) => Some(Hint::Keys(keys)),
+            Bson::String(index_name) => Some(Hint::Name(index_name)),
+            _ => None,
+        }
+    }
+}
+
+/// Built-in arguments given for a relationship target would otherwise be silently ignored.
+/// Relationships to native queries are skipped because native queries may declare parameters with
+/// the same names.
+fn check_relationship_arguments(config: &MongoConfiguration, query: &Query) -> Result<()> {
+    for relationship in query.relationships.values() {
+        if !config
+            .native_queries()
+            .contains_key(&relationship.target_collection)
+        {
+            let builtin_argument = relationship
+                .arguments
+                .keys()
+                .find(|name| [LIMIT, READ_CONCERN, HINT, INCLUDE_DELETED].contains(&name.as_str()));
+            if let Some(name) = builtin_argument {
+                Err(ArgumentError::NotOnRelationship(name.clone()))?
+            }
+        }
+        check_relationship_arguments(config, &relationship.query)?;
+    }
+    Ok(())
+}
+
+/// Queries that use `_ieq` run with a case-insensitive collation if one is configured. Strength 2
+/// compares base characters and accents, but not case.
+fn case_insensitive_collation(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+) -> Option<Collation> {
+    let locale = config.case_insensitive_collation()?;
+    let uses_ieq = query_uses_ieq(&query_plan.query)
+        || query_plan
+            .unrelated_collections
+            .values()
+            .any(|join| query_uses_ieq(&join.query));
+    if !uses_ieq {
+        return None;
+    }
+    Some(
+        Collation::builder()
+            .locale(locale.to_owned())
+            .strength(CollationStrength::Secondary)
+            .build(),
+    )
+}
+
+fn query_uses_ieq(query: &Query) -> bool {
+    query.predicate.as_ref().is_some_and(expression_uses_ieq)
+        || query
+            .relationships
+            .values()
+            .any(|relationship| query_uses_ieq(&relationship.query))
+}
+
+fn expression_uses_ieq(expression: &Expression) -> bool {
+    match expression {
+        Expression::And { expressions } | Expression::Or { expressions } => {
+            expressions.iter().any(expression_uses_ieq)
+        }
+        Expression::Not { expression } => expression_uses_ieq(expression),
+        Expression::UnaryComparisonOperator { .. } => false,
+        Expression::BinaryComparisonOperator { operator, .. } => {
+            *operator == ComparisonFunction::IEqual
+        }
+        Expression::Exists { predicate, .. } => {
+            predicate.as_deref().is_some_and(expression_uses_ieq)
+        }
+    }
+}
+
+fn parse_limit((name, value): (ndc_models::ArgumentName, Bson)) -> Result<u32> {
+    match value {
+        Bson::Int32(n) if n >= 0 => Ok(n as u32),
+        value => Err(ArgumentError::InvalidValue {
+            name,
+            message: format!("expected a non-negative integer, but got {value}"),
+        }
+        .into()),
+    }
+}
+
+fn parse_read_concern((name, value): (ndc_models::ArgumentName, Bson)) -> Result<ReadConcern> {
+    match value {
+        Bson::String(level) => match level.as_str() {
+            "local" => Ok(ReadConcern::local()),
+            "available" => Ok(ReadConcern::available()),
+            "majority" => Ok(ReadConcern::majority()),
+            "linearizable" => Ok(ReadConcern::linearizable()),
+            "snapshot" => Ok(ReadConcern::snapshot()),
+            _ => Err(ArgumentError::InvalidValue {
+                name,
+                message: format!("unknown read concern level, \"{level}\""),
+            }
+            .into()),
+        },
+        value => Err(ArgumentError::InvalidValue {
+            name,
+            message: format!("expected a string, but got {value}"),
+        }
+        .into()),
+    }
+}
+
+fn parse_hint((name, value): (ndc_models::ArgumentName, Bson)) -> Result<Bson> {
+    match value {
+        Bson::String(_) | Bson::Document(_) => Ok(value),
+        value => Err(ArgumentError::InvalidValue {
+            name,
+            message: format!(
+                "expected an index name or an index specification document, but got {value}"
+            ),
+        }
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, ObjectField, ObjectType, Type},
+        serialized::Schema,
+        Configuration,
+    };
+    use mongodb::{bson::bson, options::ReadConcern};
+    use mongodb_support::BsonScalarType;
+    use ndc_models::{Argument, RelationshipArgument};
+    use ndc_query_plan::plan_for_query_request;
+    use ndc_test_helpers::{field, query, query_request, relation_field, relationship};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::{
+        interface_types::MongoAgentError, mongo_query_plan::MongoConfiguration,
+        query::arguments::ArgumentError,
+    };
+
+    use super::CollectionArguments;
+
+    #[test]
+    fn resolves_builtin_collection_arguments() -> anyhow::Result<()> {
+        let arguments = [
+            ("limit".into(), Argument::Literal { value: json!(5) }),
+            (
+                "readConcern".into(),
+                Argument::Literal {
+                    value: json!("majority"),
+                },
+            ),
+            (
+                "hint".into(),
+                Argument::Literal {
+                    value: json!({ "title": 1 }),
+                },
+            ),
+        ]
+        .into();
+        let resolved = CollectionArguments::from_arguments(&arguments)?;
+        assert_eq!(
+            resolved,
+            CollectionArguments {
+                limit: Some(5),
+                read_concern: Some(ReadConcern::majority()),
+                hint: Some(bson!({ "title": 1 })),
+                include_deleted: false,
+                collation: None,
+                batch_size: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_collection_arguments() -> anyhow::Result<()> {
+        let arguments = [("sample".into(), Argument::Literal { value: json!(5) })].into();
+        let result = CollectionArguments::from_arguments(&arguments);
+        match result {
+            Err(MongoAgentError::Arguments(ArgumentError::Excess(names))) => {
+                assert_eq!(names, vec![ndc_models::ArgumentName::from("sample")])
+            }
+            result => panic!("expected Excess argument error, but got {result:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_variable_collection_arguments() -> anyhow::Result<()> {
+        let arguments = [(
+            "limit".into(),
+            Argument::Variable {
+                name: "limit".into(),
+            },
+        )]
+        .into();
+        let result = CollectionArguments::from_arguments(&arguments);
+        match result {
+            Err(MongoAgentError::Arguments(ArgumentError::NotLiteral(name))) => {
+                assert_eq!(name.as_str(), "limit")
+            }
+            result => panic!("expected NotLiteral argument error, but got {result:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_read_concern_level() -> anyhow::Result<()> {
+        let arguments = [(
+            "readConcern".into(),
+            Argument::Literal {
+                value: json!("eventual"),
+            },
+        )]
+        .into();
+        let result = CollectionArguments::from_arguments(&arguments);
+        match result {
+            Err(MongoAgentError::Arguments(ArgumentError::InvalidValue { name, .. })) => {
+                assert_eq!(name.as_str(), "readConcern")
+            }
+            result => panic!("expected InvalidValue argument error, but got {result:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_negative_limit() -> anyhow::Result<()> {
+        let arguments = [("limit".into(), Argument::Literal { value: json!(-1) })].into();
+        let result = CollectionArguments::from_arguments(&arguments);
+        match result {
+            Err(MongoAgentError::Arguments(ArgumentError::InvalidValue { name, .. })) => {
+                assert_eq!(name.as_str(), "limit")
+            }
+            result => panic!("expected InvalidValue argument error, but got {result:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_hint_that_is_not_an_index_name_or_specification() -> anyhow::Result<()> {
+        let arguments = [("hint".into(), Argument::Literal { value: json!(5) })].into();
+        let result = CollectionArguments::from_arguments(&arguments);
+        match result {
+            Err(MongoAgentError::Arguments(ArgumentError::InvalidValue { name, .. })) => {
+                assert_eq!(name.as_str(), "hint")
+            }
+            result => panic!("expected InvalidValue argument error, but got {result:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_builtin_arguments_for_relationship_targets() -> anyhow::Result<()> {
+        let schema = Schema {
+            collections: [(
+                "posts".into(),
+                Collection {
+                    r#type: "posts".into(),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "posts".into(),
+                ObjectType {
+                    fields: [
+                        ObjectField::new("title", Type::Scalar(BsonScalarType::String)),
+                        ObjectField::new("author", Type::Scalar(BsonScalarType::String)),
+                    ]
+                    .into_iter()
+                    .map(|(name, field)| (name.into(), field))
+                    .collect(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        let config = MongoConfiguration(Configuration::from_schema(schema)?);
+
+        let query_request = query_request()
+            .collection("posts")
+            .query(query().fields([
+                field!("title"),
+                relation_field!("by_same_author" => "by_same_author", query().fields([
+                    field!("title")
+                ])),
+            ]))
+            .relationships([(
+                "by_same_author",
+                relationship("posts", [("author", "author")]).arguments(
+                    [(
+                        "limit".into(),
+                        RelationshipArgument::Literal { value: json!(1) },
+                    )]
+                    .into(),
+                ),
+            )])
+            .into();
+        let query_plan = plan_for_query_request(&config, query_request)?;
+
+        let result = CollectionArguments::for_request(&config, &query_plan);
+        match result {
+            Err(MongoAgentError::Arguments(ArgumentError::NotOnRelationship(name))) => {
+                assert_eq!(name.as_str(), "limit")
+            }
+            result => panic!("expected NotOnRelationship argument error, but got {result:?}"),
+        }
+        Ok(())
+    }
+}
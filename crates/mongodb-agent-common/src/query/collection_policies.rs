@@ -0,0 +1,129 @@
+//! Applies the default ordering and maximum limit that collections may declare in schema files.
+//! See [configuration::collection_policies].
+
+use ndc_query_plan::OrderByElement;
+
+use crate::mongo_query_plan::{MongoConfiguration, OrderBy, OrderByTarget, Query};
+
+/// Applies policies of the given collection to the query, and policies of related collections to
+/// relationship queries.
+pub fn apply_collection_policies(
+    config: &MongoConfiguration,
+    collection: &ndc_models::CollectionName,
+    query: &mut Query,
+) {
+    if let Some(policies) = config.collection_policies(collection) {
+        if let (None, Some(default_order_by)) = (&query.order_by, &policies.default_order_by) {
+            query.order_by = Some(OrderBy {
+                elements: default_order_by
+                    .iter()
+                    .map(|element| OrderByElement {
+                        order_direction: element.direction,
+                        target: OrderByTarget::Column {
+                            name: element.column.clone(),
+                            field_path: None,
+                            path: vec![],
+                        },
+                    })
+                    .collect(),
+            });
+        }
+        if let Some(max) = policies.max_limit {
+            query.limit = Some(query.limit.map_or(max, |limit| limit.min(max)));
+        }
+    }
+    for relationship in query.relationships.values_mut() {
+        apply_collection_policies(
+            config,
+            &relationship.target_collection,
+            &mut relationship.query,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, DefaultOrderByElement, ObjectField, ObjectType, Type},
+        serialized::Schema,
+        Configuration,
+    };
+    use mongodb::bson::bson;
+    use mongodb_support::BsonScalarType;
+    use ndc_models::{OrderDirection, QueryResponse};
+    use ndc_test_helpers::{field, query, query_request, row_set};
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        mongo_query_plan::MongoConfiguration,
+        mongodb::test_helpers::mock_collection_aggregate_response_for_pipeline,
+        query::execute_query_request,
+    };
+
+    #[tokio::test]
+    async fn applies_default_order_and_max_limit() -> anyhow::Result<()> {
+        let schema = Schema {
+            collections: [(
+                "movies".into(),
+                Collection {
+                    r#type: "movies".into(),
+                    description: None,
+                    default_order_by: Some(vec![DefaultOrderByElement {
+                        column: "title".into(),
+                        direction: OrderDirection::Asc,
+                    }]),
+                    max_limit: Some(100),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "movies".into(),
+                ObjectType {
+                    fields: [ObjectField::new(
+                        "title",
+                        Type::Scalar(BsonScalarType::String),
+                    )]
+                    .into_iter()
+                    .map(|(name, field)| (name.into(), field))
+                    .collect(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        let config = MongoConfiguration(Configuration::from_schema(schema)?);
+
+        let query_request = query_request()
+            .collection("movies")
+            .query(query().fields([field!("title")]).limit(500))
+            .into();
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "movies",
+            bson!([
+                { "$sort": { "title": 1 } },
+                { "$limit": 100 },
+                { "$replaceWith": { "title": { "$ifNull": ["$title", null] } } },
+            ]),
+            bson!([{ "title": "Alien" }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set().rows([[("title", "Alien")]]).into_response()
+        );
+        Ok(())
+    }
+}
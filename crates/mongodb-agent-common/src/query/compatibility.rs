@@ -0,0 +1,296 @@
+//! Rewrites for query pipelines that run against servers implementing an older version of the
+//! MongoDB wire protocol, such as Amazon DocumentDB or Azure Cosmos DB for MongoDB. These are
+//! applied when the `queryOptions.compatibilityMode` configuration option is set.
+//!
+//! Pipelines are rewritten after they are built so that pipeline construction does not need to
+//! account for compatibility mode except where a construct has no equivalent expression (see
+//! [super::relations] and [super::foreach]).
+
+use mongodb::bson::{self, Bson, Document};
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongodb::{sanitize::is_name_safe, Pipeline, Stage},
+};
+
+type Result<T> = std::result::Result<T, MongoAgentError>;
+
+/// Replaces `$getField` and the `$first` array operator with equivalent expressions that older
+/// servers support.
+pub fn rewrite_pipeline(pipeline: Pipeline) -> Result<Pipeline> {
+    pipeline
+        .stages
+        .into_iter()
+        .map(|stage| Ok(Stage::Other(rewrite_stage(bson::to_document(&stage)?)?)))
+        .collect()
+}
+
+/// Replaces references to the given pipeline variables with literal values. This is used to run
+/// a pipeline once per variable set where the variables would otherwise be bound by a `$lookup`
+/// stage.
+pub fn bind_variables(pipeline: Pipeline, variables: &Document) -> Result<Pipeline> {
+    pipeline
+        .stages
+        .into_iter()
+        .map(|stage| {
+            let stage = bson::to_document(&stage)?;
+            match bind_variables_in(Bson::Document(stage), variables) {
+                Bson::Document(stage) => Ok(Stage::Other(stage)),
+                _ => unreachable!("binding variables in a document produces a document"),
+            }
+        })
+        .collect()
+}
+
+fn rewrite_stages(stages: Bson) -> Result<Bson> {
+    match stages {
+        Bson::Array(stages) => Ok(Bson::Array(
+            stages
+                .into_iter()
+                .map(|stage| match stage {
+                    Bson::Document(stage) => Ok(Bson::Document(rewrite_stage(stage)?)),
+                    other => Ok(other),
+                })
+                .collect::<Result<_>>()?,
+        )),
+        other => Ok(other),
+    }
+}
+
+/// Only stages that are known to contain aggregation expressions are rewritten. Other stages,
+/// such as `$setWindowFields` where `$first` is a window operator, are left as they are.
+fn rewrite_stage(stage: Document) -> Result<Document> {
+    stage
+        .into_iter()
+        .map(|(name, spec)| {
+            let spec = match (name.as_str(), spec) {
+                ("$facet", Bson::Document(facets)) => Bson::Document(
+                    facets
+                        .into_iter()
+                        .map(|(facet, stages)| Ok((facet, rewrite_stages(stages)?)))
+                        .collect::<Result<_>>()?,
+                ),
+                ("$lookup" | "$unionWith", Bson::Document(spec)) => Bson::Document(
+                    spec.into_iter()
+                        .map(|(key, value)| {
+                            let value = match key.as_str() {
+                                "pipeline" => rewrite_stages(value)?,
+                                "let" => rewrite_expression(value)?,
+                                _ => value,
+                            };
+                            Ok((key, value))
+                        })
+                        .collect::<Result<_>>()?,
+                ),
+                ("$group", Bson::Document(spec)) => Bson::Document(
+                    spec.into_iter()
+                        .map(|(key, value)| {
+                            let value = match (key.as_str(), value) {
+                                ("_id", id) => rewrite_expression(id)?,
+                                // Each field is an accumulator such as `$first` which must not be
+                                // replaced, but its argument is an expression.
+                                (_, Bson::Document(accumulator)) => Bson::Document(
+                                    accumulator
+                                        .into_iter()
+                                        .map(|(op, arg)| Ok((op, rewrite_expression(arg)?)))
+                                        .collect::<Result<_>>()?,
+                                ),
+                                (_, value) => value,
+                            };
+                            Ok((key, value))
+                        })
+                        .collect::<Result<_>>()?,
+                ),
+                (
+                    "$match" | "$project" | "$replaceWith" | "$replaceRoot" | "$addFields" | "$set",
+                    spec,
+                ) => rewrite_expression(spec)?,
+                (_, spec) => spec,
+            };
+            Ok((name, spec))
+        })
+        .collect()
+}
+
+fn rewrite_expression(expression: Bson) -> Result<Bson> {
+    match expression {
+        Bson::Document(document) => {
+            if document.len() == 1 {
+                if let Some(arg) = document.get("$getField") {
+                    return get_field_replacement(arg.clone());
+                }
+                if let Some(arg) = document.get("$first") {
+                    return first_replacement(arg.clone());
+                }
+                if document.contains_key("$literal") {
+                    return Ok(Bson::Document(document));
+                }
+            }
+            Ok(Bson::Document(
+                document
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, rewrite_expression(value)?)))
+                    .collect::<Result<_>>()?,
+            ))
+        }
+        Bson::Array(values) => Ok(Bson::Array(
+            values
+                .into_iter()
+                .map(rewrite_expression)
+                .collect::<Result<_>>()?,
+        )),
+        other => Ok(other),
+    }
+}
+
+/// `{ $first: <array> }` becomes `{ $arrayElemAt: [<array>, 0] }`. Both produce a missing value
+/// when the array is empty.
+fn first_replacement(arg: Bson) -> Result<Bson> {
+    let array = match arg {
+        Bson::Array(mut args) if args.len() == 1 => args.remove(0),
+        arg => arg,
+    };
+    Ok(bson::bson!({ "$arrayElemAt": [rewrite_expression(array)?, 0] }))
+}
+
+/// `$getField` becomes a field path. That only works for field names that do not begin with `$`
+/// and do not contain dots, which is why `$getField` is used otherwise.
+fn get_field_replacement(arg: Bson) -> Result<Bson> {
+    let (field, input) = match arg {
+        Bson::Document(mut spec) if spec.contains_key("field") => {
+            let field = spec.remove("field").unwrap_or(Bson::Null);
+            let input = spec
+                .remove("input")
+                .unwrap_or(Bson::String("$$CURRENT".into()));
+            (field, input)
+        }
+        arg => (arg, Bson::String("$$CURRENT".into())),
+    };
+    let field_name = match field {
+        Bson::String(name) => name,
+        Bson::Document(literal) => match literal.get("$literal") {
+            Some(Bson::String(name)) => name.clone(),
+            _ => return Err(unsupported_get_field()),
+        },
+        _ => return Err(unsupported_get_field()),
+    };
+    if !is_name_safe(&field_name) {
+        return Err(unsupported_get_field());
+    }
+
+    match rewrite_expression(input)? {
+        Bson::String(path) if path == "$$CURRENT" => Ok(Bson::String(format!("${field_name}"))),
+        Bson::String(path) if path.starts_with('$') => {
+            Ok(Bson::String(format!("{path}.{field_name}")))
+        }
+        input => Ok(bson::bson!({
+            "$let": {
+                "vars": { "input": input },
+                "in": format!("$$input.{field_name}"),
+            }
+        })),
+    }
+}
+
+fn unsupported_get_field() -> MongoAgentError {
+    MongoAgentError::NotImplemented(
+        "field names that begin with a dollar sign or contain dots are not supported in compatibility mode",
+    )
+}
+
+fn bind_variables_in(value: Bson, variables: &Document) -> Bson {
+    match value {
+        Bson::String(reference) => match reference.strip_prefix("$$") {
+            Some(path) => {
+                let name = path.split('.').next().unwrap_or(path);
+                match variables.get(name) {
+                    Some(value) if name == path => bson::bson!({ "$literal": value.clone() }),
+                    Some(value) => bson::bson!({
+                        "$let": {
+                            "vars": { name: { "$literal": value.clone() } },
+                            "in": reference,
+                        }
+                    }),
+                    None => Bson::String(reference),
+                }
+            }
+            None => Bson::String(reference),
+        },
+        Bson::Document(document) if document.len() == 1 && document.contains_key("$literal") => {
+            Bson::Document(document)
+        }
+        Bson::Document(document) => Bson::Document(
+            document
+                .into_iter()
+                .map(|(key, value)| (key, bind_variables_in(value, variables)))
+                .collect(),
+        ),
+        Bson::Array(values) => Bson::Array(
+            values
+                .into_iter()
+                .map(|value| bind_variables_in(value, variables))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    use super::{bind_variables, rewrite_pipeline};
+    use crate::mongodb::{Pipeline, Stage};
+
+    #[test]
+    fn replaces_get_field_and_first() -> anyhow::Result<()> {
+        let pipeline = Pipeline::new(vec![
+            Stage::Facet(
+                [(
+                    "rows".to_owned(),
+                    Pipeline::new(vec![Stage::Other(doc! {
+                        "$replaceWith": { "title": { "$getField": { "$literal": "title" } } }
+                    })]),
+                )]
+                .into(),
+            ),
+            Stage::Other(doc! {
+                "$replaceWith": {
+                    "rows": { "$getField": { "field": "rows", "input": { "$first": "$query" } } },
+                }
+            }),
+        ]);
+        let expected = vec![
+            doc! { "$facet": { "rows": [{ "$replaceWith": { "title": "$title" } }] } },
+            doc! {
+                "$replaceWith": {
+                    "rows": {
+                        "$let": {
+                            "vars": { "input": { "$arrayElemAt": ["$query", 0] } },
+                            "in": "$$input.rows",
+                        }
+                    },
+                }
+            },
+        ];
+        let actual: Vec<_> = rewrite_pipeline(pipeline)?.into_iter().collect();
+        assert_eq!(actual, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_variable_references_with_literals() -> anyhow::Result<()> {
+        let pipeline = Pipeline::new(vec![Stage::Match(doc! {
+            "$expr": { "$eq": ["$artistId", "$$artistId_int"] }
+        })]);
+        let actual: Vec<_> = bind_variables(pipeline, &doc! { "artistId_int": 1 })?
+            .into_iter()
+            .collect();
+        assert_eq!(
+            actual,
+            vec![doc! { "$match": { "$expr": { "$eq": ["$artistId", { "$literal": 1 }] } } }]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! Execution strategy for queries that request a single star count aggregate and no rows. These
+//! run as a `countDocuments` command instead of an aggregation pipeline with a `$facet` stage.
+//! The response document has the same shape as the output of the faceted pipeline so response
+//! serialization is shared.
+
+use std::time::Instant;
+
+use mongodb::{
+    bson::{doc, Bson, Document, RawDocumentBuf},
+    options::CountOptions,
+};
+use tracing::{instrument, Instrument as _};
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::{Aggregate, MongoConfiguration, QueryPlan},
+    mongodb::{CollectionTrait as _, DatabaseTrait, Pipeline, Stage},
+    monitoring::{PipelineIssued, QueryObservers, ResponseReceived},
+};
+
+use super::{
+    field_aliases::field_aliases_for_query, gap_fill::gap_fill_for_query, make_selector,
+    read_transforms::read_transforms_for_query, soft_delete::with_soft_delete_filter,
+    CollectionArguments, QueryTarget,
+};
+
+type Result<T> = std::result::Result<T, MongoAgentError>;
+
+#[derive(Clone, Debug)]
+pub struct CountCommand {
+    pub collection: ndc_models::CollectionName,
+
+    /// Name of the star count aggregate in the query request
+    pub aggregate_name: ndc_models::FieldName,
+
+    pub filter: Document,
+    pub options: CountOptions,
+}
+
+impl CountCommand {
+    /// Produces a count command if the query plan targets a regular collection, and requests only
+    /// a star count aggregate. Expects the `limit` collection argument to have already been
+    /// applied to the query plan.
+    pub fn for_query_plan(
+        config: &MongoConfiguration,
+        query_plan: &QueryPlan,
+        collection_arguments: &CollectionArguments,
+    ) -> Result<Option<CountCommand>> {
+        let QueryTarget::Collection(collection) = QueryTarget::for_request(config, query_plan)
+        else {
+            return Ok(None);
+        };
+        let query = &query_plan.query;
+        let is_count_only = query_plan.variables.is_none()
+            && query_plan.unrelated_collections.is_empty()
+            && gap_fill_for_query(config, query_plan).is_none()
+            && field_aliases_for_query(config, query_plan).is_none()
+            && read_transforms_for_query(config, query_plan).is_none()
+            && query.relationships.is_empty()
+            && query.fields.is_none();
+        let aggregate_name = match &query.aggregates {
+            Some(aggregates) if is_count_only && aggregates.len() == 1 => {
+                match aggregates.first() {
+                    Some((name, Aggregate::StarCount {})) => name.clone(),
+                    _ => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        let filter = query
+            .predicate
+            .as_ref()
+            .map(|predicate| make_selector(config, predicate))
+            .transpose()?
+            .unwrap_or_default();
+        let filter = with_soft_delete_filter(config, query_plan, filter)?;
+
+        let mut options = collection_arguments.count_options();
+        options.skip = query.offset.map(u64::from);
+        options.limit = query.aggregates_limit.map(u64::from);
+
+        Ok(Some(CountCommand {
+            collection,
+            aggregate_name,
+            filter,
+            options,
+        }))
+    }
+
+    /// An aggregation pipeline that produces the same count. Query observers are given this
+    /// pipeline when the command is issued.
+    pub fn equivalent_pipeline(&self) -> Pipeline {
+        let mut pipeline = Pipeline::new(vec![Stage::Match(self.filter.clone())]);
+        if let Some(skip) = self.options.skip {
+            pipeline.push(Stage::Skip(u32::try_from(skip).unwrap_or(u32::MAX)));
+        }
+        if let Some(limit) = self.options.limit {
+            pipeline.push(Stage::Limit(u32::try_from(limit).unwrap_or(u32::MAX)));
+        }
+        pipeline.push(Stage::Count(self.aggregate_name.to_string()));
+        pipeline
+    }
+}
+
+#[instrument(name = "Execute Count Command", skip_all, fields(internal.visibility = "user"))]
+pub async fn execute_count_command(
+    database: impl DatabaseTrait,
+    observers: &QueryObservers,
+    command: CountCommand,
+) -> Result<Vec<RawDocumentBuf>> {
+    let pipeline = command.equivalent_pipeline();
+    let CountCommand {
+        collection,
+        aggregate_name,
+        filter,
+        options,
+    } = command;
+    tracing::debug!(
+        %collection,
+        filter = %serde_json::to_string(&filter).unwrap(),
+        "executing count command"
+    );
+    observers.notify(|observer| {
+        observer.pipeline_issued(&PipelineIssued {
+            collection: Some(&collection),
+            pipeline: &pipeline,
+        })
+    });
+    let start = Instant::now();
+    let count = database
+        .collection(collection.as_str())
+        .count_documents(filter, options)
+        .instrument(tracing::info_span!(
+            "MongoDB Count Documents Command",
+            internal.visibility = "user"
+        ))
+        .await?;
+    observers.notify(|observer| {
+        observer.response_received(&ResponseReceived {
+            collection: Some(&collection),
+            documents: 1,
+            elapsed: start.elapsed(),
+        })
+    });
+
+    // Match the numeric type that the `$count` pipeline stage produces
+    let count = match i32::try_from(count) {
+        Ok(n) => Bson::Int32(n),
+        Err(_) => Bson::Int64(count as i64),
+    };
+    let response_document = doc! { "aggregates": { aggregate_name.as_str(): count } };
+    let response_document = RawDocumentBuf::from_document(&response_document)
+        .map_err(|err| MongoAgentError::AdHoc(err.into()))?;
+    Ok(vec![response_document])
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::{
+        bson::{doc, Document},
+        options::CountOptions,
+    };
+    use ndc_models::QueryResponse;
+    use ndc_test_helpers::{
+        binop, query, query_request, row_set, star_count_aggregate, target, value,
+    };
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::{
+        mongodb::{MockCollectionTrait, MockDatabaseTrait},
+        query::execute_query_request,
+        test_helpers::mflix_config,
+    };
+
+    #[tokio::test]
+    async fn executes_star_count_with_count_documents() -> anyhow::Result<()> {
+        let query_request = query_request()
+            .collection("movies")
+            .query(
+                query()
+                    .aggregates([star_count_aggregate!("count")])
+                    .predicate(binop("_gt", target!("year"), value!(2000))),
+            )
+            .into();
+
+        let mut db = MockDatabaseTrait::new();
+        db.expect_collection().returning(|name| {
+            assert_eq!(name, "movies");
+            let mut collection = MockCollectionTrait::new();
+            collection
+                .expect_count_documents()
+                .returning(|filter: Document, _: CountOptions| {
+                    assert_eq!(filter, doc! { "year": { "$gt": 2000 } });
+                    Ok(42)
+                });
+            collection
+        });
+
+        let result = execute_query_request(
+            db,
+            &mflix_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .aggregates([("count", json!({ "$numberInt": "42" }))])
+                .into_response()
+        );
+        Ok(())
+    }
+}
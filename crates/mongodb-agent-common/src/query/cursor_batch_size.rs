@@ -0,0 +1,201 @@
+//! Adaptive `batchSize` for aggregation cursors. The driver applies one batch size to the whole
+//! cursor, so the size is tuned between queries: after each query the sizes of documents in its
+//! first batch are used to pick the batch size for the next query against the same collection.
+//! Before a collection has been queried its batch size is picked from cached collection statistics
+//! if they are available (see [crate::collection_stats]).
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use configuration::ConfigurationCursorBatchSizeOptions;
+use mongodb::bson::RawDocumentBuf;
+use ndc_models as ndc;
+
+use crate::collection_stats::CollectionStatsCache;
+
+/// An adapted batch size may grow by at most this factor per query so that one query with
+/// unusually small documents does not produce a huge batch for the next.
+const MAX_GROWTH_FACTOR: u32 = 4;
+
+/// Batch sizes learned from previous queries, by collection. This is held in connector state.
+#[derive(Clone, Debug, Default)]
+pub struct CursorBatchSizes {
+    learned: Arc<Mutex<HashMap<ndc::CollectionName, u32>>>,
+
+    /// Statistics for collections that have not been queried yet
+    collection_stats: CollectionStatsCache,
+}
+
+impl CursorBatchSizes {
+    pub fn new(collection_stats: CollectionStatsCache) -> Self {
+        CursorBatchSizes {
+            learned: Default::default(),
+            collection_stats,
+        }
+    }
+}
+
+/// Batch size to request for the next query against the given collection
+pub fn cursor_batch_size(
+    batch_sizes: &CursorBatchSizes,
+    options: &ConfigurationCursorBatchSizeOptions,
+    collection: &ndc::CollectionName,
+) -> u32 {
+    if !options.adaptive {
+        return options.initial;
+    }
+    batch_sizes
+        .learned
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(collection)
+        .copied()
+        .or_else(|| {
+            let average_bytes = batch_sizes.collection_stats.get(collection)?.avg_obj_size?;
+            Some(batch_size_for_document_size(options, average_bytes as u64))
+        })
+        .unwrap_or(options.initial)
+}
+
+/// Adapts the batch size for the given collection to the documents in the first batch of
+/// a response.
+pub fn record_first_batch(
+    batch_sizes: &CursorBatchSizes,
+    options: &ConfigurationCursorBatchSizeOptions,
+    collection: &ndc::CollectionName,
+    batch_size: u32,
+    documents: &[RawDocumentBuf],
+) {
+    if !options.adaptive {
+        return;
+    }
+    if let Some(next) = next_batch_size(options, batch_size, documents) {
+        batch_sizes
+            .learned
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(collection.clone(), next);
+    }
+}
+
+fn next_batch_size(
+    options: &ConfigurationCursorBatchSizeOptions,
+    batch_size: u32,
+    documents: &[RawDocumentBuf],
+) -> Option<u32> {
+    let first_batch = &documents[..documents.len().min(batch_size as usize)];
+    if first_batch.is_empty() {
+        return None;
+    }
+    let total_bytes: usize = first_batch.iter().map(|doc| doc.as_bytes().len()).sum();
+    let average_bytes = (total_bytes / first_batch.len()) as u64;
+    let next = batch_size_for_document_size(options, average_bytes)
+        .min(batch_size.saturating_mul(MAX_GROWTH_FACTOR))
+        .max(1);
+    Some(next)
+}
+
+/// The batch size that brings batches closest to the target size, within the configured maximum
+fn batch_size_for_document_size(
+    options: &ConfigurationCursorBatchSizeOptions,
+    average_bytes: u64,
+) -> u32 {
+    let ideal = (options.target_batch_bytes / average_bytes.max(1)).min(u32::MAX as u64) as u32;
+    ideal.clamp(1, options.max.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ConfigurationCursorBatchSizeOptions;
+    use mongodb::bson::{rawdoc, RawDocumentBuf};
+
+    use crate::collection_stats::{CollectionStats, CollectionStatsCache};
+
+    use super::{
+        batch_size_for_document_size, cursor_batch_size, next_batch_size, record_first_batch,
+        CursorBatchSizes,
+    };
+
+    fn documents(count: usize, padding: usize) -> Vec<RawDocumentBuf> {
+        let padding = "x".repeat(padding);
+        (0..count)
+            .map(|_| rawdoc! { "padding": padding.as_str() })
+            .collect()
+    }
+
+    #[test]
+    fn ramps_batch_size_from_first_batch_document_sizes() {
+        let options = ConfigurationCursorBatchSizeOptions {
+            initial: 100,
+            max: 1_000,
+            target_batch_bytes: 100_000,
+            adaptive: true,
+        };
+
+        // Small documents grow the batch gradually, up to the configured maximum
+        let small = documents(200, 10);
+        assert_eq!(next_batch_size(&options, 100, &small), Some(400));
+        assert_eq!(next_batch_size(&options, 400, &small), Some(1_000));
+
+        // Large documents shrink the batch immediately
+        let large = documents(100, 9_990);
+        assert_eq!(next_batch_size(&options, 100, &large), Some(9));
+
+        assert_eq!(next_batch_size(&options, 100, &[]), None);
+    }
+
+    #[test]
+    fn learns_batch_sizes_separately_for_each_connector_state() {
+        let options = ConfigurationCursorBatchSizeOptions {
+            initial: 100,
+            max: 1_000,
+            target_batch_bytes: 100_000,
+            adaptive: true,
+        };
+        let collection = "movies".into();
+        let learned = CursorBatchSizes::default();
+        let other = CursorBatchSizes::default();
+
+        record_first_batch(&learned, &options, &collection, 100, &documents(100, 9_990));
+        assert_eq!(cursor_batch_size(&learned, &options, &collection), 9);
+        assert_eq!(cursor_batch_size(&other, &options, &collection), 100);
+    }
+
+    #[test]
+    fn picks_batch_size_from_cached_statistics_before_first_query() {
+        let options = ConfigurationCursorBatchSizeOptions {
+            initial: 100,
+            max: 1_000,
+            target_batch_bytes: 100_000,
+            adaptive: true,
+        };
+        let collection = "movies".into();
+        let collection_stats = CollectionStatsCache::default();
+        let batch_sizes = CursorBatchSizes::new(collection_stats.clone());
+        assert_eq!(cursor_batch_size(&batch_sizes, &options, &collection), 100);
+
+        collection_stats.insert(
+            collection.clone(),
+            CollectionStats {
+                count: 10,
+                avg_obj_size: Some(500.0),
+            },
+        );
+        assert_eq!(cursor_batch_size(&batch_sizes, &options, &collection), 200);
+    }
+
+    #[test]
+    fn picks_initial_batch_size_from_average_document_size() {
+        let options = ConfigurationCursorBatchSizeOptions {
+            initial: 100,
+            max: 1_000,
+            target_batch_bytes: 100_000,
+            adaptive: true,
+        };
+        assert_eq!(batch_size_for_document_size(&options, 500), 200);
+        assert_eq!(batch_size_for_document_size(&options, 10), 1_000);
+        assert_eq!(batch_size_for_document_size(&options, 1_000_000), 1);
+    }
+}
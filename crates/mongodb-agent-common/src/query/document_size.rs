@@ -0,0 +1,552 @@
+//! MongoDB rejects any document larger than 16MB, including intermediate documents that a
+//! pipeline builds. Query pipelines build large documents in a few places: `$lookup` stages
+//! collect related rows into an array in each row, `$facet` stages combine all rows and aggregates
+//! into one document, and requests with variable sets collect the row set for each variable set
+//! into one document. When the server reports that a document is too large this module works out
+//! which of those parts of the query is the likely cause so that the error can say which field to
+//! change.
+//!
+//! A selected row may also be too large on its own. When large document handling is configured
+//! rows are checked on the server before they are returned, and selected arrays may be truncated.
+
+use std::fmt::{self, Display};
+
+use indexmap::IndexMap;
+use mongodb::{
+    bson::{doc, Bson, Document, RawBsonRef, RawDocumentBuf},
+    error::ErrorKind,
+};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::{Field, MongoConfiguration, Query, QueryPlan, Type},
+    mongodb::Stage,
+};
+
+/// `BSONObjectTooLarge`, reported when a document in a pipeline or in a response exceeds 16MB
+const BSON_OBJECT_TOO_LARGE: i32 = 10334;
+
+/// Reported when the documents that a `$lookup` stage matches exceed the size limit
+const LOOKUP_RESULT_TOO_LARGE: i32 = 4568;
+
+/// Reported when the document built by a `$facet` stage exceeds the size limit
+const FACET_RESULT_TOO_LARGE: i32 = 4031700;
+
+/// Rows are returned in cursor batches which need some room beyond the row itself
+const MAX_ROW_SIZE: i64 = 16 * 1024 * 1024 - 16 * 1024;
+
+/// Replaces a row that is too large to return. Documents inside a pipeline may exceed the size
+/// limit, so the replacement can still describe the oversized row.
+const OVERSIZED_ROW_FIELD: &str = "__oversized_row";
+
+/// A query produced a document larger than the BSON size limit
+#[derive(Clone, Debug, PartialEq, Error)]
+#[error("{}", self.message())]
+pub struct DocumentTooLargeError {
+    /// Parts of the query that may have produced the document. There may be more than one
+    /// candidate when the server message does not identify the stage that failed.
+    pub causes: Vec<DocumentSizeCause>,
+    pub server_message: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum DocumentSizeCause {
+    /// A relationship field collects all related rows into an array in each row. The path is
+    /// a dot-separated list of field aliases from the top of the query.
+    Relationship { path: String },
+    /// Rows and aggregates are combined into one document. The path is `None` for the top level of
+    /// the query, or the path to a relationship field that selects aggregates.
+    Facet { path: Option<String> },
+    /// All rows for a variable set are combined into one document
+    VariableSets,
+    /// A single row is too large. Lists selected fields from largest to smallest.
+    Row { fields: Vec<FieldSize> },
+    /// The pipeline itself is too large to send. Literal comparison values and variable sets are
+    /// inlined in pipelines. Size is in bytes.
+    Pipeline { size: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FieldSize {
+    pub field: String,
+    /// Size in bytes of the field's value as BSON
+    pub size: i64,
+}
+
+impl DocumentSizeCause {
+    pub fn suggestion(&self) -> String {
+        match self {
+            DocumentSizeCause::Relationship { path } => format!(
+                "set a limit on the relationship field, {path}, or select fewer fields from it"
+            ),
+            DocumentSizeCause::Facet { path: None } => "request rows and aggregates in separate queries, or set a lower limit".to_owned(),
+            DocumentSizeCause::Facet { path: Some(path) } => format!(
+                "request aggregates for the relationship field, {path}, in a separate query, or set a lower limit on it"
+            ),
+            DocumentSizeCause::VariableSets => "set a lower limit, or enable compatibility mode so that each variable set runs as a separate aggregation; if query batching is enabled lower queryBatching.maxBatchSize".to_owned(),
+            DocumentSizeCause::Row { fields } => match fields.first() {
+                Some(largest) => format!(
+                    "select fewer fields, such as {}, or set queryOptions.largeDocuments.maxArrayLength to truncate large arrays",
+                    largest.field
+                ),
+                None => "select fewer fields".to_owned(),
+            },
+            DocumentSizeCause::Pipeline { .. } => "compare against fewer or smaller values, or split the request into several smaller requests".to_owned(),
+        }
+    }
+}
+
+impl Display for DocumentSizeCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocumentSizeCause::Relationship { path } => write!(f, "the relationship field, {path}"),
+            DocumentSizeCause::Facet { path: None } => {
+                write!(f, "the combined rows and aggregates of the query")
+            }
+            DocumentSizeCause::Facet { path: Some(path) } => write!(
+                f,
+                "the combined rows and aggregates of the relationship field, {path}"
+            ),
+            DocumentSizeCause::VariableSets => write!(f, "the row set for a variable set"),
+            DocumentSizeCause::Row { fields } => {
+                write!(f, "a row whose largest fields are")?;
+                for (i, FieldSize { field, size }) in fields.iter().take(3).enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{separator}{field} ({size} bytes)")?;
+                }
+                Ok(())
+            }
+            DocumentSizeCause::Pipeline { size } => {
+                write!(f, "the query pipeline, which is {size} bytes")
+            }
+        }
+    }
+}
+
+impl DocumentTooLargeError {
+    fn message(&self) -> String {
+        let mut message =
+            "The query produced a document larger than the 16MB limit for BSON documents"
+                .to_owned();
+        match self.causes.as_slice() {
+            [] => message.push_str("; select fewer fields, or set a lower limit"),
+            [cause] => message.push_str(&format!(" in {cause}; {}", cause.suggestion())),
+            causes => {
+                message.push_str("; it may have been produced by:");
+                for cause in causes {
+                    message.push_str(&format!(" {cause} ({});", cause.suggestion()));
+                }
+                message.pop();
+            }
+        }
+        message
+    }
+}
+
+/// Replaces a driver error that reports an oversized document with a [DocumentTooLargeError].
+/// Other errors are returned unchanged.
+pub fn with_document_size_context(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+    error: MongoAgentError,
+) -> MongoAgentError {
+    match error {
+        MongoAgentError::MongoDB(err) => match document_too_large(config, query_plan, &err) {
+            Some(too_large) => too_large.into(),
+            None => MongoAgentError::MongoDB(err),
+        },
+        error => error,
+    }
+}
+
+/// If the given error is the server reporting an oversized document, returns a typed error that
+/// identifies the parts of the query that may have produced it.
+pub fn document_too_large(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+    error: &mongodb::error::Error,
+) -> Option<DocumentTooLargeError> {
+    let ErrorKind::Command(command_error) = error.kind.as_ref() else {
+        return None;
+    };
+    let is_too_large = matches!(
+        command_error.code,
+        BSON_OBJECT_TOO_LARGE | LOOKUP_RESULT_TOO_LARGE | FACET_RESULT_TOO_LARGE
+    ) || command_error.code_name == "BSONObjectTooLarge"
+        || command_error
+            .message
+            .contains("exceeds maximum document size");
+    if !is_too_large {
+        return None;
+    }
+    Some(DocumentTooLargeError {
+        causes: document_size_causes(config, query_plan, &command_error.message),
+        server_message: command_error.message.clone(),
+    })
+}
+
+fn document_size_causes(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+    server_message: &str,
+) -> Vec<DocumentSizeCause> {
+    let mut relationships = vec![];
+    let mut facets = vec![];
+    if query_plan.query.has_aggregates() {
+        facets.push(DocumentSizeCause::Facet { path: None });
+    }
+    collect_causes(&query_plan.query, None, &mut relationships, &mut facets);
+    let variable_sets = query_plan.has_variables() && !config.compatibility_mode();
+
+    // A `$lookup` error names the collection that the stage reads from, as in "Total size of
+    // documents in Track matching pipeline's $lookup stage exceeds ..."
+    if let Some(collection) = server_message
+        .split_once("documents in ")
+        .and_then(|(_, rest)| rest.split_whitespace().next())
+    {
+        let matching: Vec<_> = relationships
+            .iter()
+            .filter(|(target, _)| target.as_str() == collection)
+            .map(|(_, cause)| cause.clone())
+            .collect();
+        if !matching.is_empty() {
+            return matching;
+        }
+        if variable_sets && query_plan.collection.as_str() == collection {
+            return vec![DocumentSizeCause::VariableSets];
+        }
+    }
+    if server_message.contains("$facet") && !facets.is_empty() {
+        return facets;
+    }
+
+    let mut causes = vec![];
+    if variable_sets {
+        causes.push(DocumentSizeCause::VariableSets);
+    }
+    causes.extend(facets);
+    causes.extend(relationships.into_iter().map(|(_, cause)| cause));
+    causes
+}
+
+/// Collects relationship fields that select rows, paired with their target collections, and
+/// relationship fields that are faceted because they select aggregates
+fn collect_causes(
+    query: &Query,
+    path: Option<&str>,
+    relationships: &mut Vec<(ndc_models::CollectionName, DocumentSizeCause)>,
+    facets: &mut Vec<DocumentSizeCause>,
+) {
+    for (alias, field) in query.fields.iter().flatten() {
+        let Field::Relationship {
+            relationship,
+            aggregates,
+            fields,
+        } = field
+        else {
+            continue;
+        };
+        let Some(relationship) = query.relationships.get(relationship) else {
+            continue;
+        };
+        let field_path = match path {
+            Some(path) => format!("{path}.{alias}"),
+            None => alias.to_string(),
+        };
+        if aggregates
+            .as_ref()
+            .is_some_and(|aggregates| !aggregates.is_empty())
+        {
+            facets.push(DocumentSizeCause::Facet {
+                path: Some(field_path.clone()),
+            });
+        }
+        if fields.is_some() {
+            relationships.push((
+                relationship.target_collection.clone(),
+                DocumentSizeCause::Relationship {
+                    path: field_path.clone(),
+                },
+            ));
+        }
+        collect_causes(
+            &relationship.query,
+            Some(&field_path),
+            relationships,
+            facets,
+        );
+    }
+}
+
+/// Truncates selected array fields to the configured maximum length. `selection` is the document
+/// given to the `$replaceWith` stage that selects `fields`.
+pub fn slice_array_fields(
+    config: &MongoConfiguration,
+    fields: &IndexMap<ndc_models::FieldName, Field>,
+    selection: &mut Document,
+) {
+    let Some(max_array_length) = config
+        .large_document_options()
+        .and_then(|options| options.max_array_length)
+    else {
+        return;
+    };
+    for (alias, field) in fields {
+        let Field::Column { column_type, .. } = field else {
+            continue;
+        };
+        if !is_array_type(column_type) {
+            continue;
+        }
+        if let Some(value) = selection.get_mut(alias.as_str()) {
+            *value = doc! {
+                "$let": {
+                    "vars": { "value": value.clone() },
+                    "in": {
+                        "$cond": {
+                            "if": { "$isArray": "$$value" },
+                            "then": { "$slice": ["$$value", max_array_length as i64] },
+                            "else": "$$value",
+                        }
+                    },
+                }
+            }
+            .into();
+        }
+    }
+}
+
+fn is_array_type(t: &Type) -> bool {
+    match t {
+        Type::ArrayOf(_) => true,
+        Type::Nullable(t) => is_array_type(t),
+        _ => false,
+    }
+}
+
+/// Stage that follows the selection of rows in the top-level query. A row that would exceed the
+/// size limit is replaced with a description of its size, and the size of each of its fields.
+pub fn row_size_guard_stage(config: &MongoConfiguration, query_plan: &QueryPlan) -> Option<Stage> {
+    config.large_document_options()?;
+    if query_plan.has_variables() || query_plan.query.has_aggregates() {
+        return None;
+    }
+    Some(Stage::Other(doc! {
+        "$replaceWith": {
+            "$cond": {
+                "if": { "$gt": [{ "$bsonSize": "$$ROOT" }, MAX_ROW_SIZE] },
+                "then": {
+                    OVERSIZED_ROW_FIELD: {
+                        "size": { "$bsonSize": "$$ROOT" },
+                        "fields": {
+                            "$map": {
+                                "input": { "$objectToArray": "$$ROOT" },
+                                "in": {
+                                    "field": "$$this.k",
+                                    "size": { "$bsonSize": { "$arrayToObject": [["$$this"]] } },
+                                },
+                            }
+                        },
+                    }
+                },
+                "else": "$$ROOT",
+            }
+        }
+    }))
+}
+
+/// Fails if any row was replaced by [row_size_guard_stage]
+pub fn check_row_sizes(documents: &[RawDocumentBuf]) -> Result<(), DocumentTooLargeError> {
+    for document in documents {
+        let Ok(Some(RawBsonRef::Document(oversized))) = document.get(OVERSIZED_ROW_FIELD) else {
+            continue;
+        };
+        let oversized: Document = oversized.try_into().unwrap_or_default();
+        let size = oversized.get("size").and_then(as_i64).unwrap_or_default();
+        let mut fields: Vec<FieldSize> = oversized
+            .get_array("fields")
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let entry = entry.as_document()?;
+                Some(FieldSize {
+                    field: entry.get_str("field").ok()?.to_owned(),
+                    size: entry.get("size").and_then(as_i64)?,
+                })
+            })
+            .collect();
+        fields.sort_by(|a, b| b.size.cmp(&a.size));
+        return Err(DocumentTooLargeError {
+            causes: vec![DocumentSizeCause::Row { fields }],
+            server_message: format!(
+                "a row of {size} bytes exceeds the limit of {MAX_ROW_SIZE} bytes"
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(n) => Some(*n as i64),
+        Bson::Int64(n) => Some(*n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ConfigurationLargeDocumentOptions;
+    use mongodb::bson::{doc, RawDocumentBuf};
+    use ndc_query_plan::plan_for_query_request;
+    use ndc_test_helpers::{
+        array, field, object, query, query_request, relation_field, star_count_aggregate,
+    };
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        mongodb::Selection,
+        test_helpers::{chinook_config, chinook_relationships, make_nested_schema},
+    };
+
+    use super::{
+        check_row_sizes, document_size_causes, slice_array_fields, DocumentSizeCause, FieldSize,
+    };
+
+    #[test]
+    fn identifies_relationship_that_produced_oversized_document() -> anyhow::Result<()> {
+        let config = chinook_config();
+        let query_plan = plan_for_query_request(
+            &config,
+            query_request()
+                .collection("Artist")
+                .query(
+                    query()
+                        .fields([
+                            field!("name" => "Name"),
+                            relation_field!("albums" => "Albums", query().fields([
+                                field!("title" => "Title"),
+                                relation_field!("tracks" => "Tracks", query().fields([
+                                    field!("name" => "Name"),
+                                ])),
+                            ])),
+                        ])
+                        .aggregates([star_count_aggregate!("count")]),
+                )
+                .relationships(chinook_relationships())
+                .into(),
+        )?;
+
+        let causes = document_size_causes(
+            &config,
+            &query_plan,
+            "Total size of documents in Track matching pipeline's $lookup stage exceeds 104857600 bytes",
+        );
+        assert_eq!(
+            causes,
+            vec![DocumentSizeCause::Relationship {
+                path: "albums.tracks".to_owned()
+            }]
+        );
+
+        let causes = document_size_causes(
+            &config,
+            &query_plan,
+            "document constructed by $facet is 16777300 bytes, which exceeds the limit of 16777216 bytes",
+        );
+        assert_eq!(causes, vec![DocumentSizeCause::Facet { path: None }]);
+
+        let causes =
+            document_size_causes(&config, &query_plan, "BSONObj size: 16793700 is invalid");
+        assert_eq!(
+            causes,
+            vec![
+                DocumentSizeCause::Facet { path: None },
+                DocumentSizeCause::Relationship {
+                    path: "albums".to_owned()
+                },
+                DocumentSizeCause::Relationship {
+                    path: "albums.tracks".to_owned()
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn truncates_selected_arrays() -> anyhow::Result<()> {
+        let mut config = make_nested_schema();
+        config.0.options.query_options.large_documents = Some(ConfigurationLargeDocumentOptions {
+            max_array_length: Some(100),
+        });
+        let query_plan = plan_for_query_request(
+            &config,
+            query_request()
+                .collection("authors")
+                .query(query().fields([
+                    field!("name"),
+                    field!("articles" => "articles", array!(object!([field!("title")]))),
+                ]))
+                .into(),
+        )?;
+        let fields = query_plan.query.fields.as_ref().unwrap();
+        let mut selection = Selection::from_fields(fields)?.0;
+        let articles = selection.get("articles").cloned();
+        slice_array_fields(&config, fields, &mut selection);
+        assert_eq!(
+            selection,
+            doc! {
+                "name": { "$ifNull": ["$name", null] },
+                "articles": {
+                    "$let": {
+                        "vars": { "value": articles },
+                        "in": {
+                            "$cond": {
+                                "if": { "$isArray": "$$value" },
+                                "then": { "$slice": ["$$value", 100_i64] },
+                                "else": "$$value",
+                            }
+                        },
+                    }
+                },
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reports_largest_fields_of_oversized_rows() -> anyhow::Result<()> {
+        let documents = vec![
+            RawDocumentBuf::from_document(&doc! { "name": "small" })?,
+            RawDocumentBuf::from_document(&doc! {
+                "__oversized_row": {
+                    "size": 17_000_000_i64,
+                    "fields": [
+                        { "field": "name", "size": 20 },
+                        { "field": "tracks", "size": 16_999_000 },
+                    ],
+                }
+            })?,
+        ];
+        let error = check_row_sizes(&documents).unwrap_err();
+        assert_eq!(
+            error.causes,
+            vec![DocumentSizeCause::Row {
+                fields: vec![
+                    FieldSize {
+                        field: "tracks".to_owned(),
+                        size: 16_999_000
+                    },
+                    FieldSize {
+                        field: "name".to_owned(),
+                        size: 20
+                    },
+                ]
+            }]
+        );
+        Ok(())
+    }
+}
@@ -1,33 +1,169 @@
+use std::{pin::pin, time::Instant};
+
+use bytes::Bytes;
 use futures::Stream;
 use futures_util::TryStreamExt as _;
-use mongodb::bson;
-use ndc_models::{QueryRequest, QueryResponse};
+use mongodb::{
+    bson::{RawArrayBuf, RawDocumentBuf},
+    options::AggregateOptions,
+};
+use ndc_models::QueryRequest;
 use ndc_query_plan::plan_for_query_request;
 use tracing::{instrument, Instrument};
 
-use super::{pipeline::pipeline_for_query_request, response::serialize_query_response};
+use super::{
+    count::{execute_count_command, CountCommand},
+    cursor_batch_size::{cursor_batch_size, record_first_batch, CursorBatchSizes},
+    document_size::{check_row_sizes, with_document_size_context},
+    find::{execute_find_command, FindCommand},
+    foreach::pipelines_for_variable_sets,
+    lookup_function::{execute_lookup_request, LookupRequest},
+    memory_budget::MemoryBudget,
+    pipeline::is_response_faceted,
+    pipeline_size::pipelines_within_size_limit,
+    response::serialize_query_response,
+};
 use crate::{
     interface_types::MongoAgentError,
     mongo_query_plan::{MongoConfiguration, QueryPlan},
     mongodb::{CollectionTrait as _, DatabaseTrait, Pipeline},
-    query::QueryTarget,
+    monitoring::{PipelineIssued, PlanBuilt, QueryObservers, ResponseReceived},
+    post_processing::ResponsePostProcessors,
+    query::{apply_collection_policies, CollectionArguments, QueryTarget},
 };
 
 type Result<T> = std::result::Result<T, MongoAgentError>;
 
-/// Execute a query request against the given collection.
+/// Execute a query request against the given collection. The response is returned as serialized
+/// JSON.
 ///
 /// The use of `DatabaseTrait` lets us inject a mock implementation of the MongoDB driver for
 /// testing.
 pub async fn execute_query_request(
     database: impl DatabaseTrait,
     config: &MongoConfiguration,
+    observers: &QueryObservers,
+    post_processors: &ResponsePostProcessors,
+    batch_sizes: &CursorBatchSizes,
     query_request: QueryRequest,
-) -> Result<QueryResponse> {
-    let query_plan = preprocess_query_request(config, query_request)?;
-    let pipeline = pipeline_for_query_request(config, &query_plan)?;
-    let documents = execute_query_pipeline(database, config, &query_plan, pipeline).await?;
-    let response = serialize_query_response(config.extended_json_mode(), &query_plan, documents)?;
+) -> Result<Bytes> {
+    let mut query_plan = preprocess_query_request(config, query_request)?;
+    observers.notify(|observer| {
+        observer.plan_built(&PlanBuilt {
+            query_plan: &query_plan,
+        })
+    });
+    if let Some(lookup_request) = LookupRequest::for_query_plan(config, &query_plan) {
+        let documents =
+            execute_lookup_request(database, config, observers, &query_plan, lookup_request)
+                .await?;
+        let response = serialize_query_response(
+            config.serialization_options(),
+            config.object_id_formats(),
+            post_processors,
+            &query_plan,
+            documents,
+        )?;
+        return Ok(response);
+    }
+    let mut collection_arguments = CollectionArguments::for_request(config, &query_plan)?;
+    collection_arguments.batch_size = config
+        .cursor_batch_size()
+        .map(|options| cursor_batch_size(batch_sizes, options, &query_plan.collection));
+    collection_arguments.apply_limit(&mut query_plan.query);
+    apply_collection_policies(config, &query_plan.collection, &mut query_plan.query);
+    if let Some(count_command) =
+        CountCommand::for_query_plan(config, &query_plan, &collection_arguments)?
+    {
+        let documents = execute_count_command(database, observers, count_command).await?;
+        let response = serialize_query_response(
+            config.serialization_options(),
+            config.object_id_formats(),
+            post_processors,
+            &query_plan,
+            documents,
+        )?;
+        return Ok(response);
+    }
+    if let Some(find_command) =
+        FindCommand::for_query_plan(config, &query_plan, &collection_arguments)?
+    {
+        let documents = execute_find_command(database, observers, find_command).await?;
+        let response = serialize_query_response(
+            config.serialization_options(),
+            config.object_id_formats(),
+            post_processors,
+            &query_plan,
+            documents,
+        )?;
+        return Ok(response);
+    }
+    // Rows are counted against the memory budget as they arrive so that a query that returns too
+    // much data fails before all of it is buffered
+    let mut budget = MemoryBudget::new(config.serialization_options().max_response_memory_bytes);
+    let documents = match (&query_plan.variables, config.compatibility_mode()) {
+        (Some(variable_sets), true) => {
+            let pipelines = pipelines_for_variable_sets(variable_sets, config, &query_plan)?;
+            let options = collection_arguments.aggregate_options(true);
+            execute_pipelines_for_variable_sets(
+                database,
+                config,
+                observers,
+                &query_plan,
+                pipelines,
+                options,
+                &mut budget,
+            )
+            .await
+            .map_err(|err| with_document_size_context(config, &query_plan, err))?
+        }
+        _ => {
+            let pipelines = pipelines_within_size_limit(config, &query_plan)?;
+            let options = collection_arguments.aggregate_options(!query_plan.has_variables());
+            let mut documents = vec![];
+            for pipeline in pipelines {
+                documents.extend(
+                    execute_query_pipeline(
+                        &database,
+                        config,
+                        observers,
+                        &query_plan,
+                        pipeline,
+                        options.clone(),
+                        &mut budget,
+                    )
+                    .await
+                    .map_err(|err| with_document_size_context(config, &query_plan, err))?,
+                );
+            }
+            documents
+        }
+    };
+    if config.large_document_options().is_some() {
+        check_row_sizes(&documents)?;
+    }
+    if let (Some(options), Some(batch_size)) =
+        (config.cursor_batch_size(), collection_arguments.batch_size)
+    {
+        // Faceted responses and responses to queries with variables are not rows of the target
+        // collection, so their sizes say nothing about the collection's documents.
+        if !query_plan.has_variables() && !is_response_faceted(&query_plan.query) {
+            record_first_batch(
+                batch_sizes,
+                options,
+                &query_plan.collection,
+                batch_size,
+                &documents,
+            );
+        }
+    }
+    let response = serialize_query_response(
+        config.serialization_options(),
+        config.object_id_formats(),
+        post_processors,
+        &query_plan,
+        documents,
+    )?;
     Ok(response)
 }
 
@@ -42,11 +178,14 @@ fn preprocess_query_request(
 
 #[instrument(name = "Execute Query Pipeline", skip_all, fields(internal.visibility = "user"))]
 async fn execute_query_pipeline(
-    database: impl DatabaseTrait,
+    database: &impl DatabaseTrait,
     config: &MongoConfiguration,
+    observers: &QueryObservers,
     query_plan: &QueryPlan,
     pipeline: Pipeline,
-) -> Result<Vec<bson::Document>> {
+    options: Option<AggregateOptions>,
+    budget: &mut MemoryBudget,
+) -> Result<Vec<RawDocumentBuf>> {
     let target = QueryTarget::for_request(config, query_plan);
     tracing::debug!(
         ?target,
@@ -54,6 +193,17 @@ async fn execute_query_pipeline(
         "executing query"
     );
 
+    let issued_to = target
+        .input_collection()
+        .filter(|_| !query_plan.has_variables());
+    observers.notify(|observer| {
+        observer.pipeline_issued(&PipelineIssued {
+            collection: issued_to,
+            pipeline: &pipeline,
+        })
+    });
+    let start = Instant::now();
+
     // The target of a query request might be a collection, or it might be a native query. In the
     // latter case there is no collection to perform the aggregation against. So instead of sending
     // the MongoDB API call `db.<collection>.aggregate` we instead call `db.aggregate`.
@@ -66,43 +216,139 @@ async fn execute_query_pipeline(
             let collection = database.collection(collection_name.as_str());
             collect_response_documents(
                 collection
-                    .aggregate(pipeline, None)
+                    .aggregate(pipeline, options)
                     .instrument(tracing::info_span!(
                         "MongoDB Aggregate Command",
                         internal.visibility = "user"
                     ))
                     .await?,
+                budget,
             )
             .await
         }
         _ => {
             collect_response_documents(
                 database
-                    .aggregate(pipeline, None)
+                    .aggregate(pipeline, options)
                     .instrument(tracing::info_span!(
                         "MongoDB Aggregate Command",
                         internal.visibility = "user"
                     ))
                     .await?,
+                budget,
             )
             .await
         }
     }?;
+    observers.notify(|observer| {
+        observer.response_received(&ResponseReceived {
+            collection: issued_to,
+            documents: documents.len(),
+            elapsed: start.elapsed(),
+        })
+    });
     tracing::debug!(response_documents = %serde_json::to_string(&documents).unwrap(), "response from MongoDB");
     Ok(documents)
 }
 
+/// Runs one pipeline per variable set, and combines the results of each into a document of the
+/// same shape that the pipeline produced by [super::foreach::pipeline_for_foreach] would produce
+/// for that variable set.
+#[instrument(name = "Execute Query Pipelines for Variable Sets", skip_all, fields(internal.visibility = "user"))]
+async fn execute_pipelines_for_variable_sets(
+    database: impl DatabaseTrait,
+    config: &MongoConfiguration,
+    observers: &QueryObservers,
+    query_plan: &QueryPlan,
+    pipelines: Vec<Pipeline>,
+    options: Option<AggregateOptions>,
+    budget: &mut MemoryBudget,
+) -> Result<Vec<RawDocumentBuf>> {
+    let target = QueryTarget::for_request(config, query_plan);
+    let mut row_sets = Vec::with_capacity(pipelines.len());
+    for pipeline in pipelines {
+        tracing::debug!(
+            ?target,
+            pipeline = %serde_json::to_string(&pipeline).unwrap(),
+            "executing query for variable set"
+        );
+        observers.notify(|observer| {
+            observer.pipeline_issued(&PipelineIssued {
+                collection: target.input_collection(),
+                pipeline: &pipeline,
+            })
+        });
+        let start = Instant::now();
+        let documents = match target.input_collection() {
+            Some(collection_name) => {
+                let collection = database.collection(collection_name.as_str());
+                collect_response_documents(
+                    collection
+                        .aggregate(pipeline, options.clone())
+                        .instrument(tracing::info_span!(
+                            "MongoDB Aggregate Command",
+                            internal.visibility = "user"
+                        ))
+                        .await?,
+                    budget,
+                )
+                .await
+            }
+            None => {
+                collect_response_documents(
+                    database
+                        .aggregate(pipeline, options.clone())
+                        .instrument(tracing::info_span!(
+                            "MongoDB Aggregate Command",
+                            internal.visibility = "user"
+                        ))
+                        .await?,
+                    budget,
+                )
+                .await
+            }
+        }?;
+        observers.notify(|observer| {
+            observer.response_received(&ResponseReceived {
+                collection: target.input_collection(),
+                documents: documents.len(),
+                elapsed: start.elapsed(),
+            })
+        });
+        let row_set = if is_response_faceted(&query_plan.query) {
+            documents.into_iter().next().unwrap_or_default()
+        } else {
+            let mut rows = RawArrayBuf::new();
+            for document in documents {
+                rows.push(document);
+            }
+            let mut row_set = RawDocumentBuf::new();
+            row_set.append("rows", rows);
+            row_set
+        };
+        row_sets.push(row_set);
+    }
+    tracing::debug!(response_documents = %serde_json::to_string(&row_sets).unwrap(), "response from MongoDB");
+    Ok(row_sets)
+}
+
 #[instrument(name = "Collect Response Documents", skip_all, fields(internal.visibility = "user"))]
 async fn collect_response_documents(
-    document_cursor: impl Stream<Item = std::result::Result<bson::Document, mongodb::error::Error>>,
-) -> Result<Vec<bson::Document>> {
-    document_cursor
-        .into_stream()
-        .map_err(MongoAgentError::MongoDB)
-        .try_collect::<Vec<_>>()
-        .instrument(tracing::info_span!(
-            "Collect Pipeline",
-            internal.visibility = "user"
-        ))
-        .await
+    document_cursor: impl Stream<Item = std::result::Result<RawDocumentBuf, mongodb::error::Error>>,
+    budget: &mut MemoryBudget,
+) -> Result<Vec<RawDocumentBuf>> {
+    async {
+        let mut document_cursor = pin!(document_cursor);
+        let mut documents = vec![];
+        while let Some(document) = document_cursor.try_next().await? {
+            budget.charge_row(&document)?;
+            documents.push(document);
+        }
+        Ok::<_, MongoAgentError>(documents)
+    }
+    .instrument(tracing::info_span!(
+        "Collect Pipeline",
+        internal.visibility = "user"
+    ))
+    .await
 }
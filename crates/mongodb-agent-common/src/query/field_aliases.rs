@@ -0,0 +1,157 @@
+//! Reads renamed fields from their previous keys in documents that have not been migrated yet.
+//! See [configuration::schema::ObjectField::previous_names].
+
+use std::collections::BTreeMap;
+
+use mongodb::bson::{doc, Bson, Document};
+
+use crate::{
+    mongo_query_plan::{MongoConfiguration, QueryPlan},
+    mongodb::Stage,
+};
+
+/// Previous names of fields of the queried collection, if there are any
+pub fn field_aliases_for_query<'a>(
+    config: &'a MongoConfiguration,
+    query_plan: &QueryPlan,
+) -> Option<&'a BTreeMap<ndc_models::FieldName, Vec<ndc_models::FieldName>>> {
+    field_aliases_for_collection(config, &query_plan.collection)
+}
+
+/// Previous names of fields of the given collection, if there are any
+pub fn field_aliases_for_collection<'a>(
+    config: &'a MongoConfiguration,
+    collection: &ndc_models::CollectionName,
+) -> Option<&'a BTreeMap<ndc_models::FieldName, Vec<ndc_models::FieldName>>> {
+    config
+        .collection_policies(collection)
+        .map(|policies| &policies.field_aliases)
+        .filter(|field_aliases| !field_aliases.is_empty())
+}
+
+/// `$addFields` stage that sets each renamed field to the value stored under its current name, or
+/// under the first of its previous names that is set. This runs before any other stage so that
+/// filters, sorts, joins, and projections see the field under its current name.
+pub fn field_aliases_stage(
+    config: &MongoConfiguration,
+    collection: &ndc_models::CollectionName,
+) -> Option<Stage> {
+    let field_aliases = field_aliases_for_collection(config, collection)?;
+    let fields: Document = field_aliases
+        .iter()
+        .map(|(name, previous_names)| {
+            // Two-argument `$ifNull` is nested instead of using the variadic form which requires
+            // MongoDB 5.0
+            let value = previous_names
+                .iter()
+                .rev()
+                .map(|previous_name| Bson::String(format!("${previous_name}")))
+                .reduce(|fallback, previous| doc! { "$ifNull": [previous, fallback] }.into());
+            let value = match value {
+                Some(fallback) => doc! { "$ifNull": [format!("${name}"), fallback] }.into(),
+                None => Bson::String(format!("${name}")),
+            };
+            (name.to_string(), value)
+        })
+        .collect();
+    Some(Stage::Other(doc! { "$addFields": fields }))
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, ObjectField, ObjectType, Type},
+        serialized::Schema,
+        Configuration,
+    };
+    use mongodb::bson::bson;
+    use mongodb_support::BsonScalarType;
+    use ndc_models::QueryResponse;
+    use ndc_test_helpers::{binop, field, query, query_request, row_set, target, value};
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        mongo_query_plan::MongoConfiguration,
+        mongodb::test_helpers::mock_collection_aggregate_response_for_pipeline,
+        query::execute_query_request,
+    };
+
+    fn users_config() -> anyhow::Result<MongoConfiguration> {
+        let (email_name, mut email) =
+            ObjectField::new("email", Type::Scalar(BsonScalarType::String));
+        email.previous_names = vec!["emailAddress".into(), "mail".into()];
+        let schema = Schema {
+            collections: [(
+                "users".into(),
+                Collection {
+                    r#type: "users".into(),
+                    description: None,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "users".into(),
+                ObjectType {
+                    fields: [
+                        ObjectField::new("name", Type::Scalar(BsonScalarType::String)),
+                        (email_name, email),
+                    ]
+                    .into_iter()
+                    .map(|(name, field)| (name.into(), field))
+                    .collect(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        Ok(MongoConfiguration(Configuration::from_schema(schema)?))
+    }
+
+    #[tokio::test]
+    async fn reads_renamed_field_from_previous_names() -> anyhow::Result<()> {
+        let query_request = query_request()
+            .collection("users")
+            .query(query().fields([field!("email")]).predicate(binop(
+                "_eq",
+                target!("email"),
+                value!("ada@example.com"),
+            )))
+            .into();
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "users",
+            bson!([
+                {
+                    "$addFields": {
+                        "email": {
+                            "$ifNull": ["$email", { "$ifNull": ["$emailAddress", "$mail"] }]
+                        },
+                    },
+                },
+                { "$match": { "email": { "$eq": "ada@example.com" } } },
+                { "$replaceWith": { "email": { "$ifNull": ["$email", null] } } },
+            ]),
+            bson!([{ "email": "ada@example.com" }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &users_config()?,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .rows([[("email", "ada@example.com")]])
+                .into_response()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+//! Execution strategy for simple queries that runs a `find` command instead of an aggregation
+//! pipeline. This is used when the `queryOptions.findFastPath` configuration option is set, and the
+//! query targets a regular collection without relationships, aggregates, or variables. A `find`
+//! skips pipeline parsing and optimization on the server, and the response documents have the same
+//! shape as rows produced by the pipeline so response serialization is shared.
+
+use std::time::Instant;
+
+use futures_util::TryStreamExt as _;
+use mongodb::{
+    bson::{doc, Document, RawDocumentBuf},
+    options::FindOptions,
+};
+use tracing::{instrument, Instrument as _};
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::{MongoConfiguration, QueryPlan},
+    mongodb::{CollectionTrait as _, DatabaseTrait, Pipeline, Selection, Stage},
+    monitoring::{PipelineIssued, QueryObservers, ResponseReceived},
+};
+
+use super::{
+    field_aliases::field_aliases_for_query, gap_fill::gap_fill_for_query, make_selector,
+    pipeline::sort_document, read_transforms::read_transforms_for_query,
+    soft_delete::with_soft_delete_filter, window_fields::window_fields_for_query,
+    CollectionArguments, QueryTarget,
+};
+
+type Result<T> = std::result::Result<T, MongoAgentError>;
+
+#[derive(Clone, Debug)]
+pub struct FindCommand {
+    pub collection: ndc_models::CollectionName,
+    pub filter: Document,
+    pub options: FindOptions,
+}
+
+impl FindCommand {
+    /// Produces a find command if the fast path is enabled and the query is simple enough to run
+    /// as a find. Expects the `limit` collection argument to have already been applied to the
+    /// query plan.
+    pub fn for_query_plan(
+        config: &MongoConfiguration,
+        query_plan: &QueryPlan,
+        collection_arguments: &CollectionArguments,
+    ) -> Result<Option<FindCommand>> {
+        if !config.find_fast_path() {
+            return Ok(None);
+        }
+        let QueryTarget::Collection(collection) = QueryTarget::for_request(config, query_plan)
+        else {
+            return Ok(None);
+        };
+        let query = &query_plan.query;
+        let is_simple_query = query_plan.variables.is_none()
+            && query_plan.unrelated_collections.is_empty()
+            && gap_fill_for_query(config, query_plan).is_none()
+            && field_aliases_for_query(config, query_plan).is_none()
+            && read_transforms_for_query(config, query_plan).is_none()
+            && window_fields_for_query(config, query_plan).is_none()
+            && config.large_document_options().is_none()
+            && query.relationships.is_empty()
+            && !query.has_aggregates();
+        let fields = match &query.fields {
+            Some(fields) if is_simple_query && !fields.is_empty() => fields,
+            _ => return Ok(None),
+        };
+
+        let filter = query
+            .predicate
+            .as_ref()
+            .map(|predicate| make_selector(config, predicate))
+            .transpose()?
+            .unwrap_or_default();
+        let filter = with_soft_delete_filter(config, query_plan, filter)?;
+
+        // Find projections accept the same aggregation expressions that the pipeline uses in its
+        // `$replaceWith` stage. Unlike `$replaceWith` a projection includes `_id` unless it is
+        // excluded explicitly.
+        let mut projection: Document = Selection::from_fields(fields)?.into();
+        if !projection.contains_key("_id") {
+            projection.insert("_id", 0);
+        }
+
+        let mut options = collection_arguments.find_options();
+        options.projection = Some(projection);
+        options.sort = sort_document(config, query)?;
+        options.skip = query.offset.map(u64::from);
+        options.limit = query.limit.map(i64::from);
+
+        Ok(Some(FindCommand {
+            collection,
+            filter,
+            options,
+        }))
+    }
+
+    /// An aggregation pipeline that produces the same documents. Query observers are given this
+    /// pipeline when the command is issued.
+    pub fn equivalent_pipeline(&self) -> Pipeline {
+        let mut pipeline = Pipeline::new(vec![Stage::Match(self.filter.clone())]);
+        if let Some(sort) = &self.options.sort {
+            pipeline.push(Stage::Sort(sort.clone()));
+        }
+        if let Some(skip) = self.options.skip {
+            pipeline.push(Stage::Skip(u32::try_from(skip).unwrap_or(u32::MAX)));
+        }
+        if let Some(limit) = self.options.limit {
+            let limit = u32::try_from(limit.unsigned_abs()).unwrap_or(u32::MAX);
+            pipeline.push(Stage::Limit(limit));
+        }
+        if let Some(projection) = &self.options.projection {
+            pipeline.push(Stage::Other(doc! { "$project": projection }));
+        }
+        pipeline
+    }
+}
+
+/// Runs a find command, and notifies observers as though the equivalent pipeline was issued
+pub(super) async fn find_and_notify(
+    database: impl DatabaseTrait,
+    observers: &QueryObservers,
+    command: FindCommand,
+) -> Result<Vec<RawDocumentBuf>> {
+    let pipeline = command.equivalent_pipeline();
+    let FindCommand {
+        collection,
+        filter,
+        options,
+    } = command;
+    observers.notify(|observer| {
+        observer.pipeline_issued(&PipelineIssued {
+            collection: Some(&collection),
+            pipeline: &pipeline,
+        })
+    });
+    let start = Instant::now();
+    let documents: Vec<RawDocumentBuf> = database
+        .collection(collection.as_str())
+        .find(filter, options)
+        .instrument(tracing::info_span!(
+            "MongoDB Find Command",
+            internal.visibility = "user"
+        ))
+        .await?
+        .try_collect()
+        .await?;
+    observers.notify(|observer| {
+        observer.response_received(&ResponseReceived {
+            collection: Some(&collection),
+            documents: documents.len(),
+            elapsed: start.elapsed(),
+        })
+    });
+    Ok(documents)
+}
+
+#[instrument(name = "Execute Find Command", skip_all, fields(internal.visibility = "user"))]
+pub async fn execute_find_command(
+    database: impl DatabaseTrait,
+    observers: &QueryObservers,
+    command: FindCommand,
+) -> Result<Vec<RawDocumentBuf>> {
+    tracing::debug!(
+        collection = %command.collection,
+        filter = %serde_json::to_string(&command.filter).unwrap(),
+        "executing find command"
+    );
+    find_and_notify(database, observers, command).await
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{Configuration, ConfigurationOptions, ConfigurationQueryOptions};
+    use mongodb::{
+        bson::{doc, rawdoc, Document},
+        options::FindOptions,
+    };
+    use ndc_models::{OrderByElement, OrderByTarget, OrderDirection, QueryResponse};
+    use ndc_test_helpers::{
+        binop, collection, field, named_type, object_type, query, query_request, row_set, target,
+        value,
+    };
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        mongo_query_plan::MongoConfiguration,
+        mongodb::{test_helpers::mock_stream, MockCollectionTrait, MockDatabaseTrait},
+        query::execute_query_request,
+    };
+
+    #[tokio::test]
+    async fn executes_simple_query_with_find() -> anyhow::Result<()> {
+        let query_request = query_request()
+            .collection("students")
+            .query(
+                query()
+                    .fields([field!("student_gpa" => "gpa")])
+                    .predicate(binop("_lt", target!("gpa"), value!(4.0)))
+                    .order_by(vec![OrderByElement {
+                        order_direction: OrderDirection::Desc,
+                        target: OrderByTarget::Column {
+                            name: "gpa".into(),
+                            field_path: None,
+                            path: vec![],
+                        },
+                    }])
+                    .limit(10),
+            )
+            .into();
+
+        let mut db = MockDatabaseTrait::new();
+        db.expect_collection().returning(|name| {
+            assert_eq!(name, "students");
+            let mut collection = MockCollectionTrait::new();
+            collection
+                .expect_find()
+                .returning(|filter: Document, options: FindOptions| {
+                    assert_eq!(filter, doc! { "gpa": { "$lt": 4.0 } });
+                    assert_eq!(
+                        options.projection,
+                        Some(doc! {
+                            "student_gpa": { "$ifNull": ["$gpa", null] },
+                            "_id": 0,
+                        })
+                    );
+                    assert_eq!(options.sort, Some(doc! { "gpa": -1 }));
+                    assert_eq!(options.limit, Some(10));
+                    Ok(mock_stream(vec![
+                        Ok(rawdoc! { "student_gpa": 3.6 }),
+                        Ok(rawdoc! { "student_gpa": 3.1 }),
+                    ]))
+                });
+            collection
+        });
+
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .rows([[("student_gpa", 3.6)], [("student_gpa", 3.1)]])
+                .into_response()
+        );
+        Ok(())
+    }
+
+    fn students_config() -> MongoConfiguration {
+        MongoConfiguration(Configuration {
+            collections: [collection("students")].into(),
+            object_types: [(
+                "students".into(),
+                object_type([("gpa", named_type("Double"))]),
+            )]
+            .into(),
+            options: ConfigurationOptions {
+                query_options: ConfigurationQueryOptions {
+                    find_fast_path: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+}
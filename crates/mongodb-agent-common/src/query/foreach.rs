@@ -4,6 +4,7 @@ use itertools::Itertools as _;
 use mongodb::bson::{self, doc, Bson};
 use ndc_query_plan::VariableSet;
 
+use super::compatibility::{bind_variables, rewrite_pipeline};
 use super::pipeline::pipeline_for_non_foreach;
 use super::query_level::QueryLevel;
 use super::query_variable_name::query_variable_name;
@@ -70,6 +71,27 @@ pub fn pipeline_for_foreach(
     })
 }
 
+/// Produces a separate pipeline for each variable set for servers that do not support the
+/// `$documents` stage. This is used in compatibility mode. References to variables are replaced
+/// with literal values, and the pipelines are rewritten by [rewrite_pipeline].
+pub fn pipelines_for_variable_sets(
+    request_variable_sets: &[VariableSet],
+    config: &MongoConfiguration,
+    query_request: &QueryPlan,
+) -> Result<Vec<Pipeline>> {
+    let variable_sets =
+        variable_sets_to_bson(request_variable_sets, &query_request.variable_types)?;
+    let query_pipeline = rewrite_pipeline(pipeline_for_non_foreach(
+        config,
+        query_request,
+        QueryLevel::Top,
+    )?)?;
+    variable_sets
+        .iter()
+        .map(|variables| bind_variables(query_pipeline.clone(), variables))
+        .try_collect()
+}
+
 fn variable_sets_to_bson(
     variable_sets: &[VariableSet],
     variable_types: &VariableTypes,
@@ -196,7 +218,16 @@ mod tests {
             ]),
         );
 
-        let result = execute_query_request(db, &music_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &music_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
 
         Ok(())
@@ -311,7 +342,16 @@ mod tests {
             ]),
         );
 
-        let result = execute_query_request(db, &music_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &music_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
 
         Ok(())
@@ -395,7 +435,16 @@ mod tests {
             ]),
         );
 
-        let result = execute_query_request(db, &music_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &music_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
 
         Ok(())
@@ -492,7 +541,16 @@ mod tests {
             ]),
         );
 
-        let result = execute_query_request(db, &music_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &music_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
 
         Ok(())
@@ -514,6 +572,10 @@ mod tests {
             procedures: Default::default(),
             native_mutations: Default::default(),
             native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
             options: Default::default(),
         })
     }
@@ -0,0 +1,206 @@
+//! Fills gaps in date or numeric series for collections that declare a `gapFill` setting. See
+//! [configuration::schema::Collection::gap_fill].
+
+use configuration::schema::{FillMethod, GapFill, GapFillUnit};
+use mongodb::bson::{doc, Bson, Document};
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::{MongoConfiguration, QueryPlan},
+    mongodb::Stage,
+};
+
+/// Gap filling settings for the queried collection, if there are any
+pub fn gap_fill_for_query<'a>(
+    config: &'a MongoConfiguration,
+    query_plan: &QueryPlan,
+) -> Option<&'a GapFill> {
+    config
+        .collection_policies(&query_plan.collection)
+        .and_then(|policies| policies.gap_fill.as_ref())
+}
+
+/// `$densify` and `$fill` stages for the queried collection, or an empty list if the collection
+/// does not declare gap filling
+pub fn gap_fill_stages(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+) -> Result<Vec<Stage>, MongoAgentError> {
+    match gap_fill_for_query(config, query_plan) {
+        Some(gap_fill) => stages_for_gap_fill(gap_fill),
+        None => Ok(vec![]),
+    }
+}
+
+fn stages_for_gap_fill(gap_fill: &GapFill) -> Result<Vec<Stage>, MongoAgentError> {
+    let field = gap_fill.field.as_str();
+    let partition_by_fields: Vec<&str> = gap_fill
+        .partition_by
+        .iter()
+        .map(|name| name.as_str())
+        .collect();
+
+    let mut range = doc! {
+        "step": gap_fill.step,
+        "bounds": if partition_by_fields.is_empty() { "full" } else { "partition" },
+    };
+    if let Some(unit) = gap_fill.unit {
+        range.insert("unit", unit_name(unit));
+    }
+    let mut densify = doc! { "field": field, "range": range };
+    if !partition_by_fields.is_empty() {
+        densify.insert("partitionByFields", partition_by_fields.clone());
+    }
+    let mut stages = vec![Stage::Other(doc! { "$densify": densify })];
+
+    if !gap_fill.fill.is_empty() {
+        let output = gap_fill
+            .fill
+            .iter()
+            .map(|(name, method)| Ok((name.to_string(), Bson::Document(fill_output(method)?))))
+            .collect::<Result<Document, MongoAgentError>>()?;
+        let mut fill = doc! { "sortBy": { field: 1 }, "output": output };
+        if !partition_by_fields.is_empty() {
+            fill.insert("partitionByFields", partition_by_fields);
+        }
+        stages.push(Stage::Other(doc! { "$fill": fill }));
+    }
+    Ok(stages)
+}
+
+fn fill_output(method: &FillMethod) -> Result<Document, MongoAgentError> {
+    let output = match method {
+        FillMethod::Locf => doc! { "method": "locf" },
+        FillMethod::Linear => doc! { "method": "linear" },
+        FillMethod::Value(value) => {
+            let value =
+                Bson::try_from(value.clone()).map_err(|err| MongoAgentError::AdHoc(err.into()))?;
+            doc! { "value": { "$literal": value } }
+        }
+    };
+    Ok(output)
+}
+
+fn unit_name(unit: GapFillUnit) -> &'static str {
+    match unit {
+        GapFillUnit::Millisecond => "millisecond",
+        GapFillUnit::Second => "second",
+        GapFillUnit::Minute => "minute",
+        GapFillUnit::Hour => "hour",
+        GapFillUnit::Day => "day",
+        GapFillUnit::Week => "week",
+        GapFillUnit::Month => "month",
+        GapFillUnit::Quarter => "quarter",
+        GapFillUnit::Year => "year",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, FillMethod, GapFill, GapFillUnit, ObjectField, ObjectType, Type},
+        serialized::Schema,
+        Configuration,
+    };
+    use mongodb::bson::bson;
+    use mongodb_support::BsonScalarType;
+    use ndc_models::QueryResponse;
+    use ndc_test_helpers::{binop, field, query, query_request, row_set, target, value};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::{
+        mongo_query_plan::MongoConfiguration,
+        mongodb::test_helpers::mock_collection_aggregate_response_for_pipeline,
+        query::execute_query_request,
+    };
+
+    #[tokio::test]
+    async fn densifies_and_fills_after_predicate() -> anyhow::Result<()> {
+        let schema = Schema {
+            collections: [(
+                "readings".into(),
+                Collection {
+                    r#type: "readings".into(),
+                    description: None,
+                    gap_fill: Some(GapFill {
+                        field: "day".into(),
+                        step: 1,
+                        unit: Some(GapFillUnit::Day),
+                        partition_by: vec![],
+                        fill: [
+                            ("value".into(), FillMethod::Locf),
+                            ("status".into(), FillMethod::Value(json!("missing"))),
+                        ]
+                        .into(),
+                    }),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "readings".into(),
+                ObjectType {
+                    fields: [
+                        ObjectField::new("day", Type::Scalar(BsonScalarType::Date)),
+                        ObjectField::new("value", Type::Scalar(BsonScalarType::Double)),
+                        ObjectField::new("status", Type::Scalar(BsonScalarType::String)),
+                    ]
+                    .into_iter()
+                    .map(|(name, field)| (name.into(), field))
+                    .collect(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        let config = MongoConfiguration(Configuration::from_schema(schema)?);
+
+        let query_request = query_request()
+            .collection("readings")
+            .query(query().fields([field!("value")]).predicate(binop(
+                "_gt",
+                target!("value"),
+                value!(1.0),
+            )))
+            .into();
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "readings",
+            bson!([
+                { "$match": { "value": { "$gt": 1.0 } } },
+                {
+                    "$densify": {
+                        "field": "day",
+                        "range": { "step": 1_i64, "bounds": "full", "unit": "day" },
+                    }
+                },
+                {
+                    "$fill": {
+                        "sortBy": { "day": 1 },
+                        "output": {
+                            "status": { "value": { "$literal": "missing" } },
+                            "value": { "method": "locf" },
+                        },
+                    }
+                },
+                { "$replaceWith": { "value": { "$ifNull": ["$value", null] } } },
+            ]),
+            bson!([{ "value": 2.5 }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(result, row_set().rows([[("value", 2.5)]]).into_response());
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+//! Runs requests for generated lookup functions as `find` commands. See
+//! [configuration::lookup_function]. Requests that need more than a selection of fields from the
+//! looked-up document fall back to the aggregation pipeline of the native query that backs the
+//! function. Either way soft-deleted documents are excluded, and the other read policies of the
+//! looked-up collection are applied by the pipeline.
+
+use configuration::{lookup_function::LookupFunction, native_query::NativeQuery};
+use indexmap::IndexMap;
+use mongodb::{
+    bson::{doc, Bson, Document, RawBson, RawDocumentBuf},
+    options::FindOptions,
+};
+use tracing::instrument;
+
+use super::{
+    arguments::resolve_arguments,
+    field_aliases::{field_aliases_for_collection, field_aliases_stage},
+    find::{find_and_notify, FindCommand},
+    read_transforms::{read_transforms_for_collection, read_transforms_stage},
+    soft_delete::collection_soft_delete_filter,
+};
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::{Field, MongoConfiguration, NestedField, NestedObject, QueryPlan},
+    mongodb::{DatabaseTrait, Pipeline, Selection, Stage},
+    monitoring::QueryObservers,
+};
+
+/// A request for a lookup function that can be answered with a `find` command
+#[derive(Debug)]
+pub struct LookupRequest<'a> {
+    lookup_function: &'a LookupFunction,
+    native_query: &'a NativeQuery,
+
+    /// Alias of the `__value` field in the request
+    alias: &'a ndc_models::FieldName,
+
+    /// Fields selected from the looked-up document, or `None` to select the entire document
+    fields: Option<&'a IndexMap<ndc_models::FieldName, Field>>,
+}
+
+impl<'a> LookupRequest<'a> {
+    /// Returns a lookup request if the query plan targets a lookup function, and only selects
+    /// fields of the result.
+    pub fn for_query_plan(
+        config: &'a MongoConfiguration,
+        query_plan: &'a QueryPlan,
+    ) -> Option<LookupRequest<'a>> {
+        let collection = query_plan.collection.as_str();
+        let lookup_function = config.lookup_functions().get(collection)?;
+        let native_query = config.native_queries().get(collection)?;
+
+        // A `find` command cannot read renamed fields from their previous names, or compute
+        // transformed values, so those collections are read with the pipeline
+        if field_aliases_for_collection(config, &lookup_function.collection).is_some()
+            || read_transforms_for_collection(config, &lookup_function.collection).is_some()
+        {
+            return None;
+        }
+
+        let query = &query_plan.query;
+        let is_simple_query = query_plan.variables.is_none()
+            && query.aggregates.is_none()
+            && query.predicate.is_none()
+            && query.order_by.is_none()
+            && query.offset.is_none()
+            && query.relationships.is_empty();
+        if !is_simple_query {
+            return None;
+        }
+
+        let selected_fields = query.fields.as_ref()?;
+        if selected_fields.len() != 1 {
+            return None;
+        }
+        let (alias, field) = selected_fields.first()?;
+        let fields = match field {
+            Field::Column {
+                column,
+                fields: None,
+                ..
+            } if column.as_str() == "__value" => None,
+            Field::Column {
+                column,
+                fields: Some(NestedField::Object(NestedObject { fields })),
+                ..
+            } if column.as_str() == "__value" => Some(fields),
+            _ => return None,
+        };
+
+        Some(LookupRequest {
+            lookup_function,
+            native_query,
+            alias,
+            fields,
+        })
+    }
+}
+
+impl LookupRequest<'_> {
+    /// The `find` command that looks up the document. Soft-deleted documents are excluded.
+    pub fn find_command(
+        &self,
+        config: &MongoConfiguration,
+        query_plan: &QueryPlan,
+    ) -> Result<FindCommand, MongoAgentError> {
+        let arguments =
+            resolve_arguments(&self.native_query.arguments, query_plan.arguments.clone())?;
+        let key_filter = self
+            .lookup_function
+            .filter(|argument| arguments.get(argument).cloned().unwrap_or(Bson::Null));
+        let filter = match collection_soft_delete_filter(config, &self.lookup_function.collection) {
+            Some(soft_delete) => doc! { "$and": [key_filter, soft_delete] },
+            None => key_filter,
+        };
+
+        let projection = match self.fields {
+            Some(fields) => {
+                let mut projection: Document = Selection::from_fields(fields)?.into();
+                if !projection.contains_key("_id") {
+                    projection.insert("_id", 0);
+                }
+                Some(projection)
+            }
+            None => None,
+        };
+        let options = FindOptions::builder()
+            .projection(projection)
+            .limit(1)
+            .build();
+
+        Ok(FindCommand {
+            collection: self.lookup_function.collection.clone(),
+            filter,
+            options,
+        })
+    }
+}
+
+/// Looks up a single document with a `find` command. Produces a response document in the same
+/// form that the native query pipeline would produce.
+#[instrument(name = "Execute Lookup Function", skip_all, fields(internal.visibility = "user"))]
+pub async fn execute_lookup_request(
+    database: impl DatabaseTrait,
+    config: &MongoConfiguration,
+    observers: &QueryObservers,
+    query_plan: &QueryPlan,
+    request: LookupRequest<'_>,
+) -> Result<Vec<RawDocumentBuf>, MongoAgentError> {
+    let command = request.find_command(config, query_plan)?;
+    tracing::debug!(
+        collection = %command.collection,
+        filter = %serde_json::to_string(&command.filter).unwrap(),
+        "executing lookup function"
+    );
+    let documents = find_and_notify(database, observers, command).await?;
+
+    let value = match documents.into_iter().next() {
+        Some(document) => RawBson::Document(document),
+        None => RawBson::Null,
+    };
+    let mut response_document = RawDocumentBuf::new();
+    response_document.append(request.alias.as_str(), value);
+    Ok(vec![response_document])
+}
+
+/// Applies policies of the looked-up collection to the pipeline of the native query that backs
+/// a lookup function. The generated pipeline matches the key in its first stage. Renamed fields,
+/// transformations that predicates see, and the soft delete filter are applied before that stage;
+/// other transformations are applied after it.
+pub fn pipeline_for_lookup_function(
+    config: &MongoConfiguration,
+    lookup_function: &LookupFunction,
+    native_query_pipeline: Pipeline,
+) -> Result<Pipeline, MongoAgentError> {
+    let collection = &lookup_function.collection;
+    let mut pipeline = Pipeline::empty();
+    if let Some(stage) = field_aliases_stage(config, collection) {
+        pipeline.push(stage);
+    }
+    if let Some(stage) = read_transforms_stage(config, collection, true)? {
+        pipeline.push(stage);
+    }
+    if let Some(filter) = collection_soft_delete_filter(config, collection) {
+        pipeline.push(Stage::Match(filter));
+    }
+    let mut stages = native_query_pipeline.stages.into_iter();
+    pipeline.stages.extend(stages.next());
+    if let Some(stage) = read_transforms_stage(config, collection, false)? {
+        pipeline.push(stage);
+    }
+    pipeline.stages.extend(stages);
+    Ok(pipeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, ObjectField, ObjectType, Type},
+        serialized::Schema,
+        Configuration, ConfigurationOptions,
+    };
+    use mongodb::{
+        bson::{self, bson, doc, rawdoc, Document, RawDocumentBuf},
+        options::FindOptions,
+    };
+    use mongodb_support::BsonScalarType;
+    use ndc_models::{Argument, QueryResponse};
+    use ndc_query_plan::plan_for_query_request;
+    use ndc_test_helpers::{field, object, query, query_request, row_set};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::{
+        mongo_query_plan::MongoConfiguration,
+        mongodb::{test_helpers::mock_stream, MockCollectionTrait, MockDatabaseTrait},
+        query::{execute_query_request, pipeline_for_query_request},
+    };
+
+    use super::LookupRequest;
+
+    fn movies_config() -> anyhow::Result<MongoConfiguration> {
+        configure_movies(|_, _| {})
+    }
+
+    /// Configuration for a `movies` collection with lookup functions, with changes to the
+    /// collection and its type made by `configure`
+    fn configure_movies(
+        configure: impl FnOnce(&mut Collection, &mut ObjectType),
+    ) -> anyhow::Result<MongoConfiguration> {
+        let mut collection = Collection {
+            r#type: "movies".into(),
+            description: None,
+            ..Default::default()
+        };
+        let mut object_type = ObjectType {
+            fields: [
+                ObjectField::new("_id", Type::Scalar(BsonScalarType::Int)),
+                ObjectField::new("title", Type::Scalar(BsonScalarType::String)),
+                ObjectField::new(
+                    "deleted_at",
+                    Type::Nullable(Box::new(Type::Scalar(BsonScalarType::Date))),
+                ),
+            ]
+            .into_iter()
+            .map(|(name, field)| (name.into(), field))
+            .collect(),
+            description: None,
+            extends: Default::default(),
+        };
+        configure(&mut collection, &mut object_type);
+        let schema = Schema {
+            collections: [("movies".into(), collection)].into(),
+            object_types: [("movies".into(), object_type)].into(),
+        };
+        let options = ConfigurationOptions {
+            lookup_functions: true,
+            ..Default::default()
+        };
+        Ok(MongoConfiguration(Configuration::validate(
+            schema,
+            Default::default(),
+            Default::default(),
+            options,
+        )?))
+    }
+
+    fn mock_find(expected_filter: Document, result: RawDocumentBuf) -> MockDatabaseTrait {
+        let mut db = MockDatabaseTrait::new();
+        db.expect_collection().returning(move |name| {
+            assert_eq!(name, "movies");
+            let expected_filter = expected_filter.clone();
+            let result = result.clone();
+            let mut collection = MockCollectionTrait::new();
+            collection
+                .expect_find()
+                .returning(move |filter: Document, options: FindOptions| {
+                    assert_eq!(filter, expected_filter);
+                    assert_eq!(options.limit, Some(1));
+                    assert_eq!(
+                        options.projection,
+                        Some(doc! {
+                            "title": { "$ifNull": ["$title", null] },
+                            "_id": 0,
+                        })
+                    );
+                    Ok(mock_stream(vec![Ok(result.clone())]))
+                });
+            collection
+        });
+        db
+    }
+
+    fn movie_by_id_request() -> ndc_models::QueryRequest {
+        query_request()
+            .collection("movies_by_id")
+            .query(query().fields([field!("__value" => "__value", object!([field!("title")]))]))
+            .arguments([("id", Argument::Literal { value: json!(1) })])
+            .into()
+    }
+
+    #[tokio::test]
+    async fn executes_lookup_function_with_find() -> anyhow::Result<()> {
+        let db = mock_find(doc! { "_id": 1 }, rawdoc! { "title": "Fight Club" });
+
+        let result = execute_query_request(
+            db,
+            &movies_config()?,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            movie_by_id_request(),
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .rows([[("__value", json!({ "title": "Fight Club" }))]])
+                .into_response()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn excludes_soft_deleted_documents_from_find() -> anyhow::Result<()> {
+        let config = configure_movies(|collection, _| {
+            collection.soft_delete_field = Some("deleted_at".into());
+        })?;
+        let db = mock_find(
+            doc! { "$and": [{ "_id": 1 }, { "deleted_at": null }] },
+            rawdoc! { "title": "Fight Club" },
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            movie_by_id_request(),
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .rows([[("__value", json!({ "title": "Fight Club" }))]])
+                .into_response()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn applies_collection_policies_to_lookup_function_pipeline() -> anyhow::Result<()> {
+        let config = configure_movies(|collection, object_type| {
+            collection.soft_delete_field = Some("deleted_at".into());
+            if let Some(title) = object_type.fields.get_mut("title") {
+                title.previous_names = vec!["name".into()];
+            }
+        })?;
+        let query_plan = plan_for_query_request(&config, movie_by_id_request())?;
+
+        // Renamed fields cannot be read with `find`
+        assert!(LookupRequest::for_query_plan(&config, &query_plan).is_none());
+
+        let pipeline = bson::to_bson(&pipeline_for_query_request(&config, &query_plan)?)?;
+        let stages = pipeline.as_array().map(|stages| &stages[..4]);
+        assert_eq!(
+            stages,
+            bson!([
+                { "$addFields": { "title": { "$ifNull": ["$title", "$name"] } } },
+                { "$match": { "deleted_at": null } },
+                { "$match": { "_id": 1 } },
+                { "$limit": 1 },
+            ])
+            .as_array()
+            .map(|stages| &stages[..])
+        );
+        Ok(())
+    }
+}
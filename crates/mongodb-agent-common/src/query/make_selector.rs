@@ -1,11 +1,14 @@
 use anyhow::anyhow;
-use mongodb::bson::{self, doc, Document};
+use mongodb::bson::{self, doc, Bson, Document};
 use ndc_models::UnaryComparisonOperator;
+use ndc_query_plan::Scope;
 
 use crate::{
     comparison_function::ComparisonFunction,
     interface_types::MongoAgentError,
-    mongo_query_plan::{ComparisonTarget, ComparisonValue, ExistsInCollection, Expression, Type},
+    mongo_query_plan::{
+        ComparisonTarget, ComparisonValue, ExistsInCollection, Expression, MongoConfiguration, Type,
+    },
     query::column_ref::{column_expression, ColumnRef},
 };
 
@@ -19,13 +22,13 @@ fn bson_from_scalar_value(value: &serde_json::Value, value_type: &Type) -> Resul
     json_to_bson(value_type, value.clone()).map_err(|e| MongoAgentError::BadQuery(anyhow!(e)))
 }
 
-pub fn make_selector(expr: &Expression) -> Result<Document> {
+pub fn make_selector(config: &MongoConfiguration, expr: &Expression) -> Result<Document> {
     match expr {
         Expression::And { expressions } => {
             let sub_exps: Vec<Document> = expressions
                 .clone()
                 .iter()
-                .map(make_selector)
+                .map(|expression| make_selector(config, expression))
                 .collect::<Result<_>>()?;
             Ok(doc! {"$and": sub_exps})
         }
@@ -33,18 +36,18 @@ pub fn make_selector(expr: &Expression) -> Result<Document> {
             let sub_exps: Vec<Document> = expressions
                 .clone()
                 .iter()
-                .map(make_selector)
+                .map(|expression| make_selector(config, expression))
                 .collect::<Result<_>>()?;
             Ok(doc! {"$or": sub_exps})
         }
-        Expression::Not { expression } => Ok(doc! { "$nor": [make_selector(expression)?]}),
+        Expression::Not { expression } => Ok(doc! { "$nor": [make_selector(config, expression)?]}),
         Expression::Exists {
             in_collection,
             predicate,
         } => Ok(match in_collection {
             ExistsInCollection::Related { relationship } => match predicate {
                 Some(predicate) => doc! {
-                    relationship.to_string(): { "$elemMatch": make_selector(predicate)? }
+                    relationship.to_string(): { "$elemMatch": make_selector(config, predicate)? }
                 },
                 None => doc! { format!("{relationship}.0"): { "$exists": true } },
             },
@@ -60,7 +63,7 @@ pub fn make_selector(expr: &Expression) -> Result<Document> {
             column,
             operator,
             value,
-        } => make_binary_comparison_selector(column, operator, value),
+        } => make_binary_comparison_selector(config, column, operator, value),
         Expression::UnaryComparisonOperator { column, operator } => match operator {
             UnaryComparisonOperator::IsNull => {
                 let match_doc = match ColumnRef::from_comparison_target(column) {
@@ -83,34 +86,83 @@ pub fn make_selector(expr: &Expression) -> Result<Document> {
 }
 
 fn make_binary_comparison_selector(
+    config: &MongoConfiguration,
     target_column: &ComparisonTarget,
     operator: &ComparisonFunction,
     value: &ComparisonValue,
 ) -> Result<Document> {
+    // Queries that use `_ieq` run with a case-insensitive collation when one is configured. See
+    // [super::collection_arguments::CollectionArguments::collation].
+    let operator = match operator {
+        ComparisonFunction::IEqual if config.case_insensitive_collation().is_some() => {
+            &ComparisonFunction::Equal
+        }
+        operator => operator,
+    };
+    let sql_null_semantics = config.sql_null_semantics() && operator.is_null_sensitive();
     let selector = match value {
         ComparisonValue::Column {
             column: value_column,
         } => {
-            if !target_column.relationship_path().is_empty()
-                || !value_column.relationship_path().is_empty()
-            {
+            if !value_column.relationship_path().is_empty() {
                 return Err(MongoAgentError::NotImplemented(
-                    "binary comparisons between two fields where either field is in a related collection",
+                    "binary comparisons with a field in a related collection on the value side",
                 ));
             }
-            doc! {
-                "$expr": operator.mongodb_aggregation_expression(
-                    column_expression(target_column),
-                    column_expression(value_column)
+            let comparison = |target_expression| {
+                aggregation_comparison(
+                    sql_null_semantics,
+                    operator,
+                    target_expression,
+                    column_expression(value_column),
+                    true,
                 )
+            };
+            match target_column {
+                ComparisonTarget::Column {
+                    name,
+                    field_path,
+                    field_type,
+                    path,
+                } if !path.is_empty() => {
+                    let target = ComparisonTarget::ColumnInScope {
+                        name: name.clone(),
+                        scope: Scope::Named(related_document_variable(path.len() - 1)),
+                        field_path: field_path.clone(),
+                        field_type: field_type.clone(),
+                    };
+                    doc! {
+                        "$expr": any_related_document(path, comparison(column_expression(&target)))
+                    }
+                }
+                _ => doc! { "$expr": comparison(column_expression(target_column)) },
             }
         }
         ComparisonValue::Scalar { value, value_type } => {
             let comparison_value = bson_from_scalar_value(value, value_type)?;
             let match_doc = match ColumnRef::from_comparison_target(target_column) {
-                ColumnRef::MatchKey(key) => operator.mongodb_match_query(key, comparison_value),
+                ColumnRef::MatchKey(key) => {
+                    // Ordering operators in match queries do not match null or missing values due
+                    // to type bracketing, but `$ne` does.
+                    if sql_null_semantics && *operator == ComparisonFunction::NotEqual {
+                        doc! {
+                            "$and": [
+                                operator.mongodb_match_query(key.clone(), comparison_value),
+                                { key: { "$ne": null } },
+                            ]
+                        }
+                    } else {
+                        operator.mongodb_match_query(key, comparison_value)
+                    }
+                }
                 ColumnRef::Expression(expr) => doc! {
-                    "$expr": operator.mongodb_aggregation_expression(expr, comparison_value)
+                    "$expr": aggregation_comparison(
+                        sql_null_semantics,
+                        operator,
+                        expr,
+                        comparison_value,
+                        false,
+                    )
                 },
             };
             traverse_relationship_path(target_column.relationship_path(), match_doc)
@@ -121,9 +173,12 @@ fn make_binary_comparison_selector(
         } => {
             let comparison_value = variable_to_mongo_expression(name, variable_type);
             let match_doc = doc! {
-                "$expr": operator.mongodb_aggregation_expression(
+                "$expr": aggregation_comparison(
+                    sql_null_semantics,
+                    operator,
                     column_expression(target_column),
-                    comparison_value
+                    comparison_value,
+                    false,
                 )
             };
             traverse_relationship_path(target_column.relationship_path(), match_doc)
@@ -132,6 +187,33 @@ fn make_binary_comparison_selector(
     Ok(selector)
 }
 
+/// Aggregation expressions order null and missing values before all other values, so for example
+/// `{ "$lt": ["$field", 5] }` is true if `field` is missing. With SQL null semantics the
+/// comparison is additionally required to have non-null column operands. `value_is_column`
+/// indicates whether the right operand is a column reference, as opposed to a literal or variable.
+fn aggregation_comparison(
+    sql_null_semantics: bool,
+    operator: &ComparisonFunction,
+    column_ref: Bson,
+    comparison_value: impl Into<Bson>,
+    value_is_column: bool,
+) -> Document {
+    let comparison_value = comparison_value.into();
+    if !sql_null_semantics {
+        return operator.mongodb_aggregation_expression(column_ref, comparison_value);
+    }
+    let mut conditions = vec![Bson::from(doc! { "$gt": [column_ref.clone(), null] })];
+    if value_is_column {
+        conditions.push(doc! { "$gt": [comparison_value.clone(), null] }.into());
+    }
+    conditions.push(
+        operator
+            .mongodb_aggregation_expression(column_ref, comparison_value)
+            .into(),
+    );
+    doc! { "$and": conditions }
+}
+
 /// For simple cases the target of an expression is a field reference. But if the target is
 /// a column of a related collection then we're implicitly making an array comparison (because
 /// related documents always come as an array, even for object relationships), so we have to wrap
@@ -151,6 +233,40 @@ fn traverse_relationship_path(
     expression
 }
 
+/// `$expr` cannot be used inside `$elemMatch`, so a comparison between a column of a related
+/// collection and a column of the current document cannot use [traverse_relationship_path].
+/// Instead this expression maps over the related documents for each relationship in the path,
+/// binding each document to a variable named by [related_document_variable]. It is true if the
+/// given expression is true for any document at the end of the path.
+fn any_related_document(path: &[ndc_models::RelationshipName], expression: Document) -> Document {
+    let mut expression = expression;
+    for (depth, relationship) in path.iter().enumerate().rev() {
+        let related_documents = match depth.checked_sub(1) {
+            None => doc! { "$getField": { "$literal": relationship.as_str() } },
+            Some(parent) => doc! {
+                "$getField": {
+                    "field": { "$literal": relationship.as_str() },
+                    "input": format!("$${}", related_document_variable(parent)),
+                }
+            },
+        };
+        expression = doc! {
+            "$anyElementTrue": [{
+                "$map": {
+                    "input": { "$ifNull": [related_documents, []] },
+                    "as": related_document_variable(depth),
+                    "in": expression,
+                }
+            }]
+        };
+    }
+    expression
+}
+
+fn related_document_variable(depth: usize) -> String {
+    format!("related_{depth}")
+}
+
 fn variable_to_mongo_expression(
     variable: &ndc_models::VariableName,
     value_type: &Type,
@@ -174,7 +290,9 @@ mod tests {
 
     use crate::{
         comparison_function::ComparisonFunction,
-        mongo_query_plan::{ComparisonTarget, ComparisonValue, Expression, Type},
+        mongo_query_plan::{
+            ComparisonTarget, ComparisonValue, ExistsInCollection, Expression, Type,
+        },
         query::pipeline_for_query_request,
         test_helpers::{chinook_config, chinook_relationships},
     };
@@ -184,19 +302,22 @@ mod tests {
     #[test]
     fn compares_fields_of_related_documents_using_elem_match_in_binary_comparison(
     ) -> anyhow::Result<()> {
-        let selector = make_selector(&Expression::BinaryComparisonOperator {
-            column: ComparisonTarget::Column {
-                name: "Name".into(),
-                field_path: None,
-                field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
-                path: vec!["Albums".into(), "Tracks".into()],
-            },
-            operator: ComparisonFunction::Equal,
-            value: ComparisonValue::Scalar {
-                value: "Helter Skelter".into(),
-                value_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+        let selector = make_selector(
+            &chinook_config(),
+            &Expression::BinaryComparisonOperator {
+                column: ComparisonTarget::Column {
+                    name: "Name".into(),
+                    field_path: None,
+                    field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                    path: vec!["Albums".into(), "Tracks".into()],
+                },
+                operator: ComparisonFunction::Equal,
+                value: ComparisonValue::Scalar {
+                    value: "Helter Skelter".into(),
+                    value_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                },
             },
-        })?;
+        )?;
 
         let expected = doc! {
             "Albums": {
@@ -214,18 +335,79 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn compares_field_of_related_documents_to_field_of_current_document() -> anyhow::Result<()> {
+        let string_type = Type::Scalar(MongoScalarType::Bson(BsonScalarType::String));
+        let selector = make_selector(
+            &chinook_config(),
+            &Expression::BinaryComparisonOperator {
+                column: ComparisonTarget::Column {
+                    name: "Name".into(),
+                    field_path: None,
+                    field_type: string_type.clone(),
+                    path: vec!["Albums".into(), "Tracks".into()],
+                },
+                operator: ComparisonFunction::Equal,
+                value: ComparisonValue::Column {
+                    column: ComparisonTarget::Column {
+                        name: "Name".into(),
+                        field_path: None,
+                        field_type: string_type,
+                        path: Default::default(),
+                    },
+                },
+            },
+        )?;
+
+        let expected = doc! {
+            "$expr": {
+                "$anyElementTrue": [{
+                    "$map": {
+                        "input": { "$ifNull": [{ "$getField": { "$literal": "Albums" } }, []] },
+                        "as": "related_0",
+                        "in": {
+                            "$anyElementTrue": [{
+                                "$map": {
+                                    "input": {
+                                        "$ifNull": [
+                                            {
+                                                "$getField": {
+                                                    "field": { "$literal": "Tracks" },
+                                                    "input": "$$related_0",
+                                                }
+                                            },
+                                            [],
+                                        ]
+                                    },
+                                    "as": "related_1",
+                                    "in": { "$eq": ["$$related_1.Name", "$Name"] },
+                                }
+                            }]
+                        },
+                    }
+                }]
+            }
+        };
+
+        assert_eq!(selector, expected);
+        Ok(())
+    }
+
     #[test]
     fn compares_fields_of_related_documents_using_elem_match_in_unary_comparison(
     ) -> anyhow::Result<()> {
-        let selector = make_selector(&Expression::UnaryComparisonOperator {
-            column: ComparisonTarget::Column {
-                name: "Name".into(),
-                field_path: None,
-                field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
-                path: vec!["Albums".into(), "Tracks".into()],
+        let selector = make_selector(
+            &chinook_config(),
+            &Expression::UnaryComparisonOperator {
+                column: ComparisonTarget::Column {
+                    name: "Name".into(),
+                    field_path: None,
+                    field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                    path: vec!["Albums".into(), "Tracks".into()],
+                },
+                operator: UnaryComparisonOperator::IsNull,
             },
-            operator: UnaryComparisonOperator::IsNull,
-        })?;
+        )?;
 
         let expected = doc! {
             "Albums": {
@@ -245,23 +427,26 @@ mod tests {
 
     #[test]
     fn compares_two_columns() -> anyhow::Result<()> {
-        let selector = make_selector(&Expression::BinaryComparisonOperator {
-            column: ComparisonTarget::Column {
-                name: "Name".into(),
-                field_path: None,
-                field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
-                path: Default::default(),
-            },
-            operator: ComparisonFunction::Equal,
-            value: ComparisonValue::Column {
+        let selector = make_selector(
+            &chinook_config(),
+            &Expression::BinaryComparisonOperator {
                 column: ComparisonTarget::Column {
-                    name: "Title".into(),
+                    name: "Name".into(),
                     field_path: None,
                     field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
                     path: Default::default(),
                 },
+                operator: ComparisonFunction::Equal,
+                value: ComparisonValue::Column {
+                    column: ComparisonTarget::Column {
+                        name: "Title".into(),
+                        field_path: None,
+                        field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                        path: Default::default(),
+                    },
+                },
             },
-        })?;
+        )?;
 
         let expected = doc! {
             "$expr": {
@@ -274,20 +459,96 @@ mod tests {
     }
 
     #[test]
-    fn compares_root_collection_column_to_scalar() -> anyhow::Result<()> {
-        let selector = make_selector(&Expression::BinaryComparisonOperator {
-            column: ComparisonTarget::ColumnInScope {
-                name: "Name".into(),
-                field_path: None,
-                field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
-                scope: Scope::Named("scope_0".to_string()),
+    fn excludes_null_values_from_not_equal_with_sql_null_semantics() -> anyhow::Result<()> {
+        let mut config = chinook_config();
+        config.0.options.query_options.sql_null_semantics = true;
+
+        let selector = make_selector(
+            &config,
+            &Expression::BinaryComparisonOperator {
+                column: ComparisonTarget::Column {
+                    name: "Name".into(),
+                    field_path: None,
+                    field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                    path: Default::default(),
+                },
+                operator: ComparisonFunction::NotEqual,
+                value: ComparisonValue::Scalar {
+                    value: "Helter Skelter".into(),
+                    value_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                },
             },
-            operator: ComparisonFunction::Equal,
-            value: ComparisonValue::Scalar {
-                value: "Lady Gaga".into(),
-                value_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+        )?;
+
+        let expected = doc! {
+            "$and": [
+                { "Name": { "$ne": "Helter Skelter" } },
+                { "Name": { "$ne": null } },
+            ]
+        };
+
+        assert_eq!(selector, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn excludes_null_values_from_column_comparison_with_sql_null_semantics() -> anyhow::Result<()> {
+        let mut config = chinook_config();
+        config.0.options.query_options.sql_null_semantics = true;
+
+        let selector = make_selector(
+            &config,
+            &Expression::BinaryComparisonOperator {
+                column: ComparisonTarget::Column {
+                    name: "Name".into(),
+                    field_path: None,
+                    field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                    path: Default::default(),
+                },
+                operator: ComparisonFunction::GreaterThan,
+                value: ComparisonValue::Column {
+                    column: ComparisonTarget::Column {
+                        name: "Title".into(),
+                        field_path: None,
+                        field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                        path: Default::default(),
+                    },
+                },
             },
-        })?;
+        )?;
+
+        let expected = doc! {
+            "$expr": {
+                "$and": [
+                    { "$gt": ["$Name", null] },
+                    { "$gt": ["$Title", null] },
+                    { "$gt": ["$Name", "$Title"] },
+                ]
+            }
+        };
+
+        assert_eq!(selector, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn compares_root_collection_column_to_scalar() -> anyhow::Result<()> {
+        let selector = make_selector(
+            &chinook_config(),
+            &Expression::BinaryComparisonOperator {
+                column: ComparisonTarget::ColumnInScope {
+                    name: "Name".into(),
+                    field_path: None,
+                    field_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                    scope: Scope::Named("scope_0".to_string()),
+                },
+                operator: ComparisonFunction::Equal,
+                value: ComparisonValue::Scalar {
+                    value: "Lady Gaga".into(),
+                    value_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+                },
+            },
+        )?;
 
         let expected = doc! {
             "$expr": {
@@ -386,4 +647,163 @@ mod tests {
         assert_eq!(bson::to_bson(&pipeline).unwrap(), expected_pipeline);
         Ok(())
     }
+
+    #[test]
+    fn compares_remainders_of_integer_columns_with_mod() -> anyhow::Result<()> {
+        let int_type = Type::Scalar(MongoScalarType::Bson(BsonScalarType::Int));
+        let milliseconds = ComparisonTarget::Column {
+            name: "Milliseconds".into(),
+            field_path: None,
+            field_type: int_type.clone(),
+            path: Default::default(),
+        };
+
+        let selector = make_selector(
+            &chinook_config(),
+            &Expression::BinaryComparisonOperator {
+                column: milliseconds.clone(),
+                operator: ComparisonFunction::ModEqual,
+                value: ComparisonValue::Scalar {
+                    value: serde_json::json!([4, 1]),
+                    value_type: Type::ArrayOf(Box::new(int_type.clone())),
+                },
+            },
+        )?;
+        assert_eq!(selector, doc! { "Milliseconds": { "$mod": [4, 1] } });
+
+        let selector = make_selector(
+            &chinook_config(),
+            &Expression::BinaryComparisonOperator {
+                column: milliseconds,
+                operator: ComparisonFunction::DivisibleBy,
+                value: ComparisonValue::Scalar {
+                    value: 0.into(),
+                    value_type: int_type,
+                },
+            },
+        )?;
+        assert_eq!(selector, doc! { "Milliseconds": { "$in": [] } });
+        Ok(())
+    }
+
+    #[test]
+    fn compares_ranges_with_between() -> anyhow::Result<()> {
+        let int_type = Type::Scalar(MongoScalarType::Bson(BsonScalarType::Int));
+        let selector = make_selector(
+            &chinook_config(),
+            &Expression::BinaryComparisonOperator {
+                column: ComparisonTarget::Column {
+                    name: "Milliseconds".into(),
+                    field_path: None,
+                    field_type: int_type.clone(),
+                    path: Default::default(),
+                },
+                operator: ComparisonFunction::Between,
+                value: ComparisonValue::Scalar {
+                    value: serde_json::json!([180000, 240000]),
+                    value_type: Type::ArrayOf(Box::new(int_type)),
+                },
+            },
+        )?;
+        assert_eq!(
+            selector,
+            doc! { "Milliseconds": { "$gte": 180000, "$lte": 240000 } }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tests_field_presence_with_is_set() -> anyhow::Result<()> {
+        let selector = make_selector(
+            &chinook_config(),
+            &Expression::BinaryComparisonOperator {
+                column: ComparisonTarget::Column {
+                    name: "Composer".into(),
+                    field_path: None,
+                    field_type: Type::Nullable(Box::new(Type::Scalar(MongoScalarType::Bson(
+                        BsonScalarType::String,
+                    )))),
+                    path: Default::default(),
+                },
+                operator: ComparisonFunction::IsSet,
+                value: ComparisonValue::Scalar {
+                    value: false.into(),
+                    value_type: Type::Scalar(MongoScalarType::Bson(BsonScalarType::Bool)),
+                },
+            },
+        )?;
+        assert_eq!(selector, doc! { "Composer": { "$exists": false } });
+        Ok(())
+    }
+
+    #[test]
+    fn escapes_substrings_in_string_operators() -> anyhow::Result<()> {
+        let string_type = Type::Scalar(MongoScalarType::Bson(BsonScalarType::String));
+        let selector = |operator, value: &str| {
+            make_selector(
+                &chinook_config(),
+                &Expression::BinaryComparisonOperator {
+                    column: ComparisonTarget::Column {
+                        name: "Name".into(),
+                        field_path: None,
+                        field_type: string_type.clone(),
+                        path: Default::default(),
+                    },
+                    operator,
+                    value: ComparisonValue::Scalar {
+                        value: value.into(),
+                        value_type: string_type.clone(),
+                    },
+                },
+            )
+        };
+
+        assert_eq!(
+            selector(ComparisonFunction::StartsWith, "Mr. (Big)")?,
+            doc! { "Name": { "$regex": r"^Mr\. \(Big\)" } }
+        );
+        assert_eq!(
+            selector(ComparisonFunction::IEndsWith, "c++")?,
+            doc! { "Name": { "$regex": r"c\+\+\z", "$options": "i" } }
+        );
+        assert_eq!(
+            selector(ComparisonFunction::Contains, "50%")?,
+            doc! { "Name": { "$regex": "50%" } }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compares_case_insensitively_with_collation_when_configured() -> anyhow::Result<()> {
+        let string_type = Type::Scalar(MongoScalarType::Bson(BsonScalarType::String));
+        let expression = Expression::BinaryComparisonOperator {
+            column: ComparisonTarget::Column {
+                name: "Name".into(),
+                field_path: None,
+                field_type: string_type.clone(),
+                path: Default::default(),
+            },
+            operator: ComparisonFunction::IEqual,
+            value: ComparisonValue::Scalar {
+                value: "balls to the wall".into(),
+                value_type: string_type,
+            },
+        };
+
+        let selector = make_selector(&chinook_config(), &expression)?;
+        assert_eq!(
+            selector,
+            doc! {
+                "$expr": {
+                    "$eq": [{ "$toLower": "$Name" }, { "$toLower": "balls to the wall" }]
+                }
+            }
+        );
+
+        let mut config = chinook_config();
+        config.0.options.query_options.case_insensitive_collation = Some("en".to_owned());
+        let selector = make_selector(&config, &expression)?;
+        assert_eq!(selector, doc! { "Name": { "$eq": "balls to the wall" } });
+        Ok(())
+    }
 }
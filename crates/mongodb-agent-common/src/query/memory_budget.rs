@@ -0,0 +1,125 @@
+//! Request-scoped memory accounting. When `serializationOptions.maxResponseMemoryBytes` is set,
+//! each query request keeps an estimate of the memory it holds while its response is built: the
+//! BSON size of each row read from MongoDB, plus the JSON written to the response so far. The
+//! request fails as soon as the estimate exceeds the budget instead of continuing to buffer rows,
+//! so that one very large query cannot exhaust the memory of a connector that serves other
+//! requests.
+//!
+//! The estimate does not count allocator overhead or the memory used by the MongoDB driver, so
+//! budgets should leave some headroom.
+
+use std::io;
+
+use mongodb::bson::RawDocument;
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    max_bytes: Option<usize>,
+    rows: usize,
+    bytes: usize,
+}
+
+#[derive(Clone, Debug, Error, Serialize)]
+#[error("the query response used approximately {estimated_bytes} bytes of memory after reading {rows} rows, which exceeds the limit of {max_bytes} bytes. Select fewer fields, or request fewer rows with a limit.")]
+pub struct MemoryBudgetExceeded {
+    pub rows: usize,
+    pub average_row_size: usize,
+    pub estimated_bytes: usize,
+    pub max_bytes: usize,
+}
+
+impl MemoryBudget {
+    /// A budget that is never exceeded if `max_bytes` is `None`
+    pub fn new(max_bytes: Option<usize>) -> Self {
+        MemoryBudget {
+            max_bytes,
+            rows: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Counts a row that is held in memory until the response is complete
+    pub fn charge_row(&mut self, row: &RawDocument) -> Result<(), MemoryBudgetExceeded> {
+        self.rows += 1;
+        self.charge_bytes(row.as_bytes().len())
+    }
+
+    pub fn charge_bytes(&mut self, bytes: usize) -> Result<(), MemoryBudgetExceeded> {
+        self.bytes = self.bytes.saturating_add(bytes);
+        match self.max_bytes {
+            Some(max_bytes) if self.bytes > max_bytes => Err(MemoryBudgetExceeded {
+                rows: self.rows,
+                average_row_size: self.bytes.checked_div(self.rows).unwrap_or_default(),
+                estimated_bytes: self.bytes,
+                max_bytes,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Counts bytes written to the inner writer against a budget. Once the budget is exceeded writes
+/// fail, and the reason is kept so that it can be reported instead of the resulting I/O error.
+pub struct BudgetedWriter<'a, W> {
+    inner: W,
+    budget: &'a mut MemoryBudget,
+    exceeded: Option<MemoryBudgetExceeded>,
+}
+
+impl<'a, W> BudgetedWriter<'a, W> {
+    pub fn new(inner: W, budget: &'a mut MemoryBudget) -> Self {
+        BudgetedWriter {
+            inner,
+            budget,
+            exceeded: None,
+        }
+    }
+
+    pub fn into_exceeded(self) -> Option<MemoryBudgetExceeded> {
+        self.exceeded
+    }
+}
+
+impl<W: io::Write> io::Write for BudgetedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Err(exceeded) = self.budget.charge_bytes(buf.len()) {
+            let err = io::Error::new(io::ErrorKind::Other, exceeded.to_string());
+            self.exceeded = Some(exceeded);
+            return Err(err);
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, RawDocumentBuf};
+
+    use super::MemoryBudget;
+
+    #[test]
+    fn fails_once_rows_exceed_the_budget() -> anyhow::Result<()> {
+        let row = RawDocumentBuf::from_document(&doc! { "title": "The Matrix" })?;
+        let row_size = row.as_bytes().len();
+
+        let mut budget = MemoryBudget::new(Some(row_size * 2));
+        assert!(budget.charge_row(&row).is_ok());
+        assert!(budget.charge_row(&row).is_ok());
+        let exceeded = budget.charge_row(&row).unwrap_err();
+        assert_eq!(exceeded.rows, 3);
+        assert_eq!(exceeded.average_row_size, row_size);
+        assert_eq!(exceeded.estimated_bytes, row_size * 3);
+
+        let mut unlimited = MemoryBudget::new(None);
+        for _ in 0..100 {
+            assert!(unlimited.charge_row(&row).is_ok());
+        }
+        Ok(())
+    }
+}
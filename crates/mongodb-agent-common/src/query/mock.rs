@@ -0,0 +1,818 @@
+//! Mock mode answers query requests with synthesized documents instead of querying MongoDB. Each
+//! collection has a fixed set of documents generated from its configured object type. Values are
+//! derived from a hash of the collection name, the document position, and the field name so the
+//! same request always gets the same response.
+//!
+//! Limits, offsets, collection policies, and orderings on columns of the queried collection are
+//! applied. Predicates that compare columns of the queried collection are evaluated; other
+//! predicates, such as those that reference related collections, are treated as matching every
+//! document. Relationships are not joined on their column mappings: a relationship field gets
+//! documents from the target collection that match the relationship's own query, and at most one
+//! document for an object relationship.
+
+use std::{cmp::Ordering, str::FromStr as _};
+
+use bytes::Bytes;
+use configuration::MongoScalarType;
+use mongodb::bson::{
+    self, oid::ObjectId, spec::BinarySubtype, Binary, Bson, Decimal128, Document, RawDocumentBuf,
+};
+use mongodb_support::BsonScalarType as S;
+use ndc_models::{OrderDirection, QueryRequest, RelationshipType, UnaryComparisonOperator};
+use ndc_query_plan::{plan_for_query_request, OrderByTarget, QueryContext as _, VariableSet};
+
+use super::{
+    apply_collection_policies, response::serialize_query_response, serialization::json_to_bson,
+    CollectionArguments,
+};
+use crate::{
+    aggregation_function::AggregationFunction,
+    comparison_function::ComparisonFunction,
+    interface_types::MongoAgentError,
+    mongo_query_plan::{
+        Aggregate, ComparisonTarget, ComparisonValue, Expression, Field, MongoConfiguration,
+        NestedField, Query, Type,
+    },
+    post_processing::ResponsePostProcessors,
+};
+
+type Result<T> = std::result::Result<T, MongoAgentError>;
+
+/// Number of documents generated for each collection
+pub const MOCK_DOCUMENT_COUNT: usize = 20;
+
+/// 2020-01-01T00:00:00Z
+const MOCK_DATE_START_MILLIS: i64 = 1_577_836_800_000;
+
+const MOCK_DATE_RANGE_MILLIS: u64 = 4 * 365 * 24 * 60 * 60 * 1000;
+
+pub fn execute_mock_query_request(
+    config: &MongoConfiguration,
+    post_processors: &ResponsePostProcessors,
+    query_request: QueryRequest,
+) -> Result<Bytes> {
+    let mut query_plan = plan_for_query_request(config, query_request)?;
+    let collection_arguments = CollectionArguments::for_request(config, &query_plan)?;
+    collection_arguments.apply_limit(&mut query_plan.query);
+    apply_collection_policies(config, &query_plan.collection, &mut query_plan.query);
+
+    let documents = mock_documents(config, &query_plan.collection)?;
+    let response_documents = match &query_plan.variables {
+        Some(variable_sets) => variable_sets
+            .iter()
+            .map(|variables| row_set(config, &query_plan.query, &documents, variables))
+            .collect::<Result<Vec<_>>>()?,
+        None if query_plan.query.has_aggregates() => vec![row_set(
+            config,
+            &query_plan.query,
+            &documents,
+            &Default::default(),
+        )?],
+        None => rows(config, &query_plan.query, &documents, &Default::default())?,
+    };
+    let response_documents = response_documents
+        .iter()
+        .map(|document| {
+            RawDocumentBuf::from_document(document)
+                .map_err(|err| MongoAgentError::AdHoc(err.into()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let response = serialize_query_response(
+        config.serialization_options(),
+        config.object_id_formats(),
+        post_processors,
+        &query_plan,
+        response_documents,
+    )?;
+    Ok(response)
+}
+
+/// The synthesized documents for the named collection
+pub fn mock_documents(
+    config: &MongoConfiguration,
+    collection: &ndc_models::CollectionName,
+) -> Result<Vec<Document>> {
+    let object_type = config.find_collection_object_type(collection)?;
+    let document_type = Type::Object(object_type);
+    Ok((0..MOCK_DOCUMENT_COUNT)
+        .map(|index| {
+            let seed = hash(&[collection.as_str().as_bytes(), &index.to_be_bytes()]);
+            match mock_value(&document_type, collection.as_str(), index, seed) {
+                Bson::Document(document) => document,
+                _ => unreachable!("an object type always produces a document"),
+            }
+        })
+        .collect())
+}
+
+/// A synthesized value of the given type for an argument with the given name
+pub fn mock_argument_value(value_type: &Type, name: &str) -> Bson {
+    mock_value(value_type, name, 0, hash(&[name.as_bytes()]))
+}
+
+fn mock_value(value_type: &Type, name: &str, index: usize, seed: u64) -> Bson {
+    match value_type {
+        Type::Nullable(underlying) => {
+            // Roughly one in ten values of a nullable type is null
+            if seed % 10 == 0 {
+                Bson::Null
+            } else {
+                mock_value(underlying, name, index, mix(seed))
+            }
+        }
+        Type::ArrayOf(element_type) => Bson::Array(
+            (0..2u64)
+                .map(|i| mock_value(element_type, name, index, mix(seed ^ i)))
+                .collect(),
+        ),
+        Type::Object(object_type) => Bson::Document(
+            object_type
+                .fields
+                .iter()
+                .map(|(field_name, field_type)| {
+                    let field_seed = mix(seed ^ hash(&[field_name.as_str().as_bytes()]));
+                    let value = mock_value(field_type, field_name.as_str(), index, field_seed);
+                    (field_name.to_string(), value)
+                })
+                .collect(),
+        ),
+        Type::Scalar(MongoScalarType::ExtendedJSON) => {
+            Bson::String(format!("{name} {}", index + 1))
+        }
+        Type::Scalar(MongoScalarType::Bson(scalar_type)) => {
+            mock_scalar(*scalar_type, name, index, seed)
+        }
+    }
+}
+
+fn mock_scalar(scalar_type: S, name: &str, index: usize, seed: u64) -> Bson {
+    match scalar_type {
+        S::Double => Bson::Double((seed % 100_000) as f64 / 100.0),
+        S::Decimal => Decimal128::from_str(&format!("{}.{:02}", seed % 1000, seed % 100))
+            .map(Bson::Decimal128)
+            .unwrap_or(Bson::Null),
+        S::Int => Bson::Int32((seed % 1000) as i32),
+        S::Long => Bson::Int64((seed % 1_000_000) as i64),
+        S::String => Bson::String(format!("{name} {}", index + 1)),
+        S::Date => Bson::DateTime(bson::DateTime::from_millis(
+            MOCK_DATE_START_MILLIS + (seed % MOCK_DATE_RANGE_MILLIS) as i64,
+        )),
+        S::Timestamp => Bson::Timestamp(bson::Timestamp {
+            time: (seed % i32::MAX as u64) as u32,
+            increment: 1,
+        }),
+        S::BinData => Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: seed.to_be_bytes().to_vec(),
+        }),
+        S::ObjectId => {
+            let mut bytes = [0u8; 12];
+            bytes[..8].copy_from_slice(&seed.to_be_bytes());
+            bytes[8..].copy_from_slice(&(index as u32).to_be_bytes());
+            Bson::ObjectId(ObjectId::from_bytes(bytes))
+        }
+        S::Bool => Bson::Boolean(seed % 2 == 0),
+        S::Null => Bson::Null,
+        S::Regex => Bson::RegularExpression(bson::Regex {
+            pattern: name.to_owned(),
+            options: String::new(),
+        }),
+        S::Javascript => Bson::JavaScriptCode(String::new()),
+        S::JavascriptWithScope => Bson::JavaScriptCodeWithScope(bson::JavaScriptCodeWithScope {
+            code: String::new(),
+            scope: Document::new(),
+        }),
+        S::MinKey => Bson::MinKey,
+        S::MaxKey => Bson::MaxKey,
+        S::Undefined => Bson::Undefined,
+        S::Symbol => Bson::Symbol(name.to_owned()),
+        // DBPointer values cannot be constructed outside of the bson crate
+        S::DbPointer => Bson::Null,
+    }
+}
+
+/// FNV-1a
+fn hash(parts: &[&[u8]]) -> u64 {
+    parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// The SplitMix64 finalizer, used to derive independent seeds from a seed
+fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A document with `rows` and `aggregates` fields in the shape that query pipelines produce
+fn row_set(
+    config: &MongoConfiguration,
+    query: &Query,
+    documents: &[Document],
+    variables: &VariableSet,
+) -> Result<Document> {
+    let mut row_set = Document::new();
+    if let Some(aggregates) = &query.aggregates {
+        let matching = matching_documents(query, documents, variables)?;
+        let aggregate_input = match query.aggregates_limit {
+            Some(limit) => &matching[..matching.len().min(limit as usize)],
+            None => &matching[..],
+        };
+        let values: Document = aggregates
+            .iter()
+            .map(|(name, aggregate)| {
+                Ok((
+                    name.to_string(),
+                    aggregate_value(aggregate, aggregate_input)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        row_set.insert("aggregates", values);
+    }
+    if query.fields.is_some() {
+        let rows = rows(config, query, documents, variables)?;
+        row_set.insert("rows", rows);
+    }
+    Ok(row_set)
+}
+
+fn rows(
+    config: &MongoConfiguration,
+    query: &Query,
+    documents: &[Document],
+    variables: &VariableSet,
+) -> Result<Vec<Document>> {
+    let Some(fields) = &query.fields else {
+        return Ok(vec![]);
+    };
+    let mut matching = matching_documents(query, documents, variables)?;
+    if let Some(order_by) = &query.order_by {
+        matching.sort_by(|a, b| {
+            order_by
+                .elements
+                .iter()
+                .filter_map(|element| match &element.target {
+                    OrderByTarget::Column {
+                        name,
+                        field_path,
+                        path,
+                    } if path.is_empty() => {
+                        let ordering = compare(
+                            &field_value(a, name, field_path.as_deref()),
+                            &field_value(b, name, field_path.as_deref()),
+                        )
+                        .unwrap_or(Ordering::Equal);
+                        Some(match element.order_direction {
+                            OrderDirection::Asc => ordering,
+                            OrderDirection::Desc => ordering.reverse(),
+                        })
+                    }
+                    _ => None,
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+    matching
+        .into_iter()
+        .skip(query.offset.unwrap_or(0) as usize)
+        .take(query.limit.map_or(usize::MAX, |limit| limit as usize))
+        .map(|document| {
+            fields
+                .iter()
+                .map(|(name, field)| {
+                    Ok((
+                        name.to_string(),
+                        field_output(config, query, document, field, variables)?,
+                    ))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn matching_documents<'a>(
+    query: &Query,
+    documents: &'a [Document],
+    variables: &VariableSet,
+) -> Result<Vec<&'a Document>> {
+    let mut matching = Vec::new();
+    for document in documents {
+        let is_match = match &query.predicate {
+            Some(predicate) => evaluate(predicate, document, variables)?,
+            None => true,
+        };
+        if is_match {
+            matching.push(document);
+        }
+    }
+    Ok(matching)
+}
+
+fn field_output(
+    config: &MongoConfiguration,
+    query: &Query,
+    document: &Document,
+    field: &Field,
+    variables: &VariableSet,
+) -> Result<Bson> {
+    match field {
+        Field::Column { column, fields, .. } => {
+            let value = document.get(column.as_str()).cloned().unwrap_or(Bson::Null);
+            Ok(match fields {
+                Some(nested) => project_nested(value, nested),
+                None => value,
+            })
+        }
+        Field::Relationship { relationship, .. } => {
+            let relationship = query
+                .relationships
+                .get(relationship)
+                .ok_or_else(|| MongoAgentError::UnspecifiedRelation(relationship.to_string()))?;
+            let mut related_query = relationship.query.clone();
+            if relationship.relationship_type == RelationshipType::Object {
+                related_query.limit = Some(1);
+            }
+            let related_documents = mock_documents(config, &relationship.target_collection)?;
+            Ok(Bson::Document(row_set(
+                config,
+                &related_query,
+                &related_documents,
+                variables,
+            )?))
+        }
+    }
+}
+
+fn project_nested(value: Bson, nested: &NestedField) -> Bson {
+    match (value, nested) {
+        (Bson::Document(document), NestedField::Object(object)) => Bson::Document(
+            object
+                .fields
+                .iter()
+                .map(|(name, field)| {
+                    let value = match field {
+                        Field::Column { column, fields, .. } => {
+                            let value =
+                                document.get(column.as_str()).cloned().unwrap_or(Bson::Null);
+                            match fields {
+                                Some(nested) => project_nested(value, nested),
+                                None => value,
+                            }
+                        }
+                        Field::Relationship { .. } => Bson::Null,
+                    };
+                    (name.to_string(), value)
+                })
+                .collect(),
+        ),
+        (Bson::Array(elements), NestedField::Array(array)) => Bson::Array(
+            elements
+                .into_iter()
+                .map(|element| project_nested(element, &array.fields))
+                .collect(),
+        ),
+        (value, _) => value,
+    }
+}
+
+fn evaluate(expression: &Expression, document: &Document, variables: &VariableSet) -> Result<bool> {
+    let result = match expression {
+        Expression::And { expressions } => {
+            for expression in expressions {
+                if !evaluate(expression, document, variables)? {
+                    return Ok(false);
+                }
+            }
+            true
+        }
+        Expression::Or { expressions } => {
+            for expression in expressions {
+                if evaluate(expression, document, variables)? {
+                    return Ok(true);
+                }
+            }
+            expressions.is_empty()
+        }
+        Expression::Not { expression } => !evaluate(expression, document, variables)?,
+        Expression::UnaryComparisonOperator { column, operator } => {
+            match (target_value(column, document), operator) {
+                (Some(value), UnaryComparisonOperator::IsNull) => value == Bson::Null,
+                (None, _) => true,
+            }
+        }
+        Expression::BinaryComparisonOperator {
+            column,
+            operator,
+            value,
+        } => {
+            let Some(left) = target_value(column, document) else {
+                return Ok(true);
+            };
+            let right = match value {
+                ComparisonValue::Column { column } => match target_value(column, document) {
+                    Some(right) => right,
+                    None => return Ok(true),
+                },
+                ComparisonValue::Scalar { value, value_type } => scalar_value(value, value_type)?,
+                ComparisonValue::Variable {
+                    name,
+                    variable_type,
+                } => {
+                    let value = variables
+                        .get(name)
+                        .ok_or_else(|| MongoAgentError::InvalidVariableName(name.to_string()))?;
+                    scalar_value(value, variable_type)?
+                }
+            };
+            compare_with_operator(*operator, &left, &right)?
+        }
+        // Related collections are not evaluated
+        Expression::Exists { .. } => true,
+    };
+    Ok(result)
+}
+
+fn scalar_value(value: &serde_json::Value, value_type: &Type) -> Result<Bson> {
+    json_to_bson(value_type, value.clone())
+        .map_err(|err| MongoAgentError::BadQuery(anyhow::anyhow!(err)))
+}
+
+/// The value of the target column, or `None` if the target is not a column of the document
+fn target_value(target: &ComparisonTarget, document: &Document) -> Option<Bson> {
+    match target {
+        ComparisonTarget::Column {
+            name,
+            field_path,
+            path,
+            ..
+        } if path.is_empty() => Some(field_value(document, name, field_path.as_deref())),
+        _ => None,
+    }
+}
+
+fn field_value(
+    document: &Document,
+    name: &ndc_models::FieldName,
+    field_path: Option<&[ndc_models::FieldName]>,
+) -> Bson {
+    let mut value = document.get(name.as_str()).cloned().unwrap_or(Bson::Null);
+    for field in field_path.unwrap_or_default() {
+        value = match value {
+            Bson::Document(document) => document.get(field.as_str()).cloned().unwrap_or(Bson::Null),
+            _ => Bson::Null,
+        };
+    }
+    value
+}
+
+fn compare_with_operator(operator: ComparisonFunction, left: &Bson, right: &Bson) -> Result<bool> {
+    use ComparisonFunction as C;
+    let result = match operator {
+        C::Equal => compare(left, right) == Some(Ordering::Equal),
+        C::NotEqual => compare(left, right) != Some(Ordering::Equal),
+        C::IEqual => match (left, right) {
+            (Bson::String(value), Bson::String(other)) => {
+                value.to_lowercase() == other.to_lowercase()
+            }
+            _ => false,
+        },
+        C::LessThan => compare(left, right) == Some(Ordering::Less),
+        C::LessThanOrEqual => compare(left, right).is_some_and(|o| o.is_le()),
+        C::GreaterThan => compare(left, right) == Some(Ordering::Greater),
+        C::GreaterThanOrEqual => compare(left, right).is_some_and(|o| o.is_ge()),
+        // Documents in the mock database are not expected to contain explicit nulls, so a null
+        // value stands for a missing field
+        C::IsSet => Bson::Boolean(*left != Bson::Null) == *right,
+        C::TypeEqual => match (type_alias(left), right) {
+            (Some(alias), Bson::String(expected)) => {
+                alias == expected
+                    || (expected == "number"
+                        && matches!(alias, "double" | "int" | "long" | "decimal"))
+            }
+            _ => false,
+        },
+        C::PathEqual => match right {
+            Bson::Document(argument) => {
+                let path = argument.get_array("path").ok().into_iter().flatten();
+                let value = path.fold(Some(left.clone()), |value, name| match (value, name) {
+                    (Some(Bson::Document(document)), Bson::String(name)) => {
+                        document.get(name).cloned()
+                    }
+                    _ => None,
+                });
+                let expected = argument.get("value").cloned().unwrap_or(Bson::Null);
+                value.is_some_and(|value| compare(&value, &expected) == Some(Ordering::Equal))
+            }
+            _ => false,
+        },
+        C::Between => match right {
+            Bson::Array(bounds) if bounds.len() == 2 => {
+                compare(left, &bounds[0]).is_some_and(|o| o.is_ge())
+                    && compare(left, &bounds[1]).is_some_and(|o| o.is_le())
+            }
+            _ => false,
+        },
+        C::Regex | C::IRegex => match (left, right) {
+            (Bson::String(value), Bson::String(pattern)) => regex::RegexBuilder::new(pattern)
+                .case_insensitive(operator == C::IRegex)
+                .build()
+                .map_err(|err| MongoAgentError::BadQuery(err.into()))?
+                .is_match(value),
+            _ => false,
+        },
+        C::StartsWith
+        | C::IStartsWith
+        | C::EndsWith
+        | C::IEndsWith
+        | C::Contains
+        | C::IContains => match (left, right) {
+            (Bson::String(value), Bson::String(substring)) => {
+                let (value, substring) = match operator {
+                    C::IStartsWith | C::IEndsWith | C::IContains => {
+                        (value.to_lowercase(), substring.to_lowercase())
+                    }
+                    _ => (value.clone(), substring.clone()),
+                };
+                match operator {
+                    C::StartsWith | C::IStartsWith => value.starts_with(&substring),
+                    C::EndsWith | C::IEndsWith => value.ends_with(&substring),
+                    _ => value.contains(&substring),
+                }
+            }
+            _ => false,
+        },
+        C::ModEqual | C::DivisibleBy => {
+            let (divisor, remainder) = match (operator, right) {
+                (C::DivisibleBy, divisor) => (as_f64(divisor), Some(0.0)),
+                (_, Bson::Array(operands)) if operands.len() == 2 => {
+                    (as_f64(&operands[0]), as_f64(&operands[1]))
+                }
+                _ => (None, None),
+            };
+            match (as_f64(left), divisor, remainder) {
+                (Some(value), Some(divisor), Some(remainder)) if divisor != 0.0 => {
+                    (value.trunc() % divisor.trunc()) == remainder
+                }
+                _ => false,
+            }
+        }
+    };
+    Ok(result)
+}
+
+/// The name that the `$type` aggregation operator reports for a value
+fn type_alias(value: &Bson) -> Option<&'static str> {
+    let alias = match value {
+        Bson::Double(_) => "double",
+        Bson::String(_) => "string",
+        Bson::Document(_) => "object",
+        Bson::Array(_) => "array",
+        Bson::Boolean(_) => "bool",
+        Bson::Null => "null",
+        Bson::Int32(_) => "int",
+        Bson::Int64(_) => "long",
+        Bson::Decimal128(_) => "decimal",
+        Bson::DateTime(_) => "date",
+        Bson::ObjectId(_) => "objectId",
+        _ => return None,
+    };
+    Some(alias)
+}
+
+/// Compares values of the same kind. Numbers of different BSON types are compared by value.
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        (Bson::Null, Bson::Null) => Some(Ordering::Equal),
+        (a, b) => match (as_f64(a), as_f64(b)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => (a == b).then_some(Ordering::Equal),
+        },
+    }
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        Bson::Decimal128(n) => n.to_string().parse().ok(),
+        _ => None,
+    }
+}
+
+fn aggregate_value(aggregate: &Aggregate, documents: &[&Document]) -> Result<Bson> {
+    let column_values = |column: &ndc_models::FieldName| -> Vec<Bson> {
+        documents
+            .iter()
+            .map(|document| document.get(column.as_str()).cloned().unwrap_or(Bson::Null))
+            .filter(|value| *value != Bson::Null)
+            .collect()
+    };
+    let value = match aggregate {
+        Aggregate::StarCount => count(documents.len()),
+        Aggregate::ColumnCount { column, distinct } => {
+            let mut values = column_values(column);
+            if *distinct {
+                values = distinct_values(values);
+            }
+            count(values.len())
+        }
+        Aggregate::SingleColumn {
+            column,
+            function,
+            arguments,
+            result_type,
+        } => {
+            use AggregationFunction as A;
+            let arguments = function.parse_arguments(arguments)?;
+            let mut values = column_values(column);
+            if arguments.distinct {
+                values = distinct_values(values);
+            }
+            match function {
+                A::Count => count(values.len()),
+                A::Min => extreme(values, Ordering::Less),
+                A::Max => extreme(values, Ordering::Greater),
+                A::Sum | A::Avg => {
+                    let numbers: Vec<f64> = values.iter().filter_map(as_f64).collect();
+                    match (function, numbers.len()) {
+                        (_, 0) => Bson::Null,
+                        (A::Sum, _)
+                            if *result_type == Type::Scalar(MongoScalarType::Bson(S::Long)) =>
+                        {
+                            Bson::Int64(numbers.iter().map(|n| *n as i64).sum())
+                        }
+                        (A::Sum, _) => decimal_or_double(result_type, numbers.iter().sum()),
+                        (_, n) => {
+                            let avg = numbers.iter().sum::<f64>() / n as f64;
+                            decimal_or_double(result_type, avg)
+                        }
+                    }
+                }
+                A::Percentile => {
+                    let mut numbers: Vec<f64> = values.iter().filter_map(as_f64).collect();
+                    numbers.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+                    let p = arguments.p.unwrap_or_default();
+                    let rank = (p * numbers.len() as f64).ceil() as usize;
+                    numbers
+                        .get(rank.saturating_sub(1))
+                        .map_or(Bson::Null, |n| Bson::Double(*n))
+                }
+                A::CountWhere => {
+                    let operator = arguments.operator.unwrap_or(ComparisonFunction::Equal);
+                    let value = arguments.value.unwrap_or(Bson::Null);
+                    let mut n = 0;
+                    for column_value in &values {
+                        if compare_with_operator(operator, column_value, &value)? {
+                            n += 1;
+                        }
+                    }
+                    count(n)
+                }
+                A::Concat => {
+                    let strings: Vec<&str> = values.iter().filter_map(Bson::as_str).collect();
+                    if strings.is_empty() {
+                        Bson::Null
+                    } else {
+                        Bson::String(strings.join(arguments.separator.as_deref().unwrap_or(", ")))
+                    }
+                }
+                A::FacetCounts => {
+                    let mut counts: Vec<(Bson, usize)> = distinct_values(values.clone())
+                        .into_iter()
+                        .map(|value| {
+                            let n = values.iter().filter(|v| **v == value).count();
+                            (value, n)
+                        })
+                        .collect();
+                    counts.sort_by(|(value_a, a), (value_b, b)| {
+                        b.cmp(a)
+                            .then(compare(value_a, value_b).unwrap_or(Ordering::Equal))
+                    });
+                    Bson::Array(
+                        counts
+                            .into_iter()
+                            .map(|(value, n)| {
+                                Bson::Document(bson::doc! { "value": value, "count": count(n) })
+                            })
+                            .collect(),
+                    )
+                }
+            }
+        }
+    };
+    Ok(value)
+}
+
+fn count(n: usize) -> Bson {
+    Bson::Int32(n as i32)
+}
+
+/// Sums and averages are serialized according to their declared result types
+fn decimal_or_double(result_type: &Type, n: f64) -> Bson {
+    if *result_type == Type::Scalar(MongoScalarType::Bson(S::Decimal)) {
+        Decimal128::from_str(&n.to_string())
+            .map(Bson::Decimal128)
+            .unwrap_or(Bson::Null)
+    } else {
+        Bson::Double(n)
+    }
+}
+
+fn distinct_values(values: Vec<Bson>) -> Vec<Bson> {
+    let mut distinct: Vec<Bson> = Vec::new();
+    for value in values {
+        if !distinct.contains(&value) {
+            distinct.push(value);
+        }
+    }
+    distinct
+}
+
+fn extreme(values: Vec<Bson>, keep: Ordering) -> Bson {
+    values
+        .into_iter()
+        .reduce(|best, value| {
+            if compare(&value, &best) == Some(keep) {
+                value
+            } else {
+                best
+            }
+        })
+        .unwrap_or(Bson::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::Configuration;
+    use ndc_models::QueryResponse;
+    use ndc_test_helpers::{
+        binop, collection, field, named_type, object_type, query, query_request, target, value,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::{execute_mock_query_request, mock_documents};
+    use crate::mongo_query_plan::MongoConfiguration;
+
+    fn config() -> MongoConfiguration {
+        MongoConfiguration(Configuration {
+            collections: [collection("movies")].into(),
+            object_types: [(
+                "movies".into(),
+                object_type([("title", named_type("String")), ("year", named_type("Int"))]),
+            )]
+            .into(),
+            functions: Default::default(),
+            procedures: Default::default(),
+            native_mutations: Default::default(),
+            native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
+            options: Default::default(),
+        })
+    }
+
+    #[test]
+    fn serves_deterministic_rows_honoring_limit_and_predicate() -> anyhow::Result<()> {
+        let config = config();
+        let documents = mock_documents(&config, &"movies".into())?;
+        assert_eq!(documents, mock_documents(&config, &"movies".into())?);
+
+        let threshold = documents[0].get_i32("year")?;
+        let query_request = query_request()
+            .collection("movies")
+            .query(
+                query()
+                    .fields([field!("title"), field!("year")])
+                    .predicate(binop("_gte", target!("year"), value!(threshold)))
+                    .limit(3),
+            )
+            .into();
+        let response = execute_mock_query_request(&config, &Default::default(), query_request)?;
+        let response: QueryResponse = serde_json::from_slice(&response)?;
+
+        let rows = response.0[0].rows.clone().unwrap_or_default();
+        assert!(!rows.is_empty() && rows.len() <= 3);
+        assert_eq!(
+            rows[0].get("title").map(|value| value.0.clone()),
+            Some(serde_json::json!("title 1"))
+        );
+        for row in rows {
+            let year = row["year"].0.as_i64().unwrap_or_default();
+            assert!(year >= threshold as i64);
+        }
+        Ok(())
+    }
+}
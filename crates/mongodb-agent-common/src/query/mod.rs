@@ -1,52 +1,214 @@
 pub mod arguments;
+mod batching;
+mod collection_arguments;
+mod collection_policies;
 mod column_ref;
+mod compatibility;
 mod constants;
+mod count;
+mod cursor_batch_size;
+mod document_size;
 mod execute_query_request;
+mod field_aliases;
+mod find;
 mod foreach;
+mod gap_fill;
+mod lookup_function;
 mod make_selector;
 mod make_sort;
+mod memory_budget;
+mod mock;
 mod native_query;
 mod pipeline;
+mod pipeline_size;
 mod query_level;
 mod query_target;
 mod query_variable_name;
+mod raw_pipeline;
+mod read_transforms;
 mod relations;
 pub mod response;
 pub mod serialization;
+mod soft_delete;
+mod window_fields;
 
-use ndc_models::{QueryRequest, QueryResponse};
+use std::time::Instant;
 
-use self::execute_query_request::execute_query_request;
+use bytes::Bytes;
+use mongodb::{bson::Bson, Database};
+use ndc_models::QueryRequest;
+
+use self::{
+    batching::execute_batched, execute_query_request::execute_query_request,
+    mock::execute_mock_query_request,
+};
 pub use self::{
+    batching::QueryBatcher,
+    collection_arguments::CollectionArguments,
+    collection_policies::apply_collection_policies,
+    count::CountCommand,
+    cursor_batch_size::CursorBatchSizes,
+    document_size::{DocumentSizeCause, DocumentTooLargeError},
+    find::FindCommand,
+    foreach::pipelines_for_variable_sets,
+    lookup_function::LookupRequest,
     make_selector::make_selector,
     make_sort::make_sort,
+    memory_budget::MemoryBudgetExceeded,
+    mock::mock_argument_value,
     pipeline::{is_response_faceted, pipeline_for_non_foreach, pipeline_for_query_request},
     query_target::QueryTarget,
     response::QueryResponseError,
 };
 use crate::{
-    interface_types::MongoAgentError, mongo_query_plan::MongoConfiguration, state::ConnectorState,
+    interface_types::MongoAgentError,
+    mongo_query_plan::MongoConfiguration,
+    mongodb::{
+        request_comment, DatabaseTrait, RecordingDatabase, SnapshotDatabase, TaggedDatabase,
+    },
+    monitoring::QueryFailed,
+    query_log::QueryShape,
+    state::ConnectorState,
+    tenancy::database_for_request,
 };
 
 pub async fn handle_query_request(
     config: &MongoConfiguration,
     state: &ConnectorState,
     query_request: QueryRequest,
-) -> Result<QueryResponse, MongoAgentError> {
-    let database = state.database();
-    // This function delegates to another function which gives is a point to inject a mock database
-    // implementation for testing.
-    execute_query_request(database, config, query_request).await
+) -> Result<Bytes, MongoAgentError> {
+    let collection = query_request.collection.clone();
+    let result = route_query_request(config, state, query_request).await;
+    if let Err(error) = &result {
+        state.query_observers().notify(|observer| {
+            observer.query_failed(&QueryFailed {
+                collection: &collection,
+                error,
+            })
+        });
+    }
+    result
+}
+
+async fn route_query_request(
+    config: &MongoConfiguration,
+    state: &ConnectorState,
+    mut query_request: QueryRequest,
+) -> Result<Bytes, MongoAgentError> {
+    if config.is_mock() {
+        return execute_mock_query_request(config, state.response_post_processors(), query_request);
+    }
+    let database = database_for_request(config, state, &mut query_request)?;
+    let _permit = match config.collection_concurrency() {
+        Some(options) => Some(
+            state
+                .bulkheads()
+                .acquire(options, &query_request.collection)
+                .await,
+        ),
+        None => None,
+    };
+    let query_log = state.query_logger().map(|logger| {
+        (
+            logger,
+            QueryShape::for_request(&query_request),
+            Instant::now(),
+        )
+    });
+    let comment = request_comment(query_request.collection.as_str());
+    // Recorded interactions are keyed by the commands that run, which depend on how requests are
+    // batched, so batching is not used while recording or replaying.
+    let result = match config.query_batching() {
+        Some(options) if config.recording().is_none() => {
+            execute_batched(state, database, comment, options, config, query_request).await
+        }
+        _ => execute_unbatched(database, comment, config, state, query_request).await,
+    };
+    if let Some((logger, shape, start)) = query_log {
+        logger.record(shape, start.elapsed(), result.is_ok());
+    }
+    result
+}
+
+/// Runs a request in a snapshot session if snapshot reads are enabled and the request may run
+/// several commands. The snapshot session wraps the database before recording so that recorded
+/// commands run in the session too.
+async fn execute_unbatched(
+    database: Database,
+    comment: Bson,
+    config: &MongoConfiguration,
+    state: &ConnectorState,
+    query_request: QueryRequest,
+) -> Result<Bytes, MongoAgentError> {
+    if config.snapshot_reads() && may_run_several_commands(&query_request) {
+        let database = SnapshotDatabase::start(database, state.snapshot_support()).await?;
+        execute_with_recording(database, comment, config, state, query_request).await
+    } else {
+        execute_with_recording(database, comment, config, state, query_request).await
+    }
+}
+
+async fn execute_with_recording<D>(
+    database: D,
+    comment: Bson,
+    config: &MongoConfiguration,
+    state: &ConnectorState,
+    query_request: QueryRequest,
+) -> Result<Bytes, MongoAgentError>
+where
+    D: DatabaseTrait + Clone + Send + Sync,
+    D::Collection: Clone + Send + Sync,
+    D::DocumentCursor: Send,
+{
+    match config.recording() {
+        Some(recording) => {
+            execute_query_request(
+                TaggedDatabase::new(RecordingDatabase::new(recording, database), comment),
+                config,
+                state.query_observers(),
+                state.response_post_processors(),
+                state.cursor_batch_sizes(),
+                query_request,
+            )
+            .await
+        }
+        // This function delegates to another function which gives is a point to inject a mock
+        // database implementation for testing.
+        None => {
+            execute_query_request(
+                TaggedDatabase::new(database, comment),
+                config,
+                state.query_observers(),
+                state.response_post_processors(),
+                state.cursor_batch_sizes(),
+                query_request,
+            )
+            .await
+        }
+    }
+}
+
+/// Requests with variable sets may be split into one command per group of variable sets
+fn may_run_several_commands(query_request: &QueryRequest) -> bool {
+    query_request
+        .variables
+        .as_ref()
+        .is_some_and(|variable_sets| variable_sets.len() > 1)
 }
 
 #[cfg(test)]
 mod tests {
-    use configuration::Configuration;
-    use mongodb::bson::{self, bson};
-    use ndc_models::{QueryResponse, RowSet};
+    use std::sync::{Arc, Mutex};
+
+    use configuration::{AggregateFunctionOptions, Configuration};
+    use mongodb::{
+        bson::{self, bson},
+        options::CountOptions,
+    };
+    use ndc_models::{OrderByElement, OrderByTarget, OrderDirection, QueryResponse, RowSet};
     use ndc_test_helpers::{
         binop, collection, column_aggregate, column_count_aggregate, field, named_type,
-        object_type, query, query_request, row_set, target, value,
+        object_type, query, query_request, row_set, star_count_aggregate, target, value,
     };
     use pretty_assertions::assert_eq;
     use serde_json::json;
@@ -54,9 +216,13 @@ mod tests {
     use super::execute_query_request;
     use crate::{
         mongo_query_plan::MongoConfiguration,
-        mongodb::test_helpers::{
-            mock_collection_aggregate_response, mock_collection_aggregate_response_for_pipeline,
+        mongodb::{
+            test_helpers::{
+                mock_collection_aggregate_response, mock_collection_aggregate_response_for_pipeline,
+            },
+            MockCollectionTrait, MockDatabaseTrait,
         },
+        monitoring::{PipelineIssued, PlanBuilt, QueryObserver, QueryObservers, ResponseReceived},
     };
 
     #[tokio::test]
@@ -88,7 +254,16 @@ mod tests {
             ]),
         );
 
-        let result = execute_query_request(db, &students_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
         Ok(())
     }
@@ -104,10 +279,7 @@ mod tests {
             .into();
 
         let expected_response = row_set()
-            .aggregates([
-                ("count", json!({ "$numberInt": "11" })),
-                ("avg", json!({ "$numberInt": "3" })),
-            ])
+            .aggregates([("count", json!({ "$numberInt": "11" })), ("avg", json!(3))])
             .into_response();
 
         let expected_pipeline = bson!([
@@ -158,7 +330,16 @@ mod tests {
             }]),
         );
 
-        let result = execute_query_request(db, &students_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(result, expected_response);
         Ok(())
     }
@@ -176,7 +357,7 @@ mod tests {
             .into();
 
         let expected_response = row_set()
-            .aggregates([("avg", json!({ "$numberDouble": "3.1" }))])
+            .aggregates([("avg", json!(3.1))])
             .row([("student_gpa", 3.1)])
             .into_response();
 
@@ -221,7 +402,383 @@ mod tests {
             }]),
         );
 
-        let result = execute_query_request(db, &students_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(result, expected_response);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn executes_facet_counts_with_fields() -> Result<(), anyhow::Error> {
+        let query_request = query_request()
+            .collection("students")
+            .query(
+                query()
+                    .aggregates([column_aggregate!("gpa_facets" => "gpa", "facet_counts")])
+                    .fields([field!("student_gpa" => "gpa")]),
+            )
+            .into();
+
+        let expected_response = row_set()
+            .aggregates([(
+                "gpa_facets",
+                json!([
+                    { "value": { "$numberDouble": "3.1" }, "count": { "$numberInt": "2" } },
+                    { "value": { "$numberDouble": "3.6" }, "count": { "$numberInt": "1" } },
+                ]),
+            )])
+            .rows([
+                [("student_gpa", 3.1)],
+                [("student_gpa", 3.1)],
+                [("student_gpa", 3.6)],
+            ])
+            .into_response();
+
+        let expected_pipeline = bson!([
+            {
+                "$facet": {
+                    "gpa_facets": [
+                        { "$match": { "gpa": { "$exists": true, "$ne": null } } },
+                        { "$group": { "_id": "$gpa", "count": { "$count": {} } } },
+                        { "$sort": { "count": -1, "_id": 1 } },
+                        {
+                            "$group": {
+                                "_id": null,
+                                "result": { "$push": { "value": "$_id", "count": "$count" } },
+                            }
+                        },
+                    ],
+                    "__ROWS__": [{
+                        "$replaceWith": {
+                            "student_gpa": { "$ifNull": ["$gpa", null] },
+                        },
+                    }],
+                },
+            },
+            {
+                "$replaceWith": {
+                    "aggregates": {
+                        "gpa_facets": { "$getField": {
+                            "field": "result",
+                            "input": { "$first": { "$getField": { "$literal": "gpa_facets" } } },
+                        } },
+                    },
+                    "rows": "$__ROWS__",
+                },
+            },
+        ]);
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "students",
+            expected_pipeline,
+            bson!([{
+                "aggregates": {
+                    "gpa_facets": [
+                        { "value": 3.1, "count": 2 },
+                        { "value": 3.6, "count": 1 },
+                    ],
+                },
+                "rows": [
+                    { "student_gpa": 3.1 },
+                    { "student_gpa": 3.1 },
+                    { "student_gpa": 3.6 },
+                ],
+            }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(result, expected_response);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn executes_aggregation_function_declared_with_arguments() -> Result<(), anyhow::Error> {
+        let mut config = students_config();
+        config.0.options.query_options.aggregate_functions = [(
+            "p90".into(),
+            AggregateFunctionOptions {
+                function: "percentile".into(),
+                arguments: [("p".into(), json!(0.9))].into(),
+            },
+        )]
+        .into();
+
+        let query_request = query_request()
+            .collection("students")
+            .query(query().aggregates([column_aggregate!("gpa_p90" => "gpa", "p90")]))
+            .into();
+
+        let expected_response = row_set()
+            .aggregates([("gpa_p90", json!({ "$numberDouble": "3.6" }))])
+            .into_response();
+
+        let expected_pipeline = bson!([
+            {
+                "$facet": {
+                    "gpa_p90": [
+                        { "$match": { "gpa": { "$exists": true, "$ne": null } } },
+                        {
+                            "$group": {
+                                "_id": null,
+                                "result": {
+                                    "$percentile": {
+                                        "input": "$gpa",
+                                        "p": [0.9],
+                                        "method": "approximate",
+                                    },
+                                },
+                            },
+                        },
+                        { "$set": { "result": { "$arrayElemAt": ["$result", 0] } } },
+                    ],
+                },
+            },
+            {
+                "$replaceWith": {
+                    "aggregates": {
+                        "gpa_p90": { "$getField": {
+                            "field": "result",
+                            "input": { "$first": { "$getField": { "$literal": "gpa_p90" } } },
+                        } },
+                    },
+                },
+            },
+        ]);
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "students",
+            expected_pipeline,
+            bson!([{
+                "aggregates": {
+                    "gpa_p90": 3.6,
+                },
+            }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(result, expected_response);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn executes_filtered_counts_in_one_aggregation() -> Result<(), anyhow::Error> {
+        let mut config = students_config();
+        config.0.options.query_options.aggregate_functions = [
+            (
+                "count_honors".into(),
+                AggregateFunctionOptions {
+                    function: "count_where".into(),
+                    arguments: [
+                        ("operator".into(), json!("_gte")),
+                        ("value".into(), json!(3.5)),
+                    ]
+                    .into(),
+                },
+            ),
+            (
+                "count_average".into(),
+                AggregateFunctionOptions {
+                    function: "count_where".into(),
+                    arguments: [("value".into(), json!(3.1))].into(),
+                },
+            ),
+        ]
+        .into();
+
+        let query_request = query_request()
+            .collection("students")
+            .query(query().aggregates([
+                column_aggregate!("honors" => "gpa", "count_honors"),
+                column_aggregate!("average" => "gpa", "count_average"),
+            ]))
+            .into();
+
+        let expected_response = row_set()
+            .aggregates([
+                ("average", json!({ "$numberInt": "2" })),
+                ("honors", json!({ "$numberInt": "1" })),
+            ])
+            .into_response();
+
+        let expected_pipeline = bson!([
+            {
+                "$facet": {
+                    "average": [
+                        { "$match": { "gpa": { "$exists": true, "$ne": null } } },
+                        {
+                            "$group": {
+                                "_id": null,
+                                "result": {
+                                    "$sum": {
+                                        "$cond": [{ "$eq": ["$gpa", { "$literal": 3.1 }] }, 1, 0],
+                                    },
+                                },
+                            },
+                        },
+                    ],
+                    "honors": [
+                        { "$match": { "gpa": { "$exists": true, "$ne": null } } },
+                        {
+                            "$group": {
+                                "_id": null,
+                                "result": {
+                                    "$sum": {
+                                        "$cond": [{ "$gte": ["$gpa", { "$literal": 3.5 }] }, 1, 0],
+                                    },
+                                },
+                            },
+                        },
+                    ],
+                },
+            },
+            {
+                "$replaceWith": {
+                    "aggregates": {
+                        "average": {
+                            "$ifNull": [
+                                {
+                                    "$getField": {
+                                        "field": "result",
+                                        "input": { "$first": { "$getField": { "$literal": "average" } } },
+                                    }
+                                },
+                                0,
+                            ]
+                        },
+                        "honors": {
+                            "$ifNull": [
+                                {
+                                    "$getField": {
+                                        "field": "result",
+                                        "input": { "$first": { "$getField": { "$literal": "honors" } } },
+                                    }
+                                },
+                                0,
+                            ]
+                        },
+                    },
+                },
+            },
+        ]);
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "students",
+            expected_pipeline,
+            bson!([{
+                "aggregates": {
+                    "average": 2,
+                    "honors": 1,
+                },
+            }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(result, expected_response);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sums_int_column_as_long() -> Result<(), anyhow::Error> {
+        let config = MongoConfiguration(Configuration {
+            collections: [collection("orders")].into(),
+            object_types: [(
+                "orders".into(),
+                object_type([("quantity", named_type("Int"))]),
+            )]
+            .into(),
+            ..Default::default()
+        });
+
+        let query_request = query_request()
+            .collection("orders")
+            .query(query().aggregates([column_aggregate!("total" => "quantity", "sum")]))
+            .into();
+
+        let expected_response = row_set()
+            .aggregates([("total", json!("4294967294"))])
+            .into_response();
+
+        let expected_pipeline = bson!([
+            {
+                "$facet": {
+                    "total": [
+                        { "$match": { "quantity": { "$exists": true, "$ne": null } } },
+                        {
+                            "$group": {
+                                "_id": null,
+                                "result": { "$sum": { "$toLong": "$quantity" } },
+                            },
+                        },
+                    ],
+                },
+            },
+            {
+                "$replaceWith": {
+                    "aggregates": {
+                        "total": { "$getField": {
+                            "field": "result",
+                            "input": { "$first": { "$getField": { "$literal": "total" } } },
+                        } },
+                    },
+                },
+            },
+        ]);
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "orders",
+            expected_pipeline,
+            bson!([{
+                "aggregates": {
+                    "total": 4294967294_i64,
+                },
+            }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(result, expected_response);
         Ok(())
     }
@@ -262,7 +819,16 @@ mod tests {
             }]),
         );
 
-        let result = execute_query_request(db, &comments_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &comments_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
         Ok(())
     }
@@ -281,11 +847,190 @@ mod tests {
 
         let db = mock_collection_aggregate_response("comments", bson!([]));
 
-        let result = execute_query_request(db, &comments_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &comments_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
         Ok(())
     }
 
+    #[tokio::test]
+    async fn appends_id_sort_for_deterministic_pagination() -> Result<(), anyhow::Error> {
+        let query_request = query_request()
+            .collection("students")
+            .query(
+                query()
+                    .fields([field!("student_gpa" => "gpa")])
+                    .order_by(vec![OrderByElement {
+                        order_direction: OrderDirection::Asc,
+                        target: OrderByTarget::Column {
+                            name: "gpa".into(),
+                            field_path: None,
+                            path: vec![],
+                        },
+                    }])
+                    .offset(10)
+                    .limit(5),
+            )
+            .into();
+
+        let expected_pipeline = bson!([
+            { "$sort": { "gpa": 1, "_id": 1 } },
+            { "$skip": 10 },
+            { "$limit": 5 },
+            { "$replaceWith": { "student_gpa": { "$ifNull": ["$gpa", null] } } },
+        ]);
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "students",
+            expected_pipeline,
+            bson!([{ "student_gpa": 3.1 }]),
+        );
+
+        let mut config = students_config();
+        config.0.options.query_options.deterministic_pagination = true;
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set().rows([[("student_gpa", 3.1)]]).into_response()
+        );
+        Ok(())
+    }
+
+    /// Records events for the collection named "observed"
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl QueryObserver for RecordingObserver {
+        fn plan_built(&self, event: &PlanBuilt<'_>) {
+            if event.query_plan.collection.as_str() == "observed" {
+                self.events.lock().unwrap().push("plan built".to_owned());
+            }
+        }
+
+        fn pipeline_issued(&self, event: &PipelineIssued<'_>) {
+            if event.collection.is_some_and(|c| c.as_str() == "observed") {
+                self.events.lock().unwrap().push(format!(
+                    "pipeline issued with {} stages",
+                    event.pipeline.stages.len()
+                ));
+            }
+        }
+
+        fn response_received(&self, event: &ResponseReceived<'_>) {
+            if event.collection.is_some_and(|c| c.as_str() == "observed") {
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("received {} documents", event.documents));
+            }
+        }
+    }
+
+    fn observed_config() -> MongoConfiguration {
+        MongoConfiguration(Configuration {
+            collections: [collection("observed")].into(),
+            object_types: [(
+                "observed".into(),
+                object_type([("title", named_type("String"))]),
+            )]
+            .into(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn notifies_query_observers() -> Result<(), anyhow::Error> {
+        let observer = Arc::new(RecordingObserver::default());
+        let observers = QueryObservers::new(vec![observer.clone()]);
+
+        let query_request = query_request()
+            .collection("observed")
+            .query(query().fields([field!("title")]))
+            .into();
+        let db = mock_collection_aggregate_response(
+            "observed",
+            bson!([{ "title": "Dune" }, { "title": "Emma" }]),
+        );
+        execute_query_request(
+            db,
+            &observed_config(),
+            &observers,
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            vec![
+                "plan built".to_owned(),
+                "pipeline issued with 1 stages".to_owned(),
+                "received 2 documents".to_owned(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notifies_query_observers_of_count_commands() -> Result<(), anyhow::Error> {
+        let observer = Arc::new(RecordingObserver::default());
+        let observers = QueryObservers::new(vec![observer.clone()]);
+
+        let query_request = query_request()
+            .collection("observed")
+            .query(query().aggregates([star_count_aggregate!("count")]))
+            .into();
+        let mut db = MockDatabaseTrait::new();
+        db.expect_collection().returning(|_| {
+            let mut collection = MockCollectionTrait::new();
+            collection
+                .expect_count_documents()
+                .returning(|_: bson::Document, _: CountOptions| Ok(3));
+            collection
+        });
+        execute_query_request(
+            db,
+            &observed_config(),
+            &observers,
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+
+        // The count command is reported as the equivalent `$match` and `$count` pipeline
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            vec![
+                "plan built".to_owned(),
+                "pipeline issued with 2 stages".to_owned(),
+                "received 1 documents".to_owned(),
+            ]
+        );
+        Ok(())
+    }
+
     fn students_config() -> MongoConfiguration {
         MongoConfiguration(Configuration {
             collections: [collection("students")].into(),
@@ -298,6 +1043,10 @@ mod tests {
             procedures: Default::default(),
             native_mutations: Default::default(),
             native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
             options: Default::default(),
         })
     }
@@ -314,6 +1063,10 @@ mod tests {
             procedures: Default::default(),
             native_mutations: Default::default(),
             native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
             options: Default::default(),
         })
     }
@@ -1,51 +1,142 @@
 use std::collections::BTreeMap;
 
-use configuration::native_query::NativeQuery;
-use itertools::Itertools as _;
+use anyhow::anyhow;
+use configuration::{
+    native_query::NativeQuery,
+    pushdown::{PushdownKind, PushdownPoint},
+    raw_pipeline,
+    system_collections::{referenced_namespaces, Namespace},
+    ConfigurationRawPipelineOptions,
+};
+use mongodb::bson::Bson;
 use ndc_models::Argument;
 
 use crate::{
     interface_types::MongoAgentError,
     mongo_query_plan::{MongoConfiguration, QueryPlan},
     mongodb::{Pipeline, Stage},
-    procedure::{interpolated_command, ProcedureError},
+    procedure::{interpolate_placeholders, ProcedureError},
+};
+
+use super::{
+    arguments::resolve_arguments, lookup_function::pipeline_for_lookup_function,
+    query_target::QueryTarget, raw_pipeline::check_raw_pipeline,
 };
 
-use super::{arguments::resolve_arguments, query_target::QueryTarget};
+/// Stages from a query request that may be injected at pushdown points in a native query
+/// pipeline. Stages are taken as they are injected so that whatever remains must be applied after
+/// the native query pipeline.
+#[derive(Debug, Default)]
+pub struct PushdownStages {
+    pub match_stage: Option<Stage>,
+    pub sort_stage: Option<Stage>,
+    pub skip_stage: Option<Stage>,
+    pub limit_stage: Option<Stage>,
+}
 
 /// Returns either the pipeline defined by a native query with variable bindings for arguments, or
 /// an empty pipeline if the query request target is not a native query
 pub fn pipeline_for_native_query(
     config: &MongoConfiguration,
     query_request: &QueryPlan,
+    pushdown: &mut PushdownStages,
 ) -> Result<Pipeline, MongoAgentError> {
     match QueryTarget::for_request(config, query_request) {
         QueryTarget::Collection(_) => Ok(Pipeline::empty()),
         QueryTarget::NativeQuery {
+            name,
             native_query,
             arguments,
-            ..
-        } => make_pipeline(native_query, arguments),
+        } => {
+            let raw_pipeline_options = config
+                .raw_pipeline()
+                .filter(|options| options.function_name.as_str() == name.as_str());
+            let pipeline = make_pipeline(
+                config,
+                native_query,
+                arguments,
+                raw_pipeline_options,
+                pushdown,
+            )?;
+            match config.lookup_functions().get(name.as_str()) {
+                Some(lookup_function) => {
+                    pipeline_for_lookup_function(config, lookup_function, pipeline)
+                }
+                None => Ok(pipeline),
+            }
+        }
     }
 }
 
 fn make_pipeline(
+    config: &MongoConfiguration,
     native_query: &NativeQuery,
     arguments: &BTreeMap<ndc_models::ArgumentName, Argument>,
+    raw_pipeline_options: Option<&ConfigurationRawPipelineOptions>,
+    pushdown: &mut PushdownStages,
 ) -> Result<Pipeline, MongoAgentError> {
     let bson_arguments = resolve_arguments(&native_query.arguments, arguments.clone())
         .map_err(ProcedureError::UnresolvableArguments)?;
 
-    // Replace argument placeholders with resolved expressions, convert document list to
-    // a `Pipeline` value
-    let stages = native_query
-        .pipeline
-        .iter()
-        .map(|document| interpolated_command(document, &bson_arguments))
-        .map_ok(Stage::Other)
-        .try_collect()?;
+    // The pipeline given to the raw pipeline function is checked before it is spliced in
+    if let Some(options) = raw_pipeline_options {
+        let pipeline = bson_arguments
+            .get(raw_pipeline::PIPELINE_ARGUMENT)
+            .unwrap_or(&Bson::Null);
+        check_raw_pipeline(options, pipeline)?;
+    }
+
+    // Replace argument placeholders with resolved expressions at the positions that were recorded
+    // when configuration was loaded, convert document list to a `Pipeline` value
+    let mut stages = native_query.pipeline.clone();
+    interpolate_placeholders(&mut stages, &native_query.placeholders, &bson_arguments)?;
 
-    Ok(Pipeline::new(stages))
+    // Arguments may name collections, so system collections are checked after interpolation
+    if !config.allow_system_collections() {
+        if let Some(namespace) = referenced_namespaces(&stages)
+            .into_iter()
+            .find(Namespace::is_system)
+        {
+            return Err(MongoAgentError::BadQuery(anyhow!(
+                "native query arguments may not reference the system collection or database, {}",
+                namespace.name()
+            )));
+        }
+    }
+
+    // Pushdown points are replaced after interpolation so that placeholder positions still line up
+    // with pipeline stages
+    let mut pipeline = Pipeline::empty();
+    for (index, stage) in stages.into_iter().enumerate() {
+        match native_query
+            .pushdown_points
+            .iter()
+            .find(|point| point.stage == index)
+        {
+            Some(point) => pipeline.append(inject_pushdown_stages(point, pushdown)),
+            None => pipeline.push(Stage::Other(stage)),
+        }
+    }
+    Ok(pipeline)
+}
+
+fn inject_pushdown_stages(point: &PushdownPoint, pushdown: &mut PushdownStages) -> Pipeline {
+    let mut stages = Vec::new();
+    if point.kinds.contains(&PushdownKind::Match) {
+        stages.extend(pushdown.match_stage.take());
+    }
+    if point.kinds.contains(&PushdownKind::Sort) {
+        stages.extend(pushdown.sort_stage.take());
+    }
+    // Offset and limit are only correct once documents have been filtered and sorted
+    if point.kinds.contains(&PushdownKind::Limit)
+        && pushdown.match_stage.is_none()
+        && pushdown.sort_stage.is_none()
+    {
+        stages.extend(pushdown.skip_stage.take());
+        stages.extend(pushdown.limit_stage.take());
+    }
+    Pipeline::new(stages)
 }
 
 #[cfg(test)]
@@ -54,18 +145,19 @@ mod tests {
         native_query::NativeQueryRepresentation,
         schema::{ObjectField, ObjectType, Type},
         serialized::NativeQuery,
-        Configuration,
+        Configuration, ConfigurationOptions,
     };
-    use mongodb::bson::{bson, doc};
+    use mongodb::bson::{bson, doc, Document};
     use mongodb_support::BsonScalarType as S;
-    use ndc_models::Argument;
-    use ndc_test_helpers::{field, query, query_request, row_set};
+    use ndc_models::{Argument, OrderByElement, OrderByTarget, OrderDirection};
+    use ndc_test_helpers::{binop, field, object, query, query_request, row_set, target, value};
     use pretty_assertions::assert_eq;
     use serde_json::json;
 
     use crate::{
         mongo_query_plan::MongoConfiguration,
-        mongodb::test_helpers::mock_aggregate_response_for_pipeline, query::execute_query_request,
+        mongodb::{test_helpers::mock_aggregate_response_for_pipeline, MockDatabaseTrait},
+        query::execute_query_request,
     };
 
     #[tokio::test]
@@ -79,6 +171,11 @@ mod tests {
                     ObjectField {
                         r#type: Type::ExtendedJSON,
                         description: None,
+                        deprecated: false,
+                        previous_names: Default::default(),
+                        read_transform: None,
+                        write_rules: None,
+                        object_id_format: None,
                     },
                 ),
                 (
@@ -86,6 +183,11 @@ mod tests {
                     ObjectField {
                         r#type: Type::ArrayOf(Box::new(Type::Scalar(S::Double))),
                         description: None,
+                        deprecated: false,
+                        previous_names: Default::default(),
+                        read_transform: None,
+                        write_rules: None,
+                        object_id_format: None,
                     },
                 ),
                 (
@@ -93,6 +195,11 @@ mod tests {
                     ObjectField {
                         r#type: Type::Scalar(S::Int),
                         description: None,
+                        deprecated: false,
+                        previous_names: Default::default(),
+                        read_transform: None,
+                        write_rules: None,
+                        object_id_format: None,
                     },
                 ),
                 (
@@ -100,6 +207,11 @@ mod tests {
                     ObjectField {
                         r#type: Type::Scalar(S::Int),
                         description: None,
+                        deprecated: false,
+                        previous_names: Default::default(),
+                        read_transform: None,
+                        write_rules: None,
+                        object_id_format: None,
                     },
                 ),
             ]
@@ -115,6 +227,11 @@ mod tests {
                             ObjectField {
                                 r#type: Type::Scalar(S::ObjectId),
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                         (
@@ -122,6 +239,11 @@ mod tests {
                             ObjectField {
                                 r#type: Type::Scalar(S::String),
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                         (
@@ -129,6 +251,11 @@ mod tests {
                             ObjectField {
                                 r#type: Type::ArrayOf(Box::new(Type::Scalar(S::String))),
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                         (
@@ -136,10 +263,16 @@ mod tests {
                             ObjectField {
                                 r#type: Type::Scalar(S::Int),
                                 description: None,
+                                deprecated: false,
+                                previous_names: Default::default(),
+                                read_transform: None,
+                                write_rules: None,
+                                object_id_format: None,
                             },
                         ),
                     ]
                     .into(),
+                    extends: Default::default(),
                 },
             )]
             .into(),
@@ -153,6 +286,7 @@ mod tests {
                 "limit": "{{ limit }}"
               }
             }],
+            materialized: None,
             description: None,
         };
 
@@ -257,8 +391,359 @@ mod tests {
             ]),
         );
 
-        let result = execute_query_request(db, &config, request).await?;
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn injects_request_stages_at_pushdown_point() -> Result<(), anyhow::Error> {
+        let config = sales_config(vec![
+            doc! { "$unwind": "$sales" },
+            doc! { "$replaceWith": "$sales" },
+            doc! { "$pushdown": ["match", "sort", "limit"] },
+            doc! { "$lookup": { "from": "regions", "localField": "region", "foreignField": "_id", "as": "regionInfo" } },
+        ])?;
+
+        let request = query_request()
+            .collection("sales")
+            .query(
+                query()
+                    .fields([field!("amount"), field!("region")])
+                    .predicate(binop("_eq", target!("region"), value!("west")))
+                    .order_by(vec![OrderByElement {
+                        order_direction: OrderDirection::Desc,
+                        target: OrderByTarget::Column {
+                            name: "amount".into(),
+                            field_path: None,
+                            path: vec![],
+                        },
+                    }])
+                    .offset(2)
+                    .limit(5),
+            )
+            .into();
+
+        let expected_pipeline = bson!([
+            { "$unwind": "$sales" },
+            { "$replaceWith": "$sales" },
+            { "$match": { "region": { "$eq": "west" } } },
+            { "$sort": { "amount": -1 } },
+            { "$skip": 2 },
+            { "$limit": 5 },
+            { "$lookup": { "from": "regions", "localField": "region", "foreignField": "_id", "as": "regionInfo" } },
+            { "$limit": 5 },
+            {
+                "$replaceWith": {
+                    "amount": { "$ifNull": ["$amount", null] },
+                    "region": { "$ifNull": ["$region", null] },
+                }
+            },
+        ]);
+
+        let db = mock_aggregate_response_for_pipeline(
+            expected_pipeline,
+            bson!([{ "amount": 40, "region": "west" }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .rows([[("amount", json!(40)), ("region", json!("west"))]])
+                .into_response()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_native_query_order_when_request_has_no_order_by() -> Result<(), anyhow::Error> {
+        let mut config = sales_config(vec![doc! { "$sort": { "amount": -1 } }])?;
+        config.0.options.query_options.deterministic_pagination = true;
+
+        let request = query_request()
+            .collection("sales")
+            .query(
+                query()
+                    .fields([field!("amount")])
+                    .predicate(binop("_eq", target!("region"), value!("west")))
+                    .offset(1)
+                    .limit(2),
+            )
+            .into();
+
+        // Filtering and paging come after the native query's own `$sort`, and no `_id` sort is
+        // added that would replace the native query's order
+        let expected_pipeline = bson!([
+            { "$sort": { "amount": -1 } },
+            { "$match": { "region": { "$eq": "west" } } },
+            { "$skip": 1 },
+            { "$limit": 2 },
+            { "$replaceWith": { "amount": { "$ifNull": ["$amount", null] } } },
+        ]);
+
+        let db = mock_aggregate_response_for_pipeline(
+            expected_pipeline,
+            bson!([{ "amount": 30 }, { "amount": 20 }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .rows([[("amount", json!(30))], [("amount", json!(20))]])
+                .into_response()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn applies_request_sort_after_native_query_sort() -> Result<(), anyhow::Error> {
+        let config = sales_config(vec![
+            doc! { "$sort": { "amount": -1 } },
+            doc! { "$limit": 100 },
+        ])?;
+
+        let request = query_request()
+            .collection("sales")
+            .query(
+                query()
+                    .fields([field!("region")])
+                    .order_by(vec![OrderByElement {
+                        order_direction: OrderDirection::Asc,
+                        target: OrderByTarget::Column {
+                            name: "region".into(),
+                            field_path: None,
+                            path: vec![],
+                        },
+                    }])
+                    .limit(3),
+            )
+            .into();
+
+        // The request's sort and limit apply to the native query's top 100, not to the whole
+        // input collection
+        let expected_pipeline = bson!([
+            { "$sort": { "amount": -1 } },
+            { "$limit": 100 },
+            { "$sort": { "region": 1 } },
+            { "$limit": 3 },
+            { "$replaceWith": { "region": { "$ifNull": ["$region", null] } } },
+        ]);
+
+        let db =
+            mock_aggregate_response_for_pipeline(expected_pipeline, bson!([{ "region": "east" }]));
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .rows([[("region", json!("east"))]])
+                .into_response()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn projects_selected_nested_fields_of_native_query_results() -> Result<(), anyhow::Error>
+    {
+        let config = sales_config(vec![doc! { "$match": { "status": "complete" } }])?;
+
+        let request = query_request()
+            .collection("sales")
+            .query(query().fields([field!("customer" => "customer", object!([field!("name")]))]))
+            .into();
+
+        // Only the selected field of `customer` is sent back, not the full native query result
+        let expected_pipeline = bson!([
+            { "$match": { "status": "complete" } },
+            {
+                "$replaceWith": {
+                    "customer": {
+                        "$cond": {
+                            "if": "$customer",
+                            "then": { "name": { "$ifNull": ["$customer.name", null] } },
+                            "else": null,
+                        }
+                    },
+                }
+            },
+        ]);
+
+        let db = mock_aggregate_response_for_pipeline(
+            expected_pipeline,
+            bson!([{ "customer": { "name": "Ada" } }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .rows([[("customer", json!({ "name": "Ada" }))]])
+                .into_response()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_arguments_that_reference_system_collections() -> Result<(), anyhow::Error> {
+        let config = MongoConfiguration(Configuration::validate(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            ConfigurationOptions {
+                raw_pipeline: Some(Default::default()),
+                ..Default::default()
+            },
+        )?);
+
+        let request_with_arguments = |collection: &str, pipeline: serde_json::Value| {
+            query_request()
+                .collection("_raw_pipeline")
+                .query(query().fields([field!("__value")]))
+                .arguments([
+                    (
+                        "collection",
+                        Argument::Literal {
+                            value: json!(collection),
+                        },
+                    ),
+                    ("pipeline", Argument::Literal { value: pipeline }),
+                ])
+                .into()
+        };
+
+        let system_collection = request_with_arguments("system.users", json!([]));
+        let result = execute_query_request(
+            MockDatabaseTrait::new(),
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            system_collection,
+        )
+        .await;
+        assert!(result.is_err());
+
+        let system_lookup = request_with_arguments(
+            "movies",
+            json!([{ "$lookup": { "from": "system.users", "as": "users", "pipeline": [] } }]),
+        );
+        let result = execute_query_request(
+            MockDatabaseTrait::new(),
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            system_lookup,
+        )
+        .await;
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    /// Configuration with a native query named `sales` that runs the given pipeline
+    fn sales_config(pipeline: Vec<Document>) -> anyhow::Result<MongoConfiguration> {
+        let field_of_type = |r#type| ObjectField {
+            r#type,
+            description: None,
+            deprecated: false,
+            previous_names: Default::default(),
+            read_transform: None,
+            write_rules: None,
+            object_id_format: None,
+        };
+        let native_query = NativeQuery {
+            representation: NativeQueryRepresentation::Collection,
+            input_collection: None,
+            arguments: Default::default(),
+            result_document_type: "Sale".into(),
+            object_types: [
+                (
+                    "Sale".into(),
+                    ObjectType {
+                        description: None,
+                        fields: [
+                            ("_id".into(), field_of_type(Type::Scalar(S::ObjectId))),
+                            ("amount".into(), field_of_type(Type::Scalar(S::Int))),
+                            ("region".into(), field_of_type(Type::Scalar(S::String))),
+                            (
+                                "customer".into(),
+                                field_of_type(Type::Object("Customer".into())),
+                            ),
+                        ]
+                        .into(),
+                        extends: Default::default(),
+                    },
+                ),
+                (
+                    "Customer".into(),
+                    ObjectType {
+                        description: None,
+                        fields: [
+                            ("name".into(), field_of_type(Type::Scalar(S::String))),
+                            ("email".into(), field_of_type(Type::Scalar(S::String))),
+                        ]
+                        .into(),
+                        extends: Default::default(),
+                    },
+                ),
+            ]
+            .into(),
+            pipeline,
+            materialized: None,
+            description: None,
+        };
+        Ok(MongoConfiguration(Configuration::validate(
+            Default::default(),
+            Default::default(),
+            [("sales".into(), native_query)].into(),
+            Default::default(),
+        )?))
+    }
 }
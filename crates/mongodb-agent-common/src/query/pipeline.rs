@@ -1,22 +1,36 @@
 use std::collections::BTreeMap;
 
+use configuration::MongoScalarType;
 use mongodb::bson::{self, doc, Bson};
+use mongodb_support::BsonScalarType;
 use tracing::instrument;
 
 use crate::{
     aggregation_function::AggregationFunction,
+    comparison_function::ComparisonFunction,
     interface_types::MongoAgentError,
-    mongo_query_plan::{Aggregate, MongoConfiguration, Query, QueryPlan},
+    mongo_query_plan::{
+        Aggregate, ComparisonTarget, ComparisonValue, Expression, MongoConfiguration, OrderBy,
+        OrderByTarget, Query, QueryPlan, Type,
+    },
     mongodb::{sanitize::get_field, Accumulator, Pipeline, Selection, Stage},
 };
 
 use super::{
+    compatibility::rewrite_pipeline,
     constants::{RESULT_FIELD, ROWS_FIELD},
+    document_size::{row_size_guard_stage, slice_array_fields},
+    field_aliases::field_aliases_stage,
     foreach::pipeline_for_foreach,
+    gap_fill::gap_fill_stages,
     make_selector, make_sort,
-    native_query::pipeline_for_native_query,
+    native_query::{pipeline_for_native_query, PushdownStages},
     query_level::QueryLevel,
+    query_target::QueryTarget,
+    read_transforms::read_transforms_stage,
     relations::pipeline_for_relations,
+    soft_delete::soft_delete_filter,
+    window_fields::window_fields_stages,
 };
 
 /// A query that includes aggregates will be run using a $facet pipeline stage, while a query
@@ -35,10 +49,18 @@ pub fn pipeline_for_query_request(
     config: &MongoConfiguration,
     query_plan: &QueryPlan,
 ) -> Result<Pipeline, MongoAgentError> {
-    if let Some(variable_sets) = &query_plan.variables {
-        pipeline_for_foreach(variable_sets, config, query_plan)
+    let mut pipeline = if let Some(variable_sets) = &query_plan.variables {
+        pipeline_for_foreach(variable_sets, config, query_plan)?
     } else {
-        pipeline_for_non_foreach(config, query_plan, QueryLevel::Top)
+        pipeline_for_non_foreach(config, query_plan, QueryLevel::Top)?
+    };
+    if let Some(stage) = row_size_guard_stage(config, query_plan) {
+        pipeline.push(stage);
+    }
+    if config.compatibility_mode() {
+        rewrite_pipeline(pipeline)
+    } else {
+        Ok(pipeline)
     }
 }
 
@@ -52,34 +74,83 @@ pub fn pipeline_for_non_foreach(
 ) -> Result<Pipeline, MongoAgentError> {
     let query = &query_plan.query;
     let Query {
-        offset,
-        order_by,
-        predicate,
-        ..
+        offset, predicate, ..
     } = query;
     let mut pipeline = Pipeline::empty();
 
-    // If this is a native query then we start with the native query's pipeline
-    pipeline.append(pipeline_for_native_query(config, query_plan)?);
-
-    // Stages common to aggregate and row queries.
-    pipeline.append(pipeline_for_relations(config, query_plan)?);
+    // Renamed fields are read from their previous names before any stage references them
+    if let Some(stage) = field_aliases_stage(config, &query_plan.collection) {
+        pipeline.push(stage);
+    }
+    if let Some(stage) = read_transforms_stage(config, &query_plan.collection, true)? {
+        pipeline.push(stage);
+    }
 
-    let match_stage = predicate
+    let mut match_stage = predicate
         .as_ref()
-        .map(make_selector)
+        .map(|predicate| make_selector(config, predicate))
         .transpose()?
         .map(Stage::Match);
-    let sort_stage: Option<Stage> = order_by
-        .iter()
-        .map(|o| Ok(Stage::Sort(make_sort(o)?)) as Result<_, MongoAgentError>)
-        .next()
-        .transpose()?;
-    let skip_stage = offset.map(Stage::Skip);
+    let mut sort_stage: Option<Stage> = sort_stage(config, query_plan)?;
+    let mut skip_stage = offset.map(Stage::Skip);
+    let soft_delete_filter = soft_delete_filter(config, query_plan, query_level)?;
+
+    // Gaps are filled after filtering so that the filled range is the range of matching
+    // documents, and before sorting so that inserted documents are sorted with the others.
+    // Window fields are computed over filled documents, and before sorting so that queries can
+    // order by window fields.
+    let gap_fill_stages = gap_fill_stages(config, query_plan)?;
+    let window_fields_stages = window_fields_stages(config, query_plan)?;
+
+    // If this is a native query then we start with the native query's pipeline. Stages that can be
+    // pushed down into the native query pipeline are offered to it, and are applied here only if
+    // the native query pipeline does not take them.
+    let push_match = predicate.as_ref().map_or(true, is_pushable_predicate);
+    let push_sort = gap_fill_stages.is_empty()
+        && window_fields_stages.is_empty()
+        && query.order_by.as_ref().map_or(true, is_pushable_order_by);
+    let push_limit =
+        push_match && push_sort && soft_delete_filter.is_none() && !is_response_faceted(query);
+    let mut pushdown = PushdownStages::default();
+    if push_match {
+        pushdown.match_stage = match_stage.take();
+    }
+    if push_sort {
+        pushdown.sort_stage = sort_stage.take();
+    }
+    if push_limit {
+        pushdown.skip_stage = skip_stage.take();
+        pushdown.limit_stage = query.limit.map(Stage::Limit);
+    }
+    pipeline.append(pipeline_for_native_query(
+        config,
+        query_plan,
+        &mut pushdown,
+    )?);
+
+    // Exclude soft-deleted documents before joining relations
+    if let Some(filter) = soft_delete_filter {
+        pipeline.push(Stage::Match(filter));
+    }
 
-    [match_stage, sort_stage, skip_stage]
+    // Stages common to aggregate and row queries.
+    pipeline.append(pipeline_for_relations(config, query_plan)?);
+
+    // Stages that were not taken by a native query pipeline are applied after joining relations.
+    // A pushed-down `$limit` is repeated by `pipeline_for_fields_facet` which is harmless.
+    match_stage
+        .or(pushdown.match_stage)
         .into_iter()
-        .flatten()
+        .chain(gap_fill_stages)
+        .chain(window_fields_stages)
+        .chain(
+            [
+                sort_stage.or(pushdown.sort_stage),
+                skip_stage.or(pushdown.skip_stage),
+            ]
+            .into_iter()
+            .flatten(),
+        )
         .for_each(|stage| pipeline.push(stage));
 
     // `diverging_stages` includes either a $facet stage if the query includes aggregates, or the
@@ -87,23 +158,96 @@ pub fn pipeline_for_non_foreach(
     // a $replaceWith.
     let diverging_stages = if is_response_faceted(query) {
         let (facet_pipelines, select_facet_results) =
-            facet_pipelines_for_query(query_plan, query_level)?;
+            facet_pipelines_for_query(config, query_plan, query_level)?;
         let aggregation_stages = Stage::Facet(facet_pipelines);
         let replace_with_stage = Stage::ReplaceWith(select_facet_results);
         Pipeline::from_iter([aggregation_stages, replace_with_stage])
     } else {
-        pipeline_for_fields_facet(query_plan, query_level)?
+        pipeline_for_fields_facet(config, query_plan, query_level)?
     };
 
     pipeline.append(diverging_stages);
     Ok(pipeline)
 }
 
+/// A predicate can be pushed into a native query pipeline if it only references columns of the
+/// native query's result documents
+fn is_pushable_predicate(predicate: &Expression) -> bool {
+    let is_local_target = |target: &ComparisonTarget| match target {
+        ComparisonTarget::Column { path, .. } => path.is_empty(),
+        ComparisonTarget::ColumnInScope { .. } => false,
+    };
+    match predicate {
+        Expression::And { expressions } | Expression::Or { expressions } => {
+            expressions.iter().all(is_pushable_predicate)
+        }
+        Expression::Not { expression } => is_pushable_predicate(expression),
+        Expression::UnaryComparisonOperator { column, .. } => is_local_target(column),
+        Expression::BinaryComparisonOperator { column, value, .. } => {
+            is_local_target(column)
+                && match value {
+                    ComparisonValue::Column { column } => is_local_target(column),
+                    ComparisonValue::Scalar { .. } | ComparisonValue::Variable { .. } => true,
+                }
+        }
+        Expression::Exists { .. } => false,
+    }
+}
+
+fn is_pushable_order_by(order_by: &OrderBy) -> bool {
+    order_by
+        .elements
+        .iter()
+        .all(|element| match &element.target {
+            OrderByTarget::Column { path, .. } => path.is_empty(),
+            OrderByTarget::SingleColumnAggregate { .. }
+            | OrderByTarget::StarCountAggregate { .. } => false,
+        })
+}
+
+fn sort_stage(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+) -> Result<Option<Stage>, MongoAgentError> {
+    // Native query pipelines may sort their own output. If the request does not specify an order
+    // then that order is kept: a `$sort` on `_id` for deterministic pagination would replace it.
+    let is_native_query = matches!(
+        QueryTarget::for_request(config, query_plan),
+        QueryTarget::NativeQuery { .. }
+    );
+    if is_native_query && query_plan.query.order_by.is_none() {
+        return Ok(None);
+    }
+    Ok(sort_document(config, &query_plan.query)?.map(Stage::Sort))
+}
+
+/// Produces a sort document for the query's `order_by`. If deterministic pagination is enabled,
+/// and the query uses `limit` or `offset`, sorting by `_id` is appended to break ties.
+pub fn sort_document(
+    config: &MongoConfiguration,
+    query: &Query,
+) -> Result<Option<bson::Document>, MongoAgentError> {
+    let sort = query.order_by.as_ref().map(make_sort).transpose()?;
+    let paginated =
+        query.limit.is_some() || query.aggregates_limit.is_some() || query.offset.is_some();
+    let sort = if paginated && config.deterministic_pagination() {
+        let mut sort = sort.unwrap_or_default();
+        if !sort.contains_key("_id") {
+            sort.insert("_id", 1);
+        }
+        Some(sort)
+    } else {
+        sort
+    };
+    Ok(sort)
+}
+
 /// Generate a pipeline to select fields requested by the given query. This is intended to be used
 /// within a $facet stage. We assume that the query's `where`, `order_by`, `offset` criteria (which
 /// are shared with aggregates) have already been applied, and that we have already joined
-/// relations.
+/// relations. Read transformations that do not apply to predicates and sorting are applied here.
 pub fn pipeline_for_fields_facet(
+    config: &MongoConfiguration,
     query_plan: &QueryPlan,
     query_level: QueryLevel,
 ) -> Result<Pipeline, MongoAgentError> {
@@ -114,6 +258,9 @@ pub fn pipeline_for_fields_facet(
     } = &query_plan.query;
 
     let mut selection = Selection::from_query_request(query_plan)?;
+    if let Some(fields) = &query_plan.query.fields {
+        slice_array_fields(config, fields, &mut selection.0);
+    }
     if query_level != QueryLevel::Top {
         // Queries higher up the chain might need to reference relationships from this query. So we
         // forward relationship arrays if this is not the top-level query.
@@ -126,10 +273,11 @@ pub fn pipeline_for_fields_facet(
     }
 
     let limit_stage = limit.map(Stage::Limit);
+    let transforms_stage = read_transforms_stage(config, &query_plan.collection, false)?;
     let replace_with_stage: Stage = Stage::ReplaceWith(selection);
 
     Ok(Pipeline::from_iter(
-        [limit_stage, replace_with_stage.into()]
+        [limit_stage, transforms_stage, replace_with_stage.into()]
             .into_iter()
             .flatten(),
     ))
@@ -139,6 +287,7 @@ pub fn pipeline_for_fields_facet(
 /// a `Selection` that converts results of each pipeline to a format compatible with
 /// `QueryResponse`.
 fn facet_pipelines_for_query(
+    config: &MongoConfiguration,
     query_plan: &QueryPlan,
     query_level: QueryLevel,
 ) -> Result<(BTreeMap<String, Pipeline>, Selection), MongoAgentError> {
@@ -161,7 +310,7 @@ fn facet_pipelines_for_query(
         .collect::<Result<BTreeMap<_, _>, MongoAgentError>>()?;
 
     if fields.is_some() {
-        let fields_pipeline = pipeline_for_fields_facet(query_plan, query_level)?;
+        let fields_pipeline = pipeline_for_fields_facet(config, query_plan, query_level)?;
         facet_pipelines.insert(ROWS_FIELD.to_owned(), fields_pipeline);
     }
 
@@ -262,16 +411,70 @@ fn pipeline_for_aggregate(
         ),
 
         Aggregate::SingleColumn {
-            column, function, ..
+            column,
+            function,
+            arguments,
+            result_type,
         } => {
             use AggregationFunction::*;
 
-            let accumulator = match function {
-                Avg => Accumulator::Avg(field_ref(column.as_str())),
-                Count => Accumulator::Count,
-                Min => Accumulator::Min(field_ref(column.as_str())),
-                Max => Accumulator::Max(field_ref(column.as_str())),
-                Sum => Accumulator::Sum(field_ref(column.as_str())),
+            let arguments = function.parse_arguments(&arguments)?;
+            let input = match function {
+                // Convert each value so that a sum of 32-bit integers does not overflow
+                Sum if result_type == Type::Scalar(MongoScalarType::Bson(BsonScalarType::Long)) => {
+                    bson::bson!({ "$toLong": field_ref(column.as_str()) })
+                }
+                _ => field_ref(column.as_str()),
+            };
+            // Some functions collect values into an array in the result field, and compute the
+            // result from that array in a following stage
+            let (accumulator, result_expression) = match function {
+                Avg | Count | Sum if arguments.distinct => (
+                    Accumulator::AddToSet(input),
+                    Some(match function {
+                        Avg => bson::bson!({ "$avg": "$result" }),
+                        Count => bson::bson!({ "$size": "$result" }),
+                        _ => bson::bson!({ "$sum": "$result" }),
+                    }),
+                ),
+                Avg => (Accumulator::Avg(input), None),
+                Count => (Accumulator::Count, None),
+                Min => (Accumulator::Min(input), None),
+                Max => (Accumulator::Max(input), None),
+                Sum => (Accumulator::Sum(input), None),
+                FacetCounts => return Ok(pipeline_for_facet_counts(column.as_str(), limit)),
+                Percentile => (
+                    Accumulator::Percentile {
+                        input,
+                        p: arguments.p.into_iter().collect(),
+                        method: "approximate".to_owned(),
+                    },
+                    Some(bson::bson!({ "$arrayElemAt": ["$result", 0] })),
+                ),
+                Concat => (
+                    if arguments.distinct {
+                        Accumulator::AddToSet(input)
+                    } else {
+                        Accumulator::Push(input)
+                    },
+                    Some(join_strings(
+                        "$result",
+                        arguments.separator.as_deref().unwrap_or(", "),
+                    )),
+                ),
+                CountWhere => {
+                    let condition = arguments
+                        .operator
+                        .unwrap_or(ComparisonFunction::Equal)
+                        .mongodb_aggregation_expression(
+                            input,
+                            bson::bson!({ "$literal": arguments.value.unwrap_or(Bson::Null) }),
+                        );
+                    (
+                        Accumulator::Sum(bson::bson!({ "$cond": [condition, 1, 0] })),
+                        None,
+                    )
+                }
             };
             Pipeline::from_iter(
                 [
@@ -283,6 +486,9 @@ fn pipeline_for_aggregate(
                         key_expression: Bson::Null,
                         accumulators: [(RESULT_FIELD.to_string(), accumulator)].into(),
                     }),
+                    result_expression.map(|expression| {
+                        Stage::Other(doc! { "$set": { RESULT_FIELD: expression } })
+                    }),
                 ]
                 .into_iter()
                 .flatten(),
@@ -300,3 +506,48 @@ fn pipeline_for_aggregate(
     };
     Ok(pipeline)
 }
+
+/// Joins an array of strings with the given separator
+fn join_strings(array: &str, separator: &str) -> Bson {
+    bson::bson!({
+        "$reduce": {
+            "input": array,
+            "initialValue": null,
+            "in": {
+                "$cond": {
+                    "if": { "$eq": ["$$value", null] },
+                    "then": "$$this",
+                    "else": { "$concat": ["$$value", { "$literal": separator }, "$$this"] },
+                },
+            },
+        }
+    })
+}
+
+/// Groups documents by the value of the given column, and collects `{ value, count }` documents
+/// for each group into a single array in the `result` field
+fn pipeline_for_facet_counts(column: &str, limit: Option<u32>) -> Pipeline {
+    Pipeline::from_iter(
+        [
+            Some(Stage::Match(
+                bson::doc! { column: { "$exists": true, "$ne": null } },
+            )),
+            limit.map(Stage::Limit),
+            Some(Stage::Group {
+                key_expression: Bson::String(format!("${column}")),
+                accumulators: [("count".to_string(), Accumulator::Count)].into(),
+            }),
+            Some(Stage::Sort(doc! { "count": -1, "_id": 1 })),
+            Some(Stage::Group {
+                key_expression: Bson::Null,
+                accumulators: [(
+                    RESULT_FIELD.to_string(),
+                    Accumulator::Push(bson::bson!({ "value": "$_id", "count": "$count" })),
+                )]
+                .into(),
+            }),
+        ]
+        .into_iter()
+        .flatten(),
+    )
+}
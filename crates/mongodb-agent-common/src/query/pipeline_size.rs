@@ -0,0 +1,166 @@
+//! The server rejects commands larger than 16MB. Literal values are inlined into pipelines -
+//! variable sets go into a `$documents` stage, and comparison values go into `$match` stages - so
+//! a large request can produce a pipeline that is too large to send. Pipelines
+//! are measured before they are sent so that such requests fail fast instead of after the server
+//! has done other work for the request. A request with variable sets is split into several
+//! pipelines that each cover some of the variable sets instead of failing.
+
+use mongodb::bson::{self, doc, to_bson};
+use ndc_query_plan::VariableSet;
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::{MongoConfiguration, QueryPlan},
+    mongodb::Pipeline,
+};
+
+use super::{
+    document_size::{DocumentSizeCause, DocumentTooLargeError},
+    pipeline::pipeline_for_query_request,
+};
+
+type Result<T> = std::result::Result<T, MongoAgentError>;
+
+/// Leaves room in the 16MB command size limit for command fields other than the pipeline
+const MAX_PIPELINE_SIZE: usize = 16 * 1024 * 1024 - 16 * 1024;
+
+/// Produces one pipeline for the given query plan, or several pipelines if the query plan has
+/// variable sets, and a pipeline for all of them would be too large. Results of split pipelines
+/// have one document per variable set, so concatenating them in order produces the same
+/// documents that a single pipeline would.
+pub fn pipelines_within_size_limit(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+) -> Result<Vec<Pipeline>> {
+    let mut pipelines = vec![];
+    push_pipelines_within_size_limit(
+        config,
+        query_plan,
+        query_plan.variables.as_deref(),
+        &mut pipelines,
+    )?;
+    if pipelines.len() > 1 {
+        tracing::debug!(
+            pipelines = pipelines.len(),
+            "split variable sets into pipelines that fit the command size limit"
+        );
+    }
+    Ok(pipelines)
+}
+
+fn push_pipelines_within_size_limit(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+    variable_sets: Option<&[VariableSet]>,
+    pipelines: &mut Vec<Pipeline>,
+) -> Result<()> {
+    let pipeline = match variable_sets {
+        Some(variable_sets) => {
+            let query_plan = QueryPlan {
+                variables: Some(variable_sets.to_vec()),
+                ..query_plan.clone()
+            };
+            pipeline_for_query_request(config, &query_plan)?
+        }
+        None => pipeline_for_query_request(config, query_plan)?,
+    };
+    let size = pipeline_size(&pipeline)?;
+    if size <= MAX_PIPELINE_SIZE {
+        pipelines.push(pipeline);
+        return Ok(());
+    }
+    match variable_sets {
+        Some(variable_sets) if variable_sets.len() > 1 => {
+            // Variable sets are usually of similar size, so aim for chunks that fit on the first
+            // try. Chunks that are still too large are split again.
+            let chunk_count = size.div_ceil(MAX_PIPELINE_SIZE).max(2);
+            let chunk_length = variable_sets.len().div_ceil(chunk_count);
+            for chunk in variable_sets.chunks(chunk_length) {
+                push_pipelines_within_size_limit(config, query_plan, Some(chunk), pipelines)?;
+            }
+            Ok(())
+        }
+        _ => Err(DocumentTooLargeError {
+            causes: vec![DocumentSizeCause::Pipeline { size }],
+            server_message: format!(
+                "the aggregate command would exceed the {MAX_PIPELINE_SIZE} byte limit for pipelines; it was not sent"
+            ),
+        })?,
+    }
+}
+
+fn pipeline_size(pipeline: &Pipeline) -> Result<usize> {
+    let command = doc! { "pipeline": to_bson(pipeline)? };
+    Ok(bson::to_vec(&command)?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::Configuration;
+    use mongodb::bson::Bson;
+    use ndc_query_plan::plan_for_query_request;
+    use ndc_test_helpers::{
+        binop, collection, field, named_type, object_type, or, query, query_request, target, value,
+        variable,
+    };
+
+    use super::pipelines_within_size_limit;
+    use crate::{interface_types::MongoAgentError, mongo_query_plan::MongoConfiguration};
+
+    fn config() -> MongoConfiguration {
+        MongoConfiguration(Configuration {
+            collections: [collection("words")].into(),
+            object_types: [(
+                "words".into(),
+                object_type([("word", named_type("String"))]),
+            )]
+            .into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn splits_variable_sets_into_pipelines_that_fit_size_limit() -> anyhow::Result<()> {
+        let long_word = "x".repeat(1024 * 1024);
+        let request = query_request()
+            .collection("words")
+            .query(query().fields([field!("word")]).predicate(binop(
+                "_eq",
+                target!("word"),
+                variable!(word),
+            )))
+            .variables((0..40).map(|_| [("word", long_word.clone().into())]))
+            .into();
+        let query_plan = plan_for_query_request(&config(), request)?;
+
+        let pipelines = pipelines_within_size_limit(&config(), &query_plan)?;
+        assert_eq!(pipelines.len(), 3);
+        let variable_set_counts: Vec<usize> = pipelines
+            .iter()
+            .map(
+                |pipeline| match mongodb::bson::to_bson(&pipeline.stages[0]) {
+                    Ok(Bson::Document(stage)) => stage.get_array("$documents").map_or(0, Vec::len),
+                    _ => 0,
+                },
+            )
+            .collect();
+        assert_eq!(variable_set_counts, vec![14, 14, 12]);
+        Ok(())
+    }
+
+    #[test]
+    fn fails_fast_when_pipeline_without_variables_is_too_large() -> anyhow::Result<()> {
+        let long_word = "x".repeat(1024 * 1024);
+        let request = query_request()
+            .collection("words")
+            .query(query().fields([field!("word")]).predicate(or(
+                (0..20).map(|_| binop("_eq", target!("word"), value!(long_word.clone()))),
+            )))
+            .into();
+        let query_plan = plan_for_query_request(&config(), request)?;
+
+        let result = pipelines_within_size_limit(&config(), &query_plan);
+        assert!(matches!(result, Err(MongoAgentError::DocumentTooLarge(_))));
+        Ok(())
+    }
+}
@@ -22,6 +22,11 @@ impl QueryTarget<'_> {
     ) -> QueryTarget<'a> {
         let collection = &query_request.collection;
         match config.native_queries().get(collection) {
+            // Results of materialized native queries are read from the backing collection
+            Some(NativeQuery {
+                materialized: Some(materialized),
+                ..
+            }) => QueryTarget::Collection(materialized.collection.clone()),
             Some(native_query) => QueryTarget::NativeQuery {
                 name: collection.to_owned(),
                 native_query,
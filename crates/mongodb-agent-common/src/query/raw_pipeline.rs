@@ -0,0 +1,97 @@
+//! Checks pipelines given to the raw pipeline function against the configured stage allow-list.
+//! See [configuration::raw_pipeline].
+
+use anyhow::anyhow;
+use configuration::ConfigurationRawPipelineOptions;
+use mongodb::bson::{Bson, Document};
+
+use crate::interface_types::MongoAgentError;
+
+/// Fails unless the given pipeline argument is a list of stages that each use an allowed stage
+/// operator. Sub-pipelines of `$lookup`, `$facet`, and `$unionWith` stages are checked too.
+pub fn check_raw_pipeline(
+    options: &ConfigurationRawPipelineOptions,
+    pipeline: &Bson,
+) -> Result<(), MongoAgentError> {
+    let Bson::Array(stages) = pipeline else {
+        return Err(bad_pipeline("the pipeline must be an array of stages"));
+    };
+    for stage in stages {
+        let Bson::Document(stage) = stage else {
+            return Err(bad_pipeline("each pipeline stage must be a document"));
+        };
+        check_stage(options, stage)?;
+    }
+    Ok(())
+}
+
+fn check_stage(
+    options: &ConfigurationRawPipelineOptions,
+    stage: &Document,
+) -> Result<(), MongoAgentError> {
+    let mut keys = stage.keys();
+    let (Some(operator), None) = (keys.next(), keys.next()) else {
+        return Err(bad_pipeline(
+            "each pipeline stage must have exactly one stage operator",
+        ));
+    };
+    if !options.allowed_stages.contains(operator) {
+        return Err(bad_pipeline(format!(
+            "the {operator} stage is not allowed in raw pipelines"
+        )));
+    }
+    match (operator.as_str(), stage.get(operator)) {
+        ("$lookup" | "$unionWith", Some(Bson::Document(spec))) => {
+            if let Some(pipeline) = spec.get("pipeline") {
+                check_raw_pipeline(options, pipeline)?;
+            }
+        }
+        ("$facet", Some(Bson::Document(facets))) => {
+            for pipeline in facets.values() {
+                check_raw_pipeline(options, pipeline)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn bad_pipeline(message: impl Into<String>) -> MongoAgentError {
+    MongoAgentError::BadQuery(anyhow!(message.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ConfigurationRawPipelineOptions;
+    use mongodb::bson::bson;
+
+    use super::check_raw_pipeline;
+
+    #[test]
+    fn rejects_stages_that_are_not_allowed_at_any_depth() {
+        let options = ConfigurationRawPipelineOptions::default();
+
+        let allowed = bson!([
+            { "$match": { "year": { "$gt": 2000 } } },
+            { "$facet": {
+                "titles": [{ "$project": { "title": 1 } }],
+                "count": [{ "$count": "n" }],
+            } },
+        ]);
+        assert!(check_raw_pipeline(&options, &allowed).is_ok());
+
+        let writes = bson!([{ "$match": {} }, { "$out": "copy" }]);
+        assert!(check_raw_pipeline(&options, &writes).is_err());
+
+        let nested_write = bson!([{
+            "$lookup": {
+                "from": "comments",
+                "as": "comments",
+                "pipeline": [{ "$merge": { "into": "copy" } }],
+            }
+        }]);
+        assert!(check_raw_pipeline(&options, &nested_write).is_err());
+
+        assert!(check_raw_pipeline(&options, &bson!({ "$match": {} })).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Computes field values that queries return from stored values. See
+//! [configuration::schema::ObjectField::read_transform].
+
+use std::collections::BTreeMap;
+
+use configuration::schema::ReadTransform;
+use mongodb::bson::{doc, Bson, Document};
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::{MongoConfiguration, QueryPlan},
+    mongodb::Stage,
+};
+
+/// Read transformations of fields of the queried collection, if there are any
+pub fn read_transforms_for_query<'a>(
+    config: &'a MongoConfiguration,
+    query_plan: &QueryPlan,
+) -> Option<&'a BTreeMap<ndc_models::FieldName, ReadTransform>> {
+    read_transforms_for_collection(config, &query_plan.collection)
+}
+
+/// Read transformations of fields of the given collection, if there are any
+pub fn read_transforms_for_collection<'a>(
+    config: &'a MongoConfiguration,
+    collection: &ndc_models::CollectionName,
+) -> Option<&'a BTreeMap<ndc_models::FieldName, ReadTransform>> {
+    config
+        .collection_policies(collection)
+        .map(|policies| &policies.read_transforms)
+        .filter(|read_transforms| !read_transforms.is_empty())
+}
+
+/// `$addFields` stage that replaces stored values with computed values for transformations with
+/// the given `filter_on_transformed` setting. Transformations that predicates and sorting see are
+/// applied before the `$match` stage. Other transformations are applied just before fields are
+/// selected so that predicates and sorting see stored values.
+pub fn read_transforms_stage(
+    config: &MongoConfiguration,
+    collection: &ndc_models::CollectionName,
+    filter_on_transformed: bool,
+) -> Result<Option<Stage>, MongoAgentError> {
+    let Some(read_transforms) = read_transforms_for_collection(config, collection) else {
+        return Ok(None);
+    };
+    let fields: Document = read_transforms
+        .iter()
+        .filter(|(_, transform)| transform.filter_on_transformed == filter_on_transformed)
+        .map(|(name, transform)| {
+            let expression = Bson::try_from(transform.expression.clone())
+                .map_err(|err| MongoAgentError::AdHoc(err.into()))?;
+            let value = doc! {
+                "$let": {
+                    "vars": { "value": format!("${name}") },
+                    "in": expression,
+                }
+            };
+            Ok((name.to_string(), value.into()))
+        })
+        .collect::<Result<_, MongoAgentError>>()?;
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Stage::Other(doc! { "$addFields": fields })))
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, ObjectField, ObjectType, ReadTransform, Type},
+        serialized::Schema,
+        Configuration,
+    };
+    use mongodb::bson::bson;
+    use mongodb_support::BsonScalarType;
+    use ndc_models::QueryResponse;
+    use ndc_test_helpers::{binop, field, query, query_request, row_set, target, value};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::{
+        mongo_query_plan::MongoConfiguration,
+        mongodb::test_helpers::mock_collection_aggregate_response_for_pipeline,
+        query::execute_query_request,
+    };
+
+    fn users_config() -> anyhow::Result<MongoConfiguration> {
+        let transformed = |name: &str, expression, filter_on_transformed| {
+            let (name, mut field) = ObjectField::new(name, Type::Scalar(BsonScalarType::String));
+            field.read_transform = Some(ReadTransform {
+                expression,
+                filter_on_transformed,
+            });
+            (name, field)
+        };
+        let schema = Schema {
+            collections: [(
+                "users".into(),
+                Collection {
+                    r#type: "users".into(),
+                    description: None,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "users".into(),
+                ObjectType {
+                    fields: [
+                        transformed("email", json!({ "$toLower": "$$value" }), true),
+                        transformed(
+                            "joined",
+                            json!({ "$dateToString": { "date": "$$value", "format": "%Y-%m-%d" } }),
+                            false,
+                        ),
+                    ]
+                    .into_iter()
+                    .map(|(name, field)| (name.into(), field))
+                    .collect(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        Ok(MongoConfiguration(Configuration::from_schema(schema)?))
+    }
+
+    #[tokio::test]
+    async fn applies_read_transforms_before_and_after_filtering() -> anyhow::Result<()> {
+        let query_request = query_request()
+            .collection("users")
+            .query(
+                query()
+                    .fields([field!("email"), field!("joined")])
+                    .predicate(binop("_eq", target!("email"), value!("ada@example.com"))),
+            )
+            .into();
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "users",
+            bson!([
+                {
+                    "$addFields": {
+                        "email": { "$let": { "vars": { "value": "$email" }, "in": { "$toLower": "$$value" } } },
+                    },
+                },
+                { "$match": { "email": { "$eq": "ada@example.com" } } },
+                {
+                    "$addFields": {
+                        "joined": {
+                            "$let": {
+                                "vars": { "value": "$joined" },
+                                "in": { "$dateToString": { "date": "$$value", "format": "%Y-%m-%d" } },
+                            },
+                        },
+                    },
+                },
+                {
+                    "$replaceWith": {
+                        "email": { "$ifNull": ["$email", null] },
+                        "joined": { "$ifNull": ["$joined", null] },
+                    },
+                },
+            ]),
+            bson!([{ "email": "ada@example.com", "joined": "1843-07-01" }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &users_config()?,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set()
+                .rows([[("email", "ada@example.com"), ("joined", "1843-07-01")]])
+                .into_response()
+        );
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ use std::collections::BTreeMap;
 
 use itertools::Itertools as _;
 use mongodb::bson::{doc, Bson, Document};
+use ndc_models::{Argument, RelationshipArgument};
 use ndc_query_plan::Scope;
 
 use crate::mongo_query_plan::{MongoConfiguration, Query, QueryPlan};
@@ -15,6 +16,7 @@ use crate::{
 
 use super::pipeline::pipeline_for_non_foreach;
 use super::query_level::QueryLevel;
+use super::query_target::QueryTarget;
 
 type Result<T> = std::result::Result<T, MongoAgentError>;
 
@@ -36,19 +38,31 @@ pub fn pipeline_for_relations(
     let lookup_stages = relationships
         .iter()
         .map(|(name, relationship)| {
+            // Targets that are native queries take their arguments from the relationship. Arguments
+            // that reference request variables resolve to variables bound for each variable set.
+            let relationship_plan = QueryPlan {
+                query: relationship.query.clone(),
+                collection: relationship.target_collection.clone(),
+                arguments: relationship_arguments(&relationship.arguments)?,
+                ..query_plan.clone()
+            };
+
             // Recursively build pipeline according to relation query
-            let lookup_pipeline = pipeline_for_non_foreach(
-                config,
-                &QueryPlan {
-                    query: relationship.query.clone(),
-                    collection: relationship.target_collection.clone(),
-                    ..query_plan.clone()
-                },
-                QueryLevel::Relationship,
-            )?;
+            let mut lookup_pipeline =
+                pipeline_for_non_foreach(config, &relationship_plan, QueryLevel::Relationship)?;
+            if is_existence_check(&relationship.query) {
+                lookup_pipeline.append(existence_check_stages());
+            }
+
+            // Native queries without an input collection start from a `$documents` stage, and
+            // are looked up without a `from` collection
+            let from = QueryTarget::for_request(config, &relationship_plan)
+                .input_collection()
+                .cloned();
 
             make_lookup_stage(
-                relationship.target_collection.clone(),
+                config,
+                from,
                 &relationship.column_mapping,
                 name.to_owned(),
                 lookup_pipeline,
@@ -60,16 +74,78 @@ pub fn pipeline_for_relations(
     Ok(lookup_stages)
 }
 
+fn relationship_arguments(
+    arguments: &BTreeMap<ndc_models::ArgumentName, RelationshipArgument>,
+) -> Result<BTreeMap<ndc_models::ArgumentName, Argument>> {
+    arguments
+        .iter()
+        .map(|(name, argument)| {
+            let argument = match argument {
+                RelationshipArgument::Literal { value } => Argument::Literal {
+                    value: value.clone(),
+                },
+                RelationshipArgument::Variable { name } => {
+                    Argument::Variable { name: name.clone() }
+                }
+                RelationshipArgument::Column { .. } => {
+                    return Err(MongoAgentError::NotImplemented(
+                        "relationship arguments that reference columns",
+                    ))
+                }
+            };
+            Ok((name.clone(), argument))
+        })
+        .collect()
+}
+
+/// A relationship that is referenced only by `exists` predicates with no predicate on the related
+/// collection has a query with no fields, aggregates, or predicate. The parent query only checks
+/// whether the joined array is empty so one related document is enough, and it does not need any
+/// of the related document's fields.
+fn is_existence_check(query: &Query) -> bool {
+    let Query {
+        aggregates,
+        fields,
+        limit,
+        aggregates_limit: _,
+        offset,
+        order_by,
+        predicate,
+        relationships,
+        scope: _,
+    } = query;
+    aggregates.is_none()
+        && fields.is_none()
+        && limit.is_none()
+        && offset.is_none()
+        && order_by.is_none()
+        && predicate.is_none()
+        && relationships.is_empty()
+}
+
+fn existence_check_stages() -> Pipeline {
+    Pipeline::from_iter([
+        Stage::Limit(1),
+        Stage::Other(doc! { "$project": { "_id": 1 } }),
+    ])
+}
+
 fn make_lookup_stage(
-    from: ndc_models::CollectionName,
+    config: &MongoConfiguration,
+    from: Option<ndc_models::CollectionName>,
     column_mapping: &BTreeMap<ndc_models::FieldName, ndc_models::FieldName>,
     r#as: ndc_models::RelationshipName,
     lookup_pipeline: Pipeline,
     scope: Option<&Scope>,
 ) -> Result<Stage> {
+    // Combining `localField` and `foreignField` with `let` or `pipeline` requires MongoDB v5.0 or
+    // later. In compatibility mode we only use the field shorthand on its own.
+    let can_use_correlated_subquery =
+        !config.compatibility_mode() || (lookup_pipeline.is_empty() && scope.is_none());
+
     // If we are mapping a single field in the source collection to a single field in the target
     // collection then we can use the correlated subquery syntax.
-    if column_mapping.len() == 1 {
+    if column_mapping.len() == 1 && can_use_correlated_subquery {
         // Safe to unwrap because we just checked the hashmap size
         let (source_selector, target_selector) = column_mapping.iter().next().unwrap();
         single_column_mapping_lookup(
@@ -87,7 +163,7 @@ fn make_lookup_stage(
 
 // TODO: MDB-160 Replace uses of [safe_name] with [ColumnRef].
 fn single_column_mapping_lookup(
-    from: ndc_models::CollectionName,
+    from: Option<ndc_models::CollectionName>,
     source_selector: &ndc_models::FieldName,
     target_selector: &ndc_models::FieldName,
     r#as: ndc_models::RelationshipName,
@@ -95,7 +171,7 @@ fn single_column_mapping_lookup(
     scope: Option<&Scope>,
 ) -> Result<Stage> {
     Ok(Stage::Lookup {
-        from: Some(from.to_string()),
+        from: from.map(|from| from.to_string()),
         local_field: Some(safe_name(source_selector.as_str())?.into_owned()),
         foreign_field: Some(safe_name(target_selector.as_str())?.into_owned()),
         r#let: scope.map(|scope| {
@@ -113,7 +189,7 @@ fn single_column_mapping_lookup(
 }
 
 fn multiple_column_mapping_lookup(
-    from: ndc_models::CollectionName,
+    from: Option<ndc_models::CollectionName>,
     column_mapping: &BTreeMap<ndc_models::FieldName, ndc_models::FieldName>,
     r#as: ndc_models::RelationshipName,
     lookup_pipeline: Pipeline,
@@ -166,7 +242,7 @@ fn multiple_column_mapping_lookup(
     let pipeline: Option<Pipeline> = pipeline.into();
 
     Ok(Stage::Lookup {
-        from: Some(from.to_string()),
+        from: from.map(|from| from.to_string()),
         local_field: None,
         foreign_field: None,
         r#let: let_bindings.into(),
@@ -177,8 +253,16 @@ fn multiple_column_mapping_lookup(
 
 #[cfg(test)]
 mod tests {
-    use configuration::Configuration;
-    use mongodb::bson::{bson, Bson};
+    use configuration::{
+        native_query::NativeQueryRepresentation,
+        schema::{self, ObjectField, ObjectType},
+        serialized::NativeQuery,
+        Configuration, MongoScalarType,
+    };
+    use mongodb::bson::{self, bson, doc, Bson};
+    use mongodb_support::BsonScalarType as S;
+    use ndc_models::RelationshipArgument;
+    use ndc_query_plan::plan_for_query_request;
     use ndc_test_helpers::{
         binop, collection, exists, field, named_type, object_type, query, query_request,
         relation_field, relationship, row_set, star_count_aggregate, target, value,
@@ -188,8 +272,9 @@ mod tests {
 
     use super::super::execute_query_request;
     use crate::{
-        mongo_query_plan::MongoConfiguration,
+        mongo_query_plan::{MongoConfiguration, Type},
         mongodb::test_helpers::mock_collection_aggregate_response_for_pipeline,
+        query::{pipeline_for_query_request, query_variable_name::query_variable_name},
         test_helpers::mflix_config,
     };
 
@@ -270,7 +355,16 @@ mod tests {
             }]),
         );
 
-        let result = execute_query_request(db, &students_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
 
         Ok(())
@@ -362,7 +456,16 @@ mod tests {
             ]),
         );
 
-        let result = execute_query_request(db, &students_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
 
         Ok(())
@@ -453,7 +556,16 @@ mod tests {
             }]),
         );
 
-        let result = execute_query_request(db, &students_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(expected_response, result);
 
         Ok(())
@@ -590,7 +702,16 @@ mod tests {
             }]),
         );
 
-        let result = execute_query_request(db, &students_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(result, expected_response);
 
         Ok(())
@@ -687,7 +808,16 @@ mod tests {
             }]),
         );
 
-        let result = execute_query_request(db, &students_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &students_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(result, expected_response);
 
         Ok(())
@@ -798,7 +928,88 @@ mod tests {
             }]),
         );
 
-        let result = execute_query_request(db, &mflix_config(), query_request).await?;
+        let result = execute_query_request(
+            db,
+            &mflix_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(result, expected_response);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_lookup_for_relationship_existence_check() -> Result<(), anyhow::Error> {
+        let query_request = query_request()
+            .collection("comments")
+            .query(
+                query()
+                    .fields([field!("name")])
+                    .predicate(ndc_models::Expression::Exists {
+                        in_collection: ndc_models::ExistsInCollection::Related {
+                            relationship: "movie".into(),
+                            arguments: Default::default(),
+                        },
+                        predicate: None,
+                    }),
+            )
+            .relationships([(
+                "movie",
+                relationship("movies", [("movie_id", "_id")]).object_type(),
+            )])
+            .into();
+
+        let expected_response = row_set()
+            .row([("name", json!("Mercedes Tyler"))])
+            .into_response();
+
+        let expected_pipeline = bson!([
+          {
+            "$lookup": {
+              "from": "movies",
+              "localField": "movie_id",
+              "foreignField": "_id",
+              "let": {
+                "scope_root": "$$ROOT",
+              },
+              "pipeline": [
+                { "$limit": Bson::Int64(1) },
+                { "$project": { "_id": 1 } },
+              ],
+              "as": "movie"
+            }
+          },
+          {
+            "$match": { "movie.0": { "$exists": true } }
+          },
+          {
+            "$replaceWith": {
+              "name": { "$ifNull": ["$name", null] }
+            }
+          },
+        ]);
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "comments",
+            expected_pipeline,
+            bson!([{ "name": "Mercedes Tyler" }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &mflix_config(),
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: ndc_models::QueryResponse = serde_json::from_slice(&result)?;
         assert_eq!(result, expected_response);
 
         Ok(())
@@ -907,12 +1118,133 @@ mod tests {
     //         }]),
     //     );
     //
-    //     let result = execute_query_request(db, &mflix_config(), query_request).await?;
+    //     let result = execute_query_request(db, &mflix_config(), &Default::default(), &Default::default(), &Default::default(), query_request).await?;
     //     assert_eq!(expected_response, result);
     //
     //     Ok(())
     // }
 
+    #[test]
+    fn passes_variables_to_native_query_relationship_targets() -> Result<(), anyhow::Error> {
+        let query_request = query_request()
+            .collection("regions")
+            .query(query().fields([
+                field!("name"),
+                relation_field!("sales" => "region_sales", query().fields([
+                    field!("amount")
+                ])),
+            ]))
+            .relationships([(
+                "region_sales",
+                relationship("sales_above", [("name", "region")]).arguments(
+                    [(
+                        "minimum".into(),
+                        RelationshipArgument::Variable {
+                            name: "minimum".into(),
+                        },
+                    )]
+                    .into(),
+                ),
+            )])
+            .variables([[("minimum", json!(100))]])
+            .into();
+
+        let config = regions_config()?;
+        let query_plan = plan_for_query_request(&config, query_request)?;
+        let pipeline = bson::to_bson(&pipeline_for_query_request(&config, &query_plan)?)?;
+
+        // The variable is bound for each variable set by the outer `$lookup` stage, and the native
+        // query pipeline references it by the name for its parameter type
+        let variable_name = query_variable_name(
+            &"minimum".into(),
+            &Type::Scalar(MongoScalarType::Bson(S::Int)),
+        );
+        let sales_lookup = bson!({
+            "$lookup": {
+                "from": "sales",
+                "localField": "name",
+                "foreignField": "region",
+                "let": { "scope_root": "$$ROOT" },
+                "pipeline": [
+                    { "$match": { "$expr": { "$gte": ["$amount", format!("$${variable_name}")] } } },
+                    { "$replaceWith": { "amount": { "$ifNull": ["$amount", null] } } },
+                ],
+                "as": "region_sales",
+            }
+        });
+        let lookup_stages = pipeline.as_array().and_then(|stages| {
+            stages[1]
+                .as_document()?
+                .get_document("$lookup")
+                .ok()?
+                .get_array("pipeline")
+                .ok()
+                .cloned()
+        });
+        assert!(lookup_stages.is_some_and(|stages| stages.contains(&sales_lookup)));
+        Ok(())
+    }
+
+    /// Configuration with a native query, `regions`, that has a relationship to a native query
+    /// with a parameter, `sales_above`
+    fn regions_config() -> anyhow::Result<MongoConfiguration> {
+        let object_type = |fields: Vec<(String, ObjectField)>| ObjectType {
+            fields: fields
+                .into_iter()
+                .map(|(name, field)| (name.into(), field))
+                .collect(),
+            description: None,
+            extends: Default::default(),
+        };
+        let regions = NativeQuery {
+            representation: NativeQueryRepresentation::Collection,
+            input_collection: None,
+            arguments: Default::default(),
+            result_document_type: "Region".into(),
+            object_types: [(
+                "Region".into(),
+                object_type(vec![ObjectField::new(
+                    "name",
+                    schema::Type::Scalar(S::String),
+                )]),
+            )]
+            .into(),
+            pipeline: vec![doc! { "$documents": [{ "name": "west" }] }],
+            materialized: None,
+            description: None,
+        };
+        let (argument_name, argument) = ObjectField::new("minimum", schema::Type::Scalar(S::Int));
+        let sales_above = NativeQuery {
+            representation: NativeQueryRepresentation::Collection,
+            input_collection: Some("sales".into()),
+            arguments: [(argument_name.into(), argument)].into(),
+            result_document_type: "Sale".into(),
+            object_types: [(
+                "Sale".into(),
+                object_type(vec![
+                    ObjectField::new("amount", schema::Type::Scalar(S::Int)),
+                    ObjectField::new("region", schema::Type::Scalar(S::String)),
+                ]),
+            )]
+            .into(),
+            pipeline: vec![
+                doc! { "$match": { "$expr": { "$gte": ["$amount", "{{ minimum }}"] } } },
+            ],
+            materialized: None,
+            description: None,
+        };
+        Ok(MongoConfiguration(Configuration::validate(
+            Default::default(),
+            Default::default(),
+            [
+                ("regions".into(), regions),
+                ("sales_above".into(), sales_above),
+            ]
+            .into(),
+            Default::default(),
+        )?))
+    }
+
     fn students_config() -> MongoConfiguration {
         MongoConfiguration(Configuration {
             collections: [
@@ -954,6 +1286,10 @@ mod tests {
             procedures: Default::default(),
             native_mutations: Default::default(),
             native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
             options: Default::default(),
         })
     }
@@ -1,21 +1,36 @@
-use std::collections::BTreeMap;
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    sync::Arc,
+};
 
-use configuration::MongoScalarType;
+use bytes::Bytes;
+use configuration::{
+    ConfigurationSerializationOptions, MongoScalarType, ObjectIdFormats, RowErrorPolicy,
+};
 use indexmap::IndexMap;
 use itertools::Itertools;
-use mongodb::bson::{self, Bson};
-use mongodb_support::ExtendedJsonMode;
-use ndc_models::{QueryResponse, RowFieldValue, RowSet};
-use serde::Deserialize;
+use mongodb::bson::{self, Bson, RawArray, RawBsonRef, RawDocument, RawDocumentBuf};
+use serde::{
+    ser::{Error as _, SerializeMap as _, SerializeSeq},
+    Serialize, Serializer,
+};
 use thiserror::Error;
 use tracing::instrument;
 
 use crate::{
+    aggregation_function::AggregationFunction,
     mongo_query_plan::{
         Aggregate, Field, NestedArray, NestedField, NestedObject, ObjectType, Query, QueryPlan,
         Type,
     },
-    query::serialization::{bson_to_json, BsonToJsonError},
+    post_processing::{ResponsePostProcessor, ResponsePostProcessors, RowContext},
+    query::{
+        memory_budget::{BudgetedWriter, MemoryBudget, MemoryBudgetExceeded},
+        serialization::{
+            bson_to_json, BsonToJsonError, RawBsonToJson, SerializationFailure, SubtreeCache,
+        },
+    },
 };
 
 use super::serialization::is_nullable;
@@ -34,147 +49,449 @@ pub enum QueryResponseError {
     #[error("expected a single response document from MongoDB, but did not get one")]
     ExpectedSingleDocument,
 
+    #[error("error writing query response: {0}")]
+    JsonSerialization(#[from] serde_json::Error),
+
     #[error("a query field referenced a relationship, but no fields from the relationship were selected")]
     NoFieldsSelected { path: Vec<String> },
+
+    #[error("{0}")]
+    MemoryBudgetExceeded(#[from] MemoryBudgetExceeded),
+
+    #[error("error reading raw BSON from MongoDB response: {0}")]
+    RawBson(#[from] bson::raw::Error),
+
+    #[error("expected rows to be an array at path {}", path.join("."))]
+    RowsNotArray { path: Vec<String> },
+
+    #[error(
+        "error serializing row {row} of the query response at {}: {message}; expected a value of type {}, got {}",
+        failure.path, failure.expected_type, failure.value
+    )]
+    ValueSerialization {
+        row: usize,
+        failure: SerializationFailure,
+        message: String,
+    },
 }
 
 type Result<T> = std::result::Result<T, QueryResponseError>;
 
-#[derive(Debug, Deserialize)]
-struct BsonRowSet {
-    #[serde(default)]
-    aggregates: Bson,
-    #[serde(default)]
-    rows: Vec<bson::Document>,
-}
-
+/// Response documents are serialized directly from raw BSON to JSON bytes. Avoiding intermediate
+/// `bson::Document` and `serde_json::Value` trees makes a large difference in query latency for
+/// responses with wide documents.
 #[instrument(name = "Serialize Query Response", skip_all, fields(internal.visibility = "user"))]
 pub fn serialize_query_response(
-    mode: ExtendedJsonMode,
+    options: ConfigurationSerializationOptions,
+    object_id_formats: &ObjectIdFormats,
+    post_processors: &ResponsePostProcessors,
     query_plan: &QueryPlan,
-    response_documents: Vec<bson::Document>,
-) -> Result<QueryResponse> {
+    response_documents: Vec<RawDocumentBuf>,
+) -> Result<Bytes> {
     let collection_name = &query_plan.collection;
+    let variables_path = [collection_name.as_str()];
+
+    // Row types are the same for every row set so we compute them once up front
+    let path: &[&str] = if query_plan.has_variables() {
+        &variables_path
+    } else {
+        &[]
+    };
+    let row_type = query_plan
+        .query
+        .fields
+        .as_ref()
+        .map(|fields| type_for_row(path, fields))
+        .transpose()?;
+
+    let mut relationship_types = vec![];
+    if let (Some(fields), Some(row_type)) = (&query_plan.query.fields, &row_type) {
+        relationship_field_types(fields, row_type, &mut relationship_types);
+    }
+    let subtree_cache = SubtreeCache::new(relationship_types);
 
-    let row_sets = if query_plan.has_variables() {
+    let mut row_sets: Vec<RowSetToJson<'_>> = if query_plan.has_variables() {
         response_documents
-            .into_iter()
+            .iter()
             .map(|document| {
-                let row_set = bson::from_document(document)?;
                 serialize_row_set_with_aggregates(
-                    mode,
-                    &[collection_name.as_str()],
+                    options,
+                    object_id_formats,
+                    &subtree_cache,
+                    path,
                     &query_plan.query,
-                    row_set,
+                    row_type.as_ref(),
+                    document,
                 )
             })
-            .try_collect()
+            .try_collect()?
     } else if query_plan.query.has_aggregates() {
-        let row_set = parse_single_document(response_documents)?;
-        Ok(vec![serialize_row_set_with_aggregates(
-            mode,
-            &[],
+        let document = parse_single_document(&response_documents)?;
+        vec![serialize_row_set_with_aggregates(
+            options,
+            object_id_formats,
+            &subtree_cache,
+            path,
             &query_plan.query,
-            row_set,
-        )?])
+            row_type.as_ref(),
+            document,
+        )?]
     } else {
-        Ok(vec![serialize_row_set_rows_only(
-            mode,
-            &[],
-            &query_plan.query,
-            response_documents,
-        )?])
-    }?;
-    let response = QueryResponse(row_sets);
-    tracing::debug!(query_response = %serde_json::to_string(&response).unwrap());
+        vec![serialize_row_set_rows_only(
+            options,
+            object_id_formats,
+            &subtree_cache,
+            row_type.as_ref(),
+            &response_documents,
+        )]
+    };
+    if !post_processors.processors().is_empty() {
+        for rows in row_sets
+            .iter_mut()
+            .filter_map(|row_set| row_set.rows.as_mut())
+        {
+            rows.post_processing = Some(PostProcessing {
+                collection: collection_name,
+                processors: post_processors.processors(),
+            });
+        }
+    }
+
+    // Rows from MongoDB are held until the response is complete, so they count against the memory
+    // budget along with the response
+    let mut budget = MemoryBudget::new(options.max_response_memory_bytes);
+    for document in &response_documents {
+        budget.charge_row(document)?;
+    }
+
+    let mut output = Vec::new();
+    let mut writer = BudgetedWriter::new(&mut output, &mut budget);
+    let result = serde_json::to_writer(&mut writer, &row_sets);
+    if let Some(exceeded) = writer.into_exceeded() {
+        return Err(exceeded.into());
+    }
+    if let Err(err) = result {
+        let failure = row_sets
+            .iter()
+            .find_map(|row_set| row_set.rows.as_ref()?.failure.take());
+        return Err(match failure {
+            Some((row, failure)) => QueryResponseError::ValueSerialization {
+                row,
+                failure,
+                message: err.to_string(),
+            },
+            None => err.into(),
+        });
+    }
+    let response = Bytes::from(output);
+    tracing::debug!(query_response = %String::from_utf8_lossy(&response));
     Ok(response)
 }
 
-// When there are no aggregates we expect a list of rows
-fn serialize_row_set_rows_only(
-    mode: ExtendedJsonMode,
-    path: &[&str],
-    query: &Query,
-    docs: Vec<bson::Document>,
-) -> Result<RowSet> {
-    let rows = query
-        .fields
-        .as_ref()
-        .map(|fields| serialize_rows(mode, path, fields, docs))
-        .transpose()?;
+/// A row set that is written to JSON as it is serialized. Aggregates are small so they are
+/// converted up front.
+#[derive(Debug)]
+struct RowSetToJson<'a> {
+    aggregates: Option<serde_json::Value>,
+    rows: Option<RowsToJson<'a>>,
+}
+
+#[derive(Debug)]
+struct RowsToJson<'a> {
+    options: ConfigurationSerializationOptions,
+    object_id_formats: &'a ObjectIdFormats,
+    subtree_cache: &'a SubtreeCache<'a>,
+    row_type: &'a Type,
+    rows: RawRows<'a>,
+    /// The index of the row that failed serialization, and the value in it that failed, so that
+    /// the failure can be reported with context
+    failure: RefCell<Option<(usize, SerializationFailure)>>,
+    /// The number of rows left out according to [RowErrorPolicy::Skip]
+    skipped: Cell<usize>,
+    post_processing: Option<PostProcessing<'a>>,
+}
+
+/// Response post-processors to apply to each row, see [crate::post_processing]
+#[derive(Clone, Copy)]
+struct PostProcessing<'a> {
+    collection: &'a ndc_models::CollectionName,
+    processors: &'a [Arc<dyn ResponsePostProcessor>],
+}
+
+impl std::fmt::Debug for PostProcessing<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PostProcessing")
+            .field("collection", &self.collection)
+            .field("processors", &self.processors.len())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+enum RawRows<'a> {
+    Documents(&'a [RawDocumentBuf]),
+    Array(&'a RawArray),
+}
+
+impl Serialize for RowSetToJson<'_> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("aggregates", &self.aggregates)?;
+        map.serialize_entry("rows", &self.rows)?;
+        // Rows are serialized first so the count is complete at this point
+        if let Some(rows) = &self.rows {
+            let skipped = rows.skipped.get();
+            if skipped > 0 {
+                map.serialize_entry("skippedRows", &skipped)?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl Serialize for RowsToJson<'_> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(None)?;
+        match self.rows {
+            RawRows::Documents(docs) => {
+                for (index, doc) in docs.iter().enumerate() {
+                    self.serialize_row(&mut seq, index, RawBsonRef::Document(doc))?;
+                }
+            }
+            RawRows::Array(rows) => {
+                for (index, row) in rows.into_iter().enumerate() {
+                    let row = row.map_err(S::Error::custom)?;
+                    self.serialize_row(&mut seq, index, row)?;
+                }
+            }
+        }
+        seq.end()
+    }
+}
+
+impl RowsToJson<'_> {
+    // The value that fails serialization, if any, is recorded so that the error can say where it
+    // is.
+    fn serialize_row<S>(
+        &self,
+        seq: &mut S,
+        index: usize,
+        row: RawBsonRef<'_>,
+    ) -> std::result::Result<(), S::Error>
+    where
+        S: SerializeSeq,
+    {
+        if self.options.row_errors == RowErrorPolicy::Skip {
+            return self.serialize_row_or_skip(seq, index, row);
+        }
+        let failure = RefCell::new(None);
+        let row = RawBsonToJson::new(self.options, self.row_type, row)
+            .with_object_id_formats(self.object_id_formats)
+            .with_subtree_cache(self.subtree_cache)
+            .recording_failure(&failure);
+        let result = self.write_row(seq, index, &row);
+        if let Some(failure) = failure.take() {
+            self.failure.borrow_mut().get_or_insert((index, failure));
+        }
+        result
+    }
+
+    // If there is a row size limit or there are post-processors each row is serialized to a buffer
+    // first so that its size can be checked. Otherwise rows are written directly to the output.
+    fn write_row<S>(
+        &self,
+        seq: &mut S,
+        index: usize,
+        row: &RawBsonToJson<'_>,
+    ) -> std::result::Result<(), S::Error>
+    where
+        S: SerializeSeq,
+    {
+        match self.options.max_row_size_bytes {
+            None if self.post_processing.is_none() => seq.serialize_element(row),
+            None => seq.serialize_element(&self.to_json(index, row).map_err(S::Error::custom)?),
+            Some(max_size) => {
+                let json = self.to_json(index, row).map_err(S::Error::custom)?;
+                let size = json.get().len();
+                if size > max_size {
+                    return Err(S::Error::custom(BsonToJsonError::RowTooLarge {
+                        row: index,
+                        size,
+                        max_size,
+                    }));
+                }
+                seq.serialize_element(&json)
+            }
+        }
+    }
+
+    /// Serializes a row to a buffer, and applies post-processors if there are any
+    fn to_json(
+        &self,
+        index: usize,
+        row: &RawBsonToJson<'_>,
+    ) -> serde_json::Result<Box<serde_json::value::RawValue>> {
+        let Some(post_processing) = self.post_processing else {
+            return serde_json::value::to_raw_value(row);
+        };
+        let mut json = serde_json::to_value(row)?;
+        if let serde_json::Value::Object(fields) = &mut json {
+            let context = RowContext {
+                collection: post_processing.collection,
+                row: index,
+            };
+            for processor in post_processing.processors {
+                processor.process_row(&context, fields).map_err(|err| {
+                    serde_json::Error::custom(format!("error post-processing row: {err:#}"))
+                })?;
+            }
+        }
+        serde_json::value::to_raw_value(&json)
+    }
+
+    // Each row is serialized to a buffer first so that a row that fails can be left out without
+    // having written part of it to the output. Row sets must contain only row objects, so failed
+    // rows are reported in logs, and only the number of skipped rows is given in the response.
+    fn serialize_row_or_skip<S>(
+        &self,
+        seq: &mut S,
+        index: usize,
+        row: RawBsonRef<'_>,
+    ) -> std::result::Result<(), S::Error>
+    where
+        S: SerializeSeq,
+    {
+        let failure = RefCell::new(None);
+        let row = RawBsonToJson::new(self.options, self.row_type, row)
+            .with_object_id_formats(self.object_id_formats)
+            .with_subtree_cache(self.subtree_cache)
+            .recording_failure(&failure);
+        let result = self
+            .to_json(index, &row)
+            .map_err(|err| {
+                (
+                    failure
+                        .take()
+                        .map_or_else(|| "$".to_owned(), |failure| failure.path),
+                    err.to_string(),
+                )
+            })
+            .and_then(|json| {
+                let size = json.get().len();
+                match self.options.max_row_size_bytes {
+                    Some(max_size) if size > max_size => {
+                        let err = BsonToJsonError::RowTooLarge {
+                            row: index,
+                            size,
+                            max_size,
+                        };
+                        Err(("$".to_owned(), err.to_string()))
+                    }
+                    _ => Ok(json),
+                }
+            });
+        match result {
+            Ok(json) => seq.serialize_element(&json),
+            Err((path, message)) => {
+                tracing::warn!(row = index, path = %path, message = %message, "skipping row that could not be serialized");
+                self.skipped.set(self.skipped.get() + 1);
+                Ok(())
+            }
+        }
+    }
+}
 
-    Ok(RowSet {
+// When there are no aggregates we expect a list of rows
+fn serialize_row_set_rows_only<'a>(
+    options: ConfigurationSerializationOptions,
+    object_id_formats: &'a ObjectIdFormats,
+    subtree_cache: &'a SubtreeCache<'a>,
+    row_type: Option<&'a Type>,
+    docs: &'a [RawDocumentBuf],
+) -> RowSetToJson<'a> {
+    RowSetToJson {
         aggregates: None,
-        rows,
-    })
+        rows: row_type.map(|row_type| RowsToJson {
+            options,
+            object_id_formats,
+            subtree_cache,
+            row_type,
+            rows: RawRows::Documents(docs),
+            failure: Default::default(),
+            skipped: Default::default(),
+            post_processing: None,
+        }),
+    }
 }
 
 // When there are aggregates we expect a single document with `rows` and `aggregates`
 // fields
-fn serialize_row_set_with_aggregates(
-    mode: ExtendedJsonMode,
+fn serialize_row_set_with_aggregates<'a>(
+    options: ConfigurationSerializationOptions,
+    object_id_formats: &'a ObjectIdFormats,
+    subtree_cache: &'a SubtreeCache<'a>,
     path: &[&str],
     query: &Query,
-    row_set: BsonRowSet,
-) -> Result<RowSet> {
+    row_type: Option<&'a Type>,
+    row_set: &'a RawDocument,
+) -> Result<RowSetToJson<'a>> {
     let aggregates = query
         .aggregates
         .as_ref()
-        .map(|aggregates| serialize_aggregates(mode, path, aggregates, row_set.aggregates))
+        .map(|aggregates| -> Result<_> {
+            let value = match row_set.get("aggregates")? {
+                Some(value) => Bson::try_from(value)?,
+                None => Bson::Null,
+            };
+            serialize_aggregates(options, path, aggregates, value)
+        })
         .transpose()?;
 
-    let rows = query
-        .fields
-        .as_ref()
-        .map(|fields| serialize_rows(mode, path, fields, row_set.rows))
+    let rows = row_type
+        .map(|row_type| -> Result<_> {
+            let rows = match row_set.get("rows")? {
+                Some(RawBsonRef::Array(rows)) => RawRows::Array(rows),
+                None => RawRows::Documents(&[]),
+                Some(_) => Err(QueryResponseError::RowsNotArray {
+                    path: path_to_owned(path),
+                })?,
+            };
+            Ok(RowsToJson {
+                options,
+                object_id_formats,
+                subtree_cache,
+                row_type,
+                rows,
+                failure: Default::default(),
+                skipped: Default::default(),
+                post_processing: None,
+            })
+        })
         .transpose()?;
 
-    Ok(RowSet { aggregates, rows })
+    Ok(RowSetToJson { aggregates, rows })
 }
 
 fn serialize_aggregates(
-    mode: ExtendedJsonMode,
+    options: ConfigurationSerializationOptions,
     path: &[&str],
-    _query_aggregates: &IndexMap<ndc_models::FieldName, Aggregate>,
+    query_aggregates: &IndexMap<ndc_models::FieldName, Aggregate>,
     value: Bson,
-) -> Result<IndexMap<ndc_models::FieldName, serde_json::Value>> {
-    let aggregates_type = type_for_aggregates()?;
-    let json = bson_to_json(mode, &aggregates_type, value)?;
-
-    // The NDC type uses an IndexMap for aggregate values; we need to convert the map
-    // underlying the Value::Object value to an IndexMap
-    let aggregate_values = match json {
-        serde_json::Value::Object(obj) => obj.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+) -> Result<serde_json::Value> {
+    let aggregates_type = type_for_aggregates(query_aggregates);
+    let json = bson_to_json(options, &aggregates_type, value)?;
+    match json {
+        serde_json::Value::Object(_) => Ok(json),
         _ => Err(QueryResponseError::AggregatesNotObject {
             path: path_to_owned(path),
-        })?,
-    };
-    Ok(aggregate_values)
-}
-
-fn serialize_rows(
-    mode: ExtendedJsonMode,
-    path: &[&str],
-    query_fields: &IndexMap<ndc_models::FieldName, Field>,
-    docs: Vec<bson::Document>,
-) -> Result<Vec<IndexMap<ndc_models::FieldName, RowFieldValue>>> {
-    let row_type = type_for_row(path, query_fields)?;
-
-    docs.into_iter()
-        .map(|doc| {
-            let json = bson_to_json(mode, &row_type, doc.into())?;
-            // The NDC types use an IndexMap for each row value; we need to convert the map
-            // underlying the Value::Object value to an IndexMap
-            let index_map = match json {
-                serde_json::Value::Object(obj) => obj
-                    .into_iter()
-                    .map(|(key, value)| (key.into(), RowFieldValue(value)))
-                    .collect(),
-                _ => unreachable!(),
-            };
-            Ok(index_map)
-        })
-        .try_collect()
+        }),
+    }
 }
 
 fn type_for_row_set(
@@ -184,8 +501,8 @@ fn type_for_row_set(
 ) -> Result<Type> {
     let mut type_fields = BTreeMap::new();
 
-    if aggregates.is_some() {
-        type_fields.insert("aggregates".into(), type_for_aggregates()?);
+    if let Some(aggregates) = aggregates {
+        type_fields.insert("aggregates".into(), type_for_aggregates(aggregates));
     }
 
     if let Some(query_fields) = fields {
@@ -199,9 +516,32 @@ fn type_for_row_set(
     }))
 }
 
-// TODO: infer response type for aggregates MDB-130
-fn type_for_aggregates() -> Result<Type> {
-    Ok(Type::Scalar(MongoScalarType::ExtendedJSON))
+fn type_for_aggregates(query_aggregates: &IndexMap<ndc_models::FieldName, Aggregate>) -> Type {
+    let fields = query_aggregates
+        .iter()
+        .map(|(name, aggregate)| (name.clone(), type_for_aggregate(aggregate)))
+        .collect();
+    Type::Object(ObjectType { fields, name: None })
+}
+
+// TODO: infer response types for the remaining aggregates MDB-130
+//
+// `min` and `max` produce a value of the column type, so they are serialized like the column
+// itself - for example a date is written as an ISO timestamp. `avg` and `sum` are serialized
+// according to their declared result types. Other aggregates are written as extended JSON.
+fn type_for_aggregate(aggregate: &Aggregate) -> Type {
+    match aggregate {
+        Aggregate::SingleColumn {
+            function:
+                AggregationFunction::Min
+                | AggregationFunction::Max
+                | AggregationFunction::Avg
+                | AggregationFunction::Sum,
+            result_type,
+            ..
+        } => result_type.clone().into_nullable(),
+        _ => Type::Nullable(Box::new(Type::Scalar(MongoScalarType::ExtendedJSON))),
+    }
 }
 
 fn type_for_row(
@@ -221,6 +561,41 @@ fn type_for_row(
     Ok(Type::Object(ObjectType { fields, name: None }))
 }
 
+/// Collects the types of relationship fields in the given row type, including relationship fields
+/// of related rows. Values of these fields are cached during serialization. See [SubtreeCache].
+fn relationship_field_types<'a>(
+    query_fields: &IndexMap<ndc_models::FieldName, Field>,
+    row_type: &'a Type,
+    types: &mut Vec<&'a Type>,
+) {
+    let Type::Object(object_type) = row_type else {
+        return;
+    };
+    for (field_name, field_definition) in query_fields {
+        let Field::Relationship {
+            fields: Some(fields),
+            ..
+        } = field_definition
+        else {
+            continue;
+        };
+        let Some(field_type) = object_type.fields.get(field_name) else {
+            continue;
+        };
+        types.push(field_type);
+        let related_row_type = match field_type {
+            Type::Object(row_set_type) => match row_set_type.fields.get("rows") {
+                Some(Type::ArrayOf(row_type)) => Some(row_type.as_ref()),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(related_row_type) = related_row_type {
+            relationship_field_types(fields, related_row_type, types);
+        }
+    }
+}
+
 fn type_for_field(path: &[&str], field_definition: &Field) -> Result<Type> {
     let field_type: Type = match field_definition {
         Field::Column {
@@ -281,17 +656,11 @@ fn element_type(probably_array_type: &Type) -> &Type {
         pt => pt,
     }
 }
-
-fn parse_single_document<T>(documents: Vec<bson::Document>) -> Result<T>
-where
-    T: for<'de> serde::Deserialize<'de>,
-{
+fn parse_single_document(documents: &[RawDocumentBuf]) -> Result<&RawDocument> {
     let document = documents
-        .into_iter()
-        .next()
+        .first()
         .ok_or(QueryResponseError::ExpectedSingleDocument)?;
-    let value = bson::from_document(document)?;
-    Ok(value)
+    Ok(document)
 }
 
 fn append_to_path<'a>(path: &[&'a str], elems: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
@@ -304,26 +673,56 @@ fn path_to_owned(path: &[&str]) -> Vec<String> {
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{str::FromStr, sync::Arc};
 
-    use configuration::{Configuration, MongoScalarType};
+    use configuration::{
+        Configuration, ConfigurationSerializationOptions, MongoScalarType, RowErrorPolicy,
+    };
     use mongodb::bson::{self, Bson};
     use mongodb_support::{BsonScalarType, ExtendedJsonMode};
     use ndc_models::{QueryRequest, QueryResponse, RowFieldValue, RowSet};
     use ndc_query_plan::plan_for_query_request;
     use ndc_test_helpers::{
-        array, collection, field, named_type, object, object_type, query, query_request,
-        relation_field, relationship,
+        array, collection, column_aggregate, field, named_type, object, object_type, query,
+        query_request, relation_field, relationship,
     };
     use pretty_assertions::assert_eq;
     use serde_json::json;
 
     use crate::{
-        mongo_query_plan::{MongoConfiguration, ObjectType, Type},
+        mongo_query_plan::{MongoConfiguration, ObjectType, QueryPlan, Type},
+        post_processing::{ResponsePostProcessor, ResponsePostProcessors, RowContext},
+        query::serialization::SerializationFailure,
         test_helpers::make_nested_schema,
     };
 
-    use super::{serialize_query_response, type_for_row_set};
+    use super::{
+        relationship_field_types, serialize_query_response, type_for_row, type_for_row_set,
+        QueryResponseError,
+    };
+
+    fn serialize(
+        mode: ExtendedJsonMode,
+        query_plan: &QueryPlan,
+        response_documents: Vec<bson::Document>,
+    ) -> anyhow::Result<QueryResponse> {
+        let raw_documents = response_documents
+            .iter()
+            .map(bson::RawDocumentBuf::from_document)
+            .collect::<Result<_, _>>()?;
+        let options = ConfigurationSerializationOptions {
+            extended_json_mode: mode,
+            ..Default::default()
+        };
+        let response = serialize_query_response(
+            options,
+            &Default::default(),
+            &Default::default(),
+            query_plan,
+            raw_documents,
+        )?;
+        Ok(serde_json::from_slice(&response)?)
+    }
 
     #[test]
     fn serializes_response_with_nested_fields() -> anyhow::Result<()> {
@@ -347,8 +746,7 @@ mod tests {
             },
         }];
 
-        let response =
-            serialize_query_response(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
+        let response = serialize(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
         assert_eq!(
             response,
             QueryResponse(vec![RowSet {
@@ -368,6 +766,170 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn skips_rows_that_fail_to_serialize() -> anyhow::Result<()> {
+        let request = query_request()
+            .collection("authors")
+            .query(query().fields([
+                field!("name"),
+                field!("address" => "address", object!([field!("street")])),
+            ]))
+            .into();
+        let query_plan = plan_for_query_request(&make_nested_schema(), request)?;
+
+        let response_documents = [
+            bson::doc! { "name": "Ada", "address": { "street": "1 Main St" } },
+            bson::doc! { "name": "Charles", "address": { "street": 2 } },
+            bson::doc! { "name": "Grace", "address": { "street": "3 Elm St" } },
+        ]
+        .iter()
+        .map(bson::RawDocumentBuf::from_document)
+        .collect::<Result<_, _>>()?;
+        let options = ConfigurationSerializationOptions {
+            row_errors: RowErrorPolicy::Skip,
+            ..Default::default()
+        };
+        let response = serialize_query_response(
+            options,
+            &Default::default(),
+            &Default::default(),
+            &query_plan,
+            response_documents,
+        )?;
+        let skipped_rows =
+            serde_json::from_slice::<serde_json::Value>(&response)?[0]["skippedRows"].clone();
+        let response: QueryResponse = serde_json::from_slice(&response)?;
+
+        assert_eq!(skipped_rows, json!(1));
+        assert_eq!(
+            response,
+            QueryResponse(vec![RowSet {
+                aggregates: Default::default(),
+                rows: Some(vec![
+                    [
+                        ("name".into(), RowFieldValue(json!("Ada"))),
+                        (
+                            "address".into(),
+                            RowFieldValue(json!({ "street": "1 Main St" }))
+                        ),
+                    ]
+                    .into(),
+                    [
+                        ("name".into(), RowFieldValue(json!("Grace"))),
+                        (
+                            "address".into(),
+                            RowFieldValue(json!({ "street": "3 Elm St" }))
+                        ),
+                    ]
+                    .into(),
+                ]),
+            }])
+        );
+
+        let options = ConfigurationSerializationOptions::default();
+        let response_documents = vec![bson::RawDocumentBuf::from_document(
+            &bson::doc! { "name": "Charles", "address": { "street": 2 } },
+        )?];
+        assert!(serialize_query_response(
+            options,
+            &Default::default(),
+            &Default::default(),
+            &query_plan,
+            response_documents
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_rows_larger_than_max_row_size() -> anyhow::Result<()> {
+        let request = query_request()
+            .collection("authors")
+            .query(query().fields([field!("name")]))
+            .into();
+        let query_plan = plan_for_query_request(&make_nested_schema(), request)?;
+        let options = ConfigurationSerializationOptions {
+            max_row_size_bytes: Some(20),
+            ..Default::default()
+        };
+        let short_row = bson::doc! { "name": "Ada" };
+        let long_row = bson::doc! { "name": "Ada Lovelace, Countess of Lovelace" };
+
+        let response = serialize_query_response(
+            options,
+            &Default::default(),
+            &Default::default(),
+            &query_plan,
+            vec![bson::RawDocumentBuf::from_document(&short_row)?],
+        )?;
+        let response: QueryResponse = serde_json::from_slice(&response)?;
+        assert_eq!(
+            response,
+            QueryResponse(vec![RowSet {
+                aggregates: Default::default(),
+                rows: Some(vec![[("name".into(), RowFieldValue(json!("Ada")))].into()]),
+            }])
+        );
+
+        let result = serialize_query_response(
+            options,
+            &Default::default(),
+            &Default::default(),
+            &query_plan,
+            [short_row, long_row]
+                .iter()
+                .map(bson::RawDocumentBuf::from_document)
+                .collect::<Result<_, _>>()?,
+        );
+        let Err(err) = result else {
+            panic!("expected an error, got {result:?}");
+        };
+        assert!(err.to_string().contains(
+            "row 1 is 45 bytes when serialized to JSON which exceeds the maximum of 20 bytes"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn reports_path_type_and_value_of_value_that_fails_to_serialize() -> anyhow::Result<()> {
+        let request = query_request()
+            .collection("authors")
+            .query(query().fields([
+                field!("name"),
+                field!("address" => "address", object!([field!("street")])),
+            ]))
+            .into();
+        let query_plan = plan_for_query_request(&make_nested_schema(), request)?;
+
+        let response_documents = [
+            bson::doc! { "name": "Ada", "address": { "street": "1 Main St" } },
+            bson::doc! { "name": "Charles", "address": { "street": 2 } },
+        ]
+        .iter()
+        .map(bson::RawDocumentBuf::from_document)
+        .collect::<Result<_, _>>()?;
+        let result = serialize_query_response(
+            Default::default(),
+            &Default::default(),
+            &Default::default(),
+            &query_plan,
+            response_documents,
+        );
+        let Err(QueryResponseError::ValueSerialization { row, failure, .. }) = result else {
+            panic!("expected a value serialization error, got {result:?}");
+        };
+        assert_eq!(row, 1);
+        assert_eq!(
+            failure,
+            SerializationFailure {
+                path: "$.address.street".to_owned(),
+                expected_type: "string".to_owned(),
+                value: "Int32(2)".to_owned(),
+            }
+        );
+        Ok(())
+    }
+
     #[test]
     fn serializes_response_with_nested_object_inside_array() -> anyhow::Result<()> {
         let request = query_request()
@@ -387,8 +949,7 @@ mod tests {
             ],
         }];
 
-        let response =
-            serialize_query_response(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
+        let response = serialize(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
         assert_eq!(
             response,
             QueryResponse(vec![RowSet {
@@ -406,6 +967,53 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn serializes_response_with_nested_arrays_of_arrays() -> anyhow::Result<()> {
+        let request = query_request()
+            .collection("authors")
+            .query(
+                query().fields([field!("shelves" => "array_of_arrays", array!(array!(
+                    object!([
+                        field!("article_title" => "title"),
+                    ])
+                )))]),
+            )
+            .into();
+        let query_plan = plan_for_query_request(&make_nested_schema(), request)?;
+
+        let response_documents = vec![bson::doc! {
+            "shelves": [
+                [{ "article_title": "Modeling MongoDB with relational model" }],
+                [],
+                [
+                    { "article_title": "NoSQL databases: MongoDB vs cassandra" },
+                    { "article_title": "Sharding strategies" },
+                ],
+            ],
+        }];
+
+        let response = serialize(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
+        assert_eq!(
+            response,
+            QueryResponse(vec![RowSet {
+                aggregates: Default::default(),
+                rows: Some(vec![[(
+                    "shelves".into(),
+                    RowFieldValue(json!([
+                        [{ "article_title": "Modeling MongoDB with relational model" }],
+                        [],
+                        [
+                            { "article_title": "NoSQL databases: MongoDB vs cassandra" },
+                            { "article_title": "Sharding strategies" },
+                        ],
+                    ]))
+                )]
+                .into()]),
+            }])
+        );
+        Ok(())
+    }
+
     #[test]
     fn serializes_response_with_aliased_fields() -> anyhow::Result<()> {
         let request = query_request()
@@ -434,8 +1042,7 @@ mod tests {
             },
         }];
 
-        let response =
-            serialize_query_response(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
+        let response = serialize(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
         assert_eq!(
             response,
             QueryResponse(vec![RowSet {
@@ -462,6 +1069,80 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn applies_response_post_processors_to_rows() -> anyhow::Result<()> {
+        struct MaskCardNumbers;
+
+        impl ResponsePostProcessor for MaskCardNumbers {
+            fn process_row(
+                &self,
+                context: &RowContext<'_>,
+                row: &mut serde_json::Map<String, serde_json::Value>,
+            ) -> anyhow::Result<()> {
+                assert_eq!(context.collection.as_str(), "cardholders");
+                if let Some(serde_json::Value::String(number)) = row.get_mut("card_number") {
+                    let last_four = number.split_off(number.len().saturating_sub(4));
+                    *number = format!("****{last_four}");
+                }
+                row.insert("row".to_owned(), json!(context.row));
+                Ok(())
+            }
+        }
+
+        let query_context = MongoConfiguration(Configuration {
+            collections: [collection("cardholders")].into(),
+            object_types: [(
+                "cardholders".into(),
+                object_type([
+                    ("name", named_type("String")),
+                    ("card_number", named_type("String")),
+                ]),
+            )]
+            .into(),
+            ..Default::default()
+        });
+        let request = query_request()
+            .collection("cardholders")
+            .query(query().fields([field!("name"), field!("card_number")]))
+            .into();
+        let query_plan = plan_for_query_request(&query_context, request)?;
+
+        let response_documents = vec![
+            bson::rawdoc! { "name": "Ada", "card_number": "4111111111111111" },
+            bson::rawdoc! { "name": "Grace", "card_number": "5500000000000004" },
+        ];
+        let post_processors = ResponsePostProcessors::new(vec![Arc::new(MaskCardNumbers)]);
+        let response = serialize_query_response(
+            Default::default(),
+            &Default::default(),
+            &post_processors,
+            &query_plan,
+            response_documents,
+        )?;
+
+        assert_eq!(
+            serde_json::from_slice::<QueryResponse>(&response)?,
+            QueryResponse(vec![RowSet {
+                aggregates: Default::default(),
+                rows: Some(vec![
+                    [
+                        ("name".into(), RowFieldValue(json!("Ada"))),
+                        ("card_number".into(), RowFieldValue(json!("****1111"))),
+                        ("row".into(), RowFieldValue(json!(0))),
+                    ]
+                    .into(),
+                    [
+                        ("name".into(), RowFieldValue(json!("Grace"))),
+                        ("card_number".into(), RowFieldValue(json!("****0004"))),
+                        ("row".into(), RowFieldValue(json!(1))),
+                    ]
+                    .into(),
+                ]),
+            }])
+        );
+        Ok(())
+    }
+
     #[test]
     fn serializes_response_with_decimal_128_fields() -> anyhow::Result<()> {
         let query_context = MongoConfiguration(Configuration {
@@ -478,6 +1159,10 @@ mod tests {
             procedures: Default::default(),
             native_mutations: Default::default(),
             native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
             options: Default::default(),
         });
 
@@ -493,8 +1178,7 @@ mod tests {
             "price_extjson": Bson::Decimal128(bson::Decimal128::from_str("-4.9999999999").unwrap()),
         }];
 
-        let response =
-            serialize_query_response(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
+        let response = serialize(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
         assert_eq!(
             response,
             QueryResponse(vec![RowSet {
@@ -527,6 +1211,10 @@ mod tests {
             procedures: Default::default(),
             native_mutations: Default::default(),
             native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
             options: Default::default(),
         });
 
@@ -551,8 +1239,7 @@ mod tests {
             },
         }];
 
-        let response =
-            serialize_query_response(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
+        let response = serialize(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
         assert_eq!(
             response,
             QueryResponse(vec![RowSet {
@@ -590,6 +1277,10 @@ mod tests {
             procedures: Default::default(),
             native_mutations: Default::default(),
             native_queries: Default::default(),
+            lookup_functions: Default::default(),
+            collection_policies: Default::default(),
+            relationships: Default::default(),
+            object_id_formats: Default::default(),
             options: Default::default(),
         });
 
@@ -614,8 +1305,7 @@ mod tests {
             },
         }];
 
-        let response =
-            serialize_query_response(ExtendedJsonMode::Relaxed, &query_plan, response_documents)?;
+        let response = serialize(ExtendedJsonMode::Relaxed, &query_plan, response_documents)?;
         assert_eq!(
             response,
             QueryResponse(vec![RowSet {
@@ -640,6 +1330,112 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn serializes_min_and_max_aggregates_according_to_column_types() -> anyhow::Result<()> {
+        let query_context = MongoConfiguration(Configuration {
+            collections: [collection("movies")].into(),
+            object_types: [(
+                "movies".into(),
+                object_type([
+                    ("released", named_type("Date")),
+                    ("title", named_type("String")),
+                ]),
+            )]
+            .into(),
+            ..Default::default()
+        });
+
+        let request = query_request()
+            .collection("movies")
+            .query(query().aggregates([
+                column_aggregate!("latest" => "released", "max"),
+                column_aggregate!("first_title" => "title", "min"),
+                column_aggregate!("count" => "title", "count"),
+            ]))
+            .into();
+
+        let query_plan = plan_for_query_request(&query_context, request)?;
+
+        let response_documents = vec![bson::doc! {
+            "aggregates": {
+                "latest": bson::DateTime::from_millis(-1675900800000),
+                "first_title": "Les vampires",
+                "count": 5,
+            },
+        }];
+
+        let response = serialize(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
+        assert_eq!(
+            response,
+            QueryResponse(vec![RowSet {
+                aggregates: Some(
+                    [
+                        ("count".into(), json!({ "$numberInt": "5" })),
+                        ("first_title".into(), json!("Les vampires")),
+                        ("latest".into(), json!("1916-11-23T00:00:00.000000000Z")),
+                    ]
+                    .into()
+                ),
+                rows: None,
+            }])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn serializes_repeated_relationship_values_once() -> anyhow::Result<()> {
+        let request = query_request()
+            .collection("appearances")
+            .relationships([("author", relationship("authors", [("authorId", "id")]))])
+            .query(query().fields([
+                relation_field!("presenter" => "author", query().fields([field!("name")])),
+            ]))
+            .into();
+        let query_plan = plan_for_query_request(&make_nested_schema(), request)?;
+
+        let row_type = query_plan
+            .query
+            .fields
+            .as_ref()
+            .map(|fields| type_for_row(&[], fields))
+            .transpose()?
+            .expect("query selects fields");
+        let mut relationship_types = vec![];
+        relationship_field_types(
+            query_plan
+                .query
+                .fields
+                .as_ref()
+                .expect("query selects fields"),
+            &row_type,
+            &mut relationship_types,
+        );
+        assert_eq!(relationship_types.len(), 1);
+
+        let presenter = |name: &str| bson::doc! { "presenter": { "rows": [{ "name": name }] } };
+        let response_documents = vec![presenter("Laura"), presenter("Ben"), presenter("Laura")];
+        let response = serialize(ExtendedJsonMode::Canonical, &query_plan, response_documents)?;
+        let presenter = |name: &str| {
+            [(
+                "presenter".into(),
+                RowFieldValue(json!({ "rows": [{ "name": name }] })),
+            )]
+            .into()
+        };
+        assert_eq!(
+            response,
+            QueryResponse(vec![RowSet {
+                aggregates: None,
+                rows: Some(vec![
+                    presenter("Laura"),
+                    presenter("Ben"),
+                    presenter("Laura")
+                ]),
+            }])
+        );
+        Ok(())
+    }
+
     #[test]
     fn uses_field_path_to_guarantee_distinct_type_names() -> anyhow::Result<()> {
         let collection_name = "appearances";
@@ -1,4 +1,7 @@
-use configuration::MongoScalarType;
+use configuration::{
+    schema::ObjectIdFormat, ConfigurationSerializationOptions, MongoScalarType,
+    NonFiniteNumberPolicy,
+};
 use itertools::Itertools as _;
 use mongodb::bson::{self, Bson};
 use mongodb_support::{BsonScalarType, ExtendedJsonMode};
@@ -21,11 +24,23 @@ pub enum BsonToJsonError {
     #[error("input object of type {0:?} is missing a field, \"{1}\"")]
     MissingObjectField(Type, String),
 
+    #[error("value at {path} is nested more than {max_depth} levels deep")]
+    NestingTooDeep { path: String, max_depth: usize },
+
+    #[error("error reading raw BSON value: {0}")]
+    RawBson(#[from] bson::raw::Error),
+
+    #[error("row {row} is {size} bytes when serialized to JSON which exceeds the maximum of {max_size} bytes")]
+    RowTooLarge {
+        row: usize,
+        size: usize,
+        max_size: usize,
+    },
+
     #[error("error converting value to JSON: {0}")]
     Serde(#[from] serde_json::Error),
 
-    // TODO: It would be great if we could capture a path into the larger BSON value here
-    #[error("expected a value of type {0:?}, but got {1}")]
+    #[error("expected a value of type {}, but got {}", describe_type(.0), describe_value(.1))]
     TypeMismatch(Type, Bson),
 
     #[error("unknown object type, \"{0}\"")]
@@ -34,6 +49,33 @@ pub enum BsonToJsonError {
 
 type Result<T> = std::result::Result<T, BsonToJsonError>;
 
+/// Values in error messages are cut off at this many characters
+const MAX_DESCRIBED_VALUE_LENGTH: usize = 200;
+
+/// A compact description of a type for error messages. Object types are described by name
+/// instead of listing their fields.
+pub fn describe_type(t: &Type) -> String {
+    match t {
+        Type::Scalar(MongoScalarType::ExtendedJSON) => "ExtendedJSON".to_owned(),
+        Type::Scalar(MongoScalarType::Bson(scalar_type)) => scalar_type.bson_name().to_owned(),
+        Type::Object(ObjectType {
+            name: Some(name), ..
+        }) => name.to_string(),
+        Type::Object(_) => "object".to_owned(),
+        Type::ArrayOf(element_type) => format!("[{}]", describe_type(element_type)),
+        Type::Nullable(underlying_type) => format!("nullable {}", describe_type(underlying_type)),
+    }
+}
+
+/// A debug rendering of a value for error messages, cut off if it is long
+pub fn describe_value(value: &Bson) -> String {
+    let rendered = format!("{value:?}");
+    match rendered.char_indices().nth(MAX_DESCRIBED_VALUE_LENGTH) {
+        Some((cutoff, _)) => format!("{}...", &rendered[..cutoff]),
+        None => rendered,
+    }
+}
+
 /// Converts BSON values to JSON.
 ///
 /// The BSON library already has a `Serialize` impl that can convert to JSON. But that
@@ -41,15 +83,21 @@ type Result<T> = std::result::Result<T, BsonToJsonError>;
 /// disambiguate types on the BSON side. We don't want those tags because we communicate type
 /// information out of band. That is except for the `Type::ExtendedJSON` type where we do want to emit
 /// Extended JSON because we don't have out-of-band information in that case.
-pub fn bson_to_json(mode: ExtendedJsonMode, expected_type: &Type, value: Bson) -> Result<Value> {
+pub fn bson_to_json(
+    options: ConfigurationSerializationOptions,
+    expected_type: &Type,
+    value: Bson,
+) -> Result<Value> {
     match expected_type {
-        Type::Scalar(configuration::MongoScalarType::ExtendedJSON) => Ok(mode.into_extjson(value)),
+        Type::Scalar(configuration::MongoScalarType::ExtendedJSON) => {
+            Ok(extended_json(options, value))
+        }
         Type::Scalar(MongoScalarType::Bson(scalar_type)) => {
-            bson_scalar_to_json(mode, *scalar_type, value)
+            bson_scalar_to_json(options, *scalar_type, value)
         }
-        Type::Object(object_type) => convert_object(mode, object_type, value),
-        Type::ArrayOf(element_type) => convert_array(mode, element_type, value),
-        Type::Nullable(t) => convert_nullable(mode, t, value),
+        Type::Object(object_type) => convert_object(options, object_type, value),
+        Type::ArrayOf(element_type) => convert_array(options, element_type, value),
+        Type::Nullable(t) => convert_nullable(options, t, value),
     }
 }
 
@@ -57,7 +105,7 @@ pub fn bson_to_json(mode: ExtendedJsonMode, expected_type: &Type, value: Bson) -
 // we do implicit conversion where the BSON types have indistinguishable JSON representations, and
 // values can be converted back to BSON without loss of meaning.
 fn bson_scalar_to_json(
-    mode: ExtendedJsonMode,
+    options: ConfigurationSerializationOptions,
     expected_type: BsonScalarType,
     value: Bson,
 ) -> Result<Value> {
@@ -68,16 +116,19 @@ fn bson_scalar_to_json(
         (BsonScalarType::MinKey, Bson::MinKey) => Ok(Value::Object(Default::default())),
         (BsonScalarType::MaxKey, Bson::MaxKey) => Ok(Value::Object(Default::default())),
         (BsonScalarType::Bool, Bson::Boolean(b)) => Ok(Value::Bool(b)),
-        (BsonScalarType::Double, v) => convert_small_number(expected_type, v),
-        (BsonScalarType::Int, v) => convert_small_number(expected_type, v),
+        (BsonScalarType::Double, v) => convert_small_number(options, expected_type, v),
+        (BsonScalarType::Int, v) => convert_small_number(options, expected_type, v),
         (BsonScalarType::Long, Bson::Int64(n)) => Ok(Value::String(n.to_string())),
         (BsonScalarType::Decimal, Bson::Decimal128(n)) => Ok(Value::String(n.to_string())),
-        (BsonScalarType::String, Bson::String(s)) => Ok(Value::String(s)),
-        (BsonScalarType::Symbol, Bson::Symbol(s)) => Ok(Value::String(s)),
+        // Symbols are a deprecated alternative to strings. Older data sets may mix the two in
+        // the same field.
+        (BsonScalarType::String | BsonScalarType::Symbol, Bson::String(s) | Bson::Symbol(s)) => {
+            Ok(Value::String(s))
+        }
         (BsonScalarType::Date, Bson::DateTime(date)) => convert_date(date),
         (BsonScalarType::Javascript, Bson::JavaScriptCode(s)) => Ok(Value::String(s)),
         (BsonScalarType::JavascriptWithScope, Bson::JavaScriptCodeWithScope(v)) => {
-            convert_code(mode, v)
+            convert_code(options.extended_json_mode, v)
         }
         (BsonScalarType::Regex, Bson::RegularExpression(regex)) => {
             Ok(to_value::<json_formats::Regex>(regex.into())?)
@@ -88,8 +139,14 @@ fn bson_scalar_to_json(
         (BsonScalarType::BinData, Bson::Binary(b)) => {
             Ok(to_value::<json_formats::BinData>(b.into())?)
         }
-        (BsonScalarType::ObjectId, Bson::ObjectId(oid)) => Ok(Value::String(oid.to_hex())),
-        (BsonScalarType::DbPointer, v) => Ok(mode.into_extjson(v)),
+        (BsonScalarType::ObjectId, Bson::ObjectId(oid)) => match options.object_id_format {
+            ObjectIdFormat::HexString => Ok(Value::String(oid.to_hex())),
+            ObjectIdFormat::ExtendedJson => Ok(serde_json::json!({ "$oid": oid.to_hex() })),
+        },
+        // dbPointer has no simpler JSON form so we emit extjson, which is accepted as input
+        (BsonScalarType::DbPointer, v @ Bson::DbPointer(_)) => {
+            Ok(options.extended_json_mode.into_extjson(v))
+        }
         (_, v) => Err(BsonToJsonError::TypeMismatch(
             Type::Scalar(MongoScalarType::Bson(expected_type)),
             v,
@@ -97,7 +154,11 @@ fn bson_scalar_to_json(
     }
 }
 
-fn convert_array(mode: ExtendedJsonMode, element_type: &Type, value: Bson) -> Result<Value> {
+fn convert_array(
+    options: ConfigurationSerializationOptions,
+    element_type: &Type,
+    value: Bson,
+) -> Result<Value> {
     let values = match value {
         Bson::Array(values) => Ok(values),
         _ => Err(BsonToJsonError::TypeMismatch(
@@ -107,12 +168,16 @@ fn convert_array(mode: ExtendedJsonMode, element_type: &Type, value: Bson) -> Re
     }?;
     let json_array = values
         .into_iter()
-        .map(|value| bson_to_json(mode, element_type, value))
+        .map(|value| bson_to_json(options, element_type, value))
         .try_collect()?;
     Ok(Value::Array(json_array))
 }
 
-fn convert_object(mode: ExtendedJsonMode, object_type: &ObjectType, value: Bson) -> Result<Value> {
+fn convert_object(
+    options: ConfigurationSerializationOptions,
+    object_type: &ObjectType,
+    value: Bson,
+) -> Result<Value> {
     let input_doc = match value {
         Bson::Document(fields) => Ok(fields),
         _ => Err(BsonToJsonError::TypeMismatch(
@@ -130,7 +195,7 @@ fn convert_object(mode: ExtendedJsonMode, object_type: &ObjectType, value: Bson)
         .map(|((field_name, field_type), field_value_result)| {
             Ok((
                 field_name.to_string(),
-                bson_to_json(mode, field_type, field_value_result?)?,
+                bson_to_json(options, field_type, field_value_result?)?,
             ))
         })
         .try_collect::<_, _, BsonToJsonError>()?;
@@ -157,10 +222,47 @@ fn get_object_field_value(
     })?))
 }
 
-fn convert_nullable(mode: ExtendedJsonMode, underlying_type: &Type, value: Bson) -> Result<Value> {
+fn convert_nullable(
+    options: ConfigurationSerializationOptions,
+    underlying_type: &Type,
+    value: Bson,
+) -> Result<Value> {
     match value {
         Bson::Null => Ok(Value::Null),
-        non_null_value => bson_to_json(mode, underlying_type, non_null_value),
+        non_null_value => bson_to_json(options, underlying_type, non_null_value),
+    }
+}
+
+/// Largest integer that a JavaScript number represents exactly, `Number.MAX_SAFE_INTEGER`
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// Converts a value of type `ExtendedJSON` according to the configured mode. If
+/// `largeLongsAsStrings` is set, integers that JavaScript cannot represent exactly are written as
+/// strings. Only relaxed mode writes 64-bit integers as JSON numbers.
+pub(super) fn extended_json(options: ConfigurationSerializationOptions, value: Bson) -> Value {
+    let json = options.extended_json_mode.into_extjson(value);
+    if options.large_longs_as_strings && options.extended_json_mode == ExtendedJsonMode::Relaxed {
+        large_integers_to_strings(json)
+    } else {
+        json
+    }
+}
+
+fn large_integers_to_strings(value: Value) -> Value {
+    match value {
+        Value::Number(n) if n.as_i64().is_some_and(|n| n.abs() > MAX_SAFE_INTEGER) => {
+            Value::String(n.to_string())
+        }
+        Value::Array(values) => {
+            Value::Array(values.into_iter().map(large_integers_to_strings).collect())
+        }
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name, large_integers_to_strings(value)))
+                .collect(),
+        ),
+        value => value,
     }
 }
 
@@ -194,11 +296,20 @@ fn convert_date(date: bson::DateTime) -> Result<Value> {
 // We can mix up doubles and 32-bit ints because they both map to JSON numbers, we don't lose
 // precision, and the carry approximately the same meaning when converted back to BSON with the
 // reversed type.
-fn convert_small_number(expected_type: BsonScalarType, value: Bson) -> Result<Value> {
+fn convert_small_number(
+    options: ConfigurationSerializationOptions,
+    expected_type: BsonScalarType,
+    value: Bson,
+) -> Result<Value> {
     match value {
-        Bson::Double(n) => Ok(Value::Number(
-            Number::from_f64(n).ok_or(BsonToJsonError::DoubleConversion(n))?,
-        )),
+        Bson::Double(n) => match Number::from_f64(n) {
+            Some(number) => Ok(Value::Number(number)),
+            None => convert_non_finite(
+                options,
+                non_finite_double_string(n),
+                BsonToJsonError::DoubleConversion(n),
+            ),
+        },
         Bson::Int32(n) => Ok(Value::Number(n.into())),
         _ => Err(BsonToJsonError::TypeMismatch(
             Type::Scalar(MongoScalarType::Bson(expected_type)),
@@ -207,10 +318,37 @@ fn convert_small_number(expected_type: BsonScalarType, value: Bson) -> Result<Va
     }
 }
 
+// Use the same spellings as extjson, and as decimal values
+fn non_finite_double_string(n: f64) -> String {
+    let string = if n.is_nan() {
+        "NaN"
+    } else if n.is_sign_positive() {
+        "Infinity"
+    } else {
+        "-Infinity"
+    };
+    string.to_owned()
+}
+
+/// JSON cannot represent NaN or infinite numbers. The serialization options determine what to emit
+/// instead.
+pub(super) fn convert_non_finite(
+    options: ConfigurationSerializationOptions,
+    string_form: String,
+    error: BsonToJsonError,
+) -> Result<Value> {
+    match options.non_finite_numbers {
+        NonFiniteNumberPolicy::Error => Err(error),
+        NonFiniteNumberPolicy::Null => Ok(Value::Null),
+        NonFiniteNumberPolicy::String => Ok(Value::String(string_form)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
+    use mongodb::bson::Decimal128;
     use pretty_assertions::assert_eq;
     use serde_json::json;
 
@@ -220,7 +358,7 @@ mod tests {
     fn serializes_object_id_to_string() -> anyhow::Result<()> {
         let expected_string = "573a1390f29313caabcd446f";
         let json = bson_to_json(
-            ExtendedJsonMode::Canonical,
+            ConfigurationSerializationOptions::default(),
             &Type::Scalar(MongoScalarType::Bson(BsonScalarType::ObjectId)),
             Bson::ObjectId(FromStr::from_str(expected_string)?),
         )?;
@@ -241,8 +379,129 @@ mod tests {
             .into(),
         });
         let value = bson::doc! {};
-        let actual = bson_to_json(ExtendedJsonMode::Canonical, &expected_type, value.into())?;
+        let actual = bson_to_json(
+            ConfigurationSerializationOptions::default(),
+            &expected_type,
+            value.into(),
+        )?;
         assert_eq!(actual, json!({}));
         Ok(())
     }
+
+    #[test]
+    fn round_trips_legacy_scalar_types() -> anyhow::Result<()> {
+        let db_pointer = Bson::try_from(json!({
+            "$dbPointer": {
+                "$ref": "movies",
+                "$id": { "$oid": "573a1390f29313caabcd446f" },
+            }
+        }))?;
+        let values = [
+            (BsonScalarType::Symbol, Bson::Symbol("a_symbol".to_owned())),
+            (
+                BsonScalarType::Javascript,
+                Bson::JavaScriptCode("console.log('hello')".to_owned()),
+            ),
+            (
+                BsonScalarType::Regex,
+                Bson::RegularExpression(bson::Regex {
+                    pattern: "^fo+$".to_owned(),
+                    options: "i".to_owned(),
+                }),
+            ),
+            (BsonScalarType::DbPointer, db_pointer),
+        ];
+        for (scalar_type, value) in values {
+            let value_type = Type::Scalar(MongoScalarType::Bson(scalar_type));
+            let json = bson_to_json(
+                ConfigurationSerializationOptions::default(),
+                &value_type,
+                value.clone(),
+            )?;
+            let actual = super::super::json_to_bson(&value_type, json)?;
+            assert_eq!(actual, value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn applies_policy_to_non_finite_numbers() -> anyhow::Result<()> {
+        let double_type = Type::Scalar(MongoScalarType::Bson(BsonScalarType::Double));
+        let decimal_type = Type::Scalar(MongoScalarType::Bson(BsonScalarType::Decimal));
+        let options = |non_finite_numbers| ConfigurationSerializationOptions {
+            non_finite_numbers,
+            ..Default::default()
+        };
+
+        let null_policy = options(NonFiniteNumberPolicy::Null);
+        assert_eq!(
+            bson_to_json(null_policy, &double_type, Bson::Double(f64::NAN))?,
+            Value::Null
+        );
+
+        let string_policy = options(NonFiniteNumberPolicy::String);
+        assert_eq!(
+            bson_to_json(string_policy, &double_type, Bson::Double(f64::NEG_INFINITY))?,
+            json!("-Infinity")
+        );
+
+        let error_policy = options(NonFiniteNumberPolicy::Error);
+        assert!(bson_to_json(error_policy, &double_type, Bson::Double(f64::INFINITY)).is_err());
+        assert_eq!(
+            bson_to_json(error_policy, &double_type, Bson::Double(1.5))?,
+            json!(1.5)
+        );
+
+        // Decimals are written as strings so non-finite decimals are valid JSON under any policy
+        for policy in [null_policy, string_policy, error_policy] {
+            assert_eq!(
+                bson_to_json(
+                    policy,
+                    &decimal_type,
+                    Bson::Decimal128(Decimal128::from_str("NaN")?)
+                )?,
+                json!("NaN")
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn accepts_symbols_in_string_fields() -> anyhow::Result<()> {
+        let json = bson_to_json(
+            ConfigurationSerializationOptions::default(),
+            &Type::Scalar(MongoScalarType::Bson(BsonScalarType::String)),
+            Bson::Symbol("a_symbol".to_owned()),
+        )?;
+        assert_eq!(json, json!("a_symbol"));
+        Ok(())
+    }
+
+    #[test]
+    fn writes_large_longs_in_relaxed_extended_json_as_strings() -> anyhow::Result<()> {
+        let value = Bson::Document(bson::doc! {
+            "small": 42_i64,
+            "ids": [9007199254740993_i64, -9007199254740993_i64],
+            "int": 7,
+        });
+        let options = ConfigurationSerializationOptions {
+            extended_json_mode: ExtendedJsonMode::Relaxed,
+            ..Default::default()
+        };
+        let extjson_type = Type::Scalar(MongoScalarType::ExtendedJSON);
+
+        assert_eq!(
+            bson_to_json(options, &extjson_type, value.clone())?,
+            json!({ "small": 42, "ids": [9007199254740993_i64, -9007199254740993_i64], "int": 7 })
+        );
+        let options = ConfigurationSerializationOptions {
+            large_longs_as_strings: true,
+            ..options
+        };
+        assert_eq!(
+            bson_to_json(options, &extjson_type, value)?,
+            json!({ "small": 42, "ids": ["9007199254740993", "-9007199254740993"], "int": 7 })
+        );
+        Ok(())
+    }
 }
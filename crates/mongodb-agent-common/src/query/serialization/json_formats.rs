@@ -84,6 +84,15 @@ impl From<bson::Regex> for Regex {
     }
 }
 
+impl From<bson::RawRegexRef<'_>> for Regex {
+    fn from(value: bson::RawRegexRef<'_>) -> Self {
+        Regex {
+            pattern: value.pattern.to_owned(),
+            options: value.options.to_owned(),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Timestamp {
     t: u32,
@@ -89,7 +89,7 @@ pub fn json_to_bson_scalar(expected_type: BsonScalarType, value: Value) -> Resul
         BsonScalarType::BinData => {
             deserialize::<json_formats::BinData>(expected_type, value)?.into()
         }
-        BsonScalarType::ObjectId => Bson::ObjectId(deserialize(expected_type, value)?),
+        BsonScalarType::ObjectId => Bson::ObjectId(convert_object_id(value)?),
         BsonScalarType::Bool => match value {
             Value::Bool(b) => Bson::Boolean(b),
             _ => incompatible_scalar_type(BsonScalarType::Bool, value)?,
@@ -110,8 +110,11 @@ pub fn json_to_bson_scalar(expected_type: BsonScalarType, value: Value) -> Resul
         BsonScalarType::MinKey => Bson::MinKey,
         BsonScalarType::MaxKey => Bson::MaxKey,
         BsonScalarType::Symbol => Bson::Symbol(deserialize(expected_type, value)?),
-        // dbPointer is deprecated
-        BsonScalarType::DbPointer => Err(JsonToBsonError::NotImplemented(expected_type))?,
+        // dbPointer is deprecated, and is only accepted in the extjson form that we emit for it
+        BsonScalarType::DbPointer => match deserialize::<Bson>(expected_type, value.clone())? {
+            db_pointer @ Bson::DbPointer(_) => db_pointer,
+            _ => incompatible_scalar_type(BsonScalarType::DbPointer, value)?,
+        },
     };
     Ok(result)
 }
@@ -172,6 +175,23 @@ fn convert_nullable(underlying_type: &Type, value: Value) -> Result<Bson> {
     }
 }
 
+/// Accepts a hex string, or an extended JSON object such as `{ "$oid": "<hex>" }`, so that
+/// inputs work with either setting of `objectIdFormat`
+fn convert_object_id(value: Value) -> Result<bson::oid::ObjectId> {
+    let hex = match &value {
+        Value::String(hex) => Some(hex.as_str()),
+        Value::Object(object) if object.len() == 1 => object.get("$oid").and_then(Value::as_str),
+        _ => None,
+    };
+    hex.and_then(|hex| bson::oid::ObjectId::parse_str(hex).ok())
+        .ok_or_else(|| {
+            JsonToBsonError::ConversionError(
+                Type::Scalar(MongoScalarType::Bson(BsonScalarType::ObjectId)),
+                value,
+            )
+        })
+}
+
 fn convert_date(value: &str) -> Result<Bson> {
     let date = OffsetDateTime::parse(value, &Iso8601::DEFAULT).map_err(|err| {
         JsonToBsonError::ConversionErrorWithContext(
@@ -348,6 +368,20 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn deserializes_object_ids_as_hex_strings_or_extended_json() -> anyhow::Result<()> {
+        let object_id_type = Type::Scalar(MongoScalarType::Bson(BsonScalarType::ObjectId));
+        let expected = Bson::ObjectId(FromStr::from_str("e7c8f79873814cbae1f8d84c")?);
+        for input in [
+            json!("e7c8f79873814cbae1f8d84c"),
+            json!({ "$oid": "e7c8f79873814cbae1f8d84c" }),
+        ] {
+            assert_eq!(json_to_bson(&object_id_type, input)?, expected);
+        }
+        assert!(json_to_bson(&object_id_type, json!({ "$oid": "not hex" })).is_err());
+        Ok(())
+    }
+
     #[test]
     fn deserializes_nullable_values() -> anyhow::Result<()> {
         let input = json!(["e7c8f79873814cbae1f8d84c", null, "fae1840a2b85872385c67de5",]);
@@ -2,10 +2,12 @@ mod bson_to_json;
 mod helpers;
 mod json_formats;
 mod json_to_bson;
+mod raw_bson_to_json;
 
 #[cfg(test)]
 mod tests;
 
-pub use bson_to_json::{bson_to_json, BsonToJsonError};
+pub use bson_to_json::{bson_to_json, describe_type, describe_value, BsonToJsonError};
 pub use helpers::is_nullable;
 pub use json_to_bson::{json_to_bson, json_to_bson_scalar, JsonToBsonError};
+pub use raw_bson_to_json::{RawBsonToJson, SerializationFailure, SubtreeCache};
@@ -0,0 +1,639 @@
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt::Display,
+    hash::{Hash as _, Hasher as _},
+};
+
+use configuration::{
+    schema::ObjectIdFormat, ConfigurationSerializationOptions, MongoScalarType, ObjectIdFormats,
+};
+use mongodb::bson::{Bson, RawArray, RawBsonRef, RawDocument};
+use mongodb_support::BsonScalarType;
+use serde::{
+    ser::{SerializeMap as _, SerializeSeq as _},
+    Serialize, Serializer,
+};
+use serde_json::value::RawValue;
+
+use crate::mongo_query_plan::{ObjectType, Type};
+
+use super::{
+    bson_to_json, bson_to_json::extended_json, describe_type, describe_value, is_nullable,
+    json_formats, BsonToJsonError,
+};
+
+/// Serializes a raw BSON value to JSON according to an expected type. The output is the same as
+/// the output of [bson_to_json], but values are written directly from raw BSON to the serializer
+/// without materializing intermediate `Bson` or `serde_json::Value` trees. The exception is
+/// values of type `ExtendedJSON`, and less common scalar types which are converted value by value
+/// using [bson_to_json].
+///
+/// Serialization fails if documents and arrays are nested more deeply than the configured maximum
+/// depth. The error identifies the path to the offending field.
+#[derive(Clone, Copy, Debug)]
+pub struct RawBsonToJson<'a> {
+    pub options: ConfigurationSerializationOptions,
+    pub expected_type: &'a Type,
+    pub value: RawBsonRef<'a>,
+    path: FieldPath<'a>,
+    depth: usize,
+    /// If set, the location of the value that fails to serialize is recorded here
+    failure: Option<&'a RefCell<Option<SerializationFailure>>>,
+    /// Per-field overrides of `options.object_id_format`, keyed by object type name
+    object_id_formats: Option<&'a ObjectIdFormats>,
+    subtree_cache: Option<&'a SubtreeCache<'a>>,
+}
+
+/// Serialized JSON for values of relationship fields, shared by all rows of a response. When
+/// a relationship joins the same related documents to many parents, the value of the
+/// relationship field is identical for every parent with the same join key values, so it is
+/// serialized once. Values are keyed by their raw BSON bytes, which are identical exactly when
+/// the same query was run with the same join keys.
+///
+/// A value is only retained once it has been seen a second time, so responses without repeated
+/// values only pay for a hash per value. The cache stops growing when the retained BSON and JSON
+/// reach [SUBTREE_CACHE_MAX_BYTES].
+#[derive(Debug, Default)]
+pub struct SubtreeCache<'a> {
+    /// Expected types of relationship fields. Only values of these exact types are cached.
+    types: Vec<&'a Type>,
+    state: RefCell<SubtreeCacheState>,
+}
+
+/// Upper bound on the size of values retained by a [SubtreeCache]
+pub const SUBTREE_CACHE_MAX_BYTES: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+struct SubtreeCacheState {
+    /// Hashes of values that have been seen once, with the index of their type
+    seen: HashSet<u64>,
+    serialized: Vec<HashMap<Box<[u8]>, Box<RawValue>>>,
+    retained_bytes: usize,
+}
+
+impl<'a> SubtreeCache<'a> {
+    pub fn new(types: Vec<&'a Type>) -> Self {
+        let state = SubtreeCacheState {
+            serialized: types.iter().map(|_| HashMap::new()).collect(),
+            ..Default::default()
+        };
+        SubtreeCache {
+            types,
+            state: RefCell::new(state),
+        }
+    }
+
+    fn type_index(&self, expected_type: &Type) -> Option<usize> {
+        self.types
+            .iter()
+            .position(|cached_type| std::ptr::eq(*cached_type, expected_type))
+    }
+}
+
+/// Describes the value that failed to serialize, and where it is
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerializationFailure {
+    /// Path from the root value, such as `$.tracks[2].name`
+    pub path: String,
+    pub expected_type: String,
+    /// Debug rendering of the value, cut off if it is long
+    pub value: String,
+}
+
+/// Path from the root value to the value being serialized, for error reporting. Each element
+/// borrows its parent from the stack frame that serializes the parent value.
+#[derive(Clone, Copy, Debug)]
+enum FieldPath<'a> {
+    Root,
+    Field(&'a FieldPath<'a>, &'a str),
+    Index(&'a FieldPath<'a>, usize),
+}
+
+impl Display for FieldPath<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldPath::Root => write!(f, "$"),
+            FieldPath::Field(parent, name) => write!(f, "{parent}.{name}"),
+            FieldPath::Index(parent, index) => write!(f, "{parent}[{index}]"),
+        }
+    }
+}
+
+impl<'a> RawBsonToJson<'a> {
+    pub fn new(
+        options: ConfigurationSerializationOptions,
+        expected_type: &'a Type,
+        value: RawBsonRef<'a>,
+    ) -> Self {
+        RawBsonToJson {
+            options,
+            expected_type,
+            value,
+            path: FieldPath::Root,
+            depth: 0,
+            failure: None,
+            object_id_formats: None,
+            subtree_cache: None,
+        }
+    }
+
+    /// Applies `objectIdFormat` settings of object type fields. A field setting applies to the
+    /// field value, and to values nested in it unless a nested field has its own setting.
+    pub fn with_object_id_formats(self, object_id_formats: &'a ObjectIdFormats) -> Self {
+        RawBsonToJson {
+            object_id_formats: Some(object_id_formats),
+            ..self
+        }
+    }
+
+    /// Serializes values of relationship fields once for each distinct value. See
+    /// [SubtreeCache].
+    pub fn with_subtree_cache(self, subtree_cache: &'a SubtreeCache<'a>) -> Self {
+        RawBsonToJson {
+            subtree_cache: Some(subtree_cache),
+            ..self
+        }
+    }
+
+    /// Records the path, expected type, and value of the value that fails to serialize, if
+    /// serialization fails. Paths start with `$`, as in `$.tracks[2].name`.
+    pub fn recording_failure(self, failure: &'a RefCell<Option<SerializationFailure>>) -> Self {
+        RawBsonToJson {
+            failure: Some(failure),
+            ..self
+        }
+    }
+
+    fn with(&self, expected_type: &'a Type, value: RawBsonRef<'a>) -> Self {
+        RawBsonToJson {
+            expected_type,
+            value,
+            ..*self
+        }
+    }
+
+    fn child<'b>(
+        &self,
+        path: &'b FieldPath<'b>,
+        expected_type: &'b Type,
+        value: RawBsonRef<'b>,
+    ) -> RawBsonToJson<'b> {
+        RawBsonToJson {
+            options: self.options,
+            expected_type,
+            value,
+            path: *path,
+            depth: self.depth + 1,
+            failure: self.failure,
+            object_id_formats: self.object_id_formats,
+            subtree_cache: self.subtree_cache,
+        }
+    }
+
+    /// If this value is the value of a relationship field that has been seen before, returns its
+    /// JSON from the subtree cache, serializing it first if it is not cached yet. Returns `None`
+    /// if the value should be serialized in place.
+    fn memoized_json<E: serde::ser::Error>(&self) -> Result<Option<Box<RawValue>>, E> {
+        let (Some(cache), RawBsonRef::Document(doc)) = (self.subtree_cache, self.value) else {
+            return Ok(None);
+        };
+        let Some(index) = cache.type_index(self.expected_type) else {
+            return Ok(None);
+        };
+        {
+            let mut state = cache.state.borrow_mut();
+            if let Some(json) = state.serialized[index].get(doc.as_bytes()) {
+                return Ok(Some(json.clone()));
+            }
+            if state.retained_bytes >= SUBTREE_CACHE_MAX_BYTES {
+                return Ok(None);
+            }
+            let mut hasher = DefaultHasher::new();
+            (index, doc.as_bytes()).hash(&mut hasher);
+            if state.seen.insert(hasher.finish()) {
+                return Ok(None);
+            }
+        }
+        let json = serde_json::value::to_raw_value(self).map_err(E::custom)?;
+        let mut state = cache.state.borrow_mut();
+        state.retained_bytes += doc.as_bytes().len() + json.get().len();
+        state.serialized[index].insert(doc.as_bytes().into(), json.clone());
+        Ok(Some(json))
+    }
+
+    /// Produces a serialization error, and records the path where it occurred. Errors propagate
+    /// through the serialization of each enclosing value so only the innermost path is kept.
+    fn error<E: serde::ser::Error>(&self, path: &FieldPath<'_>, err: impl Display) -> E {
+        if let Some(failure) = self.failure {
+            failure
+                .borrow_mut()
+                .get_or_insert_with(|| SerializationFailure {
+                    path: path.to_string(),
+                    expected_type: describe_type(self.expected_type),
+                    value: to_bson(self.value)
+                        .map(|value| describe_value(&value))
+                        .unwrap_or_else(|_| "<malformed BSON>".to_owned()),
+                });
+        }
+        E::custom(err)
+    }
+
+    /// Checks that a document or array may be nested at the current depth
+    fn check_depth(&self) -> Result<(), BsonToJsonError> {
+        if self.depth >= self.options.max_nesting_depth {
+            Err(self.nesting_too_deep())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn nesting_too_deep(&self) -> BsonToJsonError {
+        BsonToJsonError::NestingTooDeep {
+            path: self.path.to_string(),
+            max_depth: self.options.max_nesting_depth,
+        }
+    }
+}
+
+impl Serialize for RawBsonToJson<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.expected_type {
+            Type::Scalar(MongoScalarType::ExtendedJSON) => {
+                // Extended JSON values are converted recursively without a type to bound the
+                // recursion so we check the depth of the value before converting it.
+                let remaining_levels = self.options.max_nesting_depth.saturating_sub(self.depth);
+                if exceeds_nesting_depth(self.value, remaining_levels) {
+                    return Err(self.error::<S::Error>(&self.path, self.nesting_too_deep()));
+                }
+                let value =
+                    to_bson(self.value).map_err(|err| self.error::<S::Error>(&self.path, err))?;
+                extended_json(self.options, value).serialize(serializer)
+            }
+            Type::Scalar(MongoScalarType::Bson(scalar_type)) => {
+                self.serialize_scalar(*scalar_type, serializer)
+            }
+            Type::Object(object_type) => match self.value {
+                RawBsonRef::Document(doc) => self.serialize_object(object_type, doc, serializer),
+                value => {
+                    Err(self
+                        .error::<S::Error>(&self.path, type_mismatch(self.expected_type, value)))
+                }
+            },
+            Type::ArrayOf(element_type) => match self.value {
+                RawBsonRef::Array(values) => self.serialize_array(element_type, values, serializer),
+                value => {
+                    Err(self
+                        .error::<S::Error>(&self.path, type_mismatch(self.expected_type, value)))
+                }
+            },
+            Type::Nullable(underlying_type) => match self.value {
+                RawBsonRef::Null => serializer.serialize_unit(),
+                value => self.with(underlying_type, value).serialize(serializer),
+            },
+        }
+    }
+}
+
+impl RawBsonToJson<'_> {
+    // Handles common scalar types directly. Other scalar types are converted to owned BSON values
+    // to reuse the conversions in `bson_to_json`.
+    fn serialize_scalar<S>(
+        &self,
+        expected_type: BsonScalarType,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match (expected_type, self.value) {
+            (
+                BsonScalarType::Null | BsonScalarType::Undefined,
+                RawBsonRef::Null | RawBsonRef::Undefined,
+            ) => serializer.serialize_unit(),
+            (BsonScalarType::Bool, RawBsonRef::Boolean(b)) => serializer.serialize_bool(b),
+            (BsonScalarType::Int | BsonScalarType::Double, RawBsonRef::Int32(n)) => {
+                serializer.serialize_i32(n)
+            }
+            (BsonScalarType::Int | BsonScalarType::Double, RawBsonRef::Double(n))
+                if n.is_finite() =>
+            {
+                serializer.serialize_f64(n)
+            }
+            (BsonScalarType::Long, RawBsonRef::Int64(n)) => serializer.collect_str(&n),
+            (
+                BsonScalarType::String | BsonScalarType::Symbol,
+                RawBsonRef::String(s) | RawBsonRef::Symbol(s),
+            ) => serializer.serialize_str(s),
+            (BsonScalarType::Javascript, RawBsonRef::JavaScriptCode(s)) => {
+                serializer.serialize_str(s)
+            }
+            (BsonScalarType::Regex, RawBsonRef::RegularExpression(regex)) => {
+                json_formats::Regex::from(regex).serialize(serializer)
+            }
+            (BsonScalarType::ObjectId, RawBsonRef::ObjectId(oid)) => {
+                match self.options.object_id_format {
+                    ObjectIdFormat::HexString => serializer.serialize_str(&oid.to_hex()),
+                    ObjectIdFormat::ExtendedJson => {
+                        let mut map = serializer.serialize_map(Some(1))?;
+                        map.serialize_entry("$oid", &oid.to_hex())?;
+                        map.end()
+                    }
+                }
+            }
+            (_, value) => {
+                let value =
+                    to_bson(value).map_err(|err| self.error::<S::Error>(&self.path, err))?;
+                bson_to_json(self.options, self.expected_type, value)
+                    .map_err(|err| self.error::<S::Error>(&self.path, err))?
+                    .serialize(serializer)
+            }
+        }
+    }
+
+    fn serialize_object<S>(
+        &self,
+        object_type: &ObjectType,
+        doc: &RawDocument,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // Index the document once instead of scanning it for each field of the object type
+        let doc_fields: HashMap<&str, RawBsonRef<'_>> =
+            doc.into_iter()
+                .collect::<Result<_, _>>()
+                .map_err(|err| self.error::<S::Error>(&self.path, err))?;
+
+        self.check_depth()
+            .map_err(|err| self.error::<S::Error>(&self.path, err))?;
+        let mut map = serializer.serialize_map(None)?;
+        for (field_name, field_type) in object_type.named_fields() {
+            match doc_fields.get(field_name.as_str()) {
+                Some(value) => {
+                    let path = FieldPath::Field(&self.path, field_name.as_str());
+                    let mut child = self.child(&path, field_type, *value);
+                    if let Some(format) =
+                        field_object_id_format(self.object_id_formats, object_type, field_name)
+                    {
+                        child.options.object_id_format = format;
+                    }
+                    match child.memoized_json::<S::Error>()? {
+                        Some(json) => map.serialize_entry(field_name.as_str(), &json)?,
+                        None => map.serialize_entry(field_name.as_str(), &child)?,
+                    }
+                }
+                None if is_nullable(field_type) => (),
+                None => Err(self.error::<S::Error>(
+                    &FieldPath::Field(&self.path, field_name.as_str()),
+                    BsonToJsonError::MissingObjectField(
+                        Type::Object(object_type.clone()),
+                        field_name.to_string(),
+                    ),
+                ))?,
+            }
+        }
+        map.end()
+    }
+
+    fn serialize_array<S>(
+        &self,
+        element_type: &Type,
+        values: &RawArray,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.check_depth()
+            .map_err(|err| self.error::<S::Error>(&self.path, err))?;
+        let mut seq = serializer.serialize_seq(None)?;
+        for (index, value) in values.into_iter().enumerate() {
+            let value = value.map_err(|err| self.error::<S::Error>(&self.path, err))?;
+            let path = FieldPath::Index(&self.path, index);
+            seq.serialize_element(&self.child(&path, element_type, value))?;
+        }
+        seq.end()
+    }
+}
+
+fn field_object_id_format(
+    object_id_formats: Option<&ObjectIdFormats>,
+    object_type: &ObjectType,
+    field_name: &ndc_models::FieldName,
+) -> Option<ObjectIdFormat> {
+    object_id_formats?
+        .get(object_type.name.as_ref()?)?
+        .get(field_name)
+        .copied()
+}
+
+/// Checks whether a value contains documents or arrays nested more than `max_levels` deep. The
+/// check stops descending when it reaches the limit so that it cannot overflow the stack itself.
+/// Malformed elements are skipped here, and are reported when the value is converted.
+fn exceeds_nesting_depth(value: RawBsonRef<'_>, max_levels: usize) -> bool {
+    let mut children: Box<dyn Iterator<Item = RawBsonRef<'_>>> = match value {
+        RawBsonRef::Document(doc) => Box::new(doc.into_iter().flatten().map(|(_, value)| value)),
+        RawBsonRef::Array(values) => Box::new(values.into_iter().flatten()),
+        _ => return false,
+    };
+    max_levels == 0 || children.any(|child| exceeds_nesting_depth(child, max_levels - 1))
+}
+
+fn to_bson(value: RawBsonRef<'_>) -> Result<Bson, BsonToJsonError> {
+    Ok(Bson::try_from(value)?)
+}
+
+fn type_mismatch(expected_type: &Type, value: RawBsonRef<'_>) -> BsonToJsonError {
+    match to_bson(value) {
+        Ok(value) => BsonToJsonError::TypeMismatch(expected_type.clone(), value),
+        Err(err) => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::ObjectIdFormat, ConfigurationSerializationOptions, MongoScalarType, ObjectIdFormats,
+    };
+    use mongodb::bson::{self, RawBsonRef, RawDocumentBuf};
+    use mongodb_support::{BsonScalarType, ExtendedJsonMode};
+    use pretty_assertions::assert_eq;
+
+    use crate::mongo_query_plan::{ObjectType, Type};
+
+    use super::{super::bson_to_json, RawBsonToJson};
+
+    fn raw_to_json(
+        options: ConfigurationSerializationOptions,
+        expected_type: &Type,
+        doc: &bson::Document,
+    ) -> anyhow::Result<serde_json::Value> {
+        let raw = RawDocumentBuf::from_document(doc)?;
+        let json = serde_json::to_value(RawBsonToJson::new(
+            options,
+            expected_type,
+            RawBsonRef::Document(&raw),
+        ))?;
+        Ok(json)
+    }
+
+    fn scalar(t: BsonScalarType) -> Type {
+        Type::Scalar(MongoScalarType::Bson(t))
+    }
+
+    #[test]
+    fn matches_bson_to_json_output() -> anyhow::Result<()> {
+        let expected_type = Type::Object(ObjectType {
+            name: None,
+            fields: [
+                ("_id".into(), scalar(BsonScalarType::ObjectId)),
+                ("title".into(), scalar(BsonScalarType::String)),
+                ("year".into(), scalar(BsonScalarType::Int)),
+                ("votes".into(), scalar(BsonScalarType::Long)),
+                ("rating".into(), scalar(BsonScalarType::Double)),
+                ("released".into(), scalar(BsonScalarType::Date)),
+                (
+                    "cast".into(),
+                    Type::ArrayOf(Box::new(scalar(BsonScalarType::String))),
+                ),
+                (
+                    "tomatoes".into(),
+                    Type::Nullable(Box::new(Type::Scalar(MongoScalarType::ExtendedJSON))),
+                ),
+                (
+                    "plot".into(),
+                    Type::Nullable(Box::new(scalar(BsonScalarType::String))),
+                ),
+            ]
+            .into(),
+        });
+        let doc = bson::doc! {
+            "_id": bson::oid::ObjectId::parse_str("573a1390f29313caabcd446f")?,
+            "title": "The Great Train Robbery",
+            "year": 1903,
+            "votes": 9847_i64,
+            "rating": 7.4,
+            "released": bson::DateTime::from_millis(-2085177600000),
+            "cast": ["A.C. Abadie", "Gilbert M. 'Broncho Billy' Anderson"],
+            "tomatoes": { "viewer": { "rating": 3.7, "numReviews": 2559_i64 } },
+            "extra": "not selected",
+        };
+        for extended_json_mode in [ExtendedJsonMode::Canonical, ExtendedJsonMode::Relaxed] {
+            let options = ConfigurationSerializationOptions {
+                extended_json_mode,
+                ..Default::default()
+            };
+            let expected = bson_to_json(options, &expected_type, doc.clone().into())?;
+            let actual = raw_to_json(options, &expected_type, &doc)?;
+            assert_eq!(actual, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reports_path_to_value_that_is_nested_too_deeply() -> anyhow::Result<()> {
+        let options = ConfigurationSerializationOptions {
+            max_nesting_depth: 5,
+            ..Default::default()
+        };
+        let mut nested = bson::doc! { "leaf": 1 };
+        for _ in 0..10 {
+            nested = bson::doc! { "child": nested };
+        }
+
+        let extjson_type = Type::Object(ObjectType {
+            name: None,
+            fields: [(
+                "items".into(),
+                Type::ArrayOf(Box::new(Type::Scalar(MongoScalarType::ExtendedJSON))),
+            )]
+            .into(),
+        });
+        let error = raw_to_json(
+            options,
+            &extjson_type,
+            &bson::doc! { "items": [{}, nested.clone()] },
+        )
+        .expect_err("expected nesting depth error");
+        assert_eq!(
+            error.to_string(),
+            "value at $.items[1] is nested more than 5 levels deep"
+        );
+
+        // Values within the depth limit are serialized normally
+        let shallow = raw_to_json(
+            options,
+            &extjson_type,
+            &bson::doc! { "items": [{ "a": { "b": 1 } }] },
+        )?;
+        assert_eq!(
+            shallow,
+            serde_json::json!({ "items": [{ "a": { "b": { "$numberInt": "1" } } }] })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reports_missing_non_nullable_field() -> anyhow::Result<()> {
+        let expected_type = Type::Object(ObjectType {
+            name: None,
+            fields: [("title".into(), scalar(BsonScalarType::String))].into(),
+        });
+        let result = raw_to_json(Default::default(), &expected_type, &bson::doc! {});
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn applies_object_id_format_with_field_overrides() -> anyhow::Result<()> {
+        let oid = bson::oid::ObjectId::parse_str("5a9427648b0beebeb69579cc")?;
+        let expected_type = Type::Object(ObjectType {
+            name: Some("comments".into()),
+            fields: [
+                ("_id".into(), scalar(BsonScalarType::ObjectId)),
+                (
+                    "movie_ids".into(),
+                    Type::ArrayOf(Box::new(scalar(BsonScalarType::ObjectId))),
+                ),
+            ]
+            .into(),
+        });
+        let doc = bson::doc! { "_id": oid, "movie_ids": [oid] };
+        let options = ConfigurationSerializationOptions {
+            object_id_format: ObjectIdFormat::ExtendedJson,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            raw_to_json(options, &expected_type, &doc)?,
+            serde_json::json!({
+                "_id": { "$oid": "5a9427648b0beebeb69579cc" },
+                "movie_ids": [{ "$oid": "5a9427648b0beebeb69579cc" }],
+            })
+        );
+
+        let object_id_formats: ObjectIdFormats = [(
+            "comments".into(),
+            [("movie_ids".into(), ObjectIdFormat::HexString)].into(),
+        )]
+        .into();
+        let raw = RawDocumentBuf::from_document(&doc)?;
+        let json = serde_json::to_value(
+            RawBsonToJson::new(options, &expected_type, RawBsonRef::Document(&raw))
+                .with_object_id_formats(&object_id_formats),
+        )?;
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "_id": { "$oid": "5a9427648b0beebeb69579cc" },
+                "movie_ids": ["5a9427648b0beebeb69579cc"],
+            })
+        );
+        Ok(())
+    }
+}
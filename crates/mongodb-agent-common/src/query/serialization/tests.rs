@@ -1,4 +1,4 @@
-use configuration::MongoScalarType;
+use configuration::{ConfigurationSerializationOptions, MongoScalarType};
 use mongodb::bson::Bson;
 use mongodb_cli_plugin::type_from_bson;
 use mongodb_support::{BsonScalarType, ExtendedJsonMode};
@@ -21,7 +21,11 @@ proptest! {
 
         // Test using Canonical mode because Relaxed mode loses some information, and so does not
         // round-trip precisely.
-        let json = bson_to_json(ExtendedJsonMode::Canonical, &inferred_type, bson.clone()).map_err(|e| error_context("error converting bson to json", e.to_string()))?;
+        let options = ConfigurationSerializationOptions {
+            extended_json_mode: ExtendedJsonMode::Canonical,
+            ..Default::default()
+        };
+        let json = bson_to_json(options, &inferred_type, bson.clone()).map_err(|e| error_context("error converting bson to json", e.to_string()))?;
         let actual = json_to_bson(&inferred_type, json.clone()).map_err(|e| error_context("error converting json to bson", e.to_string()))?;
         prop_assert!(custom_eq(&actual, &bson),
             "`(left == right)`\nleft: `{:?}`\nright: `{:?}`\ninferred type: {:?}\nobject types: {:?}\njson_representation: {}",
@@ -39,7 +43,7 @@ proptest! {
     fn converts_datetime_from_bson_to_json_and_back(d in arb_datetime()) {
         let t = plan::Type::Scalar(MongoScalarType::Bson(BsonScalarType::Date));
         let bson = Bson::DateTime(d);
-        let json = bson_to_json(ExtendedJsonMode::Canonical, &t, bson.clone())?;
+        let json = bson_to_json(Default::default(), &t, bson.clone())?;
         let actual = json_to_bson(&t, json.clone())?;
         prop_assert_eq!(actual, bson, "json representation: {}", json)
     }
@@ -0,0 +1,178 @@
+//! Excludes soft-deleted documents from queries against collections that declare a soft delete
+//! field. See [configuration::schema::Collection::soft_delete_field].
+
+use mongodb::bson::{doc, Document};
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::{MongoConfiguration, QueryPlan},
+};
+
+use super::{query_level::QueryLevel, CollectionArguments};
+
+/// Filter that matches documents that have not been soft-deleted, or `None` if the queried
+/// collection does not have a soft delete field. The `includeDeleted` argument only applies to the
+/// top-level collection of a request; related collections always exclude soft-deleted documents.
+pub fn soft_delete_filter(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+    query_level: QueryLevel,
+) -> Result<Option<Document>, MongoAgentError> {
+    let Some(filter) = collection_soft_delete_filter(config, &query_plan.collection) else {
+        return Ok(None);
+    };
+    if query_level == QueryLevel::Top
+        && CollectionArguments::for_request(config, query_plan)?.include_deleted
+    {
+        return Ok(None);
+    }
+    Ok(Some(filter))
+}
+
+/// Filter that matches documents of the given collection that have not been soft-deleted, or
+/// `None` if the collection does not have a soft delete field
+pub fn collection_soft_delete_filter(
+    config: &MongoConfiguration,
+    collection: &ndc_models::CollectionName,
+) -> Option<Document> {
+    let field = config
+        .collection_policies(collection)?
+        .soft_delete_field
+        .as_ref()?;
+    Some(doc! { field.as_str(): null })
+}
+
+/// Combines a filter with the soft delete filter for the query, if there is one
+pub fn with_soft_delete_filter(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+    filter: Document,
+) -> Result<Document, MongoAgentError> {
+    let filter = match soft_delete_filter(config, query_plan, QueryLevel::Top)? {
+        Some(soft_delete) if filter.is_empty() => soft_delete,
+        Some(soft_delete) => doc! { "$and": [filter, soft_delete] },
+        None => filter,
+    };
+    Ok(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, ObjectField, ObjectType, Type},
+        serialized::Schema,
+        Configuration,
+    };
+    use mongodb::bson::bson;
+    use mongodb_support::BsonScalarType;
+    use ndc_models::{Argument, QueryResponse};
+    use ndc_test_helpers::{field, query, query_request, row_set};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::{
+        mongo_query_plan::MongoConfiguration,
+        mongodb::test_helpers::mock_collection_aggregate_response_for_pipeline,
+        query::execute_query_request,
+    };
+
+    fn posts_config() -> anyhow::Result<MongoConfiguration> {
+        let schema = Schema {
+            collections: [(
+                "posts".into(),
+                Collection {
+                    r#type: "posts".into(),
+                    description: None,
+                    soft_delete_field: Some("deleted_at".into()),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "posts".into(),
+                ObjectType {
+                    fields: [
+                        ObjectField::new("title", Type::Scalar(BsonScalarType::String)),
+                        ObjectField::new(
+                            "deleted_at",
+                            Type::Nullable(Box::new(Type::Scalar(BsonScalarType::Date))),
+                        ),
+                    ]
+                    .into_iter()
+                    .map(|(name, field)| (name.into(), field))
+                    .collect(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        Ok(MongoConfiguration(Configuration::from_schema(schema)?))
+    }
+
+    #[tokio::test]
+    async fn excludes_soft_deleted_documents() -> anyhow::Result<()> {
+        let query_request = query_request()
+            .collection("posts")
+            .query(query().fields([field!("title")]))
+            .into();
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "posts",
+            bson!([
+                { "$match": { "deleted_at": null } },
+                { "$replaceWith": { "title": { "$ifNull": ["$title", null] } } },
+            ]),
+            bson!([{ "title": "Hello" }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &posts_config()?,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set().rows([[("title", "Hello")]]).into_response()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn includes_soft_deleted_documents_when_requested() -> anyhow::Result<()> {
+        let query_request = query_request()
+            .collection("posts")
+            .query(query().fields([field!("title")]))
+            .arguments([("includeDeleted", Argument::Literal { value: json!(true) })])
+            .into();
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "posts",
+            bson!([
+                { "$replaceWith": { "title": { "$ifNull": ["$title", null] } } },
+            ]),
+            bson!([{ "title": "Hello" }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &posts_config()?,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set().rows([[("title", "Hello")]]).into_response()
+        );
+        Ok(())
+    }
+}
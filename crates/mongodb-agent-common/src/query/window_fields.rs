@@ -0,0 +1,237 @@
+//! Computes window fields for collections that declare them. See
+//! [configuration::schema::Collection::window_fields].
+
+use std::collections::BTreeMap;
+
+use configuration::schema::{DefaultOrderByElement, WindowField};
+use mongodb::bson::{doc, Bson, Document};
+use ndc_models::OrderDirection;
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::{MongoConfiguration, QueryPlan},
+    mongodb::Stage,
+};
+
+/// Window fields declared by the queried collection, if there are any
+pub fn window_fields_for_query<'a>(
+    config: &'a MongoConfiguration,
+    query_plan: &QueryPlan,
+) -> Option<&'a BTreeMap<ndc_models::FieldName, WindowField>> {
+    config
+        .collection_policies(&query_plan.collection)
+        .map(|policies| &policies.window_fields)
+        .filter(|window_fields| !window_fields.is_empty())
+}
+
+/// `$setWindowFields` stages for the queried collection. A `$setWindowFields` stage has a single
+/// partition and sort order so window fields are grouped into one stage for each distinct
+/// combination.
+pub fn window_fields_stages(
+    config: &MongoConfiguration,
+    query_plan: &QueryPlan,
+) -> Result<Vec<Stage>, MongoAgentError> {
+    let Some(window_fields) = window_fields_for_query(config, query_plan) else {
+        return Ok(vec![]);
+    };
+    let mut groups: Vec<(&WindowField, Document)> = Vec::new();
+    for (name, window_field) in window_fields {
+        let output = window_output(window_field)?;
+        let group = groups.iter_mut().find(|(first, _)| {
+            first.partition_by == window_field.partition_by && first.sort_by == window_field.sort_by
+        });
+        match group {
+            Some((_, outputs)) => {
+                outputs.insert(name.as_str(), output);
+            }
+            None => groups.push((window_field, doc! { name.as_str(): output })),
+        }
+    }
+    Ok(groups
+        .into_iter()
+        .map(|(window_field, output)| {
+            let mut spec = Document::new();
+            if let Some(partition_by) = partition_by(&window_field.partition_by) {
+                spec.insert("partitionBy", partition_by);
+            }
+            if !window_field.sort_by.is_empty() {
+                spec.insert("sortBy", sort_by(&window_field.sort_by));
+            }
+            spec.insert("output", output);
+            Stage::Other(doc! { "$setWindowFields": spec })
+        })
+        .collect())
+}
+
+fn window_output(window_field: &WindowField) -> Result<Document, MongoAgentError> {
+    let to_document = |value: &serde_json::Value| -> Result<Document, MongoAgentError> {
+        match Bson::try_from(value.clone()) {
+            Ok(Bson::Document(document)) => Ok(document),
+            Ok(_) => Err(MongoAgentError::AdHoc(anyhow::anyhow!(
+                "window operators and windows must be objects"
+            ))),
+            Err(err) => Err(MongoAgentError::AdHoc(err.into())),
+        }
+    };
+    let mut output = to_document(&window_field.operator)?;
+    if let Some(window) = &window_field.window {
+        output.insert("window", to_document(window)?);
+    }
+    Ok(output)
+}
+
+/// A single field is referenced directly. Multiple fields are combined into a document.
+fn partition_by(fields: &[ndc_models::FieldName]) -> Option<Bson> {
+    match fields {
+        [] => None,
+        [field] => Some(Bson::String(format!("${field}"))),
+        fields => Some(Bson::Document(
+            fields
+                .iter()
+                .map(|field| (field.to_string(), Bson::String(format!("${field}"))))
+                .collect(),
+        )),
+    }
+}
+
+fn sort_by(elements: &[DefaultOrderByElement]) -> Document {
+    elements
+        .iter()
+        .map(|element| {
+            let direction = match element.direction {
+                OrderDirection::Asc => 1,
+                OrderDirection::Desc => -1,
+            };
+            (element.column.to_string(), Bson::Int32(direction))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::{
+        schema::{Collection, DefaultOrderByElement, ObjectField, ObjectType, Type, WindowField},
+        serialized::Schema,
+        Configuration,
+    };
+    use mongodb::bson::bson;
+    use mongodb_support::BsonScalarType;
+    use ndc_models::{OrderDirection, QueryResponse};
+    use ndc_test_helpers::{field, query, query_request, row_set};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::{
+        mongo_query_plan::MongoConfiguration,
+        mongodb::test_helpers::mock_collection_aggregate_response_for_pipeline,
+        query::execute_query_request,
+    };
+
+    #[tokio::test]
+    async fn computes_window_fields_that_share_partition_and_order_in_one_stage(
+    ) -> anyhow::Result<()> {
+        let sort_by = vec![DefaultOrderByElement {
+            column: "date".into(),
+            direction: OrderDirection::Asc,
+        }];
+        let schema = Schema {
+            collections: [(
+                "sales".into(),
+                Collection {
+                    r#type: "sales".into(),
+                    description: None,
+                    window_fields: [
+                        (
+                            "running_total".into(),
+                            WindowField {
+                                r#type: Type::Scalar(BsonScalarType::Double),
+                                description: None,
+                                partition_by: vec!["store".into()],
+                                sort_by: sort_by.clone(),
+                                operator: json!({ "$sum": "$amount" }),
+                                window: Some(json!({ "documents": ["unbounded", "current"] })),
+                            },
+                        ),
+                        (
+                            "rank".into(),
+                            WindowField {
+                                r#type: Type::Scalar(BsonScalarType::Int),
+                                description: None,
+                                partition_by: vec!["store".into()],
+                                sort_by,
+                                operator: json!({ "$rank": {} }),
+                                window: None,
+                            },
+                        ),
+                    ]
+                    .into(),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            object_types: [(
+                "sales".into(),
+                ObjectType {
+                    fields: [
+                        ObjectField::new("store", Type::Scalar(BsonScalarType::String)),
+                        ObjectField::new("date", Type::Scalar(BsonScalarType::Date)),
+                        ObjectField::new("amount", Type::Scalar(BsonScalarType::Double)),
+                    ]
+                    .into_iter()
+                    .map(|(name, field)| (name.into(), field))
+                    .collect(),
+                    description: None,
+                    extends: Default::default(),
+                },
+            )]
+            .into(),
+        };
+        let config = MongoConfiguration(Configuration::from_schema(schema)?);
+
+        let query_request = query_request()
+            .collection("sales")
+            .query(query().fields([field!("running_total")]))
+            .into();
+
+        let db = mock_collection_aggregate_response_for_pipeline(
+            "sales",
+            bson!([
+                {
+                    "$setWindowFields": {
+                        "partitionBy": "$store",
+                        "sortBy": { "date": 1 },
+                        "output": {
+                            "rank": { "$rank": {} },
+                            "running_total": {
+                                "$sum": "$amount",
+                                "window": { "documents": ["unbounded", "current"] },
+                            },
+                        },
+                    }
+                },
+                {
+                    "$replaceWith": {
+                        "running_total": { "$ifNull": ["$running_total", null] },
+                    }
+                },
+            ]),
+            bson!([{ "running_total": 12.5 }]),
+        );
+
+        let result = execute_query_request(
+            db,
+            &config,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            query_request,
+        )
+        .await?;
+        let result: QueryResponse = serde_json::from_slice(&result)?;
+        assert_eq!(
+            result,
+            row_set().rows([[("running_total", 12.5)]]).into_response()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+//! Export of anonymized query shape records for analysis of which schema features are used. This
+//! is enabled by the `queryLog` configuration option. Records are sent over a channel to
+//! a background task that writes them to the configured sink so that query handling does not wait
+//! on log output.
+
+use std::{collections::BTreeSet, time::Duration};
+
+use configuration::{ConfigurationQueryLogOptions, QueryLogSink};
+use mongodb::{bson, Database};
+use ndc_models::{Expression, Field, Query, QueryRequest};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt as _},
+    sync::mpsc,
+};
+
+use crate::interface_types::MongoAgentError;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryShape {
+    pub collection: String,
+
+    /// Names of predicate operators used anywhere in the query, including `and`, `or`, `not`,
+    /// and `exists`
+    pub operators: BTreeSet<String>,
+
+    /// Number of levels of nested relationship fields. A query with no relationships has depth 0.
+    pub relationship_depth: usize,
+
+    pub has_aggregates: bool,
+    pub has_variables: bool,
+}
+
+impl QueryShape {
+    pub fn for_request(request: &QueryRequest) -> Self {
+        let mut operators = BTreeSet::new();
+        let relationship_depth = query_shape(&request.query, &mut operators);
+        QueryShape {
+            collection: request.collection.to_string(),
+            operators,
+            relationship_depth,
+            has_aggregates: request
+                .query
+                .aggregates
+                .as_ref()
+                .is_some_and(|aggregates| !aggregates.is_empty()),
+            has_variables: request.variables.is_some(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryLogRecord {
+    #[serde(with = "time::serde::rfc3339")]
+    timestamp: OffsetDateTime,
+    #[serde(flatten)]
+    shape: QueryShape,
+    duration_ms: u64,
+    succeeded: bool,
+}
+
+/// Handle for submitting records to the background exporter. Cloning the handle is cheap, and the
+/// exporter stops when every handle has been dropped.
+#[derive(Clone, Debug)]
+pub struct QueryLogger {
+    sender: mpsc::UnboundedSender<QueryLogRecord>,
+}
+
+impl QueryLogger {
+    /// Starts the background exporter. The database is used only by the collection sink.
+    pub fn spawn(options: &ConfigurationQueryLogOptions, database: Database) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let sink = options.sink.clone();
+        tokio::spawn(async move {
+            if let Err(err) = export(sink, database, receiver).await {
+                tracing::error!(error = %err, "query log exporter stopped");
+            }
+        });
+        QueryLogger { sender }
+    }
+
+    pub fn record(&self, shape: QueryShape, duration: Duration, succeeded: bool) {
+        let record = QueryLogRecord {
+            timestamp: OffsetDateTime::now_utc(),
+            shape,
+            duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+            succeeded,
+        };
+        // An error here means that the exporter has stopped, and the reason has already been
+        // logged.
+        let _ = self.sender.send(record);
+    }
+}
+
+async fn export(
+    sink: QueryLogSink,
+    database: Database,
+    mut receiver: mpsc::UnboundedReceiver<QueryLogRecord>,
+) -> Result<(), MongoAgentError> {
+    match sink {
+        QueryLogSink::Stdout => write_lines(tokio::io::stdout(), receiver).await,
+        QueryLogSink::File { path } => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+                .map_err(|err| MongoAgentError::AdHoc(err.into()))?;
+            write_lines(file, receiver).await
+        }
+        QueryLogSink::Collection { name } => {
+            let collection = database.collection::<bson::Document>(&name);
+            while let Some(record) = receiver.recv().await {
+                collection
+                    .insert_one(bson::to_document(&record)?, None)
+                    .await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn write_lines(
+    mut writer: impl AsyncWrite + Unpin,
+    mut receiver: mpsc::UnboundedReceiver<QueryLogRecord>,
+) -> Result<(), MongoAgentError> {
+    while let Some(record) = receiver.recv().await {
+        let mut line =
+            serde_json::to_vec(&record).map_err(|err| MongoAgentError::AdHoc(err.into()))?;
+        line.push(b'\n');
+        writer
+            .write_all(&line)
+            .await
+            .map_err(|err| MongoAgentError::AdHoc(err.into()))?;
+        writer
+            .flush()
+            .await
+            .map_err(|err| MongoAgentError::AdHoc(err.into()))?;
+    }
+    Ok(())
+}
+
+/// Collects operator names from the query, and returns its relationship depth
+fn query_shape(query: &Query, operators: &mut BTreeSet<String>) -> usize {
+    if let Some(predicate) = &query.predicate {
+        expression_operators(predicate, operators);
+    }
+    query
+        .fields
+        .iter()
+        .flat_map(|fields| fields.values())
+        .map(|field| match field {
+            Field::Column { .. } => 0,
+            Field::Relationship { query, .. } => 1 + query_shape(query, operators),
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn expression_operators(expression: &Expression, operators: &mut BTreeSet<String>) {
+    match expression {
+        Expression::And { expressions } => {
+            operators.insert("and".to_owned());
+            for expression in expressions {
+                expression_operators(expression, operators);
+            }
+        }
+        Expression::Or { expressions } => {
+            operators.insert("or".to_owned());
+            for expression in expressions {
+                expression_operators(expression, operators);
+            }
+        }
+        Expression::Not { expression } => {
+            operators.insert("not".to_owned());
+            expression_operators(expression, operators);
+        }
+        Expression::UnaryComparisonOperator { .. } => {
+            operators.insert("is_null".to_owned());
+        }
+        Expression::BinaryComparisonOperator { operator, .. } => {
+            operators.insert(operator.to_string());
+        }
+        Expression::Exists { predicate, .. } => {
+            operators.insert("exists".to_owned());
+            if let Some(predicate) = predicate {
+                expression_operators(predicate, operators);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndc_models::QueryRequest;
+    use ndc_test_helpers::{
+        and, binop, field, not, query, query_request, relation_field, target, value,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::QueryShape;
+
+    #[test]
+    fn records_operators_and_relationship_depth_without_values() {
+        let request: QueryRequest = query_request()
+            .collection("Artist")
+            .query(
+                query()
+                    .fields([relation_field!("albums" => "Albums", query().fields([
+                        relation_field!("tracks" => "Tracks", query().fields([field!("Name")])),
+                    ]))])
+                    .predicate(and([
+                        binop("_eq", target!("Name"), value!("AC/DC")),
+                        not(binop("_gt", target!("ArtistId"), value!(10))),
+                    ])),
+            )
+            .into();
+        assert_eq!(
+            QueryShape::for_request(&request),
+            QueryShape {
+                collection: "Artist".to_owned(),
+                operators: ["_eq", "_gt", "and", "not"].map(ToOwned::to_owned).into(),
+                relationship_depth: 2,
+                has_aggregates: false,
+                has_variables: false,
+            }
+        );
+    }
+}
@@ -15,6 +15,52 @@ use BsonScalarType as S;
 
 lazy_static! {
     pub static ref SCALAR_TYPES: BTreeMap<ndc_models::ScalarTypeName, ScalarType> = scalar_types();
+
+    /// Definitions of aggregation functions that take arguments, by scalar type name. These are
+    /// not declared in the schema under their own names because requests cannot supply arguments
+    /// for aggregation functions. Configuration declares named functions with fixed arguments
+    /// instead.
+    pub static ref PARAMETERIZED_AGGREGATE_FUNCTIONS: BTreeMap<
+        ndc_models::ScalarTypeName,
+        BTreeMap<AggregateFunctionName, AggregateFunctionDefinition>,
+    > = parameterized_aggregate_function_definitions();
+}
+
+fn parameterized_aggregate_function_definitions() -> BTreeMap<
+    ndc_models::ScalarTypeName,
+    BTreeMap<AggregateFunctionName, AggregateFunctionDefinition>,
+> {
+    enum_iterator::all::<BsonScalarType>()
+        .map(|bson_scalar_type| {
+            let functions = parameterized_aggregate_functions(bson_scalar_type)
+                .map(|(function, result_type)| {
+                    let definition = AggregateFunctionDefinition {
+                        result_type: bson_to_named_type(result_type),
+                    };
+                    (function.graphql_name().into(), definition)
+                })
+                .collect();
+            (bson_scalar_type.graphql_name().into(), functions)
+        })
+        .collect()
+}
+
+/// Finds the definition of a built-in aggregation function for the given scalar type. Functions
+/// that take arguments are included if `parameterized` is true.
+pub fn aggregate_function_definition(
+    scalar_type_name: &str,
+    function_name: &AggregateFunctionName,
+    parameterized: bool,
+) -> Option<&'static AggregateFunctionDefinition> {
+    SCALAR_TYPES
+        .get(scalar_type_name)
+        .and_then(|scalar_type| scalar_type.aggregate_functions.get(function_name))
+        .or_else(|| {
+            PARAMETERIZED_AGGREGATE_FUNCTIONS
+                .get(scalar_type_name)
+                .filter(|_| parameterized)
+                .and_then(|functions| functions.get(function_name))
+        })
 }
 
 pub fn scalar_types() -> BTreeMap<ndc_models::ScalarTypeName, ScalarType> {
@@ -30,7 +76,24 @@ fn extended_json_scalar_type() -> (ndc_models::ScalarTypeName, ScalarType) {
         ScalarType {
             representation: Some(TypeRepresentation::JSON),
             aggregate_functions: BTreeMap::new(),
-            comparison_operators: BTreeMap::new(),
+            comparison_operators: [
+                (C::IsSet, bson_to_named_type(S::Bool)),
+                (C::TypeEqual, bson_to_named_type(S::String)),
+                (
+                    C::PathEqual,
+                    Type::Named {
+                        name: mongodb_support::EXTENDED_JSON_TYPE_NAME.into(),
+                    },
+                ),
+            ]
+            .into_iter()
+            .map(|(comparison_fn, argument_type)| {
+                (
+                    comparison_fn.graphql_name().into(),
+                    ComparisonOperatorDefinition::Custom { argument_type },
+                )
+            })
+            .collect(),
         },
     )
 }
@@ -73,15 +136,16 @@ fn bson_comparison_operators(
     bson_scalar_type: BsonScalarType,
 ) -> BTreeMap<ComparisonOperatorName, ComparisonOperatorDefinition> {
     comparison_operators(bson_scalar_type)
-        .map(|(comparison_fn, arg_type)| {
+        .map(|(comparison_fn, arg_type)| (comparison_fn, bson_to_named_type(arg_type)))
+        .chain(modulus_operators(bson_scalar_type))
+        .chain(range_operators(bson_scalar_type))
+        .map(|(comparison_fn, argument_type)| {
             let fn_name = comparison_fn.graphql_name().into();
             match comparison_fn {
                 ComparisonFunction::Equal => (fn_name, ComparisonOperatorDefinition::Equal),
                 _ => (
                     fn_name,
-                    ComparisonOperatorDefinition::Custom {
-                        argument_type: bson_to_named_type(arg_type),
-                    },
+                    ComparisonOperatorDefinition::Custom { argument_type },
                 ),
             }
         })
@@ -98,6 +162,18 @@ fn bson_aggregation_functions(
             };
             (fn_name.graphql_name().into(), aggregation_definition)
         })
+        .chain(iter_if(
+            bson_scalar_type.is_comparable(),
+            [(
+                A::FacetCounts.graphql_name().into(),
+                AggregateFunctionDefinition {
+                    result_type: Type::Named {
+                        name: mongodb_support::EXTENDED_JSON_TYPE_NAME.into(),
+                    },
+                },
+            )]
+            .into_iter(),
+        ))
         .collect()
 }
 
@@ -120,12 +196,47 @@ pub fn aggregate_functions(
         ))
         .chain(iter_if(
             scalar_type.is_numeric(),
-            [A::Avg, A::Sum]
-                .into_iter()
-                .map(move |op| (op, scalar_type)),
+            [
+                (A::Avg, avg_result_type(scalar_type)),
+                (A::Sum, sum_result_type(scalar_type)),
+            ]
+            .into_iter(),
         ))
 }
 
+/// MongoDB computes averages of decimals as decimals, and averages of other numbers as doubles
+pub fn avg_result_type(scalar_type: BsonScalarType) -> BsonScalarType {
+    match scalar_type {
+        S::Decimal => S::Decimal,
+        _ => S::Double,
+    }
+}
+
+/// Sums of 32-bit integers are widened to 64-bit integers so that they do not overflow
+pub fn sum_result_type(scalar_type: BsonScalarType) -> BsonScalarType {
+    match scalar_type {
+        S::Int => S::Long,
+        scalar_type => scalar_type,
+    }
+}
+
+pub fn parameterized_aggregate_functions(
+    scalar_type: BsonScalarType,
+) -> impl Iterator<Item = (AggregationFunction, BsonScalarType)> {
+    iter_if(
+        scalar_type.is_numeric(),
+        [(A::Percentile, S::Double)].into_iter(),
+    )
+    .chain(iter_if(
+        scalar_type == S::String,
+        [(A::Concat, S::String)].into_iter(),
+    ))
+    .chain(iter_if(
+        scalar_type.is_comparable(),
+        [(A::CountWhere, S::Int)].into_iter(),
+    ))
+}
+
 pub fn comparison_operators(
     scalar_type: BsonScalarType,
 ) -> impl Iterator<Item = (ComparisonFunction, BsonScalarType)> {
@@ -133,6 +244,7 @@ pub fn comparison_operators(
         scalar_type.is_comparable(),
         [(C::Equal, scalar_type), (C::NotEqual, scalar_type)].into_iter(),
     )
+    .chain([(C::IsSet, S::Bool)])
     .chain(iter_if(
         scalar_type.is_orderable(),
         [
@@ -145,11 +257,61 @@ pub fn comparison_operators(
         .map(move |op| (op, scalar_type)),
     ))
     .chain(match scalar_type {
-        S::String => Box::new([(C::Regex, S::String), (C::IRegex, S::String)].into_iter()),
+        S::String => Box::new(
+            [
+                C::IEqual,
+                C::Regex,
+                C::IRegex,
+                C::StartsWith,
+                C::IStartsWith,
+                C::EndsWith,
+                C::IEndsWith,
+                C::Contains,
+                C::IContains,
+            ]
+            .into_iter()
+            .map(|op| (op, S::String)),
+        ),
         _ => Box::new(std::iter::empty()) as Box<dyn Iterator<Item = (C, S)>>,
     })
 }
 
+/// Operators for partition-style filtering of integer columns. `_divisible_by` takes a divisor,
+/// and `_mod_eq` takes a `[divisor, remainder]` pair.
+pub fn modulus_operators(
+    scalar_type: BsonScalarType,
+) -> impl Iterator<Item = (ComparisonFunction, Type)> {
+    iter_if(
+        matches!(scalar_type, S::Int | S::Long),
+        [
+            (
+                C::ModEqual,
+                Type::Array {
+                    element_type: Box::new(bson_to_named_type(scalar_type)),
+                },
+            ),
+            (C::DivisibleBy, bson_to_named_type(scalar_type)),
+        ]
+        .into_iter(),
+    )
+}
+
+/// `_between` takes a `[lower, upper]` pair of inclusive bounds
+pub fn range_operators(
+    scalar_type: BsonScalarType,
+) -> impl Iterator<Item = (ComparisonFunction, Type)> {
+    iter_if(
+        scalar_type.is_orderable(),
+        [(
+            C::Between,
+            Type::Array {
+                element_type: Box::new(bson_to_named_type(scalar_type)),
+            },
+        )]
+        .into_iter(),
+    )
+}
+
 /// If `condition` is true returns an iterator with the same items as the given `iter` input.
 /// Otherwise returns an empty iterator.
 fn iter_if<Item>(condition: bool, iter: impl Iterator<Item = Item>) -> impl Iterator<Item = Item> {
@@ -0,0 +1,13 @@
+use mongodb::bson::doc;
+
+use crate::{interface_types::MongoAgentError, state::ConnectorState};
+
+/// Get the version string reported by the MongoDB server, e.g. "7.0.12"
+pub async fn get_server_version(state: &ConnectorState) -> Result<String, MongoAgentError> {
+    let db = state.database();
+    let build_info = db.run_command(doc! { "buildInfo": 1 }, None).await?;
+    let version = build_info
+        .get_str("version")
+        .map_err(|err| MongoAgentError::AdHoc(err.into()))?;
+    Ok(version.to_owned())
+}
@@ -0,0 +1,149 @@
+//! Draining of in-flight requests on shutdown. Request handlers run inside [Shutdown::track].
+//! When shutdown begins new requests are rejected, and requests that are already running get
+//! until the drain timeout to complete. Requests that are still running after that are cancelled
+//! by dropping their futures, which also abandons their MongoDB operations, so that a rolling
+//! deploy is not held up by a slow query.
+
+use std::{
+    future::Future,
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{watch, Notify};
+
+use crate::interface_types::MongoAgentError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Running,
+    Draining,
+    Cancelled,
+}
+
+#[derive(Debug)]
+struct Inner {
+    phase: watch::Sender<Phase>,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+/// Tracks in-flight requests. Cloning is cheap, and clones share state.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            inner: Arc::new(Inner {
+                phase: watch::channel(Phase::Running).0,
+                in_flight: Default::default(),
+                idle: Default::default(),
+            }),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Runs a request handler. Fails with [MongoAgentError::ShuttingDown] if shutdown has begun,
+    /// or if the handler is cancelled because it did not complete before the drain timeout.
+    pub async fn track<F: Future>(&self, operation: F) -> Result<F::Output, MongoAgentError> {
+        // Count the request before checking the phase so that `drain` cannot miss it
+        let _in_flight = InFlight::new(&self.inner);
+        let mut phase = self.inner.phase.subscribe();
+        if *phase.borrow_and_update() != Phase::Running {
+            return Err(MongoAgentError::ShuttingDown);
+        }
+        tokio::select! {
+            output = operation => Ok(output),
+            _ = phase.wait_for(|phase| *phase == Phase::Cancelled) => {
+                Err(MongoAgentError::ShuttingDown)
+            }
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.inner.phase.borrow() != Phase::Running
+    }
+
+    /// Stops accepting requests, and waits for in-flight requests to complete. Requests that are
+    /// still running when the timeout elapses are cancelled.
+    pub async fn drain(&self, timeout: Duration) {
+        self.inner.phase.send_replace(Phase::Draining);
+        let drained = tokio::time::timeout(timeout, async {
+            loop {
+                let mut idle = pin!(self.inner.idle.notified());
+                idle.as_mut().enable();
+                if self.inner.in_flight.load(Ordering::Acquire) == 0 {
+                    break;
+                }
+                idle.await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!(
+                in_flight = self.inner.in_flight.load(Ordering::Acquire),
+                "drain timeout elapsed; cancelling in-flight requests"
+            );
+        }
+        self.inner.phase.send_replace(Phase::Cancelled);
+    }
+}
+
+struct InFlight<'a> {
+    inner: &'a Inner,
+}
+
+impl<'a> InFlight<'a> {
+    fn new(inner: &'a Inner) -> Self {
+        inner.in_flight.fetch_add(1, Ordering::AcqRel);
+        InFlight { inner }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.inner.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future, time::Duration};
+
+    use crate::interface_types::MongoAgentError;
+
+    use super::Shutdown;
+
+    #[tokio::test]
+    async fn rejects_new_requests_and_cancels_requests_that_outlast_the_timeout() {
+        let shutdown = Shutdown::default();
+        assert_eq!(shutdown.track(future::ready(1)).await.ok(), Some(1));
+
+        let pending = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.track(future::pending::<()>()).await }
+        });
+        tokio::task::yield_now().await;
+
+        shutdown.drain(Duration::from_millis(10)).await;
+        assert!(shutdown.is_shutting_down());
+        assert!(matches!(
+            pending.await.unwrap(),
+            Err(MongoAgentError::ShuttingDown)
+        ));
+        assert!(matches!(
+            shutdown.track(future::ready(1)).await,
+            Err(MongoAgentError::ShuttingDown)
+        ));
+    }
+}
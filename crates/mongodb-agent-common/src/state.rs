@@ -1,45 +1,251 @@
-use std::{env, error::Error};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use mongodb::{Client, Database};
+use tokio::task::AbortHandle;
 
-use crate::mongodb_connection::get_mongodb_client;
+use crate::{
+    bulkheads::Bulkheads,
+    collection_stats::CollectionStatsCache,
+    interface_types::MongoAgentError,
+    mongodb::SnapshotSupport,
+    mongodb_connection::get_mongodb_client,
+    monitoring::QueryObservers,
+    post_processing::ResponsePostProcessors,
+    query::{CursorBatchSizes, QueryBatcher},
+    query_log::QueryLogger,
+    shutdown::Shutdown,
+    warm_up::WarmUp,
+};
 
 pub const DATABASE_URI_ENV_VAR: &str = "MONGODB_DATABASE_URI";
 
+const OFFLINE_DATABASE_URI: &str = "mongodb://localhost/offline";
+
 #[derive(Clone, Debug)]
 pub struct ConnectorState {
     client: Client,
 
     /// Name of the database to connect to
     database: String,
+
+    /// Collects query requests into batches when query batching is enabled in configuration
+    query_batcher: Arc<QueryBatcher>,
+
+    /// Handles for tenant databases that have passed validation, when tenancy is enabled in
+    /// configuration
+    tenant_databases: Arc<Mutex<HashMap<String, Database>>>,
+
+    /// Sends query shape records to the exporter when the query log is enabled in configuration
+    query_logger: Option<QueryLogger>,
+
+    /// Reports whether connection warm-up has completed when warm-up is enabled in configuration
+    warm_up: Option<WarmUp>,
+
+    /// Tracks in-flight requests so that they can be drained on shutdown
+    shutdown: Shutdown,
+
+    /// Limits concurrent queries per collection when collection concurrency is configured
+    bulkheads: Arc<Bulkheads>,
+
+    /// Observers registered with the connector that are notified of query lifecycle events
+    query_observers: QueryObservers,
+
+    /// Post-processors registered with the connector that rewrite rows in query responses
+    response_post_processors: ResponsePostProcessors,
+
+    /// Remembers whether the server supports snapshot reads when snapshot reads are enabled in
+    /// configuration
+    snapshot_support: SnapshotSupport,
+
+    /// Background tasks started by the connector, such as materialized native query refreshes,
+    /// that are aborted on shutdown
+    background_tasks: Arc<Mutex<Vec<AbortHandle>>>,
+
+    /// Cursor batch sizes learned from previous queries when adaptive batch sizes are enabled in
+    /// configuration
+    cursor_batch_sizes: CursorBatchSizes,
+
+    /// Statistics read by the refresh task when collection statistics are enabled in
+    /// configuration
+    collection_stats: CollectionStatsCache,
 }
 
 impl ConnectorState {
     pub fn database(&self) -> Database {
         self.client.database(&self.database)
     }
+
+    pub fn query_batcher(&self) -> &QueryBatcher {
+        &self.query_batcher
+    }
+
+    pub fn query_logger(&self) -> Option<&QueryLogger> {
+        self.query_logger.as_ref()
+    }
+
+    pub fn with_query_logger(self, query_logger: QueryLogger) -> Self {
+        ConnectorState {
+            query_logger: Some(query_logger),
+            ..self
+        }
+    }
+
+    pub fn bulkheads(&self) -> &Bulkheads {
+        &self.bulkheads
+    }
+
+    pub fn with_bulkheads(self, bulkheads: Bulkheads) -> Self {
+        ConnectorState {
+            bulkheads: Arc::new(bulkheads),
+            ..self
+        }
+    }
+
+    pub fn query_observers(&self) -> &QueryObservers {
+        &self.query_observers
+    }
+
+    pub fn with_query_observers(self, query_observers: QueryObservers) -> Self {
+        ConnectorState {
+            query_observers,
+            ..self
+        }
+    }
+
+    pub fn response_post_processors(&self) -> &ResponsePostProcessors {
+        &self.response_post_processors
+    }
+
+    pub fn with_response_post_processors(
+        self,
+        response_post_processors: ResponsePostProcessors,
+    ) -> Self {
+        ConnectorState {
+            response_post_processors,
+            ..self
+        }
+    }
+
+    pub fn snapshot_support(&self) -> &SnapshotSupport {
+        &self.snapshot_support
+    }
+
+    pub fn cursor_batch_sizes(&self) -> &CursorBatchSizes {
+        &self.cursor_batch_sizes
+    }
+
+    pub fn collection_stats(&self) -> &CollectionStatsCache {
+        &self.collection_stats
+    }
+
+    pub fn with_warm_up(self, warm_up: WarmUp) -> Self {
+        ConnectorState {
+            warm_up: Some(warm_up),
+            ..self
+        }
+    }
+
+    /// False while connection warm-up is in progress, or after shutdown has begun
+    pub fn is_ready(&self) -> bool {
+        self.warm_up.as_ref().map_or(true, WarmUp::is_ready) && !self.shutdown.is_shutting_down()
+    }
+
+    /// Request handlers should run inside [Shutdown::track] so that they are drained on shutdown
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// Registers background tasks to abort on shutdown
+    pub fn add_background_tasks(&self, tasks: impl IntoIterator<Item = AbortHandle>) {
+        self.background_tasks.lock().unwrap().extend(tasks);
+    }
+
+    /// Drains in-flight requests, aborts background tasks, and then closes the MongoDB client
+    pub async fn shut_down(&self, drain_timeout: Duration) {
+        self.shutdown.drain(drain_timeout).await;
+        for task in self.background_tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        self.client.clone().shutdown().await;
+    }
+
+    /// Gets a handle for the named tenant database. The `validate` callback runs the first time
+    /// each tenant is requested; the handle is cached only if validation succeeds.
+    pub fn tenant_database(
+        &self,
+        tenant: &str,
+        validate: impl FnOnce() -> Result<(), MongoAgentError>,
+    ) -> Result<Database, MongoAgentError> {
+        if let Some(database) = self.tenant_databases.lock().unwrap().get(tenant) {
+            return Ok(database.clone());
+        }
+        validate()?;
+        let database = self.client.database(tenant);
+        self.tenant_databases
+            .lock()
+            .unwrap()
+            .insert(tenant.to_owned(), database.clone());
+        Ok(database)
+    }
 }
 
-/// Reads database connection URI from environment variable
-pub async fn try_init_state() -> Result<ConnectorState, Box<dyn Error + Send + Sync>> {
+/// Reads database connection URI from environment variable. If `min_pool_size` is given it
+/// overrides the `minPoolSize` option in the URI.
+pub async fn try_init_state(
+    min_pool_size: Option<u32>,
+) -> Result<ConnectorState, Box<dyn Error + Send + Sync>> {
     // Splitting this out of the `Connector` impl makes error translation easier
     let database_uri = env::var(DATABASE_URI_ENV_VAR)?;
+    init_state(&database_uri, min_pool_size).await
+}
+
+/// In mock mode and replay mode queries do not use the database. The driver only connects when an
+/// operation runs, so a placeholder URI is used if the environment does not provide one.
+pub async fn try_init_offline_state() -> Result<ConnectorState, Box<dyn Error + Send + Sync>> {
+    let database_uri =
+        env::var(DATABASE_URI_ENV_VAR).unwrap_or_else(|_| OFFLINE_DATABASE_URI.to_owned());
     try_init_state_from_uri(&database_uri).await
 }
 
 pub async fn try_init_state_from_uri(
     database_uri: &str,
 ) -> Result<ConnectorState, Box<dyn Error + Send + Sync>> {
-    let client = get_mongodb_client(database_uri).await?;
+    init_state(database_uri, None).await
+}
+
+async fn init_state(
+    database_uri: &str,
+    min_pool_size: Option<u32>,
+) -> Result<ConnectorState, Box<dyn Error + Send + Sync>> {
+    let client = get_mongodb_client(database_uri, min_pool_size).await?;
     let database_name = match client.default_database() {
         Some(database) => Ok(database.name().to_owned()),
         None => Err(anyhow!(
             "${DATABASE_URI_ENV_VAR} environment variable must include a database"
         )),
     }?;
+    let collection_stats = CollectionStatsCache::default();
     Ok(ConnectorState {
         client,
         database: database_name,
+        query_batcher: Default::default(),
+        tenant_databases: Default::default(),
+        query_logger: None,
+        warm_up: None,
+        shutdown: Default::default(),
+        bulkheads: Default::default(),
+        query_observers: Default::default(),
+        response_post_processors: Default::default(),
+        snapshot_support: Default::default(),
+        background_tasks: Default::default(),
+        cursor_batch_sizes: CursorBatchSizes::new(collection_stats.clone()),
+        collection_stats,
     })
 }
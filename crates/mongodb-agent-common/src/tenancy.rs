@@ -0,0 +1,209 @@
+//! Database-per-tenant routing. When tenancy is configured query and mutation requests may name
+//! a tenant database in a designated argument. The argument is removed from the request before
+//! planning, and the request runs against the named database instead of the database from the
+//! connection URI.
+
+use std::collections::BTreeMap;
+
+use configuration::{system_collections::is_system_database, ConfigurationTenancyOptions};
+use mongodb::Database;
+use ndc_models::{Argument, MutationOperation, MutationRequest, QueryRequest};
+
+use crate::{
+    interface_types::MongoAgentError, mongo_query_plan::MongoConfiguration,
+    query::arguments::ArgumentError, state::ConnectorState,
+};
+
+/// Characters that MongoDB does not allow in database names
+const INVALID_DATABASE_NAME_CHARACTERS: &[char] = &['/', '\\', '.', ' ', '"', '$', '\0'];
+
+/// MongoDB database names must be shorter than this many bytes
+const MAX_DATABASE_NAME_LENGTH: usize = 64;
+
+/// Selects the database for a query request, removing the tenant argument from the request if
+/// tenancy is configured.
+pub fn database_for_request(
+    config: &MongoConfiguration,
+    state: &ConnectorState,
+    query_request: &mut QueryRequest,
+) -> Result<Database, MongoAgentError> {
+    let Some(tenancy) = config.tenancy() else {
+        return Ok(state.database());
+    };
+    let tenant = take_tenant_argument(tenancy, query_request)?;
+    tenant_database(config, state, tenancy, tenant)
+}
+
+/// Selects the database for a mutation request, removing the tenant argument from each operation
+/// if tenancy is configured. All operations in a request run against the same database, so
+/// operations must not name different tenants.
+pub fn database_for_mutation_request(
+    config: &MongoConfiguration,
+    state: &ConnectorState,
+    mutation_request: &mut MutationRequest,
+) -> Result<Database, MongoAgentError> {
+    let Some(tenancy) = config.tenancy() else {
+        return Ok(state.database());
+    };
+    let mut tenants = vec![];
+    for operation in &mut mutation_request.operations {
+        let MutationOperation::Procedure { arguments, .. } = operation;
+        tenants.push(take_tenant_mutation_argument(tenancy, arguments)?);
+    }
+    let tenant = tenants.first().cloned().flatten();
+    if tenants.iter().any(|other| *other != tenant) {
+        return Err(ArgumentError::InvalidValue {
+            name: tenancy.tenant_argument.clone(),
+            message: "all operations in a mutation request must use the same tenant database"
+                .to_owned(),
+        }
+        .into());
+    }
+    tenant_database(config, state, tenancy, tenant)
+}
+
+fn tenant_database(
+    config: &MongoConfiguration,
+    state: &ConnectorState,
+    tenancy: &ConfigurationTenancyOptions,
+    tenant: Option<String>,
+) -> Result<Database, MongoAgentError> {
+    match tenant {
+        Some(tenant) => state.tenant_database(&tenant, || {
+            validate_tenant(tenancy, config.allow_system_collections(), &tenant)
+        }),
+        None => Ok(state.database()),
+    }
+}
+
+fn take_tenant_argument(
+    tenancy: &ConfigurationTenancyOptions,
+    query_request: &mut QueryRequest,
+) -> Result<Option<String>, ArgumentError> {
+    let name = &tenancy.tenant_argument;
+    match query_request.arguments.remove(name) {
+        None => Ok(None),
+        Some(Argument::Literal { value }) => tenant_name(tenancy, value),
+        Some(Argument::Variable { .. }) => Err(ArgumentError::NotLiteral(name.clone())),
+    }
+}
+
+fn take_tenant_mutation_argument(
+    tenancy: &ConfigurationTenancyOptions,
+    arguments: &mut BTreeMap<ndc_models::ArgumentName, serde_json::Value>,
+) -> Result<Option<String>, ArgumentError> {
+    match arguments.remove(&tenancy.tenant_argument) {
+        None => Ok(None),
+        Some(value) => tenant_name(tenancy, value),
+    }
+}
+
+fn tenant_name(
+    tenancy: &ConfigurationTenancyOptions,
+    value: serde_json::Value,
+) -> Result<Option<String>, ArgumentError> {
+    match value {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(tenant) => Ok(Some(tenant)),
+        value => Err(ArgumentError::InvalidValue {
+            name: tenancy.tenant_argument.clone(),
+            message: format!("expected a database name, but got {value}"),
+        }),
+    }
+}
+
+fn validate_tenant(
+    tenancy: &ConfigurationTenancyOptions,
+    allow_system_databases: bool,
+    tenant: &str,
+) -> Result<(), MongoAgentError> {
+    let is_valid_name = !tenant.is_empty()
+        && tenant.len() < MAX_DATABASE_NAME_LENGTH
+        && !tenant.contains(INVALID_DATABASE_NAME_CHARACTERS);
+    let is_allowed = allow_system_databases || !is_system_database(tenant);
+    if is_valid_name && is_allowed && tenancy.database_pattern.is_match(tenant) {
+        Ok(())
+    } else {
+        Err(ArgumentError::InvalidValue {
+            name: tenancy.tenant_argument.clone(),
+            message: format!("\"{tenant}\" is not an allowed tenant database"),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ConfigurationTenancyOptions;
+    use ndc_models::{Argument, QueryRequest};
+    use ndc_test_helpers::{query, query_request};
+    use serde_json::json;
+
+    use super::{take_tenant_argument, take_tenant_mutation_argument, validate_tenant};
+
+    fn tenancy() -> ConfigurationTenancyOptions {
+        ConfigurationTenancyOptions {
+            tenant_argument: "tenant".into(),
+            database_pattern: "tenant_[a-z0-9]+".to_owned().try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn removes_tenant_argument_from_request() -> anyhow::Result<()> {
+        let mut request: QueryRequest = query_request()
+            .collection("movies")
+            .query(query())
+            .arguments([(
+                "tenant",
+                Argument::Literal {
+                    value: json!("tenant_acme"),
+                },
+            )])
+            .into();
+        let tenant = take_tenant_argument(&tenancy(), &mut request)?;
+        assert_eq!(tenant, Some("tenant_acme".to_owned()));
+        assert!(request.arguments.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn removes_tenant_argument_from_mutation_operation() -> anyhow::Result<()> {
+        let mut arguments = [
+            ("tenant".into(), json!("tenant_acme")),
+            ("id".into(), json!(1)),
+        ]
+        .into();
+        let tenant = take_tenant_mutation_argument(&tenancy(), &mut arguments)?;
+        assert_eq!(tenant, Some("tenant_acme".to_owned()));
+        assert_eq!(arguments, [("id".into(), json!(1))].into());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_database_pattern_when_configuration_is_parsed() {
+        let tenancy = serde_json::from_value::<ConfigurationTenancyOptions>(json!({
+            "databasePattern": "tenant_[",
+        }));
+        assert!(tenancy.is_err());
+    }
+
+    #[test]
+    fn rejects_databases_outside_of_allow_list() {
+        assert!(validate_tenant(&tenancy(), false, "tenant_acme").is_ok());
+        assert!(validate_tenant(&tenancy(), false, "admin").is_err());
+        // The pattern must match the entire name
+        assert!(validate_tenant(&tenancy(), false, "tenant_acme_admin").is_err());
+        assert!(validate_tenant(&tenancy(), false, "tenant_acme.x").is_err());
+    }
+
+    #[test]
+    fn rejects_system_databases_unless_allowed() {
+        let tenancy = ConfigurationTenancyOptions {
+            database_pattern: ".+".to_owned().try_into().unwrap(),
+            ..tenancy()
+        };
+        assert!(validate_tenant(&tenancy, false, "admin").is_err());
+        assert!(validate_tenant(&tenancy, false, "local").is_err());
+        assert!(validate_tenant(&tenancy, true, "admin").is_ok());
+    }
+}
@@ -82,6 +82,10 @@ pub fn make_nested_schema() -> MongoConfiguration {
         procedures: Default::default(),
         native_mutations: Default::default(),
         native_queries: Default::default(),
+        lookup_functions: Default::default(),
+        collection_policies: Default::default(),
+        relationships: Default::default(),
+        object_id_formats: Default::default(),
         options: Default::default(),
     })
 }
@@ -135,6 +139,10 @@ pub fn chinook_config() -> MongoConfiguration {
         procedures: Default::default(),
         native_mutations: Default::default(),
         native_queries: Default::default(),
+        lookup_functions: Default::default(),
+        collection_policies: Default::default(),
+        relationships: Default::default(),
+        object_id_formats: Default::default(),
         options: Default::default(),
     })
 }
@@ -191,6 +199,10 @@ pub fn mflix_config() -> MongoConfiguration {
         procedures: Default::default(),
         native_mutations: Default::default(),
         native_queries: Default::default(),
+        lookup_functions: Default::default(),
+        collection_policies: Default::default(),
+        relationships: Default::default(),
+        object_id_formats: Default::default(),
         options: Default::default(),
     })
 }
@@ -0,0 +1,148 @@
+//! Connection warm-up at startup. This is enabled by the `warmUp` configuration option. A
+//! background task opens the configured number of connections, and runs `ping` and optionally
+//! `listCollections`. Until that succeeds the health check reports that the connector is not
+//! ready so that traffic is not routed to an instance that would make its first queries wait on
+//! connection setup.
+//!
+//! With the `verifyNativeQueries` option warm-up also checks native queries and native mutations
+//! against the server. See [crate::native_query_verification]. A rejected native query is
+//! a configuration error, so it is not retried, and the connector stays not ready.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use configuration::ConfigurationWarmUpOptions;
+use futures::future::try_join_all;
+use mongodb::{
+    bson::{doc, Document},
+    Database,
+};
+
+use crate::{
+    interface_types::MongoAgentError,
+    mongo_query_plan::MongoConfiguration,
+    native_query_verification::{verifications, verify},
+};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handle that reports whether warm-up has completed. Cloning the handle is cheap.
+#[derive(Clone, Debug)]
+pub struct WarmUp {
+    ready: Arc<AtomicBool>,
+}
+
+impl WarmUp {
+    /// Starts the warm-up task. Failed attempts are logged, and retried until one succeeds.
+    pub fn spawn(
+        config: &MongoConfiguration,
+        options: &ConfigurationWarmUpOptions,
+        database: Database,
+    ) -> Self {
+        let warm_up = WarmUp {
+            ready: Default::default(),
+        };
+        let ready = warm_up.ready.clone();
+        let options = options.clone();
+        let verifications = if options.verify_native_queries {
+            verifications(config)
+        } else {
+            vec![]
+        };
+        tokio::spawn(async move {
+            let start = Instant::now();
+            while let Err(err) = warm_up_connections(&options, &database).await {
+                tracing::warn!(error = %err, "connection warm-up failed; retrying");
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            }
+            tracing::info!(
+                connections = options.min_pool_size,
+                duration_ms = start.elapsed().as_millis() as u64,
+                "connection warm-up complete"
+            );
+            if !verifications.is_empty() {
+                let rejections = loop {
+                    match verify(&database, &verifications).await {
+                        Ok(rejections) => break rejections,
+                        Err(err) => {
+                            tracing::warn!(error = %err, "native query verification failed; retrying");
+                            tokio::time::sleep(RETRY_INTERVAL).await;
+                        }
+                    }
+                };
+                if !rejections.is_empty() {
+                    for rejection in &rejections {
+                        tracing::error!(subject = %rejection.subject, "{rejection}");
+                    }
+                    tracing::error!(
+                        rejected = rejections.len(),
+                        "native query verification failed; the connector will not report ready"
+                    );
+                    return;
+                }
+                tracing::info!(
+                    verified = verifications.len(),
+                    "native query verification complete"
+                );
+            }
+            ready.store(true, Ordering::Release);
+        });
+        warm_up
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+}
+
+async fn warm_up_connections(
+    options: &ConfigurationWarmUpOptions,
+    database: &Database,
+) -> Result<(), MongoAgentError> {
+    ping(database).await?;
+
+    // Each concurrent command checks out its own connection so the pool has to open up to
+    // `min_pool_size` connections to run them all.
+    try_join_all((0..options.min_pool_size).map(|_| ping(database))).await?;
+
+    if options.list_collections {
+        database.list_collection_names(None).await?;
+    }
+    Ok(())
+}
+
+async fn ping(database: &Database) -> Result<Document, MongoAgentError> {
+    Ok(database.run_command(doc! { "ping": 1 }, None).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use http::StatusCode;
+
+    use crate::{health::check_health, state::try_init_state_from_uri};
+
+    use super::WarmUp;
+
+    #[tokio::test]
+    async fn health_check_is_unavailable_until_warm_up_completes() -> anyhow::Result<()> {
+        let warm_up = WarmUp {
+            ready: Default::default(),
+        };
+        let state = try_init_state_from_uri("mongodb://localhost/test")
+            .await
+            .map_err(|err| anyhow::anyhow!("{err}"))?
+            .with_warm_up(warm_up.clone());
+        assert_eq!(check_health(&state).await?, StatusCode::SERVICE_UNAVAILABLE);
+
+        warm_up.ready.store(true, Ordering::Release);
+        assert!(state.is_ready());
+        Ok(())
+    }
+}
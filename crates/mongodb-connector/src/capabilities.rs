@@ -1,27 +1,6 @@
-use ndc_sdk::models::{
-    Capabilities, LeafCapability, NestedFieldCapabilities, QueryCapabilities,
-    RelationshipCapabilities,
-};
+use mongodb_agent_common::mongo_query_plan::SUPPORTED_FEATURES;
+use ndc_sdk::models::Capabilities;
 
 pub fn mongo_capabilities() -> Capabilities {
-    Capabilities {
-        query: QueryCapabilities {
-            aggregates: Some(LeafCapability {}),
-            variables: Some(LeafCapability {}),
-            explain: Some(LeafCapability {}),
-            nested_fields: NestedFieldCapabilities {
-                filter_by: Some(LeafCapability {}),
-                order_by: Some(LeafCapability {}),
-                aggregates: None,
-            },
-        },
-        mutation: ndc_sdk::models::MutationCapabilities {
-            transactional: None,
-            explain: None,
-        },
-        relationships: Some(RelationshipCapabilities {
-            relation_comparisons: Some(LeafCapability {}),
-            order_by_aggregate: None,
-        }),
-    }
+    SUPPORTED_FEATURES.capabilities()
 }
@@ -0,0 +1,7 @@
+mod capabilities;
+mod error_mapping;
+mod mongo_connector;
+mod mutation;
+mod schema;
+
+pub use mongo_connector::MongoConnector;
@@ -1,12 +1,6 @@
-mod capabilities;
-mod error_mapping;
-mod mongo_connector;
-mod mutation;
-mod schema;
-
 use std::error::Error;
 
-use mongo_connector::MongoConnector;
+use mongodb_connector::MongoConnector;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
@@ -1,11 +1,22 @@
-use std::path::Path;
+use std::{path::Path, sync::Arc};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
 use configuration::Configuration;
 use mongodb_agent_common::{
-    explain::explain_query, health::check_health, mongo_query_plan::MongoConfiguration,
-    query::handle_query_request, state::ConnectorState,
+    bulkheads::Bulkheads,
+    collection_stats,
+    explain::explain_query,
+    health::check_health,
+    materialization::spawn_refresh_tasks,
+    mongo_query_plan::MongoConfiguration,
+    monitoring::{QueryObserver, QueryObservers},
+    post_processing::{ResponsePostProcessor, ResponsePostProcessors},
+    procedure::create_ttl_index,
+    query::handle_query_request,
+    query_log::QueryLogger,
+    state::ConnectorState,
+    warm_up::WarmUp,
 };
 use ndc_sdk::{
     connector::{
@@ -27,7 +38,29 @@ use crate::error_mapping::{
 use crate::{capabilities::mongo_capabilities, mutation::handle_mutation_request};
 
 #[derive(Clone, Default)]
-pub struct MongoConnector;
+pub struct MongoConnector {
+    query_observers: Vec<Arc<dyn QueryObserver>>,
+    response_post_processors: Vec<Arc<dyn ResponsePostProcessor>>,
+}
+
+impl MongoConnector {
+    /// Registers an observer that is notified of query lifecycle events once the connector state
+    /// is initialized. See [mongodb_agent_common::monitoring].
+    pub fn with_query_observer(mut self, observer: impl QueryObserver + 'static) -> Self {
+        self.query_observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Registers a processor that rewrites rows in query responses. Processors run in the order
+    /// they are registered. See [mongodb_agent_common::post_processing].
+    pub fn with_response_post_processor(
+        mut self,
+        processor: impl ResponsePostProcessor + 'static,
+    ) -> Self {
+        self.response_post_processors.push(Arc::new(processor));
+        self
+    }
+}
 
 #[allow(clippy::blocks_in_conditions)]
 #[async_trait]
@@ -52,14 +85,101 @@ impl ConnectorSetup for MongoConnector {
     // - `skip_all` omits arguments from the trace
     async fn try_init_state(
         &self,
-        _configuration: &MongoConfiguration,
-        _metrics: &mut prometheus::Registry,
+        configuration: &MongoConfiguration,
+        metrics: &mut prometheus::Registry,
     ) -> Result<ConnectorState, InitializationError> {
-        let state = mongodb_agent_common::state::try_init_state().await?;
+        let query_observers = QueryObservers::new(self.query_observers.clone());
+        let post_processors = ResponsePostProcessors::new(self.response_post_processors.clone());
+        if configuration.is_offline() {
+            let state = mongodb_agent_common::state::try_init_offline_state().await?;
+            return Ok(state
+                .with_query_observers(query_observers)
+                .with_response_post_processors(post_processors));
+        }
+        let min_pool_size = configuration.warm_up().map(|options| options.min_pool_size);
+        let state = mongodb_agent_common::state::try_init_state(min_pool_size)
+            .await?
+            .with_query_observers(query_observers)
+            .with_response_post_processors(post_processors);
+        let state = match configuration.warm_up() {
+            Some(options) => {
+                state.with_warm_up(WarmUp::spawn(configuration, options, state.database()))
+            }
+            None => state,
+        };
+        let state = match configuration.collection_concurrency() {
+            Some(_) => state.with_bulkheads(
+                Bulkheads::with_metrics(metrics)
+                    .map_err(|err| InitializationError::Other(err.into()))?,
+            ),
+            None => state,
+        };
+        spawn_shutdown_task(configuration, state.clone());
+        state.add_background_tasks(spawn_refresh_tasks(configuration, state.database()));
+        if let Some(options) = configuration.collection_stats() {
+            let refresh_task = collection_stats::spawn_refresh_task(
+                configuration,
+                options,
+                state.database(),
+                state.collection_stats().clone(),
+            );
+            state.add_background_tasks([refresh_task.abort_handle()]);
+        }
+        if let Some(options) = configuration.idempotency().cloned() {
+            let database = state.database();
+            tokio::spawn(async move {
+                if let Err(err) = create_ttl_index(&database, &options).await {
+                    tracing::error!(error = %err, "failed to create TTL index for idempotency keys");
+                }
+            });
+        }
+        let state = match configuration.query_log() {
+            Some(options) => {
+                let query_logger = QueryLogger::spawn(options, state.database());
+                state.with_query_logger(query_logger)
+            }
+            None => state,
+        };
         Ok(state)
     }
 }
 
+/// Drains in-flight requests when the process receives `SIGTERM` or Ctrl-C. The HTTP server
+/// handles the same signals separately.
+fn spawn_shutdown_task(configuration: &MongoConfiguration, state: ConnectorState) {
+    let drain_timeout = configuration.shutdown_drain_timeout();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("shutting down; draining in-flight requests");
+        state.shut_down(drain_timeout).await;
+        tracing::info!("MongoDB client closed");
+    });
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
 #[allow(clippy::blocks_in_conditions)]
 #[async_trait]
 impl Connector for MongoConnector {
@@ -76,9 +196,12 @@ impl Connector for MongoConnector {
 
     #[instrument(err, skip_all)]
     async fn health_check(
-        _configuration: &Self::Configuration,
+        configuration: &Self::Configuration,
         state: &Self::State,
     ) -> Result<(), HealthError> {
+        if configuration.is_offline() {
+            return Ok(());
+        }
         let status = check_health(state)
             .await
             .map_err(|e| HealthError::Other(e.into(), Value::Object(Default::default())))?;
@@ -109,8 +232,11 @@ impl Connector for MongoConnector {
         state: &Self::State,
         request: QueryRequest,
     ) -> Result<JsonResponse<ExplainResponse>, ExplainError> {
-        let response = explain_query(configuration, state, request)
+        let response = state
+            .shutdown()
+            .track(explain_query(configuration, state, request))
             .await
+            .and_then(|result| result)
             .map_err(mongo_agent_error_to_explain_error)?;
         Ok(response.into())
     }
@@ -132,7 +258,11 @@ impl Connector for MongoConnector {
         state: &Self::State,
         request: MutationRequest,
     ) -> Result<JsonResponse<MutationResponse>, MutationError> {
-        handle_mutation_request(configuration, state, request).await
+        state
+            .shutdown()
+            .track(handle_mutation_request(configuration, state, request))
+            .await
+            .map_err(|err| MutationError::Other(Box::new(err), Value::Object(Default::default())))?
     }
 
     #[instrument(name = "/query", err, skip_all, fields(internal.visibility = "user"))]
@@ -141,9 +271,12 @@ impl Connector for MongoConnector {
         state: &Self::State,
         request: QueryRequest,
     ) -> Result<JsonResponse<QueryResponse>, QueryError> {
-        let response = handle_query_request(configuration, state, request)
+        let response = state
+            .shutdown()
+            .track(handle_query_request(configuration, state, request))
             .await
+            .and_then(|result| result)
             .map_err(mongo_agent_error_to_query_error)?;
-        Ok(response.into())
+        Ok(JsonResponse::Serialized(response))
     }
 }
@@ -1,4 +1,4 @@
-use futures::future::try_join_all;
+use futures::{future::try_join_all, TryFutureExt as _};
 use itertools::Itertools;
 use mongodb::{
     bson::{self, Bson},
@@ -6,9 +6,10 @@ use mongodb::{
 };
 use mongodb_agent_common::{
     mongo_query_plan::MongoConfiguration,
-    procedure::Procedure,
+    procedure::{run_with_idempotency_key, Procedure, ProcedureError},
     query::{response::type_for_nested_field, serialization::bson_to_json},
     state::ConnectorState,
+    tenancy::database_for_mutation_request,
 };
 use ndc_query_plan::type_annotated_nested_field;
 use ndc_sdk::{
@@ -25,20 +26,30 @@ use crate::error_mapping::error_response;
 pub async fn handle_mutation_request(
     config: &MongoConfiguration,
     state: &ConnectorState,
-    mutation_request: MutationRequest,
+    mut mutation_request: MutationRequest,
 ) -> Result<JsonResponse<MutationResponse>, MutationError> {
     tracing::debug!(?config, mutation_request = %serde_json::to_string(&mutation_request).unwrap(), "executing mutation");
-    let database = state.database();
+    if config.is_read_only() {
+        return Err(MutationError::UnsupportedOperation(error_response(
+            "mutations are disabled because the connector is configured in read-only or mock mode"
+                .to_owned(),
+        )));
+    }
+    let database = database_for_mutation_request(config, state, &mut mutation_request)
+        .map_err(|err| MutationError::InvalidRequest(error_response(err.to_string())))?;
     let jobs = look_up_procedures(config, &mutation_request)?;
-    let operation_results = try_join_all(jobs.into_iter().map(|(procedure, requested_fields)| {
-        execute_procedure(
-            config,
-            &mutation_request,
-            database.clone(),
-            procedure,
-            requested_fields,
-        )
-    }))
+    let operation_results = try_join_all(jobs.into_iter().map(
+        |(procedure_name, procedure, requested_fields)| {
+            execute_procedure(
+                config,
+                &mutation_request,
+                database.clone(),
+                procedure_name,
+                procedure,
+                requested_fields,
+            )
+        },
+    ))
     .await?;
     Ok(JsonResponse::Value(MutationResponse { operation_results }))
 }
@@ -48,7 +59,14 @@ pub async fn handle_mutation_request(
 fn look_up_procedures<'a, 'b>(
     config: &'a MongoConfiguration,
     mutation_request: &'b MutationRequest,
-) -> Result<Vec<(Procedure<'a>, Option<&'b NestedField>)>, MutationError> {
+) -> Result<
+    Vec<(
+        &'b ndc::ProcedureName,
+        Procedure<'a>,
+        Option<&'b NestedField>,
+    )>,
+    MutationError,
+> {
     let (procedures, not_found): (Vec<_>, Vec<String>) = mutation_request
         .operations
         .iter()
@@ -62,9 +80,13 @@ fn look_up_procedures<'a, 'b>(
                 let procedure = native_mutation
                     .ok_or(name.to_string())
                     .map(|native_mutation| {
-                        Procedure::from_native_mutation(native_mutation, arguments.clone())
+                        Procedure::from_native_mutation(
+                            native_mutation,
+                            arguments.clone(),
+                            config.allow_system_collections(),
+                        )
                     })?;
-                Ok((procedure, fields.as_ref()))
+                Ok((name, procedure, fields.as_ref()))
             }
         })
         .partition_result();
@@ -85,13 +107,59 @@ async fn execute_procedure(
     config: &MongoConfiguration,
     mutation_request: &MutationRequest,
     database: Database,
-    procedure: Procedure<'_>,
+    procedure_name: &ndc::ProcedureName,
+    mut procedure: Procedure<'_>,
     requested_fields: Option<&NestedField>,
 ) -> Result<MutationOperationResults, MutationError> {
-    let (result, result_type) = procedure
-        .execute(database.clone())
+    let idempotency = match config.idempotency() {
+        Some(options) => procedure
+            .take_idempotency_key(options)
+            .map(|key| key.map(|key| (options, key, procedure.arguments_hash()))),
+        None => Ok(None),
+    };
+    let result_type = procedure.result_type().clone();
+    let mutation = async {
+        match config.audit() {
+            Some(audit) => {
+                procedure
+                    .execute_with_audit(database.clone(), procedure_name, audit)
+                    .await
+            }
+            None => procedure.execute(database.clone()).await,
+        }
+    };
+    let execution = match idempotency {
+        Ok(Some((options, key, arguments_hash))) => run_with_idempotency_key(
+            &database,
+            options,
+            procedure_name,
+            &key,
+            &arguments_hash,
+            mutation.map_ok(|(result, _)| result),
+        )
         .await
-        .map_err(|err| MutationError::UnprocessableContent(error_response(err.to_string())))?;
+        .map(|result| (result, result_type)),
+        Ok(None) => mutation.await,
+        Err(err) => Err(err),
+    };
+    let (result, result_type) = execution.map_err(|err| match err {
+        ProcedureError::VersionConflict | ProcedureError::IdempotencyKeyInUse => {
+            MutationError::Conflict(error_response(err.to_string()))
+        }
+        ProcedureError::PartialWrite(ref failure) => {
+            MutationError::UnprocessableContent(ndc::ErrorResponse {
+                message: err.to_string(),
+                details: serde_json::to_value(failure).unwrap_or_default(),
+            })
+        }
+        ProcedureError::InvalidArguments(ref violations) => {
+            MutationError::UnprocessableContent(ndc::ErrorResponse {
+                message: err.to_string(),
+                details: serde_json::json!({ "violations": violations }),
+            })
+        }
+        err => MutationError::UnprocessableContent(error_response(err.to_string())),
+    })?;
 
     let rewritten_result = rewrite_response(requested_fields, result.into())?;
 
@@ -110,7 +178,7 @@ async fn execute_procedure(
     };
 
     let json_result = bson_to_json(
-        config.extended_json_mode(),
+        config.serialization_options(),
         &requested_result_type,
         rewritten_result,
     )
@@ -1,6 +1,4 @@
-use mongodb_agent_common::{
-    mongo_query_plan::MongoConfiguration, scalar_types_capabilities::SCALAR_TYPES,
-};
+use mongodb_agent_common::mongo_query_plan::MongoConfiguration;
 use ndc_query_plan::QueryContext as _;
 use ndc_sdk::{connector::SchemaError, models as ndc};
 
@@ -13,12 +11,16 @@ pub async fn get_schema(config: &MongoConfiguration) -> Result<ndc::SchemaRespon
             .map(|(f, _)| f)
             .cloned()
             .collect(),
-        procedures: config.procedures().values().cloned().collect(),
+        procedures: if config.is_read_only() {
+            vec![]
+        } else {
+            config.procedures().values().cloned().collect()
+        },
         object_types: config
             .object_types()
             .iter()
             .map(|(name, object_type)| (name.clone(), object_type.clone()))
             .collect(),
-        scalar_types: SCALAR_TYPES.clone(),
+        scalar_types: config.scalar_types(),
     })
 }
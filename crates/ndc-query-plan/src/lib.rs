@@ -1,5 +1,6 @@
 mod plan_for_query_request;
 mod query_plan;
+mod supported_features;
 mod type_system;
 pub mod vec_set;
 
@@ -15,4 +16,5 @@ pub use query_plan::{
     NestedField, NestedObject, OrderBy, OrderByElement, OrderByTarget, Query, QueryPlan,
     Relationship, Relationships, Scope, VariableSet, VariableTypes,
 };
+pub use supported_features::SupportedFeatures;
 pub use type_system::{inline_object_types, ObjectType, Type};
@@ -32,7 +32,18 @@ pub fn plan_for_query_request<T: QueryContext>(
     context: &T,
     request: QueryRequest,
 ) -> Result<QueryPlan<T>> {
-    let mut plan_state = QueryPlanState::new(context, &request.collection_relationships);
+    let mut collection_relationships = request.collection_relationships;
+    for (name, relationship) in context.configured_relationships() {
+        collection_relationships
+            .entry(name.clone())
+            .or_insert_with(|| relationship.clone());
+    }
+
+    if request.variables.is_some() {
+        require_feature(context.supported_features().variables, "query variables")?;
+    }
+
+    let mut plan_state = QueryPlanState::new(context, &collection_relationships);
     let collection_object_type = context.find_collection_object_type(&request.collection)?;
 
     let mut query = plan_for_query(
@@ -78,6 +89,16 @@ pub fn plan_for_query_request<T: QueryContext>(
     })
 }
 
+/// Fails with [QueryPlanError::NotImplemented] if the connector does not support the named
+/// feature
+fn require_feature(supported: bool, feature: &'static str) -> Result<()> {
+    if supported {
+        Ok(())
+    } else {
+        Err(QueryPlanError::NotImplemented(feature))
+    }
+}
+
 /// root_collection_object_type references the collection type of the nearest enclosing [ndc::Query]
 pub fn plan_for_query<T: QueryContext>(
     plan_state: &mut QueryPlanState<'_, T>,
@@ -143,6 +164,7 @@ fn plan_for_aggregates<T: QueryContext>(
 ) -> Result<Option<IndexMap<ndc::FieldName, plan::Aggregate<T>>>> {
     ndc_aggregates
         .map(|aggregates| -> Result<_> {
+            require_feature(context.supported_features().aggregates, "aggregates")?;
             aggregates
                 .into_iter()
                 .map(|(name, aggregate)| {
@@ -161,24 +183,35 @@ fn plan_for_aggregate<T: QueryContext>(
     collection_object_type: &plan::ObjectType<T::ScalarType>,
     aggregate: ndc::Aggregate,
 ) -> Result<plan::Aggregate<T>> {
+    let nested_field_aggregates = context.supported_features().nested_field_aggregates;
     match aggregate {
         ndc::Aggregate::ColumnCount {
             column,
             distinct,
-            field_path: _,
-        } => Ok(plan::Aggregate::ColumnCount { column, distinct }),
+            field_path,
+        } => {
+            if field_path.is_some() {
+                require_feature(nested_field_aggregates, "aggregates over nested fields")?;
+            }
+            Ok(plan::Aggregate::ColumnCount { column, distinct })
+        }
         ndc::Aggregate::SingleColumn {
             column,
             function,
-            field_path: _,
+            field_path,
         } => {
+            if field_path.is_some() {
+                require_feature(nested_field_aggregates, "aggregates over nested fields")?;
+            }
             let object_type_field_type = find_object_field(collection_object_type, &column)?;
             // let column_scalar_type_name = get_scalar_type_name(&object_type_field.r#type)?;
+            let arguments = context.aggregation_function_arguments(&function);
             let (function, definition) =
                 context.find_aggregation_function_definition(object_type_field_type, &function)?;
             Ok(plan::Aggregate::SingleColumn {
                 column,
                 function,
+                arguments,
                 result_type: definition.result_type.clone(),
             })
         }
@@ -240,29 +273,36 @@ fn plan_for_order_by_element<T: QueryContext>(
     object_type: &plan::ObjectType<T::ScalarType>,
     element: ndc::OrderByElement,
 ) -> Result<plan::OrderByElement<T>> {
+    let features = plan_state.context.supported_features();
     let target = match element.target {
         ndc::OrderByTarget::Column {
             name,
             field_path,
             path,
-        } => plan::OrderByTarget::Column {
-            name: name.clone(),
-            field_path,
-            path: plan_for_relationship_path(
-                plan_state,
-                root_collection_object_type,
-                object_type,
-                path,
-                vec![name],
-            )?
-            .0,
-        },
+        } => {
+            if field_path.as_ref().is_some_and(|p| !p.is_empty()) {
+                require_feature(features.nested_field_order_by, "ordering by nested fields")?;
+            }
+            plan::OrderByTarget::Column {
+                name: name.clone(),
+                field_path,
+                path: plan_for_relationship_path(
+                    plan_state,
+                    root_collection_object_type,
+                    object_type,
+                    path,
+                    vec![name],
+                )?
+                .0,
+            }
+        }
         ndc::OrderByTarget::SingleColumnAggregate {
             column,
             function,
             path,
             field_path: _,
         } => {
+            require_feature(features.order_by_aggregate, "ordering by aggregates")?;
             let (plan_path, target_object_type) = plan_for_relationship_path(
                 plan_state,
                 root_collection_object_type,
@@ -283,6 +323,7 @@ fn plan_for_order_by_element<T: QueryContext>(
             }
         }
         ndc::OrderByTarget::StarCountAggregate { path } => {
+            require_feature(features.order_by_aggregate, "ordering by aggregates")?;
             let (plan_path, _) = plan_for_relationship_path(
                 plan_state,
                 root_collection_object_type,
@@ -510,6 +551,11 @@ fn plan_for_binary_comparison<T: QueryContext>(
         }
         plan::ComparisonOperatorDefinition::Custom { argument_type } => argument_type.clone(),
     };
+    if let ndc::ComparisonValue::Scalar { value } = &value {
+        plan_state
+            .context
+            .validate_comparison_value(&operator, value)?;
+    }
     Ok(plan::Expression::BinaryComparisonOperator {
         operator,
         value: plan_for_comparison_value(
@@ -535,6 +581,19 @@ fn plan_for_comparison_target<T: QueryContext>(
             field_path,
             path,
         } => {
+            let features = plan_state.context.supported_features();
+            if !path.is_empty() {
+                require_feature(
+                    features.relation_comparisons,
+                    "comparisons on columns of related collections",
+                )?;
+            }
+            if field_path.as_ref().is_some_and(|p| !p.is_empty()) {
+                require_feature(
+                    features.nested_field_filter_by,
+                    "filtering by nested fields",
+                )?;
+            }
             let requested_columns = vec![name.clone()];
             let (path, target_object_type) = plan_for_relationship_path(
                 plan_state,
@@ -553,6 +612,15 @@ fn plan_for_comparison_target<T: QueryContext>(
             })
         }
         ndc::ComparisonTarget::RootCollectionColumn { name, field_path } => {
+            if field_path.as_ref().is_some_and(|p| !p.is_empty()) {
+                require_feature(
+                    plan_state
+                        .context
+                        .supported_features()
+                        .nested_field_filter_by,
+                    "filtering by nested fields",
+                )?;
+            }
             let field_type =
                 find_object_field_path(root_collection_object_type, &name, &field_path)?.clone();
             Ok(plan::ComparisonTarget::ColumnInScope {
@@ -28,6 +28,7 @@ pub struct TestContext {
     pub functions: BTreeMap<ndc::FunctionName, (ndc::FunctionInfo, ndc::CollectionInfo)>,
     pub procedures: BTreeMap<ndc::ProcedureName, ndc::ProcedureInfo>,
     pub object_types: BTreeMap<ndc::ObjectTypeName, ndc::ObjectType>,
+    pub relationships: BTreeMap<ndc::RelationshipName, ndc::Relationship>,
 }
 
 impl ConnectorTypes for TestContext {
@@ -93,6 +94,10 @@ impl QueryContext for TestContext {
     fn procedures(&self) -> &BTreeMap<ndc::ProcedureName, ndc::ProcedureInfo> {
         &self.procedures
     }
+
+    fn configured_relationships(&self) -> &BTreeMap<ndc::RelationshipName, ndc::Relationship> {
+        &self.relationships
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Sequence)]
@@ -283,6 +288,7 @@ pub fn make_flat_schema() -> TestContext {
             ),
         ]),
         procedures: Default::default(),
+        relationships: Default::default(),
     }
 }
 
@@ -339,5 +345,6 @@ pub fn make_nested_schema() -> TestContext {
             ),
         ]),
         procedures: Default::default(),
+        relationships: Default::default(),
     }
 }
@@ -4,7 +4,7 @@ use ndc_models as ndc;
 
 use crate::type_system::lookup_object_type;
 use crate::{self as plan, inline_object_types};
-use crate::{ConnectorTypes, Type};
+use crate::{ConnectorTypes, SupportedFeatures, Type};
 
 use super::query_plan_error::QueryPlanError;
 
@@ -36,8 +36,39 @@ pub trait QueryContext: ConnectorTypes {
     fn object_types(&self) -> &BTreeMap<ndc::ObjectTypeName, ndc::ObjectType>;
     fn procedures(&self) -> &BTreeMap<ndc::ProcedureName, ndc::ProcedureInfo>;
 
+    /// Relationships declared in connector configuration. Query requests may reference these by
+    /// name without including them in `collection_relationships`. Relationships included in
+    /// a request take precedence.
+    fn configured_relationships(&self) -> &BTreeMap<ndc::RelationshipName, ndc::Relationship>;
+
     /* Provided methods */
 
+    /// Query features that this connector can evaluate. The planner rejects requests that use
+    /// other features.
+    fn supported_features(&self) -> SupportedFeatures {
+        SupportedFeatures::ALL
+    }
+
+    /// Arguments to pass to the aggregation function with the given name. Requests cannot supply
+    /// arguments for aggregation functions, so connectors that support parameterized functions
+    /// declare named functions with fixed arguments.
+    fn aggregation_function_arguments(
+        &self,
+        _function_name: &ndc::AggregateFunctionName,
+    ) -> BTreeMap<ndc::ArgumentName, serde_json::Value> {
+        Default::default()
+    }
+
+    /// Checks a literal comparison value before it is used with the given operator. Connectors
+    /// may reject values that the database would fail to evaluate, such as malformed patterns.
+    fn validate_comparison_value(
+        &self,
+        _operator: &Self::ComparisonOperator,
+        _value: &serde_json::Value,
+    ) -> Result<()> {
+        Ok(())
+    }
+
     fn find_aggregation_function_definition(
         &self,
         input_type: &Type<Self::ScalarType>,
@@ -23,6 +23,12 @@ pub enum QueryPlanError {
     #[error("{0}")]
     TypeMismatch(String),
 
+    #[error("Invalid value for comparison operator \"{operator}\": {message}")]
+    InvalidComparisonValue {
+        operator: ndc::ComparisonOperatorName,
+        message: String,
+    },
+
     #[error("Unknown comparison operator, \"{0}\"")]
     UnknownComparisonOperator(ndc::ComparisonOperatorName),
 
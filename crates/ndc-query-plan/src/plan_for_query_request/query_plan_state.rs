@@ -82,13 +82,29 @@ impl<T: QueryContext> QueryPlanState<'_, T> {
         arguments: BTreeMap<ndc::ArgumentName, RelationshipArgument>,
         query: Query<T>,
     ) -> Result<ndc::RelationshipName> {
+        if !self.context.supported_features().relationships {
+            return Err(QueryPlanError::NotImplemented("relationships"));
+        }
         let ndc_relationship =
             lookup_relationship(self.collection_relationships, &ndc_relationship_name)?;
 
-        for argument in arguments.values() {
+        // Variables given as arguments take the type of the corresponding parameter of the target
+        // collection, which is a native query if it has parameters
+        let context = self.context;
+        let parameters = context
+            .find_collection(&ndc_relationship.target_collection)
+            .map(|collection| &collection.arguments)
+            .ok();
+        for (argument_name, argument) in &arguments {
             if let RelationshipArgument::Variable { name } = argument {
-                // TODO: Is there a way to infer a type here?
-                self.register_variable_use_of_unknown_type(name)
+                let parameter = parameters.and_then(|parameters| parameters.get(argument_name));
+                match parameter {
+                    Some(parameter) => {
+                        let parameter_type = context.ndc_to_plan_type(&parameter.argument_type)?;
+                        self.register_variable_use(name, parameter_type)
+                    }
+                    None => self.register_variable_use_of_unknown_type(name),
+                }
             }
         }
 
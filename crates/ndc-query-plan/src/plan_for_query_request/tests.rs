@@ -534,6 +534,7 @@ fn translates_aggregate_selections() -> Result<(), anyhow::Error> {
                         plan::Aggregate::SingleColumn {
                             column: "id".into(),
                             function: plan_test_helpers::AggregateFunction::Average,
+                            arguments: Default::default(),
                             result_type: plan::Type::Scalar(plan_test_helpers::ScalarType::Double),
                         },
                     ),
@@ -943,3 +944,32 @@ fn translates_predicate_referencing_field_of_related_collection() -> anyhow::Res
     assert_eq!(query_plan, expected);
     Ok(())
 }
+
+#[test]
+fn uses_relationship_declared_in_configuration() -> anyhow::Result<()> {
+    let query_context = TestContext {
+        relationships: [(
+            "author".into(),
+            relationship("authors", [("authorId", "id")]).into(),
+        )]
+        .into(),
+        ..make_nested_schema()
+    };
+    let request = query_request()
+        .collection("appearances")
+        .query(
+            query().fields([relation_field!("presenter" => "author", query().fields([
+                field!("name"),
+            ]))]),
+        )
+        .into();
+    let query_plan = plan_for_query_request(&query_context, request)?;
+
+    let relationship = &query_plan.query.relationships[&"author".into()];
+    assert_eq!(relationship.target_collection.as_str(), "authors");
+    assert_eq!(
+        relationship.column_mapping,
+        [("authorId".into(), "id".into())].into()
+    );
+    Ok(())
+}
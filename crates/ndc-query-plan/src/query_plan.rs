@@ -130,6 +130,8 @@ pub enum Aggregate<T: ConnectorTypes> {
         column: ndc_models::FieldName,
         /// Single column aggregate function name.
         function: T::AggregateFunction,
+        /// Arguments for the aggregation function, such as the percentile to compute
+        arguments: BTreeMap<ndc_models::ArgumentName, serde_json::Value>,
         result_type: Type<T::ScalarType>,
     },
     StarCount,
@@ -0,0 +1,74 @@
+use ndc_models as ndc;
+
+/// Query features that a connector is able to evaluate. This is the single source of truth for
+/// the capabilities a connector advertises, and for the query planner which rejects requests that
+/// use features the connector does not support with [crate::QueryPlanError::NotImplemented]
+/// instead of failing when the query runs.
+///
+/// Exists expressions are not listed here because ndc-spec v0.1 has no capability for them - all
+/// connectors are expected to support them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SupportedFeatures {
+    pub aggregates: bool,
+    pub variables: bool,
+    pub explain: bool,
+    pub nested_field_filter_by: bool,
+    pub nested_field_order_by: bool,
+    pub nested_field_aggregates: bool,
+    pub relationships: bool,
+    /// Comparisons that target columns of related collections
+    pub relation_comparisons: bool,
+    /// Ordering by aggregates over related collections
+    pub order_by_aggregate: bool,
+}
+
+impl SupportedFeatures {
+    pub const ALL: SupportedFeatures = SupportedFeatures {
+        aggregates: true,
+        variables: true,
+        explain: true,
+        nested_field_filter_by: true,
+        nested_field_order_by: true,
+        nested_field_aggregates: true,
+        relationships: true,
+        relation_comparisons: true,
+        order_by_aggregate: true,
+    };
+
+    pub fn capabilities(&self) -> ndc::Capabilities {
+        ndc::Capabilities {
+            query: ndc::QueryCapabilities {
+                aggregates: leaf(self.aggregates),
+                variables: leaf(self.variables),
+                explain: leaf(self.explain),
+                nested_fields: ndc::NestedFieldCapabilities {
+                    filter_by: leaf(self.nested_field_filter_by),
+                    order_by: leaf(self.nested_field_order_by),
+                    aggregates: leaf(self.nested_field_aggregates),
+                },
+            },
+            mutation: ndc::MutationCapabilities {
+                transactional: None,
+                explain: None,
+            },
+            relationships: if self.relationships {
+                Some(ndc::RelationshipCapabilities {
+                    relation_comparisons: leaf(self.relation_comparisons),
+                    order_by_aggregate: leaf(self.order_by_aggregate),
+                })
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl Default for SupportedFeatures {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+fn leaf(supported: bool) -> Option<ndc::LeafCapability> {
+    supported.then_some(ndc::LeafCapability {})
+}
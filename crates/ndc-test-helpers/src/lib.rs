@@ -182,6 +182,11 @@ impl QueryBuilder {
         self
     }
 
+    pub fn offset(mut self, n: u32) -> Self {
+        self.offset = Some(n);
+        self
+    }
+
     pub fn order_by(mut self, elements: Vec<OrderByElement>) -> Self {
         self.order_by = Some(OrderBy { elements });
         self